@@ -15,16 +15,21 @@ use lightning::chain;
 use lightning::chain::chaininterface::{BroadcasterInterface, FeeEstimator};
 use lightning::chain::chainmonitor::{ChainMonitor, Persist};
 use lightning::chain::keysinterface::{Sign, KeysInterface};
-use lightning::ln::channelmanager::ChannelManager;
+use lightning::ln::channelmanager::{ChannelManager, Sleeper};
 use lightning::ln::msgs::{ChannelMessageHandler, RoutingMessageHandler};
 use lightning::ln::peer_handler::{CustomMessageHandler, PeerManager, SocketDescriptor};
+use lightning::ln::peer_policy::WriteablePeerPolicy;
 use lightning::routing::gossip::{NetworkGraph, P2PGossipSync};
 use lightning::routing::scoring::WriteableScore;
-use lightning::util::events::{Event, EventHandler, EventsProvider};
+use lightning::util::events::{AsyncEventsProvider, Event, EventHandler, EventsProvider};
 use lightning::util::logger::Logger;
 use lightning::util::persist::Persister;
 use lightning_rapid_gossip_sync::RapidGossipSync;
-use std::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::thread::JoinHandle;
@@ -59,6 +64,7 @@ use std::ops::Deref;
 pub struct BackgroundProcessor {
 	stop_thread: Arc<AtomicBool>,
 	thread_handle: Option<JoinHandle<Result<(), std::io::Error>>>,
+	metrics: MetricsHandle,
 }
 
 #[cfg(not(test))]
@@ -84,11 +90,206 @@ const SCORER_PERSIST_TIMER: u64 = 30;
 #[cfg(test)]
 const SCORER_PERSIST_TIMER: u64 = 1;
 
+#[cfg(not(test))]
+const PEER_POLICY_PERSIST_TIMER: u64 = 60;
+#[cfg(test)]
+const PEER_POLICY_PERSIST_TIMER: u64 = 1;
+
 #[cfg(not(test))]
 const FIRST_NETWORK_PRUNE_TIMER: u64 = 60;
 #[cfg(test)]
 const FIRST_NETWORK_PRUNE_TIMER: u64 = 1;
 
+/// A job registered via [`BackgroundProcessorConfig::register_periodic_task`].
+struct PeriodicTask {
+	name: &'static str,
+	interval: Duration,
+	task: Box<dyn FnMut() + Send>,
+}
+
+/// Configures the intervals at which the background processor performs its periodic work, along
+/// with any additional periodic tasks to run in the same loop.
+///
+/// Built starting from [`BackgroundProcessorConfig::default`], which uses LDK's recommended
+/// intervals, and passed to [`BackgroundProcessor::start_with_config`] or
+/// [`process_events_async`].
+pub struct BackgroundProcessorConfig {
+	freshness_timer: Duration,
+	ping_timer: Duration,
+	network_prune_timer: Duration,
+	first_network_prune_timer: Duration,
+	scorer_persist_timer: Duration,
+	peer_policy_persist_timer: Duration,
+	periodic_tasks: Vec<PeriodicTask>,
+	time_source: Option<fn() -> Duration>,
+	metrics: MetricsHandle,
+}
+
+impl Default for BackgroundProcessorConfig {
+	fn default() -> Self {
+		Self {
+			freshness_timer: Duration::from_secs(FRESHNESS_TIMER),
+			ping_timer: Duration::from_secs(PING_TIMER),
+			network_prune_timer: Duration::from_secs(NETWORK_PRUNE_TIMER),
+			first_network_prune_timer: Duration::from_secs(FIRST_NETWORK_PRUNE_TIMER),
+			scorer_persist_timer: Duration::from_secs(SCORER_PERSIST_TIMER),
+			peer_policy_persist_timer: Duration::from_secs(PEER_POLICY_PERSIST_TIMER),
+			periodic_tasks: Vec::new(),
+			time_source: None,
+			metrics: MetricsHandle(Arc::new(Mutex::new(BackgroundProcessorMetrics::default()))),
+		}
+	}
+}
+
+impl BackgroundProcessorConfig {
+	/// Sets how often [`ChannelManager::timer_tick_occurred`] is called.
+	///
+	/// [`ChannelManager::timer_tick_occurred`]: lightning::ln::channelmanager::ChannelManager::timer_tick_occurred
+	pub fn freshness_timer(mut self, interval: Duration) -> Self {
+		self.freshness_timer = interval;
+		self
+	}
+
+	/// Sets how often [`PeerManager::timer_tick_occurred`] is called, which pings our peers and
+	/// disconnects those which did not respond in time.
+	pub fn ping_timer(mut self, interval: Duration) -> Self {
+		self.ping_timer = interval;
+		self
+	}
+
+	/// Sets how often the [`NetworkGraph`] is pruned of stale entries and persisted, once it has
+	/// been pruned for the first time.
+	pub fn network_prune_timer(mut self, interval: Duration) -> Self {
+		self.network_prune_timer = interval;
+		self
+	}
+
+	/// Sets how long after startup we first prune the [`NetworkGraph`]. This is also the interval
+	/// at which we re-check whether the graph can be pruned while rapid gossip sync is pending.
+	pub fn first_network_prune_timer(mut self, interval: Duration) -> Self {
+		self.first_network_prune_timer = interval;
+		self
+	}
+
+	/// Sets how often the scorer is persisted.
+	pub fn scorer_persist_timer(mut self, interval: Duration) -> Self {
+		self.scorer_persist_timer = interval;
+		self
+	}
+
+	/// Sets how often the [`PeerManager`]'s peer policy is persisted.
+	pub fn peer_policy_persist_timer(mut self, interval: Duration) -> Self {
+		self.peer_policy_persist_timer = interval;
+		self
+	}
+
+	/// Registers a task to be run on the background processing loop roughly every `interval`,
+	/// e.g. to rebroadcast transactions, update fees or sweep outputs.
+	///
+	/// Tasks run inline with the rest of the loop, so long-running tasks delay event handling and
+	/// [`ChannelManager`] persistence and should instead be spawned elsewhere. The time spent in
+	/// each task is reported under its `name` in [`BackgroundProcessorMetrics::periodic_tasks`],
+	/// with tasks registered under the same name reported together.
+	///
+	/// [`ChannelManager`]: lightning::ln::channelmanager::ChannelManager
+	pub fn register_periodic_task<F: 'static + FnMut() + Send>(mut self, name: &'static str, interval: Duration, task: F) -> Self {
+		self.periodic_tasks.push(PeriodicTask { name, interval, task: Box::new(task) });
+		self.metrics.0.lock().unwrap().periodic_tasks.entry(name).or_default();
+		self
+	}
+
+	/// Sets the clock used by [`process_events_async`] to time each loop phase, returning the time
+	/// elapsed since an arbitrary, fixed point.
+	///
+	/// [`process_events_async`] does not otherwise consult a clock, so it can run in environments
+	/// where [`std::time::Instant`] is unavailable. Without a time source no metrics are recorded
+	/// there. [`BackgroundProcessor`] always records metrics using [`std::time::Instant`].
+	pub fn time_source(mut self, time_source: fn() -> Duration) -> Self {
+		self.time_source = Some(time_source);
+		self
+	}
+
+	/// Gets a handle through which the metrics recorded by a background processor started with
+	/// this config can be read.
+	pub fn metrics(&self) -> MetricsHandle {
+		self.metrics.clone()
+	}
+}
+
+/// Timing statistics for one phase of the background processing loop.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PhaseMetrics {
+	/// The number of times the phase ran.
+	pub count: u64,
+	/// The total time spent in the phase.
+	pub total_time: Duration,
+	/// The longest time a single run of the phase took.
+	pub max_time: Duration,
+	/// The time the most recent run of the phase took.
+	pub last_time: Duration,
+}
+
+impl PhaseMetrics {
+	fn record(&mut self, time: Duration) {
+		self.count += 1;
+		self.total_time += time;
+		self.max_time = core::cmp::max(self.max_time, time);
+		self.last_time = time;
+	}
+
+	/// The average time a single run of the phase took.
+	pub fn average_time(&self) -> Duration {
+		if self.count == 0 {
+			return Duration::from_secs(0);
+		}
+		Duration::from_nanos((self.total_time.as_nanos() / self.count as u128) as u64)
+	}
+}
+
+/// Timing statistics for each phase of the background processing loop, useful to spot e.g. slow
+/// persisters or event handlers.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BackgroundProcessorMetrics {
+	/// Handling [`ChannelManager`] and [`ChainMonitor`] events.
+	///
+	/// [`ChannelManager`]: lightning::ln::channelmanager::ChannelManager
+	/// [`ChainMonitor`]: lightning::chain::chainmonitor::ChainMonitor
+	pub event_handling: PhaseMetrics,
+	/// Calling [`PeerManager::process_events`].
+	pub peer_events: PhaseMetrics,
+	/// Persisting the [`ChannelManager`].
+	///
+	/// [`ChannelManager`]: lightning::ln::channelmanager::ChannelManager
+	pub manager_persistence: PhaseMetrics,
+	/// Pruning and persisting the [`NetworkGraph`].
+	pub graph_persistence: PhaseMetrics,
+	/// Persisting the scorer.
+	pub scorer_persistence: PhaseMetrics,
+	/// Persisting the [`PeerManager`]'s peer policy.
+	pub peer_policy_persistence: PhaseMetrics,
+	/// Running tasks registered via [`BackgroundProcessorConfig::register_periodic_task`], by the
+	/// name they were registered under.
+	pub periodic_tasks: HashMap<&'static str, PhaseMetrics>,
+}
+
+/// A handle to the [`BackgroundProcessorMetrics`] recorded by a running background processor.
+#[derive(Clone)]
+pub struct MetricsHandle(Arc<Mutex<BackgroundProcessorMetrics>>);
+
+impl MetricsHandle {
+	/// Gets a snapshot of the metrics recorded so far.
+	pub fn get(&self) -> BackgroundProcessorMetrics {
+		self.0.lock().unwrap().clone()
+	}
+
+	fn record<P: FnOnce(&mut BackgroundProcessorMetrics) -> &mut PhaseMetrics>(&self, phase: P, start: Option<Duration>, end: Option<Duration>) {
+		if let (Some(start), Some(end)) = (start, end) {
+			let mut metrics = self.0.lock().unwrap();
+			phase(&mut metrics).record(end.checked_sub(start).unwrap_or(Duration::from_secs(0)));
+		}
+	}
+}
+
 /// Either [`P2PGossipSync`] or [`RapidGossipSync`].
 pub enum GossipSync<
 	P: Deref<Target = P2PGossipSync<G, A, L>>,
@@ -219,6 +420,412 @@ where A::Target: chain::Access, L::Target: Logger {
 	}
 }
 
+macro_rules! define_run_body {
+	($persister_trait: ident, $persister: ident, $chain_monitor: ident, $process_chain_monitor_events: expr,
+	 $channel_manager: ident, $process_channel_manager_events: expr,
+	 $gossip_sync: ident, $peer_manager: ident, $logger: ident, $scorer: ident, $config: ident,
+	 $loop_exit_check: expr, $await: expr, $get_timer: expr, $timer_elapsed: expr, $now: expr,
+	 [$($maybe_await: tt)*]) => { {
+		log_trace!($logger, "Calling ChannelManager's timer_tick_occurred on startup");
+		$channel_manager.timer_tick_occurred();
+
+		let metrics = $config.metrics.clone();
+		let mut last_freshness_call = $get_timer($config.freshness_timer);
+		let mut last_ping_call = $get_timer($config.ping_timer);
+		let mut last_prune_call = $get_timer($config.first_network_prune_timer);
+		let mut last_scorer_persist_call = $get_timer($config.scorer_persist_timer);
+		let mut last_peer_policy_persist_call = $get_timer($config.peer_policy_persist_timer);
+		let mut last_task_calls: Vec<_> = $config.periodic_tasks.iter().map(|task| $get_timer(task.interval)).collect();
+		let mut have_pruned = false;
+
+		loop {
+			let phase_start = $now();
+			$process_channel_manager_events;
+			$process_chain_monitor_events;
+			metrics.record(|m| &mut m.event_handling, phase_start, $now());
+
+			// Note that the PeerManager::process_events may block on ChannelManager's locks,
+			// hence it comes last here. When the ChannelManager finishes whatever it's doing,
+			// we want to ensure we get into `persist_manager` as quickly as we can, especially
+			// without running the normal event processing above and handing events to users.
+			//
+			// Specifically, on an *extremely* slow machine, we may see ChannelManager start
+			// processing a message effectively at any point during this loop. In order to
+			// minimize the time between such processing completing and persisting the updated
+			// ChannelManager, we want to minimize methods blocking on a ChannelManager
+			// generally, and as a fallback place such blocking only immediately before
+			// persistence.
+			let phase_start = $now();
+			$peer_manager.process_events();
+			metrics.record(|m| &mut m.peer_events, phase_start, $now());
+
+			// Wait for the ChannelManager to need persisting, for at most 100ms. Alongside whether
+			// updates are available, `$await` reports whether that wait took far longer than it
+			// should have, indicating we were put to sleep.
+			let (updates_available, await_slow): (bool, bool) = $await;
+
+			if updates_available {
+				log_trace!($logger, "Persisting ChannelManager...");
+				let phase_start = $now();
+				$persister_trait::persist_manager(&*$persister, &*$channel_manager)$($maybe_await)*?;
+				metrics.record(|m| &mut m.manager_persistence, phase_start, $now());
+				log_trace!($logger, "Done persisting ChannelManager.");
+			}
+			// Exit the loop if the background processor was requested to stop.
+			if $loop_exit_check {
+				log_trace!($logger, "Terminating background processor.");
+				break;
+			}
+			if $timer_elapsed(&mut last_freshness_call, $config.freshness_timer)$($maybe_await)* {
+				log_trace!($logger, "Calling ChannelManager's timer_tick_occurred");
+				$channel_manager.timer_tick_occurred();
+				last_freshness_call = $get_timer($config.freshness_timer);
+			}
+			if await_slow {
+				// On various platforms, we may be starved of CPU cycles for several reasons.
+				// E.g. on iOS, if we've been in the background, we will be entirely paused.
+				// Similarly, if we're on a desktop platform and the device has been asleep, we
+				// may not get any cycles.
+				// We detect this by checking if our max-100ms-sleep, above, ran longer than a
+				// full second, at which point we assume sockets may have been killed (they
+				// appear to be at least on some platforms, even if it has only been a second).
+				// Note that we have to take care to not get here just because user event
+				// processing was slow at the top of the loop. For example, the sample client
+				// may call Bitcoin Core RPCs during event handling, which very often takes
+				// more than a handful of seconds to complete, and shouldn't disconnect all our
+				// peers.
+				log_trace!($logger, "100ms sleep took more than a second, disconnecting peers.");
+				$peer_manager.disconnect_all_peers();
+				last_ping_call = $get_timer($config.ping_timer);
+			} else if $timer_elapsed(&mut last_ping_call, $config.ping_timer)$($maybe_await)* {
+				log_trace!($logger, "Calling PeerManager's timer_tick_occurred");
+				$peer_manager.timer_tick_occurred();
+				last_ping_call = $get_timer($config.ping_timer);
+			}
+
+			// Note that we want to run a graph prune once not long after startup before
+			// falling back to our usual hourly prunes. This avoids short-lived clients never
+			// pruning their network graph. We run once 60 seconds after startup before
+			// continuing our normal cadence.
+			if $timer_elapsed(&mut last_prune_call, if have_pruned { $config.network_prune_timer } else { $config.first_network_prune_timer })$($maybe_await)* {
+				// The network graph must not be pruned while rapid sync completion is pending
+				log_trace!($logger, "Assessing prunability of network graph");
+				if let Some(network_graph) = $gossip_sync.prunable_network_graph() {
+					let phase_start = $now();
+					network_graph.remove_stale_channels();
+
+					if let Err(e) = $persister_trait::persist_graph(&*$persister, &**network_graph)$($maybe_await)* {
+						log_error!($logger, "Error: Failed to persist network graph, check your disk and permissions {}", e)
+					}
+					metrics.record(|m| &mut m.graph_persistence, phase_start, $now());
+
+					last_prune_call = $get_timer($config.network_prune_timer);
+					have_pruned = true;
+				} else {
+					log_trace!($logger, "Not pruning network graph, either due to pending rapid gossip sync or absence of a prunable graph.");
+				}
+			}
+
+			if $timer_elapsed(&mut last_scorer_persist_call, $config.scorer_persist_timer)$($maybe_await)* {
+				if let Some(ref scorer) = $scorer {
+					log_trace!($logger, "Persisting scorer");
+					let phase_start = $now();
+					if let Err(e) = $persister_trait::persist_scorer(&*$persister, &**scorer)$($maybe_await)* {
+						log_error!($logger, "Error: Failed to persist scorer, check your disk and permissions {}", e)
+					}
+					metrics.record(|m| &mut m.scorer_persistence, phase_start, $now());
+				}
+				last_scorer_persist_call = $get_timer($config.scorer_persist_timer);
+			}
+
+			if $timer_elapsed(&mut last_peer_policy_persist_call, $config.peer_policy_persist_timer)$($maybe_await)* {
+				log_trace!($logger, "Persisting peer policy");
+				let phase_start = $now();
+				if let Err(e) = $persister_trait::persist_peer_policy(&*$persister, $peer_manager.peer_policy())$($maybe_await)* {
+					log_error!($logger, "Error: Failed to persist peer policy, check your disk and permissions {}", e)
+				}
+				metrics.record(|m| &mut m.peer_policy_persistence, phase_start, $now());
+				last_peer_policy_persist_call = $get_timer($config.peer_policy_persist_timer);
+			}
+
+			for (task, last_task_call) in $config.periodic_tasks.iter_mut().zip(last_task_calls.iter_mut()) {
+				if $timer_elapsed(last_task_call, task.interval)$($maybe_await)* {
+					let phase_start = $now();
+					(task.task)();
+					metrics.record(|m| m.periodic_tasks.entry(task.name).or_default(), phase_start, $now());
+					*last_task_call = $get_timer(task.interval);
+				}
+			}
+		}
+
+		// After we exit, ensure we persist the ChannelManager one final time - this avoids
+		// some races where users quit while channel updates were in-flight, with
+		// ChannelMonitor update(s) persisted without a corresponding ChannelManager update.
+		$persister_trait::persist_manager(&*$persister, &*$channel_manager)$($maybe_await)*?;
+
+		// Persist Scorer on exit
+		if let Some(ref scorer) = $scorer {
+			$persister_trait::persist_scorer(&*$persister, &**scorer)$($maybe_await)*?;
+		}
+
+		// Persist NetworkGraph on exit
+		if let Some(network_graph) = $gossip_sync.network_graph() {
+			$persister_trait::persist_graph(&*$persister, &**network_graph)$($maybe_await)*?;
+		}
+
+		// Persist the peer policy on exit
+		$persister_trait::persist_peer_policy(&*$persister, $peer_manager.peer_policy())$($maybe_await)*?;
+
+		Ok(())
+	} }
+}
+
+/// The future returned by the methods of an [`AsyncPersister`].
+pub type AsyncPersistResult<'a> = Pin<Box<dyn Future<Output = Result<(), std::io::Error>> + 'a>>;
+
+/// An asynchronous version of [`Persister`], used by [`process_events_async`].
+///
+/// Any [`Persister`] is also an [`AsyncPersister`] which completes its work before returning the
+/// future, so only persisters which actually need to await I/O have to implement this directly.
+///
+/// Note that the returned futures are not required to be `Send`, allowing implementations to be
+/// used on single-threaded runtimes.
+pub trait AsyncPersister<'a, Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref, S>
+	where M::Target: 'static + chain::Watch<Signer>,
+		T::Target: 'static + BroadcasterInterface,
+		K::Target: 'static + KeysInterface<Signer = Signer>,
+		F::Target: 'static + FeeEstimator,
+		L::Target: 'static + Logger,
+		S: WriteableScore<'a>,
+{
+	/// Persist the given [`ChannelManager`], resolving to an error if persistence failed.
+	fn persist_manager<'b>(&'b self, channel_manager: &'b ChannelManager<Signer, M, T, K, F, L>) -> AsyncPersistResult<'b>;
+
+	/// Persist the given [`NetworkGraph`], resolving to an error if persistence failed.
+	fn persist_graph<'b>(&'b self, network_graph: &'b NetworkGraph<L>) -> AsyncPersistResult<'b>;
+
+	/// Persist the given [`WriteableScore`], resolving to an error if persistence failed.
+	fn persist_scorer<'b>(&'b self, scorer: &'b S) -> AsyncPersistResult<'b>;
+
+	/// Persist the given [`WriteablePeerPolicy`], resolving to an error if persistence failed.
+	fn persist_peer_policy<'b, P: WriteablePeerPolicy>(&'b self, peer_policy: &'b P) -> AsyncPersistResult<'b>;
+}
+
+impl<'a, A: Persister<'a, Signer, M, T, K, F, L, S>, Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref, S>
+	AsyncPersister<'a, Signer, M, T, K, F, L, S> for A
+	where M::Target: 'static + chain::Watch<Signer>,
+		T::Target: 'static + BroadcasterInterface,
+		K::Target: 'static + KeysInterface<Signer = Signer>,
+		F::Target: 'static + FeeEstimator,
+		L::Target: 'static + Logger,
+		S: WriteableScore<'a>,
+{
+	fn persist_manager<'b>(&'b self, channel_manager: &'b ChannelManager<Signer, M, T, K, F, L>) -> AsyncPersistResult<'b> {
+		let res = Persister::persist_manager(self, channel_manager);
+		Box::pin(async move { res })
+	}
+
+	fn persist_graph<'b>(&'b self, network_graph: &'b NetworkGraph<L>) -> AsyncPersistResult<'b> {
+		let res = Persister::persist_graph(self, network_graph);
+		Box::pin(async move { res })
+	}
+
+	fn persist_scorer<'b>(&'b self, scorer: &'b S) -> AsyncPersistResult<'b> {
+		let res = Persister::persist_scorer(self, scorer);
+		Box::pin(async move { res })
+	}
+
+	fn persist_peer_policy<'b, P: WriteablePeerPolicy>(&'b self, peer_policy: &'b P) -> AsyncPersistResult<'b> {
+		let res = Persister::persist_peer_policy(self, peer_policy);
+		Box::pin(async move { res })
+	}
+}
+
+enum SelectorOutput {
+	A, B, C(bool),
+}
+
+/// Polls three futures, completing as soon as any one does, preferring `a`, then `b`, if several
+/// are ready.
+struct Selector<A: Future<Output = ()> + Unpin, B: Future<Output = ()> + Unpin, C: Future<Output = bool> + Unpin> {
+	a: A,
+	b: B,
+	c: C,
+}
+
+impl<A: Future<Output = ()> + Unpin, B: Future<Output = ()> + Unpin, C: Future<Output = bool> + Unpin> Future for Selector<A, B, C> {
+	type Output = SelectorOutput;
+
+	fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<SelectorOutput> {
+		match Pin::new(&mut self.a).poll(ctx) {
+			Poll::Ready(()) => { return Poll::Ready(SelectorOutput::A); },
+			Poll::Pending => {},
+		}
+		match Pin::new(&mut self.b).poll(ctx) {
+			Poll::Ready(()) => { return Poll::Ready(SelectorOutput::B); },
+			Poll::Pending => {},
+		}
+		match Pin::new(&mut self.c).poll(ctx) {
+			Poll::Ready(res) => { return Poll::Ready(SelectorOutput::C(res)); },
+			Poll::Pending => {},
+		}
+		Poll::Pending
+	}
+}
+
+/// A timer of [`process_events_async`], backed by a sleeper future, which remembers once it has
+/// elapsed so that the sleeper future is never polled again after completing.
+struct AsyncTimer<F: Future<Output = bool> + Unpin> {
+	sleep_future: F,
+	elapsed: bool,
+}
+
+/// Completes immediately, indicating whether the timer has elapsed, without waiting for it to do
+/// so.
+struct TimerElapsed<'a, F: Future<Output = bool> + Unpin> {
+	timer: &'a mut AsyncTimer<F>,
+}
+
+impl<'a, F: Future<Output = bool> + Unpin> Future for TimerElapsed<'a, F> {
+	type Output = bool;
+
+	fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<bool> {
+		let timer = &mut *self.timer;
+		if !timer.elapsed {
+			timer.elapsed = Pin::new(&mut timer.sleep_future).poll(ctx).is_ready();
+		}
+		Poll::Ready(timer.elapsed)
+	}
+}
+
+fn timer_elapsed<F: Future<Output = bool> + Unpin>(timer: &mut AsyncTimer<F>, _: Duration) -> TimerElapsed<'_, F> {
+	TimerElapsed { timer }
+}
+
+/// Hands all pending events from `events_provider` to the async `event_handler`, one at a time,
+/// after applying them to the `network_graph` (if any). Each event is only marked handled once
+/// `event_handler` has completed for it.
+async fn handle_events_async<
+	EP: AsyncEventsProvider, G: Deref,
+	EventHandlerFuture: Future<Output = ()>, EH: Fn(Event) -> EventHandlerFuture,
+>(events_provider: &EP, network_graph: Option<&G>, event_handler: &EH) where G::Target: EventHandler {
+	for event in events_provider.get_pending_events() {
+		if let Some(network_graph) = network_graph {
+			network_graph.handle_event(&event);
+		}
+		event_handler(event).await;
+		events_provider.events_handled(1);
+	}
+}
+
+/// Processes background events in a future.
+///
+/// This is the async equivalent of [`BackgroundProcessor::start`], taking care of the same
+/// responsibilities but without spawning a thread or blocking on the [`ChannelManager`]'s
+/// persistence notifier. It can thus be driven by any executor, including single-threaded ones
+/// such as those available in WASM environments.
+///
+/// `sleeper` should return a future which completes after the given [`Duration`] has elapsed. It
+/// is used both to bound how long we wait for [`ChannelManager`] updates and to drive our timers.
+/// The future's output indicates whether the processor should shut down: once a sleeper future
+/// bounding our wait for updates resolves to `true`, the [`ChannelManager`], scorer,
+/// [`NetworkGraph`] and peer policy are persisted one last time and the returned future completes. The output of
+/// the sleeper futures driving our timers is ignored.
+///
+/// `event_handler` is called with each [`Event`] and the resulting future is awaited before the
+/// next event is handled. Events are only marked handled once the future completes, so that any
+/// event whose handling was interrupted is handled again after a restart. As in
+/// [`BackgroundProcessor::start`], events are first used to update the [`NetworkGraph`] if one is
+/// provided via `gossip_sync`.
+///
+/// Intervals, additional periodic tasks and metrics are configured via `config`, as in
+/// [`BackgroundProcessor::start_with_config`].
+///
+/// Unlike [`BackgroundProcessor::start`], no wall clock is consulted, so we cannot detect having
+/// been put to sleep by the OS and will not disconnect peers in response. For the same reason,
+/// loop phases are only timed if a [`BackgroundProcessorConfig::time_source`] is set.
+///
+/// The returned future resolves to an error if persisting the [`ChannelManager`] fails, or if
+/// persisting any of the scorer, [`NetworkGraph`] or peer policy fails on shutdown.
+///
+/// [`ChannelManager`]: lightning::ln::channelmanager::ChannelManager
+/// [`NetworkGraph`]: lightning::routing::gossip::NetworkGraph
+/// [`Event`]: lightning::util::events::Event
+pub async fn process_events_async<
+	'a,
+	Signer: 'static + Sign,
+	CA: 'static + Deref,
+	CF: 'static + Deref,
+	CW: 'static + Deref,
+	T: 'static + Deref,
+	K: 'static + Deref,
+	F: 'static + Deref,
+	G: 'static + Deref<Target = NetworkGraph<L>>,
+	L: 'static + Deref,
+	P: 'static + Deref,
+	Descriptor: 'static + SocketDescriptor,
+	CMH: 'static + Deref,
+	RMH: 'static + Deref,
+	EventHandlerFuture: Future<Output = ()>,
+	EH: Fn(Event) -> EventHandlerFuture,
+	PS: 'static + Deref,
+	M: 'static + Deref<Target = ChainMonitor<Signer, CF, T, F, L, P>>,
+	CM: 'static + Deref<Target = ChannelManager<Signer, CW, T, K, F, L>>,
+	PGS: 'static + Deref<Target = P2PGossipSync<G, CA, L>>,
+	RGS: 'static + Deref<Target = RapidGossipSync<G, L>>,
+	UMH: 'static + Deref,
+	PP: 'static + Deref,
+	PM: 'static + Deref<Target = PeerManager<Descriptor, CMH, RMH, L, UMH, PP>>,
+	S: 'static + Deref<Target = SC>,
+	SC: WriteableScore<'a>,
+	SleepFuture: Future<Output = bool> + Unpin,
+	Sl: Fn(Duration) -> SleepFuture,
+>(
+	persister: PS, event_handler: EH, chain_monitor: M, channel_manager: CM,
+	gossip_sync: GossipSync<PGS, RGS, G, CA, L>, peer_manager: PM, logger: L, scorer: Option<S>,
+	sleeper: Sl, mut config: BackgroundProcessorConfig,
+) -> Result<(), std::io::Error>
+where
+	CA::Target: 'static + chain::Access,
+	CF::Target: 'static + chain::Filter,
+	CW::Target: 'static + chain::Watch<Signer>,
+	T::Target: 'static + BroadcasterInterface,
+	K::Target: 'static + KeysInterface<Signer = Signer>,
+	F::Target: 'static + FeeEstimator,
+	L::Target: 'static + Logger,
+	P::Target: 'static + Persist<Signer>,
+	CMH::Target: 'static + ChannelMessageHandler,
+	RMH::Target: 'static + RoutingMessageHandler,
+	UMH::Target: 'static + CustomMessageHandler,
+	PP::Target: 'static + WriteablePeerPolicy + Sized,
+	PS::Target: 'static + AsyncPersister<'a, Signer, CW, T, K, F, L, SC>,
+{
+	let mut should_break = false;
+	let time_source = config.time_source;
+	define_run_body!(AsyncPersister, persister,
+		chain_monitor, handle_events_async(&*chain_monitor, gossip_sync.network_graph(), &event_handler).await,
+		channel_manager, handle_events_async(&*channel_manager, gossip_sync.network_graph(), &event_handler).await,
+		gossip_sync, peer_manager, logger, scorer, config, should_break,
+		{
+			let fut = Selector {
+				a: channel_manager.get_persistable_update_future(),
+				b: chain_monitor.get_update_future(),
+				c: sleeper(Duration::from_millis(100)),
+			};
+			match fut.await {
+				SelectorOutput::A => (true, false),
+				// New events are handled at the top of the loop, no need to persist.
+				SelectorOutput::B => (false, false),
+				SelectorOutput::C(exit) => {
+					should_break = exit;
+					(false, false)
+				},
+			}
+		},
+		// Our timers are sleeper futures, which we check for completion without blocking.
+		|interval| AsyncTimer { sleep_future: sleeper(interval), elapsed: false }, timer_elapsed, || time_source.map(|now| now()), [.await])
+}
+
 impl BackgroundProcessor {
 	/// Start a background thread that takes care of responsibilities enumerated in the [top-level
 	/// documentation].
@@ -238,6 +845,10 @@ impl BackgroundProcessor {
 	/// [`GossipSync`] is supplied. See [`NetworkGraph::write`] for writing out a [`NetworkGraph`].
 	/// See the `lightning-persister` crate for LDK's provided implementation.
 	///
+	/// [`Persister::persist_peer_policy`] is responsible for writing out the [`PeerManager`]'s peer
+	/// policy, e.g. the bans of a [`DefaultPeerPolicy`], which can be read back with
+	/// [`read_peer_policy`].
+	///
 	/// Typically, users should either implement [`Persister::persist_manager`] to never return an
 	/// error or call [`join`] and handle any error that may arise. For the latter case,
 	/// `BackgroundProcessor` must be restarted by calling `start` again after handling the error.
@@ -262,6 +873,9 @@ impl BackgroundProcessor {
 	/// [`ChannelManager::write`]: lightning::ln::channelmanager::ChannelManager#impl-Writeable
 	/// [`Persister::persist_manager`]: lightning::util::persist::Persister::persist_manager
 	/// [`Persister::persist_graph`]: lightning::util::persist::Persister::persist_graph
+	/// [`Persister::persist_peer_policy`]: lightning::util::persist::Persister::persist_peer_policy
+	/// [`DefaultPeerPolicy`]: lightning::ln::peer_policy::DefaultPeerPolicy
+	/// [`read_peer_policy`]: lightning::util::persist::read_peer_policy
 	/// [`NetworkGraph`]: lightning::routing::gossip::NetworkGraph
 	/// [`NetworkGraph::write`]: lightning::routing::gossip::NetworkGraph#impl-Writeable
 	pub fn start<
@@ -286,12 +900,65 @@ impl BackgroundProcessor {
 		PGS: 'static + Deref<Target = P2PGossipSync<G, CA, L>> + Send + Sync,
 		RGS: 'static + Deref<Target = RapidGossipSync<G, L>> + Send,
 		UMH: 'static + Deref + Send + Sync,
-		PM: 'static + Deref<Target = PeerManager<Descriptor, CMH, RMH, L, UMH>> + Send + Sync,
+		PP: 'static + Deref + Send + Sync,
+		PM: 'static + Deref<Target = PeerManager<Descriptor, CMH, RMH, L, UMH, PP>> + Send + Sync,
+		S: 'static + Deref<Target = SC> + Send + Sync,
+		SC: WriteableScore<'a>,
+	>(
+		persister: PS, event_handler: EH, chain_monitor: M, channel_manager: CM,
+		gossip_sync: GossipSync<PGS, RGS, G, CA, L>, peer_manager: PM, logger: L, scorer: Option<S>,
+	) -> Self
+	where
+		CA::Target: 'static + chain::Access,
+		CF::Target: 'static + chain::Filter,
+		CW::Target: 'static + chain::Watch<Signer>,
+		T::Target: 'static + BroadcasterInterface,
+		K::Target: 'static + KeysInterface<Signer = Signer>,
+		F::Target: 'static + FeeEstimator,
+		L::Target: 'static + Logger,
+		P::Target: 'static + Persist<Signer>,
+		CMH::Target: 'static + ChannelMessageHandler,
+		RMH::Target: 'static + RoutingMessageHandler,
+		UMH::Target: 'static + CustomMessageHandler,
+		PP::Target: 'static + WriteablePeerPolicy + Sized,
+		PS::Target: 'static + Persister<'a, Signer, CW, T, K, F, L, SC>,
+	{
+		Self::start_with_config(persister, event_handler, chain_monitor, channel_manager, gossip_sync,
+			peer_manager, logger, scorer, BackgroundProcessorConfig::default())
+	}
+
+	/// Start a background thread like [`Self::start`], using the intervals and periodic tasks from
+	/// the given [`BackgroundProcessorConfig`].
+	pub fn start_with_config<
+		'a,
+		Signer: 'static + Sign,
+		CA: 'static + Deref + Send + Sync,
+		CF: 'static + Deref + Send + Sync,
+		CW: 'static + Deref + Send + Sync,
+		T: 'static + Deref + Send + Sync,
+		K: 'static + Deref + Send + Sync,
+		F: 'static + Deref + Send + Sync,
+		G: 'static + Deref<Target = NetworkGraph<L>> + Send + Sync,
+		L: 'static + Deref + Send + Sync,
+		P: 'static + Deref + Send + Sync,
+		Descriptor: 'static + SocketDescriptor + Send + Sync,
+		CMH: 'static + Deref + Send + Sync,
+		RMH: 'static + Deref + Send + Sync,
+		EH: 'static + EventHandler + Send,
+		PS: 'static + Deref + Send,
+		M: 'static + Deref<Target = ChainMonitor<Signer, CF, T, F, L, P>> + Send + Sync,
+		CM: 'static + Deref<Target = ChannelManager<Signer, CW, T, K, F, L>> + Send + Sync,
+		PGS: 'static + Deref<Target = P2PGossipSync<G, CA, L>> + Send + Sync,
+		RGS: 'static + Deref<Target = RapidGossipSync<G, L>> + Send,
+		UMH: 'static + Deref + Send + Sync,
+		PP: 'static + Deref + Send + Sync,
+		PM: 'static + Deref<Target = PeerManager<Descriptor, CMH, RMH, L, UMH, PP>> + Send + Sync,
 		S: 'static + Deref<Target = SC> + Send + Sync,
 		SC: WriteableScore<'a>,
 	>(
 		persister: PS, event_handler: EH, chain_monitor: M, channel_manager: CM,
 		gossip_sync: GossipSync<PGS, RGS, G, CA, L>, peer_manager: PM, logger: L, scorer: Option<S>,
+		config: BackgroundProcessorConfig,
 	) -> Self
 	where
 		CA::Target: 'static + chain::Access,
@@ -305,136 +972,44 @@ impl BackgroundProcessor {
 		CMH::Target: 'static + ChannelMessageHandler,
 		RMH::Target: 'static + RoutingMessageHandler,
 		UMH::Target: 'static + CustomMessageHandler,
+		PP::Target: 'static + WriteablePeerPolicy + Sized,
 		PS::Target: 'static + Persister<'a, Signer, CW, T, K, F, L, SC>,
 	{
 		let stop_thread = Arc::new(AtomicBool::new(false));
 		let stop_thread_clone = stop_thread.clone();
+		let metrics = config.metrics();
 		let handle = thread::spawn(move || -> Result<(), std::io::Error> {
+			let mut config = config;
+			let clock_start = Instant::now();
 			let event_handler = DecoratingEventHandler {
 				event_handler,
 				gossip_sync: &gossip_sync,
 			};
-
-			log_trace!(logger, "Calling ChannelManager's timer_tick_occurred on startup");
-			channel_manager.timer_tick_occurred();
-
-			let mut last_freshness_call = Instant::now();
-			let mut last_ping_call = Instant::now();
-			let mut last_prune_call = Instant::now();
-			let mut last_scorer_persist_call = Instant::now();
-			let mut have_pruned = false;
-
-			loop {
-				channel_manager.process_pending_events(&event_handler);
-				chain_monitor.process_pending_events(&event_handler);
-
-				// Note that the PeerManager::process_events may block on ChannelManager's locks,
-				// hence it comes last here. When the ChannelManager finishes whatever it's doing,
-				// we want to ensure we get into `persist_manager` as quickly as we can, especially
-				// without running the normal event processing above and handing events to users.
-				//
-				// Specifically, on an *extremely* slow machine, we may see ChannelManager start
-				// processing a message effectively at any point during this loop. In order to
-				// minimize the time between such processing completing and persisting the updated
-				// ChannelManager, we want to minimize methods blocking on a ChannelManager
-				// generally, and as a fallback place such blocking only immediately before
-				// persistence.
-				peer_manager.process_events();
-
-				// We wait up to 100ms, but track how long it takes to detect being put to sleep,
-				// see `await_start`'s use below.
-				let await_start = Instant::now();
-				let updates_available =
-					channel_manager.await_persistable_update_timeout(Duration::from_millis(100));
-				let await_time = await_start.elapsed();
-
-				if updates_available {
-					log_trace!(logger, "Persisting ChannelManager...");
-					persister.persist_manager(&*channel_manager)?;
-					log_trace!(logger, "Done persisting ChannelManager.");
-				}
-				// Exit the loop if the background processor was requested to stop.
-				if stop_thread.load(Ordering::Acquire) == true {
-					log_trace!(logger, "Terminating background processor.");
-					break;
-				}
-				if last_freshness_call.elapsed().as_secs() > FRESHNESS_TIMER {
-					log_trace!(logger, "Calling ChannelManager's timer_tick_occurred");
-					channel_manager.timer_tick_occurred();
-					last_freshness_call = Instant::now();
-				}
-				if await_time > Duration::from_secs(1) {
-					// On various platforms, we may be starved of CPU cycles for several reasons.
-					// E.g. on iOS, if we've been in the background, we will be entirely paused.
-					// Similarly, if we're on a desktop platform and the device has been asleep, we
-					// may not get any cycles.
-					// We detect this by checking if our max-100ms-sleep, above, ran longer than a
-					// full second, at which point we assume sockets may have been killed (they
-					// appear to be at least on some platforms, even if it has only been a second).
-					// Note that we have to take care to not get here just because user event
-					// processing was slow at the top of the loop. For example, the sample client
-					// may call Bitcoin Core RPCs during event handling, which very often takes
-					// more than a handful of seconds to complete, and shouldn't disconnect all our
-					// peers.
-					log_trace!(logger, "100ms sleep took more than a second, disconnecting peers.");
-					peer_manager.disconnect_all_peers();
-					last_ping_call = Instant::now();
-				} else if last_ping_call.elapsed().as_secs() > PING_TIMER {
-					log_trace!(logger, "Calling PeerManager's timer_tick_occurred");
-					peer_manager.timer_tick_occurred();
-					last_ping_call = Instant::now();
-				}
-
-				// Note that we want to run a graph prune once not long after startup before
-				// falling back to our usual hourly prunes. This avoids short-lived clients never
-				// pruning their network graph. We run once 60 seconds after startup before
-				// continuing our normal cadence.
-				if last_prune_call.elapsed().as_secs() > if have_pruned { NETWORK_PRUNE_TIMER } else { FIRST_NETWORK_PRUNE_TIMER } {
-					// The network graph must not be pruned while rapid sync completion is pending
-					log_trace!(logger, "Assessing prunability of network graph");
-					if let Some(network_graph) = gossip_sync.prunable_network_graph() {
-						network_graph.remove_stale_channels();
-
-						if let Err(e) = persister.persist_graph(network_graph) {
-							log_error!(logger, "Error: Failed to persist network graph, check your disk and permissions {}", e)
-						}
-
-						last_prune_call = Instant::now();
-						have_pruned = true;
-					} else {
-						log_trace!(logger, "Not pruning network graph, either due to pending rapid gossip sync or absence of a prunable graph.");
-					}
-				}
-
-				if last_scorer_persist_call.elapsed().as_secs() > SCORER_PERSIST_TIMER {
-					if let Some(ref scorer) = scorer {
-						log_trace!(logger, "Persisting scorer");
-						if let Err(e) = persister.persist_scorer(&scorer) {
-							log_error!(logger, "Error: Failed to persist scorer, check your disk and permissions {}", e)
-						}
-					}
-					last_scorer_persist_call = Instant::now();
-				}
-			}
-
-			// After we exit, ensure we persist the ChannelManager one final time - this avoids
-			// some races where users quit while channel updates were in-flight, with
-			// ChannelMonitor update(s) persisted without a corresponding ChannelManager update.
-			persister.persist_manager(&*channel_manager)?;
-
-			// Persist Scorer on exit
-			if let Some(ref scorer) = scorer {
-				persister.persist_scorer(&scorer)?;
-			}
-
-			// Persist NetworkGraph on exit
-			if let Some(network_graph) = gossip_sync.network_graph() {
-				persister.persist_graph(network_graph)?;
-			}
-
-			Ok(())
+			define_run_body!(Persister, persister, chain_monitor, chain_monitor.process_pending_events(&event_handler),
+				channel_manager, channel_manager.process_pending_events(&event_handler),
+				gossip_sync, peer_manager, logger, scorer, config, stop_thread.load(Ordering::Acquire),
+				{
+					// We wait up to 100ms, but track how long it takes to detect being put to sleep,
+					// see `await_slow`'s use in `define_run_body`.
+					let await_start = Instant::now();
+					// New ChainMonitor events wake us up too, but are handled at the top of the loop, so
+					// we only persist if the ChannelManager's future completed.
+					let completed = Sleeper::from_two_futures(
+						channel_manager.get_persistable_update_future(), chain_monitor.get_update_future()
+					).wait_timeout_completed(Duration::from_millis(100));
+					(completed[0], await_start.elapsed() > Duration::from_secs(1))
+				},
+				// Note that timers fire once their interval has elapsed, rather than once another
+				// whole second has passed as when intervals were fixed whole numbers of seconds.
+				|_| Instant::now(), |last: &mut Instant, timeout| last.elapsed() > timeout,
+				|| Some(clock_start.elapsed()), [])
 		});
-		Self { stop_thread: stop_thread_clone, thread_handle: Some(handle) }
+		Self { stop_thread: stop_thread_clone, thread_handle: Some(handle), metrics }
+	}
+
+	/// Gets a snapshot of the time spent in each phase of the background processing loop so far.
+	pub fn metrics(&self) -> BackgroundProcessorMetrics {
+		self.metrics.get()
 	}
 
 	/// Join `BackgroundProcessor`'s thread, returning any error that occurred while persisting
@@ -501,7 +1076,7 @@ mod tests {
 	use lightning::ln::peer_handler::{PeerManager, MessageHandler, SocketDescriptor, IgnoringMessageHandler};
 	use lightning::routing::gossip::{NetworkGraph, P2PGossipSync};
 	use lightning::util::config::UserConfig;
-	use lightning::util::events::{Event, MessageSendEventsProvider, MessageSendEvent};
+	use lightning::util::events::{AsyncEventsProvider, Event, MessageSendEventsProvider, MessageSendEvent};
 	use lightning::util::ser::Writeable;
 	use lightning::util::test_utils;
 	use lightning::util::persist::KVStorePersister;
@@ -511,11 +1086,12 @@ mod tests {
 	use std::fs;
 	use std::path::PathBuf;
 	use std::sync::{Arc, Mutex};
+	use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 	use std::sync::mpsc::SyncSender;
-	use std::time::Duration;
+	use std::time::{Duration, SystemTime, UNIX_EPOCH};
 	use lightning::routing::scoring::{FixedPenaltyScorer};
 	use lightning_rapid_gossip_sync::RapidGossipSync;
-	use super::{BackgroundProcessor, GossipSync, FRESHNESS_TIMER};
+	use super::{BackgroundProcessor, BackgroundProcessorConfig, GossipSync, FRESHNESS_TIMER, process_events_async};
 
 	const EVENT_DEADLINE: u64 = 5 * FRESHNESS_TIMER;
 
@@ -577,13 +1153,14 @@ mod tests {
 		graph_persistence_notifier: Option<SyncSender<()>>,
 		manager_error: Option<(std::io::ErrorKind, &'static str)>,
 		scorer_error: Option<(std::io::ErrorKind, &'static str)>,
+		peer_policy_error: Option<(std::io::ErrorKind, &'static str)>,
 		filesystem_persister: FilesystemPersister,
 	}
 
 	impl Persister {
 		fn new(data_dir: String) -> Self {
 			let filesystem_persister = FilesystemPersister::new(data_dir.clone());
-			Self { graph_error: None, graph_persistence_notifier: None, manager_error: None, scorer_error: None, filesystem_persister }
+			Self { graph_error: None, graph_persistence_notifier: None, manager_error: None, scorer_error: None, peer_policy_error: None, filesystem_persister }
 		}
 
 		fn with_graph_error(self, error: std::io::ErrorKind, message: &'static str) -> Self {
@@ -601,6 +1178,10 @@ mod tests {
 		fn with_scorer_error(self, error: std::io::ErrorKind, message: &'static str) -> Self {
 			Self { scorer_error: Some((error, message)), ..self }
 		}
+
+		fn with_peer_policy_error(self, error: std::io::ErrorKind, message: &'static str) -> Self {
+			Self { peer_policy_error: Some((error, message)), ..self }
+		}
 	}
 
 	impl KVStorePersister for Persister {
@@ -627,6 +1208,12 @@ mod tests {
 				}
 			}
 
+			if key == "peer_policy" {
+				if let Some((error, message)) = self.peer_policy_error {
+					return Err(std::io::Error::new(error, message))
+				}
+			}
+
 			self.filesystem_persister.persist(key, object)
 		}
 	}
@@ -809,6 +1396,121 @@ mod tests {
 		let filepath = get_full_filepath("test_background_processor_persister_0".to_string(), "scorer".to_string());
 		check_persisted_data!(nodes[0].scorer, filepath.clone());
 
+		// Check peer policy is persisted
+		let filepath = get_full_filepath("test_background_processor_persister_0".to_string(), "peer_policy".to_string());
+		let expected_bytes = Writeable::encode(nodes[0].peer_manager.peer_policy());
+		loop {
+			if let Ok(bytes) = std::fs::read(filepath.clone()) {
+				if bytes == expected_bytes { break }
+			}
+		}
+
+		assert!(bg_processor.stop().is_ok());
+	}
+
+	#[tokio::test]
+	async fn test_process_events_async() {
+		// Test that the async processor persists the ChannelManager when it needs it, hands events
+		// to an async event handler, only marking them handled once it completes, ticks timers and
+		// exits cleanly once the sleeper asks it to.
+		let nodes = create_nodes(2, "test_process_events_async".to_string());
+		let tx = open_channel!(nodes[0], nodes[1], 100000);
+
+		let data_dir = nodes[0].persister.get_data_dir();
+		let persister = Arc::new(Persister::new(data_dir));
+		let handled_events = Arc::new(Mutex::new(Vec::new()));
+		let channel_manager = Arc::clone(&nodes[0].node);
+		let event_handler = |event: Event| {
+			let handled_events = Arc::clone(&handled_events);
+			let channel_manager = Arc::clone(&channel_manager);
+			async move {
+				tokio::time::sleep(Duration::from_millis(1)).await;
+				// The event remains pending until we're done handling it.
+				let pending_events = AsyncEventsProvider::get_pending_events(&*channel_manager);
+				assert!(pending_events.iter().any(|pending_event| format!("{:?}", pending_event) == format!("{:?}", event)));
+				handled_events.lock().unwrap().push(event);
+			}
+		};
+		let exit = Arc::new(AtomicBool::new(false));
+		let sleeper = |duration: Duration| {
+			let exit = Arc::clone(&exit);
+			Box::pin(async move {
+				tokio::time::sleep(duration).await;
+				exit.load(Ordering::Acquire)
+			})
+		};
+		let config = BackgroundProcessorConfig::default()
+			.time_source(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap());
+		let metrics = config.metrics();
+		let bp_future = process_events_async(persister, event_handler, nodes[0].chain_monitor.clone(), nodes[0].node.clone(), nodes[0].p2p_gossip_sync(), nodes[0].peer_manager.clone(), nodes[0].logger.clone(), Some(nodes[0].scorer.clone()), sleeper, config);
+
+		let manager_filepath = get_full_filepath("test_process_events_async_persister_0".to_string(), "manager".to_string());
+		let driver = async {
+			nodes[0].node.force_close_broadcasting_latest_txn(&OutPoint { txid: tx.txid(), index: 0 }.to_channel_id(), &nodes[1].node.get_our_node_id()).unwrap();
+			loop {
+				let closed_event_handled = handled_events.lock().unwrap().iter()
+					.any(|event| if let Event::ChannelClosed { .. } = event { true } else { false });
+				let persisted_manager = std::fs::read(&manager_filepath).ok();
+				let timer_ticked = nodes[0].logger.lines.lock().unwrap().get(&("lightning_background_processor".to_string(), "Calling ChannelManager's timer_tick_occurred".to_string())).is_some();
+				if closed_event_handled && timer_ticked && persisted_manager == Some(nodes[0].node.encode()) {
+					break;
+				}
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+			exit.store(true, Ordering::Release);
+		};
+
+		let (res, ()) = tokio::join!(bp_future, driver);
+		assert!(res.is_ok());
+		assert!(AsyncEventsProvider::get_pending_events(&*nodes[0].node).is_empty());
+
+		// The ChannelManager and NetworkGraph are persisted once more on exit.
+		assert_eq!(std::fs::read(&manager_filepath).unwrap(), nodes[0].node.encode());
+		let graph_filepath = get_full_filepath("test_process_events_async_persister_0".to_string(), "network_graph".to_string());
+		assert_eq!(std::fs::read(&graph_filepath).unwrap(), nodes[0].network_graph.encode());
+
+		// With a time source set, each loop phase is timed.
+		let metrics = metrics.get();
+		assert!(metrics.event_handling.count > 0);
+		assert!(metrics.peer_events.count > 0);
+		assert!(metrics.manager_persistence.count > 0);
+	}
+
+	#[test]
+	fn test_periodic_tasks_and_metrics() {
+		// Test that registered tasks run at their configured interval alongside the configured
+		// timers, and that the time spent in each loop phase is reported.
+		let nodes = create_nodes(2, "test_periodic_tasks_and_metrics".to_string());
+		open_channel!(nodes[0], nodes[1], 100000);
+		let data_dir = nodes[0].persister.get_data_dir();
+		let persister = Arc::new(Persister::new(data_dir));
+		let event_handler = |_: &_| {};
+
+		let task_runs = Arc::new(AtomicUsize::new(0));
+		let task_runs_ref = Arc::clone(&task_runs);
+		let config = BackgroundProcessorConfig::default()
+			.freshness_timer(Duration::from_millis(50))
+			.scorer_persist_timer(Duration::from_millis(50))
+			.register_periodic_task("counter", Duration::from_millis(10), move || { task_runs_ref.fetch_add(1, Ordering::AcqRel); })
+			.register_periodic_task("idle", Duration::from_secs(3600), || panic!("Task shouldn't run yet"));
+		let bg_processor = BackgroundProcessor::start_with_config(persister, event_handler, nodes[0].chain_monitor.clone(), nodes[0].node.clone(), nodes[0].no_gossip_sync(), nodes[0].peer_manager.clone(), nodes[0].logger.clone(), Some(nodes[0].scorer.clone()), config);
+
+		loop {
+			let metrics = bg_processor.metrics();
+			if metrics.periodic_tasks["counter"].count >= 3 && metrics.manager_persistence.count > 0 && metrics.scorer_persistence.count > 0 {
+				assert!(task_runs.load(Ordering::Acquire) >= 3);
+				assert_eq!(metrics.periodic_tasks["idle"], Default::default());
+				assert!(metrics.event_handling.count > 0);
+				assert!(metrics.peer_events.count > 0);
+				assert!(metrics.manager_persistence.max_time >= metrics.manager_persistence.average_time());
+				break;
+			}
+			std::thread::sleep(Duration::from_millis(10));
+		}
+		let log_entries = nodes[0].logger.lines.lock().unwrap();
+		assert!(log_entries.get(&("lightning_background_processor".to_string(), "Calling ChannelManager's timer_tick_occurred".to_string())).is_some());
+		drop(log_entries);
+
 		assert!(bg_processor.stop().is_ok());
 	}
 
@@ -889,6 +1591,24 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn test_peer_policy_persist_error() {
+		// Test that if we encounter an error during peer policy persistence, an error gets returned.
+		let nodes = create_nodes(2, "test_persist_peer_policy_error".to_string());
+		let data_dir = nodes[0].persister.get_data_dir();
+		let persister = Arc::new(Persister::new(data_dir).with_peer_policy_error(std::io::ErrorKind::Other, "test"));
+		let event_handler = |_: &_| {};
+		let bg_processor = BackgroundProcessor::start(persister, event_handler, nodes[0].chain_monitor.clone(), nodes[0].node.clone(), nodes[0].no_gossip_sync(), nodes[0].peer_manager.clone(), nodes[0].logger.clone(), Some(nodes[0].scorer.clone()));
+
+		match bg_processor.stop() {
+			Ok(_) => panic!("Expected error persisting peer policy"),
+			Err(e) => {
+				assert_eq!(e.kind(), std::io::ErrorKind::Other);
+				assert_eq!(e.get_ref().unwrap().to_string(), "test");
+			},
+		}
+	}
+
 	#[test]
 	fn test_background_event_handling() {
 		let mut nodes = create_nodes(2, "test_background_event_handling".to_string());
@@ -963,6 +1683,25 @@ mod tests {
 		assert!(bg_processor.stop().is_ok());
 	}
 
+	#[test]
+	fn test_peer_policy_persistence() {
+		let nodes = create_nodes(2, "test_peer_policy_persistence".to_string());
+		let data_dir = nodes[0].persister.get_data_dir();
+		let persister = Arc::new(Persister::new(data_dir));
+		let event_handler = |_: &_| {};
+		let bg_processor = BackgroundProcessor::start(persister, event_handler, nodes[0].chain_monitor.clone(), nodes[0].node.clone(), nodes[0].no_gossip_sync(), nodes[0].peer_manager.clone(), nodes[0].logger.clone(), Some(nodes[0].scorer.clone()));
+
+		loop {
+			let log_entries = nodes[0].logger.lines.lock().unwrap();
+			let expected_log = "Persisting peer policy".to_string();
+			if log_entries.get(&("lightning_background_processor".to_string(), expected_log)).is_some() {
+				break
+			}
+		}
+
+		assert!(bg_processor.stop().is_ok());
+	}
+
 	#[test]
 	fn test_not_pruning_network_graph_until_graph_sync_completion() {
 		let nodes = create_nodes(2, "test_not_pruning_network_graph_until_graph_sync_completion".to_string());
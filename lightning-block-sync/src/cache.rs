@@ -0,0 +1,461 @@
+//! [`Cache`] implementations with bounded memory usage and persistence across restarts.
+//!
+//! [`UnboundedCache`] retains every header connected to the best chain, which for a long-running
+//! node amounts to an ever-growing map. In practice, only headers near the chain tip are ever
+//! needed to disconnect blocks. [`BoundedCache`] retains only a fixed number of the most recently
+//! connected headers, and [`PersistentCache`] additionally persists them through a [`KVStore`]
+//! so that they are available to [`synchronize_listeners`] after a restart.
+//!
+//! [`UnboundedCache`]: crate::UnboundedCache
+//! [`synchronize_listeners`]: crate::init::synchronize_listeners
+
+use crate::{BlockHeaderData, Cache};
+use crate::poll::{Validate, ValidatedBlockHeader};
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::consensus::encode;
+use bitcoin::hash_types::BlockHash;
+use bitcoin::util::uint::Uint256;
+
+use lightning::io;
+use lightning::ln::msgs::DecodeError;
+use lightning::util::logger::Logger;
+use lightning::util::persist::KVStore;
+use lightning::util::ser::{Readable, Writeable, Writer};
+
+use std::collections::{HashMap, VecDeque};
+use std::ops::Deref;
+
+/// The [`KVStore`] namespace used by [`PersistentCache`] when persisting headers, each of which is
+/// stored under its block hash.
+pub const HEADER_CACHE_PERSISTENCE_NAMESPACE: &str = "header_cache";
+
+/// The default number of headers retained by a [`BoundedCache`], which is well beyond the depth of
+/// any reorg expected in practice.
+pub const DEFAULT_HEADER_CACHE_CAPACITY: usize = 1008;
+
+const SERIALIZATION_VERSION: u8 = 1;
+const MIN_SERIALIZATION_VERSION: u8 = 1;
+
+/// A cache of block headers holding at most a fixed number of headers, evicting the least recently
+/// connected header when full.
+///
+/// As headers are connected in chain order, the headers retained are those closest to the chain
+/// tip, which are the ones needed to disconnect blocks during a reorg.
+pub struct BoundedCache {
+	capacity: usize,
+	headers: HashMap<BlockHash, (u64, ValidatedBlockHeader)>,
+	/// Connection order of the headers, possibly including stale entries for headers that have
+	/// since been disconnected or re-connected. Entries are stale if their sequence number does not
+	/// match that in `headers`.
+	connection_order: VecDeque<(u64, BlockHash)>,
+	next_sequence: u64,
+}
+
+impl BoundedCache {
+	/// Creates an empty cache holding at most `capacity` headers.
+	///
+	/// # Panics
+	///
+	/// If `capacity` is zero.
+	pub fn new(capacity: usize) -> Self {
+		assert!(capacity > 0, "capacity must be non-zero");
+		Self {
+			capacity,
+			headers: HashMap::new(),
+			connection_order: VecDeque::new(),
+			next_sequence: 0,
+		}
+	}
+
+	/// Returns the maximum number of headers held by the cache.
+	pub fn capacity(&self) -> usize {
+		self.capacity
+	}
+
+	/// Returns the number of headers held by the cache.
+	pub fn len(&self) -> usize {
+		self.headers.len()
+	}
+
+	/// Returns whether the cache holds no headers.
+	pub fn is_empty(&self) -> bool {
+		self.headers.is_empty()
+	}
+
+	/// Returns the headers held by the cache, from least to most recently connected.
+	fn iter(&self) -> impl Iterator<Item = &ValidatedBlockHeader> {
+		let headers = &self.headers;
+		self.connection_order.iter().filter_map(move |(sequence, block_hash)| {
+			match headers.get(block_hash) {
+				Some((header_sequence, header)) if header_sequence == sequence => Some(header),
+				_ => None,
+			}
+		})
+	}
+
+	/// Inserts a header with the given sequence number, which must be higher than that of any
+	/// header previously inserted, returning the block hashes of any headers evicted as a result.
+	fn insert(&mut self, sequence: u64, block_hash: BlockHash, block_header: ValidatedBlockHeader) -> Vec<BlockHash> {
+		debug_assert!(sequence >= self.next_sequence);
+		self.next_sequence = sequence + 1;
+		self.headers.insert(block_hash, (sequence, block_header));
+		self.connection_order.push_back((sequence, block_hash));
+		self.evict()
+	}
+
+	fn evict(&mut self) -> Vec<BlockHash> {
+		let mut evicted = Vec::new();
+		while self.headers.len() > self.capacity {
+			let (sequence, block_hash) = self.connection_order.pop_front().unwrap();
+			if self.headers.get(&block_hash).map(|(header_sequence, _)| *header_sequence) == Some(sequence) {
+				self.headers.remove(&block_hash);
+				evicted.push(block_hash);
+			}
+		}
+
+		// Drop stale entries so the connection order remains bounded by the capacity.
+		if self.connection_order.len() > 2 * self.capacity {
+			let headers = &self.headers;
+			self.connection_order.retain(|(sequence, block_hash)| {
+				headers.get(block_hash).map(|(header_sequence, _)| header_sequence) == Some(sequence)
+			});
+		}
+		evicted
+	}
+}
+
+impl Default for BoundedCache {
+	fn default() -> Self {
+		Self::new(DEFAULT_HEADER_CACHE_CAPACITY)
+	}
+}
+
+impl Cache for BoundedCache {
+	fn look_up(&self, block_hash: &BlockHash) -> Option<&ValidatedBlockHeader> {
+		self.headers.get(block_hash).map(|(_, header)| header)
+	}
+
+	fn block_connected(&mut self, block_hash: BlockHash, block_header: ValidatedBlockHeader) {
+		self.insert(self.next_sequence, block_hash, block_header);
+	}
+
+	fn block_disconnected(&mut self, block_hash: &BlockHash) -> Option<ValidatedBlockHeader> {
+		self.headers.remove(block_hash).map(|(_, header)| header)
+	}
+}
+
+fn write_header<W: Writer>(header: &ValidatedBlockHeader, writer: &mut W) -> Result<(), io::Error> {
+	writer.write_all(&encode::serialize(&header.header))?;
+	header.height.write(writer)?;
+	writer.write_all(&header.chainwork.to_be_bytes())
+}
+
+fn read_header<R: io::Read>(reader: &mut R) -> Result<(BlockHash, ValidatedBlockHeader), DecodeError> {
+	let mut header_bytes = [0; 80];
+	reader.read_exact(&mut header_bytes)?;
+	let header: BlockHeader = encode::deserialize(&header_bytes)
+		.map_err(|_| DecodeError::InvalidValue)?;
+	let height: u32 = Readable::read(reader)?;
+	let mut chainwork_bytes = [0; 32];
+	reader.read_exact(&mut chainwork_bytes)?;
+	let chainwork = Uint256::from_be_bytes(chainwork_bytes);
+
+	let block_hash = header.block_hash();
+	let header = BlockHeaderData { header, height, chainwork }
+		.validate(block_hash)
+		.map_err(|_| DecodeError::InvalidValue)?;
+	Ok((block_hash, header))
+}
+
+impl Writeable for BoundedCache {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		writer.write_all(&[SERIALIZATION_VERSION, MIN_SERIALIZATION_VERSION])?;
+		(self.capacity as u64).write(writer)?;
+		(self.headers.len() as u64).write(writer)?;
+		for header in self.iter() {
+			write_header(header, writer)?;
+		}
+		Ok(())
+	}
+}
+
+impl Readable for BoundedCache {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let _ver: u8 = Readable::read(reader)?;
+		let min_ver: u8 = Readable::read(reader)?;
+		if min_ver > SERIALIZATION_VERSION {
+			return Err(DecodeError::UnknownVersion);
+		}
+
+		let capacity: u64 = Readable::read(reader)?;
+		if capacity == 0 {
+			return Err(DecodeError::InvalidValue);
+		}
+		let mut cache = BoundedCache::new(capacity as usize);
+
+		let header_count: u64 = Readable::read(reader)?;
+		for _ in 0..header_count {
+			let (block_hash, header) = read_header(reader)?;
+			cache.block_connected(block_hash, header);
+		}
+		Ok(cache)
+	}
+}
+
+/// A header persisted by [`PersistentCache`], along with its position in the connection order.
+struct PersistedHeader<'a> {
+	sequence: u64,
+	header: &'a ValidatedBlockHeader,
+}
+
+impl<'a> Writeable for PersistedHeader<'a> {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		writer.write_all(&[SERIALIZATION_VERSION, MIN_SERIALIZATION_VERSION])?;
+		self.sequence.write(writer)?;
+		write_header(self.header, writer)
+	}
+}
+
+fn read_persisted_header<R: io::Read>(reader: &mut R) -> Result<(u64, BlockHash, ValidatedBlockHeader), DecodeError> {
+	let _ver: u8 = Readable::read(reader)?;
+	let min_ver: u8 = Readable::read(reader)?;
+	if min_ver > SERIALIZATION_VERSION {
+		return Err(DecodeError::UnknownVersion);
+	}
+	let sequence: u64 = Readable::read(reader)?;
+	let (block_hash, header) = read_header(reader)?;
+	Ok((sequence, block_hash, header))
+}
+
+/// A [`BoundedCache`] persisted through a [`KVStore`] in the
+/// [`HEADER_CACHE_PERSISTENCE_NAMESPACE`].
+///
+/// Each header is stored under its own key, so connecting a block writes only the new header and
+/// removes any header evicted as a result, while disconnecting a block removes only its header.
+///
+/// On restart, create the cache via [`PersistentCache::new`] to read back the previously persisted
+/// headers before calling [`synchronize_listeners`]. Failing to persist is logged but otherwise
+/// not fatal, as headers missing from the cache are fetched from the block source when needed.
+///
+/// [`synchronize_listeners`]: crate::init::synchronize_listeners
+pub struct PersistentCache<K: Deref, L: Deref> where K::Target: KVStore, L::Target: Logger {
+	cache: BoundedCache,
+	kv_store: K,
+	logger: L,
+}
+
+impl<K: Deref, L: Deref> PersistentCache<K, L> where K::Target: KVStore, L::Target: Logger {
+	/// Creates a cache holding at most `capacity` headers, initialized with the headers previously
+	/// persisted in `kv_store`.
+	///
+	/// Persisted headers beyond `capacity`, e.g. if the capacity was lowered, are evicted and
+	/// removed from `kv_store`.
+	///
+	/// # Panics
+	///
+	/// If `capacity` is zero.
+	pub fn new(kv_store: K, capacity: usize, logger: L) -> Result<Self, io::Error> {
+		let mut persisted_headers = Vec::new();
+		for key in kv_store.list(HEADER_CACHE_PERSISTENCE_NAMESPACE)? {
+			let buf = kv_store.read(HEADER_CACHE_PERSISTENCE_NAMESPACE, &key)?;
+			let persisted_header = read_persisted_header(&mut &buf[..])
+				.map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Failed to read persisted block header"))?;
+			persisted_headers.push(persisted_header);
+		}
+		persisted_headers.sort_unstable_by_key(|(sequence, _, _)| *sequence);
+
+		let mut persistent_cache = Self { cache: BoundedCache::new(capacity), kv_store, logger };
+		for (sequence, block_hash, header) in persisted_headers {
+			for evicted_block_hash in persistent_cache.cache.insert(sequence, block_hash, header) {
+				persistent_cache.remove_persisted(&evicted_block_hash);
+			}
+		}
+		Ok(persistent_cache)
+	}
+
+	/// Returns the underlying cache.
+	pub fn into_inner(self) -> BoundedCache {
+		self.cache
+	}
+
+	fn persist(&self, sequence: u64, block_hash: &BlockHash, header: &ValidatedBlockHeader) {
+		let buf = PersistedHeader { sequence, header }.encode();
+		if let Err(e) = self.kv_store.write(HEADER_CACHE_PERSISTENCE_NAMESPACE, &block_hash.to_string(), &buf) {
+			log_error!(self.logger, "Failed to persist block header {}: {}", block_hash, e);
+		}
+	}
+
+	fn remove_persisted(&self, block_hash: &BlockHash) {
+		if let Err(e) = self.kv_store.remove(HEADER_CACHE_PERSISTENCE_NAMESPACE, &block_hash.to_string()) {
+			log_error!(self.logger, "Failed to remove persisted block header {}: {}", block_hash, e);
+		}
+	}
+}
+
+impl<K: Deref, L: Deref> Cache for PersistentCache<K, L> where K::Target: KVStore, L::Target: Logger {
+	fn look_up(&self, block_hash: &BlockHash) -> Option<&ValidatedBlockHeader> {
+		self.cache.look_up(block_hash)
+	}
+
+	fn block_connected(&mut self, block_hash: BlockHash, block_header: ValidatedBlockHeader) {
+		let sequence = self.cache.next_sequence;
+		self.persist(sequence, &block_hash, &block_header);
+		for evicted_block_hash in self.cache.insert(sequence, block_hash, block_header) {
+			self.remove_persisted(&evicted_block_hash);
+		}
+	}
+
+	fn block_disconnected(&mut self, block_hash: &BlockHash) -> Option<ValidatedBlockHeader> {
+		let block_header = self.cache.block_disconnected(block_hash);
+		if block_header.is_some() {
+			self.remove_persisted(block_hash);
+		}
+		block_header
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::Blockchain;
+
+	use lightning::util::test_utils::{TestLogger, TestStore};
+
+	use std::sync::atomic::Ordering;
+
+	fn connect(cache: &mut impl Cache, chain: &Blockchain, heights: std::ops::RangeInclusive<usize>) {
+		for height in heights {
+			let header = chain.at_height(height);
+			cache.block_connected(header.block_hash, header);
+		}
+	}
+
+	#[test]
+	fn evicts_least_recently_connected_headers() {
+		let chain = Blockchain::default().with_height(5);
+		let mut cache = BoundedCache::new(3);
+		connect(&mut cache, &chain, 0..=5);
+
+		assert_eq!(cache.len(), 3);
+		for height in 0..=2 {
+			assert!(cache.look_up(&chain.at_height(height).block_hash).is_none());
+		}
+		for height in 3..=5 {
+			assert_eq!(cache.look_up(&chain.at_height(height).block_hash), Some(&chain.at_height(height)));
+		}
+	}
+
+	#[test]
+	fn retains_headers_across_reorg() {
+		let main_chain = Blockchain::default().with_height(4);
+		let fork_chain = main_chain.fork_at_height(2);
+		let mut cache = BoundedCache::new(4);
+		connect(&mut cache, &main_chain, 0..=4);
+
+		for height in (3..=4).rev() {
+			let block_hash = main_chain.at_height(height).block_hash;
+			assert_eq!(cache.block_disconnected(&block_hash), Some(main_chain.at_height(height)));
+		}
+		assert_eq!(cache.len(), 2);
+
+		connect(&mut cache, &fork_chain, 3..=4);
+		assert_eq!(cache.len(), 4);
+		assert!(cache.look_up(&main_chain.at_height(0).block_hash).is_none());
+		for height in 1..=4 {
+			assert_eq!(cache.look_up(&fork_chain.at_height(height).block_hash), Some(&fork_chain.at_height(height)));
+		}
+		assert!(cache.connection_order.len() <= 2 * cache.capacity());
+	}
+
+	#[test]
+	fn bounds_connection_order() {
+		let chain = Blockchain::default().with_height(1);
+		let mut cache = BoundedCache::new(2);
+		for _ in 0..10 {
+			connect(&mut cache, &chain, 0..=1);
+			assert!(cache.block_disconnected(&chain.at_height(1).block_hash).is_some());
+		}
+		assert_eq!(cache.len(), 1);
+		assert!(cache.connection_order.len() <= 2 * cache.capacity());
+	}
+
+	#[test]
+	fn serializes_headers_in_connection_order() {
+		let chain = Blockchain::default().with_height(6);
+		let mut cache = BoundedCache::new(4);
+		connect(&mut cache, &chain, 0..=5);
+
+		let mut read_cache = BoundedCache::read(&mut &cache.encode()[..]).unwrap();
+		assert_eq!(read_cache.capacity(), 4);
+		assert_eq!(read_cache.len(), 4);
+		for height in 2..=5 {
+			assert_eq!(read_cache.look_up(&chain.at_height(height).block_hash), Some(&chain.at_height(height)));
+		}
+
+		// Eviction order should be preserved.
+		connect(&mut read_cache, &chain, 6..=6);
+		assert!(read_cache.look_up(&chain.at_height(2).block_hash).is_none());
+		assert!(read_cache.look_up(&chain.at_height(3).block_hash).is_some());
+	}
+
+	#[test]
+	fn fails_reading_invalid_header() {
+		let chain = Blockchain::default().with_height(1);
+		let mut cache = BoundedCache::new(2);
+		connect(&mut cache, &chain, 0..=1);
+
+		let mut encoded_cache = cache.encode();
+		// Tamper with the nonce of the genesis block, invalidating its proof of work.
+		encoded_cache[2 + 8 + 8 + 76] ^= 0xff;
+		match BoundedCache::read(&mut &encoded_cache[..]) {
+			Err(e) => assert_eq!(e, DecodeError::InvalidValue),
+			Ok(_) => panic!("Expected error"),
+		}
+	}
+
+	#[test]
+	fn persists_only_changed_headers() {
+		let chain = Blockchain::default().with_height(6);
+		let store = TestStore::new();
+		let logger = TestLogger::new();
+		let mut cache = PersistentCache::new(&store, 3, &logger).unwrap();
+		let persisted_count = || store.list(HEADER_CACHE_PERSISTENCE_NAMESPACE).unwrap().len();
+
+		// Each connected block writes its header once, removing any evicted header.
+		connect(&mut cache, &chain, 0..=5);
+		assert_eq!(store.writes.load(Ordering::Acquire), 6);
+		assert_eq!(persisted_count(), 3);
+
+		assert_eq!(cache.block_disconnected(&chain.at_height(5).block_hash), Some(chain.at_height(5)));
+		assert_eq!(store.writes.load(Ordering::Acquire), 6);
+		assert_eq!(persisted_count(), 2);
+
+		// Headers are read back in connection order, so eviction continues where it left off.
+		let mut cache = PersistentCache::new(&store, 3, &logger).unwrap();
+		assert_eq!(cache.look_up(&chain.at_height(3).block_hash), Some(&chain.at_height(3)));
+		assert_eq!(cache.look_up(&chain.at_height(4).block_hash), Some(&chain.at_height(4)));
+		connect(&mut cache, &chain, 5..=6);
+		assert!(cache.look_up(&chain.at_height(3).block_hash).is_none());
+		assert_eq!(persisted_count(), 3);
+
+		// Reading with a lower capacity evicts the least recently connected headers.
+		let cache = PersistentCache::new(&store, 1, &logger).unwrap();
+		assert_eq!(cache.look_up(&chain.tip().block_hash), Some(&chain.tip()));
+		assert_eq!(persisted_count(), 1);
+	}
+
+	#[test]
+	fn logs_persistence_failures() {
+		let chain = Blockchain::default().with_height(1);
+		let store = TestStore::new();
+		let logger = TestLogger::new();
+		let mut cache = PersistentCache::new(&store, 3, &logger).unwrap();
+
+		store.fail_writes.store(true, Ordering::Release);
+		connect(&mut cache, &chain, 0..=1);
+		assert_eq!(cache.look_up(&chain.tip().block_hash), Some(&chain.tip()));
+		logger.assert_log_contains("lightning_block_sync::cache".to_string(),
+			"Failed to persist block header".to_string(), 2);
+	}
+}
@@ -181,6 +181,23 @@ impl TryInto<Txid> for JsonResponse {
 	}
 }
 
+/// Converts a JSON value into a list of txids, e.g., as returned by `getrawmempool`.
+impl TryInto<Vec<Txid>> for JsonResponse {
+	type Error = std::io::Error;
+	fn try_into(self) -> std::io::Result<Vec<Txid>> {
+		let txids = match self.0.as_array() {
+			None => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "expected JSON array")),
+			Some(txids) => txids,
+		};
+
+		txids.iter().map(|txid| match txid.as_str() {
+			None => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "expected JSON string")),
+			Some(hex_data) => Txid::from_hex(hex_data)
+				.map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid txid")),
+		}).collect()
+	}
+}
+
 /// Converts a JSON value into a transaction. WATCH OUT! this cannot be used for zero-input transactions
 /// (e.g. createrawtransaction). See <https://github.com/rust-bitcoin/rust-bitcoincore-rpc/issues/197>
 impl TryInto<Transaction> for JsonResponse {
@@ -606,6 +623,40 @@ pub(crate) mod tests {
 		}
 	}
 
+	#[test]
+	fn into_txids_from_json_response_with_unexpected_type() {
+		let response = JsonResponse(serde_json::json!("foo"));
+		match TryInto::<Vec<Txid>>::try_into(response) {
+			Err(e) => {
+				assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+				assert_eq!(e.get_ref().unwrap().to_string(), "expected JSON array");
+			}
+			Ok(_) => panic!("Expected error"),
+		}
+	}
+
+	#[test]
+	fn into_txids_from_json_response_with_invalid_txid() {
+		let response = JsonResponse(serde_json::json!(["abcd"]));
+		match TryInto::<Vec<Txid>>::try_into(response) {
+			Err(e) => {
+				assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+				assert_eq!(e.get_ref().unwrap().to_string(), "invalid txid");
+			}
+			Ok(_) => panic!("Expected error"),
+		}
+	}
+
+	#[test]
+	fn into_txids_from_json_response_with_valid_txids() {
+		let target_txids = vec![Txid::from_slice(&[1; 32]).unwrap(), Txid::from_slice(&[2; 32]).unwrap()];
+		let response = JsonResponse(serde_json::json!([target_txids[0].to_hex(), target_txids[1].to_hex()]));
+		match TryInto::<Vec<Txid>>::try_into(response) {
+			Err(e) => panic!("Unexpected error: {:?}", e),
+			Ok(txids) => assert_eq!(txids, target_txids),
+		}
+	}
+
 	// TryInto<Transaction> can be used in two ways, first with plain hex response where data is
 	// the hex encoded transaction (e.g. as a result of getrawtransaction) or as a JSON object
 	// where the hex encoded transaction can be found in the hex field of the object (if present)
@@ -0,0 +1,1741 @@
+//! Simple Electrum client implementation which keeps [`Confirm`] implementations in sync with the
+//! chain using an Electrum server.
+//!
+//! Unlike the [`BlockSource`]-based clients, [`ElectrumSyncClient`] only fetches chain data of
+//! interest. It implements [`Filter`] by subscribing to the script hash of any script registered
+//! via [`Filter::register_tx`] or [`Filter::register_output`]. When syncing, it fetches the history
+//! of any script whose status changed, verifies merkle proofs for confirmed transactions of
+//! interest against the corresponding block headers, and checks transactions returned by
+//! [`Confirm::get_relevant_txids`] for having been reorganized out of the chain. Block headers are
+//! checked to attain their target and to build on each other up to the tip before being used.
+//!
+//! [`BlockSource`]: crate::BlockSource
+
+use crate::{BlockSourceError, BlockSourceResult};
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{OutPoint, Transaction};
+use bitcoin::consensus::encode;
+use bitcoin::hash_types::{BlockHash, Txid};
+use bitcoin::hashes::{Hash, HashEngine};
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::sha256d;
+use bitcoin::network::constants::Network;
+
+use lightning::chain::{Confirm, Filter, WatchedOutput};
+use lightning::chain::channelmonitor::ANTI_REORG_DELAY;
+
+use futures::lock::Mutex;
+
+use serde_json;
+
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+#[cfg(feature = "tokio")]
+use tokio::io::BufReader;
+#[cfg(feature = "tokio")]
+use tokio::net::TcpStream;
+
+#[cfg(not(feature = "tokio"))]
+use std::io::{BufRead, BufReader, Read, Write};
+#[cfg(not(feature = "tokio"))]
+use std::net::TcpStream;
+
+/// Timeout for operations on TCP streams.
+const TCP_STREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Timeout for reading a response. Electrum servers may take a while to respond to history requests
+/// for scripts with many transactions, so this is more lenient than [`TCP_STREAM_TIMEOUT`].
+const TCP_STREAM_RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Maximum Electrum message size in bytes. Enough for a hex-encoded transaction in JSON format or a
+/// script history with many entries.
+const MAX_ELECTRUM_MESSAGE_SIZE: usize = 2 * 4_000_000 + 32_000;
+
+/// Maximum number of headers requested at once, which is what servers return at most.
+const MAX_HEADERS_PER_REQUEST: u32 = 2016;
+
+/// Number of headers below the tip kept to find where a reorganization forks off the known chain.
+/// The chain is started anew at the fork for any deeper reorganization.
+const HEADER_CHAIN_REORG_WINDOW: u32 = 144;
+
+/// Client name sent to the server during version negotiation.
+const CLIENT_NAME: &str = "lightning-block-sync";
+
+/// Electrum protocol version used when negotiating with the server.
+const PROTOCOL_VERSION: &str = "1.4";
+
+/// Client for calling methods using the Electrum protocol, a line-delimited JSON-RPC protocol over
+/// TCP.
+///
+/// Any notifications received from the server while awaiting a response are queued and may be
+/// retrieved using [`ElectrumClient::take_notifications`].
+pub struct ElectrumClient {
+	address: SocketAddr,
+	reader: BufReader<TcpStream>,
+	id: u64,
+	version_negotiated: bool,
+	reconnected: bool,
+	notifications: VecDeque<ElectrumNotification>,
+}
+
+/// A notification pushed by an Electrum server for a subscription.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ElectrumNotification {
+	/// A new chain tip was connected, given as its header and height.
+	Header(BlockHeader, u32),
+
+	/// The status of a subscribed script hash changed. The status is `None` if the script has no
+	/// history.
+	ScriptHashStatus(sha256::Hash, Option<String>),
+}
+
+impl ElectrumClient {
+	/// Opens a connection to an Electrum server.
+	pub fn connect<E: ToSocketAddrs>(endpoint: E) -> std::io::Result<Self> {
+		let address = match endpoint.to_socket_addrs()?.next() {
+			None => {
+				return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "could not resolve to any addresses"));
+			},
+			Some(address) => address,
+		};
+		let stream = std::net::TcpStream::connect_timeout(&address, TCP_STREAM_TIMEOUT)?;
+		stream.set_read_timeout(Some(TCP_STREAM_RESPONSE_TIMEOUT))?;
+		stream.set_write_timeout(Some(TCP_STREAM_TIMEOUT))?;
+
+		#[cfg(feature = "tokio")]
+		let stream = {
+			stream.set_nonblocking(true)?;
+			TcpStream::from_std(stream)?
+		};
+
+		Ok(Self {
+			address,
+			reader: BufReader::new(stream),
+			id: 0,
+			version_negotiated: false,
+			reconnected: false,
+			notifications: VecDeque::new(),
+		})
+	}
+
+	/// Calls a method with the given parameters, returning the JSON result. Attempts to reconnect
+	/// and retry if the connection has been closed.
+	///
+	/// Errors returned by the server are given as an [`ElectrumError`] wrapped in an `std::io::Error`
+	/// of kind `Other`.
+	pub async fn call_method(&mut self, method: &str, params: &[serde_json::Value]) -> std::io::Result<serde_json::Value> {
+		let response = match self.send_request(method, params).await {
+			Ok(response) => response,
+			Err(_) => {
+				// Reconnect and retry on fail. Any subscriptions are lost when reconnecting, which
+				// is indicated by `take_reconnected` so they can be made again.
+				#[cfg(feature = "tokio")]
+				tokio::time::sleep(Duration::from_millis(100)).await;
+				#[cfg(not(feature = "tokio"))]
+				std::thread::sleep(Duration::from_millis(100));
+				let notifications = std::mem::take(&mut self.notifications);
+				*self = Self::connect(self.address)?;
+				self.notifications = notifications;
+				self.reconnected = true;
+				self.send_request(method, params).await?
+			},
+		};
+		Self::into_result(response)
+	}
+
+	/// Returns any notifications received since the last call.
+	pub fn take_notifications(&mut self) -> VecDeque<ElectrumNotification> {
+		std::mem::take(&mut self.notifications)
+	}
+
+	/// Returns whether the client had to reconnect since the last call, in which case any
+	/// subscriptions need to be made again.
+	pub fn take_reconnected(&mut self) -> bool {
+		std::mem::replace(&mut self.reconnected, false)
+	}
+
+	/// Subscribes to new chain tips, returning the current tip's header and height.
+	pub async fn subscribe_headers(&mut self) -> std::io::Result<(BlockHeader, u32)> {
+		let response = self.call_method("blockchain.headers.subscribe", &[]).await?;
+		parse_header_notification(&response)
+	}
+
+	/// Returns the header of the block at the given height on the server's best chain.
+	pub async fn get_header(&mut self, height: u32) -> std::io::Result<BlockHeader> {
+		let response = self.call_method("blockchain.block.header", &[serde_json::json!(height)]).await?;
+		match response.as_str() {
+			None => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "expected JSON string")),
+			Some(hex_data) => deserialize_hex(hex_data, "invalid header data"),
+		}
+	}
+
+	/// Returns the headers of up to `count` consecutive blocks on the server's best chain, starting
+	/// at the given height. Servers may return fewer headers than requested.
+	pub async fn get_headers(&mut self, start_height: u32, count: u32) -> std::io::Result<Vec<BlockHeader>> {
+		let response = self.call_method(
+			"blockchain.block.headers", &[serde_json::json!(start_height), serde_json::json!(count)]).await?;
+		let data = match response["hex"].as_str().map(Vec::<u8>::from_hex) {
+			Some(Ok(data)) => data,
+			_ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid headers data")),
+		};
+		if data.len() % 80 != 0 {
+			return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid headers data"));
+		}
+
+		let mut headers = Vec::with_capacity(data.len() / 80);
+		for header_data in data.chunks(80) {
+			match encode::deserialize(header_data) {
+				Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid header data")),
+				Ok(header) => headers.push(header),
+			}
+		}
+		Ok(headers)
+	}
+
+	/// Subscribes to status changes of the given script hash, returning its current status.
+	pub async fn subscribe_script_hash(&mut self, script_hash: &sha256::Hash) -> std::io::Result<Option<String>> {
+		let response = self.call_method(
+			"blockchain.scripthash.subscribe", &[serde_json::json!(script_hash_to_hex(script_hash))]).await?;
+		parse_script_hash_status(&response)
+	}
+
+	/// Stops notifying of status changes of the given script hash, returning whether it was
+	/// subscribed to.
+	pub async fn unsubscribe_script_hash(&mut self, script_hash: &sha256::Hash) -> std::io::Result<bool> {
+		let response = self.call_method(
+			"blockchain.scripthash.unsubscribe", &[serde_json::json!(script_hash_to_hex(script_hash))]).await?;
+		match response.as_bool() {
+			None => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "expected JSON boolean")),
+			Some(subscribed) => Ok(subscribed),
+		}
+	}
+
+	/// Returns the confirmed and unconfirmed history of the given script hash as pairs of txid and
+	/// height, where a non-positive height indicates an unconfirmed transaction.
+	pub async fn get_history(&mut self, script_hash: &sha256::Hash) -> std::io::Result<Vec<(Txid, i64)>> {
+		let response = self.call_method(
+			"blockchain.scripthash.get_history", &[serde_json::json!(script_hash_to_hex(script_hash))]).await?;
+		let entries = match response.as_array() {
+			None => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "expected JSON array")),
+			Some(entries) => entries,
+		};
+
+		let mut history = Vec::with_capacity(entries.len());
+		for entry in entries {
+			let txid = match entry["tx_hash"].as_str().map(Txid::from_hex) {
+				Some(Ok(txid)) => txid,
+				_ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid txid")),
+			};
+			let height = match entry["height"].as_i64() {
+				None => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid height")),
+				Some(height) => height,
+			};
+			history.push((txid, height));
+		}
+		Ok(history)
+	}
+
+	/// Returns the transaction with the given txid.
+	pub async fn get_transaction(&mut self, txid: &Txid) -> std::io::Result<Transaction> {
+		let response = self.call_method("blockchain.transaction.get", &[serde_json::json!(txid.to_hex())]).await?;
+		match response.as_str() {
+			None => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "expected JSON string")),
+			Some(hex_data) => deserialize_hex(hex_data, "invalid transaction"),
+		}
+	}
+
+	/// Returns the merkle branch and position of the transaction with the given txid in the block at
+	/// the given height.
+	pub async fn get_merkle(&mut self, txid: &Txid, height: u32) -> std::io::Result<MerkleProof> {
+		let response = self.call_method(
+			"blockchain.transaction.get_merkle", &[serde_json::json!(txid.to_hex()), serde_json::json!(height)]).await?;
+		if !response.is_object() {
+			return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "expected JSON object"));
+		}
+
+		let pos = match response["pos"].as_u64() {
+			None => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid position")),
+			Some(pos) => pos as usize,
+		};
+		let branch = match response["merkle"].as_array() {
+			None => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "expected JSON array")),
+			Some(branch) => branch,
+		};
+		let mut merkle = Vec::with_capacity(branch.len());
+		for hash in branch {
+			match hash.as_str().map(sha256d::Hash::from_hex) {
+				Some(Ok(hash)) => merkle.push(hash),
+				_ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid merkle branch")),
+			}
+		}
+		Ok(MerkleProof { merkle, pos })
+	}
+
+	/// Sends a request, negotiating the protocol version first if not yet done, and reads the
+	/// response, returning it in full.
+	async fn send_request(&mut self, method: &str, params: &[serde_json::Value]) -> std::io::Result<serde_json::Value> {
+		if !self.version_negotiated {
+			let version_params = [serde_json::json!(CLIENT_NAME), serde_json::json!(PROTOCOL_VERSION)];
+			let response = self.exchange("server.version", &version_params).await?;
+			Self::into_result(response)?;
+			self.version_negotiated = true;
+		}
+		self.exchange(method, params).await
+	}
+
+	/// Writes a request message and reads messages until the corresponding response, queueing any
+	/// notifications read beforehand.
+	async fn exchange(&mut self, method: &str, params: &[serde_json::Value]) -> std::io::Result<serde_json::Value> {
+		let id = self.id;
+		self.id += 1;
+		let request = serde_json::json!({
+			"jsonrpc": "2.0",
+			"method": method,
+			"params": params,
+			"id": id,
+		});
+		self.write_message(&(request.to_string() + "\n")).await?;
+
+		loop {
+			let message = self.read_message().await?;
+			if message["id"].as_u64() == Some(id) {
+				return Ok(message);
+			}
+
+			match message["method"].as_str() {
+				Some("blockchain.headers.subscribe") => {
+					let (header, height) = parse_header_notification(&message["params"][0])?;
+					self.notifications.push_back(ElectrumNotification::Header(header, height));
+				},
+				Some("blockchain.scripthash.subscribe") => {
+					let script_hash = match message["params"][0].as_str().map(script_hash_from_hex) {
+						Some(Ok(script_hash)) => script_hash,
+						_ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid script hash")),
+					};
+					let status = parse_script_hash_status(&message["params"][1])?;
+					self.notifications.push_back(ElectrumNotification::ScriptHashStatus(script_hash, status));
+				},
+				// Ignore responses to earlier requests and unknown notifications.
+				_ => {},
+			}
+		}
+	}
+
+	/// Writes a line-delimited message.
+	async fn write_message(&mut self, message: &str) -> std::io::Result<()> {
+		#[cfg(feature = "tokio")]
+		{
+			let stream = self.reader.get_mut();
+			stream.write_all(message.as_bytes()).await?;
+			stream.flush().await
+		}
+		#[cfg(not(feature = "tokio"))]
+		{
+			let stream = self.reader.get_mut();
+			stream.write_all(message.as_bytes())?;
+			stream.flush()
+		}
+	}
+
+	/// Reads a line-delimited message as a JSON value.
+	async fn read_message(&mut self) -> std::io::Result<serde_json::Value> {
+		#[cfg(feature = "tokio")]
+		let reader = &mut self.reader;
+		#[cfg(not(feature = "tokio"))]
+		let reader = Read::by_ref(&mut self.reader);
+
+		let mut limited_reader = reader.take(MAX_ELECTRUM_MESSAGE_SIZE as u64);
+		let mut line = String::new();
+		#[cfg(feature = "tokio")]
+		let bytes_read = limited_reader.read_line(&mut line).await?;
+		#[cfg(not(feature = "tokio"))]
+		let bytes_read = limited_reader.read_line(&mut line)?;
+
+		if bytes_read == 0 {
+			return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "no message"));
+		}
+		if !line.ends_with('\n') {
+			return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "out of range"));
+		}
+		Ok(serde_json::from_str(&line)?)
+	}
+
+	/// Converts a response into its result or into an [`ElectrumError`] if the server returned an
+	/// error.
+	fn into_result(mut response: serde_json::Value) -> std::io::Result<serde_json::Value> {
+		if !response.is_object() {
+			return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "expected JSON object"));
+		}
+
+		let error = &response["error"];
+		if !error.is_null() {
+			let error = ElectrumError {
+				code: error["code"].as_i64(),
+				message: match error {
+					serde_json::Value::String(message) => message.clone(),
+					_ => error["message"].as_str().unwrap_or("unknown error").to_string(),
+				},
+			};
+			return Err(std::io::Error::new(std::io::ErrorKind::Other, error));
+		}
+
+		Ok(response["result"].take())
+	}
+}
+
+/// Error returned by an Electrum server in response to a request.
+#[derive(Debug)]
+pub struct ElectrumError {
+	/// The error code, if any was given.
+	pub code: Option<i64>,
+
+	/// The error message.
+	pub message: String,
+}
+
+impl std::error::Error for ElectrumError {}
+
+impl fmt::Display for ElectrumError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.code {
+			None => write!(f, "{}", self.message),
+			Some(code) => write!(f, "{} (code {})", self.message, code),
+		}
+	}
+}
+
+/// A merkle branch proving the inclusion of a transaction in a block.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MerkleProof {
+	/// The hashes needed to compute the merkle root, ordered from leaf to root.
+	pub merkle: Vec<sha256d::Hash>,
+
+	/// The position of the transaction in the block.
+	pub pos: usize,
+}
+
+impl MerkleProof {
+	/// Returns whether the proof shows that the transaction with the given txid is included in the
+	/// block with the given header.
+	pub fn verify(&self, txid: &Txid, header: &BlockHeader) -> bool {
+		let mut hash = txid.as_hash();
+		for (level, sibling) in self.merkle.iter().enumerate() {
+			let mut engine = sha256d::Hash::engine();
+			if (self.pos >> level) & 1 == 0 {
+				engine.input(&hash[..]);
+				engine.input(&sibling[..]);
+			} else {
+				engine.input(&sibling[..]);
+				engine.input(&hash[..]);
+			}
+			hash = sha256d::Hash::from_engine(engine);
+		}
+		hash == header.merkle_root.as_hash()
+	}
+}
+
+/// Returns the script hash used by the Electrum protocol to identify a script.
+pub fn script_hash(script: &Script) -> sha256::Hash {
+	sha256::Hash::hash(script.as_bytes())
+}
+
+/// Encodes a script hash in hex as expected by Electrum servers, which is in reverse byte order.
+fn script_hash_to_hex(script_hash: &sha256::Hash) -> String {
+	let mut bytes = script_hash.into_inner();
+	bytes.reverse();
+	bytes.to_hex()
+}
+
+fn script_hash_from_hex(hex_data: &str) -> Result<sha256::Hash, bitcoin::hashes::hex::Error> {
+	let mut bytes = <[u8; 32]>::from_hex(hex_data)?;
+	bytes.reverse();
+	Ok(sha256::Hash::from_inner(bytes))
+}
+
+fn deserialize_hex<T: encode::Decodable>(hex_data: &str, error: &'static str) -> std::io::Result<T> {
+	match Vec::<u8>::from_hex(hex_data) {
+		Err(_) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid hex data")),
+		Ok(data) => match encode::deserialize(&data) {
+			Err(_) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, error)),
+			Ok(value) => Ok(value),
+		},
+	}
+}
+
+fn parse_header_notification(value: &serde_json::Value) -> std::io::Result<(BlockHeader, u32)> {
+	if !value.is_object() {
+		return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "expected JSON object"));
+	}
+
+	let height = match value["height"].as_u64() {
+		Some(height) if height <= u32::MAX as u64 => height as u32,
+		_ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid height")),
+	};
+	let header = match value["hex"].as_str() {
+		None => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "expected JSON string")),
+		Some(hex_data) => deserialize_hex(hex_data, "invalid header data")?,
+	};
+	Ok((header, height))
+}
+
+fn parse_script_hash_status(value: &serde_json::Value) -> std::io::Result<Option<String>> {
+	match value {
+		serde_json::Value::Null => Ok(None),
+		serde_json::Value::String(status) => Ok(Some(status.clone())),
+		_ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid script hash status")),
+	}
+}
+
+/// Converts an error from the Electrum client into a [`BlockSourceError`], treating errors returned
+/// by the server or caused by invalid data as persistent.
+fn into_block_source_error(e: std::io::Error) -> BlockSourceError {
+	match e.kind() {
+		std::io::ErrorKind::InvalidData => BlockSourceError::persistent(e),
+		std::io::ErrorKind::InvalidInput => BlockSourceError::persistent(e),
+		std::io::ErrorKind::Other if e.get_ref().map_or(false, |e| e.is::<ElectrumError>()) => {
+			BlockSourceError::persistent(e)
+		},
+		_ => BlockSourceError::transient(e),
+	}
+}
+
+/// A transaction which was given to [`Confirm::transactions_confirmed`], along with the block it
+/// was confirmed in.
+#[derive(Clone, Copy)]
+struct ConfirmedTx {
+	block_hash: BlockHash,
+	height: u32,
+}
+
+/// A confirmed transaction fetched during an ongoing sync, which [`Filter::register_output`] may
+/// return as the in-block spend of a newly watched output.
+struct BlockSpend {
+	block_hash: BlockHash,
+	height: u32,
+	pos: usize,
+	tx: Transaction,
+}
+
+/// A spend returned by [`Filter::register_output`], which was given to the [`Confirm`]
+/// implementation registering the output rather than by the sync client.
+struct ReturnedSpend {
+	outpoint: OutPoint,
+	script_hash: sha256::Hash,
+	txid: Txid,
+	confirmed_tx: ConfirmedTx,
+}
+
+/// Transactions and outputs registered via [`Filter`] but not yet subscribed to.
+#[derive(Default)]
+struct FilterQueue {
+	transactions: Vec<(Txid, Script)>,
+	outputs: Vec<WatchedOutput>,
+}
+
+impl FilterQueue {
+	fn is_empty(&self) -> bool {
+		self.transactions.is_empty() && self.outputs.is_empty()
+	}
+}
+
+/// An output registered via [`Filter::register_output`].
+struct WatchedOutputState {
+	/// The script hash subscribed to for the output.
+	script_hash: sha256::Hash,
+
+	/// The confirmed transaction spending the output, if any.
+	spending_txid: Option<Txid>,
+}
+
+/// State maintained across calls to [`ElectrumSyncClient::sync`].
+struct SyncState {
+	client: ElectrumClient,
+
+	/// Transactions registered via [`Filter::register_tx`] along with the script hash subscribed to
+	/// for each.
+	watched_transactions: HashMap<Txid, sha256::Hash>,
+
+	/// Outputs registered via [`Filter::register_output`].
+	watched_outputs: HashMap<OutPoint, WatchedOutputState>,
+
+	/// Scripts of interest keyed by their script hash along with the last known status.
+	script_statuses: HashMap<sha256::Hash, Option<String>>,
+
+	/// Script hashes whose history must be fetched since their status has changed.
+	dirty_script_hashes: HashSet<sha256::Hash>,
+
+	/// Transactions which were given to [`Confirm::transactions_confirmed`].
+	confirmed_txs: HashMap<Txid, ConfirmedTx>,
+
+	/// The best block given to [`Confirm::best_block_updated`], if any.
+	best_block: Option<(BlockHash, u32)>,
+
+	/// Headers of the best chain up to the tip, starting at height `header_chain_start`. Each of
+	/// them has been checked to attain its target and to build on the previous one.
+	header_chain: VecDeque<BlockHeader>,
+
+	/// The height of the first header in `header_chain`.
+	header_chain_start: u32,
+}
+
+/// A client for keeping [`Confirm`] implementations, such as `ChannelManager` and `ChainMonitor`,
+/// in sync with the chain using an Electrum server.
+///
+/// The client should be given as the [`Filter`] when constructing the `ChainMonitor` so that it is
+/// notified of any transactions and outputs to watch. [`ElectrumSyncClient::sync`] should then be
+/// called periodically, and upon startup before processing any other chain data.
+///
+/// Registered transactions and outputs are queued and only subscribed to on the next sync, as
+/// required by [`Filter`] to avoid blocking on I/O. Consequently, [`Filter::register_output`] only
+/// returns a spending transaction if it was already fetched during an ongoing sync. Any other
+/// in-block descendants of a watched output are instead found when fetching the history of the
+/// output's script, which includes any spending transactions.
+///
+/// Transactions and outputs are no longer watched once their confirmation or spend, respectively,
+/// is [`ANTI_REORG_DELAY`] blocks deep, at which point their scripts are unsubscribed from.
+pub struct ElectrumSyncClient {
+	network: Network,
+	state: Mutex<SyncState>,
+	queue: std::sync::Mutex<FilterQueue>,
+
+	/// Confirmed transactions fetched during the ongoing sync, keyed by the outputs they spend.
+	block_spends: std::sync::Mutex<HashMap<OutPoint, BlockSpend>>,
+
+	/// Spends returned by [`Filter::register_output`] during the ongoing sync.
+	returned_spends: std::sync::Mutex<Vec<ReturnedSpend>>,
+}
+
+impl ElectrumSyncClient {
+	/// Creates a new sync client connected to the Electrum server at the given endpoint, which
+	/// serves the chain of the given network.
+	pub fn new<E: ToSocketAddrs>(endpoint: E, network: Network) -> std::io::Result<Self> {
+		let client = ElectrumClient::connect(endpoint)?;
+		Ok(Self {
+			network,
+			state: Mutex::new(SyncState {
+				client,
+				watched_transactions: HashMap::new(),
+				watched_outputs: HashMap::new(),
+				script_statuses: HashMap::new(),
+				dirty_script_hashes: HashSet::new(),
+				confirmed_txs: HashMap::new(),
+				best_block: None,
+				header_chain: VecDeque::new(),
+				header_chain_start: 0,
+			}),
+			queue: std::sync::Mutex::new(FilterQueue::default()),
+			block_spends: std::sync::Mutex::new(HashMap::new()),
+			returned_spends: std::sync::Mutex::new(Vec::new()),
+		})
+	}
+
+	/// Synchronizes the given [`Confirm`] implementations with the chain as seen by the Electrum
+	/// server.
+	///
+	/// Any transactions returned by [`Confirm::get_relevant_txids`] which are no longer confirmed
+	/// in the same block are first given to [`Confirm::transaction_unconfirmed`]. Then any newly
+	/// confirmed transactions of interest are given to [`Confirm::transactions_confirmed`] in chain
+	/// order, after verifying their merkle proofs. Finally, the new chain tip is given to
+	/// [`Confirm::best_block_updated`].
+	///
+	/// Block headers are only used once they have been checked to attain their target and to form
+	/// a chain up to the tip, failing the sync with a persistent error otherwise.
+	///
+	/// All implementations must be given in each call, as the client does not track which of them
+	/// were previously notified of a transaction.
+	pub async fn sync(&self, confirmables: Vec<&dyn Confirm>) -> BlockSourceResult<()> {
+		let mut state = self.state.lock().await;
+		loop {
+			if state.client.take_reconnected() {
+				self.resubscribe(&mut state).await?;
+			}
+			self.process_queue(&mut state).await?;
+
+			// Fetching the tip also reads any notifications sent since the last request.
+			let (tip_header, tip_height) = state.client.subscribe_headers().await
+				.map_err(into_block_source_error)?;
+			Self::process_notifications(&mut state);
+
+			let tip_hash = tip_header.block_hash();
+			let tip_changed = state.best_block != Some((tip_hash, tip_height));
+			if tip_changed {
+				self.update_header_chain(&mut state, tip_header, tip_height).await?;
+				self.sync_unconfirmed_transactions(&mut state, &confirmables, tip_height).await?;
+			}
+
+			self.sync_confirmed_transactions(&mut state, &confirmables, tip_height).await?;
+
+			if tip_changed {
+				for confirmable in confirmables.iter() {
+					confirmable.best_block_updated(&tip_header, tip_height);
+				}
+				state.best_block = Some((tip_hash, tip_height));
+			}
+
+			Self::prune_header_chain(&mut state, tip_height);
+			Self::prune_watched_scripts(&mut state, tip_height).await?;
+
+			// Confirmed transactions may have resulted in new outputs to watch, which could have been
+			// spent already. Similarly, subscriptions must be made again after reconnecting.
+			let queue_empty = self.queue.lock().unwrap().is_empty();
+			if queue_empty && !state.client.reconnected {
+				break;
+			}
+		}
+		Ok(())
+	}
+
+	/// Subscribes to any scripts registered via [`Filter`] since the last sync.
+	async fn process_queue(&self, state: &mut SyncState) -> BlockSourceResult<()> {
+		let queue = std::mem::take(&mut *self.queue.lock().unwrap());
+		for (txid, script_pubkey) in queue.transactions {
+			let script_hash = Self::subscribe(state, &script_pubkey).await?;
+			state.watched_transactions.insert(txid, script_hash);
+		}
+		for output in queue.outputs {
+			let script_hash = Self::subscribe(state, &output.script_pubkey).await?;
+			state.watched_outputs.entry(output.outpoint.into_bitcoin_outpoint())
+				.or_insert(WatchedOutputState { script_hash, spending_txid: None });
+		}
+		Ok(())
+	}
+
+	/// Subscribes to the script hash of the given script if not already subscribed, marking it for
+	/// fetching its history if it has any, and returns the script hash.
+	async fn subscribe(state: &mut SyncState, script: &Script) -> BlockSourceResult<sha256::Hash> {
+		let script_hash = script_hash(script);
+		let status = match state.script_statuses.get(&script_hash) {
+			// The history must be fetched again, as it may include spends of a newly watched output.
+			Some(status) => status.clone(),
+			None => {
+				let status = state.client.subscribe_script_hash(&script_hash).await
+					.map_err(into_block_source_error)?;
+				state.script_statuses.insert(script_hash, status.clone());
+				status
+			},
+		};
+		if status.is_some() {
+			state.dirty_script_hashes.insert(script_hash);
+		}
+		Ok(script_hash)
+	}
+
+	/// Subscribes to all known script hashes after reconnecting, marking any with a changed status
+	/// for fetching their history.
+	async fn resubscribe(&self, state: &mut SyncState) -> BlockSourceResult<()> {
+		let script_hashes: Vec<sha256::Hash> = state.script_statuses.keys().cloned().collect();
+		for script_hash in script_hashes {
+			let status = state.client.subscribe_script_hash(&script_hash).await
+				.map_err(into_block_source_error)?;
+			if state.script_statuses.insert(script_hash, status.clone()) != Some(status) {
+				state.dirty_script_hashes.insert(script_hash);
+			}
+		}
+		Ok(())
+	}
+
+	/// Marks any script hashes with changed status for fetching their history.
+	fn process_notifications(state: &mut SyncState) {
+		for notification in state.client.take_notifications() {
+			match notification {
+				// The tip is always fetched when syncing, so header notifications need no handling.
+				ElectrumNotification::Header(..) => {},
+				ElectrumNotification::ScriptHashStatus(script_hash, status) => {
+					if let Some(known_status) = state.script_statuses.get_mut(&script_hash) {
+						if *known_status != status {
+							*known_status = status;
+							state.dirty_script_hashes.insert(script_hash);
+						}
+					}
+				},
+			}
+		}
+	}
+
+	/// Updates the header chain to end at the given tip, fetching the headers from the tip down to
+	/// where it forks off the known chain.
+	async fn update_header_chain(
+		&self, state: &mut SyncState, tip_header: BlockHeader, tip_height: u32,
+	) -> BlockSourceResult<()> {
+		let tip_hash = validate_pow(&tip_header)?;
+		if state.header_chain.is_empty() || tip_height == 0 {
+			state.header_chain = VecDeque::from(vec![tip_header]);
+			state.header_chain_start = tip_height;
+			return Ok(());
+		}
+
+		let chain_end = state.header_chain_start + state.header_chain.len() as u32 - 1;
+		let mut fork_height = cmp::min(chain_end, tip_height - 1);
+		let mut step = 1;
+		loop {
+			let headers = self.fetch_headers(state, fork_height, tip_height).await?;
+			if headers.last().map(|header| header.block_hash()) != Some(tip_hash) {
+				return Err(BlockSourceError::transient("tip changed while fetching headers"));
+			}
+
+			if fork_height < state.header_chain_start {
+				// The tip forks off below any known header, so start the chain anew.
+				state.header_chain = headers.into();
+				state.header_chain_start = fork_height;
+				return Ok(());
+			}
+
+			let index = (fork_height - state.header_chain_start) as usize;
+			if state.header_chain[index].block_hash() == headers[0].block_hash() {
+				state.header_chain.truncate(index);
+				state.header_chain.extend(headers);
+				return Ok(());
+			}
+
+			if fork_height == 0 {
+				return Err(BlockSourceError::persistent("invalid genesis block"));
+			}
+			fork_height = fork_height.saturating_sub(step);
+			step *= 2;
+		}
+	}
+
+	/// Fetches the headers of the blocks from `start_height` to `end_height` inclusive, checking
+	/// that each attains its target and builds on the previous one.
+	async fn fetch_headers(
+		&self, state: &mut SyncState, start_height: u32, end_height: u32,
+	) -> BlockSourceResult<Vec<BlockHeader>> {
+		let mut headers: Vec<BlockHeader> = Vec::with_capacity((end_height - start_height + 1) as usize);
+		let mut height = start_height;
+		while height <= end_height {
+			let count = cmp::min(end_height - height + 1, MAX_HEADERS_PER_REQUEST);
+			let batch = state.client.get_headers(height, count).await.map_err(into_block_source_error)?;
+			if batch.is_empty() {
+				return Err(BlockSourceError::transient("missing headers"));
+			}
+			for header in batch.into_iter().take(count as usize) {
+				validate_pow(&header)?;
+				if let Some(previous_header) = headers.last() {
+					check_builds_on(&header, height, previous_header, self.network)?;
+				}
+				headers.push(header);
+				height += 1;
+			}
+		}
+		Ok(headers)
+	}
+
+	/// Returns the header at the given height on the header chain, first extending the chain down
+	/// to that height if needed.
+	async fn get_header(&self, state: &mut SyncState, height: u32) -> BlockSourceResult<BlockHeader> {
+		if height < state.header_chain_start {
+			let headers = self.fetch_headers(state, height, state.header_chain_start - 1).await?;
+			check_builds_on(&state.header_chain[0], state.header_chain_start, headers.last().unwrap(), self.network)?;
+			for header in headers.into_iter().rev() {
+				state.header_chain.push_front(header);
+			}
+			state.header_chain_start = height;
+		}
+
+		state.header_chain.get((height - state.header_chain_start) as usize).copied()
+			.ok_or_else(|| BlockSourceError::transient("block beyond the tip"))
+	}
+
+	/// Drops any headers below both the reorganization window and the blocks of any confirmed
+	/// transactions, which are no longer needed.
+	fn prune_header_chain(state: &mut SyncState, tip_height: u32) {
+		let min_height = state.confirmed_txs.values()
+			.map(|confirmed_tx| confirmed_tx.height)
+			.fold(tip_height.saturating_sub(HEADER_CHAIN_REORG_WINDOW), cmp::min);
+		while state.header_chain_start < min_height && state.header_chain.len() > 1 {
+			state.header_chain.pop_front();
+			state.header_chain_start += 1;
+		}
+	}
+
+	/// Stops watching transactions and outputs once their confirmation or spend, respectively, is
+	/// [`ANTI_REORG_DELAY`] blocks deep, unsubscribing from any scripts no longer of interest.
+	async fn prune_watched_scripts(state: &mut SyncState, tip_height: u32) -> BlockSourceResult<()> {
+		let confirmed_txs = &state.confirmed_txs;
+		let is_buried = |txid: &Txid| confirmed_txs.get(txid).map_or(false, |confirmed_tx| {
+			tip_height.saturating_sub(confirmed_tx.height) + 1 >= ANTI_REORG_DELAY
+		});
+		state.watched_transactions.retain(|txid, _| !is_buried(txid));
+		state.watched_outputs.retain(|_, output| !output.spending_txid.as_ref().map_or(false, &is_buried));
+
+		let watched_script_hashes: HashSet<sha256::Hash> = state.watched_transactions.values().cloned()
+			.chain(state.watched_outputs.values().map(|output| output.script_hash))
+			.collect();
+		let unwatched_script_hashes: Vec<sha256::Hash> = state.script_statuses.keys()
+			.filter(|script_hash| !watched_script_hashes.contains(*script_hash))
+			.cloned()
+			.collect();
+		for script_hash in unwatched_script_hashes {
+			match state.client.unsubscribe_script_hash(&script_hash).await {
+				Ok(_) => {},
+				// Servers predating protocol version 1.4.2 cannot unsubscribe, in which case any
+				// further notifications are ignored.
+				Err(e) if e.get_ref().map_or(false, |e| e.is::<ElectrumError>()) => {},
+				Err(e) => return Err(into_block_source_error(e)),
+			}
+			state.script_statuses.remove(&script_hash);
+			state.dirty_script_hashes.remove(&script_hash);
+		}
+		Ok(())
+	}
+
+	/// Gives any relevant transactions which are no longer confirmed in the same block to
+	/// [`Confirm::transaction_unconfirmed`].
+	async fn sync_unconfirmed_transactions(
+		&self, state: &mut SyncState, confirmables: &[&dyn Confirm], tip_height: u32,
+	) -> BlockSourceResult<()> {
+		let mut relevant_txids = Vec::new();
+		for confirmable in confirmables.iter() {
+			for txid in confirmable.get_relevant_txids() {
+				if !relevant_txids.contains(&txid) {
+					relevant_txids.push(txid);
+				}
+			}
+		}
+
+		// Forget about transactions which are buried deep enough to no longer be of interest, unless
+		// they are still needed to stop watching the corresponding scripts.
+		let watched_transactions = &state.watched_transactions;
+		let watched_outputs = &state.watched_outputs;
+		state.confirmed_txs.retain(|txid, confirmed_tx| {
+			relevant_txids.contains(txid) || tip_height.saturating_sub(confirmed_tx.height) + 1 < ANTI_REORG_DELAY ||
+				watched_transactions.contains_key(txid) ||
+				watched_outputs.values().any(|output| output.spending_txid == Some(*txid))
+		});
+
+		let mut unconfirmed_txids = Vec::new();
+		for txid in relevant_txids {
+			let confirmed_tx = match state.confirmed_txs.get(&txid) {
+				Some(confirmed_tx) => Some(*confirmed_tx),
+				// The transaction may have been confirmed prior to a restart, so look it up.
+				None => self.look_up_confirmed_tx(state, &txid).await?,
+			};
+			let still_confirmed = match confirmed_tx {
+				None => false,
+				Some(ConfirmedTx { block_hash, height }) => {
+					let header = self.get_header(state, height).await?;
+					header.block_hash() == block_hash
+				},
+			};
+			if still_confirmed {
+				state.confirmed_txs.insert(txid, confirmed_tx.unwrap());
+			} else {
+				unconfirmed_txids.push(txid);
+			}
+		}
+
+		if !unconfirmed_txids.is_empty() {
+			for txid in unconfirmed_txids {
+				state.confirmed_txs.remove(&txid);
+				for confirmable in confirmables.iter() {
+					confirmable.transaction_unconfirmed(&txid);
+				}
+			}
+
+			// Any unconfirmed transactions may have been reconfirmed in a different block.
+			let script_hashes: Vec<sha256::Hash> = state.script_statuses.keys().cloned().collect();
+			state.dirty_script_hashes.extend(script_hashes);
+		}
+		Ok(())
+	}
+
+	/// Looks up the block in which the transaction with the given txid was confirmed, if any, using
+	/// the history of its first output's script.
+	async fn look_up_confirmed_tx(
+		&self, state: &mut SyncState, txid: &Txid,
+	) -> BlockSourceResult<Option<ConfirmedTx>> {
+		let tx = match state.client.get_transaction(txid).await {
+			Ok(tx) => tx,
+			// The server may not know about transactions which were reorganized out of the chain and
+			// subsequently dropped from its mempool.
+			Err(e) if e.get_ref().map_or(false, |e| e.is::<ElectrumError>()) => return Ok(None),
+			Err(e) => return Err(into_block_source_error(e)),
+		};
+		let output = match tx.output.first() {
+			None => return Ok(None),
+			Some(output) => output,
+		};
+
+		let history = state.client.get_history(&script_hash(&output.script_pubkey)).await
+			.map_err(into_block_source_error)?;
+		let height = match history.iter().find(|(history_txid, _)| history_txid == txid) {
+			Some((_, height)) if *height > 0 => *height as u32,
+			_ => return Ok(None),
+		};
+
+		let header = self.get_header(state, height).await?;
+		Self::verify_merkle_proof(state, txid, height, &header).await?;
+		Ok(Some(ConfirmedTx { block_hash: header.block_hash(), height }))
+	}
+
+	/// Fetches the history of any scripts with changed status, giving any newly confirmed
+	/// transactions of interest to [`Confirm::transactions_confirmed`] in chain order.
+	async fn sync_confirmed_transactions(
+		&self, state: &mut SyncState, confirmables: &[&dyn Confirm], tip_height: u32,
+	) -> BlockSourceResult<()> {
+		self.block_spends.lock().unwrap().clear();
+		let mut fetched_txids = HashSet::new();
+		let mut confirmed_txs = Vec::new();
+		let mut deferred_script_hashes = HashSet::new();
+		let script_hashes: Vec<sha256::Hash> = state.dirty_script_hashes.iter().cloned().collect();
+		for script_hash in script_hashes.iter() {
+			let history = state.client.get_history(script_hash).await
+				.map_err(into_block_source_error)?;
+			for (txid, height) in history {
+				if height <= 0 || fetched_txids.contains(&txid) {
+					continue;
+				}
+				// Transactions confirmed after the tip are processed on the next sync, for which the
+				// script must remain marked.
+				if height > tip_height as i64 {
+					deferred_script_hashes.insert(*script_hash);
+					continue;
+				}
+				fetched_txids.insert(txid);
+				let height = height as u32;
+
+				let header = self.get_header(state, height).await?;
+				let block_hash = header.block_hash();
+				if let Some(confirmed_tx) = state.confirmed_txs.get(&txid) {
+					if confirmed_tx.block_hash == block_hash {
+						continue;
+					}
+				}
+
+				let tx = state.client.get_transaction(&txid).await
+					.map_err(into_block_source_error)?;
+				let pos = Self::verify_merkle_proof(state, &txid, height, &header).await?;
+				let is_relevant = state.watched_transactions.contains_key(&txid) ||
+					tx.input.iter().any(|input| state.watched_outputs.contains_key(&input.previous_output));
+
+				let mut block_spends = self.block_spends.lock().unwrap();
+				for input in tx.input.iter() {
+					block_spends.insert(input.previous_output, BlockSpend { block_hash, height, pos, tx: tx.clone() });
+				}
+				if is_relevant {
+					confirmed_txs.push((height, pos, tx, header));
+				}
+			}
+		}
+
+		confirmed_txs.sort_unstable_by_key(|(height, pos, _, _)| (*height, *pos));
+		for (height, pos, tx, header) in confirmed_txs {
+			for confirmable in confirmables.iter() {
+				confirmable.transactions_confirmed(&header, &[(pos, &tx)], height);
+			}
+
+			let txid = tx.txid();
+			state.confirmed_txs.insert(txid, ConfirmedTx { block_hash: header.block_hash(), height });
+			for input in tx.input.iter() {
+				if let Some(output) = state.watched_outputs.get_mut(&input.previous_output) {
+					output.spending_txid = Some(txid);
+				}
+			}
+			for spend in self.returned_spends.lock().unwrap().drain(..) {
+				state.confirmed_txs.insert(spend.txid, spend.confirmed_tx);
+				state.watched_outputs.insert(spend.outpoint, WatchedOutputState {
+					script_hash: spend.script_hash,
+					spending_txid: Some(spend.txid),
+				});
+			}
+		}
+		self.block_spends.lock().unwrap().clear();
+
+		for script_hash in script_hashes {
+			if !deferred_script_hashes.contains(&script_hash) {
+				state.dirty_script_hashes.remove(&script_hash);
+			}
+		}
+		Ok(())
+	}
+
+	/// Verifies that the transaction with the given txid is included in the block with the given
+	/// header, returning its position in the block.
+	async fn verify_merkle_proof(
+		state: &mut SyncState, txid: &Txid, height: u32, header: &BlockHeader,
+	) -> BlockSourceResult<usize> {
+		let proof = state.client.get_merkle(txid, height).await.map_err(into_block_source_error)?;
+		if !proof.verify(txid, header) {
+			return Err(BlockSourceError::persistent("invalid merkle proof"));
+		}
+		Ok(proof.pos)
+	}
+}
+
+/// Checks that the given header attains the target it claims, returning its block hash.
+fn validate_pow(header: &BlockHeader) -> BlockSourceResult<BlockHash> {
+	header.validate_pow(&header.target()).map_err(BlockSourceError::persistent)
+}
+
+/// Checks that the given header at `height` builds on `previous_header` and, on mainnet, that the
+/// difficulty transition is possible, as [`ChainPoller`] does.
+///
+/// [`ChainPoller`]: crate::poll::ChainPoller
+fn check_builds_on(
+	header: &BlockHeader, height: u32, previous_header: &BlockHeader, network: Network,
+) -> BlockSourceResult<()> {
+	if header.prev_blockhash != previous_header.block_hash() {
+		return Err(BlockSourceError::persistent("invalid previous block hash"));
+	}
+
+	if let Network::Bitcoin = network {
+		if height % 2016 == 0 {
+			let work = header.work();
+			let previous_work = previous_header.work();
+			if work > (previous_work << 2) || work < (previous_work >> 2) {
+				return Err(BlockSourceError::persistent("invalid difficulty transition"));
+			}
+		} else if header.bits != previous_header.bits {
+			return Err(BlockSourceError::persistent("invalid difficulty"));
+		}
+	}
+	Ok(())
+}
+
+impl Filter for ElectrumSyncClient {
+	fn register_tx(&self, txid: &Txid, script_pubkey: &Script) {
+		self.queue.lock().unwrap().transactions.push((*txid, script_pubkey.clone()));
+	}
+
+	fn register_output(&self, output: WatchedOutput) -> Option<(usize, Transaction)> {
+		let outpoint = output.outpoint.into_bitcoin_outpoint();
+		let spend = output.block_hash.and_then(|block_hash| {
+			let block_spends = self.block_spends.lock().unwrap();
+			block_spends.get(&outpoint)
+				.filter(|spend| spend.block_hash == block_hash)
+				.map(|spend| (spend.pos, spend.tx.clone(), ConfirmedTx { block_hash, height: spend.height }))
+		});
+		if let Some((_, tx, confirmed_tx)) = &spend {
+			self.returned_spends.lock().unwrap().push(ReturnedSpend {
+				outpoint,
+				script_hash: script_hash(&output.script_pubkey),
+				txid: tx.txid(),
+				confirmed_tx: *confirmed_tx,
+			});
+		}
+		self.queue.lock().unwrap().outputs.push(output);
+		spend.map(|(pos, tx, _)| (pos, tx))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use bitcoin::blockdata::block::Block;
+	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::blockdata::transaction::{TxIn, TxOut};
+	use bitcoin::util::hash::bitcoin_merkle_root;
+
+	use lightning::chain::transaction::TransactionData;
+
+	use std::io::{BufRead, Write};
+	use std::sync::{Arc, Mutex as StdMutex};
+
+	/// Chain data served by an [`ElectrumServer`].
+	struct ElectrumChain {
+		blocks: Vec<Block>,
+		corrupt_merkle_proofs: bool,
+
+		/// Number of blocks by which the reported tip lags behind the served history.
+		tip_lag: u32,
+	}
+
+	impl ElectrumChain {
+		fn transactions(&self) -> impl Iterator<Item = (u32, usize, &Transaction)> {
+			self.blocks.iter().enumerate().flat_map(|(height, block)| {
+				block.txdata.iter().enumerate().map(move |(pos, tx)| (height as u32, pos, tx))
+			})
+		}
+
+		fn find_output(&self, outpoint: &OutPoint) -> Option<&TxOut> {
+			self.transactions()
+				.find(|(_, _, tx)| tx.txid() == outpoint.txid)
+				.and_then(|(_, _, tx)| tx.output.get(outpoint.vout as usize))
+		}
+
+		fn history(&self, script_hash: &sha256::Hash) -> Vec<(Txid, u32)> {
+			self.transactions()
+				.filter(|(_, _, tx)| {
+					tx.output.iter().any(|output| super::script_hash(&output.script_pubkey) == *script_hash) ||
+						tx.input.iter().any(|input| match self.find_output(&input.previous_output) {
+							None => false,
+							Some(output) => super::script_hash(&output.script_pubkey) == *script_hash,
+						})
+				})
+				.map(|(height, _, tx)| (tx.txid(), height))
+				.collect()
+		}
+
+		fn status(&self, script_hash: &sha256::Hash) -> Option<String> {
+			let history = self.history(script_hash);
+			if history.is_empty() {
+				return None;
+			}
+
+			let mut engine = sha256::Hash::engine();
+			for (txid, height) in history {
+				engine.input(format!("{}:{}:", txid, height).as_bytes());
+			}
+			Some(sha256::Hash::from_engine(engine).to_hex())
+		}
+
+		fn merkle_proof(&self, txid: &Txid, height: u32) -> Option<serde_json::Value> {
+			let block = self.blocks.get(height as usize)?;
+			let mut pos = block.txdata.iter().position(|tx| tx.txid() == *txid)?;
+			let result_pos = pos;
+
+			let mut level: Vec<sha256d::Hash> = block.txdata.iter().map(|tx| tx.txid().as_hash()).collect();
+			let mut merkle = Vec::new();
+			while level.len() > 1 {
+				if level.len() % 2 == 1 {
+					level.push(*level.last().unwrap());
+				}
+				let sibling = if self.corrupt_merkle_proofs { sha256d::Hash::default() } else { level[pos ^ 1] };
+				merkle.push(serde_json::json!(sibling.to_hex()));
+				level = level.chunks(2).map(|pair| {
+					let mut engine = sha256d::Hash::engine();
+					engine.input(&pair[0][..]);
+					engine.input(&pair[1][..]);
+					sha256d::Hash::from_engine(engine)
+				}).collect();
+				pos /= 2;
+			}
+			Some(serde_json::json!({ "block_height": height, "merkle": merkle, "pos": result_pos }))
+		}
+	}
+
+	/// A local stand-in for an Electrum server serving a mutable chain.
+	struct ElectrumServer {
+		address: SocketAddr,
+		chain: Arc<StdMutex<ElectrumChain>>,
+	}
+
+	impl ElectrumServer {
+		fn new(chain_height: usize) -> Self {
+			let mut chain = ElectrumChain {
+				blocks: vec![genesis_block(Network::Regtest)],
+				corrupt_merkle_proofs: false,
+				tip_lag: 0,
+			};
+			for _ in 0..chain_height {
+				mine_block(&mut chain.blocks, Vec::new());
+			}
+			let chain = Arc::new(StdMutex::new(chain));
+
+			let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+			let address = listener.local_addr().unwrap();
+			let server_chain = Arc::clone(&chain);
+			std::thread::spawn(move || {
+				for stream in listener.incoming() {
+					let stream = stream.unwrap();
+					let chain = Arc::clone(&server_chain);
+					std::thread::spawn(move || Self::serve(stream, chain));
+				}
+			});
+
+			Self { address, chain }
+		}
+
+		fn serve(stream: std::net::TcpStream, chain: Arc<StdMutex<ElectrumChain>>) {
+			let mut writer = stream.try_clone().unwrap();
+			let mut subscriptions: HashMap<sha256::Hash, Option<String>> = HashMap::new();
+			for line in std::io::BufReader::new(stream).lines() {
+				let line = match line { Ok(line) => line, Err(_) => return };
+				let request: serde_json::Value = serde_json::from_str(&line).unwrap();
+				let chain = chain.lock().unwrap();
+
+				// Notify of any status changes since the last request, as a server would have done.
+				let mut messages = Vec::new();
+				for (script_hash, known_status) in subscriptions.iter_mut() {
+					let status = chain.status(script_hash);
+					if status != *known_status {
+						*known_status = status.clone();
+						messages.push(serde_json::json!({
+							"jsonrpc": "2.0",
+							"method": "blockchain.scripthash.subscribe",
+							"params": [script_hash_to_hex(script_hash), status],
+						}));
+					}
+				}
+
+				let params = &request["params"];
+				let tip_height = chain.blocks.len() as u32 - 1 - chain.tip_lag;
+				let result = match request["method"].as_str().unwrap() {
+					"server.version" => Ok(serde_json::json!(["ElectrumServer", PROTOCOL_VERSION])),
+					"blockchain.headers.subscribe" => Ok(serde_json::json!({
+						"height": tip_height,
+						"hex": encode::serialize_hex(&chain.blocks[tip_height as usize].header),
+					})),
+					"blockchain.block.header" => {
+						match chain.blocks.get(params[0].as_u64().unwrap() as usize) {
+							None => Err("height out of range"),
+							Some(block) => Ok(serde_json::json!(encode::serialize_hex(&block.header))),
+						}
+					},
+					"blockchain.block.headers" => {
+						let start_height = params[0].as_u64().unwrap() as usize;
+						let count = params[1].as_u64().unwrap() as usize;
+						let hex: String = chain.blocks.iter().skip(start_height).take(count)
+							.map(|block| encode::serialize_hex(&block.header))
+							.collect();
+						Ok(serde_json::json!({ "count": hex.len() / 160, "hex": hex, "max": MAX_HEADERS_PER_REQUEST }))
+					},
+					"blockchain.scripthash.subscribe" => {
+						let script_hash = script_hash_from_hex(params[0].as_str().unwrap()).unwrap();
+						let status = chain.status(&script_hash);
+						subscriptions.insert(script_hash, status.clone());
+						Ok(serde_json::json!(status))
+					},
+					"blockchain.scripthash.unsubscribe" => {
+						let script_hash = script_hash_from_hex(params[0].as_str().unwrap()).unwrap();
+						Ok(serde_json::json!(subscriptions.remove(&script_hash).is_some()))
+					},
+					"blockchain.scripthash.get_history" => {
+						let script_hash = script_hash_from_hex(params[0].as_str().unwrap()).unwrap();
+						let history: Vec<serde_json::Value> = chain.history(&script_hash).iter()
+							.map(|(txid, height)| serde_json::json!({ "tx_hash": txid.to_hex(), "height": height }))
+							.collect();
+						Ok(serde_json::json!(history))
+					},
+					"blockchain.transaction.get" => {
+						let txid = Txid::from_hex(params[0].as_str().unwrap()).unwrap();
+						match chain.transactions().find(|(_, _, tx)| tx.txid() == txid) {
+							None => Err("transaction not found"),
+							Some((_, _, tx)) => Ok(serde_json::json!(encode::serialize_hex(tx))),
+						}
+					},
+					"blockchain.transaction.get_merkle" => {
+						let txid = Txid::from_hex(params[0].as_str().unwrap()).unwrap();
+						let height = params[1].as_u64().unwrap() as u32;
+						chain.merkle_proof(&txid, height).ok_or("transaction not in block")
+					},
+					_ => Err("unknown method"),
+				};
+				messages.push(match result {
+					Ok(result) => serde_json::json!({ "jsonrpc": "2.0", "result": result, "id": request["id"] }),
+					Err(message) => serde_json::json!({
+						"jsonrpc": "2.0", "error": { "code": 1, "message": message }, "id": request["id"],
+					}),
+				});
+
+				for message in messages {
+					if writer.write_all((message.to_string() + "\n").as_bytes()).is_err() {
+						return;
+					}
+				}
+			}
+		}
+
+		fn client(&self) -> ElectrumSyncClient {
+			ElectrumSyncClient::new(self.address, Network::Regtest).unwrap()
+		}
+
+		fn mine_block(&self, txdata: Vec<Transaction>) -> Block {
+			mine_block(&mut self.chain.lock().unwrap().blocks, txdata)
+		}
+
+		fn reorg(&self, height: usize) {
+			self.chain.lock().unwrap().blocks.truncate(height);
+		}
+
+		fn tip_at(&self, height: u32) -> BlockHash {
+			self.chain.lock().unwrap().blocks[height as usize].block_hash()
+		}
+
+		fn tip(&self) -> (BlockHash, u32) {
+			let chain = self.chain.lock().unwrap();
+			(chain.blocks.last().unwrap().block_hash(), chain.blocks.len() as u32 - 1)
+		}
+	}
+
+	/// Mines a block on top of `blocks` with a coinbase transaction followed by `txdata`.
+	fn mine_block(blocks: &mut Vec<Block>, txdata: Vec<Transaction>) -> Block {
+		let prev_block = blocks.last().unwrap();
+		let height = blocks.len();
+		let coinbase = Transaction {
+			version: 1,
+			lock_time: height as u32,
+			input: vec![TxIn::default()],
+			output: vec![TxOut { value: 0, script_pubkey: Script::new() }],
+		};
+		let txdata: Vec<Transaction> = Some(coinbase).into_iter().chain(txdata).collect();
+		let merkle_root = bitcoin_merkle_root(txdata.iter().map(|tx| tx.txid().as_hash())).unwrap();
+		let mut block = Block {
+			header: BlockHeader {
+				version: 1,
+				prev_blockhash: prev_block.block_hash(),
+				merkle_root: merkle_root.into(),
+				time: prev_block.header.time + 1,
+				bits: prev_block.header.bits,
+				nonce: 0,
+			},
+			txdata,
+		};
+		grind(&mut block.header);
+		blocks.push(block.clone());
+		block
+	}
+
+	/// Sets the nonce of the given header such that it attains its target.
+	fn grind(header: &mut BlockHeader) {
+		header.nonce = 0;
+		while header.validate_pow(&header.target()).is_err() {
+			header.nonce += 1;
+		}
+	}
+
+	fn transaction_paying_to(script_pubkey: Script, input: Vec<TxIn>) -> Transaction {
+		Transaction { version: 2, lock_time: 0, input, output: vec![TxOut { value: 1_000, script_pubkey }] }
+	}
+
+	#[derive(Debug, PartialEq)]
+	enum ConfirmEvent {
+		Confirmed(Txid, u32),
+		Unconfirmed(Txid),
+		BestBlock(BlockHash, u32),
+	}
+
+	#[derive(Default)]
+	struct TestConfirmable {
+		events: StdMutex<Vec<ConfirmEvent>>,
+		relevant_txids: StdMutex<Vec<Txid>>,
+	}
+
+	impl TestConfirmable {
+		fn take_events(&self) -> Vec<ConfirmEvent> {
+			std::mem::take(&mut *self.events.lock().unwrap())
+		}
+	}
+
+	impl Confirm for TestConfirmable {
+		fn transactions_confirmed(&self, _header: &BlockHeader, txdata: &TransactionData, height: u32) {
+			assert!(!txdata.is_empty());
+			for (_, tx) in txdata.iter() {
+				self.events.lock().unwrap().push(ConfirmEvent::Confirmed(tx.txid(), height));
+				self.relevant_txids.lock().unwrap().push(tx.txid());
+			}
+		}
+
+		fn transaction_unconfirmed(&self, txid: &Txid) {
+			self.events.lock().unwrap().push(ConfirmEvent::Unconfirmed(*txid));
+			self.relevant_txids.lock().unwrap().retain(|relevant_txid| relevant_txid != txid);
+		}
+
+		fn best_block_updated(&self, header: &BlockHeader, height: u32) {
+			self.events.lock().unwrap().push(ConfirmEvent::BestBlock(header.block_hash(), height));
+		}
+
+		fn get_relevant_txids(&self) -> Vec<Txid> {
+			self.relevant_txids.lock().unwrap().clone()
+		}
+	}
+
+	#[tokio::test]
+	async fn sync_without_registered_scripts() {
+		let server = ElectrumServer::new(3);
+		let client = server.client();
+		let confirmable = TestConfirmable::default();
+
+		client.sync(vec![&confirmable]).await.unwrap();
+		let (tip_hash, tip_height) = server.tip();
+		assert_eq!(confirmable.take_events(), vec![ConfirmEvent::BestBlock(tip_hash, tip_height)]);
+
+		client.sync(vec![&confirmable]).await.unwrap();
+		assert!(confirmable.take_events().is_empty());
+	}
+
+	#[tokio::test]
+	async fn sync_registered_transaction() {
+		let server = ElectrumServer::new(3);
+		let client = server.client();
+		let confirmable = TestConfirmable::default();
+
+		let script_pubkey = Script::from(vec![0x51]);
+		let tx = transaction_paying_to(script_pubkey.clone(), vec![TxIn::default()]);
+		client.register_tx(&tx.txid(), &script_pubkey);
+		client.sync(vec![&confirmable]).await.unwrap();
+		assert_eq!(confirmable.take_events(), vec![ConfirmEvent::BestBlock(server.tip().0, 3)]);
+
+		let block = server.mine_block(vec![tx.clone()]);
+		client.sync(vec![&confirmable]).await.unwrap();
+		assert_eq!(confirmable.take_events(), vec![
+			ConfirmEvent::Confirmed(tx.txid(), 4),
+			ConfirmEvent::BestBlock(block.block_hash(), 4),
+		]);
+
+		// A transaction should only be confirmed once.
+		let block = server.mine_block(Vec::new());
+		client.sync(vec![&confirmable]).await.unwrap();
+		assert_eq!(confirmable.take_events(), vec![ConfirmEvent::BestBlock(block.block_hash(), 5)]);
+	}
+
+	#[tokio::test]
+	async fn sync_spends_of_registered_output() {
+		let server = ElectrumServer::new(1);
+		let client = server.client();
+		let confirmable = TestConfirmable::default();
+
+		let script_pubkey = Script::from(vec![0x51]);
+		let funding_tx = transaction_paying_to(script_pubkey.clone(), vec![TxIn::default()]);
+		server.mine_block(vec![funding_tx.clone()]);
+		let outpoint = OutPoint { txid: funding_tx.txid(), vout: 0 };
+		let spending_tx = transaction_paying_to(
+			Script::from(vec![0x52]), vec![TxIn { previous_output: outpoint, ..Default::default() }]);
+		let unrelated_tx = transaction_paying_to(Script::from(vec![0x53]), vec![TxIn::default()]);
+		let block = server.mine_block(vec![unrelated_tx, spending_tx.clone()]);
+
+		client.register_output(WatchedOutput {
+			block_hash: None,
+			outpoint: lightning::chain::transaction::OutPoint { txid: outpoint.txid, index: 0 },
+			script_pubkey,
+		});
+		client.sync(vec![&confirmable]).await.unwrap();
+		assert_eq!(confirmable.take_events(), vec![
+			ConfirmEvent::Confirmed(spending_tx.txid(), 3),
+			ConfirmEvent::BestBlock(block.block_hash(), 3),
+		]);
+	}
+
+	#[tokio::test]
+	async fn sync_reorganized_transaction() {
+		let server = ElectrumServer::new(3);
+		let client = server.client();
+		let confirmable = TestConfirmable::default();
+
+		let script_pubkey = Script::from(vec![0x51]);
+		let tx = transaction_paying_to(script_pubkey.clone(), vec![TxIn::default()]);
+		client.register_tx(&tx.txid(), &script_pubkey);
+		server.mine_block(vec![tx.clone()]);
+		client.sync(vec![&confirmable]).await.unwrap();
+		assert_eq!(confirmable.take_events(), vec![
+			ConfirmEvent::Confirmed(tx.txid(), 4),
+			ConfirmEvent::BestBlock(server.tip().0, 4),
+		]);
+
+		// Reorganize the transaction into a later block on a fork.
+		server.reorg(4);
+		server.mine_block(Vec::new());
+		let block = server.mine_block(vec![tx.clone()]);
+		client.sync(vec![&confirmable]).await.unwrap();
+		assert_eq!(confirmable.take_events(), vec![
+			ConfirmEvent::Unconfirmed(tx.txid()),
+			ConfirmEvent::Confirmed(tx.txid(), 5),
+			ConfirmEvent::BestBlock(block.block_hash(), 5),
+		]);
+
+		// Reorganize the transaction out of the chain entirely.
+		server.reorg(5);
+		let block = server.mine_block(Vec::new());
+		client.sync(vec![&confirmable]).await.unwrap();
+		assert_eq!(confirmable.take_events(), vec![
+			ConfirmEvent::Unconfirmed(tx.txid()),
+			ConfirmEvent::BestBlock(block.block_hash(), 5),
+		]);
+	}
+
+	#[tokio::test]
+	async fn sync_with_invalid_merkle_proof() {
+		let server = ElectrumServer::new(1);
+		let client = server.client();
+		let confirmable = TestConfirmable::default();
+
+		let script_pubkey = Script::from(vec![0x51]);
+		let tx = transaction_paying_to(script_pubkey.clone(), vec![TxIn::default()]);
+		server.mine_block(vec![tx.clone()]);
+		server.chain.lock().unwrap().corrupt_merkle_proofs = true;
+
+		client.register_tx(&tx.txid(), &script_pubkey);
+		match client.sync(vec![&confirmable]).await {
+			Err(e) => {
+				assert_eq!(e.kind(), crate::BlockSourceErrorKind::Persistent);
+				assert_eq!(e.into_inner().as_ref().to_string(), "invalid merkle proof");
+			},
+			Ok(_) => panic!("Expected error"),
+		}
+		assert!(confirmable.take_events().is_empty());
+	}
+
+	#[tokio::test]
+	async fn sync_transaction_confirmed_beyond_tip() {
+		let server = ElectrumServer::new(3);
+		let client = server.client();
+		let confirmable = TestConfirmable::default();
+
+		let script_pubkey = Script::from(vec![0x51]);
+		let tx = transaction_paying_to(script_pubkey.clone(), vec![TxIn::default()]);
+		client.register_tx(&tx.txid(), &script_pubkey);
+		let block = server.mine_block(vec![tx.clone()]);
+
+		// The history includes a block which is not yet reported as the tip.
+		server.chain.lock().unwrap().tip_lag = 1;
+		client.sync(vec![&confirmable]).await.unwrap();
+		assert_eq!(confirmable.take_events(), vec![ConfirmEvent::BestBlock(server.tip_at(3), 3)]);
+
+		// The script status is unchanged, yet the confirmation must still be delivered.
+		server.chain.lock().unwrap().tip_lag = 0;
+		client.sync(vec![&confirmable]).await.unwrap();
+		assert_eq!(confirmable.take_events(), vec![
+			ConfirmEvent::Confirmed(tx.txid(), 4),
+			ConfirmEvent::BestBlock(block.block_hash(), 4),
+		]);
+	}
+
+	#[tokio::test]
+	async fn sync_with_unconnected_header() {
+		let server = ElectrumServer::new(3);
+		let client = server.client();
+		let confirmable = TestConfirmable::default();
+		client.sync(vec![&confirmable]).await.unwrap();
+		confirmable.take_events();
+
+		// Mine a block attaining its target but not building on the previous block.
+		server.mine_block(Vec::new());
+		{
+			let mut chain = server.chain.lock().unwrap();
+			let header = &mut chain.blocks.last_mut().unwrap().header;
+			header.prev_blockhash = BlockHash::default();
+			grind(header);
+		}
+		server.mine_block(Vec::new());
+
+		match client.sync(vec![&confirmable]).await {
+			Err(e) => {
+				assert_eq!(e.kind(), crate::BlockSourceErrorKind::Persistent);
+				assert_eq!(e.into_inner().as_ref().to_string(), "invalid previous block hash");
+			},
+			Ok(_) => panic!("Expected error"),
+		}
+		assert!(confirmable.take_events().is_empty());
+	}
+
+	#[tokio::test]
+	async fn sync_with_insufficient_work() {
+		let server = ElectrumServer::new(3);
+		let client = server.client();
+		let confirmable = TestConfirmable::default();
+
+		{
+			let mut chain = server.chain.lock().unwrap();
+			let header = &mut chain.blocks.last_mut().unwrap().header;
+			while header.validate_pow(&header.target()).is_ok() {
+				header.nonce += 1;
+			}
+		}
+
+		match client.sync(vec![&confirmable]).await {
+			Err(e) => assert_eq!(e.kind(), crate::BlockSourceErrorKind::Persistent),
+			Ok(_) => panic!("Expected error"),
+		}
+		assert!(confirmable.take_events().is_empty());
+	}
+
+	/// Registers the first output of the given transaction upon its confirmation, as a
+	/// `ChainMonitor` does for funding transactions.
+	struct RegisteringConfirmable<'a> {
+		client: &'a ElectrumSyncClient,
+		confirmable: TestConfirmable,
+		funding_tx: Transaction,
+		returned_spends: StdMutex<Vec<(usize, Transaction)>>,
+	}
+
+	impl<'a> Confirm for RegisteringConfirmable<'a> {
+		fn transactions_confirmed(&self, header: &BlockHeader, txdata: &TransactionData, height: u32) {
+			self.confirmable.transactions_confirmed(header, txdata, height);
+			for (_, tx) in txdata.iter() {
+				if tx.txid() == self.funding_tx.txid() {
+					let output = WatchedOutput {
+						block_hash: Some(header.block_hash()),
+						outpoint: lightning::chain::transaction::OutPoint { txid: tx.txid(), index: 0 },
+						script_pubkey: tx.output[0].script_pubkey.clone(),
+					};
+					if let Some(spend) = self.client.register_output(output) {
+						self.returned_spends.lock().unwrap().push(spend);
+					}
+				}
+			}
+		}
+
+		fn transaction_unconfirmed(&self, txid: &Txid) {
+			self.confirmable.transaction_unconfirmed(txid);
+		}
+
+		fn best_block_updated(&self, header: &BlockHeader, height: u32) {
+			self.confirmable.best_block_updated(header, height);
+		}
+
+		fn get_relevant_txids(&self) -> Vec<Txid> {
+			self.confirmable.get_relevant_txids()
+		}
+	}
+
+	#[tokio::test]
+	async fn register_output_returns_spend_in_same_block() {
+		let server = ElectrumServer::new(1);
+		let client = server.client();
+
+		let script_pubkey = Script::from(vec![0x51]);
+		let funding_tx = transaction_paying_to(script_pubkey.clone(), vec![TxIn::default()]);
+		let outpoint = OutPoint { txid: funding_tx.txid(), vout: 0 };
+		let spending_tx = transaction_paying_to(
+			Script::from(vec![0x52]), vec![TxIn { previous_output: outpoint, ..Default::default() }]);
+		let block = server.mine_block(vec![funding_tx.clone(), spending_tx.clone()]);
+
+		let confirmable = RegisteringConfirmable {
+			client: &client,
+			confirmable: TestConfirmable::default(),
+			funding_tx: funding_tx.clone(),
+			returned_spends: StdMutex::new(Vec::new()),
+		};
+		client.register_tx(&funding_tx.txid(), &script_pubkey);
+		client.sync(vec![&confirmable]).await.unwrap();
+		assert_eq!(*confirmable.returned_spends.lock().unwrap(), vec![(2, spending_tx)]);
+
+		// The returned spend is not given to the confirmable again.
+		assert_eq!(confirmable.confirmable.take_events(), vec![
+			ConfirmEvent::Confirmed(funding_tx.txid(), 2),
+			ConfirmEvent::BestBlock(block.block_hash(), 2),
+		]);
+	}
+
+	#[tokio::test]
+	async fn sync_unsubscribes_once_buried() {
+		let server = ElectrumServer::new(1);
+		let client = server.client();
+		let confirmable = TestConfirmable::default();
+
+		let script_pubkey = Script::from(vec![0x51]);
+		let tx = transaction_paying_to(script_pubkey.clone(), vec![TxIn::default()]);
+		client.register_tx(&tx.txid(), &script_pubkey);
+		server.mine_block(vec![tx.clone()]);
+		for _ in 0..ANTI_REORG_DELAY - 2 {
+			server.mine_block(Vec::new());
+		}
+		client.sync(vec![&confirmable]).await.unwrap();
+		assert_eq!(client.state.lock().await.script_statuses.len(), 1);
+
+		server.mine_block(Vec::new());
+		client.sync(vec![&confirmable]).await.unwrap();
+		let state = client.state.lock().await;
+		assert!(state.script_statuses.is_empty());
+		assert!(state.watched_transactions.is_empty());
+	}
+
+	#[test]
+	fn script_hash_matches_electrum_encoding() {
+		// Example from the Electrum protocol documentation for a P2PKH script.
+		let script = Script::from(Vec::<u8>::from_hex("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac").unwrap());
+		assert_eq!(
+			script_hash_to_hex(&script_hash(&script)),
+			"8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161");
+	}
+}
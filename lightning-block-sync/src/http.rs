@@ -9,10 +9,17 @@ use std::fmt;
 #[cfg(not(feature = "tokio"))]
 use std::io::Write;
 use std::net::{SocketAddr, ToSocketAddrs};
+#[cfg(feature = "tls")]
+use std::sync::Arc;
 use std::time::Duration;
 
 #[cfg(feature = "tokio")]
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use std::pin::Pin;
+#[cfg(feature = "tokio")]
+use std::task::{Context, Poll};
+
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 #[cfg(feature = "tokio")]
 use tokio::net::TcpStream;
 
@@ -45,6 +52,9 @@ pub struct HttpEndpoint {
 	host: String,
 	port: Option<u16>,
 	path: String,
+	timeout: Duration,
+	#[cfg(feature = "tls")]
+	tls_config: Option<TlsConfig>,
 }
 
 impl HttpEndpoint {
@@ -54,6 +64,9 @@ impl HttpEndpoint {
 			host,
 			port: None,
 			path: String::from("/"),
+			timeout: TCP_STREAM_RESPONSE_TIMEOUT,
+			#[cfg(feature = "tls")]
+			tls_config: None,
 		}
 	}
 
@@ -69,6 +82,41 @@ impl HttpEndpoint {
 		self
 	}
 
+	/// Specifies how long to wait for a response to a request before failing, which defaults to
+	/// five minutes to accommodate Bitcoin Core being blocked on long-running operations. Note that
+	/// failed requests are retried once, so a request may take up to twice this long.
+	pub fn with_timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = timeout;
+		self
+	}
+
+	/// Specifies that connections to the endpoint should use TLS, verifying the server's
+	/// certificate against the Mozilla root certificates.
+	#[cfg(feature = "tls")]
+	pub fn with_tls(mut self) -> Self {
+		let mut root_store = rustls::RootCertStore::empty();
+		root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|trust_anchor| {
+			rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+				trust_anchor.subject, trust_anchor.spki, trust_anchor.name_constraints)
+		}));
+		self.tls_config = Some(TlsConfig::new(root_store));
+		self
+	}
+
+	/// Specifies that connections to the endpoint should use TLS, verifying the server's
+	/// certificate against only the given DER-encoded root certificates. Useful for servers with
+	/// certificates issued by a private certificate authority.
+	#[cfg(feature = "tls")]
+	pub fn with_tls_root_certificates(mut self, certificates: &[Vec<u8>]) -> std::io::Result<Self> {
+		let mut root_store = rustls::RootCertStore::empty();
+		for certificate in certificates {
+			root_store.add(&rustls::Certificate(certificate.clone()))
+				.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+		}
+		self.tls_config = Some(TlsConfig::new(root_store));
+		Ok(self)
+	}
+
 	/// Returns the endpoint host.
 	pub fn host(&self) -> &str {
 		&self.host
@@ -77,6 +125,7 @@ impl HttpEndpoint {
 	/// Returns the endpoint port.
 	pub fn port(&self) -> u16 {
 		match self.port {
+			None if self.uses_tls() => 443,
 			None => 80,
 			Some(port) => port,
 		}
@@ -86,6 +135,19 @@ impl HttpEndpoint {
 	pub fn path(&self) -> &str {
 		&self.path
 	}
+
+	/// Returns how long to wait for a response to a request.
+	pub fn timeout(&self) -> Duration {
+		self.timeout
+	}
+
+	/// Returns whether connections to the endpoint use TLS.
+	pub fn uses_tls(&self) -> bool {
+		#[cfg(feature = "tls")]
+		return self.tls_config.is_some();
+		#[cfg(not(feature = "tls"))]
+		return false;
+	}
 }
 
 impl<'a> std::net::ToSocketAddrs for &'a HttpEndpoint {
@@ -96,23 +158,180 @@ impl<'a> std::net::ToSocketAddrs for &'a HttpEndpoint {
 	}
 }
 
+/// Configuration for connecting to an endpoint using TLS.
+#[cfg(feature = "tls")]
+#[derive(Clone)]
+struct TlsConfig(Arc<rustls::ClientConfig>);
+
+#[cfg(feature = "tls")]
+impl TlsConfig {
+	fn new(root_store: rustls::RootCertStore) -> Self {
+		let config = rustls::ClientConfig::builder()
+			.with_safe_defaults()
+			.with_root_certificates(root_store)
+			.with_no_client_auth();
+		Self(Arc::new(config))
+	}
+}
+
+#[cfg(feature = "tls")]
+impl fmt::Debug for TlsConfig {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("TlsConfig")
+	}
+}
+
+#[cfg(all(feature = "tls", feature = "tokio"))]
+type TlsStream = tokio_rustls::client::TlsStream<TcpStream>;
+#[cfg(all(feature = "tls", not(feature = "tokio")))]
+type TlsStream = rustls::StreamOwned<rustls::ClientConnection, TcpStream>;
+
+/// A connection to an HTTP server, which may be secured using TLS.
+enum HttpStream {
+	Tcp(TcpStream),
+	#[cfg(feature = "tls")]
+	Tls(Box<TlsStream>),
+	/// A connection for which the TLS handshake has yet to be performed, as doing so requires an
+	/// async context. Only `None` if the handshake failed.
+	#[cfg(all(feature = "tls", feature = "tokio"))]
+	TlsHandshakePending(Option<TcpStream>),
+}
+
+#[cfg(not(feature = "tokio"))]
+impl std::io::Read for HttpStream {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		match self {
+			HttpStream::Tcp(stream) => stream.read(buf),
+			#[cfg(feature = "tls")]
+			HttpStream::Tls(stream) => stream.read(buf),
+		}
+	}
+}
+
+#[cfg(not(feature = "tokio"))]
+impl std::io::Write for HttpStream {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		match self {
+			HttpStream::Tcp(stream) => stream.write(buf),
+			#[cfg(feature = "tls")]
+			HttpStream::Tls(stream) => stream.write(buf),
+		}
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		match self {
+			HttpStream::Tcp(stream) => stream.flush(),
+			#[cfg(feature = "tls")]
+			HttpStream::Tls(stream) => stream.flush(),
+		}
+	}
+}
+
+#[cfg(feature = "tokio")]
+macro_rules! poll_http_stream {
+	($self: expr, $stream: ident => $poll: expr) => {
+		match $self.get_mut() {
+			HttpStream::Tcp($stream) => $poll,
+			#[cfg(feature = "tls")]
+			HttpStream::Tls($stream) => $poll,
+			#[cfg(feature = "tls")]
+			HttpStream::TlsHandshakePending(_) => {
+				Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::NotConnected, "TLS handshake not completed")))
+			},
+		}
+	}
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncRead for HttpStream {
+	fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+		poll_http_stream!(self, stream => Pin::new(stream).poll_read(cx, buf))
+	}
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncWrite for HttpStream {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+		poll_http_stream!(self, stream => Pin::new(stream).poll_write(cx, buf))
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		poll_http_stream!(self, stream => Pin::new(stream).poll_flush(cx))
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		poll_http_stream!(self, stream => Pin::new(stream).poll_shutdown(cx))
+	}
+}
+
 /// Client for making HTTP requests.
 pub(crate) struct HttpClient {
 	address: SocketAddr,
-	stream: TcpStream,
+	stream: HttpStream,
+	timeout: Duration,
+	#[cfg(feature = "tls")]
+	tls: Option<(rustls::ServerName, TlsConfig)>,
+	/// Whether the server indicated that it closed the connection after its last response.
+	connection_closed: bool,
 }
 
 impl HttpClient {
 	/// Opens a connection to an HTTP endpoint.
+	#[cfg(test)]
 	pub fn connect<E: ToSocketAddrs>(endpoint: E) -> std::io::Result<Self> {
-		let address = match endpoint.to_socket_addrs()?.next() {
-			None => {
-				return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "could not resolve to any addresses"));
-			},
-			Some(address) => address,
-		};
+		let address = Self::resolve(endpoint)?;
+		#[cfg(feature = "tls")]
+		return Self::connect_with_config(address, TCP_STREAM_RESPONSE_TIMEOUT, None);
+		#[cfg(not(feature = "tls"))]
+		return Self::connect_with_config(address, TCP_STREAM_RESPONSE_TIMEOUT);
+	}
+
+	/// Opens a connection to an HTTP endpoint using its configured timeout and, if enabled, TLS.
+	pub fn connect_to_endpoint(endpoint: &HttpEndpoint) -> std::io::Result<Self> {
+		let address = Self::resolve(endpoint)?;
+		#[cfg(feature = "tls")]
+		{
+			let tls = match &endpoint.tls_config {
+				None => None,
+				Some(tls_config) => {
+					let server_name = rustls::ServerName::try_from(endpoint.host())
+						.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+					Some((server_name, tls_config.clone()))
+				},
+			};
+			Self::connect_with_config(address, endpoint.timeout(), tls)
+		}
+		#[cfg(not(feature = "tls"))]
+		Self::connect_with_config(address, endpoint.timeout())
+	}
+
+	fn resolve<E: ToSocketAddrs>(endpoint: E) -> std::io::Result<SocketAddr> {
+		match endpoint.to_socket_addrs()?.next() {
+			None => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "could not resolve to any addresses")),
+			Some(address) => Ok(address),
+		}
+	}
+
+	fn connect_with_config(
+		address: SocketAddr, timeout: Duration, #[cfg(feature = "tls")] tls: Option<(rustls::ServerName, TlsConfig)>
+	) -> std::io::Result<Self> {
+		let stream = Self::open_stream(address, timeout, #[cfg(feature = "tls")] &tls)?;
+		Ok(Self {
+			address,
+			stream,
+			timeout,
+			#[cfg(feature = "tls")]
+			tls,
+			connection_closed: false,
+		})
+	}
+
+	/// Opens a connection to `address`, which is secured using TLS if `tls` is set.
+	fn open_stream(
+		address: SocketAddr, timeout: Duration, #[cfg(feature = "tls")] tls: &Option<(rustls::ServerName, TlsConfig)>
+	) -> std::io::Result<HttpStream> {
 		let stream = std::net::TcpStream::connect_timeout(&address, TCP_STREAM_TIMEOUT)?;
-		stream.set_read_timeout(Some(TCP_STREAM_TIMEOUT))?;
+		stream.set_read_timeout(Some(std::cmp::min(TCP_STREAM_TIMEOUT, timeout)))?;
 		stream.set_write_timeout(Some(TCP_STREAM_TIMEOUT))?;
 
 		#[cfg(feature = "tokio")]
@@ -121,7 +340,44 @@ impl HttpClient {
 			TcpStream::from_std(stream)?
 		};
 
-		Ok(Self { address, stream })
+		#[cfg(all(feature = "tls", feature = "tokio"))]
+		{
+			if tls.is_some() {
+				return Ok(HttpStream::TlsHandshakePending(Some(stream)));
+			}
+		}
+		#[cfg(all(feature = "tls", not(feature = "tokio")))]
+		{
+			if let Some((server_name, tls_config)) = tls {
+				let connection = rustls::ClientConnection::new(tls_config.0.clone(), server_name.clone())
+					.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+				return Ok(HttpStream::Tls(Box::new(rustls::StreamOwned::new(connection, stream))));
+			}
+		}
+
+		Ok(HttpStream::Tcp(stream))
+	}
+
+	/// Replaces the connection with a new one to the same address.
+	fn reconnect(&mut self) -> std::io::Result<()> {
+		self.stream = Self::open_stream(self.address, self.timeout, #[cfg(feature = "tls")] &self.tls)?;
+		self.connection_closed = false;
+		Ok(())
+	}
+
+	/// Performs the TLS handshake if it is pending, which is deferred until a request is made given
+	/// it requires an async context.
+	#[cfg(all(feature = "tls", feature = "tokio"))]
+	async fn complete_tls_handshake(&mut self) -> std::io::Result<()> {
+		if let HttpStream::TlsHandshakePending(stream) = &mut self.stream {
+			let stream = stream.take()
+				.ok_or(std::io::Error::new(std::io::ErrorKind::NotConnected, "TLS handshake failed"))?;
+			let (server_name, tls_config) = self.tls.as_ref().unwrap();
+			let connector = tokio_rustls::TlsConnector::from(tls_config.0.clone());
+			let stream = connector.connect(server_name.clone(), stream).await?;
+			self.stream = HttpStream::Tls(Box::new(stream));
+		}
+		Ok(())
 	}
 
 	/// Sends a `GET` request for a resource identified by `uri` at the `host`.
@@ -164,6 +420,10 @@ impl HttpClient {
 	/// Sends an HTTP request message and reads the response, returning its body. Attempts to
 	/// reconnect and retry if the connection has been closed.
 	async fn send_request_with_retry(&mut self, request: &str) -> std::io::Result<Vec<u8>> {
+		if self.connection_closed {
+			// Reconnect up front rather than failing on a connection known to be closed.
+			self.reconnect()?;
+		}
 		match self.send_request(request).await {
 			Ok(bytes) => Ok(bytes),
 			Err(_) => {
@@ -177,7 +437,7 @@ impl HttpClient {
 				tokio::time::sleep(Duration::from_millis(100)).await;
 				#[cfg(not(feature = "tokio"))]
 				std::thread::sleep(Duration::from_millis(100));
-				*self = Self::connect(self.address)?;
+				self.reconnect()?;
 				self.send_request(request).await
 			},
 		}
@@ -185,8 +445,26 @@ impl HttpClient {
 
 	/// Sends an HTTP request message and reads the response, returning its body.
 	async fn send_request(&mut self, request: &str) -> std::io::Result<Vec<u8>> {
-		self.write_request(request).await?;
-		self.read_response().await
+		#[cfg(feature = "tokio")]
+		{
+			// Reads on tokio streams do not time out, so instead bound the entire request.
+			let timeout = self.timeout;
+			let request = async {
+				#[cfg(feature = "tls")]
+				self.complete_tls_handshake().await?;
+				self.write_request(request).await?;
+				self.read_response().await
+			};
+			match tokio::time::timeout(timeout, request).await {
+				Ok(result) => result,
+				Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "request timed out")),
+			}
+		}
+		#[cfg(not(feature = "tokio"))]
+		{
+			self.write_request(request).await?;
+			self.read_response().await
+		}
 	}
 
 	/// Writes an HTTP request message.
@@ -205,8 +483,13 @@ impl HttpClient {
 
 	/// Reads an HTTP response message.
 	async fn read_response(&mut self) -> std::io::Result<Vec<u8>> {
+		// Each read times out after at most TCP_STREAM_TIMEOUT, so allow retrying when reading the
+		// status line until the request timeout is reached.
+		let read_timeout = std::cmp::min(TCP_STREAM_TIMEOUT, self.timeout);
+		let status_line_retry_count = (self.timeout.as_millis() / std::cmp::max(read_timeout.as_millis(), 1)) as u64;
+
 		#[cfg(feature = "tokio")]
-		let stream = self.stream.split().0;
+		let stream = &mut self.stream;
 		#[cfg(not(feature = "tokio"))]
 		let stream = std::io::Read::by_ref(&mut self.stream);
 
@@ -253,13 +536,13 @@ impl HttpClient {
 		}
 
 		// Read and parse status line
-		// Note that we allow retrying a few times to reach TCP_STREAM_RESPONSE_TIMEOUT.
-		let status_line = read_line!(TCP_STREAM_RESPONSE_TIMEOUT.as_secs() / TCP_STREAM_TIMEOUT.as_secs())
+		let status_line = read_line!(status_line_retry_count)
 			.ok_or(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "no status line"))?;
 		let status = HttpStatus::parse(&status_line)?;
 
 		// Read and parse relevant headers
 		let mut message_length = HttpMessageLength::Empty;
+		let mut connection_closed = false;
 		loop {
 			let line = read_line!()
 				.ok_or(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "no headers"))?;
@@ -279,6 +562,11 @@ impl HttpClient {
 				message_length = HttpMessageLength::TransferEncoding(header.value.into());
 				continue;
 			}
+
+			if header.has_name("Connection") {
+				connection_closed = header.value.eq_ignore_ascii_case("close");
+				continue;
+			}
 		}
 
 		// Read message body
@@ -347,6 +635,7 @@ impl HttpClient {
 				}
 			},
 		};
+		self.connection_closed = connection_closed;
 
 		if !status.is_ok() {
 			// TODO: Handle 3xx redirection responses.
@@ -488,6 +777,23 @@ mod endpoint_tests {
 		assert_eq!(endpoint.port(), 80);
 	}
 
+	#[cfg(feature = "tls")]
+	#[test]
+	fn with_default_tls_port() {
+		let endpoint = HttpEndpoint::for_host("foo.com".into()).with_tls();
+		assert!(endpoint.uses_tls());
+		assert_eq!(endpoint.port(), 443);
+	}
+
+	#[cfg(feature = "tls")]
+	#[test]
+	fn with_invalid_tls_root_certificate() {
+		match HttpEndpoint::for_host("foo.com".into()).with_tls_root_certificates(&[vec![0; 32]]) {
+			Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput),
+			Ok(_) => panic!("Expected error"),
+		}
+	}
+
 	#[test]
 	fn with_custom_port() {
 		let endpoint = HttpEndpoint::for_host("foo.com".into()).with_port(8080);
@@ -544,6 +850,28 @@ pub(crate) mod client_tests {
 		shutdown: std::sync::Arc<std::sync::atomic::AtomicBool>,
 	}
 
+	/// DER-encoded certificate of a test certificate authority.
+	#[cfg(feature = "tls")]
+	const TEST_CA_CERTIFICATE: &str = "3082016a30820110a00302010202144ce0131e5c6df3a79d21ded4b3dba2d8a16de8c1300a06082a8648ce3d04030230123110300e06035504030c07546573742043413020170d3236313031383137313230375a180f32313236303932343137313230375a30123110300e06035504030c07546573742043413059301306072a8648ce3d020106082a8648ce3d030107034200044298f712b71e52e1a3af55c0b0818f1f9e65b08705451129947fc941930335b4e477d8c699f64fda09dfe9766729a41cc166c97a94ffc02e51660ac171a1e408a3423040300f0603551d130101ff040530030101ff300e0603551d0f0101ff040403020106301d0603551d0e04160414ac2a4889c56040282c9ce31d3f1cce4158934f23300a06082a8648ce3d0403020348003045022100f4522f997b093ae088d5401afa1867ea4defe5a0f7825d4f5a91237051f5be6702202fbe518828906f4a71ec9720e54cb7ebc0d0eff43640403ddf0bc318d87a494f";
+
+	/// DER-encoded certificate for `localhost` issued by [`TEST_CA_CERTIFICATE`].
+	#[cfg(feature = "tls")]
+	const TEST_SERVER_CERTIFICATE: &str = "308201b130820157a00302010202142d3a8d02049dc58491997aea3977133265f02980300a06082a8648ce3d04030230123110300e06035504030c07546573742043413020170d3236313031383137313230375a180f32313236303932343137313230375a30143112301006035504030c096c6f63616c686f73743059301306072a8648ce3d020106082a8648ce3d030107034200040476d846947b2906de8ff4631e447a544b5c747c427270ec292e4c97f618354d2a83e72be5ecaceef953f35f744b8dfde1539d8af4dfbd1fa54d0bc295801be3a3818630818330090603551d1304023000300b0603551d0f04040302078030130603551d25040c300a06082b0601050507030130140603551d11040d300b82096c6f63616c686f7374301d0603551d0e04160414af9a7a4813ee510569021f301d578982faf74395301f0603551d23041830168014ac2a4889c56040282c9ce31d3f1cce4158934f23300a06082a8648ce3d0403020348003045022018900c177ab3359e4fafff176259d238644c4f64ab48f5f732db6d846d51a145022100a65e00cbb29589b70d7d006c10dc482e0f736e422d5217fe21f24f357ccb0356";
+
+	/// DER-encoded PKCS #8 private key for [`TEST_SERVER_CERTIFICATE`].
+	#[cfg(feature = "tls")]
+	const TEST_SERVER_PRIVATE_KEY: &str = "308187020100301306072a8648ce3d020106082a8648ce3d030107046d306b0201010420441461bbccfd73859041cabb2791541e840393756625819fb527509e85b56747a144034200040476d846947b2906de8ff4631e447a544b5c747c427270ec292e4c97f618354d2a83e72be5ecaceef953f35f744b8dfde1539d8af4dfbd1fa54d0bc295801be3";
+
+	#[cfg(feature = "tls")]
+	type ServerTlsConfig = Arc<rustls::ServerConfig>;
+	#[cfg(not(feature = "tls"))]
+	type ServerTlsConfig = ();
+
+	#[cfg(feature = "tls")]
+	trait ReadWrite: std::io::Read + std::io::Write + Send {}
+	#[cfg(feature = "tls")]
+	impl<T: std::io::Read + std::io::Write + Send> ReadWrite for T {}
+
 	/// Body of HTTP response messages.
 	pub enum MessageBody<T: ToString> {
 		Empty,
@@ -594,22 +922,73 @@ pub(crate) mod client_tests {
 		}
 
 		fn responding_with(response: String) -> Self {
+			HttpServer::responding_with_handler(move |_| response.clone())
+		}
+
+		/// Responds to each request with the response returned by `handler` when given the lines
+		/// of the request's message header.
+		pub fn responding_with_handler<H>(handler: H) -> Self
+		where H: Fn(&[String]) -> String + Send + 'static {
 			let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+			HttpServer::serve(listener, None, handler)
+		}
+
+		/// Responds to each request with `response` over TLS using a certificate for `localhost`
+		/// issued by [`TEST_CA_CERTIFICATE`].
+		#[cfg(feature = "tls")]
+		pub fn responding_with_tls(response: String) -> Self {
+			use bitcoin::hashes::hex::FromHex;
+			let certificate = rustls::Certificate(Vec::<u8>::from_hex(TEST_SERVER_CERTIFICATE).unwrap());
+			let private_key = rustls::PrivateKey(Vec::<u8>::from_hex(TEST_SERVER_PRIVATE_KEY).unwrap());
+			let config = rustls::ServerConfig::builder()
+				.with_safe_defaults()
+				.with_no_client_auth()
+				.with_single_cert(vec![certificate], private_key)
+				.unwrap();
+
+			// Bind to the address the client will connect to when resolving `localhost`.
+			let address = ("localhost", 0).to_socket_addrs().unwrap().next().unwrap();
+			let listener = std::net::TcpListener::bind(address).unwrap();
+			HttpServer::serve(listener, Some(Arc::new(config)), move |_| response.clone())
+		}
+
+		fn serve<H>(listener: std::net::TcpListener, tls_config: Option<ServerTlsConfig>, handler: H) -> Self
+		where H: Fn(&[String]) -> String + Send + 'static {
 			let address = listener.local_addr().unwrap();
 
 			let shutdown = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
 			let shutdown_signaled = std::sync::Arc::clone(&shutdown);
 			let handler = std::thread::spawn(move || {
 				for stream in listener.incoming() {
-					let mut stream = stream.unwrap();
+					let stream = stream.unwrap();
 					stream.set_write_timeout(Some(TCP_STREAM_TIMEOUT)).unwrap();
+					#[cfg(feature = "tls")]
+					let stream: Box<dyn ReadWrite> = match &tls_config {
+						None => Box::new(stream),
+						Some(tls_config) => {
+							let connection = rustls::ServerConnection::new(tls_config.clone()).unwrap();
+							Box::new(rustls::StreamOwned::new(connection, stream))
+						},
+					};
+					#[cfg(not(feature = "tls"))]
+					let _ = &tls_config;
+					let mut reader = std::io::BufReader::new(stream);
+
+					let mut lines = Vec::new();
+					loop {
+						let mut line = String::new();
+						match reader.read_line(&mut line) {
+							Ok(0) | Err(_) => break,
+							Ok(_) => {},
+						}
+						let line = line.trim_end().to_string();
+						if line.is_empty() { break; }
+						lines.push(line);
+					}
+					if lines.is_empty() { continue; }
 
-					let lines_read = std::io::BufReader::new(&stream)
-						.lines()
-						.take_while(|line| !line.as_ref().unwrap().is_empty())
-						.count();
-					if lines_read == 0 { continue; }
-
+					let response = handler(&lines);
+					let stream = reader.get_mut();
 					for chunk in response.as_bytes().chunks(16) {
 						if shutdown_signaled.load(std::sync::atomic::Ordering::SeqCst) {
 							return;
@@ -632,6 +1011,17 @@ pub(crate) mod client_tests {
 		pub fn endpoint(&self) -> HttpEndpoint {
 			HttpEndpoint::for_host(self.address.ip().to_string()).with_port(self.address.port())
 		}
+
+		/// Returns an endpoint for `localhost` trusting only [`TEST_CA_CERTIFICATE`].
+		#[cfg(feature = "tls")]
+		pub fn tls_endpoint(&self) -> HttpEndpoint {
+			use bitcoin::hashes::hex::FromHex;
+			let ca_certificate = Vec::<u8>::from_hex(TEST_CA_CERTIFICATE).unwrap();
+			HttpEndpoint::for_host("localhost".to_string())
+				.with_port(self.address.port())
+				.with_tls_root_certificates(&[ca_certificate])
+				.unwrap()
+		}
 	}
 
 	#[test]
@@ -820,6 +1210,77 @@ pub(crate) mod client_tests {
 		}
 	}
 
+	#[tokio::test]
+	async fn reconnect_after_connection_close() {
+		let response = String::from(
+			"HTTP/1.1 200 OK\r\n\
+			 Connection: close\r\n\
+			 \r\n");
+		let server = HttpServer::responding_with(response);
+
+		let mut client = HttpClient::connect(&server.endpoint()).unwrap();
+		assert!(client.get::<BinaryResponse>("/foo", "foo.com").await.is_ok());
+		assert!(client.connection_closed);
+		assert!(client.get::<BinaryResponse>("/foo", "foo.com").await.is_ok());
+	}
+
+	#[tokio::test]
+	async fn read_response_timeout() {
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let address = listener.local_addr().unwrap();
+		let endpoint = HttpEndpoint::for_host(address.ip().to_string())
+			.with_port(address.port())
+			.with_timeout(Duration::from_millis(100));
+
+		let mut client = HttpClient::connect_to_endpoint(&endpoint).unwrap();
+		let start = std::time::Instant::now();
+		match client.get::<BinaryResponse>("/foo", "foo.com").await {
+			#[cfg(feature = "tokio")]
+			Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::TimedOut),
+			#[cfg(not(feature = "tokio"))]
+			Err(e) => assert!(e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut),
+			Ok(_) => panic!("Expected error"),
+		}
+		assert!(start.elapsed() < TCP_STREAM_TIMEOUT);
+		drop(listener);
+	}
+
+	#[cfg(feature = "tls")]
+	#[tokio::test]
+	async fn read_message_body_over_tls() {
+		let body = "foo bar baz qux".repeat(32);
+		let response = format!(
+			"HTTP/1.1 200 OK\r\n\
+			 Content-Length: {}\r\n\
+			 \r\n\
+			 {}", body.len(), body);
+		let server = HttpServer::responding_with_tls(response);
+
+		let mut client = HttpClient::connect_to_endpoint(&server.tls_endpoint()).unwrap();
+		for _ in 0..2 {
+			match client.get::<BinaryResponse>("/foo", "localhost").await {
+				Err(e) => panic!("Unexpected error: {:?}", e),
+				Ok(bytes) => assert_eq!(bytes.0, body.as_bytes()),
+			}
+		}
+	}
+
+	#[cfg(feature = "tls")]
+	#[tokio::test]
+	async fn fail_tls_handshake_with_untrusted_certificate() {
+		let server = HttpServer::responding_with_tls("HTTP/1.1 200 OK\r\n\r\n".to_string());
+
+		// The test certificate authority is not among the public root certificates.
+		let endpoint = HttpEndpoint::for_host("localhost".to_string())
+			.with_port(server.tls_endpoint().port())
+			.with_tls();
+		let mut client = HttpClient::connect_to_endpoint(&endpoint).unwrap();
+		match client.get::<BinaryResponse>("/foo", "localhost").await {
+			Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidData),
+			Ok(_) => panic!("Expected error"),
+		}
+	}
+
 	#[test]
 	fn from_bytes_into_binary_response() {
 		let bytes = b"foo";
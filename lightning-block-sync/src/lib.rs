@@ -8,9 +8,19 @@
 //! and data.
 //!
 //! Enabling feature `rest-client` or `rpc-client` allows configuring the client to fetch blocks
-//! using Bitcoin Core's REST or RPC interface, respectively.
+//! using Bitcoin Core's REST or RPC interface, respectively. Enabling feature `tls` additionally
+//! allows connecting to either interface over HTTPS, e.g., when served behind a TLS proxy.
 //!
-//! Both features support either blocking I/O using `std::net::TcpStream` or, with feature `tokio`,
+//! Defines a [`MempoolPoller`] utility for polling a [`MempoolSource`] for unconfirmed
+//! transactions, which may be used to learn of HTLC resolutions before they confirm.
+//!
+//! Enabling feature `electrum-client` allows keeping [`chain::Confirm`] implementations in sync
+//! using an Electrum server instead of a [`BlockSource`].
+//!
+//! Enabling feature `zmq-client` allows polling as soon as Bitcoin Core publishes a new block over
+//! ZMQ, rather than only at a fixed interval.
+//!
+//! All features support either blocking I/O using `std::net::TcpStream` or, with feature `tokio`,
 //! non-blocking I/O using `tokio::net::TcpStream` from inside a Tokio runtime.
 
 #![deny(broken_intra_doc_links)]
@@ -19,10 +29,15 @@
 
 #![cfg_attr(docsrs, feature(doc_auto_cfg))]
 
+#[macro_use]
+extern crate lightning;
+
 #[cfg(any(feature = "rest-client", feature = "rpc-client"))]
 pub mod http;
 
+pub mod cache;
 pub mod init;
+pub mod mempool;
 pub mod poll;
 
 #[cfg(feature = "rest-client")]
@@ -31,6 +46,12 @@ pub mod rest;
 #[cfg(feature = "rpc-client")]
 pub mod rpc;
 
+#[cfg(feature = "electrum-client")]
+pub mod electrum;
+
+#[cfg(feature = "zmq-client")]
+pub mod zmq;
+
 #[cfg(any(feature = "rest-client", feature = "rpc-client"))]
 mod convert;
 
@@ -192,6 +213,9 @@ pub trait Cache {
 }
 
 /// Unbounded cache of block headers keyed by block hash.
+///
+/// Grows with every block connected. See the [`cache`] module for bounded and persistent
+/// alternatives better suited to long-running nodes.
 pub type UnboundedCache = std::collections::HashMap<BlockHash, ValidatedBlockHeader>;
 
 impl Cache for UnboundedCache {
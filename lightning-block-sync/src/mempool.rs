@@ -0,0 +1,275 @@
+//! Utilities for polling a source of unconfirmed transactions and notifying listeners of any newly
+//! seen ones.
+//!
+//! Useful for learning about the resolution of HTLCs before the resolving transactions confirm. See
+//! [`chain::MempoolListener`] for details.
+//!
+//! [`chain::MempoolListener`]: lightning::chain::MempoolListener
+
+use crate::{AsyncBlockSourceResult, BlockSourceResult};
+
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::hash_types::Txid;
+
+use lightning::chain;
+use lightning::chain::MempoolListener;
+
+use std::collections::HashSet;
+use std::ops::Deref;
+
+/// The maximum number of transactions given to a [`chain::MempoolListener`] in a single call.
+const MEMPOOL_NOTIFICATION_BATCH_SIZE: usize = 100;
+
+/// Abstract type for retrieving unconfirmed transactions, such as those in Bitcoin Core's mempool.
+///
+/// Enabling feature `rpc-client` provides an implementation for [`RpcClient`] using the
+/// `getrawmempool` and `getrawtransaction` methods.
+///
+/// [`RpcClient`]: crate::rpc::RpcClient
+pub trait MempoolSource : Sync + Send {
+	/// Returns the txids of all transactions currently in the mempool.
+	fn get_mempool_txids<'a>(&'a self) -> AsyncBlockSourceResult<'a, Vec<Txid>>;
+
+	/// Returns the transaction with the given `txid`, which may have left the mempool since it was
+	/// last returned by [`get_mempool_txids`].
+	///
+	/// [`get_mempool_txids`]: Self::get_mempool_txids
+	fn get_mempool_transaction<'a>(&'a self, txid: &'a Txid) -> AsyncBlockSourceResult<'a, Transaction>;
+}
+
+/// Polls a [`MempoolSource`] for transactions which have entered the mempool since the last poll.
+///
+/// Only the txids of transactions currently in the mempool are remembered, so memory use is bounded
+/// by the size of the mempool. A transaction which leaves the mempool and later re-enters it is
+/// given to the listener again, as is any transaction which could not be fetched when first seen.
+pub struct MempoolPoller<M: Deref<Target=S> + Sized + Send + Sync, S: MempoolSource + ?Sized> {
+	mempool_source: M,
+	seen_txids: HashSet<Txid>,
+}
+
+impl<M: Deref<Target=S> + Sized + Send + Sync, S: MempoolSource + ?Sized> MempoolPoller<M, S> {
+	/// Creates a new poller for the given mempool source.
+	pub fn new(mempool_source: M) -> Self {
+		Self { mempool_source, seen_txids: HashSet::new() }
+	}
+
+	/// Polls the mempool source, notifying `listener` of any transactions not seen in a prior poll.
+	///
+	/// Returns the number of transactions the listener was notified of. The first poll notifies of
+	/// the entire mempool, fetching each transaction individually. Transactions which fail to be
+	/// fetched are skipped and retried on the next poll if still in the mempool.
+	pub async fn poll_mempool<L: Deref>(&mut self, listener: L) -> BlockSourceResult<usize>
+	where L::Target: chain::MempoolListener {
+		let mempool_txids = self.mempool_source.get_mempool_txids().await?;
+		let mut seen_txids = HashSet::with_capacity(mempool_txids.len());
+		let mut txdata = Vec::new();
+		let mut notified_count = 0;
+		for txid in mempool_txids {
+			if self.seen_txids.contains(&txid) {
+				seen_txids.insert(txid);
+				continue;
+			}
+
+			match self.mempool_source.get_mempool_transaction(&txid).await {
+				Ok(transaction) => {
+					seen_txids.insert(txid);
+					txdata.push(transaction);
+				},
+				Err(_) => continue,
+			}
+
+			if txdata.len() == MEMPOOL_NOTIFICATION_BATCH_SIZE {
+				listener.transactions_seen_in_mempool(&txdata);
+				notified_count += txdata.len();
+				txdata.clear();
+			}
+		}
+
+		if !txdata.is_empty() {
+			listener.transactions_seen_in_mempool(&txdata);
+			notified_count += txdata.len();
+		}
+
+		self.seen_txids = seen_txids;
+		Ok(notified_count)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::BlockSourceError;
+
+	use bitcoin::blockdata::transaction::{OutPoint, TxIn};
+
+	use std::collections::HashMap;
+	use std::sync::Mutex;
+
+	#[derive(Default)]
+	struct Mempool {
+		transactions: Mutex<Vec<Transaction>>,
+		unavailable_txids: Mutex<HashSet<Txid>>,
+		fails_listing: Mutex<bool>,
+	}
+
+	impl Mempool {
+		fn insert(&self, transaction: Transaction) {
+			self.transactions.lock().unwrap().push(transaction);
+		}
+
+		fn remove(&self, txid: &Txid) {
+			self.transactions.lock().unwrap().retain(|transaction| transaction.txid() != *txid);
+		}
+	}
+
+	impl MempoolSource for Mempool {
+		fn get_mempool_txids<'a>(&'a self) -> AsyncBlockSourceResult<'a, Vec<Txid>> {
+			Box::pin(async move {
+				if *self.fails_listing.lock().unwrap() {
+					return Err(BlockSourceError::transient("mempool unavailable"));
+				}
+				Ok(self.transactions.lock().unwrap().iter().map(|transaction| transaction.txid()).collect())
+			})
+		}
+
+		fn get_mempool_transaction<'a>(&'a self, txid: &'a Txid) -> AsyncBlockSourceResult<'a, Transaction> {
+			Box::pin(async move {
+				if self.unavailable_txids.lock().unwrap().contains(txid) {
+					return Err(BlockSourceError::transient("transaction unavailable"));
+				}
+				self.transactions.lock().unwrap().iter()
+					.find(|transaction| transaction.txid() == *txid)
+					.cloned()
+					.ok_or(BlockSourceError::persistent("transaction not found"))
+			})
+		}
+	}
+
+	#[derive(Default)]
+	struct TransactionRecorder {
+		notifications: Mutex<HashMap<Txid, usize>>,
+	}
+
+	impl TransactionRecorder {
+		fn notification_count(&self, txid: &Txid) -> usize {
+			*self.notifications.lock().unwrap().get(txid).unwrap_or(&0)
+		}
+	}
+
+	impl MempoolListener for TransactionRecorder {
+		fn transactions_seen_in_mempool(&self, txdata: &[Transaction]) {
+			assert!(txdata.len() <= MEMPOOL_NOTIFICATION_BATCH_SIZE);
+			let mut notifications = self.notifications.lock().unwrap();
+			for transaction in txdata {
+				*notifications.entry(transaction.txid()).or_insert(0) += 1;
+			}
+		}
+	}
+
+	fn transaction(n: u32) -> Transaction {
+		Transaction {
+			version: 2,
+			lock_time: 0,
+			input: vec![TxIn { previous_output: OutPoint { txid: Default::default(), vout: n }, ..Default::default() }],
+			output: Vec::new(),
+		}
+	}
+
+	#[tokio::test]
+	async fn poll_empty_mempool() {
+		let mempool = Mempool::default();
+		let listener = TransactionRecorder::default();
+		let mut poller = MempoolPoller::new(&mempool);
+		assert_eq!(poller.poll_mempool(&listener).await.unwrap(), 0);
+		assert!(listener.notifications.lock().unwrap().is_empty());
+	}
+
+	#[tokio::test]
+	async fn poll_mempool_notifies_of_new_transactions_only() {
+		let mempool = Mempool::default();
+		let listener = TransactionRecorder::default();
+		let mut poller = MempoolPoller::new(&mempool);
+
+		mempool.insert(transaction(0));
+		mempool.insert(transaction(1));
+		assert_eq!(poller.poll_mempool(&listener).await.unwrap(), 2);
+		assert_eq!(poller.poll_mempool(&listener).await.unwrap(), 0);
+
+		mempool.insert(transaction(2));
+		assert_eq!(poller.poll_mempool(&listener).await.unwrap(), 1);
+		for n in 0..3 {
+			assert_eq!(listener.notification_count(&transaction(n).txid()), 1);
+		}
+	}
+
+	#[tokio::test]
+	async fn poll_mempool_in_batches() {
+		let mempool = Mempool::default();
+		let listener = TransactionRecorder::default();
+		let mut poller = MempoolPoller::new(&mempool);
+
+		let transaction_count = 2 * MEMPOOL_NOTIFICATION_BATCH_SIZE as u32 + 1;
+		for n in 0..transaction_count {
+			mempool.insert(transaction(n));
+		}
+		assert_eq!(poller.poll_mempool(&listener).await.unwrap(), transaction_count as usize);
+		assert_eq!(listener.notifications.lock().unwrap().len(), transaction_count as usize);
+	}
+
+	#[tokio::test]
+	async fn poll_mempool_forgets_removed_transactions() {
+		let mempool = Mempool::default();
+		let listener = TransactionRecorder::default();
+		let mut poller = MempoolPoller::new(&mempool);
+
+		let txid = transaction(0).txid();
+		mempool.insert(transaction(0));
+		assert_eq!(poller.poll_mempool(&listener).await.unwrap(), 1);
+
+		mempool.remove(&txid);
+		assert_eq!(poller.poll_mempool(&listener).await.unwrap(), 0);
+		assert!(poller.seen_txids.is_empty());
+
+		mempool.insert(transaction(0));
+		assert_eq!(poller.poll_mempool(&listener).await.unwrap(), 1);
+		assert_eq!(listener.notification_count(&txid), 2);
+	}
+
+	#[tokio::test]
+	async fn poll_mempool_retries_unavailable_transactions() {
+		let mempool = Mempool::default();
+		let listener = TransactionRecorder::default();
+		let mut poller = MempoolPoller::new(&mempool);
+
+		mempool.insert(transaction(0));
+		mempool.insert(transaction(1));
+		mempool.unavailable_txids.lock().unwrap().insert(transaction(1).txid());
+		assert_eq!(poller.poll_mempool(&listener).await.unwrap(), 1);
+		assert_eq!(listener.notification_count(&transaction(1).txid()), 0);
+
+		mempool.unavailable_txids.lock().unwrap().clear();
+		assert_eq!(poller.poll_mempool(&listener).await.unwrap(), 1);
+		assert_eq!(listener.notification_count(&transaction(0).txid()), 1);
+		assert_eq!(listener.notification_count(&transaction(1).txid()), 1);
+	}
+
+	#[tokio::test]
+	async fn poll_mempool_failing_to_list_transactions() {
+		let mempool = Mempool::default();
+		let listener = TransactionRecorder::default();
+		let mut poller = MempoolPoller::new(&mempool);
+
+		mempool.insert(transaction(0));
+		assert_eq!(poller.poll_mempool(&listener).await.unwrap(), 1);
+
+		*mempool.fails_listing.lock().unwrap() = true;
+		match poller.poll_mempool(&listener).await {
+			Err(e) => assert_eq!(e.into_inner().to_string(), "mempool unavailable"),
+			Ok(_) => panic!("Expected error"),
+		}
+
+		// Previously seen transactions are still remembered after a failed poll.
+		*mempool.fails_listing.lock().unwrap() = false;
+		assert_eq!(poller.poll_mempool(&listener).await.unwrap(), 0);
+	}
+}
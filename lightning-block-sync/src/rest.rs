@@ -24,7 +24,7 @@ impl RestClient {
 	///
 	/// The endpoint should contain the REST path component (e.g., http://127.0.0.1:8332/rest).
 	pub fn new(endpoint: HttpEndpoint) -> std::io::Result<Self> {
-		let client = Mutex::new(HttpClient::connect(&endpoint)?);
+		let client = Mutex::new(HttpClient::connect_to_endpoint(&endpoint)?);
 		Ok(Self { endpoint, client })
 	}
 
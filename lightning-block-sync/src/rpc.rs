@@ -2,23 +2,29 @@
 //! endpoint.
 
 use crate::{BlockHeaderData, BlockSource, AsyncBlockSourceResult};
+use crate::mempool::MempoolSource;
 use crate::http::{HttpClient, HttpEndpoint, HttpError, JsonResponse};
 
 use bitcoin::blockdata::block::Block;
-use bitcoin::hash_types::BlockHash;
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::hash_types::{BlockHash, Txid};
 use bitcoin::hashes::hex::ToHex;
 
 use futures::lock::Mutex;
 
 use serde_json;
 
+use base64;
+
 use std::convert::TryFrom;
 use std::convert::TryInto;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
 
 /// A simple RPC client for calling methods using HTTP `POST`.
 pub struct RpcClient {
-	basic_auth: String,
+	basic_auth: std::sync::Mutex<String>,
+	cookie_path: Option<PathBuf>,
 	endpoint: HttpEndpoint,
 	client: Mutex<HttpClient>,
 	id: AtomicUsize,
@@ -29,15 +35,29 @@ impl RpcClient {
 	/// credentials should be a base64 encoding of a user name and password joined by a colon, as is
 	/// required for HTTP basic access authentication.
 	pub fn new(credentials: &str, endpoint: HttpEndpoint) -> std::io::Result<Self> {
-		let client = Mutex::new(HttpClient::connect(&endpoint)?);
+		let client = Mutex::new(HttpClient::connect_to_endpoint(&endpoint)?);
 		Ok(Self {
-			basic_auth: "Basic ".to_string() + credentials,
+			basic_auth: std::sync::Mutex::new("Basic ".to_string() + credentials),
+			cookie_path: None,
 			endpoint,
 			client,
 			id: AtomicUsize::new(0),
 		})
 	}
 
+	/// Creates a new RPC client connected to the given endpoint using the credentials in the cookie
+	/// file written by Bitcoin Core (i.e., `.cookie` in its data directory by default).
+	///
+	/// Bitcoin Core writes a new cookie each time it starts, so the cookie file is read again
+	/// whenever the credentials are rejected.
+	pub fn with_cookie_file<P: AsRef<Path>>(cookie_path: P, endpoint: HttpEndpoint) -> std::io::Result<Self> {
+		let cookie_path = cookie_path.as_ref().to_path_buf();
+		let credentials = read_cookie_file(&cookie_path)?;
+		let mut client = Self::new(&credentials, endpoint)?;
+		client.cookie_path = Some(cookie_path);
+		Ok(client)
+	}
+
 	/// Calls a method with the response encoded in JSON format and interpreted as type `T`.
 	pub async fn call_method<T>(&self, method: &str, params: &[serde_json::Value]) -> std::io::Result<T>
 	where JsonResponse: TryFrom<Vec<u8>, Error = std::io::Error> + TryInto<T, Error = std::io::Error> {
@@ -49,10 +69,19 @@ impl RpcClient {
 			"id": &self.id.fetch_add(1, Ordering::AcqRel).to_string()
 		});
 
-		let mut response = match self.client.lock().await.post::<JsonResponse>(&uri, &host, &self.basic_auth, content).await {
+		let basic_auth = self.basic_auth.lock().unwrap().clone();
+		let mut client = self.client.lock().await;
+		let mut response = match client.post::<JsonResponse>(uri, &host, &basic_auth, content.clone()).await {
 			Ok(JsonResponse(response)) => response,
 			Err(e) if e.kind() == std::io::ErrorKind::Other => {
 				match e.get_ref().unwrap().downcast_ref::<HttpError>() {
+					Some(http_error) if http_error.status_code == "401" && self.cookie_path.is_some() => {
+						// Bitcoin Core may have restarted with a new cookie, so read it again.
+						let credentials = read_cookie_file(self.cookie_path.as_ref().unwrap())?;
+						let basic_auth = "Basic ".to_string() + &credentials;
+						*self.basic_auth.lock().unwrap() = basic_auth.clone();
+						client.post::<JsonResponse>(uri, &host, &basic_auth, content).await?.0
+					},
 					Some(http_error) => match JsonResponse::try_from(http_error.contents.clone()) {
 						Ok(JsonResponse(response)) => response,
 						Err(_) => Err(e)?,
@@ -62,6 +91,7 @@ impl RpcClient {
 			},
 			Err(e) => Err(e)?,
 		};
+		drop(client);
 
 		if !response.is_object() {
 			return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "expected JSON object"));
@@ -106,11 +136,39 @@ impl BlockSource for RpcClient {
 	}
 }
 
+impl MempoolSource for RpcClient {
+	fn get_mempool_txids<'a>(&'a self) -> AsyncBlockSourceResult<'a, Vec<Txid>> {
+		Box::pin(async move {
+			Ok(self.call_method("getrawmempool", &[]).await?)
+		})
+	}
+
+	fn get_mempool_transaction<'a>(&'a self, txid: &'a Txid) -> AsyncBlockSourceResult<'a, Transaction> {
+		Box::pin(async move {
+			let txid = serde_json::json!(txid.to_hex());
+			Ok(self.call_method("getrawtransaction", &[txid]).await?)
+		})
+	}
+}
+
+/// Reads a cookie file written by Bitcoin Core, returning its contents as credentials for HTTP
+/// basic access authentication.
+fn read_cookie_file(cookie_path: &Path) -> std::io::Result<String> {
+	let cookie = std::fs::read_to_string(cookie_path)?;
+	let cookie = cookie.trim_end();
+	if !cookie.contains(':') {
+		return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid cookie file"));
+	}
+	Ok(base64::encode(cookie.as_bytes()))
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
 	use crate::http::client_tests::{HttpServer, MessageBody};
 
+	use bitcoin::hashes::Hash;
+
 	/// Credentials encoded in base64.
 	const CREDENTIALS: &'static str = "dXNlcjpwYXNzd29yZA==";
 
@@ -211,4 +269,87 @@ mod tests {
 			Ok(count) => assert_eq!(count, 654470),
 		}
 	}
+
+	#[tokio::test]
+	async fn get_mempool_txids_from_rpc() {
+		let txid = Txid::from_slice(&[1; 32]).unwrap();
+		let response = serde_json::json!({ "result": [txid.to_hex()] });
+		let server = HttpServer::responding_with_ok(MessageBody::Content(response));
+		let client = RpcClient::new(CREDENTIALS, server.endpoint()).unwrap();
+
+		match client.get_mempool_txids().await {
+			Err(e) => panic!("Unexpected error: {:?}", e),
+			Ok(txids) => assert_eq!(txids, vec![txid]),
+		}
+	}
+
+	#[tokio::test]
+	async fn get_mempool_transaction_from_rpc() {
+		let transaction = Transaction { version: 2, lock_time: 0, input: Vec::new(), output: vec![Default::default()] };
+		let response = serde_json::json!({ "result": bitcoin::consensus::encode::serialize_hex(&transaction) });
+		let server = HttpServer::responding_with_ok(MessageBody::Content(response));
+		let client = RpcClient::new(CREDENTIALS, server.endpoint()).unwrap();
+
+		match client.get_mempool_transaction(&transaction.txid()).await {
+			Err(e) => panic!("Unexpected error: {:?}", e),
+			Ok(mempool_transaction) => assert_eq!(mempool_transaction, transaction),
+		}
+	}
+
+	#[test]
+	fn read_invalid_cookie_file() {
+		let cookie_path = std::env::temp_dir().join(format!("rpc-invalid-cookie-{}", std::process::id()));
+		std::fs::write(&cookie_path, "foo").unwrap();
+		match read_cookie_file(&cookie_path) {
+			Err(e) => {
+				assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+				assert_eq!(e.get_ref().unwrap().to_string(), "invalid cookie file");
+			},
+			Ok(_) => panic!("Expected error"),
+		}
+		std::fs::remove_file(&cookie_path).unwrap();
+	}
+
+	#[tokio::test]
+	async fn call_method_rereading_cookie_file() {
+		let cookie_path = std::env::temp_dir().join(format!("rpc-cookie-{}", std::process::id()));
+		std::fs::write(&cookie_path, "__cookie__:foo\n").unwrap();
+
+		// Accept only the credentials currently in the cookie file, as Bitcoin Core would.
+		let server_cookie_path = cookie_path.clone();
+		let server = HttpServer::responding_with_handler(move |request| {
+			let credentials = read_cookie_file(&server_cookie_path).unwrap();
+			let authorization = format!("Authorization: Basic {}", credentials);
+			if request.contains(&authorization) {
+				let body = serde_json::json!({ "result": 654470 }).to_string();
+				format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body)
+			} else {
+				"HTTP/1.1 401 Unauthorized\r\n\r\n".to_string()
+			}
+		});
+		let client = RpcClient::with_cookie_file(&cookie_path, server.endpoint()).unwrap();
+		assert_eq!(client.call_method::<u64>("getblockcount", &[]).await.unwrap(), 654470);
+
+		// Simulate Bitcoin Core restarting with a new cookie.
+		std::fs::write(&cookie_path, "__cookie__:bar\n").unwrap();
+		assert_eq!(client.call_method::<u64>("getblockcount", &[]).await.unwrap(), 654470);
+		assert_eq!(*client.basic_auth.lock().unwrap(), format!("Basic {}", base64::encode(b"__cookie__:bar")));
+
+		std::fs::remove_file(&cookie_path).unwrap();
+	}
+
+	#[tokio::test]
+	async fn call_method_with_rejected_credentials() {
+		let server = HttpServer::responding_with_handler(|_| "HTTP/1.1 401 Unauthorized\r\n\r\n".to_string());
+		let client = RpcClient::new(CREDENTIALS, server.endpoint()).unwrap();
+
+		match client.call_method::<u64>("getblockcount", &[]).await {
+			Err(e) => {
+				assert_eq!(e.kind(), std::io::ErrorKind::Other);
+				let http_error = e.into_inner().unwrap().downcast::<HttpError>().unwrap();
+				assert_eq!(http_error.status_code, "401");
+			},
+			Ok(_) => panic!("Expected error"),
+		}
+	}
 }
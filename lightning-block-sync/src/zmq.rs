@@ -0,0 +1,650 @@
+//! Push-based block notifications using Bitcoin Core's ZMQ interface, allowing for polling a block
+//! source as soon as a new block is available rather than only on a timer.
+//!
+//! Bitcoin Core publishes notifications on a ZMQ `PUB` socket when configured with, e.g.,
+//! `-zmqpubhashblock=tcp://127.0.0.1:28332`. A [`ZmqBlockNotifier`] subscribes to such a socket
+//! and resolves [`ZmqBlockNotifier::wait_for_poll`] as soon as a block notification arrives. As ZMQ
+//! notifications are not guaranteed to be delivered, it also resolves after a fallback interval
+//! without notifications, so that polling continues as before should notifications stop arriving.
+//!
+//! A minimal implementation of the [ZMTP 3.0] wire protocol is used for subscribing, supporting
+//! only the `NULL` security mechanism as used by Bitcoin Core.
+//!
+//! [ZMTP 3.0]: https://rfc.zeromq.org/spec/23/
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::consensus::encode;
+use bitcoin::hash_types::BlockHash;
+use bitcoin::hashes::Hash;
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+#[cfg(feature = "tokio")]
+use tokio::net::TcpStream;
+
+#[cfg(not(feature = "tokio"))]
+use std::io::{Read, Write};
+#[cfg(not(feature = "tokio"))]
+use std::net::TcpStream;
+
+/// Timeout for connecting and completing the ZMTP handshake.
+const TCP_STREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum ZMTP frame size in bytes. Enough for a serialized block published on `rawblock`.
+const MAX_ZMTP_FRAME_SIZE: u64 = 4_000_000 + 32_000;
+
+/// Size of the ZMTP greeting in bytes.
+const ZMTP_GREETING_SIZE: usize = 64;
+
+/// Frame flag indicating more frames follow in the same message.
+const ZMTP_FLAG_MORE: u8 = 0x01;
+
+/// Frame flag indicating an 8-byte frame size.
+const ZMTP_FLAG_LONG: u8 = 0x02;
+
+/// Frame flag indicating a command frame.
+const ZMTP_FLAG_COMMAND: u8 = 0x04;
+
+/// A block notification topic published by Bitcoin Core.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ZmqTopic {
+	/// Publishes the hash of each block connected to the best chain. Enabled in Bitcoin Core with
+	/// `-zmqpubhashblock`.
+	HashBlock,
+
+	/// Publishes each block connected to the best chain in full. Enabled in Bitcoin Core with
+	/// `-zmqpubrawblock`.
+	RawBlock,
+}
+
+impl ZmqTopic {
+	fn name(&self) -> &'static [u8] {
+		match self {
+			ZmqTopic::HashBlock => b"hashblock",
+			ZmqTopic::RawBlock => b"rawblock",
+		}
+	}
+
+	/// Parses the block hash from the body of a notification for the topic.
+	fn parse_block_hash(&self, body: &[u8]) -> Option<BlockHash> {
+		match self {
+			ZmqTopic::HashBlock => {
+				// The hash is published in the reversed byte order used by the RPC interface.
+				if body.len() != 32 {
+					return None;
+				}
+				let mut bytes = [0; 32];
+				bytes.copy_from_slice(body);
+				bytes.reverse();
+				Some(BlockHash::from_inner(bytes))
+			},
+			ZmqTopic::RawBlock => {
+				if body.len() < 80 {
+					return None;
+				}
+				encode::deserialize::<BlockHeader>(&body[..80]).ok().map(|header| header.block_hash())
+			},
+		}
+	}
+}
+
+/// The reason [`ZmqBlockNotifier::wait_for_poll`] resolved.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PollTrigger {
+	/// A notification for a block with the given hash was received.
+	Notification(BlockHash),
+
+	/// No notification was received within the fallback interval, or the subscription was
+	/// interrupted and notifications may have been missed.
+	Fallback,
+}
+
+/// Waits for block notifications published by Bitcoin Core over ZMQ, falling back to a fixed
+/// interval when none arrive.
+///
+/// Intended to drive calls to [`SpvClient::poll_best_tip`] as follows:
+///
+/// ```
+/// use lightning::chain;
+/// use lightning_block_sync::{Cache, SpvClient};
+/// use lightning_block_sync::poll::Poll;
+/// use lightning_block_sync::zmq::ZmqBlockNotifier;
+///
+/// use std::ops::Deref;
+///
+/// async fn sync_on_notifications<P: Poll, C: Cache, L: Deref>(
+/// 	mut spv_client: SpvClient<'_, P, C, L>,
+/// 	mut notifier: ZmqBlockNotifier,
+/// ) where L::Target: chain::Listen {
+/// 	loop {
+/// 		notifier.wait_for_poll().await;
+/// 		let _ = spv_client.poll_best_tip().await;
+/// 	}
+/// }
+/// ```
+///
+/// The subscription is made lazily and re-made after any disconnection, e.g., if Bitcoin Core
+/// restarts.
+///
+/// [`SpvClient::poll_best_tip`]: crate::SpvClient::poll_best_tip
+pub struct ZmqBlockNotifier {
+	address: SocketAddr,
+	topic: ZmqTopic,
+	fallback_interval: Duration,
+	subscriber: Option<ZmqSubscriber>,
+}
+
+impl ZmqBlockNotifier {
+	/// Creates a notifier for the ZMQ endpoint at which Bitcoin Core publishes `topic`.
+	///
+	/// The `fallback_interval` should be the interval at which the block source would otherwise be
+	/// polled.
+	pub fn new<E: ToSocketAddrs>(endpoint: E, topic: ZmqTopic, fallback_interval: Duration) -> std::io::Result<Self> {
+		let address = match endpoint.to_socket_addrs()?.next() {
+			None => {
+				return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "could not resolve to any addresses"));
+			},
+			Some(address) => address,
+		};
+		Ok(Self { address, topic, fallback_interval, subscriber: None })
+	}
+
+	/// Returns whether there is currently an active subscription.
+	pub fn is_subscribed(&self) -> bool {
+		self.subscriber.is_some()
+	}
+
+	/// Waits until a block notification is received or the fallback interval elapses, whichever
+	/// happens first.
+	///
+	/// Resolves immediately with [`PollTrigger::Fallback`] if an active subscription is interrupted,
+	/// as notifications may have been missed. Subscribing is attempted again on the next call.
+	pub async fn wait_for_poll(&mut self) -> PollTrigger {
+		let deadline = Instant::now() + self.fallback_interval;
+		if self.subscriber.is_none() {
+			match ZmqSubscriber::connect(self.address, self.topic).await {
+				Ok(subscriber) => self.subscriber = Some(subscriber),
+				Err(_) => {
+					sleep_until(deadline).await;
+					return PollTrigger::Fallback;
+				},
+			}
+		}
+
+		let subscriber = self.subscriber.as_mut().unwrap();
+		loop {
+			match subscriber.receive_message(deadline).await {
+				Ok(None) => return PollTrigger::Fallback,
+				Ok(Some(frames)) => {
+					if frames.len() < 2 || frames[0] != self.topic.name() {
+						continue;
+					}
+					if let Some(block_hash) = self.topic.parse_block_hash(&frames[1]) {
+						return PollTrigger::Notification(block_hash);
+					}
+				},
+				Err(_) => {
+					self.subscriber = None;
+					return PollTrigger::Fallback;
+				},
+			}
+		}
+	}
+}
+
+async fn sleep_until(deadline: Instant) {
+	let duration = deadline.saturating_duration_since(Instant::now());
+	#[cfg(feature = "tokio")]
+	tokio::time::sleep(duration).await;
+	#[cfg(not(feature = "tokio"))]
+	std::thread::sleep(duration);
+}
+
+/// A ZMTP `SUB` socket connected to a single publisher.
+struct ZmqSubscriber {
+	stream: TcpStream,
+	buffer: Vec<u8>,
+}
+
+impl ZmqSubscriber {
+	/// Connects to the publisher at `address`, performs the ZMTP handshake, and subscribes to
+	/// `topic`.
+	async fn connect(address: SocketAddr, topic: ZmqTopic) -> std::io::Result<Self> {
+		let stream = std::net::TcpStream::connect_timeout(&address, TCP_STREAM_TIMEOUT)?;
+		stream.set_write_timeout(Some(TCP_STREAM_TIMEOUT))?;
+
+		#[cfg(feature = "tokio")]
+		let stream = {
+			stream.set_nonblocking(true)?;
+			TcpStream::from_std(stream)?
+		};
+
+		let mut subscriber = Self { stream, buffer: Vec::new() };
+		let deadline = Instant::now() + TCP_STREAM_TIMEOUT;
+
+		subscriber.write(&greeting()).await?;
+		let greeting = subscriber.read_exact(ZMTP_GREETING_SIZE, deadline).await?;
+		check_greeting(&greeting)?;
+
+		subscriber.write(&encode_frame(ZMTP_FLAG_COMMAND, &ready_command())).await?;
+		let (flags, command) = subscriber.read_frame(deadline).await?
+			.ok_or(std::io::Error::new(std::io::ErrorKind::TimedOut, "handshake timed out"))?;
+		if flags & ZMTP_FLAG_COMMAND == 0 {
+			return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "expected READY command"));
+		}
+		check_ready_command(&command)?;
+
+		// ZMTP 3.0 subscriptions are sent as a message consisting of 0x01 followed by the topic.
+		let mut subscription = vec![0x01];
+		subscription.extend_from_slice(topic.name());
+		subscriber.write(&encode_frame(0, &subscription)).await?;
+
+		Ok(subscriber)
+	}
+
+	/// Reads the next message as a list of frames, skipping any command frames. Returns `None` if
+	/// no complete message is received before `deadline`.
+	async fn receive_message(&mut self, deadline: Instant) -> std::io::Result<Option<Vec<Vec<u8>>>> {
+		let mut frames = Vec::new();
+		loop {
+			let (flags, body) = match self.read_frame(deadline).await? {
+				None => {
+					// A partially received message is discarded, which is fine given notifications
+					// only serve as a hint to poll.
+					return Ok(None);
+				},
+				Some(frame) => frame,
+			};
+			if flags & ZMTP_FLAG_COMMAND != 0 {
+				continue;
+			}
+			frames.push(body);
+			if flags & ZMTP_FLAG_MORE == 0 {
+				return Ok(Some(frames));
+			}
+		}
+	}
+
+	/// Reads the next frame as its flags and body. Returns `None` if the frame is not fully received
+	/// before `deadline`, in which case any received bytes remain buffered.
+	async fn read_frame(&mut self, deadline: Instant) -> std::io::Result<Option<(u8, Vec<u8>)>> {
+		loop {
+			if let Some((flags, header_size, body_size)) = parse_frame_header(&self.buffer)? {
+				let frame_size = header_size + body_size;
+				if self.buffer.len() >= frame_size {
+					let body = self.buffer[header_size..frame_size].to_vec();
+					self.buffer.drain(..frame_size);
+					return Ok(Some((flags, body)));
+				}
+			}
+			if !self.fill_buffer(deadline).await? {
+				return Ok(None);
+			}
+		}
+	}
+
+	/// Reads exactly `size` bytes, failing if they are not received before `deadline`.
+	async fn read_exact(&mut self, size: usize, deadline: Instant) -> std::io::Result<Vec<u8>> {
+		while self.buffer.len() < size {
+			if !self.fill_buffer(deadline).await? {
+				return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "handshake timed out"));
+			}
+		}
+		Ok(self.buffer.drain(..size).collect())
+	}
+
+	/// Reads available bytes into the buffer. Returns `false` if none are available before
+	/// `deadline`.
+	async fn fill_buffer(&mut self, deadline: Instant) -> std::io::Result<bool> {
+		let timeout = deadline.saturating_duration_since(Instant::now());
+		if timeout == Duration::from_secs(0) {
+			return Ok(false);
+		}
+
+		let mut bytes = [0; 8192];
+		#[cfg(feature = "tokio")]
+		let read_res = match tokio::time::timeout(timeout, self.stream.read(&mut bytes)).await {
+			Err(_) => return Ok(false),
+			Ok(read_res) => read_res,
+		};
+		#[cfg(not(feature = "tokio"))]
+		let read_res = {
+			self.stream.set_read_timeout(Some(timeout))?;
+			self.stream.read(&mut bytes)
+		};
+
+		match read_res {
+			Ok(0) => Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "connection closed")),
+			Ok(bytes_read) => {
+				self.buffer.extend_from_slice(&bytes[..bytes_read]);
+				Ok(true)
+			},
+			Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => {
+				Ok(false)
+			},
+			Err(e) => Err(e),
+		}
+	}
+
+	async fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+		#[cfg(feature = "tokio")]
+		{
+			self.stream.write_all(bytes).await?;
+			self.stream.flush().await
+		}
+		#[cfg(not(feature = "tokio"))]
+		{
+			self.stream.write_all(bytes)?;
+			self.stream.flush()
+		}
+	}
+}
+
+/// Returns a ZMTP 3.0 greeting for the `NULL` security mechanism in the client role.
+fn greeting() -> [u8; ZMTP_GREETING_SIZE] {
+	let mut greeting = [0; ZMTP_GREETING_SIZE];
+	greeting[0] = 0xff;
+	greeting[9] = 0x7f;
+	greeting[10] = 3;
+	greeting[11] = 0;
+	greeting[12..16].copy_from_slice(b"NULL");
+	greeting
+}
+
+fn check_greeting(greeting: &[u8]) -> std::io::Result<()> {
+	if greeting[0] != 0xff || greeting[9] & 0x01 != 0x01 {
+		return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid ZMTP signature"));
+	}
+	if greeting[10] < 3 {
+		return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "unsupported ZMTP version"));
+	}
+	if greeting[12..32] != self::greeting()[12..32] {
+		return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "unsupported security mechanism"));
+	}
+	Ok(())
+}
+
+/// Returns the body of a `READY` command for a `SUB` socket.
+fn ready_command() -> Vec<u8> {
+	let mut command = Vec::new();
+	command.push(5);
+	command.extend_from_slice(b"READY");
+	command.push(11);
+	command.extend_from_slice(b"Socket-Type");
+	command.extend_from_slice(&3u32.to_be_bytes());
+	command.extend_from_slice(b"SUB");
+	command
+}
+
+/// Checks that the body of a command is a `READY` command for a `PUB` or `XPUB` socket.
+fn check_ready_command(command: &[u8]) -> std::io::Result<()> {
+	if command.len() < 6 || &command[..6] != b"\x05READY" {
+		return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "expected READY command"));
+	}
+
+	let mut properties = &command[6..];
+	while !properties.is_empty() {
+		let name_size = properties[0] as usize;
+		if properties.len() < 1 + name_size + 4 {
+			break;
+		}
+		let name = &properties[1..1 + name_size];
+		let mut value_size_bytes = [0; 4];
+		value_size_bytes.copy_from_slice(&properties[1 + name_size..1 + name_size + 4]);
+		let value_size = u32::from_be_bytes(value_size_bytes) as usize;
+		let value_offset = 1 + name_size + 4;
+		if properties.len() < value_offset + value_size {
+			break;
+		}
+		let value = &properties[value_offset..value_offset + value_size];
+		if name.eq_ignore_ascii_case(b"Socket-Type") {
+			if value == b"PUB" || value == b"XPUB" {
+				return Ok(());
+			}
+			return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "incompatible socket type"));
+		}
+		properties = &properties[value_offset + value_size..];
+	}
+	Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "missing socket type"))
+}
+
+/// Encodes a frame with the given flags and body, setting the long flag if needed.
+fn encode_frame(flags: u8, body: &[u8]) -> Vec<u8> {
+	let mut frame = Vec::with_capacity(body.len() + 9);
+	if body.len() > u8::MAX as usize {
+		frame.push(flags | ZMTP_FLAG_LONG);
+		frame.extend_from_slice(&(body.len() as u64).to_be_bytes());
+	} else {
+		frame.push(flags);
+		frame.push(body.len() as u8);
+	}
+	frame.extend_from_slice(body);
+	frame
+}
+
+/// Parses a frame header from the start of `bytes` as its flags, header size, and body size.
+/// Returns `None` if the header is incomplete.
+fn parse_frame_header(bytes: &[u8]) -> std::io::Result<Option<(u8, usize, usize)>> {
+	if bytes.len() < 2 {
+		return Ok(None);
+	}
+
+	let flags = bytes[0];
+	if flags & ZMTP_FLAG_LONG == 0 {
+		return Ok(Some((flags, 2, bytes[1] as usize)));
+	}
+
+	if bytes.len() < 9 {
+		return Ok(None);
+	}
+	let mut size_bytes = [0; 8];
+	size_bytes.copy_from_slice(&bytes[1..9]);
+	let size = u64::from_be_bytes(size_bytes);
+	if size > MAX_ZMTP_FRAME_SIZE {
+		return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "out of range"));
+	}
+	Ok(Some((flags, 9, size as usize)))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::hashes::hex::FromHex;
+	use bitcoin::network::constants::Network;
+
+	use std::io::{Read as StdRead, Write as StdWrite};
+	use std::sync::mpsc;
+
+	/// A local ZMTP publisher standing in for Bitcoin Core.
+	///
+	/// Publishes messages sent over its channel to the connected subscriber, or disconnects the
+	/// subscriber when sent `None`.
+	struct ZmqPublisher {
+		address: SocketAddr,
+		sender: mpsc::Sender<Option<Vec<Vec<u8>>>>,
+	}
+
+	impl ZmqPublisher {
+		fn new(topic: ZmqTopic) -> Self {
+			let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+			let address = listener.local_addr().unwrap();
+			let (sender, receiver) = mpsc::channel::<Option<Vec<Vec<u8>>>>();
+			std::thread::spawn(move || {
+				for stream in listener.incoming() {
+					let mut stream = stream.unwrap();
+					Self::handshake(&mut stream, topic);
+					loop {
+						match receiver.recv() {
+							Err(_) => return,
+							Ok(None) => break,
+							Ok(Some(frames)) => {
+								for (i, frame) in frames.iter().enumerate() {
+									let flags = if i + 1 < frames.len() { ZMTP_FLAG_MORE } else { 0 };
+									stream.write_all(&encode_frame(flags, frame)).unwrap();
+								}
+							},
+						}
+					}
+				}
+			});
+			Self { address, sender }
+		}
+
+		fn handshake(stream: &mut std::net::TcpStream, topic: ZmqTopic) {
+			let mut greeting = [0; ZMTP_GREETING_SIZE];
+			stream.read_exact(&mut greeting).unwrap();
+			check_greeting(&greeting).unwrap();
+			stream.write_all(&super::greeting()).unwrap();
+
+			let command = Self::read_frame(stream);
+			assert_eq!(command, encode_frame(ZMTP_FLAG_COMMAND, &ready_command()));
+			let mut ready = vec![5];
+			ready.extend_from_slice(b"READY");
+			ready.push(11);
+			ready.extend_from_slice(b"Socket-Type");
+			ready.extend_from_slice(&3u32.to_be_bytes());
+			ready.extend_from_slice(b"PUB");
+			stream.write_all(&encode_frame(ZMTP_FLAG_COMMAND, &ready)).unwrap();
+
+			let mut subscription = vec![0x01];
+			subscription.extend_from_slice(topic.name());
+			assert_eq!(Self::read_frame(stream), encode_frame(0, &subscription));
+		}
+
+		fn read_frame(stream: &mut std::net::TcpStream) -> Vec<u8> {
+			let mut header = [0; 2];
+			stream.read_exact(&mut header).unwrap();
+			assert_eq!(header[0] & ZMTP_FLAG_LONG, 0);
+			let mut body = vec![0; header[1] as usize];
+			stream.read_exact(&mut body).unwrap();
+			let mut frame = header.to_vec();
+			frame.extend_from_slice(&body);
+			frame
+		}
+
+		fn publish(&self, topic: &[u8], body: Vec<u8>, sequence: u32) {
+			let frames = vec![topic.to_vec(), body, sequence.to_le_bytes().to_vec()];
+			self.sender.send(Some(frames)).unwrap();
+		}
+
+		fn disconnect(&self) {
+			self.sender.send(None).unwrap();
+		}
+	}
+
+	fn hashblock_body(block_hash: &BlockHash) -> Vec<u8> {
+		let mut body = block_hash.into_inner().to_vec();
+		body.reverse();
+		body
+	}
+
+	#[tokio::test]
+	async fn wait_for_hashblock_notification() {
+		let publisher = ZmqPublisher::new(ZmqTopic::HashBlock);
+		let mut notifier = ZmqBlockNotifier::new(publisher.address, ZmqTopic::HashBlock, Duration::from_secs(10)).unwrap();
+
+		let block_hash = BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap();
+		assert_eq!(hashblock_body(&block_hash)[0], 0x00);
+		publisher.publish(b"hashblock", hashblock_body(&block_hash), 0);
+		assert_eq!(notifier.wait_for_poll().await, PollTrigger::Notification(block_hash));
+		assert!(notifier.is_subscribed());
+	}
+
+	#[tokio::test]
+	async fn wait_for_rawblock_notification() {
+		let publisher = ZmqPublisher::new(ZmqTopic::RawBlock);
+		let mut notifier = ZmqBlockNotifier::new(publisher.address, ZmqTopic::RawBlock, Duration::from_secs(10)).unwrap();
+
+		let block = genesis_block(Network::Bitcoin);
+		publisher.publish(b"rawblock", encode::serialize(&block), 0);
+		assert_eq!(notifier.wait_for_poll().await, PollTrigger::Notification(block.block_hash()));
+	}
+
+	#[tokio::test]
+	async fn wait_for_notification_ignoring_other_topics() {
+		let publisher = ZmqPublisher::new(ZmqTopic::HashBlock);
+		let mut notifier = ZmqBlockNotifier::new(publisher.address, ZmqTopic::HashBlock, Duration::from_secs(10)).unwrap();
+
+		let block_hash = genesis_block(Network::Bitcoin).block_hash();
+		publisher.publish(b"hashtx", vec![0; 32], 0);
+		publisher.publish(b"hashblock", vec![0; 31], 1);
+		publisher.publish(b"hashblock", hashblock_body(&block_hash), 2);
+		assert_eq!(notifier.wait_for_poll().await, PollTrigger::Notification(block_hash));
+	}
+
+	#[tokio::test]
+	async fn wait_for_fallback_without_notifications() {
+		let publisher = ZmqPublisher::new(ZmqTopic::HashBlock);
+		let mut notifier = ZmqBlockNotifier::new(publisher.address, ZmqTopic::HashBlock, Duration::from_millis(100)).unwrap();
+
+		let start = Instant::now();
+		assert_eq!(notifier.wait_for_poll().await, PollTrigger::Fallback);
+		assert!(start.elapsed() >= Duration::from_millis(100));
+		assert!(notifier.is_subscribed());
+	}
+
+	#[tokio::test]
+	async fn wait_for_fallback_without_publisher() {
+		let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+		let mut notifier = ZmqBlockNotifier::new(address, ZmqTopic::HashBlock, Duration::from_millis(100)).unwrap();
+
+		let start = Instant::now();
+		assert_eq!(notifier.wait_for_poll().await, PollTrigger::Fallback);
+		assert!(start.elapsed() >= Duration::from_millis(100));
+		assert!(!notifier.is_subscribed());
+	}
+
+	#[tokio::test]
+	async fn resubscribe_after_disconnection() {
+		let publisher = ZmqPublisher::new(ZmqTopic::HashBlock);
+		let mut notifier = ZmqBlockNotifier::new(publisher.address, ZmqTopic::HashBlock, Duration::from_secs(10)).unwrap();
+
+		let block_hash = genesis_block(Network::Bitcoin).block_hash();
+		publisher.publish(b"hashblock", hashblock_body(&block_hash), 0);
+		assert_eq!(notifier.wait_for_poll().await, PollTrigger::Notification(block_hash));
+
+		// A disconnection should trigger a poll immediately since notifications may be missed.
+		publisher.disconnect();
+		let start = Instant::now();
+		assert_eq!(notifier.wait_for_poll().await, PollTrigger::Fallback);
+		assert!(start.elapsed() < Duration::from_secs(10));
+		assert!(!notifier.is_subscribed());
+
+		let block_hash = genesis_block(Network::Testnet).block_hash();
+		publisher.publish(b"hashblock", hashblock_body(&block_hash), 1);
+		assert_eq!(notifier.wait_for_poll().await, PollTrigger::Notification(block_hash));
+		assert!(notifier.is_subscribed());
+	}
+
+	#[test]
+	fn reject_incompatible_socket_type() {
+		let mut command = vec![5];
+		command.extend_from_slice(b"READY");
+		command.push(11);
+		command.extend_from_slice(b"Socket-Type");
+		command.extend_from_slice(&3u32.to_be_bytes());
+		command.extend_from_slice(b"REP");
+		match check_ready_command(&command) {
+			Err(e) => {
+				assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+				assert_eq!(e.get_ref().unwrap().to_string(), "incompatible socket type");
+			},
+			Ok(_) => panic!("Expected error"),
+		}
+	}
+
+	#[test]
+	fn reject_too_large_frame() {
+		let mut header = vec![ZMTP_FLAG_LONG];
+		header.extend_from_slice(&(MAX_ZMTP_FRAME_SIZE + 1).to_be_bytes());
+		match parse_frame_header(&header) {
+			Err(e) => assert_eq!(e.get_ref().unwrap().to_string(), "out of range"),
+			Ok(_) => panic!("Expected error"),
+		}
+	}
+}
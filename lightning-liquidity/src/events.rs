@@ -0,0 +1,58 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Events are returned from the [`LiquidityManager`] to notify the user of responses received
+//! from LSPs, or of requests received from clients which need a decision from the user.
+//!
+//! Events are retrieved via [`LiquidityManager::get_and_clear_pending_events`] and should be
+//! polled regularly, e.g. after calling [`PeerManager::process_events`].
+//!
+//! [`LiquidityManager`]: crate::LiquidityManager
+//! [`LiquidityManager::get_and_clear_pending_events`]: crate::LiquidityManager::get_and_clear_pending_events
+//! [`PeerManager::process_events`]: lightning::ln::peer_handler::PeerManager::process_events
+
+use crate::lsps0::client::LSPS0ClientEvent;
+use crate::lsps1::event::{LSPS1ClientEvent, LSPS1ServiceEvent};
+use crate::lsps2::event::{LSPS2ClientEvent, LSPS2ServiceEvent};
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// An event which the user should handle.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+	/// An LSPS0 client event.
+	LSPS0Client(LSPS0ClientEvent),
+	/// An LSPS1 (channel purchase) client event.
+	LSPS1Client(LSPS1ClientEvent),
+	/// An LSPS1 (channel purchase) service event.
+	LSPS1Service(LSPS1ServiceEvent),
+	/// An LSPS2 (JIT channel) client event.
+	LSPS2Client(LSPS2ClientEvent),
+	/// An LSPS2 (JIT channel) service event.
+	LSPS2Service(LSPS2ServiceEvent),
+}
+
+pub(crate) struct EventQueue {
+	queue: Mutex<VecDeque<Event>>,
+}
+
+impl EventQueue {
+	pub(crate) fn new() -> Self {
+		Self { queue: Mutex::new(VecDeque::new()) }
+	}
+
+	pub(crate) fn enqueue(&self, event: Event) {
+		self.queue.lock().unwrap().push_back(event);
+	}
+
+	pub(crate) fn get_and_clear_pending_events(&self) -> Vec<Event> {
+		self.queue.lock().unwrap().drain(..).collect()
+	}
+}
@@ -0,0 +1,83 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! An implementation of the Lightning Service Provider (LSP) specifications, which allow wallets
+//! to buy inbound liquidity from LSPs in a standardized way.
+//!
+//! The following protocols are supported, in both the client and the service (LSP) role:
+//! * LSPS0: the JSON-RPC 2.0 transport, sent as custom messages of type 37913, which the other
+//!   protocols build on, as well as the discovery of the protocols a peer supports.
+//! * LSPS1: buying a channel, paid for ahead of the LSP opening it.
+//! * LSPS2: buying a just-in-time (JIT) channel, opened by the LSP once a payment to the client
+//!   arrives, with the opening fee deducted from the payment.
+//!
+//! The [`LiquidityManager`] implements [`CustomMessageHandler`] and must be passed to the
+//! [`PeerManager`] (possibly alongside other custom message handlers, via
+//! [`composite_custom_message_handler`]). Requests are made via the handler of each protocol,
+//! while responses, and requests which need a decision from the user, are surfaced as [`Event`]s.
+//!
+//! # Example
+//! ```
+//! use bitcoin::secp256k1::PublicKey;
+//! use lightning_liquidity::{LiquidityClientConfig, LiquidityManager};
+//! use lightning_liquidity::events::Event;
+//! use lightning_liquidity::lsps2::client::LSPS2ClientConfig;
+//! use lightning_liquidity::lsps2::event::LSPS2ClientEvent;
+//!
+//! fn new_liquidity_manager(entropy: &[u8; 32]) -> LiquidityManager {
+//! 	let client_config = LiquidityClientConfig {
+//! 		lsps2_client_config: Some(LSPS2ClientConfig::default()),
+//! 		..Default::default()
+//! 	};
+//! 	LiquidityManager::new(entropy, None, Some(client_config))
+//! }
+//!
+//! fn buy_jit_channel(liquidity_manager: &LiquidityManager, lsp_node_id: &PublicKey, payment_size_msat: u64) {
+//! 	let client = liquidity_manager.lsps2_client_handler().unwrap();
+//! 	client.request_opening_params(lsp_node_id, None);
+//!
+//! 	// ...once the request has been sent and responded to via the PeerManager:
+//! 	for event in liquidity_manager.get_and_clear_pending_events() {
+//! 		match event {
+//! 			Event::LSPS2Client(LSPS2ClientEvent::OpeningParametersReady {
+//! 				counterparty_node_id, mut opening_fee_params_menu, ..
+//! 			}) if !opening_fee_params_menu.is_empty() => {
+//! 				// The menu is ordered by increasing fees, so pick the cheapest entry.
+//! 				let params = opening_fee_params_menu.remove(0);
+//! 				client.select_opening_params(&counterparty_node_id, Some(payment_size_msat), params).unwrap();
+//! 			},
+//! 			Event::LSPS2Client(LSPS2ClientEvent::InvoiceParametersReady { intercept_scid, cltv_expiry_delta, .. }) => {
+//! 				// Create an invoice with a route hint through the LSP using these parameters.
+//! 			},
+//! 			_ => {},
+//! 		}
+//! 	}
+//! }
+//! ```
+//!
+//! [`CustomMessageHandler`]: lightning::ln::peer_handler::CustomMessageHandler
+//! [`PeerManager`]: lightning::ln::peer_handler::PeerManager
+//! [`composite_custom_message_handler`]: lightning::composite_custom_message_handler
+//! [`Event`]: crate::events::Event
+
+#![deny(broken_intra_doc_links)]
+#![deny(missing_docs)]
+#![deny(unsafe_code)]
+
+#![cfg_attr(docsrs, feature(doc_auto_cfg))]
+
+pub mod events;
+pub mod lsps0;
+pub mod lsps1;
+pub mod lsps2;
+mod manager;
+mod message_queue;
+pub mod utils;
+
+pub use manager::{LiquidityClientConfig, LiquidityManager, LiquidityServiceConfig, LSPS_FEATURE_BIT};
@@ -0,0 +1,78 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Contains the client side of LSPS0, used to discover the protocols supported by a peer.
+
+use crate::events::{Event, EventQueue};
+use crate::lsps0::msgs::{LSPS0Message, LSPS0Request, LSPS0Response, LSPSMessage, ListProtocolsRequest, RequestId, ResponseError};
+use crate::message_queue::MessageQueue;
+use crate::utils::RequestIdGenerator;
+
+use bitcoin::secp256k1::PublicKey;
+
+use std::sync::Arc;
+
+/// An event which an LSPS0 client should handle.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LSPS0ClientEvent {
+	/// A peer told us which LSPS protocols it supports, in response to
+	/// [`LSPS0ClientHandler::list_protocols`].
+	ListProtocolsResponse {
+		/// The node id of the peer.
+		counterparty_node_id: PublicKey,
+		/// The numbers of the LSPS protocols the peer supports, e.g. 1 for LSPS1.
+		protocols: Vec<u16>,
+	},
+	/// A peer failed to tell us which LSPS protocols it supports, e.g. because it does not
+	/// support LSPS at all.
+	ListProtocolsFailed {
+		/// The node id of the peer.
+		counterparty_node_id: PublicKey,
+		/// The error returned by the peer.
+		error: ResponseError,
+	},
+}
+
+/// The main object allowing to discover the LSPS protocols supported by a peer.
+pub struct LSPS0ClientHandler {
+	pending_messages: Arc<MessageQueue>,
+	pending_events: Arc<EventQueue>,
+	request_id_generator: Arc<RequestIdGenerator>,
+}
+
+impl LSPS0ClientHandler {
+	pub(crate) fn new(pending_messages: Arc<MessageQueue>, pending_events: Arc<EventQueue>,
+		request_id_generator: Arc<RequestIdGenerator>) -> Self {
+		Self { pending_messages, pending_events, request_id_generator }
+	}
+
+	/// Asks the given peer which LSPS protocols it supports.
+	///
+	/// The result is returned as an [`LSPS0ClientEvent::ListProtocolsResponse`] event.
+	pub fn list_protocols(&self, counterparty_node_id: &PublicKey) -> RequestId {
+		let request_id = RequestId(self.request_id_generator.next_id());
+		self.pending_messages.enqueue(counterparty_node_id, LSPSMessage::LSPS0(LSPS0Message::Request(
+			request_id.clone(), LSPS0Request::ListProtocols(ListProtocolsRequest {}))));
+		request_id
+	}
+
+	pub(crate) fn handle_response(&self, response: LSPS0Response, counterparty_node_id: &PublicKey) {
+		let event = match response {
+			LSPS0Response::ListProtocols(response) => LSPS0ClientEvent::ListProtocolsResponse {
+				counterparty_node_id: *counterparty_node_id,
+				protocols: response.protocols,
+			},
+			LSPS0Response::ListProtocolsError(error) => LSPS0ClientEvent::ListProtocolsFailed {
+				counterparty_node_id: *counterparty_node_id,
+				error,
+			},
+		};
+		self.pending_events.enqueue(Event::LSPS0Client(event));
+	}
+}
@@ -0,0 +1,14 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Types and primitives implementing LSPS0: the JSON-RPC transport over custom messages which
+//! all other LSPS protocols build on, and the discovery of the protocols a peer supports.
+
+pub mod client;
+pub mod msgs;
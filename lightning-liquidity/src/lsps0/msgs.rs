@@ -0,0 +1,430 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Message, request, and other primitive types used to implement LSPS0, i.e., the JSON-RPC
+//! transport all other LSPS protocols are built on.
+
+use crate::lsps1::msgs::{LSPS1Message, LSPS1Request, LSPS1Response};
+use crate::lsps2::msgs::{LSPS2Message, LSPS2Request, LSPS2Response};
+
+use lightning::io;
+use lightning::ln::wire;
+use lightning::util::ser::{Writeable, Writer};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use std::collections::HashMap;
+
+/// The custom message type used for all LSPS messages.
+pub const LSPS_MESSAGE_TYPE_ID: u16 = 37913;
+
+/// The error code returned when a message is not valid JSON.
+pub const JSONRPC_PARSE_ERROR_CODE: i32 = -32700;
+/// The error code returned when a message is valid JSON but not a valid JSON-RPC request.
+pub const JSONRPC_INVALID_REQUEST_ERROR_CODE: i32 = -32600;
+/// The error code returned when the method of a request is not supported.
+pub const JSONRPC_METHOD_NOT_FOUND_ERROR_CODE: i32 = -32601;
+/// The error code returned when the parameters of a request are invalid.
+pub const JSONRPC_INVALID_PARAMS_ERROR_CODE: i32 = -32602;
+/// The error code returned when the request could not be handled due to an internal error.
+pub const JSONRPC_INTERNAL_ERROR_CODE: i32 = -32603;
+/// The error code returned when a service refuses to handle a request from the client, e.g.
+/// because the client has too many requests pending already.
+pub const LSPS0_CLIENT_REJECTED_ERROR_CODE: i32 = 1;
+
+const JSONRPC_VERSION: &str = "2.0";
+
+/// A message in the wire format used by all LSPS protocols, i.e., a UTF-8 encoded JSON-RPC 2.0
+/// object sent as the entire payload of a custom message of type [`LSPS_MESSAGE_TYPE_ID`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RawLSPSMessage {
+	/// The JSON-encoded message.
+	pub payload: String,
+}
+
+impl wire::Type for RawLSPSMessage {
+	fn type_id(&self) -> u16 {
+		LSPS_MESSAGE_TYPE_ID
+	}
+}
+
+impl Writeable for RawLSPSMessage {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+		w.write_all(self.payload.as_bytes())
+	}
+}
+
+/// The identifier of a JSON-RPC request, used to match it with its response.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RequestId(pub String);
+
+/// An error returned in response to a JSON-RPC request.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResponseError {
+	/// A number indicating the error type which occurred.
+	pub code: i32,
+	/// A short description of the error.
+	pub message: String,
+	/// Additional information about the error, as defined by the method which failed.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub data: Option<Value>,
+}
+
+/// A request made to learn which LSPS protocols a peer supports.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListProtocolsRequest {}
+
+/// A response to a [`ListProtocolsRequest`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListProtocolsResponse {
+	/// The numbers of the LSPS protocols supported, e.g. 1 for LSPS1.
+	pub protocols: Vec<u16>,
+}
+
+/// An enum that captures all the valid JSON-RPC requests in the LSPS0 protocol.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LSPS0Request {
+	/// A request to learn the protocols a peer supports.
+	ListProtocols(ListProtocolsRequest),
+}
+
+/// An enum that captures all the valid JSON-RPC responses in the LSPS0 protocol.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LSPS0Response {
+	/// A successful response to a [`LSPS0Request::ListProtocols`] request.
+	ListProtocols(ListProtocolsResponse),
+	/// An error response to a [`LSPS0Request::ListProtocols`] request.
+	ListProtocolsError(ResponseError),
+}
+
+/// An enum that captures all valid JSON-RPC messages in the LSPS0 protocol.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LSPS0Message {
+	/// An LSPS0 JSON-RPC request.
+	Request(RequestId, LSPS0Request),
+	/// An LSPS0 JSON-RPC response.
+	Response(RequestId, LSPS0Response),
+}
+
+/// A JSON-RPC message of any of the supported LSPS protocols.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LSPSMessage {
+	/// An error response to a request which could not be parsed, along with its id if known.
+	Invalid {
+		/// The id of the request, if it could be determined.
+		id: Option<RequestId>,
+		/// The error which occurred.
+		error: ResponseError,
+	},
+	/// An LSPS0 message.
+	LSPS0(LSPS0Message),
+	/// An LSPS1 message.
+	LSPS1(LSPS1Message),
+	/// An LSPS2 message.
+	LSPS2(LSPS2Message),
+}
+
+/// The methods of the requests we may send and receive. Responses do not name the method of the
+/// request they respond to, so we track it to be able to parse them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum LSPSMethod {
+	LSPS0ListProtocols,
+	LSPS1GetInfo,
+	LSPS1CreateOrder,
+	LSPS1GetOrder,
+	LSPS2GetInfo,
+	LSPS2Buy,
+}
+
+impl LSPSMethod {
+	fn as_str(&self) -> &'static str {
+		match self {
+			LSPSMethod::LSPS0ListProtocols => "lsps0.list_protocols",
+			LSPSMethod::LSPS1GetInfo => "lsps1.get_info",
+			LSPSMethod::LSPS1CreateOrder => "lsps1.create_order",
+			LSPSMethod::LSPS1GetOrder => "lsps1.get_order",
+			LSPSMethod::LSPS2GetInfo => "lsps2.get_info",
+			LSPSMethod::LSPS2Buy => "lsps2.buy",
+		}
+	}
+
+	fn from_str(method: &str) -> Option<Self> {
+		match method {
+			"lsps0.list_protocols" => Some(LSPSMethod::LSPS0ListProtocols),
+			"lsps1.get_info" => Some(LSPSMethod::LSPS1GetInfo),
+			"lsps1.create_order" => Some(LSPSMethod::LSPS1CreateOrder),
+			"lsps1.get_order" => Some(LSPSMethod::LSPS1GetOrder),
+			"lsps2.get_info" => Some(LSPSMethod::LSPS2GetInfo),
+			"lsps2.buy" => Some(LSPSMethod::LSPS2Buy),
+			_ => None,
+		}
+	}
+}
+
+/// The ways in which parsing an [`LSPSMessage`] can fail.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum LSPSParseError {
+	/// The message was (or may have been) a request we could not handle. The given error should be
+	/// sent in response.
+	InvalidRequest(Option<RequestId>, ResponseError),
+	/// The message was a response we could not handle, e.g. because we never sent the request it
+	/// responds to. It must not be responded to.
+	InvalidResponse(String),
+}
+
+pub(crate) fn error_response(code: i32, message: &str) -> ResponseError {
+	ResponseError { code, message: message.to_string(), data: None }
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value, id: &RequestId) -> Result<T, LSPSParseError> {
+	serde_json::from_value(params).map_err(|e| LSPSParseError::InvalidRequest(Some(id.clone()),
+		error_response(JSONRPC_INVALID_PARAMS_ERROR_CODE, &format!("Invalid params: {}", e))))
+}
+
+fn parse_result<T: DeserializeOwned>(result: Value) -> Result<T, LSPSParseError> {
+	serde_json::from_value(result).map_err(|e| LSPSParseError::InvalidResponse(format!("Invalid result: {}", e)))
+}
+
+impl LSPSMessage {
+	/// Returns the id and method of the message, if it is a request.
+	pub(crate) fn request_id_and_method(&self) -> Option<(RequestId, LSPSMethod)> {
+		match self {
+			LSPSMessage::LSPS0(LSPS0Message::Request(id, LSPS0Request::ListProtocols(_))) =>
+				Some((id.clone(), LSPSMethod::LSPS0ListProtocols)),
+			LSPSMessage::LSPS1(LSPS1Message::Request(id, request)) => Some((id.clone(), match request {
+				LSPS1Request::GetInfo(_) => LSPSMethod::LSPS1GetInfo,
+				LSPS1Request::CreateOrder(_) => LSPSMethod::LSPS1CreateOrder,
+				LSPS1Request::GetOrder(_) => LSPSMethod::LSPS1GetOrder,
+			})),
+			LSPSMessage::LSPS2(LSPS2Message::Request(id, request)) => Some((id.clone(), match request {
+				LSPS2Request::GetInfo(_) => LSPSMethod::LSPS2GetInfo,
+				LSPS2Request::Buy(_) => LSPSMethod::LSPS2Buy,
+			})),
+			_ => None,
+		}
+	}
+
+	/// Parses a JSON-RPC message, using `pending_requests` to look up (and remove) the method of
+	/// the request a response responds to.
+	pub(crate) fn from_json(json: &str, pending_requests: &mut HashMap<RequestId, LSPSMethod>) -> Result<Self, LSPSParseError> {
+		let value: Value = serde_json::from_str(json).map_err(|e| LSPSParseError::InvalidRequest(None,
+			error_response(JSONRPC_PARSE_ERROR_CODE, &format!("Parse error: {}", e))))?;
+		let mut object = match value {
+			Value::Object(object) => object,
+			_ => return Err(LSPSParseError::InvalidRequest(None,
+				error_response(JSONRPC_INVALID_REQUEST_ERROR_CODE, "Invalid request: not an object"))),
+		};
+
+		let id = match object.remove("id") {
+			Some(Value::String(id)) => Some(RequestId(id)),
+			_ => None,
+		};
+		let is_request = object.contains_key("method");
+		if object.get("jsonrpc").and_then(|v| v.as_str()) != Some(JSONRPC_VERSION) {
+			return Err(if is_request {
+				LSPSParseError::InvalidRequest(id,
+					error_response(JSONRPC_INVALID_REQUEST_ERROR_CODE, "Invalid request: unsupported jsonrpc version"))
+			} else {
+				LSPSParseError::InvalidResponse("Unsupported jsonrpc version".to_string())
+			});
+		}
+
+		if is_request {
+			let id = id.ok_or_else(|| LSPSParseError::InvalidRequest(None,
+				error_response(JSONRPC_INVALID_REQUEST_ERROR_CODE, "Invalid request: missing string id")))?;
+			let method = object.get("method").and_then(|v| v.as_str()).and_then(LSPSMethod::from_str)
+				.ok_or_else(|| LSPSParseError::InvalidRequest(Some(id.clone()),
+					error_response(JSONRPC_METHOD_NOT_FOUND_ERROR_CODE, "Method not found")))?;
+			let params = object.remove("params").unwrap_or_else(|| Value::Object(Map::new()));
+			Ok(match method {
+				LSPSMethod::LSPS0ListProtocols => LSPSMessage::LSPS0(LSPS0Message::Request(id.clone(),
+					LSPS0Request::ListProtocols(parse_params(params, &id)?))),
+				LSPSMethod::LSPS1GetInfo => LSPSMessage::LSPS1(LSPS1Message::Request(id.clone(),
+					LSPS1Request::GetInfo(parse_params(params, &id)?))),
+				LSPSMethod::LSPS1CreateOrder => LSPSMessage::LSPS1(LSPS1Message::Request(id.clone(),
+					LSPS1Request::CreateOrder(parse_params(params, &id)?))),
+				LSPSMethod::LSPS1GetOrder => LSPSMessage::LSPS1(LSPS1Message::Request(id.clone(),
+					LSPS1Request::GetOrder(parse_params(params, &id)?))),
+				LSPSMethod::LSPS2GetInfo => LSPSMessage::LSPS2(LSPS2Message::Request(id.clone(),
+					LSPS2Request::GetInfo(parse_params(params, &id)?))),
+				LSPSMethod::LSPS2Buy => LSPSMessage::LSPS2(LSPS2Message::Request(id.clone(),
+					LSPS2Request::Buy(parse_params(params, &id)?))),
+			})
+		} else {
+			let id = id.ok_or_else(|| LSPSParseError::InvalidResponse("Response without an id".to_string()))?;
+			let method = pending_requests.remove(&id)
+				.ok_or_else(|| LSPSParseError::InvalidResponse(format!("Response to unknown request {}", id.0)))?;
+			let outcome = match (object.remove("result"), object.remove("error")) {
+				(Some(result), None) => Ok(result),
+				(None, Some(error)) => Err(serde_json::from_value::<ResponseError>(error)
+					.map_err(|e| LSPSParseError::InvalidResponse(format!("Invalid error: {}", e)))?),
+				_ => return Err(LSPSParseError::InvalidResponse(
+					"Response must contain exactly one of result and error".to_string())),
+			};
+			Ok(match method {
+				LSPSMethod::LSPS0ListProtocols => LSPSMessage::LSPS0(LSPS0Message::Response(id, match outcome {
+					Ok(result) => LSPS0Response::ListProtocols(parse_result(result)?),
+					Err(error) => LSPS0Response::ListProtocolsError(error),
+				})),
+				LSPSMethod::LSPS1GetInfo => LSPSMessage::LSPS1(LSPS1Message::Response(id, match outcome {
+					Ok(result) => LSPS1Response::GetInfo(parse_result(result)?),
+					Err(error) => LSPS1Response::GetInfoError(error),
+				})),
+				LSPSMethod::LSPS1CreateOrder => LSPSMessage::LSPS1(LSPS1Message::Response(id, match outcome {
+					Ok(result) => LSPS1Response::CreateOrder(parse_result(result)?),
+					Err(error) => LSPS1Response::CreateOrderError(error),
+				})),
+				LSPSMethod::LSPS1GetOrder => LSPSMessage::LSPS1(LSPS1Message::Response(id, match outcome {
+					Ok(result) => LSPS1Response::GetOrder(parse_result(result)?),
+					Err(error) => LSPS1Response::GetOrderError(error),
+				})),
+				LSPSMethod::LSPS2GetInfo => LSPSMessage::LSPS2(LSPS2Message::Response(id, match outcome {
+					Ok(result) => LSPS2Response::GetInfo(parse_result(result)?),
+					Err(error) => LSPS2Response::GetInfoError(error),
+				})),
+				LSPSMethod::LSPS2Buy => LSPSMessage::LSPS2(LSPS2Message::Response(id, match outcome {
+					Ok(result) => LSPS2Response::Buy(parse_result(result)?),
+					Err(error) => LSPS2Response::BuyError(error),
+				})),
+			})
+		}
+	}
+
+	/// Encodes the message as a JSON-RPC 2.0 object.
+	pub(crate) fn to_json(&self) -> String {
+		fn request<T: Serialize>(id: &RequestId, method: LSPSMethod, params: &T) -> Value {
+			json!({ "jsonrpc": JSONRPC_VERSION, "id": id, "method": method.as_str(), "params": params })
+		}
+		fn result<T: Serialize>(id: &RequestId, result: &T) -> Value {
+			json!({ "jsonrpc": JSONRPC_VERSION, "id": id, "result": result })
+		}
+		fn error(id: Option<&RequestId>, error: &ResponseError) -> Value {
+			json!({ "jsonrpc": JSONRPC_VERSION, "id": id, "error": error })
+		}
+
+		let value = match self {
+			LSPSMessage::Invalid { id, error: err } => error(id.as_ref(), err),
+			LSPSMessage::LSPS0(LSPS0Message::Request(id, LSPS0Request::ListProtocols(params))) =>
+				request(id, LSPSMethod::LSPS0ListProtocols, params),
+			LSPSMessage::LSPS0(LSPS0Message::Response(id, response)) => match response {
+				LSPS0Response::ListProtocols(res) => result(id, res),
+				LSPS0Response::ListProtocolsError(err) => error(Some(id), err),
+			},
+			LSPSMessage::LSPS1(LSPS1Message::Request(id, req)) => match req {
+				LSPS1Request::GetInfo(params) => request(id, LSPSMethod::LSPS1GetInfo, params),
+				LSPS1Request::CreateOrder(params) => request(id, LSPSMethod::LSPS1CreateOrder, params),
+				LSPS1Request::GetOrder(params) => request(id, LSPSMethod::LSPS1GetOrder, params),
+			},
+			LSPSMessage::LSPS1(LSPS1Message::Response(id, response)) => match response {
+				LSPS1Response::GetInfo(res) => result(id, res),
+				LSPS1Response::CreateOrder(res) | LSPS1Response::GetOrder(res) => result(id, res),
+				LSPS1Response::GetInfoError(err) | LSPS1Response::CreateOrderError(err)
+					| LSPS1Response::GetOrderError(err) => error(Some(id), err),
+			},
+			LSPSMessage::LSPS2(LSPS2Message::Request(id, req)) => match req {
+				LSPS2Request::GetInfo(params) => request(id, LSPSMethod::LSPS2GetInfo, params),
+				LSPS2Request::Buy(params) => request(id, LSPSMethod::LSPS2Buy, params),
+			},
+			LSPSMessage::LSPS2(LSPS2Message::Response(id, response)) => match response {
+				LSPS2Response::GetInfo(res) => result(id, res),
+				LSPS2Response::Buy(res) => result(id, res),
+				LSPS2Response::GetInfoError(err) | LSPS2Response::BuyError(err) => error(Some(id), err),
+			},
+		};
+		value.to_string()
+	}
+}
+
+impl From<&LSPSMessage> for RawLSPSMessage {
+	fn from(msg: &LSPSMessage) -> Self {
+		RawLSPSMessage { payload: msg.to_json() }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::lsps1::msgs::{GetOrderRequest, OrderId, LSPS1_GET_ORDER_REQUEST_ORDER_NOT_FOUND_ERROR_CODE};
+	use crate::lsps2::msgs::{BuyResponse, GetInfoRequest};
+
+	#[test]
+	fn request_round_trip() {
+		let msg = LSPSMessage::LSPS2(LSPS2Message::Request(RequestId("abc".to_string()),
+			LSPS2Request::GetInfo(GetInfoRequest { token: Some("coupon".to_string()) })));
+		let json = msg.to_json();
+		let value: Value = serde_json::from_str(&json).unwrap();
+		assert_eq!(value, json!({
+			"jsonrpc": "2.0", "id": "abc", "method": "lsps2.get_info", "params": { "token": "coupon" }
+		}));
+		assert_eq!(LSPSMessage::from_json(&json, &mut HashMap::new()), Ok(msg));
+
+		// Omitted params are treated as empty.
+		let json = r#"{"jsonrpc":"2.0","id":"def","method":"lsps0.list_protocols"}"#;
+		assert_eq!(LSPSMessage::from_json(json, &mut HashMap::new()), Ok(LSPSMessage::LSPS0(
+			LSPS0Message::Request(RequestId("def".to_string()), LSPS0Request::ListProtocols(ListProtocolsRequest {})))));
+	}
+
+	#[test]
+	fn response_parsing_uses_request_method() {
+		let id = RequestId("abc".to_string());
+		let response = LSPSMessage::LSPS2(LSPS2Message::Response(id.clone(), LSPS2Response::Buy(BuyResponse {
+			jit_channel_scid: (700_000 << 40) | (1 << 16) | 2, lsp_cltv_expiry_delta: 144, client_trusts_lsp: false,
+		})));
+		let json = response.to_json();
+		assert!(json.contains(r#""jit_channel_scid":"700000x1x2""#));
+
+		// Responses to requests we did not send are rejected.
+		let mut pending_requests = HashMap::new();
+		match LSPSMessage::from_json(&json, &mut pending_requests) {
+			Err(LSPSParseError::InvalidResponse(_)) => {},
+			res => panic!("Unexpected result {:?}", res),
+		}
+
+		pending_requests.insert(id.clone(), LSPSMethod::LSPS2Buy);
+		assert_eq!(LSPSMessage::from_json(&json, &mut pending_requests), Ok(response));
+		assert!(pending_requests.is_empty());
+
+		let error = LSPSMessage::LSPS1(LSPS1Message::Response(id.clone(), LSPS1Response::GetOrderError(ResponseError {
+			code: LSPS1_GET_ORDER_REQUEST_ORDER_NOT_FOUND_ERROR_CODE, message: "Order not found".to_string(), data: None,
+		})));
+		pending_requests.insert(id.clone(), LSPSMethod::LSPS1GetOrder);
+		assert_eq!(LSPSMessage::from_json(&error.to_json(), &mut pending_requests), Ok(error));
+	}
+
+	#[test]
+	fn invalid_requests() {
+		let mut pending_requests = HashMap::new();
+		let error_code = |json: &str, pending_requests: &mut HashMap<RequestId, LSPSMethod>| {
+			match LSPSMessage::from_json(json, pending_requests) {
+				Err(LSPSParseError::InvalidRequest(id, error)) => (id, error.code),
+				res => panic!("Unexpected result {:?}", res),
+			}
+		};
+		assert_eq!(error_code("{", &mut pending_requests), (None, JSONRPC_PARSE_ERROR_CODE));
+		assert_eq!(error_code("[]", &mut pending_requests), (None, JSONRPC_INVALID_REQUEST_ERROR_CODE));
+		assert_eq!(error_code(r#"{"jsonrpc":"1.0","id":"a","method":"lsps1.get_info"}"#, &mut pending_requests),
+			(Some(RequestId("a".to_string())), JSONRPC_INVALID_REQUEST_ERROR_CODE));
+		assert_eq!(error_code(r#"{"jsonrpc":"2.0","id":"a","method":"lsps9.foo"}"#, &mut pending_requests),
+			(Some(RequestId("a".to_string())), JSONRPC_METHOD_NOT_FOUND_ERROR_CODE));
+		assert_eq!(error_code(r#"{"jsonrpc":"2.0","id":"a","method":"lsps1.get_order","params":{}}"#, &mut pending_requests),
+			(Some(RequestId("a".to_string())), JSONRPC_INVALID_PARAMS_ERROR_CODE));
+
+		let valid = LSPSMessage::LSPS1(LSPS1Message::Request(RequestId("a".to_string()),
+			LSPS1Request::GetOrder(GetOrderRequest { order_id: OrderId("order".to_string()) })));
+		assert_eq!(LSPSMessage::from_json(&valid.to_json(), &mut pending_requests), Ok(valid));
+
+		// Errors responding to unparseable requests carry a null id.
+		let error = LSPSMessage::Invalid { id: None, error: error_response(JSONRPC_PARSE_ERROR_CODE, "Parse error") };
+		let value: Value = serde_json::from_str(&error.to_json()).unwrap();
+		assert_eq!(value["id"], Value::Null);
+	}
+}
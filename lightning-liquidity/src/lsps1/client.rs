@@ -0,0 +1,98 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Contains the main LSPS1 client object, [`LSPS1ClientHandler`].
+
+use crate::events::{Event, EventQueue};
+use crate::lsps0::msgs::{LSPSMessage, RequestId};
+use crate::lsps1::event::LSPS1ClientEvent;
+use crate::lsps1::msgs::{CreateOrderRequest, GetInfoRequest, GetOrderRequest, LSPS1Message, LSPS1Request,
+	LSPS1Response, OrderId, OrderParameters};
+use crate::message_queue::MessageQueue;
+use crate::utils::RequestIdGenerator;
+
+use bitcoin::secp256k1::PublicKey;
+
+use std::sync::Arc;
+
+/// Client-side configuration options for LSPS1 channel purchases.
+#[derive(Clone, Debug, Default)]
+pub struct LSPS1ClientConfig {}
+
+/// The main object allowing to buy channels from an LSP via LSPS1.
+///
+/// Responses are returned as [`LSPS1ClientEvent`]s. Note that the client does not pay for orders
+/// itself: the invoice or address given in [`CreateOrderResponse::payment`] should be paid using
+/// the wallet (or [`ChannelManager`]) of the user's choice.
+///
+/// [`CreateOrderResponse::payment`]: crate::lsps1::msgs::CreateOrderResponse::payment
+/// [`ChannelManager`]: lightning::ln::channelmanager::ChannelManager
+pub struct LSPS1ClientHandler {
+	pending_messages: Arc<MessageQueue>,
+	pending_events: Arc<EventQueue>,
+	request_id_generator: Arc<RequestIdGenerator>,
+}
+
+impl LSPS1ClientHandler {
+	pub(crate) fn new(pending_messages: Arc<MessageQueue>, pending_events: Arc<EventQueue>,
+		request_id_generator: Arc<RequestIdGenerator>, _config: LSPS1ClientConfig) -> Self {
+		Self { pending_messages, pending_events, request_id_generator }
+	}
+
+	fn enqueue_request(&self, counterparty_node_id: &PublicKey, request: LSPS1Request) -> RequestId {
+		let request_id = RequestId(self.request_id_generator.next_id());
+		self.pending_messages.enqueue(counterparty_node_id,
+			LSPSMessage::LSPS1(LSPS1Message::Request(request_id.clone(), request)));
+		request_id
+	}
+
+	/// Asks the given LSP which channel parameters it supports.
+	///
+	/// The result is returned as an [`LSPS1ClientEvent::SupportedOptionsReady`] event.
+	pub fn request_supported_options(&self, counterparty_node_id: &PublicKey) -> RequestId {
+		self.enqueue_request(counterparty_node_id, LSPS1Request::GetInfo(GetInfoRequest {}))
+	}
+
+	/// Asks the given LSP to create an order for a channel with the given parameters.
+	///
+	/// The result is returned as an [`LSPS1ClientEvent::OrderCreated`] event.
+	pub fn create_order(&self, counterparty_node_id: &PublicKey, order: OrderParameters,
+		refund_onchain_address: Option<String>) -> RequestId {
+		self.enqueue_request(counterparty_node_id,
+			LSPS1Request::CreateOrder(CreateOrderRequest { order, refund_onchain_address }))
+	}
+
+	/// Asks the given LSP for the current state of an order, e.g. to learn whether our payment was
+	/// received or the channel was opened.
+	///
+	/// The result is returned as an [`LSPS1ClientEvent::OrderStatus`] event.
+	pub fn check_order_status(&self, counterparty_node_id: &PublicKey, order_id: OrderId) -> RequestId {
+		self.enqueue_request(counterparty_node_id, LSPS1Request::GetOrder(GetOrderRequest { order_id }))
+	}
+
+	pub(crate) fn handle_response(&self, request_id: RequestId, response: LSPS1Response, counterparty_node_id: &PublicKey) {
+		let counterparty_node_id = *counterparty_node_id;
+		let event = match response {
+			LSPS1Response::GetInfo(response) => LSPS1ClientEvent::SupportedOptionsReady {
+				request_id, counterparty_node_id, supported_options: response.options,
+			},
+			LSPS1Response::CreateOrder(order) => LSPS1ClientEvent::OrderCreated {
+				request_id, counterparty_node_id, order,
+			},
+			LSPS1Response::GetOrder(order) => LSPS1ClientEvent::OrderStatus {
+				request_id, counterparty_node_id, order,
+			},
+			LSPS1Response::GetInfoError(error) | LSPS1Response::CreateOrderError(error)
+				| LSPS1Response::GetOrderError(error) => LSPS1ClientEvent::RequestFailed {
+				request_id, counterparty_node_id, error,
+			},
+		};
+		self.pending_events.enqueue(Event::LSPS1Client(event));
+	}
+}
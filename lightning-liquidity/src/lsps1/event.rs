@@ -0,0 +1,92 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Contains LSPS1 event types.
+
+use crate::lsps0::msgs::{RequestId, ResponseError};
+use crate::lsps1::msgs::{CreateOrderResponse, LSPS1Options, OrderParameters};
+
+use bitcoin::secp256k1::PublicKey;
+
+/// An event which an LSPS1 client should handle.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LSPS1ClientEvent {
+	/// An LSP told us the channel parameters it supports, in response to
+	/// [`LSPS1ClientHandler::request_supported_options`].
+	///
+	/// [`LSPS1ClientHandler::request_supported_options`]: crate::lsps1::client::LSPS1ClientHandler::request_supported_options
+	SupportedOptionsReady {
+		/// The id of the request this responds to.
+		request_id: RequestId,
+		/// The node id of the LSP.
+		counterparty_node_id: PublicKey,
+		/// The channel parameters supported by the LSP.
+		supported_options: LSPS1Options,
+	},
+	/// An LSP created an order, in response to [`LSPS1ClientHandler::create_order`].
+	///
+	/// The order should be paid for as described by [`CreateOrderResponse::payment`], after
+	/// checking the fees it implies are acceptable.
+	///
+	/// [`LSPS1ClientHandler::create_order`]: crate::lsps1::client::LSPS1ClientHandler::create_order
+	OrderCreated {
+		/// The id of the request this responds to.
+		request_id: RequestId,
+		/// The node id of the LSP.
+		counterparty_node_id: PublicKey,
+		/// The order, as created by the LSP.
+		order: CreateOrderResponse,
+	},
+	/// An LSP told us the current state of an order, in response to
+	/// [`LSPS1ClientHandler::check_order_status`].
+	///
+	/// [`LSPS1ClientHandler::check_order_status`]: crate::lsps1::client::LSPS1ClientHandler::check_order_status
+	OrderStatus {
+		/// The id of the request this responds to.
+		request_id: RequestId,
+		/// The node id of the LSP.
+		counterparty_node_id: PublicKey,
+		/// The current state of the order.
+		order: CreateOrderResponse,
+	},
+	/// An LSP failed one of our requests.
+	RequestFailed {
+		/// The id of the request which failed.
+		request_id: RequestId,
+		/// The node id of the LSP.
+		counterparty_node_id: PublicKey,
+		/// The error returned by the LSP.
+		error: ResponseError,
+	},
+}
+
+/// An event which an LSPS1 service should handle.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LSPS1ServiceEvent {
+	/// A client requested a channel with parameters within the bounds of
+	/// [`LSPS1ServiceConfig::supported_options`].
+	///
+	/// The service should decide on the fee, prepare the means for the client to pay (e.g. an
+	/// invoice), and call [`LSPS1ServiceHandler::send_payment_details`] to create the order.
+	///
+	/// [`LSPS1ServiceConfig::supported_options`]: crate::lsps1::service::LSPS1ServiceConfig::supported_options
+	/// [`LSPS1ServiceHandler::send_payment_details`]: crate::lsps1::service::LSPS1ServiceHandler::send_payment_details
+	RequestForPaymentDetails {
+		/// The id of the request, to be passed to [`LSPS1ServiceHandler::send_payment_details`].
+		///
+		/// [`LSPS1ServiceHandler::send_payment_details`]: crate::lsps1::service::LSPS1ServiceHandler::send_payment_details
+		request_id: RequestId,
+		/// The node id of the client.
+		counterparty_node_id: PublicKey,
+		/// The parameters of the requested channel.
+		order: OrderParameters,
+		/// The address any on-chain payment should be refunded to if the order fails.
+		refund_onchain_address: Option<String>,
+	},
+}
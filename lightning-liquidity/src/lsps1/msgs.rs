@@ -0,0 +1,268 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Message, request, and other primitive types used to implement LSPS1.
+
+use crate::lsps0::msgs::{RequestId, ResponseError};
+use crate::utils::{string_encoded, LSPSDateTime};
+
+use bitcoin::OutPoint;
+
+use serde::{Deserialize, Serialize};
+
+/// The error code returned when the parameters of a `lsps1.create_order` request are not
+/// supported by the LSP. The error's `data` names the offending `property`.
+pub const LSPS1_CREATE_ORDER_REQUEST_OPTION_MISMATCH_ERROR_CODE: i32 = 100;
+/// The error code returned when the order requested via `lsps1.get_order` is not known to the
+/// LSP.
+pub const LSPS1_GET_ORDER_REQUEST_ORDER_NOT_FOUND_ERROR_CODE: i32 = 101;
+
+/// The identifier of an order, as chosen by the LSP.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct OrderId(pub String);
+
+/// A request made to an LSP to learn the channel parameters it supports.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GetInfoRequest {}
+
+/// The bounds an LSP places on the channels it sells.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LSPS1Options {
+	/// The smallest number of confirmations the LSP will let the client require before the
+	/// channel is considered open. 0 indicates the LSP supports zero-conf channels.
+	pub min_required_channel_confirmations: u16,
+	/// The smallest number of blocks the client may request the funding transaction to confirm
+	/// within.
+	pub min_funding_confirms_within_blocks: u16,
+	/// Whether the LSP supports opening channels without requiring a reserve from the client.
+	pub supports_zero_channel_reserve: bool,
+	/// The maximum number of blocks for which the client may request the channel to be kept open.
+	pub max_channel_expiry_blocks: u32,
+	/// The minimum balance, in satoshis, the client may request to hold when the channel opens.
+	#[serde(with = "string_encoded")]
+	pub min_initial_client_balance_sat: u64,
+	/// The maximum balance, in satoshis, the client may request to hold when the channel opens.
+	#[serde(with = "string_encoded")]
+	pub max_initial_client_balance_sat: u64,
+	/// The minimum balance, in satoshis, the client may request the LSP to hold when the channel
+	/// opens.
+	#[serde(with = "string_encoded")]
+	pub min_initial_lsp_balance_sat: u64,
+	/// The maximum balance, in satoshis, the client may request the LSP to hold when the channel
+	/// opens.
+	#[serde(with = "string_encoded")]
+	pub max_initial_lsp_balance_sat: u64,
+	/// The minimum value, in satoshis, of the channels the LSP sells.
+	#[serde(with = "string_encoded")]
+	pub min_channel_balance_sat: u64,
+	/// The maximum value, in satoshis, of the channels the LSP sells.
+	#[serde(with = "string_encoded")]
+	pub max_channel_balance_sat: u64,
+}
+
+/// A response to a [`GetInfoRequest`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GetInfoResponse {
+	/// The channel parameters supported by the LSP.
+	#[serde(flatten)]
+	pub options: LSPS1Options,
+}
+
+/// The parameters of the channel a client wishes to buy.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrderParameters {
+	/// The balance, in satoshis, the LSP will hold when the channel opens, i.e., the inbound
+	/// liquidity being bought.
+	#[serde(with = "string_encoded")]
+	pub lsp_balance_sat: u64,
+	/// The balance, in satoshis, the client will hold when the channel opens, paid for as part of
+	/// the order.
+	#[serde(with = "string_encoded")]
+	pub client_balance_sat: u64,
+	/// The number of confirmations the funding transaction must have before the channel is
+	/// considered open.
+	pub required_channel_confirmations: u16,
+	/// The number of blocks within which the funding transaction should confirm.
+	pub funding_confirms_within_blocks: u16,
+	/// The number of blocks the LSP promises to keep the channel open for.
+	pub channel_expiry_blocks: u32,
+	/// A token, e.g. a coupon, the LSP may use to adjust the order.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub token: Option<String>,
+	/// Whether the channel should be announced to the network.
+	pub announce_channel: bool,
+}
+
+/// A request made to an LSP to create an order for a channel.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CreateOrderRequest {
+	/// The parameters of the channel being bought.
+	#[serde(flatten)]
+	pub order: OrderParameters,
+	/// The address any on-chain payment should be refunded to if the order fails.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub refund_onchain_address: Option<String>,
+}
+
+/// The state of an order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum OrderState {
+	/// The order was created, but the channel has not been opened yet.
+	Created,
+	/// The channel was opened.
+	Completed,
+	/// The order failed, e.g. because it was not paid in time or the channel could not be opened.
+	Failed,
+}
+
+/// The state of the payment for an order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum PaymentState {
+	/// The LSP is waiting for the payment.
+	ExpectPayment,
+	/// The payment was received but is being held until the channel is open.
+	Hold,
+	/// The payment was received and settled.
+	Paid,
+	/// The payment was returned to the client.
+	Refunded,
+}
+
+/// Details on how to pay for an order over lightning.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Bolt11PaymentInfo {
+	/// The state of the payment.
+	pub state: PaymentState,
+	/// The time at which the invoice expires.
+	pub expires_at: LSPSDateTime,
+	/// The fee the LSP charges for the channel, in satoshis.
+	#[serde(with = "string_encoded")]
+	pub fee_total_sat: u64,
+	/// The total amount to be paid, in satoshis, i.e., the fee and the client balance.
+	#[serde(with = "string_encoded")]
+	pub order_total_sat: u64,
+	/// The BOLT 11 invoice to pay.
+	pub invoice: String,
+}
+
+/// Details on how to pay for an order on-chain.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OnchainPaymentInfo {
+	/// The state of the payment.
+	pub state: PaymentState,
+	/// The time after which payments to `address` are no longer accepted.
+	pub expires_at: LSPSDateTime,
+	/// The fee the LSP charges for the channel, in satoshis.
+	#[serde(with = "string_encoded")]
+	pub fee_total_sat: u64,
+	/// The total amount to be paid, in satoshis, i.e., the fee and the client balance.
+	#[serde(with = "string_encoded")]
+	pub order_total_sat: u64,
+	/// The address to pay to.
+	pub address: String,
+	/// The number of confirmations the LSP requires for the payment before opening the channel,
+	/// if any.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub min_onchain_payment_confirmations: Option<u16>,
+	/// The minimum fee rate, in satoshis per vbyte, of a payment the LSP will accept without it
+	/// confirming.
+	pub min_fee_for_0conf: u64,
+	/// The address any payment is refunded to if the order fails.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub refund_onchain_address: Option<String>,
+}
+
+/// The ways in which an order may be paid for. At least one of them is set.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PaymentInfo {
+	/// Details on paying over lightning, if supported.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub bolt11: Option<Bolt11PaymentInfo>,
+	/// Details on paying on-chain, if supported.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub onchain: Option<OnchainPaymentInfo>,
+}
+
+/// Details on the channel opened for an order.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelInfo {
+	/// The time at which the funding transaction was published.
+	pub funded_at: LSPSDateTime,
+	/// The outpoint of the funding transaction.
+	#[serde(with = "string_encoded")]
+	pub funding_outpoint: OutPoint,
+	/// The earliest time at which the LSP may close the channel.
+	pub expires_at: LSPSDateTime,
+}
+
+/// A response to a [`CreateOrderRequest`] or [`GetOrderRequest`], describing the current state of
+/// an order.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CreateOrderResponse {
+	/// The identifier of the order.
+	pub order_id: OrderId,
+	/// The parameters of the channel being bought.
+	#[serde(flatten)]
+	pub order: OrderParameters,
+	/// The time at which the order was created.
+	pub created_at: LSPSDateTime,
+	/// The state of the order.
+	pub order_state: OrderState,
+	/// Details on how to pay for the order.
+	pub payment: PaymentInfo,
+	/// Details on the channel, once it has been opened.
+	pub channel: Option<ChannelInfo>,
+}
+
+/// A request made to an LSP to retrieve the current state of an order.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GetOrderRequest {
+	/// The identifier of the order.
+	pub order_id: OrderId,
+}
+
+/// An enum that captures all the valid JSON-RPC requests in the LSPS1 protocol.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LSPS1Request {
+	/// A request to learn the channel parameters the LSP supports.
+	GetInfo(GetInfoRequest),
+	/// A request to create an order.
+	CreateOrder(CreateOrderRequest),
+	/// A request to retrieve the state of an order.
+	GetOrder(GetOrderRequest),
+}
+
+/// An enum that captures all the valid JSON-RPC responses in the LSPS1 protocol.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LSPS1Response {
+	/// A successful response to a [`LSPS1Request::GetInfo`] request.
+	GetInfo(GetInfoResponse),
+	/// An error response to a [`LSPS1Request::GetInfo`] request.
+	GetInfoError(ResponseError),
+	/// A successful response to a [`LSPS1Request::CreateOrder`] request.
+	CreateOrder(CreateOrderResponse),
+	/// An error response to a [`LSPS1Request::CreateOrder`] request.
+	CreateOrderError(ResponseError),
+	/// A successful response to a [`LSPS1Request::GetOrder`] request.
+	GetOrder(CreateOrderResponse),
+	/// An error response to a [`LSPS1Request::GetOrder`] request.
+	GetOrderError(ResponseError),
+}
+
+/// An enum that captures all valid JSON-RPC messages in the LSPS1 protocol.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LSPS1Message {
+	/// An LSPS1 JSON-RPC request.
+	Request(RequestId, LSPS1Request),
+	/// An LSPS1 JSON-RPC response.
+	Response(RequestId, LSPS1Response),
+}
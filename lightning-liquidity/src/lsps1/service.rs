@@ -0,0 +1,312 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Contains the main LSPS1 service object, [`LSPS1ServiceHandler`].
+
+use crate::events::{Event, EventQueue};
+use crate::lsps0::msgs::{error_response, LSPSMessage, RequestId, ResponseError, LSPS0_CLIENT_REJECTED_ERROR_CODE};
+use crate::lsps1::event::LSPS1ServiceEvent;
+use crate::lsps1::msgs::{ChannelInfo, CreateOrderRequest, CreateOrderResponse, GetInfoResponse, LSPS1Message,
+	LSPS1Options, LSPS1Request, LSPS1Response, OrderId, OrderParameters, OrderState, PaymentInfo, PaymentState,
+	LSPS1_CREATE_ORDER_REQUEST_OPTION_MISMATCH_ERROR_CODE, LSPS1_GET_ORDER_REQUEST_ORDER_NOT_FOUND_ERROR_CODE};
+use crate::message_queue::MessageQueue;
+use crate::utils::{LSPSDateTime, RequestIdGenerator};
+
+use bitcoin::OutPoint;
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::secp256k1::PublicKey;
+
+use lightning::chain;
+use lightning::chain::chaininterface::{BroadcasterInterface, FeeEstimator};
+use lightning::chain::keysinterface::{KeysInterface, Sign};
+use lightning::ln::channelmanager::ChannelManager;
+use lightning::util::config::UserConfig;
+use lightning::util::errors::APIError;
+use lightning::util::logger::Logger;
+
+use serde_json::json;
+
+use std::collections::{HashMap, VecDeque};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+/// The average number of seconds between blocks, used to convert the order's channel expiry into
+/// a point in time.
+const SECS_PER_BLOCK: u64 = 600;
+
+/// The maximum number of order requests and unfinished orders a single peer may have at once.
+const MAX_PENDING_REQUESTS_PER_PEER: usize = 10;
+/// The maximum number of order requests and unfinished orders all peers together may have at once.
+const MAX_TOTAL_PENDING_REQUESTS: usize = 1000;
+/// The number of completed or failed orders we keep per peer so that they may still be polled.
+const MAX_FINISHED_ORDERS_PER_PEER: usize = 10;
+
+/// Service-side configuration options for LSPS1 channel sales.
+#[derive(Clone, Debug)]
+pub struct LSPS1ServiceConfig {
+	/// The bounds on the channels we sell. Orders outside of these bounds are rejected before
+	/// being surfaced to the user.
+	pub supported_options: LSPS1Options,
+}
+
+#[derive(Default)]
+struct PeerState {
+	pending_requests: HashMap<RequestId, CreateOrderRequest>,
+	orders: HashMap<OrderId, CreateOrderResponse>,
+	/// The ids of `orders`, oldest first.
+	order_ids: VecDeque<OrderId>,
+}
+
+impl PeerState {
+	/// Returns the number of order requests and orders still awaiting action by the user.
+	fn pending_count(&self) -> usize {
+		self.pending_requests.len()
+			+ self.orders.values().filter(|order| order.order_state == OrderState::Created).count()
+	}
+
+	/// Forgets the oldest completed or failed orders beyond [`MAX_FINISHED_ORDERS_PER_PEER`].
+	fn prune_finished_orders(&mut self) {
+		let is_finished = |order: &CreateOrderResponse| order.order_state != OrderState::Created;
+		let finished_count = self.orders.values().filter(|order| is_finished(order)).count();
+		let mut excess = finished_count.saturating_sub(MAX_FINISHED_ORDERS_PER_PEER);
+		let orders = &mut self.orders;
+		self.order_ids.retain(|order_id| {
+			if excess > 0 && orders.get(order_id).map_or(false, is_finished) {
+				orders.remove(order_id);
+				excess -= 1;
+				false
+			} else { true }
+		});
+	}
+}
+
+/// The main object allowing to sell channels to clients via LSPS1.
+///
+/// Orders go through the following steps:
+/// 1. A client requests a channel, which (if within our [`LSPS1ServiceConfig::supported_options`])
+///    is surfaced as an [`LSPS1ServiceEvent::RequestForPaymentDetails`]. The user decides on the
+///    fee and calls [`Self::send_payment_details`], creating the order.
+/// 2. Once the payment arrives, the user calls [`Self::update_payment_state`] and then
+///    [`Self::open_channel`], which opens the channel via the [`ChannelManager`].
+/// 3. Once the funding transaction is published, the user calls [`Self::channel_opened`],
+///    completing the order. If the order cannot be completed, the user calls
+///    [`Self::order_failed`] instead.
+///
+/// Clients may poll the state of their orders at any time, though only the most recent of their
+/// completed or failed orders are kept. Order requests are rejected while the client has too many
+/// of them, or too many unpaid orders, pending.
+pub struct LSPS1ServiceHandler {
+	pending_messages: Arc<MessageQueue>,
+	pending_events: Arc<EventQueue>,
+	request_id_generator: Arc<RequestIdGenerator>,
+	config: LSPS1ServiceConfig,
+	per_peer_state: Mutex<HashMap<PublicKey, PeerState>>,
+}
+
+impl LSPS1ServiceHandler {
+	pub(crate) fn new(pending_messages: Arc<MessageQueue>, pending_events: Arc<EventQueue>,
+		request_id_generator: Arc<RequestIdGenerator>, config: LSPS1ServiceConfig) -> Self {
+		Self { pending_messages, pending_events, request_id_generator, config, per_peer_state: Mutex::new(HashMap::new()) }
+	}
+
+	fn enqueue_response(&self, counterparty_node_id: &PublicKey, request_id: RequestId, response: LSPS1Response) {
+		self.pending_messages.enqueue(counterparty_node_id,
+			LSPSMessage::LSPS1(LSPS1Message::Response(request_id, response)));
+	}
+
+	pub(crate) fn handle_request(&self, request_id: RequestId, request: LSPS1Request, counterparty_node_id: &PublicKey) {
+		match request {
+			LSPS1Request::GetInfo(_) => {
+				self.enqueue_response(counterparty_node_id, request_id,
+					LSPS1Response::GetInfo(GetInfoResponse { options: self.config.supported_options.clone() }));
+			},
+			LSPS1Request::CreateOrder(request) => {
+				if let Err(property) = check_order_parameters(&request.order, &self.config.supported_options) {
+					let error = ResponseError {
+						code: LSPS1_CREATE_ORDER_REQUEST_OPTION_MISMATCH_ERROR_CODE,
+						message: "Option mismatch".to_string(),
+						data: Some(json!({ "property": property })),
+					};
+					self.enqueue_response(counterparty_node_id, request_id, LSPS1Response::CreateOrderError(error));
+					return;
+				}
+
+				let mut per_peer_state = self.per_peer_state.lock().unwrap();
+				let total_pending_requests: usize = per_peer_state.values().map(PeerState::pending_count).sum();
+				let peer_pending_requests = per_peer_state.get(counterparty_node_id).map_or(0, PeerState::pending_count);
+				if total_pending_requests >= MAX_TOTAL_PENDING_REQUESTS || peer_pending_requests >= MAX_PENDING_REQUESTS_PER_PEER {
+					self.enqueue_response(counterparty_node_id, request_id, LSPS1Response::CreateOrderError(
+						error_response(LSPS0_CLIENT_REJECTED_ERROR_CODE, "Too many pending requests")));
+					return;
+				}
+				per_peer_state.entry(*counterparty_node_id).or_default()
+					.pending_requests.insert(request_id.clone(), request.clone());
+				self.pending_events.enqueue(Event::LSPS1Service(LSPS1ServiceEvent::RequestForPaymentDetails {
+					request_id,
+					counterparty_node_id: *counterparty_node_id,
+					order: request.order,
+					refund_onchain_address: request.refund_onchain_address,
+				}));
+			},
+			LSPS1Request::GetOrder(request) => {
+				let order = self.per_peer_state.lock().unwrap().get(counterparty_node_id)
+					.and_then(|peer_state| peer_state.orders.get(&request.order_id).cloned());
+				let response = match order {
+					Some(order) => LSPS1Response::GetOrder(order),
+					None => LSPS1Response::GetOrderError(error_response(
+						LSPS1_GET_ORDER_REQUEST_ORDER_NOT_FOUND_ERROR_CODE, "Order not found")),
+				};
+				self.enqueue_response(counterparty_node_id, request_id, response);
+			},
+		}
+	}
+
+	/// Creates the order requested in an [`LSPS1ServiceEvent::RequestForPaymentDetails`] event,
+	/// telling the client how to pay for it. Returns the id of the new order.
+	///
+	/// `payment` must include at least one payment method, in the
+	/// [`PaymentState::ExpectPayment`] state.
+	pub fn send_payment_details(&self, request_id: RequestId, counterparty_node_id: &PublicKey,
+		payment: PaymentInfo) -> Result<OrderId, APIError> {
+		if payment.bolt11.is_none() && payment.onchain.is_none() {
+			return Err(APIError::APIMisuseError { err: "At least one payment method must be given".to_string() });
+		}
+
+		let mut per_peer_state = self.per_peer_state.lock().unwrap();
+		let peer_state = per_peer_state.get_mut(counterparty_node_id)
+			.ok_or_else(|| APIError::APIMisuseError { err: format!("No pending requests for peer {}", counterparty_node_id) })?;
+		let request = peer_state.pending_requests.remove(&request_id)
+			.ok_or_else(|| APIError::APIMisuseError { err: format!("No pending request with id {}", request_id.0) })?;
+
+		let order_id = OrderId(self.request_id_generator.get_random_bytes().to_hex());
+		let order = CreateOrderResponse {
+			order_id: order_id.clone(),
+			order: request.order,
+			created_at: LSPSDateTime::from_now(0),
+			order_state: OrderState::Created,
+			payment,
+			channel: None,
+		};
+		peer_state.orders.insert(order_id.clone(), order.clone());
+		peer_state.order_ids.push_back(order_id.clone());
+		peer_state.prune_finished_orders();
+		self.enqueue_response(counterparty_node_id, request_id, LSPS1Response::CreateOrder(order));
+		Ok(order_id)
+	}
+
+	fn with_order<R, F: FnOnce(&mut CreateOrderResponse) -> Result<R, APIError>>(&self,
+		counterparty_node_id: &PublicKey, order_id: &OrderId, f: F) -> Result<R, APIError> {
+		let mut per_peer_state = self.per_peer_state.lock().unwrap();
+		let order = per_peer_state.get_mut(counterparty_node_id)
+			.and_then(|peer_state| peer_state.orders.get_mut(order_id))
+			.ok_or_else(|| APIError::APIMisuseError { err: format!("Unknown order {}", order_id.0) })?;
+		f(order)
+	}
+
+	/// Updates the state of the payment for the given order, e.g. once it has been received.
+	pub fn update_payment_state(&self, counterparty_node_id: &PublicKey, order_id: &OrderId,
+		state: PaymentState) -> Result<(), APIError> {
+		self.with_order(counterparty_node_id, order_id, |order| {
+			if let Some(bolt11) = order.payment.bolt11.as_mut() { bolt11.state = state; }
+			if let Some(onchain) = order.payment.onchain.as_mut() { onchain.state = state; }
+			Ok(())
+		})
+	}
+
+	/// Opens the channel bought by the given order, which must have been paid for (i.e., its
+	/// payment must be in the [`PaymentState::Hold`] or [`PaymentState::Paid`] state).
+	///
+	/// The channel is opened via [`ChannelManager::create_channel`], with the client's balance
+	/// pushed to it. `override_config` is passed through, except that the channel is announced
+	/// only if the client asked for it. Returns the channel's temporary channel id.
+	pub fn open_channel<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>(
+		&self, channel_manager: &ChannelManager<Signer, M, T, K, F, L>, counterparty_node_id: &PublicKey,
+		order_id: &OrderId, user_channel_id: u64, override_config: Option<UserConfig>
+	) -> Result<[u8; 32], APIError>
+	where
+		M::Target: chain::Watch<Signer>,
+		T::Target: BroadcasterInterface,
+		K::Target: KeysInterface<Signer = Signer>,
+		F::Target: FeeEstimator,
+		L::Target: Logger,
+	{
+		let order = self.with_order(counterparty_node_id, order_id, |order| {
+			if order.order_state != OrderState::Created {
+				return Err(APIError::APIMisuseError { err: format!("Order {} is no longer pending", order_id.0) });
+			}
+			let is_paid = |state: PaymentState| state == PaymentState::Hold || state == PaymentState::Paid;
+			if !order.payment.bolt11.as_ref().map_or(false, |p| is_paid(p.state))
+				&& !order.payment.onchain.as_ref().map_or(false, |p| is_paid(p.state)) {
+				return Err(APIError::APIMisuseError { err: format!("Order {} has not been paid for", order_id.0) });
+			}
+			Ok(order.order.clone())
+		})?;
+
+		let channel_value_satoshis = order.lsp_balance_sat.checked_add(order.client_balance_sat)
+			.ok_or_else(|| APIError::APIMisuseError { err: "Channel value overflows".to_string() })?;
+		let push_msat = order.client_balance_sat.checked_mul(1000)
+			.ok_or_else(|| APIError::APIMisuseError { err: "Client balance overflows".to_string() })?;
+		let mut config = override_config.unwrap_or_else(|| *channel_manager.get_current_default_configuration());
+		config.channel_handshake_config.announced_channel = order.announce_channel;
+		channel_manager.create_channel(*counterparty_node_id, channel_value_satoshis,
+			push_msat, user_channel_id, Some(config))
+	}
+
+	/// Marks the given order as completed, once the funding transaction of its channel has been
+	/// published.
+	pub fn channel_opened(&self, counterparty_node_id: &PublicKey, order_id: &OrderId,
+		funding_outpoint: OutPoint) -> Result<(), APIError> {
+		self.with_order(counterparty_node_id, order_id, |order| {
+			let expiry_secs = (order.order.channel_expiry_blocks as u64) * SECS_PER_BLOCK;
+			order.order_state = OrderState::Completed;
+			order.channel = Some(ChannelInfo {
+				funded_at: LSPSDateTime::from_now(0),
+				funding_outpoint,
+				expires_at: LSPSDateTime::from_now(expiry_secs),
+			});
+			Ok(())
+		})
+	}
+
+	/// Marks the given order as failed, e.g. because it was not paid for in time. Any payment
+	/// received should be refunded, after which [`Self::update_payment_state`] should be called.
+	pub fn order_failed(&self, counterparty_node_id: &PublicKey, order_id: &OrderId) -> Result<(), APIError> {
+		self.with_order(counterparty_node_id, order_id, |order| {
+			order.order_state = OrderState::Failed;
+			Ok(())
+		})
+	}
+}
+
+/// Checks the given order against our options, returning the name of the first offending
+/// property, if any.
+fn check_order_parameters(order: &OrderParameters, options: &LSPS1Options) -> Result<(), &'static str> {
+	if order.lsp_balance_sat < options.min_initial_lsp_balance_sat
+		|| order.lsp_balance_sat > options.max_initial_lsp_balance_sat {
+		return Err("lsp_balance_sat");
+	}
+	if order.client_balance_sat < options.min_initial_client_balance_sat
+		|| order.client_balance_sat > options.max_initial_client_balance_sat {
+		return Err("client_balance_sat");
+	}
+	match order.lsp_balance_sat.checked_add(order.client_balance_sat) {
+		Some(total) if total >= options.min_channel_balance_sat && total <= options.max_channel_balance_sat => {},
+		_ => return Err("lsp_balance_sat"),
+	}
+	if order.required_channel_confirmations < options.min_required_channel_confirmations {
+		return Err("required_channel_confirmations");
+	}
+	if order.funding_confirms_within_blocks < options.min_funding_confirms_within_blocks {
+		return Err("funding_confirms_within_blocks");
+	}
+	if order.channel_expiry_blocks > options.max_channel_expiry_blocks {
+		return Err("channel_expiry_blocks");
+	}
+	Ok(())
+}
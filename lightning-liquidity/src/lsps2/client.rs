@@ -0,0 +1,136 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Contains the main LSPS2 client object, [`LSPS2ClientHandler`].
+
+use crate::events::{Event, EventQueue};
+use crate::lsps0::msgs::{LSPSMessage, RequestId};
+use crate::lsps2::event::LSPS2ClientEvent;
+use crate::lsps2::msgs::{BuyRequest, GetInfoRequest, LSPS2Message, LSPS2Request, LSPS2Response, OpeningFeeParams};
+use crate::lsps2::utils::compute_opening_fee;
+use crate::message_queue::MessageQueue;
+use crate::utils::RequestIdGenerator;
+
+use bitcoin::secp256k1::PublicKey;
+
+use lightning::util::errors::APIError;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Client-side configuration options for LSPS2 JIT channels.
+#[derive(Clone, Debug, Default)]
+pub struct LSPS2ClientConfig {}
+
+/// The main object allowing to buy JIT channels from an LSP via LSPS2.
+///
+/// The flow is as follows:
+/// 1. The client learns the LSP's fees via [`Self::request_opening_params`].
+/// 2. The client picks one of the offered fees and buys a channel via
+///    [`Self::select_opening_params`], learning the short channel id to use in its invoice.
+/// 3. The client creates an invoice with a route hint through the LSP. Once it is paid, the LSP
+///    opens a channel to the client and forwards the payment, minus the opening fee.
+pub struct LSPS2ClientHandler {
+	pending_messages: Arc<MessageQueue>,
+	pending_events: Arc<EventQueue>,
+	request_id_generator: Arc<RequestIdGenerator>,
+	/// The payment size given in each pending buy request, as the response does not repeat it.
+	pending_buy_requests: Mutex<HashMap<RequestId, Option<u64>>>,
+}
+
+impl LSPS2ClientHandler {
+	pub(crate) fn new(pending_messages: Arc<MessageQueue>, pending_events: Arc<EventQueue>,
+		request_id_generator: Arc<RequestIdGenerator>, _config: LSPS2ClientConfig) -> Self {
+		Self { pending_messages, pending_events, request_id_generator, pending_buy_requests: Mutex::new(HashMap::new()) }
+	}
+
+	fn enqueue_request(&self, counterparty_node_id: &PublicKey, request: LSPS2Request) -> RequestId {
+		let request_id = RequestId(self.request_id_generator.next_id());
+		self.pending_messages.enqueue(counterparty_node_id,
+			LSPSMessage::LSPS2(LSPS2Message::Request(request_id.clone(), request)));
+		request_id
+	}
+
+	/// Asks the given LSP for the fees it charges for JIT channels, optionally passing a token
+	/// (e.g. a coupon) the LSP gave us out of band.
+	///
+	/// The result is returned as an [`LSPS2ClientEvent::OpeningParametersReady`] event.
+	///
+	/// [`LSPS2ClientEvent::OpeningParametersReady`]: crate::lsps2::event::LSPS2ClientEvent::OpeningParametersReady
+	pub fn request_opening_params(&self, counterparty_node_id: &PublicKey, token: Option<String>) -> RequestId {
+		self.enqueue_request(counterparty_node_id, LSPS2Request::GetInfo(GetInfoRequest { token }))
+	}
+
+	/// Buys a JIT channel from the given LSP, with fees taken from an
+	/// [`LSPS2ClientEvent::OpeningParametersReady`] event.
+	///
+	/// If `payment_size_msat` is given, the channel is opened once that amount has been paid,
+	/// possibly over several parts. Otherwise, the channel is opened for the first payment.
+	///
+	/// The result is returned as an [`LSPS2ClientEvent::InvoiceParametersReady`] event. Fails if
+	/// the fees have expired or the payment size is out of the offered bounds or does not cover
+	/// the opening fee.
+	///
+	/// [`LSPS2ClientEvent::OpeningParametersReady`]: crate::lsps2::event::LSPS2ClientEvent::OpeningParametersReady
+	/// [`LSPS2ClientEvent::InvoiceParametersReady`]: crate::lsps2::event::LSPS2ClientEvent::InvoiceParametersReady
+	pub fn select_opening_params(&self, counterparty_node_id: &PublicKey, payment_size_msat: Option<u64>,
+		opening_fee_params: OpeningFeeParams) -> Result<RequestId, APIError> {
+		if opening_fee_params.valid_until.is_past() {
+			return Err(APIError::APIMisuseError { err: "The opening fee parameters have expired".to_string() });
+		}
+		if let Some(payment_size_msat) = payment_size_msat {
+			if payment_size_msat < opening_fee_params.min_payment_size_msat
+				|| payment_size_msat > opening_fee_params.max_payment_size_msat {
+				return Err(APIError::APIMisuseError {
+					err: format!("Payment size {} is out of the offered bounds", payment_size_msat)
+				});
+			}
+			match compute_opening_fee(payment_size_msat, opening_fee_params.min_fee_msat,
+				opening_fee_params.proportional as u64) {
+				Some(fee) if fee < payment_size_msat => {},
+				_ => return Err(APIError::APIMisuseError {
+					err: format!("Payment size {} does not cover the opening fee", payment_size_msat)
+				}),
+			}
+		}
+
+		let request_id = self.enqueue_request(counterparty_node_id,
+			LSPS2Request::Buy(BuyRequest { opening_fee_params, payment_size_msat }));
+		self.pending_buy_requests.lock().unwrap().insert(request_id.clone(), payment_size_msat);
+		Ok(request_id)
+	}
+
+	pub(crate) fn handle_response(&self, request_id: RequestId, response: LSPS2Response, counterparty_node_id: &PublicKey) {
+		let counterparty_node_id = *counterparty_node_id;
+		let event = match response {
+			LSPS2Response::GetInfo(response) => LSPS2ClientEvent::OpeningParametersReady {
+				request_id, counterparty_node_id, opening_fee_params_menu: response.opening_fee_params_menu,
+			},
+			LSPS2Response::Buy(response) => {
+				let payment_size_msat = self.pending_buy_requests.lock().unwrap().remove(&request_id).unwrap_or(None);
+				LSPS2ClientEvent::InvoiceParametersReady {
+					request_id,
+					counterparty_node_id,
+					intercept_scid: response.jit_channel_scid,
+					cltv_expiry_delta: response.lsp_cltv_expiry_delta,
+					payment_size_msat,
+					client_trusts_lsp: response.client_trusts_lsp,
+				}
+			},
+			LSPS2Response::GetInfoError(error) => LSPS2ClientEvent::RequestFailed {
+				request_id, counterparty_node_id, error,
+			},
+			LSPS2Response::BuyError(error) => {
+				self.pending_buy_requests.lock().unwrap().remove(&request_id);
+				LSPS2ClientEvent::RequestFailed { request_id, counterparty_node_id, error }
+			},
+		};
+		self.pending_events.enqueue(Event::LSPS2Client(event));
+	}
+}
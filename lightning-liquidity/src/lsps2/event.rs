@@ -0,0 +1,129 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Contains LSPS2 event types.
+
+use crate::lsps0::msgs::{RequestId, ResponseError};
+use crate::lsps2::msgs::OpeningFeeParams;
+
+use bitcoin::secp256k1::PublicKey;
+
+/// An event which an LSPS2 client should handle.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LSPS2ClientEvent {
+	/// An LSP told us the fees it charges for JIT channels, in response to
+	/// [`LSPS2ClientHandler::request_opening_params`].
+	///
+	/// One of the offered parameters should be chosen and passed to
+	/// [`LSPS2ClientHandler::select_opening_params`].
+	///
+	/// [`LSPS2ClientHandler::request_opening_params`]: crate::lsps2::client::LSPS2ClientHandler::request_opening_params
+	/// [`LSPS2ClientHandler::select_opening_params`]: crate::lsps2::client::LSPS2ClientHandler::select_opening_params
+	OpeningParametersReady {
+		/// The id of the request this responds to.
+		request_id: RequestId,
+		/// The node id of the LSP.
+		counterparty_node_id: PublicKey,
+		/// The fees and limits offered by the LSP, ordered by increasing fees.
+		opening_fee_params_menu: Vec<OpeningFeeParams>,
+	},
+	/// An LSP agreed to open a JIT channel, in response to
+	/// [`LSPS2ClientHandler::select_opening_params`].
+	///
+	/// The client should now create an invoice with a route hint through the LSP, using the given
+	/// short channel id and CLTV expiry delta. The invoice should be created via
+	/// [`ChannelManager::create_inbound_payment`] so that we can claim the payment once the
+	/// channel is open.
+	///
+	/// [`LSPS2ClientHandler::select_opening_params`]: crate::lsps2::client::LSPS2ClientHandler::select_opening_params
+	/// [`ChannelManager::create_inbound_payment`]: lightning::ln::channelmanager::ChannelManager::create_inbound_payment
+	InvoiceParametersReady {
+		/// The id of the request this responds to.
+		request_id: RequestId,
+		/// The node id of the LSP.
+		counterparty_node_id: PublicKey,
+		/// The short channel id to use in the route hint.
+		intercept_scid: u64,
+		/// The CLTV expiry delta to use in the route hint.
+		cltv_expiry_delta: u32,
+		/// The amount the invoice should be for, if fixed when buying the channel.
+		payment_size_msat: Option<u64>,
+		/// Whether the LSP requires us to reveal the payment preimage before it publishes the
+		/// funding transaction.
+		client_trusts_lsp: bool,
+	},
+	/// An LSP failed one of our requests.
+	RequestFailed {
+		/// The id of the request which failed.
+		request_id: RequestId,
+		/// The node id of the LSP.
+		counterparty_node_id: PublicKey,
+		/// The error returned by the LSP.
+		error: ResponseError,
+	},
+}
+
+/// An event which an LSPS2 service should handle.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LSPS2ServiceEvent {
+	/// A client asked for the fees we charge for JIT channels.
+	///
+	/// The service should call [`LSPS2ServiceHandler::opening_fee_params_generated`] with the
+	/// fees it offers the client, or [`LSPS2ServiceHandler::invalid_token_provided`] if the
+	/// client's token is not acceptable.
+	///
+	/// [`LSPS2ServiceHandler::opening_fee_params_generated`]: crate::lsps2::service::LSPS2ServiceHandler::opening_fee_params_generated
+	/// [`LSPS2ServiceHandler::invalid_token_provided`]: crate::lsps2::service::LSPS2ServiceHandler::invalid_token_provided
+	GetInfo {
+		/// The id of the request.
+		request_id: RequestId,
+		/// The node id of the client.
+		counterparty_node_id: PublicKey,
+		/// The token given by the client, if any.
+		token: Option<String>,
+	},
+	/// A client wants to buy a JIT channel with fees we offered them (which were validated).
+	///
+	/// The service should pick an unused short channel id, which it is able to intercept payments
+	/// to, and call [`LSPS2ServiceHandler::invoice_parameters_generated`].
+	///
+	/// [`LSPS2ServiceHandler::invoice_parameters_generated`]: crate::lsps2::service::LSPS2ServiceHandler::invoice_parameters_generated
+	BuyRequest {
+		/// The id of the request.
+		request_id: RequestId,
+		/// The node id of the client.
+		counterparty_node_id: PublicKey,
+		/// The fees the client chose.
+		opening_fee_params: OpeningFeeParams,
+		/// The size of the payment which will open the channel, if fixed.
+		payment_size_msat: Option<u64>,
+	},
+	/// Enough has been paid to a JIT channel's short channel id to open the channel.
+	///
+	/// The service should open a channel to the client via [`ChannelManager::create_channel`]
+	/// large enough to forward `amt_to_forward_msat`, and forward the intercepted payment once
+	/// the channel is ready, deducting `opening_fee_msat`.
+	///
+	/// [`ChannelManager::create_channel`]: lightning::ln::channelmanager::ChannelManager::create_channel
+	OpenChannel {
+		/// The node id of the client.
+		their_network_key: PublicKey,
+		/// The amount to forward to the client, after deducting the opening fee.
+		amt_to_forward_msat: u64,
+		/// The opening fee to deduct.
+		opening_fee_msat: u64,
+		/// The user channel id given in
+		/// [`LSPS2ServiceHandler::invoice_parameters_generated`].
+		///
+		/// [`LSPS2ServiceHandler::invoice_parameters_generated`]: crate::lsps2::service::LSPS2ServiceHandler::invoice_parameters_generated
+		user_channel_id: u64,
+		/// The short channel id the payment was sent to.
+		intercept_scid: u64,
+	},
+}
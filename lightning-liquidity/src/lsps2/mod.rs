@@ -0,0 +1,18 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Types and primitives implementing LSPS2: just-in-time (JIT) channels, opened by an LSP when a
+//! payment to a client without sufficient inbound liquidity arrives, with the opening fee
+//! deducted from the payment.
+
+pub mod client;
+pub mod event;
+pub mod msgs;
+pub mod service;
+pub mod utils;
@@ -0,0 +1,167 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Message, request, and other primitive types used to implement LSPS2.
+
+use crate::lsps0::msgs::{RequestId, ResponseError};
+use crate::lsps2::utils::compute_promise;
+use crate::utils::{human_readable_scid, string_encoded, string_encoded_option, LSPSDateTime};
+
+use serde::{Deserialize, Serialize};
+
+/// The error code returned when the token given in a `lsps2.get_info` request is not recognized
+/// by the LSP.
+pub const LSPS2_GET_INFO_REQUEST_UNRECOGNIZED_OR_STALE_TOKEN_ERROR_CODE: i32 = 200;
+/// The error code returned when the `opening_fee_params` of a `lsps2.buy` request were not
+/// issued by the LSP or have expired.
+pub const LSPS2_BUY_REQUEST_INVALID_OPENING_FEE_PARAMS_ERROR_CODE: i32 = 201;
+/// The error code returned when the `payment_size_msat` of a `lsps2.buy` request is below the
+/// minimum, or too small to cover the opening fee.
+pub const LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_SMALL_ERROR_CODE: i32 = 202;
+/// The error code returned when the `payment_size_msat` of a `lsps2.buy` request is above the
+/// maximum.
+pub const LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_LARGE_ERROR_CODE: i32 = 203;
+
+/// A request made to an LSP to learn the fees it charges for opening JIT channels.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GetInfoRequest {
+	/// A token, e.g. a coupon, the LSP may use to adjust the fees it offers.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub token: Option<String>,
+}
+
+/// The fees and limits of a JIT channel offered by an LSP, before the LSP committed to them by
+/// computing their `promise`.
+///
+/// Converted into [`OpeningFeeParams`] by the service handler when responding to a
+/// `lsps2.get_info` request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RawOpeningFeeParams {
+	/// The minimum fee, in millisatoshis, charged for opening the channel.
+	pub min_fee_msat: u64,
+	/// The fee charged for opening the channel, in parts-per-million of the payment size.
+	pub proportional: u32,
+	/// The time until which the offer is valid.
+	pub valid_until: LSPSDateTime,
+	/// The number of blocks the LSP promises to keep the channel open for.
+	pub min_lifetime: u32,
+	/// The maximum `to_self_delay` the LSP will require of the client.
+	pub max_client_to_self_delay: u32,
+	/// The minimum payment size, in millisatoshis, the LSP will open a channel for.
+	pub min_payment_size_msat: u64,
+	/// The maximum payment size, in millisatoshis, the LSP will open a channel for.
+	pub max_payment_size_msat: u64,
+}
+
+impl RawOpeningFeeParams {
+	pub(crate) fn into_opening_fee_params(self, promise_secret: &[u8; 32]) -> OpeningFeeParams {
+		let promise = compute_promise(&self, promise_secret);
+		OpeningFeeParams {
+			min_fee_msat: self.min_fee_msat,
+			proportional: self.proportional,
+			valid_until: self.valid_until,
+			min_lifetime: self.min_lifetime,
+			max_client_to_self_delay: self.max_client_to_self_delay,
+			min_payment_size_msat: self.min_payment_size_msat,
+			max_payment_size_msat: self.max_payment_size_msat,
+			promise,
+		}
+	}
+}
+
+/// The fees and limits of a JIT channel offered by an LSP.
+///
+/// The `promise` allows the LSP to verify it issued these parameters when they are echoed back in
+/// a `lsps2.buy` request. Clients must not modify any of the fields.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpeningFeeParams {
+	/// The minimum fee, in millisatoshis, charged for opening the channel.
+	#[serde(with = "string_encoded")]
+	pub min_fee_msat: u64,
+	/// The fee charged for opening the channel, in parts-per-million of the payment size.
+	pub proportional: u32,
+	/// The time until which the offer is valid.
+	pub valid_until: LSPSDateTime,
+	/// The number of blocks the LSP promises to keep the channel open for.
+	pub min_lifetime: u32,
+	/// The maximum `to_self_delay` the LSP will require of the client.
+	pub max_client_to_self_delay: u32,
+	/// The minimum payment size, in millisatoshis, the LSP will open a channel for.
+	#[serde(with = "string_encoded")]
+	pub min_payment_size_msat: u64,
+	/// The maximum payment size, in millisatoshis, the LSP will open a channel for.
+	#[serde(with = "string_encoded")]
+	pub max_payment_size_msat: u64,
+	/// The LSP's commitment to the above parameters.
+	pub promise: String,
+}
+
+/// A response to a [`GetInfoRequest`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GetInfoResponse {
+	/// The fees and limits offered by the LSP, ordered by increasing fees.
+	pub opening_fee_params_menu: Vec<OpeningFeeParams>,
+}
+
+/// A request made to an LSP to buy a JIT channel with the given fees.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuyRequest {
+	/// One of the entries of an [`GetInfoResponse::opening_fee_params_menu`], unmodified.
+	pub opening_fee_params: OpeningFeeParams,
+	/// The size of the payment which will open the channel, if fixed. If `None`, the channel is
+	/// opened for the first payment received, whatever its size.
+	#[serde(default, skip_serializing_if = "Option::is_none", with = "string_encoded_option")]
+	pub payment_size_msat: Option<u64>,
+}
+
+/// A response to a [`BuyRequest`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuyResponse {
+	/// The short channel id the client should use in the route hint of its invoice. Payments to it
+	/// will be intercepted by the LSP and trigger the channel open.
+	#[serde(with = "human_readable_scid")]
+	pub jit_channel_scid: u64,
+	/// The CLTV expiry delta the client should use in the route hint of its invoice.
+	pub lsp_cltv_expiry_delta: u32,
+	/// Whether the LSP requires the client to reveal the payment preimage before it publishes the
+	/// funding transaction.
+	#[serde(default)]
+	pub client_trusts_lsp: bool,
+}
+
+/// An enum that captures all the valid JSON-RPC requests in the LSPS2 protocol.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LSPS2Request {
+	/// A request to learn the fees the LSP charges for JIT channels.
+	GetInfo(GetInfoRequest),
+	/// A request to buy a JIT channel.
+	Buy(BuyRequest),
+}
+
+/// An enum that captures all the valid JSON-RPC responses in the LSPS2 protocol.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LSPS2Response {
+	/// A successful response to a [`LSPS2Request::GetInfo`] request.
+	GetInfo(GetInfoResponse),
+	/// An error response to a [`LSPS2Request::GetInfo`] request.
+	GetInfoError(ResponseError),
+	/// A successful response to a [`LSPS2Request::Buy`] request.
+	Buy(BuyResponse),
+	/// An error response to a [`LSPS2Request::Buy`] request.
+	BuyError(ResponseError),
+}
+
+/// An enum that captures all valid JSON-RPC messages in the LSPS2 protocol.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LSPS2Message {
+	/// An LSPS2 JSON-RPC request.
+	Request(RequestId, LSPS2Request),
+	/// An LSPS2 JSON-RPC response.
+	Response(RequestId, LSPS2Response),
+}
@@ -0,0 +1,376 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Contains the main LSPS2 service object, [`LSPS2ServiceHandler`].
+
+use crate::events::{Event, EventQueue};
+use crate::lsps0::msgs::{error_response, LSPSMessage, RequestId, LSPS0_CLIENT_REJECTED_ERROR_CODE};
+use crate::lsps2::event::LSPS2ServiceEvent;
+use crate::lsps2::msgs::{BuyRequest, BuyResponse, GetInfoResponse, LSPS2Message, LSPS2Request, LSPS2Response,
+	OpeningFeeParams, RawOpeningFeeParams, LSPS2_BUY_REQUEST_INVALID_OPENING_FEE_PARAMS_ERROR_CODE,
+	LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_LARGE_ERROR_CODE, LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_SMALL_ERROR_CODE,
+	LSPS2_GET_INFO_REQUEST_UNRECOGNIZED_OR_STALE_TOKEN_ERROR_CODE};
+use crate::lsps2::utils::{compute_opening_fee, is_valid_opening_fee_params};
+use crate::message_queue::MessageQueue;
+use crate::utils::TimeSource;
+
+use bitcoin::secp256k1::PublicKey;
+
+use lightning::ln::PaymentHash;
+use lightning::util::errors::APIError;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// The maximum number of requests a single peer may have pending with the user at once.
+const MAX_PENDING_REQUESTS_PER_PEER: usize = 10;
+/// The maximum number of requests all peers together may have pending with the user at once.
+const MAX_TOTAL_PENDING_REQUESTS: usize = 1000;
+
+/// Service-side configuration options for LSPS2 JIT channels.
+#[derive(Clone, Debug)]
+pub struct LSPS2ServiceConfig {
+	/// The secret used to compute the `promise` of the fees we offer, allowing us to check that
+	/// fees a client wants to buy a channel with were issued by us, without storing them.
+	///
+	/// This should be random and kept secret. Changing it invalidates all outstanding offers.
+	pub promise_secret: [u8; 32],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OutboundJITChannelState {
+	AwaitingPayment,
+	PendingChannelOpen,
+}
+
+struct OutboundJITChannel {
+	counterparty_node_id: PublicKey,
+	user_channel_id: u64,
+	opening_fee_params: OpeningFeeParams,
+	payment_size_msat: Option<u64>,
+	payment_hash: Option<PaymentHash>,
+	intercepted_msat: u64,
+	state: OutboundJITChannelState,
+}
+
+impl OutboundJITChannel {
+	/// Returns whether the offered fees expired before any part of the payment arrived, in which
+	/// case the channel is no longer on offer.
+	fn is_expired(&self, unix_secs_now: u64) -> bool {
+		self.state == OutboundJITChannelState::AwaitingPayment && self.payment_hash.is_none()
+			&& self.opening_fee_params.valid_until.is_before(unix_secs_now)
+	}
+}
+
+/// The main object allowing to sell JIT channels to clients via LSPS2.
+///
+/// The flow is as follows:
+/// 1. A client asks for our fees, surfaced as an [`LSPS2ServiceEvent::GetInfo`] event, to which
+///    the user responds via [`Self::opening_fee_params_generated`].
+/// 2. The client buys a channel with one of the fees, which (once validated) is surfaced as an
+///    [`LSPS2ServiceEvent::BuyRequest`] event. The user picks a short channel id for the client
+///    to use in its invoice and calls [`Self::invoice_parameters_generated`].
+/// 3. Payments sent to that short channel id must be held and reported via
+///    [`Self::htlc_intercepted`]. Once enough has been paid, an [`LSPS2ServiceEvent::OpenChannel`]
+///    event is generated, upon which the user opens the channel via
+///    [`ChannelManager::create_channel`] and forwards the payments, less the opening fee. If the
+///    payments have to be failed back instead, the user calls [`Self::htlc_failed`].
+/// 4. Once the channel is ready and the payments were forwarded over it, the user calls
+///    [`Self::channel_ready`], after which the JIT channel is forgotten.
+///
+/// Requests are rejected while the client has too many of them pending with the user, and JIT
+/// channels are forgotten once their fees expire before being paid for.
+///
+/// Note that [`ChannelManager`] can currently neither hold payments to unknown short channel ids
+/// nor forward less than the amount an HTLC's onion asks for, so step 3 requires the service to
+/// intercept and forward payments by other means.
+///
+/// [`ChannelManager`]: lightning::ln::channelmanager::ChannelManager
+/// [`ChannelManager::create_channel`]: lightning::ln::channelmanager::ChannelManager::create_channel
+pub struct LSPS2ServiceHandler {
+	pending_messages: Arc<MessageQueue>,
+	pending_events: Arc<EventQueue>,
+	config: LSPS2ServiceConfig,
+	pending_requests: Mutex<HashMap<PublicKey, HashMap<RequestId, LSPS2Request>>>,
+	outbound_channels_by_intercept_scid: Mutex<HashMap<u64, OutboundJITChannel>>,
+	time_source: Arc<dyn TimeSource>,
+}
+
+impl LSPS2ServiceHandler {
+	pub(crate) fn new(pending_messages: Arc<MessageQueue>, pending_events: Arc<EventQueue>,
+		config: LSPS2ServiceConfig, time_source: Arc<dyn TimeSource>) -> Self {
+		Self {
+			pending_messages, pending_events, config,
+			pending_requests: Mutex::new(HashMap::new()),
+			outbound_channels_by_intercept_scid: Mutex::new(HashMap::new()),
+			time_source,
+		}
+	}
+
+	fn enqueue_response(&self, counterparty_node_id: &PublicKey, request_id: RequestId, response: LSPS2Response) {
+		self.pending_messages.enqueue(counterparty_node_id,
+			LSPSMessage::LSPS2(LSPS2Message::Response(request_id, response)));
+	}
+
+	pub(crate) fn handle_request(&self, request_id: RequestId, request: LSPS2Request, counterparty_node_id: &PublicKey) {
+		let mut pending_requests = self.pending_requests.lock().unwrap();
+		let total_pending_requests: usize = pending_requests.values().map(|requests| requests.len()).sum();
+		let peer_pending_requests = pending_requests.get(counterparty_node_id).map_or(0, |requests| requests.len());
+		if total_pending_requests >= MAX_TOTAL_PENDING_REQUESTS || peer_pending_requests >= MAX_PENDING_REQUESTS_PER_PEER {
+			let error = error_response(LSPS0_CLIENT_REJECTED_ERROR_CODE, "Too many pending requests");
+			let response = match request {
+				LSPS2Request::GetInfo(_) => LSPS2Response::GetInfoError(error),
+				LSPS2Request::Buy(_) => LSPS2Response::BuyError(error),
+			};
+			self.enqueue_response(counterparty_node_id, request_id, response);
+			return;
+		}
+
+		let event = match &request {
+			LSPS2Request::GetInfo(params) => LSPS2ServiceEvent::GetInfo {
+				request_id: request_id.clone(),
+				counterparty_node_id: *counterparty_node_id,
+				token: params.token.clone(),
+			},
+			LSPS2Request::Buy(params) => {
+				if let Err(error_code) = self.check_buy_request(params) {
+					let message = match error_code {
+						LSPS2_BUY_REQUEST_INVALID_OPENING_FEE_PARAMS_ERROR_CODE => "Invalid opening fee params",
+						LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_SMALL_ERROR_CODE => "Payment size too small",
+						_ => "Payment size too large",
+					};
+					self.enqueue_response(counterparty_node_id, request_id,
+						LSPS2Response::BuyError(error_response(error_code, message)));
+					return;
+				}
+				LSPS2ServiceEvent::BuyRequest {
+					request_id: request_id.clone(),
+					counterparty_node_id: *counterparty_node_id,
+					opening_fee_params: params.opening_fee_params.clone(),
+					payment_size_msat: params.payment_size_msat,
+				}
+			},
+		};
+		pending_requests.entry(*counterparty_node_id).or_insert_with(HashMap::new).insert(request_id, request);
+		self.pending_events.enqueue(Event::LSPS2Service(event));
+	}
+
+	/// Checks the given buy request, returning the error code to respond with if it is invalid.
+	fn check_buy_request(&self, params: &BuyRequest) -> Result<(), i32> {
+		let fee_params = &params.opening_fee_params;
+		if !is_valid_opening_fee_params(fee_params, &self.config.promise_secret) {
+			return Err(LSPS2_BUY_REQUEST_INVALID_OPENING_FEE_PARAMS_ERROR_CODE);
+		}
+		if let Some(payment_size_msat) = params.payment_size_msat {
+			if payment_size_msat < fee_params.min_payment_size_msat {
+				return Err(LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_SMALL_ERROR_CODE);
+			}
+			if payment_size_msat > fee_params.max_payment_size_msat {
+				return Err(LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_LARGE_ERROR_CODE);
+			}
+			match compute_opening_fee(payment_size_msat, fee_params.min_fee_msat, fee_params.proportional as u64) {
+				Some(fee) if fee < payment_size_msat => {},
+				Some(_) => return Err(LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_SMALL_ERROR_CODE),
+				None => return Err(LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_LARGE_ERROR_CODE),
+			}
+		}
+		Ok(())
+	}
+
+	/// Removes the given pending request, if it is of the kind `is_expected_request` accepts.
+	fn take_pending_request<F: Fn(&LSPS2Request) -> bool>(&self, counterparty_node_id: &PublicKey,
+		request_id: &RequestId, is_expected_request: F) -> Result<LSPS2Request, APIError> {
+		let mut pending_requests = self.pending_requests.lock().unwrap();
+		let peer_requests = pending_requests.get_mut(counterparty_node_id)
+			.ok_or_else(|| APIError::APIMisuseError { err: format!("No pending requests for peer {}", counterparty_node_id) })?;
+		match peer_requests.get(request_id) {
+			Some(request) if is_expected_request(request) => {},
+			Some(_) => return Err(APIError::APIMisuseError { err: format!("Request {} is of another kind", request_id.0) }),
+			None => return Err(APIError::APIMisuseError { err: format!("No pending request with id {}", request_id.0) }),
+		}
+		let request = peer_requests.remove(request_id).unwrap();
+		if peer_requests.is_empty() {
+			pending_requests.remove(counterparty_node_id);
+		}
+		Ok(request)
+	}
+
+	/// Responds to an [`LSPS2ServiceEvent::GetInfo`] event with the fees we offer the client.
+	///
+	/// The offers are committed to using [`LSPS2ServiceConfig::promise_secret`] and sent ordered
+	/// by increasing fees.
+	pub fn opening_fee_params_generated(&self, counterparty_node_id: &PublicKey, request_id: RequestId,
+		mut opening_fee_params_menu: Vec<RawOpeningFeeParams>) -> Result<(), APIError> {
+		self.take_pending_request(counterparty_node_id, &request_id,
+			|request| if let LSPS2Request::GetInfo(_) = request { true } else { false })?;
+
+		opening_fee_params_menu.sort_by_key(|params| (params.min_fee_msat, params.proportional));
+		let opening_fee_params_menu = opening_fee_params_menu.into_iter()
+			.map(|params| params.into_opening_fee_params(&self.config.promise_secret))
+			.collect();
+		self.enqueue_response(counterparty_node_id, request_id,
+			LSPS2Response::GetInfo(GetInfoResponse { opening_fee_params_menu }));
+		Ok(())
+	}
+
+	/// Responds to an [`LSPS2ServiceEvent::GetInfo`] event, rejecting the client's token.
+	pub fn invalid_token_provided(&self, counterparty_node_id: &PublicKey, request_id: RequestId) -> Result<(), APIError> {
+		self.take_pending_request(counterparty_node_id, &request_id,
+			|request| if let LSPS2Request::GetInfo(_) = request { true } else { false })?;
+
+		self.enqueue_response(counterparty_node_id, request_id, LSPS2Response::GetInfoError(error_response(
+			LSPS2_GET_INFO_REQUEST_UNRECOGNIZED_OR_STALE_TOKEN_ERROR_CODE, "Unrecognized or stale token")));
+		Ok(())
+	}
+
+	/// Responds to an [`LSPS2ServiceEvent::BuyRequest`] event, telling the client the short
+	/// channel id and CLTV expiry delta to use in its invoice.
+	///
+	/// `intercept_scid` must not be the short channel id of any real channel and must not have
+	/// been used for another JIT channel. `user_channel_id` is reported back in the
+	/// [`LSPS2ServiceEvent::OpenChannel`] event.
+	pub fn invoice_parameters_generated(&self, counterparty_node_id: &PublicKey, request_id: RequestId,
+		intercept_scid: u64, cltv_expiry_delta: u32, client_trusts_lsp: bool, user_channel_id: u64
+	) -> Result<(), APIError> {
+		let mut outbound_channels = self.outbound_channels_by_intercept_scid.lock().unwrap();
+		let now = self.time_source.unix_secs_now();
+		outbound_channels.retain(|_, channel| !channel.is_expired(now));
+		if outbound_channels.contains_key(&intercept_scid) {
+			return Err(APIError::APIMisuseError { err: format!("Intercept scid {} is already in use", intercept_scid) });
+		}
+		let params = match self.take_pending_request(counterparty_node_id, &request_id,
+			|request| if let LSPS2Request::Buy(_) = request { true } else { false })? {
+			LSPS2Request::Buy(params) => params,
+			LSPS2Request::GetInfo(_) => unreachable!(),
+		};
+
+		outbound_channels.insert(intercept_scid, OutboundJITChannel {
+			counterparty_node_id: *counterparty_node_id,
+			user_channel_id,
+			opening_fee_params: params.opening_fee_params,
+			payment_size_msat: params.payment_size_msat,
+			payment_hash: None,
+			intercepted_msat: 0,
+			state: OutboundJITChannelState::AwaitingPayment,
+		});
+		self.enqueue_response(counterparty_node_id, request_id, LSPS2Response::Buy(BuyResponse {
+			jit_channel_scid: intercept_scid,
+			lsp_cltv_expiry_delta: cltv_expiry_delta,
+			client_trusts_lsp,
+		}));
+		Ok(())
+	}
+
+	/// Reports a payment (part) sent to the given intercept short channel id, which should be held
+	/// until the channel is open.
+	///
+	/// Once the payment size the client bought the channel for has been reached (or, if none was
+	/// given, on the first payment), an [`LSPS2ServiceEvent::OpenChannel`] event is generated.
+	///
+	/// Returns [`APIError::ChannelUnavailable`] if the payment must be failed back, e.g. because it
+	/// does not cover the opening fee, belongs to a different payment than earlier parts, or the
+	/// offered fees expired before its first part arrived (in which case the JIT channel is
+	/// forgotten).
+	pub fn htlc_intercepted(&self, intercept_scid: u64, payment_hash: PaymentHash,
+		expected_outbound_amount_msat: u64) -> Result<(), APIError> {
+		let mut outbound_channels = self.outbound_channels_by_intercept_scid.lock().unwrap();
+		let now = self.time_source.unix_secs_now();
+		if outbound_channels.get(&intercept_scid).map_or(false, |channel| channel.is_expired(now)) {
+			outbound_channels.remove(&intercept_scid);
+			return Err(APIError::ChannelUnavailable { err: "The opening fee params have expired".to_string() });
+		}
+		let channel = outbound_channels.get_mut(&intercept_scid)
+			.ok_or_else(|| APIError::APIMisuseError { err: format!("Unknown intercept scid {}", intercept_scid) })?;
+		if channel.payment_hash.map_or(false, |hash| hash != payment_hash) {
+			return Err(APIError::ChannelUnavailable { err: "Payment hash differs from earlier parts".to_string() });
+		}
+		if channel.state == OutboundJITChannelState::PendingChannelOpen {
+			// Further parts of the payment we are already opening the channel for.
+			return Ok(());
+		}
+
+		let fee_params = &channel.opening_fee_params;
+		let payment_size_msat = match channel.payment_size_msat {
+			Some(payment_size_msat) => {
+				let intercepted_msat = channel.intercepted_msat.checked_add(expected_outbound_amount_msat)
+					.ok_or_else(|| APIError::ChannelUnavailable { err: "Payment amount overflows".to_string() })?;
+				channel.payment_hash = Some(payment_hash);
+				channel.intercepted_msat = intercepted_msat;
+				if intercepted_msat < payment_size_msat {
+					return Ok(());
+				}
+				payment_size_msat
+			},
+			None => {
+				if expected_outbound_amount_msat < fee_params.min_payment_size_msat
+					|| expected_outbound_amount_msat > fee_params.max_payment_size_msat {
+					return Err(APIError::ChannelUnavailable {
+						err: format!("Payment of {} msat is out of the offered bounds", expected_outbound_amount_msat)
+					});
+				}
+				channel.intercepted_msat = expected_outbound_amount_msat;
+				expected_outbound_amount_msat
+			},
+		};
+
+		let opening_fee_msat = match compute_opening_fee(payment_size_msat, fee_params.min_fee_msat, fee_params.proportional as u64) {
+			Some(fee) if fee < channel.intercepted_msat => fee,
+			_ => return Err(APIError::ChannelUnavailable {
+				err: format!("Payment of {} msat does not cover the opening fee", channel.intercepted_msat)
+			}),
+		};
+		channel.payment_hash = Some(payment_hash);
+		channel.state = OutboundJITChannelState::PendingChannelOpen;
+		self.pending_events.enqueue(Event::LSPS2Service(LSPS2ServiceEvent::OpenChannel {
+			their_network_key: channel.counterparty_node_id,
+			amt_to_forward_msat: channel.intercepted_msat - opening_fee_msat,
+			opening_fee_msat,
+			user_channel_id: channel.user_channel_id,
+			intercept_scid,
+		}));
+		Ok(())
+	}
+
+	/// Reports that the payment parts held for the given intercept short channel id were failed
+	/// back, e.g. because they timed out or the channel could not be opened. All held parts must
+	/// be failed back before calling this.
+	///
+	/// The client may then pay again, unless the offered fees have expired by now, in which case
+	/// the JIT channel is forgotten.
+	pub fn htlc_failed(&self, intercept_scid: u64) -> Result<(), APIError> {
+		let mut outbound_channels = self.outbound_channels_by_intercept_scid.lock().unwrap();
+		let channel = outbound_channels.get_mut(&intercept_scid)
+			.ok_or_else(|| APIError::APIMisuseError { err: format!("Unknown intercept scid {}", intercept_scid) })?;
+		channel.payment_hash = None;
+		channel.intercepted_msat = 0;
+		channel.state = OutboundJITChannelState::AwaitingPayment;
+		if channel.is_expired(self.time_source.unix_secs_now()) {
+			outbound_channels.remove(&intercept_scid);
+		}
+		Ok(())
+	}
+
+	/// Reports that the channel opened in response to an [`LSPS2ServiceEvent::OpenChannel`] event
+	/// for the given intercept short channel id is ready and the held payment parts were forwarded
+	/// over it, upon which the JIT channel is forgotten.
+	///
+	/// Returns [`APIError::APIMisuseError`] if no channel open is pending for `intercept_scid`.
+	pub fn channel_ready(&self, intercept_scid: u64) -> Result<(), APIError> {
+		let mut outbound_channels = self.outbound_channels_by_intercept_scid.lock().unwrap();
+		match outbound_channels.get(&intercept_scid) {
+			Some(channel) if channel.state == OutboundJITChannelState::PendingChannelOpen => {
+				outbound_channels.remove(&intercept_scid);
+				Ok(())
+			},
+			_ => Err(APIError::APIMisuseError {
+				err: format!("No channel open is pending for intercept scid {}", intercept_scid)
+			}),
+		}
+	}
+}
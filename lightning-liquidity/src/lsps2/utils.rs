@@ -0,0 +1,111 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Utilities for computing and checking LSPS2 opening fees.
+
+use crate::lsps2::msgs::{OpeningFeeParams, RawOpeningFeeParams};
+
+use bitcoin::hashes::{Hash, HashEngine};
+use bitcoin::hashes::cmp::fixed_time_eq;
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use bitcoin::hashes::sha256::Hash as Sha256;
+
+/// Computes the `promise` committing to the given parameters, as the hex-encoded HMAC-SHA256 of
+/// the parameters keyed by `promise_secret`.
+pub(crate) fn compute_promise(params: &RawOpeningFeeParams, promise_secret: &[u8; 32]) -> String {
+	let mut hmac = HmacEngine::<Sha256>::new(promise_secret);
+	hmac.input(&params.min_fee_msat.to_be_bytes());
+	hmac.input(&params.proportional.to_be_bytes());
+	hmac.input(params.valid_until.as_str().as_bytes());
+	hmac.input(&params.min_lifetime.to_be_bytes());
+	hmac.input(&params.max_client_to_self_delay.to_be_bytes());
+	hmac.input(&params.min_payment_size_msat.to_be_bytes());
+	hmac.input(&params.max_payment_size_msat.to_be_bytes());
+	Hmac::from_engine(hmac).into_inner().to_hex()
+}
+
+/// Returns whether the given parameters were issued using `promise_secret` and have not expired.
+pub fn is_valid_opening_fee_params(fee_params: &OpeningFeeParams, promise_secret: &[u8; 32]) -> bool {
+	if fee_params.valid_until.is_past() {
+		return false;
+	}
+	let raw = RawOpeningFeeParams {
+		min_fee_msat: fee_params.min_fee_msat,
+		proportional: fee_params.proportional,
+		valid_until: fee_params.valid_until.clone(),
+		min_lifetime: fee_params.min_lifetime,
+		max_client_to_self_delay: fee_params.max_client_to_self_delay,
+		min_payment_size_msat: fee_params.min_payment_size_msat,
+		max_payment_size_msat: fee_params.max_payment_size_msat,
+	};
+	// Compare in constant time so as not to leak how much of a forged promise is correct.
+	let promise = compute_promise(&raw, promise_secret);
+	promise.len() == fee_params.promise.len() && fixed_time_eq(promise.as_bytes(), fee_params.promise.as_bytes())
+}
+
+/// Computes the opening fee for a payment of `payment_size_msat`, i.e., the greater of
+/// `min_fee_msat` and `proportional` parts-per-million of the payment size, rounded up.
+///
+/// Returns `None` if the computation overflows.
+pub fn compute_opening_fee(payment_size_msat: u64, opening_fee_min_fee_msat: u64, opening_fee_proportional: u64) -> Option<u64> {
+	payment_size_msat.checked_mul(opening_fee_proportional)
+		.and_then(|f| f.checked_add(999_999))
+		.map(|f| f / 1_000_000)
+		.map(|f| core::cmp::max(f, opening_fee_min_fee_msat))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utils::LSPSDateTime;
+
+	fn raw_params(valid_until: LSPSDateTime) -> RawOpeningFeeParams {
+		RawOpeningFeeParams {
+			min_fee_msat: 546_000,
+			proportional: 1_200,
+			valid_until,
+			min_lifetime: 1_008,
+			max_client_to_self_delay: 2_016,
+			min_payment_size_msat: 1_000_000,
+			max_payment_size_msat: 1_000_000_000,
+		}
+	}
+
+	#[test]
+	fn opening_fee_computation() {
+		// The proportional fee is rounded up...
+		assert_eq!(compute_opening_fee(1_000_001, 0, 1_000), Some(1_001));
+		assert_eq!(compute_opening_fee(1_000_000, 0, 1_000), Some(1_000));
+		// ...but never below the minimum fee.
+		assert_eq!(compute_opening_fee(1_000_000, 546_000, 1_200), Some(546_000));
+		assert_eq!(compute_opening_fee(1_000_000_000, 546_000, 1_200), Some(1_200_000));
+		assert_eq!(compute_opening_fee(u64::max_value(), 546_000, 1_200), None);
+	}
+
+	#[test]
+	fn promise_validation() {
+		let secret = [42; 32];
+		let params = raw_params(LSPSDateTime::from_now(3600)).into_opening_fee_params(&secret);
+		assert_eq!(params.promise.len(), 64);
+		assert!(is_valid_opening_fee_params(&params, &secret));
+		assert!(!is_valid_opening_fee_params(&params, &[43; 32]));
+
+		let mut tampered = params.clone();
+		tampered.min_fee_msat -= 1;
+		assert!(!is_valid_opening_fee_params(&tampered, &secret));
+
+		let mut truncated = params.clone();
+		truncated.promise.pop();
+		assert!(!is_valid_opening_fee_params(&truncated, &secret));
+
+		let expired = raw_params(LSPSDateTime::from_unix_secs(1_000)).into_opening_fee_params(&secret);
+		assert!(!is_valid_opening_fee_params(&expired, &secret));
+	}
+}
@@ -0,0 +1,857 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Contains the [`LiquidityManager`], which plugs the LSPS protocols into the [`PeerManager`].
+//!
+//! [`PeerManager`]: lightning::ln::peer_handler::PeerManager
+
+use crate::events::{Event, EventQueue};
+use crate::lsps0::client::LSPS0ClientHandler;
+use crate::lsps0::msgs::{error_response, LSPS0Message, LSPS0Request, LSPS0Response, LSPSMessage, LSPSMethod,
+	LSPSParseError, ListProtocolsResponse, RawLSPSMessage, RequestId, JSONRPC_METHOD_NOT_FOUND_ERROR_CODE,
+	LSPS_MESSAGE_TYPE_ID};
+use crate::lsps1::client::{LSPS1ClientConfig, LSPS1ClientHandler};
+use crate::lsps1::msgs::LSPS1Message;
+use crate::lsps1::service::{LSPS1ServiceConfig, LSPS1ServiceHandler};
+use crate::lsps2::client::{LSPS2ClientConfig, LSPS2ClientHandler};
+use crate::lsps2::msgs::LSPS2Message;
+use crate::lsps2::service::{LSPS2ServiceConfig, LSPS2ServiceHandler};
+use crate::message_queue::MessageQueue;
+use crate::utils::{RequestIdGenerator, SystemTimeSource, TimeSource};
+
+use bitcoin::secp256k1::PublicKey;
+
+use lightning::io::Read;
+use lightning::ln::features::{InitFeatures, NodeFeatures};
+use lightning::ln::msgs::{DecodeError, ErrorAction, LightningError};
+use lightning::ln::peer_handler::CustomMessageHandler;
+use lightning::ln::wire::CustomMessageReader;
+use lightning::util::logger::Level;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// The (optional) feature bit LSPs set in their `init` and `node_announcement` messages to signal
+/// they support LSPS0.
+pub const LSPS_FEATURE_BIT: usize = 729;
+
+/// The protocols we act as a service for, and their configuration.
+#[derive(Clone, Debug, Default)]
+pub struct LiquidityServiceConfig {
+	/// The configuration of the LSPS1 service, or `None` to not sell channels via LSPS1.
+	pub lsps1_service_config: Option<LSPS1ServiceConfig>,
+	/// The configuration of the LSPS2 service, or `None` to not sell JIT channels via LSPS2.
+	pub lsps2_service_config: Option<LSPS2ServiceConfig>,
+}
+
+/// The protocols we act as a client for, and their configuration.
+#[derive(Clone, Debug, Default)]
+pub struct LiquidityClientConfig {
+	/// The configuration of the LSPS1 client, or `None` to not buy channels via LSPS1.
+	pub lsps1_client_config: Option<LSPS1ClientConfig>,
+	/// The configuration of the LSPS2 client, or `None` to not buy JIT channels via LSPS2.
+	pub lsps2_client_config: Option<LSPS2ClientConfig>,
+}
+
+/// The main interface into the LSPS protocols, acting as a client, a service (i.e., an LSP), or
+/// both.
+///
+/// It implements [`CustomMessageHandler`] and must be given to the [`PeerManager`], through which
+/// all LSPS messages are sent and received. Requests are made through the handlers of each
+/// protocol (e.g. [`Self::lsps2_client_handler`]), while responses and requests which need the
+/// user's attention are returned as [`Event`]s from [`Self::get_and_clear_pending_events`].
+///
+/// When acting as a service, we signal [`LSPS_FEATURE_BIT`] in our `init` messages. To also
+/// signal it in our `node_announcement`, pass [`CustomMessageHandler::provided_node_features`] to
+/// [`ChannelManager::broadcast_node_announcement_with_features`].
+///
+/// [`PeerManager`]: lightning::ln::peer_handler::PeerManager
+/// [`ChannelManager::broadcast_node_announcement_with_features`]: lightning::ln::channelmanager::ChannelManager::broadcast_node_announcement_with_features
+pub struct LiquidityManager {
+	pending_messages: Arc<MessageQueue>,
+	pending_events: Arc<EventQueue>,
+	/// The method of each request we sent to each peer and have not received a response to yet,
+	/// which we need to parse the response.
+	outbound_requests: Mutex<HashMap<PublicKey, HashMap<RequestId, LSPSMethod>>>,
+	lsps0_client_handler: LSPS0ClientHandler,
+	lsps1_client_handler: Option<LSPS1ClientHandler>,
+	lsps1_service_handler: Option<LSPS1ServiceHandler>,
+	lsps2_client_handler: Option<LSPS2ClientHandler>,
+	lsps2_service_handler: Option<LSPS2ServiceHandler>,
+}
+
+impl LiquidityManager {
+	/// Constructs a new `LiquidityManager`.
+	///
+	/// `entropy_source` must be random data, used to generate the ids of our requests and orders.
+	pub fn new(entropy_source: &[u8; 32], service_config: Option<LiquidityServiceConfig>,
+		client_config: Option<LiquidityClientConfig>) -> Self {
+		Self::with_time_source(entropy_source, service_config, client_config, Arc::new(SystemTimeSource))
+	}
+
+	/// Constructs a new `LiquidityManager` which reads the current time from `time_source`.
+	pub(crate) fn with_time_source(entropy_source: &[u8; 32], service_config: Option<LiquidityServiceConfig>,
+		client_config: Option<LiquidityClientConfig>, time_source: Arc<dyn TimeSource>) -> Self {
+		let pending_messages = Arc::new(MessageQueue::new());
+		let pending_events = Arc::new(EventQueue::new());
+		let request_id_generator = Arc::new(RequestIdGenerator::new(entropy_source));
+		let service_config = service_config.unwrap_or_default();
+		let client_config = client_config.unwrap_or_default();
+
+		let lsps0_client_handler = LSPS0ClientHandler::new(Arc::clone(&pending_messages),
+			Arc::clone(&pending_events), Arc::clone(&request_id_generator));
+		let lsps1_client_handler = client_config.lsps1_client_config.map(|config|
+			LSPS1ClientHandler::new(Arc::clone(&pending_messages), Arc::clone(&pending_events),
+				Arc::clone(&request_id_generator), config));
+		let lsps1_service_handler = service_config.lsps1_service_config.map(|config|
+			LSPS1ServiceHandler::new(Arc::clone(&pending_messages), Arc::clone(&pending_events),
+				Arc::clone(&request_id_generator), config));
+		let lsps2_client_handler = client_config.lsps2_client_config.map(|config|
+			LSPS2ClientHandler::new(Arc::clone(&pending_messages), Arc::clone(&pending_events),
+				Arc::clone(&request_id_generator), config));
+		let lsps2_service_handler = service_config.lsps2_service_config.map(|config|
+			LSPS2ServiceHandler::new(Arc::clone(&pending_messages), Arc::clone(&pending_events), config,
+				Arc::clone(&time_source)));
+
+		Self {
+			pending_messages,
+			pending_events,
+			outbound_requests: Mutex::new(HashMap::new()),
+			lsps0_client_handler,
+			lsps1_client_handler,
+			lsps1_service_handler,
+			lsps2_client_handler,
+			lsps2_service_handler,
+		}
+	}
+
+	/// Returns the LSPS0 client handler, used to discover the protocols a peer supports.
+	pub fn lsps0_client_handler(&self) -> &LSPS0ClientHandler {
+		&self.lsps0_client_handler
+	}
+
+	/// Returns the LSPS1 client handler, if configured.
+	pub fn lsps1_client_handler(&self) -> Option<&LSPS1ClientHandler> {
+		self.lsps1_client_handler.as_ref()
+	}
+
+	/// Returns the LSPS1 service handler, if configured.
+	pub fn lsps1_service_handler(&self) -> Option<&LSPS1ServiceHandler> {
+		self.lsps1_service_handler.as_ref()
+	}
+
+	/// Returns the LSPS2 client handler, if configured.
+	pub fn lsps2_client_handler(&self) -> Option<&LSPS2ClientHandler> {
+		self.lsps2_client_handler.as_ref()
+	}
+
+	/// Returns the LSPS2 service handler, if configured.
+	pub fn lsps2_service_handler(&self) -> Option<&LSPS2ServiceHandler> {
+		self.lsps2_service_handler.as_ref()
+	}
+
+	/// Returns and clears all events which have been generated since the last call.
+	pub fn get_and_clear_pending_events(&self) -> Vec<Event> {
+		self.pending_events.get_and_clear_pending_events()
+	}
+
+	fn is_service(&self) -> bool {
+		self.lsps1_service_handler.is_some() || self.lsps2_service_handler.is_some()
+	}
+
+	fn handle_lsps_message(&self, msg: LSPSMessage, sender_node_id: &PublicKey) -> Result<(), LightningError> {
+		let method_not_found = |request_id: RequestId| {
+			self.pending_messages.enqueue(sender_node_id, LSPSMessage::Invalid {
+				id: Some(request_id),
+				error: error_response(JSONRPC_METHOD_NOT_FOUND_ERROR_CODE, "Method not found"),
+			});
+		};
+		match msg {
+			LSPSMessage::Invalid { .. } => {
+				// We never parse messages into this variant, it is only used to send errors.
+				debug_assert!(false);
+			},
+			LSPSMessage::LSPS0(LSPS0Message::Request(request_id, LSPS0Request::ListProtocols(_))) => {
+				let mut protocols = Vec::new();
+				if self.lsps1_service_handler.is_some() { protocols.push(1); }
+				if self.lsps2_service_handler.is_some() { protocols.push(2); }
+				self.pending_messages.enqueue(sender_node_id, LSPSMessage::LSPS0(LSPS0Message::Response(
+					request_id, LSPS0Response::ListProtocols(ListProtocolsResponse { protocols }))));
+			},
+			LSPSMessage::LSPS0(LSPS0Message::Response(_, response)) => {
+				self.lsps0_client_handler.handle_response(response, sender_node_id);
+			},
+			LSPSMessage::LSPS1(LSPS1Message::Request(request_id, request)) => {
+				match &self.lsps1_service_handler {
+					Some(handler) => handler.handle_request(request_id, request, sender_node_id),
+					None => method_not_found(request_id),
+				}
+			},
+			LSPSMessage::LSPS1(LSPS1Message::Response(request_id, response)) => {
+				match &self.lsps1_client_handler {
+					Some(handler) => handler.handle_response(request_id, response, sender_node_id),
+					None => return Err(LightningError {
+						err: "Received LSPS1 response without an LSPS1 client configured".to_string(),
+						action: ErrorAction::IgnoreAndLog(Level::Info),
+					}),
+				}
+			},
+			LSPSMessage::LSPS2(LSPS2Message::Request(request_id, request)) => {
+				match &self.lsps2_service_handler {
+					Some(handler) => handler.handle_request(request_id, request, sender_node_id),
+					None => method_not_found(request_id),
+				}
+			},
+			LSPSMessage::LSPS2(LSPS2Message::Response(request_id, response)) => {
+				match &self.lsps2_client_handler {
+					Some(handler) => handler.handle_response(request_id, response, sender_node_id),
+					None => return Err(LightningError {
+						err: "Received LSPS2 response without an LSPS2 client configured".to_string(),
+						action: ErrorAction::IgnoreAndLog(Level::Info),
+					}),
+				}
+			},
+		}
+		Ok(())
+	}
+}
+
+impl CustomMessageReader for LiquidityManager {
+	type CustomMessage = RawLSPSMessage;
+
+	fn read<R: Read>(&self, message_type: u16, buffer: &mut R) -> Result<Option<Self::CustomMessage>, DecodeError> {
+		if message_type != LSPS_MESSAGE_TYPE_ID {
+			return Ok(None);
+		}
+		let mut payload = Vec::new();
+		buffer.read_to_end(&mut payload).map_err(|e| DecodeError::Io(e.kind()))?;
+		let payload = String::from_utf8(payload).map_err(|_| DecodeError::InvalidValue)?;
+		Ok(Some(RawLSPSMessage { payload }))
+	}
+}
+
+impl CustomMessageHandler for LiquidityManager {
+	fn handle_custom_message(&self, msg: RawLSPSMessage, sender_node_id: &PublicKey) -> Result<(), LightningError> {
+		let message = {
+			let mut outbound_requests = self.outbound_requests.lock().unwrap();
+			let mut no_requests = HashMap::new();
+			let peer_requests = outbound_requests.get_mut(sender_node_id).unwrap_or(&mut no_requests);
+			LSPSMessage::from_json(&msg.payload, peer_requests)
+		};
+		match message {
+			Ok(message) => self.handle_lsps_message(message, sender_node_id),
+			Err(LSPSParseError::InvalidRequest(id, error)) => {
+				let err = format!("Received invalid LSPS request: {}", error.message);
+				self.pending_messages.enqueue(sender_node_id, LSPSMessage::Invalid { id, error });
+				Err(LightningError { err, action: ErrorAction::IgnoreAndLog(Level::Debug) })
+			},
+			Err(LSPSParseError::InvalidResponse(err)) => Err(LightningError {
+				err: format!("Received invalid LSPS response: {}", err),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			}),
+		}
+	}
+
+	fn get_and_clear_pending_msg(&self) -> Vec<(PublicKey, RawLSPSMessage)> {
+		let msgs = self.pending_messages.get_and_clear_pending_msgs();
+		let mut outbound_requests = self.outbound_requests.lock().unwrap();
+		msgs.into_iter().map(|(node_id, msg)| {
+			if let Some((request_id, method)) = msg.request_id_and_method() {
+				outbound_requests.entry(node_id).or_insert_with(HashMap::new).insert(request_id, method);
+			}
+			(node_id, RawLSPSMessage::from(&msg))
+		}).collect()
+	}
+
+	fn peer_disconnected(&self, their_node_id: &PublicKey) {
+		// Responses can only arrive over the connection the request was sent over.
+		self.outbound_requests.lock().unwrap().remove(their_node_id);
+	}
+
+	fn provided_init_features(&self, _their_node_id: &PublicKey) -> InitFeatures {
+		let mut features = InitFeatures::empty();
+		if self.is_service() {
+			features.set_optional_custom_bit(LSPS_FEATURE_BIT).expect("LSPS_FEATURE_BIT is a valid custom bit");
+		}
+		features
+	}
+
+	fn provided_node_features(&self) -> NodeFeatures {
+		let mut features = NodeFeatures::empty();
+		if self.is_service() {
+			features.set_optional_custom_bit(LSPS_FEATURE_BIT).expect("LSPS_FEATURE_BIT is a valid custom bit");
+		}
+		features
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::lsps0::client::LSPS0ClientEvent;
+	use crate::lsps0::msgs::{JSONRPC_PARSE_ERROR_CODE, LSPS0_CLIENT_REJECTED_ERROR_CODE};
+	use crate::lsps1::event::{LSPS1ClientEvent, LSPS1ServiceEvent};
+	use crate::lsps1::msgs::{Bolt11PaymentInfo, LSPS1Options, OrderId, OrderParameters, OrderState, PaymentInfo,
+		PaymentState, LSPS1_CREATE_ORDER_REQUEST_OPTION_MISMATCH_ERROR_CODE,
+		LSPS1_GET_ORDER_REQUEST_ORDER_NOT_FOUND_ERROR_CODE};
+	use crate::lsps2::event::{LSPS2ClientEvent, LSPS2ServiceEvent};
+	use crate::lsps2::msgs::{RawOpeningFeeParams, LSPS2_BUY_REQUEST_INVALID_OPENING_FEE_PARAMS_ERROR_CODE,
+		LSPS2_GET_INFO_REQUEST_UNRECOGNIZED_OR_STALE_TOKEN_ERROR_CODE};
+	use crate::utils::LSPSDateTime;
+
+	use bitcoin::{OutPoint, Txid};
+	use bitcoin::hashes::Hash;
+	use bitcoin::secp256k1::{Secp256k1, SecretKey};
+
+	use lightning::ln::PaymentHash;
+	use lightning::ln::functional_test_utils::*;
+	use lightning::util::errors::APIError;
+	use lightning::util::events::{MessageSendEvent, MessageSendEventsProvider};
+	use lightning::util::ser::Writeable;
+
+	use std::sync::atomic::{AtomicU64, Ordering};
+	use std::time::{SystemTime, UNIX_EPOCH};
+
+	/// A [`TimeSource`] which only moves forward when told to.
+	struct TestTimeSource(AtomicU64);
+
+	impl TestTimeSource {
+		fn advance(&self, secs: u64) {
+			self.0.fetch_add(secs, Ordering::AcqRel);
+		}
+	}
+
+	impl TimeSource for TestTimeSource {
+		fn unix_secs_now(&self) -> u64 {
+			self.0.load(Ordering::Acquire)
+		}
+	}
+
+	fn node_id(byte: u8) -> PublicKey {
+		PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[byte; 32]).unwrap())
+	}
+
+	/// Delivers all messages `from` wishes to send to `to`, returning how many there were.
+	fn deliver_messages(from: &LiquidityManager, from_id: &PublicKey, to: &LiquidityManager, to_id: &PublicKey) -> usize {
+		let msgs = from.get_and_clear_pending_msg();
+		for (node_id, msg) in msgs.iter() {
+			assert_eq!(node_id, to_id);
+			let encoded = msg.encode();
+			let decoded = to.read(LSPS_MESSAGE_TYPE_ID, &mut &encoded[..]).unwrap().unwrap();
+			to.handle_custom_message(decoded, from_id).unwrap();
+		}
+		msgs.len()
+	}
+
+	fn round_trip(client: &LiquidityManager, client_id: &PublicKey, service: &LiquidityManager, service_id: &PublicKey) {
+		assert_eq!(deliver_messages(client, client_id, service, service_id), 1);
+		assert_eq!(deliver_messages(service, service_id, client, client_id), 1);
+	}
+
+	fn lsps1_options() -> LSPS1Options {
+		LSPS1Options {
+			min_required_channel_confirmations: 0,
+			min_funding_confirms_within_blocks: 6,
+			supports_zero_channel_reserve: false,
+			max_channel_expiry_blocks: 20_160,
+			min_initial_client_balance_sat: 0,
+			max_initial_client_balance_sat: 100_000,
+			min_initial_lsp_balance_sat: 10_000,
+			max_initial_lsp_balance_sat: 1_000_000,
+			min_channel_balance_sat: 10_000,
+			max_channel_balance_sat: 1_000_000,
+		}
+	}
+
+	fn service_config() -> LiquidityServiceConfig {
+		LiquidityServiceConfig {
+			lsps1_service_config: Some(LSPS1ServiceConfig { supported_options: lsps1_options() }),
+			lsps2_service_config: Some(LSPS2ServiceConfig { promise_secret: [42; 32] }),
+		}
+	}
+
+	fn client_config() -> LiquidityClientConfig {
+		LiquidityClientConfig {
+			lsps1_client_config: Some(LSPS1ClientConfig::default()),
+			lsps2_client_config: Some(LSPS2ClientConfig::default()),
+		}
+	}
+
+	#[test]
+	fn lists_protocols_and_features() {
+		let (client_id, service_id) = (node_id(1), node_id(2));
+		let client = LiquidityManager::new(&[1; 32], None, Some(client_config()));
+		let service = LiquidityManager::new(&[2; 32], Some(service_config()), None);
+
+		assert_eq!(client.provided_init_features(&service_id), InitFeatures::empty());
+		assert_eq!(client.provided_node_features(), NodeFeatures::empty());
+		let mut expected_features = InitFeatures::empty();
+		expected_features.set_optional_custom_bit(LSPS_FEATURE_BIT).unwrap();
+		assert_eq!(service.provided_init_features(&client_id), expected_features);
+
+		client.lsps0_client_handler().list_protocols(&service_id);
+		round_trip(&client, &client_id, &service, &service_id);
+		assert_eq!(client.get_and_clear_pending_events(), vec![Event::LSPS0Client(
+			LSPS0ClientEvent::ListProtocolsResponse { counterparty_node_id: service_id, protocols: vec![1, 2] })]);
+
+		// A peer which is only a client supports no protocols.
+		service.lsps0_client_handler().list_protocols(&client_id);
+		round_trip(&service, &service_id, &client, &client_id);
+		assert_eq!(service.get_and_clear_pending_events(), vec![Event::LSPS0Client(
+			LSPS0ClientEvent::ListProtocolsResponse { counterparty_node_id: client_id, protocols: vec![] })]);
+	}
+
+	#[test]
+	fn rejects_invalid_messages() {
+		let (client_id, service_id) = (node_id(1), node_id(2));
+		let client = LiquidityManager::new(&[1; 32], None, Some(client_config()));
+		let service = LiquidityManager::new(&[2; 32], Some(service_config()), None);
+
+		// Unparseable requests are responded to with an error carrying a null id...
+		assert!(service.handle_custom_message(RawLSPSMessage { payload: "{".to_string() }, &client_id).is_err());
+		let msgs = service.get_and_clear_pending_msg();
+		assert_eq!(msgs.len(), 1);
+		let response: serde_json::Value = serde_json::from_str(&msgs[0].1.payload).unwrap();
+		assert_eq!(response["id"], serde_json::Value::Null);
+		assert_eq!(response["error"]["code"], JSONRPC_PARSE_ERROR_CODE);
+
+		// ...which is not responded to in turn, nor are responses to requests we never sent.
+		assert!(client.handle_custom_message(msgs[0].1.clone(), &service_id).is_err());
+		assert!(client.get_and_clear_pending_msg().is_empty());
+
+		// Responses are only accepted from the peer the request was sent to.
+		client.lsps1_client_handler().unwrap().request_supported_options(&service_id);
+		assert_eq!(deliver_messages(&client, &client_id, &service, &service_id), 1);
+		let response = service.get_and_clear_pending_msg().pop().unwrap().1;
+		assert!(client.handle_custom_message(response.clone(), &node_id(3)).is_err());
+		client.handle_custom_message(response.clone(), &service_id).unwrap();
+		assert_eq!(client.get_and_clear_pending_events().len(), 1);
+		assert!(client.handle_custom_message(response, &service_id).is_err());
+
+		// Requests for protocols we do not provide a service for are rejected.
+		let only_client = LiquidityManager::new(&[3; 32], None, None);
+		client.lsps2_client_handler().unwrap().request_opening_params(&node_id(3), None);
+		assert_eq!(deliver_messages(&client, &client_id, &only_client, &node_id(3)), 1);
+		assert_eq!(deliver_messages(&only_client, &node_id(3), &client, &client_id), 1);
+		match &client.get_and_clear_pending_events()[..] {
+			[Event::LSPS2Client(LSPS2ClientEvent::RequestFailed { error, .. })] =>
+				assert_eq!(error.code, JSONRPC_METHOD_NOT_FOUND_ERROR_CODE),
+			events => panic!("Unexpected events {:?}", events),
+		}
+
+		// Pending requests are forgotten when the peer disconnects.
+		client.lsps1_client_handler().unwrap().request_supported_options(&service_id);
+		assert_eq!(deliver_messages(&client, &client_id, &service, &service_id), 1);
+		client.peer_disconnected(&service_id);
+		let response = service.get_and_clear_pending_msg().pop().unwrap().1;
+		assert!(client.handle_custom_message(response, &service_id).is_err());
+	}
+
+	#[test]
+	fn lsps1_order_flow() {
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+		let service_id = nodes[0].node.get_our_node_id();
+		let client_id = nodes[1].node.get_our_node_id();
+
+		let client_manager = LiquidityManager::new(&[1; 32], None, Some(client_config()));
+		let service_manager = LiquidityManager::new(&[2; 32], Some(service_config()), None);
+		let client = client_manager.lsps1_client_handler().unwrap();
+		let service = service_manager.lsps1_service_handler().unwrap();
+
+		client.request_supported_options(&service_id);
+		round_trip(&client_manager, &client_id, &service_manager, &service_id);
+		match &client_manager.get_and_clear_pending_events()[..] {
+			[Event::LSPS1Client(LSPS1ClientEvent::SupportedOptionsReady { supported_options, .. })] =>
+				assert_eq!(*supported_options, lsps1_options()),
+			events => panic!("Unexpected events {:?}", events),
+		}
+
+		let mut order = OrderParameters {
+			lsp_balance_sat: 2_000_000,
+			client_balance_sat: 10_000,
+			required_channel_confirmations: 1,
+			funding_confirms_within_blocks: 6,
+			channel_expiry_blocks: 4032,
+			token: None,
+			announce_channel: true,
+		};
+
+		// Orders outside of our options are rejected without involving the user.
+		client.create_order(&service_id, order.clone(), None);
+		round_trip(&client_manager, &client_id, &service_manager, &service_id);
+		assert!(service_manager.get_and_clear_pending_events().is_empty());
+		match &client_manager.get_and_clear_pending_events()[..] {
+			[Event::LSPS1Client(LSPS1ClientEvent::RequestFailed { error, .. })] => {
+				assert_eq!(error.code, LSPS1_CREATE_ORDER_REQUEST_OPTION_MISMATCH_ERROR_CODE);
+				assert_eq!(error.data, Some(serde_json::json!({ "property": "lsp_balance_sat" })));
+			},
+			events => panic!("Unexpected events {:?}", events),
+		}
+
+		order.lsp_balance_sat = 200_000;
+		client.create_order(&service_id, order.clone(), None);
+		assert_eq!(deliver_messages(&client_manager, &client_id, &service_manager, &service_id), 1);
+		let request_id = match &service_manager.get_and_clear_pending_events()[..] {
+			[Event::LSPS1Service(LSPS1ServiceEvent::RequestForPaymentDetails { request_id, counterparty_node_id, order: requested, .. })] => {
+				assert_eq!(*counterparty_node_id, client_id);
+				assert_eq!(*requested, order);
+				request_id.clone()
+			},
+			events => panic!("Unexpected events {:?}", events),
+		};
+
+		let payment = PaymentInfo {
+			bolt11: Some(Bolt11PaymentInfo {
+				state: PaymentState::ExpectPayment,
+				expires_at: LSPSDateTime::from_now(3600),
+				fee_total_sat: 2_000,
+				order_total_sat: 12_000,
+				invoice: "lnbc...".to_string(),
+			}),
+			onchain: None,
+		};
+		let order_id = service.send_payment_details(request_id.clone(), &client_id, payment.clone()).unwrap();
+		assert!(service.send_payment_details(request_id, &client_id, payment.clone()).is_err());
+		assert_eq!(deliver_messages(&service_manager, &service_id, &client_manager, &client_id), 1);
+		match &client_manager.get_and_clear_pending_events()[..] {
+			[Event::LSPS1Client(LSPS1ClientEvent::OrderCreated { order: created, .. })] => {
+				assert_eq!(created.order_id, order_id);
+				assert_eq!(created.order, order);
+				assert_eq!(created.order_state, OrderState::Created);
+				assert_eq!(created.payment, payment);
+				assert_eq!(created.channel, None);
+			},
+			events => panic!("Unexpected events {:?}", events),
+		}
+
+		// The channel can only be opened once the order has been paid for.
+		match service.open_channel(nodes[0].node, &client_id, &order_id, 42, None) {
+			Err(APIError::APIMisuseError { .. }) => {},
+			res => panic!("Unexpected result {:?}", res),
+		}
+		service.update_payment_state(&client_id, &order_id, PaymentState::Paid).unwrap();
+		service.open_channel(nodes[0].node, &client_id, &order_id, 42, None).unwrap();
+		let channel = &nodes[0].node.list_channels()[0];
+		assert_eq!(channel.channel_value_satoshis, 210_000);
+		assert_eq!(channel.user_channel_id, 42);
+		match &nodes[0].node.get_and_clear_pending_msg_events()[..] {
+			[MessageSendEvent::SendOpenChannel { node_id, msg }] => {
+				assert_eq!(*node_id, client_id);
+				assert_eq!(msg.push_msat, 10_000_000);
+				assert_eq!(msg.channel_flags & 1, 1);
+			},
+			events => panic!("Unexpected events {:?}", events),
+		}
+
+		let funding_outpoint = OutPoint { txid: Txid::from_inner([1; 32]), vout: 0 };
+		service.channel_opened(&client_id, &order_id, funding_outpoint).unwrap();
+		client.check_order_status(&service_id, order_id.clone());
+		round_trip(&client_manager, &client_id, &service_manager, &service_id);
+		match &client_manager.get_and_clear_pending_events()[..] {
+			[Event::LSPS1Client(LSPS1ClientEvent::OrderStatus { order: status, .. })] => {
+				assert_eq!(status.order_state, OrderState::Completed);
+				assert_eq!(status.payment.bolt11.as_ref().unwrap().state, PaymentState::Paid);
+				assert_eq!(status.channel.as_ref().unwrap().funding_outpoint, funding_outpoint);
+			},
+			events => panic!("Unexpected events {:?}", events),
+		}
+
+		client.check_order_status(&service_id, OrderId("unknown".to_string()));
+		round_trip(&client_manager, &client_id, &service_manager, &service_id);
+		match &client_manager.get_and_clear_pending_events()[..] {
+			[Event::LSPS1Client(LSPS1ClientEvent::RequestFailed { error, .. })] =>
+				assert_eq!(error.code, LSPS1_GET_ORDER_REQUEST_ORDER_NOT_FOUND_ERROR_CODE),
+			events => panic!("Unexpected events {:?}", events),
+		}
+	}
+
+	#[test]
+	fn lsps2_jit_channel_flow() {
+		let (client_id, service_id) = (node_id(1), node_id(2));
+		let client_manager = LiquidityManager::new(&[1; 32], None, Some(client_config()));
+		let service_manager = LiquidityManager::new(&[2; 32], Some(service_config()), None);
+		let client = client_manager.lsps2_client_handler().unwrap();
+		let service = service_manager.lsps2_service_handler().unwrap();
+
+		let get_info_request_id = |token: Option<String>| {
+			client.request_opening_params(&service_id, token.clone());
+			assert_eq!(deliver_messages(&client_manager, &client_id, &service_manager, &service_id), 1);
+			match &service_manager.get_and_clear_pending_events()[..] {
+				[Event::LSPS2Service(LSPS2ServiceEvent::GetInfo { request_id, counterparty_node_id, token: received })] => {
+					assert_eq!(*counterparty_node_id, client_id);
+					assert_eq!(*received, token);
+					request_id.clone()
+				},
+				events => panic!("Unexpected events {:?}", events),
+			}
+		};
+
+		let request_id = get_info_request_id(Some("stale".to_string()));
+		service.invalid_token_provided(&client_id, request_id).unwrap();
+		assert_eq!(deliver_messages(&service_manager, &service_id, &client_manager, &client_id), 1);
+		match &client_manager.get_and_clear_pending_events()[..] {
+			[Event::LSPS2Client(LSPS2ClientEvent::RequestFailed { error, .. })] =>
+				assert_eq!(error.code, LSPS2_GET_INFO_REQUEST_UNRECOGNIZED_OR_STALE_TOKEN_ERROR_CODE),
+			events => panic!("Unexpected events {:?}", events),
+		}
+
+		let raw_params = |min_fee_msat| RawOpeningFeeParams {
+			min_fee_msat,
+			proportional: 10_000,
+			valid_until: LSPSDateTime::from_now(3600),
+			min_lifetime: 1008,
+			max_client_to_self_delay: 2016,
+			min_payment_size_msat: 1_000_000,
+			max_payment_size_msat: 100_000_000,
+		};
+		let request_id = get_info_request_id(None);
+		service.opening_fee_params_generated(&client_id, request_id, vec![raw_params(2_000_000), raw_params(1_000_000)]).unwrap();
+		assert_eq!(deliver_messages(&service_manager, &service_id, &client_manager, &client_id), 1);
+		let menu = match client_manager.get_and_clear_pending_events().pop() {
+			Some(Event::LSPS2Client(LSPS2ClientEvent::OpeningParametersReady { opening_fee_params_menu, .. })) =>
+				opening_fee_params_menu,
+			event => panic!("Unexpected event {:?}", event),
+		};
+		assert_eq!(menu.iter().map(|params| params.min_fee_msat).collect::<Vec<_>>(), vec![1_000_000, 2_000_000]);
+
+		// Parameters which were tampered with are rejected.
+		let mut tampered = menu[0].clone();
+		tampered.min_fee_msat = 0;
+		client.select_opening_params(&service_id, None, tampered).unwrap();
+		round_trip(&client_manager, &client_id, &service_manager, &service_id);
+		assert!(service_manager.get_and_clear_pending_events().is_empty());
+		match &client_manager.get_and_clear_pending_events()[..] {
+			[Event::LSPS2Client(LSPS2ClientEvent::RequestFailed { error, .. })] =>
+				assert_eq!(error.code, LSPS2_BUY_REQUEST_INVALID_OPENING_FEE_PARAMS_ERROR_CODE),
+			events => panic!("Unexpected events {:?}", events),
+		}
+
+		// Payment sizes out of the offered bounds are caught before the request is sent.
+		assert!(client.select_opening_params(&service_id, Some(200_000_000), menu[0].clone()).is_err());
+		let buy_request_id = client.select_opening_params(&service_id, Some(50_000_000), menu[0].clone()).unwrap();
+		assert_eq!(deliver_messages(&client_manager, &client_id, &service_manager, &service_id), 1);
+		let request_id = match &service_manager.get_and_clear_pending_events()[..] {
+			[Event::LSPS2Service(LSPS2ServiceEvent::BuyRequest { request_id, opening_fee_params, payment_size_msat, .. })] => {
+				assert_eq!(*opening_fee_params, menu[0]);
+				assert_eq!(*payment_size_msat, Some(50_000_000));
+				request_id.clone()
+			},
+			events => panic!("Unexpected events {:?}", events),
+		};
+
+		let intercept_scid = (700_000 << 40) | (1 << 16) | 1;
+		service.invoice_parameters_generated(&client_id, request_id, intercept_scid, 144, false, 42).unwrap();
+		assert_eq!(deliver_messages(&service_manager, &service_id, &client_manager, &client_id), 1);
+		match &client_manager.get_and_clear_pending_events()[..] {
+			[Event::LSPS2Client(LSPS2ClientEvent::InvoiceParametersReady {
+				request_id, counterparty_node_id, intercept_scid: scid, cltv_expiry_delta, payment_size_msat, client_trusts_lsp,
+			})] => {
+				assert_eq!(*request_id, buy_request_id);
+				assert_eq!(*counterparty_node_id, service_id);
+				assert_eq!(*scid, intercept_scid);
+				assert_eq!(*cltv_expiry_delta, 144);
+				assert_eq!(*payment_size_msat, Some(50_000_000));
+				assert!(!client_trusts_lsp);
+			},
+			events => panic!("Unexpected events {:?}", events),
+		}
+
+		// The channel is opened once the full payment size has arrived, less the minimum opening fee
+		// (which exceeds the proportional fee of 1%), with later parts accepted without further events.
+		let payment_hash = PaymentHash([3; 32]);
+		assert!(service.htlc_intercepted(intercept_scid + 1, payment_hash, 25_000_000).is_err());
+		service.htlc_intercepted(intercept_scid, payment_hash, 25_000_000).unwrap();
+		assert!(service_manager.get_and_clear_pending_events().is_empty());
+		match service.htlc_intercepted(intercept_scid, PaymentHash([4; 32]), 25_000_000) {
+			Err(APIError::ChannelUnavailable { .. }) => {},
+			res => panic!("Unexpected result {:?}", res),
+		}
+		service.htlc_intercepted(intercept_scid, payment_hash, 25_000_000).unwrap();
+		assert_eq!(service_manager.get_and_clear_pending_events(), vec![Event::LSPS2Service(
+			LSPS2ServiceEvent::OpenChannel {
+				their_network_key: client_id,
+				amt_to_forward_msat: 49_000_000,
+				opening_fee_msat: 1_000_000,
+				user_channel_id: 42,
+				intercept_scid,
+			})]);
+		service.htlc_intercepted(intercept_scid, payment_hash, 1_000).unwrap();
+		assert!(service_manager.get_and_clear_pending_events().is_empty());
+		match service.htlc_intercepted(intercept_scid, PaymentHash([4; 32]), 1_000) {
+			Err(APIError::ChannelUnavailable { .. }) => {},
+			res => panic!("Unexpected result {:?}", res),
+		}
+
+		// Once the held payment has been failed back, the client may pay again, with another hash.
+		service.htlc_failed(intercept_scid).unwrap();
+		assert!(service.htlc_failed(intercept_scid + 1).is_err());
+		let payment_hash = PaymentHash([4; 32]);
+		service.htlc_intercepted(intercept_scid, payment_hash, 30_000_000).unwrap();
+		assert!(service_manager.get_and_clear_pending_events().is_empty());
+		assert!(service.channel_ready(intercept_scid).is_err());
+		service.htlc_intercepted(intercept_scid, payment_hash, 20_000_000).unwrap();
+		assert_eq!(service_manager.get_and_clear_pending_events().len(), 1);
+
+		// Once the channel is ready, the JIT channel is forgotten.
+		service.channel_ready(intercept_scid).unwrap();
+		match service.htlc_intercepted(intercept_scid, payment_hash, 1_000) {
+			Err(APIError::APIMisuseError { .. }) => {},
+			res => panic!("Unexpected result {:?}", res),
+		}
+	}
+
+	#[test]
+	fn lsps2_forgets_expired_jit_channels() {
+		let (client_id, service_id) = (node_id(1), node_id(2));
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+		let time_source = Arc::new(TestTimeSource(AtomicU64::new(now)));
+		let client_manager = LiquidityManager::new(&[1; 32], None, Some(client_config()));
+		let service_manager = LiquidityManager::with_time_source(&[2; 32], Some(service_config()), None,
+			Arc::clone(&time_source) as Arc<dyn TimeSource>);
+		let client = client_manager.lsps2_client_handler().unwrap();
+		let service = service_manager.lsps2_service_handler().unwrap();
+
+		client.request_opening_params(&service_id, None);
+		assert_eq!(deliver_messages(&client_manager, &client_id, &service_manager, &service_id), 1);
+		let request_id = match service_manager.get_and_clear_pending_events().pop() {
+			Some(Event::LSPS2Service(LSPS2ServiceEvent::GetInfo { request_id, .. })) => request_id,
+			event => panic!("Unexpected event {:?}", event),
+		};
+		service.opening_fee_params_generated(&client_id, request_id, vec![RawOpeningFeeParams {
+			min_fee_msat: 1_000_000,
+			proportional: 10_000,
+			valid_until: LSPSDateTime::from_now(3600),
+			min_lifetime: 1008,
+			max_client_to_self_delay: 2016,
+			min_payment_size_msat: 1_000_000,
+			max_payment_size_msat: 100_000_000,
+		}]).unwrap();
+		assert_eq!(deliver_messages(&service_manager, &service_id, &client_manager, &client_id), 1);
+		let opening_fee_params = match client_manager.get_and_clear_pending_events().pop() {
+			Some(Event::LSPS2Client(LSPS2ClientEvent::OpeningParametersReady { mut opening_fee_params_menu, .. })) =>
+				opening_fee_params_menu.pop().unwrap(),
+			event => panic!("Unexpected event {:?}", event),
+		};
+
+		client.select_opening_params(&service_id, None, opening_fee_params).unwrap();
+		assert_eq!(deliver_messages(&client_manager, &client_id, &service_manager, &service_id), 1);
+		let request_id = match service_manager.get_and_clear_pending_events().pop() {
+			Some(Event::LSPS2Service(LSPS2ServiceEvent::BuyRequest { request_id, .. })) => request_id,
+			event => panic!("Unexpected event {:?}", event),
+		};
+		let intercept_scid = (700_000 << 40) | (1 << 16) | 1;
+		service.invoice_parameters_generated(&client_id, request_id, intercept_scid, 144, false, 42).unwrap();
+
+		// Payments arriving after the fees expired are rejected and the JIT channel is forgotten.
+		time_source.advance(3601);
+		match service.htlc_intercepted(intercept_scid, PaymentHash([3; 32]), 50_000_000) {
+			Err(APIError::ChannelUnavailable { .. }) => {},
+			res => panic!("Unexpected result {:?}", res),
+		}
+		match service.htlc_intercepted(intercept_scid, PaymentHash([3; 32]), 50_000_000) {
+			Err(APIError::APIMisuseError { .. }) => {},
+			res => panic!("Unexpected result {:?}", res),
+		}
+		assert!(service_manager.get_and_clear_pending_events().is_empty());
+	}
+
+	#[test]
+	fn limits_pending_requests_and_orders() {
+		let (client_id, service_id) = (node_id(1), node_id(2));
+		let client_manager = LiquidityManager::new(&[1; 32], None, Some(client_config()));
+		let service_manager = LiquidityManager::new(&[2; 32], Some(service_config()), None);
+
+		// Requests beyond what a client may have pending with the user are rejected...
+		let lsps2_client = client_manager.lsps2_client_handler().unwrap();
+		for _ in 0..10 {
+			lsps2_client.request_opening_params(&service_id, None);
+		}
+		assert_eq!(deliver_messages(&client_manager, &client_id, &service_manager, &service_id), 10);
+		let mut events = service_manager.get_and_clear_pending_events();
+		assert_eq!(events.len(), 10);
+		lsps2_client.request_opening_params(&service_id, None);
+		round_trip(&client_manager, &client_id, &service_manager, &service_id);
+		assert!(service_manager.get_and_clear_pending_events().is_empty());
+		match &client_manager.get_and_clear_pending_events()[..] {
+			[Event::LSPS2Client(LSPS2ClientEvent::RequestFailed { error, .. })] =>
+				assert_eq!(error.code, LSPS0_CLIENT_REJECTED_ERROR_CODE),
+			events => panic!("Unexpected events {:?}", events),
+		}
+
+		// ...until the user responds to one of them.
+		match events.pop() {
+			Some(Event::LSPS2Service(LSPS2ServiceEvent::GetInfo { request_id, .. })) =>
+				service_manager.lsps2_service_handler().unwrap().invalid_token_provided(&client_id, request_id).unwrap(),
+			event => panic!("Unexpected event {:?}", event),
+		}
+		assert_eq!(deliver_messages(&service_manager, &service_id, &client_manager, &client_id), 1);
+		client_manager.get_and_clear_pending_events();
+		lsps2_client.request_opening_params(&service_id, None);
+		assert_eq!(deliver_messages(&client_manager, &client_id, &service_manager, &service_id), 1);
+		assert_eq!(service_manager.get_and_clear_pending_events().len(), 1);
+
+		// Unpaid LSPS1 orders count as pending, too.
+		let lsps1_client = client_manager.lsps1_client_handler().unwrap();
+		let lsps1_service = service_manager.lsps1_service_handler().unwrap();
+		let order = OrderParameters {
+			lsp_balance_sat: 200_000,
+			client_balance_sat: 10_000,
+			required_channel_confirmations: 1,
+			funding_confirms_within_blocks: 6,
+			channel_expiry_blocks: 4032,
+			token: None,
+			announce_channel: true,
+		};
+		let payment = PaymentInfo {
+			bolt11: Some(Bolt11PaymentInfo {
+				state: PaymentState::ExpectPayment,
+				expires_at: LSPSDateTime::from_now(3600),
+				fee_total_sat: 2_000,
+				order_total_sat: 12_000,
+				invoice: "lnbc...".to_string(),
+			}),
+			onchain: None,
+		};
+		let create_order = || {
+			lsps1_client.create_order(&service_id, order.clone(), None);
+			assert_eq!(deliver_messages(&client_manager, &client_id, &service_manager, &service_id), 1);
+			let order_id = match service_manager.get_and_clear_pending_events().pop() {
+				Some(Event::LSPS1Service(LSPS1ServiceEvent::RequestForPaymentDetails { request_id, .. })) =>
+					lsps1_service.send_payment_details(request_id, &client_id, payment.clone()).unwrap(),
+				event => panic!("Unexpected event {:?}", event),
+			};
+			assert_eq!(deliver_messages(&service_manager, &service_id, &client_manager, &client_id), 1);
+			client_manager.get_and_clear_pending_events();
+			order_id
+		};
+		let order_ids: Vec<_> = (0..10).map(|_| create_order()).collect();
+		lsps1_client.create_order(&service_id, order.clone(), None);
+		round_trip(&client_manager, &client_id, &service_manager, &service_id);
+		assert!(service_manager.get_and_clear_pending_events().is_empty());
+		match &client_manager.get_and_clear_pending_events()[..] {
+			[Event::LSPS1Client(LSPS1ClientEvent::RequestFailed { error, .. })] =>
+				assert_eq!(error.code, LSPS0_CLIENT_REJECTED_ERROR_CODE),
+			events => panic!("Unexpected events {:?}", events),
+		}
+
+		// Only the most recent finished orders are kept around for clients to poll.
+		for order_id in order_ids.iter() {
+			lsps1_service.order_failed(&client_id, order_id).unwrap();
+		}
+		let order_id = create_order();
+		lsps1_service.order_failed(&client_id, &order_id).unwrap();
+		create_order();
+		assert!(lsps1_service.order_failed(&client_id, &order_ids[0]).is_err());
+		lsps1_service.order_failed(&client_id, &order_ids[1]).unwrap();
+	}
+}
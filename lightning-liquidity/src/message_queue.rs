@@ -0,0 +1,36 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Holds the messages the protocol handlers wish to send until the [`PeerManager`] picks them up.
+//!
+//! [`PeerManager`]: lightning::ln::peer_handler::PeerManager
+
+use crate::lsps0::msgs::LSPSMessage;
+
+use bitcoin::secp256k1::PublicKey;
+
+use std::sync::Mutex;
+
+pub(crate) struct MessageQueue {
+	queue: Mutex<Vec<(PublicKey, LSPSMessage)>>,
+}
+
+impl MessageQueue {
+	pub(crate) fn new() -> Self {
+		Self { queue: Mutex::new(Vec::new()) }
+	}
+
+	pub(crate) fn enqueue(&self, counterparty_node_id: &PublicKey, msg: LSPSMessage) {
+		self.queue.lock().unwrap().push((*counterparty_node_id, msg));
+	}
+
+	pub(crate) fn get_and_clear_pending_msgs(&self) -> Vec<(PublicKey, LSPSMessage)> {
+		core::mem::replace(&mut *self.queue.lock().unwrap(), Vec::new())
+	}
+}
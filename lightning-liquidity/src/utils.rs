@@ -0,0 +1,347 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Encoding helpers shared by the LSPS protocol implementations.
+
+use bitcoin::hashes::{Hash, HashEngine};
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::hashes::sha256::Hash as Sha256;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Parses a short channel id given in the `BLOCKxTXxOUTPUT` form used throughout the LSPS
+/// specifications.
+pub fn scid_from_human_readable_string(human_readable_scid: &str) -> Result<u64, ()> {
+	let mut parts = human_readable_scid.split('x');
+	let block = parts.next().ok_or(())?.parse::<u64>().map_err(|_| ())?;
+	let tx_index = parts.next().ok_or(())?.parse::<u64>().map_err(|_| ())?;
+	let vout_index = parts.next().ok_or(())?.parse::<u64>().map_err(|_| ())?;
+	if parts.next().is_some() || block >= (1 << 24) || tx_index >= (1 << 24) || vout_index >= (1 << 16) {
+		return Err(());
+	}
+	Ok((block << 40) | (tx_index << 16) | vout_index)
+}
+
+/// Formats a short channel id in the `BLOCKxTXxOUTPUT` form used throughout the LSPS
+/// specifications.
+pub fn scid_to_human_readable_string(scid: u64) -> String {
+	format!("{}x{}x{}", scid >> 40, (scid >> 16) & 0xff_ffff, scid & 0xffff)
+}
+
+/// Generates the ids of the JSON-RPC requests we send.
+///
+/// Ids are derived by hashing the random data given on construction together with a counter, so
+/// that they are unique and do not leak how many requests we have made.
+pub(crate) struct RequestIdGenerator {
+	entropy: [u8; 32],
+	counter: AtomicU64,
+}
+
+impl RequestIdGenerator {
+	pub(crate) fn new(entropy: &[u8; 32]) -> Self {
+		Self { entropy: *entropy, counter: AtomicU64::new(0) }
+	}
+
+	/// Returns 16 fresh pseudo-random bytes.
+	pub(crate) fn get_random_bytes(&self) -> [u8; 16] {
+		let mut engine = Sha256::engine();
+		engine.input(&self.entropy);
+		engine.input(&self.counter.fetch_add(1, Ordering::AcqRel).to_be_bytes());
+		let hash = Sha256::from_engine(engine).into_inner();
+		let mut res = [0; 16];
+		res.copy_from_slice(&hash[..16]);
+		res
+	}
+
+	pub(crate) fn next_id(&self) -> String {
+		self.get_random_bytes().to_hex()
+	}
+}
+
+/// Serializes a value as a JSON string using its `Display` and `FromStr` implementations, as the
+/// LSPS specifications require for amounts (which may not fit in the integers of some JSON
+/// implementations) and outpoints.
+pub(crate) mod string_encoded {
+	use serde::{Deserialize, Deserializer, Serializer};
+	use std::fmt::Display;
+	use std::str::FromStr;
+
+	pub(crate) fn serialize<T: Display, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.collect_str(value)
+	}
+
+	pub(crate) fn deserialize<'de, T: FromStr, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+		let s = String::deserialize(deserializer)?;
+		s.parse::<T>().map_err(|_| serde::de::Error::custom(format!("invalid value: {}", s)))
+	}
+}
+
+/// As [`string_encoded`], but for optional values. Fields using this should also be marked
+/// `#[serde(default, skip_serializing_if = "Option::is_none")]`.
+pub(crate) mod string_encoded_option {
+	use serde::{Deserialize, Deserializer, Serializer};
+	use std::fmt::Display;
+	use std::str::FromStr;
+
+	pub(crate) fn serialize<T: Display, S: Serializer>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+		match value {
+			Some(value) => serializer.collect_str(value),
+			None => serializer.serialize_none(),
+		}
+	}
+
+	pub(crate) fn deserialize<'de, T: FromStr, D: Deserializer<'de>>(deserializer: D) -> Result<Option<T>, D::Error> {
+		match Option::<String>::deserialize(deserializer)? {
+			Some(s) => s.parse::<T>().map(Some)
+				.map_err(|_| serde::de::Error::custom(format!("invalid value: {}", s))),
+			None => Ok(None),
+		}
+	}
+}
+
+/// Serializes a short channel id in the `BLOCKxTXxOUTPUT` form.
+pub(crate) mod human_readable_scid {
+	use serde::{Deserialize, Deserializer, Serializer};
+
+	pub(crate) fn serialize<S: Serializer>(scid: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.serialize_str(&super::scid_to_human_readable_string(*scid))
+	}
+
+	pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+		let s = String::deserialize(deserializer)?;
+		super::scid_from_human_readable_string(&s)
+			.map_err(|_| serde::de::Error::custom(format!("invalid short channel id: {}", s)))
+	}
+}
+
+/// A point in time, as an ISO 8601 formatted string in UTC, e.g. `2023-02-23T08:47:30.511Z`.
+///
+/// The string is kept as given, rather than re-encoded, as some values (e.g. LSPS2's
+/// `valid_until`) are covered by a MAC computed over the encoding chosen by the service.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LSPSDateTime(String);
+
+impl LSPSDateTime {
+	/// Formats the given number of seconds since the UNIX epoch, with second precision.
+	pub fn from_unix_secs(secs: u64) -> Self {
+		let days = (secs / 86400) as i64;
+		let secs_of_day = secs % 86400;
+		let (year, month, day) = civil_from_days(days);
+		LSPSDateTime(format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day,
+			secs_of_day / 3600, (secs_of_day / 60) % 60, secs_of_day % 60))
+	}
+
+	/// Returns the point in time `secs` seconds from now.
+	pub fn from_now(secs: u64) -> Self {
+		Self::from_unix_secs(unix_time_now().saturating_add(secs))
+	}
+
+	/// Parses the given ISO 8601 string, returning `Err` if it is malformed or predates the UNIX
+	/// epoch.
+	pub fn parse(s: &str) -> Result<Self, ()> {
+		let res = LSPSDateTime(s.to_string());
+		res.to_unix_secs()?;
+		Ok(res)
+	}
+
+	/// Returns the number of seconds since the UNIX epoch, ignoring any fractional seconds.
+	pub fn to_unix_secs(&self) -> Result<u64, ()> {
+		let s = self.0.as_bytes();
+		if s.len() < 20 || s[4] != b'-' || s[7] != b'-' || (s[10] != b'T' && s[10] != b't')
+			|| s[13] != b':' || s[16] != b':' {
+			return Err(());
+		}
+		let num = |range: core::ops::Range<usize>| -> Result<i64, ()> {
+			let digits = &s[range];
+			if !digits.iter().all(|c| c.is_ascii_digit()) { return Err(()); }
+			Ok(digits.iter().fold(0, |acc, c| acc * 10 + (c - b'0') as i64))
+		};
+		let (year, month, day) = (num(0..4)?, num(5..7)?, num(8..10)?);
+		let (hour, minute, second) = (num(11..13)?, num(14..16)?, num(17..19)?);
+		if month < 1 || month > 12 || day < 1 || day > days_in_month(year, month)
+			|| hour > 23 || minute > 59 || second > 60 {
+			return Err(());
+		}
+
+		let mut pos = 19;
+		if s[pos] == b'.' {
+			pos += 1;
+			let fraction_start = pos;
+			while pos < s.len() && s[pos].is_ascii_digit() { pos += 1; }
+			if pos == fraction_start { return Err(()); }
+		}
+		let offset_secs = match &s[pos..] {
+			b"Z" | b"z" => 0,
+			offset if offset.len() == 6 && offset[3] == b':' && (offset[0] == b'+' || offset[0] == b'-') => {
+				let (hours, minutes) = (num(pos + 1..pos + 3)?, num(pos + 4..pos + 6)?);
+				if hours > 23 || minutes > 59 { return Err(()); }
+				let offset_secs = hours * 3600 + minutes * 60;
+				if offset[0] == b'+' { offset_secs } else { -offset_secs }
+			},
+			_ => return Err(()),
+		};
+
+		let secs = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second
+			- offset_secs;
+		if secs < 0 { return Err(()); }
+		Ok(secs as u64)
+	}
+
+	/// Returns whether this point in time lies before `unix_secs`. Malformed values are considered
+	/// to lie in the past.
+	pub fn is_before(&self, unix_secs: u64) -> bool {
+		self.to_unix_secs().map_or(true, |secs| secs < unix_secs)
+	}
+
+	/// Returns whether this point in time has passed, according to the system clock.
+	pub fn is_past(&self) -> bool {
+		self.is_before(unix_time_now())
+	}
+
+	/// Returns the string encoding of this point in time.
+	pub fn as_str(&self) -> &str {
+		&self.0
+	}
+}
+
+impl fmt::Display for LSPSDateTime {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(&self.0)
+	}
+}
+
+impl Serialize for LSPSDateTime {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.serialize_str(&self.0)
+	}
+}
+
+impl<'de> Deserialize<'de> for LSPSDateTime {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		let s = String::deserialize(deserializer)?;
+		LSPSDateTime::parse(&s).map_err(|_| serde::de::Error::custom(format!("invalid datetime: {}", s)))
+	}
+}
+
+/// A source of the current time, which tests replace to move time forward without waiting.
+pub(crate) trait TimeSource: Send + Sync {
+	/// Returns the number of seconds since the UNIX epoch.
+	fn unix_secs_now(&self) -> u64;
+}
+
+/// The [`TimeSource`] reading the system clock.
+pub(crate) struct SystemTimeSource;
+
+impl TimeSource for SystemTimeSource {
+	fn unix_secs_now(&self) -> u64 {
+		unix_time_now()
+	}
+}
+
+fn unix_time_now() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).expect("Time must be > 1970").as_secs()
+}
+
+fn is_leap_year(year: i64) -> bool {
+	(year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+	match month {
+		2 if is_leap_year(year) => 29,
+		2 => 28,
+		4 | 6 | 9 | 11 => 30,
+		_ => 31,
+	}
+}
+
+// The conversions between days since the UNIX epoch and (proleptic Gregorian) calendar dates
+// below follow http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+	let year = if month <= 2 { year - 1 } else { year };
+	let era = (if year >= 0 { year } else { year - 399 }) / 400;
+	let year_of_era = year - era * 400;
+	let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+	let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+	era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+	let days = days + 719468;
+	let era = (if days >= 0 { days } else { days - 146096 }) / 146097;
+	let day_of_era = days - era * 146097;
+	let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let mp = (5 * day_of_year + 2) / 153;
+	let day = day_of_year - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+	(year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn scid_string_round_trip() {
+		let scid = (700_000 << 40) | (1234 << 16) | 1;
+		assert_eq!(scid_to_human_readable_string(scid), "700000x1234x1");
+		assert_eq!(scid_from_human_readable_string("700000x1234x1"), Ok(scid));
+
+		assert!(scid_from_human_readable_string("700000x1234").is_err());
+		assert!(scid_from_human_readable_string("700000x1234x1x1").is_err());
+		assert!(scid_from_human_readable_string("16777216x0x0").is_err());
+		assert!(scid_from_human_readable_string("0x0x65536").is_err());
+		assert!(scid_from_human_readable_string("ax0x0").is_err());
+	}
+
+	#[test]
+	fn datetime_conversions() {
+		assert_eq!(LSPSDateTime::from_unix_secs(0).as_str(), "1970-01-01T00:00:00Z");
+		assert_eq!(LSPSDateTime::from_unix_secs(1677142050).as_str(), "2023-02-23T08:47:30Z");
+		assert_eq!(LSPSDateTime::from_unix_secs(951782400).as_str(), "2000-02-29T00:00:00Z");
+
+		for secs in [0, 951782400, 1677142050, 4102444799].iter() {
+			assert_eq!(LSPSDateTime::from_unix_secs(*secs).to_unix_secs(), Ok(*secs));
+		}
+
+		// Fractional seconds are accepted but ignored, and offsets are applied.
+		assert_eq!(LSPSDateTime::parse("2023-02-23T08:47:30.511Z").unwrap().to_unix_secs(), Ok(1677142050));
+		assert_eq!(LSPSDateTime::parse("2023-02-23T09:47:30+01:00").unwrap().to_unix_secs(), Ok(1677142050));
+		assert_eq!(LSPSDateTime::parse("2023-02-23T07:17:30-01:30").unwrap().to_unix_secs(), Ok(1677142050));
+
+		assert!(LSPSDateTime::parse("2023-02-23 08:47:30Z").is_err());
+		assert!(LSPSDateTime::parse("2023-02-29T08:47:30Z").is_err());
+		assert!(LSPSDateTime::parse("2023-13-01T08:47:30Z").is_err());
+		assert!(LSPSDateTime::parse("2023-02-23T08:47:30").is_err());
+		assert!(LSPSDateTime::parse("2023-02-23T08:47:30.Z").is_err());
+		assert!(LSPSDateTime::parse("1969-12-31T23:59:59Z").is_err());
+
+		let date = LSPSDateTime::from_unix_secs(1000);
+		assert!(date.is_before(1001));
+		assert!(!date.is_before(1000));
+		assert!(date.is_past());
+		assert!(!LSPSDateTime::from_now(3600).is_past());
+	}
+
+	#[test]
+	fn request_ids_are_unique() {
+		let generator = RequestIdGenerator::new(&[42; 32]);
+		let first = generator.next_id();
+		let second = generator.next_id();
+		assert_eq!(first.len(), 32);
+		assert_ne!(first, second);
+		assert_ne!(first, RequestIdGenerator::new(&[43; 32]).next_id());
+	}
+}
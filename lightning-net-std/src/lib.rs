@@ -0,0 +1,747 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! A socket handling library for those who wish to use rust-lightning with std TcpStreams but
+//! without an async runtime.
+//!
+//! All sockets are driven by an [`EventLoop`] running on a single thread, which waits for sockets
+//! to become readable or writable using the operating system's polling mechanism (epoll, kqueue
+//! or similar) and feeds their data to the PeerManager. New connections may be handed to the
+//! event loop from any thread via an [`EventLoopHandle`].
+//!
+//! The PeerManager must be an Arc, and must use the SocketDescriptor provided here as the
+//! PeerManager's SocketDescriptor. As with `lightning-net-tokio`, you still need to call
+//! [`PeerManager::timer_tick_occurred`] regularly and handle events, e.g. using the
+//! `lightning-background-processor` crate.
+//!
+//! # Example
+//! ```
+//! use std::net::{SocketAddr, TcpListener};
+//! use std::sync::Arc;
+//! use bitcoin::secp256k1::PublicKey;
+//!
+//! // Define concrete types for our high-level objects:
+//! type TxBroadcaster = dyn lightning::chain::chaininterface::BroadcasterInterface + Send + Sync;
+//! type FeeEstimator = dyn lightning::chain::chaininterface::FeeEstimator + Send + Sync;
+//! type Logger = dyn lightning::util::logger::Logger + Send + Sync;
+//! type ChainAccess = dyn lightning::chain::Access + Send + Sync;
+//! type DataPersister = dyn lightning::chain::chainmonitor::Persist<lightning::chain::keysinterface::InMemorySigner> + Send + Sync;
+//! type ChainFilter = dyn lightning::chain::Filter + Send + Sync;
+//! type ChainMonitor = lightning::chain::chainmonitor::ChainMonitor<lightning::chain::keysinterface::InMemorySigner, Arc<ChainFilter>, Arc<TxBroadcaster>, Arc<FeeEstimator>, Arc<Logger>, Arc<DataPersister>>;
+//! type PeerManager = lightning::ln::peer_handler::SimpleArcPeerManager<lightning_net_std::SocketDescriptor, ChainMonitor, TxBroadcaster, FeeEstimator, ChainAccess, Logger>;
+//!
+//! fn run_network(peer_manager: Arc<PeerManager>, listener: TcpListener, their_node_id: PublicKey, addr: SocketAddr) -> std::io::Result<()> {
+//! 	let mut event_loop = lightning_net_std::EventLoop::new(peer_manager)?;
+//! 	let handle = event_loop.handle();
+//! 	// Accept inbound connections and connect to a peer from another thread:
+//! 	handle.listen(listener)?;
+//! 	std::thread::spawn(move || handle.connect_outbound(their_node_id, addr));
+//! 	// Drive all sockets from this thread until `EventLoopHandle::stop` is called:
+//! 	event_loop.run()
+//! }
+//! ```
+//!
+//! [`PeerManager::timer_tick_occurred`]: lightning::ln::peer_handler::PeerManager::timer_tick_occurred
+
+#![deny(broken_intra_doc_links)]
+#![deny(missing_docs)]
+
+#![cfg_attr(docsrs, feature(doc_auto_cfg))]
+
+use bitcoin::secp256k1::PublicKey;
+
+use mio::{Events, Interest, Poll, Token, Waker};
+use mio::net::{TcpListener, TcpStream};
+
+use lightning::ln::peer_handler;
+use lightning::ln::peer_handler::SocketDescriptor as LnSocketTrait;
+use lightning::ln::peer_handler::CustomMessageHandler;
+use lightning::ln::peer_policy::PeerPolicy;
+use lightning::ln::msgs::{ChannelMessageHandler, RoutingMessageHandler, NetAddress};
+use lightning::util::logger::Logger;
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::io;
+use std::io::{Read, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddr};
+use std::net::TcpListener as StdTcpListener;
+use std::net::TcpStream as StdTcpStream;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+// Token 0 is reserved for our Waker, so we start handing out IDs at 1.
+static ID_COUNTER: AtomicUsize = AtomicUsize::new(1);
+const WAKER_TOKEN: Token = Token(0);
+
+/// The number of reads we do from a single socket before giving other sockets a turn. Without
+/// this a peer which sends us data as fast as we can process it may starve other peers, which
+/// would eventually be disconnected for ping timeouts.
+const READS_PER_TURN: usize = 4;
+
+/// Work handed to the event loop from other threads.
+#[derive(Default)]
+struct PendingWork {
+	inbound: Vec<TcpStream>,
+	outbound: Vec<(PublicKey, TcpStream)>,
+	listeners: Vec<TcpListener>,
+	resume_read: Vec<Token>,
+	disconnect: Vec<Token>,
+	stop: bool,
+}
+
+/// State shared between the event loop, its handles and all connections.
+struct Shared {
+	waker: Waker,
+	pending: Mutex<PendingWork>,
+}
+impl Shared {
+	fn push_work<F: FnOnce(&mut PendingWork)>(&self, f: F) {
+		f(&mut self.pending.lock().unwrap());
+		// If we fail to wake the event loop it has gone away, at which point there is nothing left
+		// to do.
+		let _ = self.waker.wake();
+	}
+}
+
+/// Connection contains all our internal state for a connection - we hold a reference to the
+/// Connection object (in an Arc<Mutex<>>) in each SocketDescriptor we create as well as in the
+/// event loop.
+struct Connection {
+	stream: TcpStream,
+	token: Token,
+	shared: Arc<Shared>,
+	// When we are told by rust-lightning to pause read (because we have writes backing up), we do
+	// so by setting read_paused. At that point, the event loop will stop reading bytes from the
+	// socket until we are told to resume, at which point we ask the event loop to read again.
+	read_paused: bool,
+	// Set when a write failed as the kernel buffer was full, in which case we call
+	// PeerManager::write_buffer_space_avail once the socket becomes writable.
+	awaiting_write_event: bool,
+	// Set once the connection is closed, either because rust-lightning asked us to or because the
+	// event loop noticed the peer went away. We never read or write once this is set.
+	disconnected: bool,
+}
+
+/// An enum describing why we are disconnecting.
+enum Disconnect {
+	/// Rust-Lightning told us to disconnect, either by returning an Err or by calling
+	/// SocketDescriptor::disconnect_socket. In this case, we do not call
+	/// peer_manager.socket_disconnected() as Rust-Lightning already knows we're disconnected.
+	CloseConnection,
+	/// The connection was disconnected for some other reason, ie because the socket was closed.
+	/// In this case, we do need to call peer_manager.socket_disconnected() to inform
+	/// Rust-Lightning that the socket is gone.
+	PeerDisconnected,
+}
+
+fn get_addr_from_stream(stream: &TcpStream) -> Option<NetAddress> {
+	match stream.peer_addr() {
+		Ok(SocketAddr::V4(sockaddr)) => Some(NetAddress::IPv4 {
+			addr: sockaddr.ip().octets(),
+			port: sockaddr.port(),
+		}),
+		Ok(SocketAddr::V6(sockaddr)) => Some(NetAddress::IPv6 {
+			addr: sockaddr.ip().octets(),
+			port: sockaddr.port(),
+		}),
+		Err(_) => None,
+	}
+}
+
+/// A handle to an [`EventLoop`], which can be used to hand it new connections from any thread.
+#[derive(Clone)]
+pub struct EventLoopHandle {
+	shared: Arc<Shared>,
+}
+
+impl EventLoopHandle {
+	/// Hands a socket generated by accepting an incoming connection to the event loop, which will
+	/// process incoming messages and feed outgoing messages on it.
+	pub fn setup_inbound(&self, stream: StdTcpStream) -> io::Result<()> {
+		stream.set_nonblocking(true)?;
+		let stream = TcpStream::from_std(stream);
+		self.shared.push_work(|work| work.inbound.push(stream));
+		Ok(())
+	}
+
+	/// Hands a socket generated by making an outbound connection, which is expected to be
+	/// accepted by a peer with the given public key, to the event loop, which will process
+	/// incoming messages and feed outgoing messages on it.
+	pub fn setup_outbound(&self, their_node_id: PublicKey, stream: StdTcpStream) -> io::Result<()> {
+		stream.set_nonblocking(true)?;
+		let stream = TcpStream::from_std(stream);
+		self.shared.push_work(|work| work.outbound.push((their_node_id, stream)));
+		Ok(())
+	}
+
+	/// Makes a new connection to the given socket address, which is expected to be accepted by a
+	/// peer with the given public key, and hands it to the event loop.
+	///
+	/// Shorthand for TcpStream::connect_timeout(addr) followed by setup_outbound(). Blocks until
+	/// the connection is made or fails.
+	pub fn connect_outbound(&self, their_node_id: PublicKey, addr: SocketAddr) -> io::Result<()> {
+		let stream = StdTcpStream::connect_timeout(&addr, Duration::from_secs(10))?;
+		self.setup_outbound(their_node_id, stream)
+	}
+
+	/// Hands a listening socket to the event loop, which will accept all inbound connections made
+	/// to it as with [`Self::setup_inbound`].
+	pub fn listen(&self, listener: StdTcpListener) -> io::Result<()> {
+		listener.set_nonblocking(true)?;
+		let listener = TcpListener::from_std(listener);
+		self.shared.push_work(|work| work.listeners.push(listener));
+		Ok(())
+	}
+
+	/// Stops the event loop, causing [`EventLoop::run`] to return. Connections are left open and
+	/// will continue to be handled if the event loop is run again.
+	pub fn stop(&self) {
+		self.shared.push_work(|work| work.stop = true);
+	}
+}
+
+/// An event loop driving all connections of a PeerManager from a single thread.
+///
+/// Hand it connections via an [`EventLoopHandle`], obtained from [`Self::handle`], and drive it
+/// by calling [`Self::run`] on a dedicated thread (or [`Self::run_once`] from your own loop).
+pub struct EventLoop<CMH, RMH, L, UMH, PP> where
+		CMH: Deref + 'static + Send + Sync,
+		RMH: Deref + 'static + Send + Sync,
+		L: Deref + 'static + Send + Sync,
+		UMH: Deref + 'static + Send + Sync,
+		PP: Deref + 'static + Send + Sync,
+		CMH::Target: ChannelMessageHandler + Send + Sync,
+		RMH::Target: RoutingMessageHandler + Send + Sync,
+		L::Target: Logger + Send + Sync,
+		UMH::Target: CustomMessageHandler + Send + Sync,
+		PP::Target: PeerPolicy + Send + Sync,
+{
+	peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, CMH, RMH, L, UMH, PP>>,
+	poll: Poll,
+	events: Events,
+	shared: Arc<Shared>,
+	connections: HashMap<Token, SocketDescriptor>,
+	listeners: HashMap<Token, TcpListener>,
+	/// Connections which may have data for us to read, in the order we'll read from them.
+	read_queue: VecDeque<Token>,
+}
+
+impl<CMH, RMH, L, UMH, PP> EventLoop<CMH, RMH, L, UMH, PP> where
+		CMH: Deref + 'static + Send + Sync,
+		RMH: Deref + 'static + Send + Sync,
+		L: Deref + 'static + Send + Sync,
+		UMH: Deref + 'static + Send + Sync,
+		PP: Deref + 'static + Send + Sync,
+		CMH::Target: ChannelMessageHandler + Send + Sync,
+		RMH::Target: RoutingMessageHandler + Send + Sync,
+		L::Target: Logger + Send + Sync,
+		UMH::Target: CustomMessageHandler + Send + Sync,
+		PP::Target: PeerPolicy + Send + Sync,
+{
+	/// Creates a new event loop driving connections for the given PeerManager.
+	pub fn new(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, CMH, RMH, L, UMH, PP>>) -> io::Result<Self> {
+		let poll = Poll::new()?;
+		let waker = Waker::new(poll.registry(), WAKER_TOKEN)?;
+		Ok(Self {
+			peer_manager, poll,
+			events: Events::with_capacity(1024),
+			shared: Arc::new(Shared { waker, pending: Mutex::new(PendingWork::default()) }),
+			connections: HashMap::new(),
+			listeners: HashMap::new(),
+			read_queue: VecDeque::new(),
+		})
+	}
+
+	/// Gets a handle which can be used to hand connections to this event loop from any thread.
+	pub fn handle(&self) -> EventLoopHandle {
+		EventLoopHandle { shared: Arc::clone(&self.shared) }
+	}
+
+	/// Handles socket events until [`EventLoopHandle::stop`] is called, returning an error only if
+	/// waiting for events fails.
+	pub fn run(&mut self) -> io::Result<()> {
+		while self.run_once(None)? {}
+		Ok(())
+	}
+
+	/// Waits for socket events for up to `timeout` (or forever if `None`) and handles them,
+	/// returning false if [`EventLoopHandle::stop`] has been called.
+	pub fn run_once(&mut self, timeout: Option<Duration>) -> io::Result<bool> {
+		// If we stopped reading from some sockets to give others a turn, don't wait for new events.
+		let timeout = if self.read_queue.is_empty() { timeout } else { Some(Duration::from_secs(0)) };
+		match self.poll.poll(&mut self.events, timeout) {
+			Ok(()) => {},
+			Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+			Err(e) => return Err(e),
+		}
+
+		let mut writable = Vec::new();
+		let mut readable = Vec::new();
+		let mut accepting = Vec::new();
+		for event in self.events.iter() {
+			let token = event.token();
+			if token == WAKER_TOKEN { continue; }
+			if self.listeners.contains_key(&token) {
+				accepting.push(token);
+				continue;
+			}
+			if event.is_writable() {
+				writable.push(token);
+			}
+			if event.is_readable() || event.is_read_closed() || event.is_error() {
+				readable.push(token);
+			}
+		}
+		for token in readable {
+			self.queue_read(token);
+		}
+
+		let stop = self.handle_pending_work();
+		for token in accepting {
+			self.accept_connections(token);
+		}
+		for token in writable {
+			self.write_buffer_space_avail(token);
+		}
+
+		let mut read_any = false;
+		for token in mem::take(&mut self.read_queue) {
+			read_any = true;
+			if self.read_from(token) {
+				self.queue_read(token);
+			}
+		}
+		if read_any {
+			self.peer_manager.process_events();
+		}
+		Ok(!stop)
+	}
+
+	fn queue_read(&mut self, token: Token) {
+		if !self.read_queue.contains(&token) {
+			self.read_queue.push_back(token);
+		}
+	}
+
+	/// Handles work handed to us via our handle or SocketDescriptors, returning whether we've been
+	/// asked to stop.
+	fn handle_pending_work(&mut self) -> bool {
+		let work = mem::take(&mut *self.shared.pending.lock().unwrap());
+		for mut listener in work.listeners {
+			let token = Token(ID_COUNTER.fetch_add(1, Ordering::AcqRel));
+			if self.poll.registry().register(&mut listener, token, Interest::READABLE).is_ok() {
+				self.listeners.insert(token, listener);
+				// Accept any connections which were made before we registered.
+				self.accept_connections(token);
+			}
+		}
+		for stream in work.inbound {
+			self.setup_inbound(stream);
+		}
+		for (their_node_id, stream) in work.outbound {
+			self.setup_outbound(their_node_id, stream);
+		}
+		for token in work.resume_read {
+			self.queue_read(token);
+		}
+		for token in work.disconnect {
+			self.disconnect(token, Disconnect::CloseConnection);
+		}
+		work.stop
+	}
+
+	fn accept_connections(&mut self, token: Token) {
+		loop {
+			let accepted = match self.listeners.get(&token) {
+				Some(listener) => listener.accept(),
+				None => return,
+			};
+			match accepted {
+				Ok((stream, _)) => self.setup_inbound(stream),
+				Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+				// On WouldBlock we've accepted everything. Otherwise, we're most likely out of file
+				// descriptors, and will try again when the next connection comes in.
+				Err(_) => return,
+			}
+		}
+	}
+
+	fn register(&mut self, mut stream: TcpStream) -> Option<SocketDescriptor> {
+		let id = ID_COUNTER.fetch_add(1, Ordering::AcqRel);
+		let token = Token(id);
+		self.poll.registry().register(&mut stream, token, Interest::READABLE | Interest::WRITABLE).ok()?;
+		let conn = Arc::new(Mutex::new(Connection {
+			stream, token,
+			shared: Arc::clone(&self.shared),
+			read_paused: false,
+			awaiting_write_event: false,
+			disconnected: false,
+		}));
+		Some(SocketDescriptor { conn, id })
+	}
+
+	fn setup_inbound(&mut self, stream: TcpStream) {
+		let remote_addr = get_addr_from_stream(&stream);
+		let descriptor = match self.register(stream) { Some(descriptor) => descriptor, None => return };
+		if self.peer_manager.new_inbound_connection(descriptor.clone(), remote_addr).is_ok() {
+			let token = descriptor.token();
+			self.connections.insert(token, descriptor);
+			self.queue_read(token);
+		} else {
+			// Note that we will skip socket_disconnected here, in accordance with the PeerManager
+			// requirements.
+			self.close(&descriptor);
+		}
+	}
+
+	fn setup_outbound(&mut self, their_node_id: PublicKey, stream: TcpStream) {
+		let remote_addr = get_addr_from_stream(&stream);
+		let mut descriptor = match self.register(stream) { Some(descriptor) => descriptor, None => return };
+		if let Ok(initial_send) = self.peer_manager.new_outbound_connection(their_node_id, descriptor.clone(), remote_addr) {
+			let token = descriptor.token();
+			self.connections.insert(token, descriptor.clone());
+			// We should essentially always have enough room in a fresh TCP socket buffer to send
+			// the initial 10s of bytes.
+			if descriptor.send_data(&initial_send, true) != initial_send.len() {
+				self.disconnect(token, Disconnect::PeerDisconnected);
+				return;
+			}
+			self.queue_read(token);
+		} else {
+			// Note that we will skip socket_disconnected here, in accordance with the PeerManager
+			// requirements.
+			self.close(&descriptor);
+		}
+	}
+
+	fn write_buffer_space_avail(&mut self, token: Token) {
+		let mut descriptor = match self.connections.get(&token) { Some(descriptor) => descriptor.clone(), None => return };
+		{
+			let mut us = descriptor.conn.lock().unwrap();
+			if !us.awaiting_write_event { return; }
+			us.awaiting_write_event = false;
+		}
+		if self.peer_manager.write_buffer_space_avail(&mut descriptor).is_err() {
+			self.disconnect(token, Disconnect::CloseConnection);
+		}
+	}
+
+	/// Reads from the given socket until we've read all available data, reading is paused or we
+	/// hit [`READS_PER_TURN`], returning true only in the last case.
+	fn read_from(&mut self, token: Token) -> bool {
+		let mut descriptor = match self.connections.get(&token) { Some(descriptor) => descriptor.clone(), None => return false };
+		// 8KB is nice and big but also should never cause any issues with stack overflowing.
+		let mut buf = [0; 8192];
+		for _ in 0..READS_PER_TURN {
+			let read_res = {
+				let mut us = descriptor.conn.lock().unwrap();
+				if us.disconnected {
+					mem::drop(us);
+					self.disconnect(token, Disconnect::CloseConnection);
+					return false;
+				}
+				if us.read_paused { return false; }
+				us.stream.read(&mut buf)
+			};
+			match read_res {
+				Ok(0) => {
+					self.disconnect(token, Disconnect::PeerDisconnected);
+					return false;
+				},
+				Ok(len) => match self.peer_manager.read_event(&mut descriptor, &buf[0..len]) {
+					Ok(pause_read) => {
+						if pause_read {
+							descriptor.conn.lock().unwrap().read_paused = true;
+						}
+					},
+					Err(_) => {
+						self.disconnect(token, Disconnect::CloseConnection);
+						return false;
+					},
+				},
+				Err(e) if e.kind() == io::ErrorKind::WouldBlock => return false,
+				Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+				Err(_) => {
+					self.disconnect(token, Disconnect::PeerDisconnected);
+					return false;
+				},
+			}
+		}
+		true
+	}
+
+	fn disconnect(&mut self, token: Token, disconnect_type: Disconnect) {
+		if let Some(descriptor) = self.connections.remove(&token) {
+			self.close(&descriptor);
+			if let Disconnect::PeerDisconnected = disconnect_type {
+				self.peer_manager.socket_disconnected(&descriptor);
+				self.peer_manager.process_events();
+			}
+		}
+	}
+
+	fn close(&self, descriptor: &SocketDescriptor) {
+		let mut us = descriptor.conn.lock().unwrap();
+		us.disconnected = true;
+		let _ = self.poll.registry().deregister(&mut us.stream);
+		// If the socket is already closed, shutdown() will fail, so just ignore it.
+		let _ = us.stream.shutdown(Shutdown::Both);
+	}
+}
+
+/// The SocketDescriptor used to refer to sockets by a PeerHandler. This is pub only as it is a
+/// type in the template of PeerHandler.
+pub struct SocketDescriptor {
+	conn: Arc<Mutex<Connection>>,
+	id: usize,
+}
+impl SocketDescriptor {
+	fn token(&self) -> Token {
+		Token(self.id)
+	}
+}
+impl peer_handler::SocketDescriptor for SocketDescriptor {
+	fn send_data(&mut self, data: &[u8], resume_read: bool) -> usize {
+		let mut us = self.conn.lock().unwrap();
+		if us.disconnected {
+			return 0;
+		}
+
+		if resume_read && us.read_paused {
+			us.read_paused = false;
+			let token = us.token;
+			us.shared.push_work(|work| work.resume_read.push(token));
+		}
+		let mut written_len = 0;
+		while written_len < data.len() {
+			match us.stream.write(&data[written_len..]) {
+				Ok(0) => break,
+				Ok(len) => written_len += len,
+				Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+				Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+					// The event loop will call write_buffer_space_avail once the socket becomes
+					// writable, but we need to make sure we also pause read given we're now
+					// waiting on the remote end to ACK (and in accordance with the send_data()
+					// docs).
+					us.awaiting_write_event = true;
+					us.read_paused = true;
+					break;
+				},
+				// Probably we've already been closed, just return what we have and let the event
+				// loop handle closing logic.
+				Err(_) => break,
+			}
+		}
+		written_len
+	}
+
+	fn disconnect_socket(&mut self) {
+		let mut us = self.conn.lock().unwrap();
+		us.disconnected = true;
+		// Shut the socket down immediately so the peer notices promptly, leaving the event loop
+		// to stop watching it.
+		let _ = us.stream.shutdown(Shutdown::Both);
+		let token = us.token;
+		us.shared.push_work(|work| work.disconnect.push(token));
+	}
+}
+impl Clone for SocketDescriptor {
+	fn clone(&self) -> Self {
+		Self {
+			conn: Arc::clone(&self.conn),
+			id: self.id,
+		}
+	}
+}
+impl Eq for SocketDescriptor {}
+impl PartialEq for SocketDescriptor {
+	fn eq(&self, o: &Self) -> bool {
+		self.id == o.id
+	}
+}
+impl Hash for SocketDescriptor {
+	fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+		self.id.hash(state);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::EventLoop;
+
+	use lightning::ln::msgs::ErrorAction;
+	use lightning::ln::peer_handler::{MessageHandler, PeerManager};
+	use lightning::util::events::MessageSendEvent;
+	use lightning::util::test_utils::{TestLogger, TestPeerConnectionHandler};
+	use bitcoin::secp256k1::{Secp256k1, SecretKey, PublicKey};
+
+	use std::sync::atomic::Ordering;
+	use std::sync::{mpsc, Arc, Mutex};
+	use std::time::Duration;
+
+	fn make_tcp_connection() -> (std::net::TcpStream, std::net::TcpStream) {
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		(std::net::TcpStream::connect(addr).unwrap(), listener.accept().unwrap().0)
+	}
+
+	fn make_handler(expected_pubkey: PublicKey) -> (Arc<TestPeerConnectionHandler>, mpsc::Receiver<()>, mpsc::Receiver<()>) {
+		let (connected_sender, connected) = mpsc::channel();
+		let (disconnected_sender, disconnected) = mpsc::channel();
+		let connected_sender = Mutex::new(connected_sender);
+		let disconnected_sender = Mutex::new(disconnected_sender);
+		let handler = Arc::new(TestPeerConnectionHandler::new(expected_pubkey,
+			move || connected_sender.lock().unwrap().send(()).unwrap(),
+			move || disconnected_sender.lock().unwrap().send(()).unwrap()));
+		(handler, connected, disconnected)
+	}
+
+	#[test]
+	fn basic_connection_test() {
+		let secp_ctx = Secp256k1::new();
+		let a_key = SecretKey::from_slice(&[1; 32]).unwrap();
+		let b_key = SecretKey::from_slice(&[2; 32]).unwrap();
+		let a_pub = PublicKey::from_secret_key(&secp_ctx, &a_key);
+		let b_pub = PublicKey::from_secret_key(&secp_ctx, &b_key);
+
+		let (a_handler, a_connected, a_disconnected) = make_handler(b_pub);
+		let a_manager = Arc::new(PeerManager::new(MessageHandler {
+			chan_handler: Arc::clone(&a_handler),
+			route_handler: Arc::clone(&a_handler),
+		}, a_key, &[1; 32], Arc::new(TestLogger::new()), Arc::new(lightning::ln::peer_handler::IgnoringMessageHandler{})));
+		let (b_handler, b_connected, b_disconnected) = make_handler(a_pub);
+		let b_manager = Arc::new(PeerManager::new(MessageHandler {
+			chan_handler: Arc::clone(&b_handler),
+			route_handler: Arc::clone(&b_handler),
+		}, b_key, &[2; 32], Arc::new(TestLogger::new()), Arc::new(lightning::ln::peer_handler::IgnoringMessageHandler{})));
+
+		let mut event_loop = EventLoop::new(Arc::clone(&a_manager)).unwrap();
+		let mut b_event_loop = EventLoop::new(Arc::clone(&b_manager)).unwrap();
+		let (handle, b_handle) = (event_loop.handle(), b_event_loop.handle());
+		let a_thread = std::thread::spawn(move || event_loop.run().unwrap());
+		let b_thread = std::thread::spawn(move || b_event_loop.run().unwrap());
+
+		let (conn_a, conn_b) = make_tcp_connection();
+		handle.setup_outbound(b_pub, conn_a).unwrap();
+		b_handle.setup_inbound(conn_b).unwrap();
+
+		a_connected.recv_timeout(Duration::from_secs(10)).unwrap();
+		b_connected.recv_timeout(Duration::from_secs(1)).unwrap();
+
+		a_handler.pending_events.lock().unwrap().push(MessageSendEvent::HandleError {
+			node_id: b_pub, action: ErrorAction::DisconnectPeer { msg: None }
+		});
+		assert!(!a_handler.disconnected_flag.load(Ordering::SeqCst));
+		assert!(!b_handler.disconnected_flag.load(Ordering::SeqCst));
+
+		a_manager.process_events();
+		a_disconnected.recv_timeout(Duration::from_secs(10)).unwrap();
+		b_disconnected.recv_timeout(Duration::from_secs(1)).unwrap();
+		assert!(a_handler.disconnected_flag.load(Ordering::SeqCst));
+		assert!(b_handler.disconnected_flag.load(Ordering::SeqCst));
+
+		handle.stop();
+		b_handle.stop();
+		a_thread.join().unwrap();
+		b_thread.join().unwrap();
+	}
+
+	#[test]
+	fn listen_and_connect_outbound() {
+		let secp_ctx = Secp256k1::new();
+		let a_key = SecretKey::from_slice(&[1; 32]).unwrap();
+		let b_key = SecretKey::from_slice(&[2; 32]).unwrap();
+		let a_pub = PublicKey::from_secret_key(&secp_ctx, &a_key);
+		let b_pub = PublicKey::from_secret_key(&secp_ctx, &b_key);
+
+		let (a_handler, a_connected, _a_disconnected) = make_handler(b_pub);
+		let a_manager = Arc::new(PeerManager::new(MessageHandler {
+			chan_handler: Arc::clone(&a_handler),
+			route_handler: Arc::clone(&a_handler),
+		}, a_key, &[1; 32], Arc::new(TestLogger::new()), Arc::new(lightning::ln::peer_handler::IgnoringMessageHandler{})));
+		let (b_handler, b_connected, b_disconnected) = make_handler(a_pub);
+		let b_manager = Arc::new(PeerManager::new(MessageHandler {
+			chan_handler: Arc::clone(&b_handler),
+			route_handler: Arc::clone(&b_handler),
+		}, b_key, &[2; 32], Arc::new(TestLogger::new()), Arc::new(lightning::ln::peer_handler::IgnoringMessageHandler{})));
+
+		// Drive the connecting side from its own thread and the listening side via run_once.
+		let mut a_event_loop = EventLoop::new(Arc::clone(&a_manager)).unwrap();
+		let a_handle = a_event_loop.handle();
+		let a_thread = std::thread::spawn(move || a_event_loop.run().unwrap());
+		let mut b_event_loop = EventLoop::new(Arc::clone(&b_manager)).unwrap();
+		let b_handle = b_event_loop.handle();
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let b_addr = listener.local_addr().unwrap();
+		b_handle.listen(listener).unwrap();
+
+		a_handle.connect_outbound(b_pub, b_addr).unwrap();
+		while b_connected.try_recv().is_err() {
+			assert!(b_event_loop.run_once(Some(Duration::from_millis(10))).unwrap());
+		}
+		a_connected.recv_timeout(Duration::from_secs(10)).unwrap();
+
+		// Once we stop the connecting side, it stops handling events but the connection stays
+		// open.
+		a_handle.stop();
+		a_thread.join().unwrap();
+		assert_eq!(b_manager.get_peer_node_ids(), vec![a_pub]);
+
+		b_manager.disconnect_all_peers();
+		b_disconnected.recv_timeout(Duration::from_secs(1)).unwrap();
+		b_handle.stop();
+		assert!(!b_event_loop.run_once(Some(Duration::from_millis(10))).unwrap());
+	}
+
+	#[test]
+	fn race_disconnect_accept() {
+		// Previously, if we handed an already-disconnected socket to `setup_inbound` the tokio
+		// driver would panic. This attempts to find similar races by opening connections and
+		// shutting them down while connecting.
+		let secp_ctx = Secp256k1::new();
+		let a_key = SecretKey::from_slice(&[1; 32]).unwrap();
+		let b_key = SecretKey::from_slice(&[2; 32]).unwrap();
+		let b_pub = PublicKey::from_secret_key(&secp_ctx, &b_key);
+
+		let a_manager = Arc::new(PeerManager::new(MessageHandler {
+			chan_handler: Arc::new(lightning::ln::peer_handler::ErroringMessageHandler::new()),
+			route_handler: Arc::new(lightning::ln::peer_handler::IgnoringMessageHandler{}),
+		}, a_key, &[1; 32], Arc::new(TestLogger::new()), Arc::new(lightning::ln::peer_handler::IgnoringMessageHandler{})));
+
+		let mut event_loop = EventLoop::new(Arc::clone(&a_manager)).unwrap();
+		let handle = event_loop.handle();
+
+		// Make two connections, one for an inbound and one for an outbound connection
+		let conn_a = {
+			let (conn_a, _) = make_tcp_connection();
+			conn_a
+		};
+		let conn_b = {
+			let (_, conn_b) = make_tcp_connection();
+			conn_b
+		};
+		handle.setup_inbound(conn_a).unwrap();
+		handle.setup_outbound(b_pub, conn_b).unwrap();
+
+		// Both connections are closed by the remote end, after which the event loop forgets them.
+		assert!(event_loop.run_once(Some(Duration::from_millis(10))).unwrap());
+		for _ in 0..1000 {
+			if event_loop.connections.is_empty() { break; }
+			assert!(event_loop.run_once(Some(Duration::from_millis(10))).unwrap());
+		}
+		assert!(event_loop.connections.is_empty());
+		assert!(a_manager.get_peer_node_ids().is_empty());
+		handle.stop();
+		assert!(!event_loop.run_once(Some(Duration::from_millis(10))).unwrap());
+	}
+}
@@ -0,0 +1,728 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! A [`PeerConnectionManager`] which keeps us connected to the peers we care about, such as those
+//! we have channels with, reconnecting to them with backoff whenever they drop off.
+
+use bitcoin::hashes::Hash;
+use bitcoin::hashes::HashEngine;
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::secp256k1::PublicKey;
+
+use lightning::chain;
+use lightning::chain::chaininterface::{BroadcasterInterface, FeeEstimator};
+use lightning::chain::keysinterface::{KeysInterface, Sign};
+use lightning::io;
+use lightning::ln::channelmanager::ChannelManager;
+use lightning::ln::msgs::{ChannelMessageHandler, DecodeError, NetAddress, RoutingMessageHandler};
+use lightning::ln::peer_handler;
+use lightning::ln::peer_handler::CustomMessageHandler;
+use lightning::ln::peer_policy::PeerPolicy;
+use lightning::routing::gossip::NetworkGraph;
+use lightning::util::logger::Logger;
+use lightning::util::persist::KVStorePersister;
+use lightning::util::ser::{Readable, Writeable, Writer};
+
+use crate::{HandshakeSignal, ProxyCredentials, SocketDescriptor, Socks5Proxy};
+
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::Deref;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// The key under which [`PeerConnectionManager`] persists its [`PeerAddressBook`].
+pub const PEER_ADDRESS_BOOK_PERSISTENCE_KEY: &str = "peer_address_book";
+
+/// The maximum number of addresses we remember per peer.
+const MAX_ADDRESSES_PER_PEER: usize = 8;
+
+/// How long we wait for the handshake with a peer to complete once connected.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The port Lightning nodes listen on by default, at which we guess peers which connected to us
+/// from an address they didn't announce can be reached.
+const DEFAULT_LIGHTNING_PORT: u16 = 9735;
+
+const SERIALIZATION_VERSION: u8 = 1;
+
+/// A future which completes once we're disconnected from a peer.
+type Connection = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Lists the counterparties of our channels, see [`PeerConnectionManager::track_channel_peers`].
+type ChannelPeerSource = Box<dyn Fn() -> Vec<PublicKey> + Send + Sync>;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct AddressBookEntry {
+	/// Known addresses, the one we most recently connected to first.
+	addresses: Vec<NetAddress>,
+	/// Whether we want to stay connected to the peer.
+	persistent: bool,
+}
+
+/// The addresses at which we have reached peers, along with the set of peers we want to stay
+/// connected to.
+///
+/// Persisted by the [`PeerConnectionManager`] under [`PEER_ADDRESS_BOOK_PERSISTENCE_KEY`] and
+/// read back with [`Readable`] to be handed to [`PeerConnectionManager::new`] on startup.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PeerAddressBook {
+	peers: HashMap<PublicKey, AddressBookEntry>,
+}
+
+impl PeerAddressBook {
+	/// Creates an empty address book.
+	pub fn new() -> Self {
+		Self { peers: HashMap::new() }
+	}
+
+	/// Gets the addresses known for the given peer, the one we most recently connected to first.
+	pub fn addresses(&self, node_id: &PublicKey) -> Vec<NetAddress> {
+		self.peers.get(node_id).map(|entry| entry.addresses.clone()).unwrap_or_default()
+	}
+
+	/// Gets the peers we want to stay connected to.
+	pub fn persistent_peers(&self) -> Vec<PublicKey> {
+		self.peers.iter().filter(|(_, entry)| entry.persistent).map(|(node_id, _)| *node_id).collect()
+	}
+
+	/// Records that the given peer is reachable at `address`, returning whether anything changed.
+	fn add_address(&mut self, node_id: PublicKey, address: NetAddress) -> bool {
+		let addresses = &mut self.peers.entry(node_id).or_default().addresses;
+		if addresses.first() == Some(&address) {
+			return false;
+		}
+		addresses.retain(|known| *known != address);
+		addresses.insert(0, address);
+		addresses.truncate(MAX_ADDRESSES_PER_PEER);
+		true
+	}
+
+	/// Sets whether we want to stay connected to the given peer, returning whether anything
+	/// changed.
+	fn set_persistent(&mut self, node_id: PublicKey, persistent: bool) -> bool {
+		let entry = self.peers.entry(node_id).or_default();
+		let changed = entry.persistent != persistent;
+		entry.persistent = persistent;
+		if !entry.persistent && entry.addresses.is_empty() {
+			self.peers.remove(&node_id);
+		}
+		changed
+	}
+}
+
+impl Writeable for PeerAddressBook {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		SERIALIZATION_VERSION.write(writer)?;
+		(self.peers.len() as u64).write(writer)?;
+		for (node_id, entry) in self.peers.iter() {
+			node_id.write(writer)?;
+			entry.persistent.write(writer)?;
+			(entry.addresses.len() as u16).write(writer)?;
+			for address in entry.addresses.iter() {
+				address.write(writer)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+impl Readable for PeerAddressBook {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let version: u8 = Readable::read(reader)?;
+		if version > SERIALIZATION_VERSION {
+			return Err(DecodeError::UnknownVersion);
+		}
+		let peer_count: u64 = Readable::read(reader)?;
+		let mut peers = HashMap::new();
+		for _ in 0..peer_count {
+			let node_id: PublicKey = Readable::read(reader)?;
+			let persistent: bool = Readable::read(reader)?;
+			let address_count: u16 = Readable::read(reader)?;
+			let mut addresses = Vec::new();
+			for _ in 0..address_count {
+				addresses.push(Readable::read(reader)?);
+			}
+			peers.insert(node_id, AddressBookEntry { addresses, persistent });
+		}
+		Ok(Self { peers })
+	}
+}
+
+/// A change in our connection to a peer, see [`PeerConnectionManager::get_and_clear_pending_events`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerConnectionEvent {
+	/// We have connected to the given peer, whether we dialed it or it connected to us.
+	Connected {
+		/// The peer's node id.
+		node_id: PublicKey,
+	},
+	/// We are no longer connected to the given peer.
+	Disconnected {
+		/// The peer's node id.
+		node_id: PublicKey,
+	},
+	/// We failed to reach a peer we want to stay connected to at any of its known addresses.
+	ReconnectFailed {
+		/// The peer's node id.
+		node_id: PublicKey,
+		/// How long we'll wait before trying again.
+		retry_in: Duration,
+	},
+}
+
+/// Configuration for a [`PeerConnectionManager`].
+#[derive(Clone, Debug)]
+pub struct PeerConnectionManagerConfig {
+	/// How long to wait before retrying a peer after our first failure to reach it. This doubles
+	/// with each further attempt up to [`Self::max_backoff`], and the actual delay is picked
+	/// randomly between half of and the full backoff to avoid reconnecting in lockstep.
+	///
+	/// Default value: 1 second.
+	pub initial_backoff: Duration,
+	/// The maximum delay between attempts to reach a peer.
+	///
+	/// Default value: 5 minutes.
+	pub max_backoff: Duration,
+	/// How often [`PeerConnectionManager::run`] checks our connections.
+	///
+	/// Default value: 1 second.
+	pub check_interval: Duration,
+	/// If set, all connections are made through this SOCKS5 proxy, with each peer's connections
+	/// using distinct credentials so that Tor isolates them from each other. Otherwise we only
+	/// dial IP and DNS hostname addresses directly.
+	///
+	/// Default value: `None`.
+	pub proxy: Option<Socks5Proxy>,
+}
+
+impl Default for PeerConnectionManagerConfig {
+	fn default() -> Self {
+		Self {
+			initial_backoff: Duration::from_secs(1),
+			max_backoff: Duration::from_secs(5 * 60),
+			check_interval: Duration::from_secs(1),
+			proxy: None,
+		}
+	}
+}
+
+struct Backoff {
+	attempts: u32,
+	next_attempt: Instant,
+}
+
+struct ConnectionState {
+	address_book: PeerAddressBook,
+	address_book_dirty: bool,
+	connected_peers: HashSet<PublicKey>,
+	connecting_peers: HashSet<PublicKey>,
+	backoff: HashMap<PublicKey, Backoff>,
+	pending_events: Vec<PeerConnectionEvent>,
+}
+
+/// Keeps us connected to a set of persistent peers, such as those we have channels with.
+///
+/// Peers are registered via [`Self::add_peer`], while the counterparties of our channels are
+/// tracked automatically once [`Self::track_channel_peers`] was called. Whenever one of them is no
+/// longer listed in [`PeerManager::get_peer_node_ids`], we try to reconnect to it at the addresses
+/// we last reached it at, followed by any it announced via gossip in the [`NetworkGraph`], backing
+/// off exponentially (with jitter) while it remains unreachable.
+///
+/// Once the handshake with a peer has completed, the address it can be reached at is remembered in
+/// a [`PeerAddressBook`] which is persisted whenever it changes. For peers we connected to, this is
+/// the address we reached it at, i.e. the one we tell it about in our `Init` message's
+/// `remote_network_address`. As peers which connected to us generally do so from an ephemeral
+/// port, we instead remember the address they announced with the IP they connected from, or that
+/// IP with the default port if they didn't announce it.
+///
+/// [`PeerManager::get_peer_node_ids`]: lightning::ln::peer_handler::PeerManager::get_peer_node_ids
+pub struct PeerConnectionManager<CMH, RMH, L, UMH, PP, G> where
+		CMH: Deref + 'static + Send + Sync,
+		RMH: Deref + 'static + Send + Sync,
+		L: Deref + 'static + Send + Sync,
+		UMH: Deref + 'static + Send + Sync,
+		PP: Deref + 'static + Send + Sync,
+		G: Deref<Target = NetworkGraph<L>> + 'static + Send + Sync,
+		CMH::Target: ChannelMessageHandler + Send + Sync,
+		RMH::Target: RoutingMessageHandler + Send + Sync,
+		L::Target: Logger + Send + Sync,
+		UMH::Target: CustomMessageHandler + Send + Sync,
+		PP::Target: PeerPolicy + Send + Sync,
+{
+	peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, CMH, RMH, L, UMH, PP>>,
+	network_graph: Option<G>,
+	logger: L,
+	config: PeerConnectionManagerConfig,
+	state: Mutex<ConnectionState>,
+	channel_peers: Mutex<Option<ChannelPeerSource>>,
+	jitter_seed: [u8; 32],
+	jitter_counter: AtomicU64,
+}
+
+impl<CMH, RMH, L, UMH, PP, G> PeerConnectionManager<CMH, RMH, L, UMH, PP, G> where
+		CMH: Deref + 'static + Send + Sync,
+		RMH: Deref + 'static + Send + Sync,
+		L: Deref + 'static + Send + Sync,
+		UMH: Deref + 'static + Send + Sync,
+		PP: Deref + 'static + Send + Sync,
+		G: Deref<Target = NetworkGraph<L>> + 'static + Send + Sync,
+		CMH::Target: ChannelMessageHandler + Send + Sync,
+		RMH::Target: RoutingMessageHandler + Send + Sync,
+		L::Target: Logger + Send + Sync,
+		UMH::Target: CustomMessageHandler + Send + Sync,
+		PP::Target: PeerPolicy + Send + Sync,
+{
+	/// Creates a new manager for connections made through `peer_manager`, starting from the given
+	/// (possibly previously persisted) address book.
+	///
+	/// If a `network_graph` is provided, peers' announced addresses are used when we cannot reach
+	/// them at any address in the address book. The `keys_manager` provides the randomness with
+	/// which we jitter our reconnection backoff.
+	pub fn new<K: Deref>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, CMH, RMH, L, UMH, PP>>, network_graph: Option<G>, address_book: PeerAddressBook, keys_manager: K, logger: L, config: PeerConnectionManagerConfig) -> Self where K::Target: KeysInterface {
+		Self {
+			peer_manager, network_graph, logger, config,
+			state: Mutex::new(ConnectionState {
+				address_book,
+				address_book_dirty: false,
+				connected_peers: HashSet::new(),
+				connecting_peers: HashSet::new(),
+				backoff: HashMap::new(),
+				pending_events: Vec::new(),
+			}),
+			channel_peers: Mutex::new(None),
+			jitter_seed: keys_manager.get_secure_random_bytes(),
+			jitter_counter: AtomicU64::new(0),
+		}
+	}
+
+	/// Starts keeping us connected to the given peer, optionally recording an address at which it
+	/// can be reached.
+	pub fn add_peer(&self, node_id: PublicKey, address: Option<NetAddress>) {
+		let mut state = self.state.lock().unwrap();
+		let mut changed = state.address_book.set_persistent(node_id, true);
+		if let Some(address) = address {
+			changed |= state.address_book.add_address(node_id, address);
+		}
+		state.address_book_dirty |= changed;
+	}
+
+	/// Starts keeping us connected to the counterparties of all channels listed by
+	/// [`ChannelManager::list_channels`] each time we check our connections, in addition to the
+	/// peers added via [`Self::add_peer`]. We stop reconnecting to a counterparty once we no
+	/// longer have a channel with it.
+	pub fn track_channel_peers<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, CL: Deref, CM>(&self, channel_manager: CM) where
+			CM: Deref<Target = ChannelManager<Signer, M, T, K, F, CL>> + 'static + Send + Sync,
+			M::Target: chain::Watch<Signer>,
+			T::Target: BroadcasterInterface,
+			K::Target: KeysInterface<Signer = Signer>,
+			F::Target: FeeEstimator,
+			CL::Target: Logger,
+	{
+		*self.channel_peers.lock().unwrap() = Some(Box::new(move || {
+			channel_manager.list_channels().iter().map(|channel| channel.counterparty.node_id).collect()
+		}));
+	}
+
+	/// Stops reconnecting to the given peer, unless we have a channel with it and are tracking
+	/// channel peers (see [`Self::track_channel_peers`]). Its known addresses are kept and the
+	/// current connection, if any, is left open.
+	pub fn remove_peer(&self, node_id: &PublicKey) {
+		let mut state = self.state.lock().unwrap();
+		state.address_book_dirty |= state.address_book.set_persistent(*node_id, false);
+		state.backoff.remove(node_id);
+	}
+
+	/// Gets a copy of the current address book.
+	pub fn address_book(&self) -> PeerAddressBook {
+		self.state.lock().unwrap().address_book.clone()
+	}
+
+	/// Gets the connection events which occurred since this was last called.
+	pub fn get_and_clear_pending_events(&self) -> Vec<PeerConnectionEvent> {
+		core::mem::take(&mut self.state.lock().unwrap().pending_events)
+	}
+
+	/// Persists the address book if it changed since it was last persisted.
+	pub fn persist_address_book<K: Deref>(&self, persister: &K) -> Result<(), io::Error> where K::Target: KVStorePersister {
+		let address_book = {
+			let mut state = self.state.lock().unwrap();
+			if !state.address_book_dirty {
+				return Ok(());
+			}
+			state.address_book_dirty = false;
+			state.address_book.clone()
+		};
+		persister.persist(PEER_ADDRESS_BOOK_PERSISTENCE_KEY, &address_book).map_err(|e| {
+			self.state.lock().unwrap().address_book_dirty = true;
+			e
+		})
+	}
+
+	/// Checks which peers we are connected to, generating [`PeerConnectionEvent`]s for any changes
+	/// and spawning (via tokio::spawn) reconnection attempts to persistent peers which are due.
+	pub fn process_connections(self: &Arc<Self>) {
+		let channel_peers = self.channel_peers.lock().unwrap().as_ref().map_or(Vec::new(), |list_channel_peers| list_channel_peers());
+		let peers = self.peer_manager.peer_stats();
+		let connected_peers: HashSet<PublicKey> = peers.iter().map(|peer| peer.node_id).collect();
+		let mut state = self.state.lock().unwrap();
+		let state = &mut *state;
+
+		for peer in peers {
+			if state.connected_peers.contains(&peer.node_id) {
+				continue;
+			}
+			state.pending_events.push(PeerConnectionEvent::Connected { node_id: peer.node_id });
+			state.backoff.remove(&peer.node_id);
+			let address = match (peer.inbound_connection, peer.remote_network_address) {
+				(false, address) => address,
+				(true, Some(address)) => self.inbound_peer_address(&peer.node_id, address),
+				(true, None) => None,
+			};
+			if let Some(address) = address {
+				state.address_book_dirty |= state.address_book.add_address(peer.node_id, address);
+			}
+		}
+		for node_id in state.connected_peers.difference(&connected_peers) {
+			state.pending_events.push(PeerConnectionEvent::Disconnected { node_id: *node_id });
+		}
+		state.connected_peers = connected_peers;
+
+		let now = Instant::now();
+		let mut persistent_peers = state.address_book.persistent_peers();
+		for node_id in channel_peers {
+			if !persistent_peers.contains(&node_id) {
+				persistent_peers.push(node_id);
+			}
+		}
+		for node_id in persistent_peers {
+			if state.connected_peers.contains(&node_id) || state.connecting_peers.contains(&node_id) {
+				continue;
+			}
+			if let Some(backoff) = state.backoff.get(&node_id) {
+				if backoff.next_attempt > now {
+					continue;
+				}
+			}
+			state.connecting_peers.insert(node_id);
+			tokio::spawn(Arc::clone(self).reconnect(node_id));
+		}
+	}
+
+	/// Gets the address at which we can reach a peer which connected to us from `remote_address`,
+	/// preferring one it announced with the same IP over guessing it listens on the default port.
+	fn inbound_peer_address(&self, node_id: &PublicKey, remote_address: NetAddress) -> Option<NetAddress> {
+		let announced_addresses = self.network_graph.as_ref()
+			.and_then(|network_graph| network_graph.read_only().get_addresses(node_id))
+			.unwrap_or_default();
+		match remote_address {
+			NetAddress::IPv4 { addr, .. } => Some(announced_addresses.into_iter()
+				.find(|announced| match announced { NetAddress::IPv4 { addr: announced_addr, .. } => *announced_addr == addr, _ => false })
+				.unwrap_or(NetAddress::IPv4 { addr, port: DEFAULT_LIGHTNING_PORT })),
+			NetAddress::IPv6 { addr, .. } => Some(announced_addresses.into_iter()
+				.find(|announced| match announced { NetAddress::IPv6 { addr: announced_addr, .. } => *announced_addr == addr, _ => false })
+				.unwrap_or(NetAddress::IPv6 { addr, port: DEFAULT_LIGHTNING_PORT })),
+			_ => None,
+		}
+	}
+
+	/// Keeps us connected to our persistent peers, checking our connections every
+	/// [`PeerConnectionManagerConfig::check_interval`] and persisting the address book whenever it
+	/// changes. Never returns, so should be spawned and aborted on shutdown.
+	pub async fn run<K: Deref>(self: Arc<Self>, persister: K) where K::Target: KVStorePersister {
+		loop {
+			self.process_connections();
+			if let Err(e) = self.persist_address_book(&persister) {
+				log_error!(self.logger, "Failed to persist peer address book: {}", e);
+			}
+			tokio::time::sleep(self.config.check_interval).await;
+		}
+	}
+
+	async fn reconnect(self: Arc<Self>, node_id: PublicKey) {
+		let mut addresses = self.state.lock().unwrap().address_book.addresses(&node_id);
+		if let Some(network_graph) = &self.network_graph {
+			for address in network_graph.read_only().get_addresses(&node_id).unwrap_or_default() {
+				if !addresses.contains(&address) {
+					addresses.push(address);
+				}
+			}
+		}
+
+		let mut reached = false;
+		for address in addresses {
+			log_trace!(self.logger, "Attempting to reconnect to peer {} at {:?}", node_id, address);
+			if self.connect(node_id, address).await {
+				reached = true;
+				break;
+			}
+		}
+
+		let mut state = self.state.lock().unwrap();
+		state.connecting_peers.remove(&node_id);
+		let attempts = state.backoff.get(&node_id).map_or(0, |backoff| backoff.attempts) + 1;
+		let retry_in = self.jittered_backoff(attempts);
+		// Even once we've reached the peer, we may be disconnected again before noticing, so we
+		// keep backing off until the peer shows up as connected in `process_connections`, which
+		// also records the address we reached it at.
+		state.backoff.insert(node_id, Backoff { attempts, next_attempt: Instant::now() + retry_in });
+		if !reached {
+			log_trace!(self.logger, "Failed to reconnect to peer {}, retrying in {:?}", node_id, retry_in);
+			state.pending_events.push(PeerConnectionEvent::ReconnectFailed { node_id, retry_in });
+		}
+	}
+
+	/// Opens a connection to the given peer at `address`, returning whether we reached it, i.e.
+	/// completed the handshake rather than merely connected.
+	async fn connect(&self, node_id: PublicKey, address: NetAddress) -> bool {
+		let (handshake_signal, handshake_completed) = HandshakeSignal::new(node_id);
+		let connection = match self.open_connection(node_id, address, handshake_signal).await {
+			Some(connection) => connection,
+			None => return false,
+		};
+		tokio::pin!(connection);
+		tokio::select! {
+			// The connection was closed before the handshake completed.
+			_ = &mut connection => false,
+			res = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake_completed) => {
+				if res.is_err() {
+					// Don't leave the connection lingering, though if the peer didn't even complete
+					// the noise handshake it will only be closed on a later
+					// `PeerManager::timer_tick_occurred`.
+					log_trace!(self.logger, "Timed out waiting for the handshake with peer {}", node_id);
+					self.peer_manager.disconnect_by_node_id(node_id, false);
+				}
+				// The signal is only dropped without being sent if the connection closed.
+				res.map_or(false, |signal| signal.is_ok())
+			},
+		}
+	}
+
+	/// Opens a connection to the given peer at `address`, returning a future which completes once
+	/// we're disconnected if we reached the address.
+	async fn open_connection(&self, node_id: PublicKey, address: NetAddress, handshake_signal: HandshakeSignal) -> Option<Connection> {
+		let peer_manager = Arc::clone(&self.peer_manager);
+		if let Some(proxy) = &self.config.proxy {
+			let credentials = ProxyCredentials { username: node_id.to_string(), password: "lightning".to_string() };
+			return crate::connect_outbound_via_proxy_with_signal(peer_manager, node_id, address, proxy, Some(&credentials), Some(handshake_signal)).await
+				.map(|connection| Box::pin(connection) as Connection);
+		}
+		let socket_addr = match address {
+			NetAddress::IPv4 { addr, port } => SocketAddr::new(IpAddr::V4(Ipv4Addr::from(addr)), port),
+			NetAddress::IPv6 { addr, port } => SocketAddr::new(IpAddr::V6(Ipv6Addr::from(addr)), port),
+			NetAddress::Hostname { hostname, port } => {
+				match tokio::net::lookup_host((hostname.as_str(), port)).await.ok().and_then(|mut addrs| addrs.next()) {
+					Some(socket_addr) => socket_addr,
+					None => return None,
+				}
+			},
+			// Onion addresses can only be reached through a proxy.
+			NetAddress::OnionV2(_) | NetAddress::OnionV3 { .. } => return None,
+		};
+		crate::connect_outbound_with_signal(peer_manager, node_id, socket_addr, Some(handshake_signal)).await
+			.map(|connection| Box::pin(connection) as Connection)
+	}
+
+	fn jittered_backoff(&self, attempts: u32) -> Duration {
+		let backoff = self.config.initial_backoff.checked_mul(1 << (attempts - 1).min(16))
+			.map_or(self.config.max_backoff, |backoff| backoff.min(self.config.max_backoff));
+		let half = backoff / 2;
+		let mut engine = Sha256::engine();
+		engine.input(&self.jitter_seed);
+		engine.input(&self.jitter_counter.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+		let mut random_bytes = [0; 8];
+		random_bytes.copy_from_slice(&Sha256::from_engine(engine)[..8]);
+		let random = u64::from_be_bytes(random_bytes);
+		half + Duration::from_nanos(random % (half.as_nanos() as u64 + 1))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use lightning::util::test_utils::TestLogger;
+
+	use bitcoin::secp256k1::{Secp256k1, SecretKey};
+	use lightning::chain::keysinterface::KeysManager;
+	use lightning::ln::peer_handler::{ErroringMessageHandler, IgnoringMessageHandler, MessageHandler, PeerManager};
+	use lightning::ln::peer_policy::AllowAllPeerPolicy;
+	use lightning::util::ser::Writeable;
+
+	type TestPeerManager = PeerManager<SocketDescriptor, Arc<ErroringMessageHandler>, Arc<IgnoringMessageHandler>, Arc<TestLogger>, Arc<IgnoringMessageHandler>>;
+	type TestConnectionManager = PeerConnectionManager<Arc<ErroringMessageHandler>, Arc<IgnoringMessageHandler>, Arc<TestLogger>, Arc<IgnoringMessageHandler>, AllowAllPeerPolicy, Arc<NetworkGraph<Arc<TestLogger>>>>;
+
+	fn make_peer_manager(seed: u8) -> (Arc<TestPeerManager>, PublicKey) {
+		let secp_ctx = Secp256k1::new();
+		let key = SecretKey::from_slice(&[seed; 32]).unwrap();
+		let peer_manager = Arc::new(PeerManager::new(MessageHandler {
+			chan_handler: Arc::new(ErroringMessageHandler::new()),
+			route_handler: Arc::new(IgnoringMessageHandler{}),
+		}, key, &[seed; 32], Arc::new(TestLogger::new()), Arc::new(IgnoringMessageHandler{})));
+		(peer_manager, PublicKey::from_secret_key(&secp_ctx, &key))
+	}
+
+	fn make_connection_manager(peer_manager: Arc<TestPeerManager>, config: PeerConnectionManagerConfig) -> TestConnectionManager {
+		let keys_manager = KeysManager::new(&[42; 32], 42, 42);
+		PeerConnectionManager::new(peer_manager, None, PeerAddressBook::new(), &keys_manager, Arc::new(TestLogger::new()), config)
+	}
+
+	struct TestPersister {
+		persisted: Mutex<Vec<Vec<u8>>>,
+	}
+	impl KVStorePersister for TestPersister {
+		fn persist<W: Writeable>(&self, key: &str, object: &W) -> io::Result<()> {
+			assert_eq!(key, PEER_ADDRESS_BOOK_PERSISTENCE_KEY);
+			self.persisted.lock().unwrap().push(object.encode());
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn address_book_serialization() {
+		let (_, node_a) = make_peer_manager(1);
+		let (_, node_b) = make_peer_manager(2);
+		let mut address_book = PeerAddressBook::new();
+		assert!(address_book.set_persistent(node_a, true));
+		assert!(address_book.add_address(node_a, NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 }));
+		assert!(address_book.add_address(node_a, NetAddress::IPv6 { addr: [1; 16], port: 9736 }));
+		assert!(!address_book.add_address(node_a, NetAddress::IPv6 { addr: [1; 16], port: 9736 }));
+		assert!(address_book.add_address(node_b, NetAddress::OnionV3 { ed25519_pubkey: [42; 32], checksum: 1, version: 3, port: 9735 }));
+
+		assert_eq!(address_book.persistent_peers(), vec![node_a]);
+		assert_eq!(address_book.addresses(&node_a), vec![NetAddress::IPv6 { addr: [1; 16], port: 9736 }, NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 }]);
+		let read_address_book: PeerAddressBook = Readable::read(&mut io::Cursor::new(address_book.encode())).unwrap();
+		assert_eq!(read_address_book, address_book);
+
+		// Peers we no longer want to stay connected to are forgotten once we know no address for
+		// them.
+		let (_, node_c) = make_peer_manager(3);
+		assert!(address_book.set_persistent(node_c, true));
+		assert!(address_book.set_persistent(node_c, false));
+		assert!(address_book.addresses(&node_c).is_empty());
+		assert_eq!(address_book, read_address_book);
+	}
+
+	#[tokio::test]
+	async fn backoff_grows_with_jitter() {
+		let (peer_manager, _) = make_peer_manager(1);
+		let config = PeerConnectionManagerConfig {
+			initial_backoff: Duration::from_secs(1), max_backoff: Duration::from_secs(60), ..Default::default()
+		};
+		let manager = make_connection_manager(peer_manager, config);
+		for (attempts, expected) in [(1, 1), (2, 2), (3, 4), (7, 60), (100, 60)].iter() {
+			let backoff = manager.jittered_backoff(*attempts);
+			assert!(backoff >= Duration::from_secs(*expected) / 2);
+			assert!(backoff <= Duration::from_secs(*expected));
+		}
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn reconnects_to_persistent_peers() {
+		let (a_manager, _) = make_peer_manager(1);
+		let (b_manager, b_pub) = make_peer_manager(2);
+
+		// Node B accepts any number of inbound connections.
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let b_port = listener.local_addr().unwrap().port();
+		let b_manager_ref = Arc::clone(&b_manager);
+		std::thread::spawn(move || {
+			for stream in listener.incoming() {
+				let b_manager = Arc::clone(&b_manager_ref);
+				let stream = stream.unwrap();
+				tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async move {
+					crate::setup_inbound(b_manager, stream).await;
+				});
+			}
+		});
+
+		let config = PeerConnectionManagerConfig {
+			initial_backoff: Duration::from_millis(10), check_interval: Duration::from_millis(10), ..Default::default()
+		};
+		let persister = Arc::new(TestPersister { persisted: Mutex::new(Vec::new()) });
+		let manager = Arc::new(make_connection_manager(Arc::clone(&a_manager), config));
+		// The first address accepts connections but immediately closes them, which doesn't count as
+		// reaching the peer, and the second is unreachable, so we fall back to the third.
+		let closing_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let closing_port = closing_listener.local_addr().unwrap().port();
+		std::thread::spawn(move || {
+			for stream in closing_listener.incoming() {
+				drop(stream);
+			}
+		});
+		let closed_port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+		manager.add_peer(b_pub, Some(NetAddress::IPv4 { addr: [127, 0, 0, 1], port: b_port }));
+		manager.add_peer(b_pub, Some(NetAddress::IPv4 { addr: [127, 0, 0, 1], port: closed_port }));
+		manager.add_peer(b_pub, Some(NetAddress::IPv4 { addr: [127, 0, 0, 1], port: closing_port }));
+		let run_handle = tokio::spawn(Arc::clone(&manager).run(Arc::clone(&persister)));
+
+		let wait_for_event = |expected: PeerConnectionEvent| {
+			let manager = Arc::clone(&manager);
+			async move {
+				loop {
+					if manager.get_and_clear_pending_events().contains(&expected) { break; }
+					tokio::time::sleep(Duration::from_millis(10)).await;
+				}
+			}
+		};
+		tokio::time::timeout(Duration::from_secs(10), wait_for_event(PeerConnectionEvent::Connected { node_id: b_pub })).await.unwrap();
+
+		// Once we've reached the peer, the working address is the first we try in the future.
+		assert_eq!(manager.address_book().addresses(&b_pub)[0], NetAddress::IPv4 { addr: [127, 0, 0, 1], port: b_port });
+		tokio::time::sleep(Duration::from_millis(50)).await;
+		let last_persisted = persister.persisted.lock().unwrap().last().unwrap().clone();
+		assert_eq!(last_persisted, manager.address_book().encode());
+
+		// If the peer drops us, we reconnect automatically.
+		b_manager.disconnect_all_peers();
+		tokio::time::timeout(Duration::from_secs(10), wait_for_event(PeerConnectionEvent::Disconnected { node_id: b_pub })).await.unwrap();
+		tokio::time::timeout(Duration::from_secs(10), wait_for_event(PeerConnectionEvent::Connected { node_id: b_pub })).await.unwrap();
+
+		// Once removed, we no longer reconnect.
+		manager.remove_peer(&b_pub);
+		b_manager.disconnect_all_peers();
+		tokio::time::timeout(Duration::from_secs(10), wait_for_event(PeerConnectionEvent::Disconnected { node_id: b_pub })).await.unwrap();
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		assert!(a_manager.get_peer_node_ids().is_empty());
+
+		run_handle.abort();
+	}
+	#[tokio::test(flavor = "multi_thread")]
+	async fn learns_addresses_of_inbound_peers() {
+		let (a_manager, _) = make_peer_manager(1);
+		let (b_manager, b_pub) = make_peer_manager(2);
+		let (_, a_pub) = make_peer_manager(1);
+
+		// Node B's connection manager learns how to reach node A once it connected to us. As node A
+		// didn't announce any address, we guess it listens on the default port.
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let b_addr = listener.local_addr().unwrap();
+		let manager = Arc::new(make_connection_manager(Arc::clone(&b_manager), PeerConnectionManagerConfig::default()));
+		let b_accept = tokio::task::spawn_blocking(move || listener.accept().unwrap().0);
+		let fut_a = crate::connect_outbound(Arc::clone(&a_manager), b_pub, b_addr).await.unwrap();
+		let fut_b = crate::setup_inbound(Arc::clone(&b_manager), b_accept.await.unwrap());
+		tokio::time::timeout(Duration::from_secs(10), async {
+			while b_manager.get_peer_node_ids().is_empty() {
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+		}).await.unwrap();
+
+		manager.process_connections();
+		assert_eq!(manager.get_and_clear_pending_events(), vec![PeerConnectionEvent::Connected { node_id: a_pub }]);
+		assert_eq!(manager.address_book().addresses(&a_pub), vec![NetAddress::IPv4 { addr: [127, 0, 0, 1], port: DEFAULT_LIGHTNING_PORT }]);
+		// Inbound peers aren't reconnected to unless we want to stay connected to them.
+		assert!(manager.address_book().persistent_peers().is_empty());
+
+		a_manager.disconnect_all_peers();
+		fut_a.await;
+		fut_b.await;
+	}
+}
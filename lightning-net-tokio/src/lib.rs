@@ -17,8 +17,12 @@
 //! The PeerHandler, due to the fire-and-forget nature of this logic, must be an Arc, and must use
 //! the SocketDescriptor provided here as the PeerHandler's SocketDescriptor.
 //!
-//! Three methods are exposed to register a new connection for handling in tokio::spawn calls; see
-//! their individual docs for details.
+//! Four methods are exposed to register a new connection for handling in tokio::spawn calls; see
+//! their individual docs for details. To accept all connections made to a listening socket, see
+//! [`accept_inbound_connections`], and for keeping connections to specific peers alive, see the
+//! [`connection_manager`] module. Peers which cannot make raw TCP connections, such as wallets
+//! running in a browser, may instead connect over WebSockets as provided by the [`websocket`]
+//! module (behind the `websocket` feature), sharing the same PeerManager and SocketDescriptor.
 //!
 //! # Example
 //! ```
@@ -71,16 +75,24 @@
 
 #![cfg_attr(docsrs, feature(doc_auto_cfg))]
 
+#[macro_use]
+extern crate lightning;
+
+pub mod connection_manager;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
 use bitcoin::secp256k1::PublicKey;
 
 use tokio::net::TcpStream;
 use tokio::{io, time};
-use tokio::sync::mpsc;
-use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use lightning::ln::peer_handler;
 use lightning::ln::peer_handler::SocketDescriptor as LnSocketTrait;
 use lightning::ln::peer_handler::CustomMessageHandler;
+use lightning::ln::peer_policy::PeerPolicy;
 use lightning::ln::msgs::{ChannelMessageHandler, RoutingMessageHandler, NetAddress};
 use lightning::util::logger::Logger;
 
@@ -95,11 +107,22 @@ use std::hash::Hash;
 
 static ID_COUNTER: AtomicU64 = AtomicU64::new(0);
 
+/// A byte stream over which we exchange the Noise-encrypted message stream with a peer, e.g. a
+/// TCP socket or a WebSocket connection.
+trait Transport: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Transport for T {}
+type BoxedTransport = Box<dyn Transport>;
+
+fn tcp_transport(stream: StdTcpStream) -> BoxedTransport {
+	stream.set_nonblocking(true).unwrap();
+	Box::new(TcpStream::from_std(stream).unwrap())
+}
+
 /// Connection contains all our internal state for a connection - we hold a reference to the
 /// Connection object (in an Arc<Mutex<>>) in each SocketDescriptor we create as well as in the
 /// read future (which is returned by schedule_read).
 struct Connection {
-	writer: Option<io::WriteHalf<TcpStream>>,
+	writer: Option<io::WriteHalf<BoxedTransport>>,
 	// Because our PeerManager is templated by user-provided types, and we can't (as far as I can
 	// tell) have a const RawWakerVTable built out of templated functions, we need some indirection
 	// between being woken up with write-ready and calling PeerManager::write_buffer_space_avail.
@@ -121,15 +144,17 @@ struct Connection {
 	id: u64,
 }
 impl Connection {
-	async fn poll_event_process<CMH, RMH, L, UMH>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, CMH, RMH, L, UMH>>, mut event_receiver: mpsc::Receiver<()>) where
+	async fn poll_event_process<CMH, RMH, L, UMH, PP>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, CMH, RMH, L, UMH, PP>>, mut event_receiver: mpsc::Receiver<()>) where
 			CMH: Deref + 'static + Send + Sync,
 			RMH: Deref + 'static + Send + Sync,
 			L: Deref + 'static + Send + Sync,
 			UMH: Deref + 'static + Send + Sync,
+			PP: Deref + 'static + Send + Sync,
 			CMH::Target: ChannelMessageHandler + Send + Sync,
 			RMH::Target: RoutingMessageHandler + Send + Sync,
 			L::Target: Logger + Send + Sync,
 			UMH::Target: CustomMessageHandler + Send + Sync,
+			PP::Target: PeerPolicy + Send + Sync,
     {
 		loop {
 			if event_receiver.recv().await.is_none() {
@@ -139,15 +164,17 @@ impl Connection {
 		}
 	}
 
-	async fn schedule_read<CMH, RMH, L, UMH>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, CMH, RMH, L, UMH>>, us: Arc<Mutex<Self>>, mut reader: io::ReadHalf<TcpStream>, mut read_wake_receiver: mpsc::Receiver<()>, mut write_avail_receiver: mpsc::Receiver<()>) where
+	async fn schedule_read<CMH, RMH, L, UMH, PP>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, CMH, RMH, L, UMH, PP>>, us: Arc<Mutex<Self>>, mut reader: io::ReadHalf<BoxedTransport>, mut read_wake_receiver: mpsc::Receiver<()>, mut write_avail_receiver: mpsc::Receiver<()>, mut handshake_signal: Option<HandshakeSignal>) where
 			CMH: Deref + 'static + Send + Sync,
 			RMH: Deref + 'static + Send + Sync,
 			L: Deref + 'static + Send + Sync,
 			UMH: Deref + 'static + Send + Sync,
+			PP: Deref + 'static + Send + Sync,
 			CMH::Target: ChannelMessageHandler + 'static + Send + Sync,
 			RMH::Target: RoutingMessageHandler + 'static + Send + Sync,
 			L::Target: Logger + 'static + Send + Sync,
 			UMH::Target: CustomMessageHandler + 'static + Send + Sync,
+			PP::Target: PeerPolicy + 'static + Send + Sync,
         {
 		// Create a waker to wake up poll_event_process, above
 		let (event_waker, event_receiver) = mpsc::channel(1);
@@ -181,6 +208,7 @@ impl Connection {
 			tokio::select! {
 				v = write_avail_receiver.recv() => {
 					assert!(v.is_some()); // We can't have dropped the sending end, its in the us Arc!
+					us.lock().unwrap().flush_writer();
 					if let Err(_) = peer_manager.write_buffer_space_avail(&mut our_descriptor) {
 						break Disconnect::CloseConnection;
 					}
@@ -199,6 +227,14 @@ impl Connection {
 							},
 							Err(_) => break Disconnect::CloseConnection,
 						}
+						drop(us_lock);
+						// The handshake can only complete upon reading the peer's Init message, so
+						// we only need to check for it here.
+						let handshake_completed = handshake_signal.as_ref()
+							.map_or(false, |signal| peer_manager.get_peer_node_ids().contains(&signal.their_node_id));
+						if handshake_completed {
+							let _ = handshake_signal.take().unwrap().sender.send(());
+						}
 					},
 					Err(_) => break Disconnect::PeerDisconnected,
 				},
@@ -223,7 +259,19 @@ impl Connection {
 		}
 	}
 
-	fn new(stream: StdTcpStream) -> (io::ReadHalf<TcpStream>, mpsc::Receiver<()>, mpsc::Receiver<()>, Arc<Mutex<Self>>) {
+	/// Writes out any bytes which our transport accepted in an earlier `send_data` call but buffered
+	/// internally, as a WebSocket connection may do with a partially-written frame. Should the
+	/// transport not be able to make progress, we'll be woken via `write_avail` to try again.
+	fn flush_writer(&mut self) {
+		if let Some(writer) = self.writer.as_mut() {
+			let waker = unsafe { task::Waker::from_raw(write_avail_to_waker(&self.write_avail)) };
+			let mut ctx = task::Context::from_waker(&waker);
+			// Any error will be noticed by the next send_data or read, so we can ignore it here.
+			let _ = std::pin::Pin::new(writer).poll_flush(&mut ctx);
+		}
+	}
+
+	fn new(stream: BoxedTransport) -> (io::ReadHalf<BoxedTransport>, mpsc::Receiver<()>, mpsc::Receiver<()>, Arc<Mutex<Self>>) {
 		// We only ever need a channel of depth 1 here: if we returned a non-full write to the
 		// PeerManager, we will eventually get notified that there is room in the socket to write
 		// new bytes, which will generate an event. That event will be popped off the queue before
@@ -234,8 +282,7 @@ impl Connection {
 		// we shove a value into the channel which comes after we've reset the read_paused bool to
 		// false.
 		let (read_waker, read_receiver) = mpsc::channel(1);
-		stream.set_nonblocking(true).unwrap();
-		let (reader, writer) = io::split(TcpStream::from_std(stream).unwrap());
+		let (reader, writer) = io::split(stream);
 
 		(reader, write_receiver, read_receiver,
 		Arc::new(Mutex::new(Self {
@@ -247,16 +294,19 @@ impl Connection {
 }
 
 fn get_addr_from_stream(stream: &StdTcpStream) -> Option<NetAddress> {
-	match stream.peer_addr() {
-		Ok(SocketAddr::V4(sockaddr)) => Some(NetAddress::IPv4 {
+	stream.peer_addr().ok().map(net_address_from_socket_addr)
+}
+
+fn net_address_from_socket_addr(addr: SocketAddr) -> NetAddress {
+	match addr {
+		SocketAddr::V4(sockaddr) => NetAddress::IPv4 {
 			addr: sockaddr.ip().octets(),
 			port: sockaddr.port(),
-		}),
-		Ok(SocketAddr::V6(sockaddr)) => Some(NetAddress::IPv6 {
+		},
+		SocketAddr::V6(sockaddr) => NetAddress::IPv6 {
 			addr: sockaddr.ip().octets(),
 			port: sockaddr.port(),
-		}),
-		Err(_) => None,
+		},
 	}
 }
 
@@ -266,23 +316,40 @@ fn get_addr_from_stream(stream: &StdTcpStream) -> Option<NetAddress> {
 /// The returned future will complete when the peer is disconnected and associated handling
 /// futures are freed, though, because all processing futures are spawned with tokio::spawn, you do
 /// not need to poll the provided future in order to make progress.
-pub fn setup_inbound<CMH, RMH, L, UMH>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, CMH, RMH, L, UMH>>, stream: StdTcpStream) -> impl std::future::Future<Output=()> where
+pub fn setup_inbound<CMH, RMH, L, UMH, PP>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, CMH, RMH, L, UMH, PP>>, stream: StdTcpStream) -> impl std::future::Future<Output=()> where
 		CMH: Deref + 'static + Send + Sync,
 		RMH: Deref + 'static + Send + Sync,
 		L: Deref + 'static + Send + Sync,
 		UMH: Deref + 'static + Send + Sync,
+		PP: Deref + 'static + Send + Sync,
 		CMH::Target: ChannelMessageHandler + Send + Sync,
 		RMH::Target: RoutingMessageHandler + Send + Sync,
 		L::Target: Logger + Send + Sync,
 		UMH::Target: CustomMessageHandler + Send + Sync,
+		PP::Target: PeerPolicy + Send + Sync,
 {
 	let remote_addr = get_addr_from_stream(&stream);
+	setup_inbound_transport(peer_manager, tcp_transport(stream), remote_addr)
+}
+
+fn setup_inbound_transport<CMH, RMH, L, UMH, PP>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, CMH, RMH, L, UMH, PP>>, stream: BoxedTransport, remote_addr: Option<NetAddress>) -> impl std::future::Future<Output=()> where
+		CMH: Deref + 'static + Send + Sync,
+		RMH: Deref + 'static + Send + Sync,
+		L: Deref + 'static + Send + Sync,
+		UMH: Deref + 'static + Send + Sync,
+		PP: Deref + 'static + Send + Sync,
+		CMH::Target: ChannelMessageHandler + Send + Sync,
+		RMH::Target: RoutingMessageHandler + Send + Sync,
+		L::Target: Logger + Send + Sync,
+		UMH::Target: CustomMessageHandler + Send + Sync,
+		PP::Target: PeerPolicy + Send + Sync,
+{
 	let (reader, write_receiver, read_receiver, us) = Connection::new(stream);
 	#[cfg(debug_assertions)]
 	let last_us = Arc::clone(&us);
 
 	let handle_opt = if let Ok(_) = peer_manager.new_inbound_connection(SocketDescriptor::new(us.clone()), remote_addr) {
-		Some(tokio::spawn(Connection::schedule_read(peer_manager, us, reader, read_receiver, write_receiver)))
+		Some(tokio::spawn(Connection::schedule_read(peer_manager, us, reader, read_receiver, write_receiver, None)))
 	} else {
 		// Note that we will skip socket_disconnected here, in accordance with the PeerManager
 		// requirements.
@@ -306,6 +373,43 @@ pub fn setup_inbound<CMH, RMH, L, UMH>(peer_manager: Arc<peer_handler::PeerManag
 	}
 }
 
+/// Accepts inbound connections on the given listener, handing each to [`setup_inbound`] (which
+/// schedules their processing with tokio::spawn). The returned future never completes, so should
+/// itself be spawned.
+///
+/// Connections which would exceed the [`PeerConnectionLimits`] the `PeerManager` was constructed
+/// with are refused by [`PeerManager::new_inbound_connection`] and closed immediately, while
+/// connections which don't complete their handshake in time are closed on a later
+/// [`PeerManager::timer_tick_occurred`] call.
+///
+/// If accepting a connection fails, e.g. because we've run out of file descriptors, we wait
+/// briefly before trying again.
+///
+/// [`PeerConnectionLimits`]: lightning::ln::peer_handler::PeerConnectionLimits
+/// [`PeerManager::new_inbound_connection`]: lightning::ln::peer_handler::PeerManager::new_inbound_connection
+/// [`PeerManager::timer_tick_occurred`]: lightning::ln::peer_handler::PeerManager::timer_tick_occurred
+pub async fn accept_inbound_connections<CMH, RMH, L, UMH, PP>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, CMH, RMH, L, UMH, PP>>, listener: tokio::net::TcpListener) where
+		CMH: Deref + 'static + Send + Sync,
+		RMH: Deref + 'static + Send + Sync,
+		L: Deref + 'static + Send + Sync,
+		UMH: Deref + 'static + Send + Sync,
+		PP: Deref + 'static + Send + Sync,
+		CMH::Target: ChannelMessageHandler + Send + Sync,
+		RMH::Target: RoutingMessageHandler + Send + Sync,
+		L::Target: Logger + Send + Sync,
+		UMH::Target: CustomMessageHandler + Send + Sync,
+		PP::Target: PeerPolicy + Send + Sync,
+{
+	loop {
+		match listener.accept().await.and_then(|(stream, _)| stream.into_std()) {
+			Ok(stream) => {
+				tokio::spawn(setup_inbound(Arc::clone(&peer_manager), stream));
+			},
+			Err(_) => time::sleep(Duration::from_millis(100)).await,
+		}
+	}
+}
+
 /// Process incoming messages and feed outgoing messages on the provided socket generated by
 /// making an outbound connection which is expected to be accepted by a peer with the given
 /// public key. The relevant processing is set to run free (via tokio::spawn).
@@ -313,17 +417,52 @@ pub fn setup_inbound<CMH, RMH, L, UMH>(peer_manager: Arc<peer_handler::PeerManag
 /// The returned future will complete when the peer is disconnected and associated handling
 /// futures are freed, though, because all processing futures are spawned with tokio::spawn, you do
 /// not need to poll the provided future in order to make progress.
-pub fn setup_outbound<CMH, RMH, L, UMH>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, CMH, RMH, L, UMH>>, their_node_id: PublicKey, stream: StdTcpStream) -> impl std::future::Future<Output=()> where
+pub fn setup_outbound<CMH, RMH, L, UMH, PP>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, CMH, RMH, L, UMH, PP>>, their_node_id: PublicKey, stream: StdTcpStream) -> impl std::future::Future<Output=()> where
 		CMH: Deref + 'static + Send + Sync,
 		RMH: Deref + 'static + Send + Sync,
 		L: Deref + 'static + Send + Sync,
 		UMH: Deref + 'static + Send + Sync,
+		PP: Deref + 'static + Send + Sync,
 		CMH::Target: ChannelMessageHandler + Send + Sync,
 		RMH::Target: RoutingMessageHandler + Send + Sync,
 		L::Target: Logger + Send + Sync,
 		UMH::Target: CustomMessageHandler + Send + Sync,
+		PP::Target: PeerPolicy + Send + Sync,
 {
 	let remote_addr = get_addr_from_stream(&stream);
+	setup_outbound_transport(peer_manager, their_node_id, tcp_transport(stream), remote_addr, None)
+}
+
+/// Signals the completion of the handshake with a peer we connected to, i.e. once the peer shows
+/// up in [`PeerManager::get_peer_node_ids`].
+///
+/// [`PeerManager::get_peer_node_ids`]: lightning::ln::peer_handler::PeerManager::get_peer_node_ids
+pub(crate) struct HandshakeSignal {
+	their_node_id: PublicKey,
+	sender: oneshot::Sender<()>,
+}
+
+impl HandshakeSignal {
+	/// Creates a signal for the handshake with the given peer, along with the receiver which
+	/// completes once the handshake has.
+	pub(crate) fn new(their_node_id: PublicKey) -> (Self, oneshot::Receiver<()>) {
+		let (sender, receiver) = oneshot::channel();
+		(Self { their_node_id, sender }, receiver)
+	}
+}
+
+fn setup_outbound_transport<CMH, RMH, L, UMH, PP>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, CMH, RMH, L, UMH, PP>>, their_node_id: PublicKey, stream: BoxedTransport, remote_addr: Option<NetAddress>, handshake_signal: Option<HandshakeSignal>) -> impl std::future::Future<Output=()> where
+		CMH: Deref + 'static + Send + Sync,
+		RMH: Deref + 'static + Send + Sync,
+		L: Deref + 'static + Send + Sync,
+		UMH: Deref + 'static + Send + Sync,
+		PP: Deref + 'static + Send + Sync,
+		CMH::Target: ChannelMessageHandler + Send + Sync,
+		RMH::Target: RoutingMessageHandler + Send + Sync,
+		L::Target: Logger + Send + Sync,
+		UMH::Target: CustomMessageHandler + Send + Sync,
+		PP::Target: PeerPolicy + Send + Sync,
+{
 	let (reader, mut write_receiver, read_receiver, us) = Connection::new(stream);
 	#[cfg(debug_assertions)]
 	let last_us = Arc::clone(&us);
@@ -352,7 +491,7 @@ pub fn setup_outbound<CMH, RMH, L, UMH>(peer_manager: Arc<peer_handler::PeerMana
 					}
 				}
 			}).await {
-				Connection::schedule_read(peer_manager, us, reader, read_receiver, write_receiver).await;
+				Connection::schedule_read(peer_manager, us, reader, read_receiver, write_receiver, handshake_signal).await;
 			}
 		}))
 	} else {
@@ -389,21 +528,223 @@ pub fn setup_outbound<CMH, RMH, L, UMH>(peer_manager: Arc<peer_handler::PeerMana
 /// disconnected and associated handling futures are freed, though, because all processing in said
 /// futures are spawned with tokio::spawn, you do not need to poll the second future in order to
 /// make progress.
-pub async fn connect_outbound<CMH, RMH, L, UMH>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, CMH, RMH, L, UMH>>, their_node_id: PublicKey, addr: SocketAddr) -> Option<impl std::future::Future<Output=()>> where
+pub async fn connect_outbound<CMH, RMH, L, UMH, PP>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, CMH, RMH, L, UMH, PP>>, their_node_id: PublicKey, addr: SocketAddr) -> Option<impl std::future::Future<Output=()>> where
+		CMH: Deref + 'static + Send + Sync,
+		RMH: Deref + 'static + Send + Sync,
+		L: Deref + 'static + Send + Sync,
+		UMH: Deref + 'static + Send + Sync,
+		PP: Deref + 'static + Send + Sync,
+		CMH::Target: ChannelMessageHandler + Send + Sync,
+		RMH::Target: RoutingMessageHandler + Send + Sync,
+		L::Target: Logger + Send + Sync,
+		UMH::Target: CustomMessageHandler + Send + Sync,
+		PP::Target: PeerPolicy + Send + Sync,
+{
+	connect_outbound_with_signal(peer_manager, their_node_id, addr, None).await
+}
+
+/// [`connect_outbound`], additionally signalling the completion of the handshake, if requested.
+pub(crate) async fn connect_outbound_with_signal<CMH, RMH, L, UMH, PP>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, CMH, RMH, L, UMH, PP>>, their_node_id: PublicKey, addr: SocketAddr, handshake_signal: Option<HandshakeSignal>) -> Option<impl std::future::Future<Output=()>> where
 		CMH: Deref + 'static + Send + Sync,
 		RMH: Deref + 'static + Send + Sync,
 		L: Deref + 'static + Send + Sync,
 		UMH: Deref + 'static + Send + Sync,
+		PP: Deref + 'static + Send + Sync,
 		CMH::Target: ChannelMessageHandler + Send + Sync,
 		RMH::Target: RoutingMessageHandler + Send + Sync,
 		L::Target: Logger + Send + Sync,
 		UMH::Target: CustomMessageHandler + Send + Sync,
+		PP::Target: PeerPolicy + Send + Sync,
 {
 	if let Ok(Ok(stream)) = time::timeout(Duration::from_secs(10), async { TcpStream::connect(&addr).await.map(|s| s.into_std().unwrap()) }).await {
-		Some(setup_outbound(peer_manager, their_node_id, stream))
+		let remote_addr = get_addr_from_stream(&stream);
+		Some(setup_outbound_transport(peer_manager, their_node_id, tcp_transport(stream), remote_addr, handshake_signal))
+	} else { None }
+}
+
+/// How long we wait for a proxied connection, including the SOCKS5 handshake, to be established.
+/// This is longer than for direct connections as the proxy may first need to build a Tor circuit.
+const PROXY_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A SOCKS5 proxy, such as a Tor daemon, through which [`connect_outbound_via_proxy`] makes
+/// outbound connections.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Socks5Proxy {
+	/// The address the proxy listens on, e.g. `127.0.0.1:9050` for a default Tor daemon.
+	pub addr: SocketAddr,
+	/// If set, only [`NetAddress::OnionV3`] peers are dialed and attempts to reach a peer at an IP
+	/// address or DNS hostname are refused, ensuring we never connect via a Tor exit to the
+	/// clearnet.
+	pub tor_only: bool,
+}
+
+/// Username/password credentials presented to a SOCKS5 proxy for a single connection.
+///
+/// Tor routes streams opened with differing credentials over separate circuits (see its
+/// `IsolateSOCKSAuth` option), so using distinct credentials per peer avoids our connections to
+/// different peers being linkable by sharing a circuit.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProxyCredentials {
+	/// The username, which must not exceed 255 bytes.
+	pub username: String,
+	/// The password, which must not exceed 255 bytes.
+	pub password: String,
+}
+
+/// Process incoming messages and feed outgoing messages on a new connection made through the given
+/// SOCKS5 proxy to a peer at the given [`NetAddress`], which is expected to be accepted by a peer
+/// with the given public key (by scheduling futures with tokio::spawn).
+///
+/// Unlike [`connect_outbound`], this can reach peers at [`NetAddress::OnionV3`] and
+/// [`NetAddress::Hostname`] addresses, which are resolved by the proxy. If `credentials` are
+/// provided they are used to authenticate to the proxy, allowing for per-connection stream
+/// isolation.
+///
+/// Returns `None` if the connection could not be established, including if the address is of a
+/// type we cannot dial ([`NetAddress::OnionV2`]) or is not an onion address while
+/// [`Socks5Proxy::tor_only`] is set. Otherwise the returned future behaves as the one returned by
+/// [`connect_outbound`].
+pub async fn connect_outbound_via_proxy<CMH, RMH, L, UMH, PP>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, CMH, RMH, L, UMH, PP>>, their_node_id: PublicKey, addr: NetAddress, proxy: &Socks5Proxy, credentials: Option<&ProxyCredentials>) -> Option<impl std::future::Future<Output=()>> where
+		CMH: Deref + 'static + Send + Sync,
+		RMH: Deref + 'static + Send + Sync,
+		L: Deref + 'static + Send + Sync,
+		UMH: Deref + 'static + Send + Sync,
+		PP: Deref + 'static + Send + Sync,
+		CMH::Target: ChannelMessageHandler + Send + Sync,
+		RMH::Target: RoutingMessageHandler + Send + Sync,
+		L::Target: Logger + Send + Sync,
+		UMH::Target: CustomMessageHandler + Send + Sync,
+		PP::Target: PeerPolicy + Send + Sync,
+{
+	connect_outbound_via_proxy_with_signal(peer_manager, their_node_id, addr, proxy, credentials, None).await
+}
+
+/// [`connect_outbound_via_proxy`], additionally signalling the completion of the handshake, if
+/// requested.
+pub(crate) async fn connect_outbound_via_proxy_with_signal<CMH, RMH, L, UMH, PP>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, CMH, RMH, L, UMH, PP>>, their_node_id: PublicKey, addr: NetAddress, proxy: &Socks5Proxy, credentials: Option<&ProxyCredentials>, handshake_signal: Option<HandshakeSignal>) -> Option<impl std::future::Future<Output=()>> where
+		CMH: Deref + 'static + Send + Sync,
+		RMH: Deref + 'static + Send + Sync,
+		L: Deref + 'static + Send + Sync,
+		UMH: Deref + 'static + Send + Sync,
+		PP: Deref + 'static + Send + Sync,
+		CMH::Target: ChannelMessageHandler + Send + Sync,
+		RMH::Target: RoutingMessageHandler + Send + Sync,
+		L::Target: Logger + Send + Sync,
+		UMH::Target: CustomMessageHandler + Send + Sync,
+		PP::Target: PeerPolicy + Send + Sync,
+{
+	if proxy.tor_only {
+		if let NetAddress::OnionV3 { .. } = addr {} else { return None; }
+	}
+	if let Ok(Ok(stream)) = time::timeout(PROXY_CONNECT_TIMEOUT, socks5_connect(proxy.addr, &addr, credentials)).await {
+		// The stream's peer address is that of the proxy, so tell the PeerManager who we actually
+		// connected to instead.
+		Some(setup_outbound_transport(peer_manager, their_node_id, Box::new(stream), Some(addr), handshake_signal))
 	} else { None }
 }
 
+/// Returns the `.onion` hostname of the given Tor v3 onion service.
+fn onion_v3_hostname(ed25519_pubkey: &[u8; 32], checksum: u16, version: u8) -> String {
+	const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+	let mut data = Vec::with_capacity(35);
+	data.extend_from_slice(ed25519_pubkey);
+	data.extend_from_slice(&checksum.to_be_bytes());
+	data.push(version);
+
+	// 35 bytes is exactly 56 base32 characters, so no padding is ever needed.
+	let mut hostname = String::with_capacity(56 + 6);
+	for chunk in data.chunks(5) {
+		let mut buf = [0u8; 8];
+		buf[3..].copy_from_slice(chunk);
+		let bits = u64::from_be_bytes(buf);
+		for i in (0..8).rev() {
+			hostname.push(ALPHABET[((bits >> (i * 5)) & 0x1f) as usize] as char);
+		}
+	}
+	hostname.push_str(".onion");
+	hostname
+}
+
+fn socks5_error(msg: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::Other, format!("SOCKS5 proxy error: {}", msg))
+}
+
+/// Connects to `target` through the SOCKS5 proxy at `proxy_addr` as described in RFC 1928,
+/// authenticating with `credentials` as described in RFC 1929 if given.
+async fn socks5_connect(proxy_addr: SocketAddr, target: &NetAddress, credentials: Option<&ProxyCredentials>) -> Result<TcpStream, io::Error> {
+	let mut request = vec![5, 1, 0];
+	let port = match target {
+		NetAddress::IPv4 { addr, port } => { request.push(1); request.extend_from_slice(addr); *port },
+		NetAddress::IPv6 { addr, port } => { request.push(4); request.extend_from_slice(addr); *port },
+		NetAddress::OnionV3 { ed25519_pubkey, checksum, version, port } => {
+			let hostname = onion_v3_hostname(ed25519_pubkey, *checksum, *version);
+			request.push(3);
+			request.push(hostname.len() as u8);
+			request.extend_from_slice(hostname.as_bytes());
+			*port
+		},
+		NetAddress::Hostname { hostname, port } => {
+			request.push(3);
+			request.push(hostname.len());
+			request.extend_from_slice(hostname.as_bytes());
+			*port
+		},
+		NetAddress::OnionV2(_) => return Err(socks5_error("Tor v2 onion addresses are not supported")),
+	};
+	request.extend_from_slice(&port.to_be_bytes());
+
+	let mut stream = TcpStream::connect(&proxy_addr).await?;
+
+	// Offer username/password authentication only if we have credentials to present.
+	let method = if credentials.is_some() { 2 } else { 0 };
+	stream.write_all(&[5, 1, method]).await?;
+	let mut method_selection = [0u8; 2];
+	stream.read_exact(&mut method_selection).await?;
+	if method_selection != [5, method] {
+		return Err(socks5_error("no acceptable authentication method"));
+	}
+
+	if let Some(credentials) = credentials {
+		if credentials.username.len() > 255 || credentials.password.len() > 255 {
+			return Err(socks5_error("credentials too long"));
+		}
+		let mut auth = vec![1, credentials.username.len() as u8];
+		auth.extend_from_slice(credentials.username.as_bytes());
+		auth.push(credentials.password.len() as u8);
+		auth.extend_from_slice(credentials.password.as_bytes());
+		stream.write_all(&auth).await?;
+		let mut auth_status = [0u8; 2];
+		stream.read_exact(&mut auth_status).await?;
+		if auth_status[0] != 1 {
+			return Err(socks5_error("unexpected authentication protocol version"));
+		}
+		if auth_status[1] != 0 {
+			return Err(socks5_error("authentication failed"));
+		}
+	}
+
+	stream.write_all(&request).await?;
+	let mut reply = [0u8; 4];
+	stream.read_exact(&mut reply).await?;
+	if reply[0] != 5 {
+		return Err(socks5_error("unexpected protocol version"));
+	}
+	if reply[1] != 0 {
+		return Err(socks5_error(&format!("connection refused with reply code {}", reply[1])));
+	}
+	// Skip over the address the proxy bound for us, followed by its port.
+	let bound_addr_len = match reply[3] {
+		1 => 4,
+		4 => 16,
+		3 => stream.read_u8().await? as usize,
+		_ => return Err(socks5_error("unknown bound address type")),
+	};
+	let mut bound_addr = vec![0u8; bound_addr_len + 2];
+	stream.read_exact(&mut bound_addr).await?;
+
+	Ok(stream)
+}
+
 const SOCK_WAKER_VTABLE: task::RawWakerVTable =
 	task::RawWakerVTable::new(clone_socket_waker, wake_socket_waker, wake_socket_waker_by_ref, drop_socket_waker);
 
@@ -531,84 +872,18 @@ impl Hash for SocketDescriptor {
 
 #[cfg(test)]
 mod tests {
-	use lightning::ln::features::*;
 	use lightning::ln::msgs::*;
-	use lightning::ln::peer_handler::{MessageHandler, PeerManager};
+	use lightning::ln::peer_handler::{MessageHandler, PeerConnectionLimits, PeerManager};
 	use lightning::util::events::*;
+	use lightning::util::test_utils::{TestLogger, TestPeerConnectionHandler};
 	use bitcoin::secp256k1::{Secp256k1, SecretKey, PublicKey};
 
 	use tokio::sync::mpsc;
 
-	use std::mem;
-	use std::sync::atomic::{AtomicBool, Ordering};
-	use std::sync::{Arc, Mutex};
+	use std::sync::atomic::Ordering;
+	use std::sync::Arc;
 	use std::time::Duration;
 
-	pub struct TestLogger();
-	impl lightning::util::logger::Logger for TestLogger {
-		fn log(&self, record: &lightning::util::logger::Record) {
-			println!("{:<5} [{} : {}, {}] {}", record.level.to_string(), record.module_path, record.file, record.line, record.args);
-		}
-	}
-
-	struct MsgHandler{
-		expected_pubkey: PublicKey,
-		pubkey_connected: mpsc::Sender<()>,
-		pubkey_disconnected: mpsc::Sender<()>,
-		disconnected_flag: AtomicBool,
-		msg_events: Mutex<Vec<MessageSendEvent>>,
-	}
-	impl RoutingMessageHandler for MsgHandler {
-		fn handle_node_announcement(&self, _msg: &NodeAnnouncement) -> Result<bool, LightningError> { Ok(false) }
-		fn handle_channel_announcement(&self, _msg: &ChannelAnnouncement) -> Result<bool, LightningError> { Ok(false) }
-		fn handle_channel_update(&self, _msg: &ChannelUpdate) -> Result<bool, LightningError> { Ok(false) }
-		fn get_next_channel_announcements(&self, _starting_point: u64, _batch_amount: u8) -> Vec<(ChannelAnnouncement, Option<ChannelUpdate>, Option<ChannelUpdate>)> { Vec::new() }
-		fn get_next_node_announcements(&self, _starting_point: Option<&PublicKey>, _batch_amount: u8) -> Vec<NodeAnnouncement> { Vec::new() }
-		fn peer_connected(&self, _their_node_id: &PublicKey, _init_msg: &Init) { }
-		fn handle_reply_channel_range(&self, _their_node_id: &PublicKey, _msg: ReplyChannelRange) -> Result<(), LightningError> { Ok(()) }
-		fn handle_reply_short_channel_ids_end(&self, _their_node_id: &PublicKey, _msg: ReplyShortChannelIdsEnd) -> Result<(), LightningError> { Ok(()) }
-		fn handle_query_channel_range(&self, _their_node_id: &PublicKey, _msg: QueryChannelRange) -> Result<(), LightningError> { Ok(()) }
-		fn handle_query_short_channel_ids(&self, _their_node_id: &PublicKey, _msg: QueryShortChannelIds) -> Result<(), LightningError> { Ok(()) }
-	}
-	impl ChannelMessageHandler for MsgHandler {
-		fn handle_open_channel(&self, _their_node_id: &PublicKey, _their_features: InitFeatures, _msg: &OpenChannel) {}
-		fn handle_accept_channel(&self, _their_node_id: &PublicKey, _their_features: InitFeatures, _msg: &AcceptChannel) {}
-		fn handle_funding_created(&self, _their_node_id: &PublicKey, _msg: &FundingCreated) {}
-		fn handle_funding_signed(&self, _their_node_id: &PublicKey, _msg: &FundingSigned) {}
-		fn handle_channel_ready(&self, _their_node_id: &PublicKey, _msg: &ChannelReady) {}
-		fn handle_shutdown(&self, _their_node_id: &PublicKey, _their_features: &InitFeatures, _msg: &Shutdown) {}
-		fn handle_closing_signed(&self, _their_node_id: &PublicKey, _msg: &ClosingSigned) {}
-		fn handle_update_add_htlc(&self, _their_node_id: &PublicKey, _msg: &UpdateAddHTLC) {}
-		fn handle_update_fulfill_htlc(&self, _their_node_id: &PublicKey, _msg: &UpdateFulfillHTLC) {}
-		fn handle_update_fail_htlc(&self, _their_node_id: &PublicKey, _msg: &UpdateFailHTLC) {}
-		fn handle_update_fail_malformed_htlc(&self, _their_node_id: &PublicKey, _msg: &UpdateFailMalformedHTLC) {}
-		fn handle_commitment_signed(&self, _their_node_id: &PublicKey, _msg: &CommitmentSigned) {}
-		fn handle_revoke_and_ack(&self, _their_node_id: &PublicKey, _msg: &RevokeAndACK) {}
-		fn handle_update_fee(&self, _their_node_id: &PublicKey, _msg: &UpdateFee) {}
-		fn handle_announcement_signatures(&self, _their_node_id: &PublicKey, _msg: &AnnouncementSignatures) {}
-		fn handle_channel_update(&self, _their_node_id: &PublicKey, _msg: &ChannelUpdate) {}
-		fn peer_disconnected(&self, their_node_id: &PublicKey, _no_connection_possible: bool) {
-			if *their_node_id == self.expected_pubkey {
-				self.disconnected_flag.store(true, Ordering::SeqCst);
-				self.pubkey_disconnected.clone().try_send(()).unwrap();
-			}
-		}
-		fn peer_connected(&self, their_node_id: &PublicKey, _msg: &Init) {
-			if *their_node_id == self.expected_pubkey {
-				self.pubkey_connected.clone().try_send(()).unwrap();
-			}
-		}
-		fn handle_channel_reestablish(&self, _their_node_id: &PublicKey, _msg: &ChannelReestablish) {}
-		fn handle_error(&self, _their_node_id: &PublicKey, _msg: &ErrorMessage) {}
-	}
-	impl MessageSendEventsProvider for MsgHandler {
-		fn get_and_clear_pending_msg_events(&self) -> Vec<MessageSendEvent> {
-			let mut ret = Vec::new();
-			mem::swap(&mut *self.msg_events.lock().unwrap(), &mut ret);
-			ret
-		}
-	}
-
 	fn make_tcp_connection() -> (std::net::TcpStream, std::net::TcpStream) {
 		if let Ok(listener) = std::net::TcpListener::bind("127.0.0.1:9735") {
 			(std::net::TcpStream::connect("127.0.0.1:9735").unwrap(), listener.accept().unwrap().0)
@@ -625,6 +900,15 @@ mod tests {
 		} else { panic!("Failed to bind to v4 localhost on common ports"); }
 	}
 
+	fn make_handler(expected_pubkey: PublicKey) -> (Arc<TestPeerConnectionHandler>, mpsc::Receiver<()>, mpsc::Receiver<()>) {
+		let (connected_sender, connected) = mpsc::channel(1);
+		let (disconnected_sender, disconnected) = mpsc::channel(1);
+		let handler = Arc::new(TestPeerConnectionHandler::new(expected_pubkey,
+			move || connected_sender.try_send(()).unwrap(),
+			move || disconnected_sender.try_send(()).unwrap()));
+		(handler, connected, disconnected)
+	}
+
 	async fn do_basic_connection_test() {
 		let secp_ctx = Secp256k1::new();
 		let a_key = SecretKey::from_slice(&[1; 32]).unwrap();
@@ -632,33 +916,17 @@ mod tests {
 		let a_pub = PublicKey::from_secret_key(&secp_ctx, &a_key);
 		let b_pub = PublicKey::from_secret_key(&secp_ctx, &b_key);
 
-		let (a_connected_sender, mut a_connected) = mpsc::channel(1);
-		let (a_disconnected_sender, mut a_disconnected) = mpsc::channel(1);
-		let a_handler = Arc::new(MsgHandler {
-			expected_pubkey: b_pub,
-			pubkey_connected: a_connected_sender,
-			pubkey_disconnected: a_disconnected_sender,
-			disconnected_flag: AtomicBool::new(false),
-			msg_events: Mutex::new(Vec::new()),
-		});
+		let (a_handler, mut a_connected, mut a_disconnected) = make_handler(b_pub);
 		let a_manager = Arc::new(PeerManager::new(MessageHandler {
 			chan_handler: Arc::clone(&a_handler),
 			route_handler: Arc::clone(&a_handler),
-		}, a_key.clone(), &[1; 32], Arc::new(TestLogger()), Arc::new(lightning::ln::peer_handler::IgnoringMessageHandler{})));
-
-		let (b_connected_sender, mut b_connected) = mpsc::channel(1);
-		let (b_disconnected_sender, mut b_disconnected) = mpsc::channel(1);
-		let b_handler = Arc::new(MsgHandler {
-			expected_pubkey: a_pub,
-			pubkey_connected: b_connected_sender,
-			pubkey_disconnected: b_disconnected_sender,
-			disconnected_flag: AtomicBool::new(false),
-			msg_events: Mutex::new(Vec::new()),
-		});
+		}, a_key.clone(), &[1; 32], Arc::new(TestLogger::new()), Arc::new(lightning::ln::peer_handler::IgnoringMessageHandler{})));
+
+		let (b_handler, mut b_connected, mut b_disconnected) = make_handler(a_pub);
 		let b_manager = Arc::new(PeerManager::new(MessageHandler {
 			chan_handler: Arc::clone(&b_handler),
 			route_handler: Arc::clone(&b_handler),
-		}, b_key.clone(), &[2; 32], Arc::new(TestLogger()), Arc::new(lightning::ln::peer_handler::IgnoringMessageHandler{})));
+		}, b_key.clone(), &[2; 32], Arc::new(TestLogger::new()), Arc::new(lightning::ln::peer_handler::IgnoringMessageHandler{})));
 
 		// We bind on localhost, hoping the environment is properly configured with a local
 		// address. This may not always be the case in containers and the like, so if this test is
@@ -672,7 +940,7 @@ mod tests {
 		tokio::time::timeout(Duration::from_secs(10), a_connected.recv()).await.unwrap();
 		tokio::time::timeout(Duration::from_secs(1), b_connected.recv()).await.unwrap();
 
-		a_handler.msg_events.lock().unwrap().push(MessageSendEvent::HandleError {
+		a_handler.pending_events.lock().unwrap().push(MessageSendEvent::HandleError {
 			node_id: b_pub, action: ErrorAction::DisconnectPeer { msg: None }
 		});
 		assert!(!a_handler.disconnected_flag.load(Ordering::SeqCst));
@@ -710,7 +978,7 @@ mod tests {
 		let a_manager = Arc::new(PeerManager::new(MessageHandler {
 			chan_handler: Arc::new(lightning::ln::peer_handler::ErroringMessageHandler::new()),
 			route_handler: Arc::new(lightning::ln::peer_handler::IgnoringMessageHandler{}),
-		}, a_key, &[1; 32], Arc::new(TestLogger()), Arc::new(lightning::ln::peer_handler::IgnoringMessageHandler{})));
+		}, a_key, &[1; 32], Arc::new(TestLogger::new()), Arc::new(lightning::ln::peer_handler::IgnoringMessageHandler{})));
 
 		// Make two connections, one for an inbound and one for an outbound connection
 		let conn_a = {
@@ -732,6 +1000,145 @@ mod tests {
 		});
 	}
 
+	#[test]
+	fn onion_v3_hostname() {
+		let mut pubkey = [0; 32];
+		for (i, byte) in pubkey.iter_mut().enumerate() { *byte = i as u8; }
+		assert_eq!(super::onion_v3_hostname(&pubkey, 0xbeef, 3),
+			"aaaqeayeaudaocajbifqydiob4ibceqtcqkrmfyydenbwha5dyp353yd.onion");
+	}
+
+	/// The target and credentials requested of a [`run_socks5_stand_in`] proxy.
+	#[derive(Debug, PartialEq)]
+	struct ProxiedConnection {
+		credentials: Option<(Vec<u8>, Vec<u8>)>,
+		address_type: u8,
+		address: Vec<u8>,
+		port: u16,
+	}
+
+	/// A minimal SOCKS5 proxy which accepts a single connection, records what it was asked to
+	/// connect to and then relays it to `relay_to`, regardless of the requested target.
+	async fn run_socks5_stand_in(listener: tokio::net::TcpListener, relay_to: std::net::SocketAddr) -> ProxiedConnection {
+		use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+		let (mut client, _) = listener.accept().await.unwrap();
+		let mut greeting = [0; 2];
+		client.read_exact(&mut greeting).await.unwrap();
+		assert_eq!(greeting[0], 5);
+		let mut methods = vec![0; greeting[1] as usize];
+		client.read_exact(&mut methods).await.unwrap();
+		let credentials = if methods.contains(&2) {
+			client.write_all(&[5, 2]).await.unwrap();
+			assert_eq!(client.read_u8().await.unwrap(), 1);
+			let mut username = vec![0; client.read_u8().await.unwrap() as usize];
+			client.read_exact(&mut username).await.unwrap();
+			let mut password = vec![0; client.read_u8().await.unwrap() as usize];
+			client.read_exact(&mut password).await.unwrap();
+			client.write_all(&[1, 0]).await.unwrap();
+			Some((username, password))
+		} else {
+			client.write_all(&[5, 0]).await.unwrap();
+			None
+		};
+
+		let mut request = [0; 4];
+		client.read_exact(&mut request).await.unwrap();
+		assert_eq!(request[..3], [5, 1, 0]);
+		let address_len = match request[3] {
+			1 => 4,
+			4 => 16,
+			3 => client.read_u8().await.unwrap() as usize,
+			_ => panic!("Unknown address type"),
+		};
+		let mut address = vec![0; address_len];
+		client.read_exact(&mut address).await.unwrap();
+		let port = client.read_u16().await.unwrap();
+
+		let mut upstream = tokio::net::TcpStream::connect(relay_to).await.unwrap();
+		client.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).await.unwrap();
+		tokio::spawn(async move {
+			let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+		});
+		ProxiedConnection { credentials, address_type: request[3], address, port }
+	}
+
+	#[tokio::test]
+	async fn socks5_rejects_unexpected_auth_version() {
+		use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+		// A proxy replying to our credentials with a SOCKS5 rather than an RFC 1929 version byte
+		// is not trusted to have authenticated us, even if it reports success.
+		let proxy_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let proxy_addr = proxy_listener.local_addr().unwrap();
+		let proxy = tokio::spawn(async move {
+			let (mut client, _) = proxy_listener.accept().await.unwrap();
+			let mut greeting = [0; 3];
+			client.read_exact(&mut greeting).await.unwrap();
+			client.write_all(&[5, 2]).await.unwrap();
+			let mut auth = [0; 6];
+			client.read_exact(&mut auth).await.unwrap();
+			client.write_all(&[5, 0]).await.unwrap();
+		});
+
+		let target = NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 };
+		let credentials = super::ProxyCredentials { username: "ab".to_string(), password: "c".to_string() };
+		let err = super::socks5_connect(proxy_addr, &target, Some(&credentials)).await.unwrap_err();
+		assert!(err.to_string().contains("unexpected authentication protocol version"));
+		proxy.await.unwrap();
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn connect_via_socks5_proxy() {
+		let secp_ctx = Secp256k1::new();
+		let a_key = SecretKey::from_slice(&[1; 32]).unwrap();
+		let b_key = SecretKey::from_slice(&[2; 32]).unwrap();
+		let a_pub = PublicKey::from_secret_key(&secp_ctx, &a_key);
+		let b_pub = PublicKey::from_secret_key(&secp_ctx, &b_key);
+
+		let (a_handler, mut a_connected, _a_disconnected) = make_handler(b_pub);
+		let a_manager = Arc::new(PeerManager::new(MessageHandler {
+			chan_handler: Arc::clone(&a_handler),
+			route_handler: Arc::clone(&a_handler),
+		}, a_key, &[1; 32], Arc::new(TestLogger::new()), Arc::new(lightning::ln::peer_handler::IgnoringMessageHandler{})));
+
+		let (b_handler, mut b_connected, _b_disconnected) = make_handler(a_pub);
+		let b_manager = Arc::new(PeerManager::new(MessageHandler {
+			chan_handler: Arc::clone(&b_handler),
+			route_handler: Arc::clone(&b_handler),
+		}, b_key, &[2; 32], Arc::new(TestLogger::new()), Arc::new(lightning::ln::peer_handler::IgnoringMessageHandler{})));
+
+		let b_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let b_addr = b_listener.local_addr().unwrap();
+		let proxy_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let proxy = super::Socks5Proxy { addr: proxy_listener.local_addr().unwrap(), tor_only: true };
+		let proxy_handle = tokio::spawn(run_socks5_stand_in(proxy_listener, b_addr));
+		let b_accept = tokio::task::spawn_blocking(move || b_listener.accept().unwrap().0);
+
+		// In Tor-only mode we refuse to dial clearnet addresses without ever contacting the proxy.
+		let clearnet_addr = NetAddress::IPv4 { addr: [127, 0, 0, 1], port: b_addr.port() };
+		assert!(super::connect_outbound_via_proxy(Arc::clone(&a_manager), b_pub, clearnet_addr, &proxy, None).await.is_none());
+
+		let onion_addr = NetAddress::OnionV3 { ed25519_pubkey: [42; 32], checksum: 0x1234, version: 3, port: 9735 };
+		let credentials = super::ProxyCredentials { username: "peer".to_string(), password: "b".to_string() };
+		let fut_a = super::connect_outbound_via_proxy(Arc::clone(&a_manager), b_pub, onion_addr, &proxy, Some(&credentials)).await.unwrap();
+		let fut_b = super::setup_inbound(Arc::clone(&b_manager), b_accept.await.unwrap());
+
+		tokio::time::timeout(Duration::from_secs(10), a_connected.recv()).await.unwrap();
+		tokio::time::timeout(Duration::from_secs(10), b_connected.recv()).await.unwrap();
+
+		assert_eq!(proxy_handle.await.unwrap(), ProxiedConnection {
+			credentials: Some((b"peer".to_vec(), b"b".to_vec())),
+			address_type: 3,
+			address: super::onion_v3_hostname(&[42; 32], 0x1234, 3).into_bytes(),
+			port: 9735,
+		});
+
+		a_manager.disconnect_all_peers();
+		fut_a.await;
+		fut_b.await;
+	}
+
 	#[tokio::test(flavor = "multi_thread")]
 	async fn threaded_race_disconnect_accept() {
 		race_disconnect_accept().await;
@@ -741,4 +1148,41 @@ mod tests {
 	async fn unthreaded_race_disconnect_accept() {
 		race_disconnect_accept().await;
 	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn accept_inbound_connections_within_limits() {
+		let secp_ctx = Secp256k1::new();
+		let make_manager = |seed: u8, limits: PeerConnectionLimits| {
+			let key = SecretKey::from_slice(&[seed; 32]).unwrap();
+			let manager = Arc::new(PeerManager::new_with_limits(MessageHandler {
+				chan_handler: Arc::new(lightning::ln::peer_handler::ErroringMessageHandler::new()),
+				route_handler: Arc::new(lightning::ln::peer_handler::IgnoringMessageHandler{}),
+			}, key, &[seed; 32], Arc::new(TestLogger::new()), Arc::new(lightning::ln::peer_handler::IgnoringMessageHandler{}), limits));
+			(manager, PublicKey::from_secret_key(&secp_ctx, &key))
+		};
+		// All our connections come from localhost, so only the first is accepted.
+		let (listening_manager, listening_pub) = make_manager(1, PeerConnectionLimits {
+			max_inbound_connections_per_subnet: 1, ..Default::default()
+		});
+		let (a_manager, a_pub) = make_manager(2, Default::default());
+		let (c_manager, _) = make_manager(3, Default::default());
+
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		let accept_handle = tokio::spawn(super::accept_inbound_connections(Arc::clone(&listening_manager), listener));
+
+		let _fut_a = super::connect_outbound(a_manager, listening_pub, addr).await.unwrap();
+		tokio::time::timeout(Duration::from_secs(10), async {
+			while listening_manager.get_peer_node_ids().is_empty() {
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+		}).await.unwrap();
+
+		// The refused connection is closed immediately, completing its future.
+		let fut_c = super::connect_outbound(c_manager, listening_pub, addr).await.unwrap();
+		tokio::time::timeout(Duration::from_secs(10), fut_c).await.unwrap();
+		assert_eq!(listening_manager.get_peer_node_ids(), vec![a_pub]);
+
+		accept_handle.abort();
+	}
 }
@@ -0,0 +1,674 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Carries the Lightning P2P message stream over WebSockets (RFC 6455), for peers which cannot
+//! make raw TCP connections, such as wallets running in a browser or behind HTTP-only firewalls.
+//!
+//! The Noise-encrypted byte stream is exchanged unmodified in binary WebSocket frames, so the
+//! connection is handled exactly as a TCP one would be, and WebSocket and TCP peers may share a
+//! single PeerManager. Use [`setup_inbound`] or [`accept_inbound_connections`] to serve WebSocket
+//! clients, and [`setup_outbound`] to connect to a WebSocket server over any stream implementing
+//! tokio's `AsyncRead` and `AsyncWrite`.
+//!
+//! Only what is needed to carry our byte stream is supported: text frames are rejected, pings are
+//! answered as soon as they're received, and no extensions or subprotocols are negotiated.
+
+use bitcoin::hashes::{sha1, Hash, HashEngine};
+use bitcoin::secp256k1::PublicKey;
+
+use tokio::{io, time};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use lightning::ln::peer_handler;
+use lightning::ln::peer_handler::CustomMessageHandler;
+use lightning::ln::peer_policy::PeerPolicy;
+use lightning::ln::msgs::{ChannelMessageHandler, RoutingMessageHandler, NetAddress};
+use lightning::util::logger::Logger;
+
+use crate::SocketDescriptor;
+
+use std::cmp;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+/// The GUID which is appended to the client's key to compute the server's accept value.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// The maximum length of the HTTP request or response which opens a connection.
+const MAX_HANDSHAKE_LEN: usize = 8192;
+/// How long we give the other end to complete the HTTP upgrade handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// The maximum payload we put in a single frame. Larger writes are split across frames.
+const MAX_FRAME_PAYLOAD: usize = 65536;
+/// The maximum payload of a control frame, as set by the RFC.
+const MAX_CONTROL_PAYLOAD: u64 = 125;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Role {
+	/// We accepted the connection, so receive masked frames and send unmasked ones.
+	Server,
+	/// We opened the connection, so send masked frames and receive unmasked ones.
+	Client,
+}
+
+/// Fills `buf` from the OS RNG, used for handshake keys and frame masks, which the RFC requires to
+/// be unpredictable to anyone who can see the connection.
+fn fill_random(buf: &mut [u8]) {
+	getrandom::getrandom(buf).expect("Failed to read from the OS RNG");
+}
+
+/// Computes the `Sec-WebSocket-Accept` value a server must reply with for the given client key.
+fn accept_key(key: &str) -> String {
+	let mut engine = sha1::Hash::engine();
+	engine.input(key.as_bytes());
+	engine.input(WEBSOCKET_GUID.as_bytes());
+	base64::encode(sha1::Hash::from_engine(engine).into_inner())
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, format!("WebSocket protocol error: {}", msg))
+}
+
+/// Reads an HTTP request or response header, returning its lines and any bytes the other end sent
+/// after it.
+async fn read_http_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<(Vec<String>, Vec<u8>), io::Error> {
+	let mut buf = Vec::new();
+	let mut read_buf = [0u8; 1024];
+	loop {
+		if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+			let header = std::str::from_utf8(&buf[..end]).map_err(|_| protocol_error("invalid HTTP header"))?;
+			let lines = header.split("\r\n").map(|line| line.to_owned()).collect();
+			return Ok((lines, buf[end + 4..].to_vec()));
+		}
+		if buf.len() > MAX_HANDSHAKE_LEN {
+			return Err(protocol_error("HTTP header too long"));
+		}
+		let len = stream.read(&mut read_buf).await?;
+		if len == 0 {
+			return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed during WebSocket handshake"));
+		}
+		buf.extend_from_slice(&read_buf[..len]);
+	}
+}
+
+/// Returns the value of the given header, matching its name case-insensitively.
+fn header_value<'a>(lines: &'a [String], name: &str) -> Option<&'a str> {
+	lines.iter().skip(1).filter_map(|line| {
+		let mut parts = line.splitn(2, ':');
+		let key = parts.next()?.trim();
+		let value = parts.next()?.trim();
+		if key.eq_ignore_ascii_case(name) { Some(value) } else { None }
+	}).next()
+}
+
+/// Returns true if the given comma-separated header contains the given token.
+fn header_contains(lines: &[String], name: &str, token: &str) -> bool {
+	header_value(lines, name).map_or(false, |value| value.split(',').any(|v| v.trim().eq_ignore_ascii_case(token)))
+}
+
+/// Handles a client's request to upgrade the connection to a WebSocket, returning any bytes the
+/// client already sent after its request.
+async fn server_handshake<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Result<Vec<u8>, io::Error> {
+	let (lines, leftover) = read_http_header(stream).await?;
+	let key = {
+		let is_get = lines[0].starts_with("GET ") && lines[0].ends_with(" HTTP/1.1");
+		let key = header_value(&lines, "Sec-WebSocket-Key");
+		match key {
+			Some(key) if is_get && header_contains(&lines, "Upgrade", "websocket") &&
+				header_contains(&lines, "Connection", "upgrade") &&
+				header_value(&lines, "Sec-WebSocket-Version") == Some("13") => key.to_owned(),
+			_ => {
+				let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\nSec-WebSocket-Version: 13\r\nContent-Length: 0\r\n\r\n").await;
+				return Err(protocol_error("not a WebSocket upgrade request"));
+			},
+		}
+	};
+	let response = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", accept_key(&key));
+	stream.write_all(response.as_bytes()).await?;
+	Ok(leftover)
+}
+
+/// Asks the server to upgrade the connection to a WebSocket, returning any bytes the server
+/// already sent after its response.
+async fn client_handshake<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, host: &str, path: &str) -> Result<Vec<u8>, io::Error> {
+	let mut key_bytes = [0u8; 16];
+	fill_random(&mut key_bytes);
+	let key = base64::encode(key_bytes);
+	let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n", path, host, key);
+	stream.write_all(request.as_bytes()).await?;
+
+	let (lines, leftover) = read_http_header(stream).await?;
+	if !lines[0].starts_with("HTTP/1.1 101 ") && lines[0] != "HTTP/1.1 101" {
+		return Err(protocol_error(&format!("server refused the upgrade with {}", lines[0])));
+	}
+	if !header_contains(&lines, "Upgrade", "websocket") || !header_contains(&lines, "Connection", "upgrade") {
+		return Err(protocol_error("server did not upgrade to a WebSocket"));
+	}
+	if header_value(&lines, "Sec-WebSocket-Accept") != Some(&accept_key(&key)) {
+		return Err(protocol_error("server sent an invalid Sec-WebSocket-Accept"));
+	}
+	Ok(leftover)
+}
+
+/// The header of a frame we've received.
+struct FrameHeader {
+	len: usize,
+	fin: bool,
+	opcode: u8,
+	payload_len: u64,
+	mask: Option<[u8; 4]>,
+}
+
+/// Wraps a stream over which a WebSocket handshake has completed, exposing the byte stream carried
+/// in its binary frames.
+struct WebSocketStream<S> {
+	inner: S,
+	role: Role,
+	/// Bytes read from `inner` which we have yet to process.
+	read_buf: Vec<u8>,
+	read_pos: usize,
+	/// The number of payload bytes left in the data frame we're currently reading.
+	payload_remaining: u64,
+	/// Set while we're receiving a fragmented binary message, i.e. after a data frame without the
+	/// FIN bit, until the final continuation frame.
+	in_fragmented_message: bool,
+	payload_mask: Option<[u8; 4]>,
+	mask_offset: usize,
+	/// Set once we've received a close frame, after which we only return EOF.
+	read_closed: bool,
+	/// Encoded frames which we have yet to write to `inner`.
+	write_buf: Vec<u8>,
+	write_pos: usize,
+	/// Set once we've queued a close frame, after which we don't send any more frames.
+	write_closed: bool,
+	/// The waker of the last write which couldn't complete, woken if a read steals its place in
+	/// `inner`'s write queue to send a pong.
+	write_waker: Option<Waker>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocketStream<S> {
+	fn new(inner: S, role: Role, leftover: Vec<u8>) -> Self {
+		Self {
+			inner, role,
+			read_buf: leftover,
+			read_pos: 0,
+			payload_remaining: 0,
+			in_fragmented_message: false,
+			payload_mask: None,
+			mask_offset: 0,
+			read_closed: false,
+			write_buf: Vec::new(),
+			write_pos: 0,
+			write_closed: false,
+			write_waker: None,
+		}
+	}
+
+	fn encode_frame(&mut self, opcode: u8, payload: &[u8]) {
+		let mask_bit = if self.role == Role::Client { 0x80 } else { 0 };
+		self.write_buf.push(0x80 | opcode);
+		if payload.len() < 126 {
+			self.write_buf.push(mask_bit | payload.len() as u8);
+		} else if payload.len() <= 0xffff {
+			self.write_buf.push(mask_bit | 126);
+			self.write_buf.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+		} else {
+			self.write_buf.push(mask_bit | 127);
+			self.write_buf.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+		}
+		if self.role == Role::Client {
+			let mut mask = [0u8; 4];
+			fill_random(&mut mask);
+			self.write_buf.extend_from_slice(&mask);
+			self.write_buf.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+		} else {
+			self.write_buf.extend_from_slice(payload);
+		}
+	}
+
+	/// Writes out any frames we've encoded but not yet written.
+	fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+		while self.write_pos < self.write_buf.len() {
+			match Pin::new(&mut self.inner).poll_write(cx, &self.write_buf[self.write_pos..]) {
+				Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+				Poll::Ready(Ok(len)) => self.write_pos += len,
+				Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+				Poll::Pending => return Poll::Pending,
+			}
+		}
+		self.write_buf.clear();
+		self.write_pos = 0;
+		Poll::Ready(Ok(()))
+	}
+
+	/// Writes out any frames we've encoded but not yet written on behalf of a writer, remembering
+	/// its waker if it has to wait.
+	fn poll_write_buf_for_writer(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+		let res = self.poll_write_buf(cx);
+		self.write_waker = if res.is_pending() { Some(cx.waker().clone()) } else { None };
+		res
+	}
+
+	/// Parses the header of the next frame if we've read all of it.
+	fn parse_header(&self) -> Result<Option<FrameHeader>, io::Error> {
+		let buf = &self.read_buf[self.read_pos..];
+		if buf.len() < 2 { return Ok(None); }
+		if buf[0] & 0x70 != 0 {
+			return Err(protocol_error("unexpected reserved bits"));
+		}
+		let fin = buf[0] & 0x80 != 0;
+		let opcode = buf[0] & 0x0f;
+		let masked = buf[1] & 0x80 != 0;
+		if masked != (self.role == Role::Server) {
+			return Err(protocol_error("frame masking is incorrect for our role"));
+		}
+		let (mut len, payload_len) = match buf[1] & 0x7f {
+			126 => {
+				if buf.len() < 4 { return Ok(None); }
+				(4, u16::from_be_bytes([buf[2], buf[3]]) as u64)
+			},
+			127 => {
+				if buf.len() < 10 { return Ok(None); }
+				let mut len_bytes = [0u8; 8];
+				len_bytes.copy_from_slice(&buf[2..10]);
+				(10, u64::from_be_bytes(len_bytes))
+			},
+			short_len => (2, short_len as u64),
+		};
+		if opcode & 0x08 != 0 && (!fin || payload_len > MAX_CONTROL_PAYLOAD) {
+			return Err(protocol_error("invalid control frame"));
+		}
+		let mask = if masked {
+			if buf.len() < len + 4 { return Ok(None); }
+			let mut mask = [0u8; 4];
+			mask.copy_from_slice(&buf[len..len + 4]);
+			len += 4;
+			Some(mask)
+		} else { None };
+		Ok(Some(FrameHeader { len, fin, opcode, payload_len, mask }))
+	}
+
+	/// Processes buffered frames until we have payload bytes to return. Returns true if the caller
+	/// should return (because data is available or the stream has closed) and false if we need to
+	/// read more from `inner`.
+	fn process_frames(&mut self) -> Result<bool, io::Error> {
+		loop {
+			if self.read_closed { return Ok(true); }
+			if self.payload_remaining > 0 {
+				return Ok(self.read_pos < self.read_buf.len());
+			}
+			let header = match self.parse_header()? {
+				Some(header) => header,
+				None => return Ok(false),
+			};
+			match header.opcode {
+				OPCODE_CONTINUATION|OPCODE_BINARY => {
+					if (header.opcode == OPCODE_CONTINUATION) != self.in_fragmented_message {
+						return Err(protocol_error("unexpected data frame fragmentation"));
+					}
+					self.in_fragmented_message = !header.fin;
+					self.read_pos += header.len;
+					self.payload_remaining = header.payload_len;
+					self.payload_mask = header.mask;
+					self.mask_offset = 0;
+				},
+				OPCODE_CLOSE|OPCODE_PING|OPCODE_PONG => {
+					let frame_len = header.len + header.payload_len as usize;
+					if self.read_buf.len() - self.read_pos < frame_len { return Ok(false); }
+					let mut payload = self.read_buf[self.read_pos + header.len..self.read_pos + frame_len].to_vec();
+					if let Some(mask) = header.mask {
+						for (i, b) in payload.iter_mut().enumerate() { *b ^= mask[i % 4]; }
+					}
+					self.read_pos += frame_len;
+					match header.opcode {
+						// We'll reply with our own close frame when the connection is shut down.
+						OPCODE_CLOSE => self.read_closed = true,
+						// The pong is written out by `poll_read` before it returns.
+						OPCODE_PING if !self.write_closed => self.encode_frame(OPCODE_PONG, &payload),
+						_ => {},
+					}
+				},
+				OPCODE_TEXT => return Err(protocol_error("unexpected text frame")),
+				_ => return Err(protocol_error("unknown opcode")),
+			}
+		}
+	}
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketStream<S> {
+	fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<Result<(), io::Error>> {
+		let this = &mut *self;
+		loop {
+			let processed = this.process_frames()?;
+
+			// Send any pong we've queued right away. If `inner` can't take it yet, our waker has
+			// replaced that of any pending writer, so wake the writer to have it register again.
+			// Until a writer does, we'll be woken to retry the write ourselves.
+			if !this.write_buf.is_empty() {
+				match this.poll_write_buf(cx) {
+					Poll::Ready(Ok(())) => {},
+					Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+					Poll::Pending => if let Some(waker) = this.write_waker.take() { waker.wake(); },
+				}
+			}
+
+			if processed {
+				if this.read_closed { return Poll::Ready(Ok(())); }
+				let available = this.read_buf.len() - this.read_pos;
+				let len = cmp::min(cmp::min(this.payload_remaining, available as u64) as usize, buf.remaining());
+				let start = buf.filled().len();
+				buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + len]);
+				if let Some(mask) = this.payload_mask {
+					for (i, b) in buf.filled_mut()[start..].iter_mut().enumerate() {
+						*b ^= mask[(this.mask_offset + i) % 4];
+					}
+				}
+				this.read_pos += len;
+				this.payload_remaining -= len as u64;
+				this.mask_offset = (this.mask_offset + len) % 4;
+				return Poll::Ready(Ok(()));
+			}
+
+			// Drop the bytes we've processed and read more from the underlying stream.
+			this.read_buf.drain(..this.read_pos);
+			this.read_pos = 0;
+			let filled = this.read_buf.len();
+			this.read_buf.resize(filled + 8192, 0);
+			let mut inner_buf = ReadBuf::new(&mut this.read_buf[filled..]);
+			let res = Pin::new(&mut this.inner).poll_read(cx, &mut inner_buf);
+			let read_len = inner_buf.filled().len();
+			this.read_buf.truncate(filled + read_len);
+			match res {
+				Poll::Ready(Ok(())) if read_len == 0 => return Poll::Ready(Ok(())),
+				Poll::Ready(Ok(())) => {},
+				Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+				Poll::Pending => return Poll::Pending,
+			}
+		}
+	}
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketStream<S> {
+	fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<Result<usize, io::Error>> {
+		// Only accept new data once any previous frame has been written out, bounding how much we
+		// buffer to a single frame.
+		match self.poll_write_buf_for_writer(cx) {
+			Poll::Ready(Ok(())) => {},
+			Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+			Poll::Pending => return Poll::Pending,
+		}
+		if self.write_closed {
+			return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+		}
+		let len = cmp::min(data.len(), MAX_FRAME_PAYLOAD);
+		self.encode_frame(OPCODE_BINARY, &data[..len]);
+		// The frame is ours to write now, so we've accepted the data even if the underlying stream
+		// is full. Whatever remains is written on the next write or flush.
+		if let Poll::Ready(Err(e)) = self.poll_write_buf_for_writer(cx) {
+			return Poll::Ready(Err(e));
+		}
+		Poll::Ready(Ok(len))
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+		match self.poll_write_buf_for_writer(cx) {
+			Poll::Ready(Ok(())) => Pin::new(&mut self.inner).poll_flush(cx),
+			res => res,
+		}
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+		if !self.write_closed {
+			self.write_closed = true;
+			self.encode_frame(OPCODE_CLOSE, &[]);
+		}
+		match self.poll_write_buf_for_writer(cx) {
+			Poll::Ready(Ok(())) => Pin::new(&mut self.inner).poll_shutdown(cx),
+			res => res,
+		}
+	}
+}
+
+/// Completes the WebSocket handshake with a client on the provided stream and then processes
+/// incoming messages and feeds outgoing messages on it, as [`crate::setup_inbound`] does for TCP
+/// sockets.
+///
+/// `remote_addr` is passed to [`PeerManager::new_inbound_connection`] and should be the client's
+/// address if known. When the connection was forwarded by an HTTP proxy, this would be taken from
+/// the proxy's `X-Forwarded-For` header rather than the address of the proxy itself.
+///
+/// Returns `None` if the stream is not a valid WebSocket upgrade request or the client does not
+/// complete the handshake in time. Otherwise the returned future will complete when the peer is
+/// disconnected, though, as with [`crate::setup_inbound`], it need not be polled to make progress.
+///
+/// [`PeerManager::new_inbound_connection`]: lightning::ln::peer_handler::PeerManager::new_inbound_connection
+pub async fn setup_inbound<CMH, RMH, L, UMH, PP, S>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, CMH, RMH, L, UMH, PP>>, mut stream: S, remote_addr: Option<NetAddress>) -> Option<impl std::future::Future<Output=()>> where
+		CMH: Deref + 'static + Send + Sync,
+		RMH: Deref + 'static + Send + Sync,
+		L: Deref + 'static + Send + Sync,
+		UMH: Deref + 'static + Send + Sync,
+		PP: Deref + 'static + Send + Sync,
+		CMH::Target: ChannelMessageHandler + Send + Sync,
+		RMH::Target: RoutingMessageHandler + Send + Sync,
+		L::Target: Logger + Send + Sync,
+		UMH::Target: CustomMessageHandler + Send + Sync,
+		PP::Target: PeerPolicy + Send + Sync,
+		S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+	if let Ok(Ok(leftover)) = time::timeout(HANDSHAKE_TIMEOUT, server_handshake(&mut stream)).await {
+		let transport = WebSocketStream::new(stream, Role::Server, leftover);
+		Some(crate::setup_inbound_transport(peer_manager, Box::new(transport), remote_addr))
+	} else { None }
+}
+
+/// Accepts TCP connections on the given listener and serves each as a WebSocket connection via
+/// [`setup_inbound`], for WebSocket clients which connect to us directly rather than through an
+/// HTTP proxy. The returned future never completes, so should itself be spawned.
+///
+/// As with [`crate::accept_inbound_connections`], connections beyond the PeerManager's
+/// [`PeerConnectionLimits`] are refused.
+///
+/// [`PeerConnectionLimits`]: lightning::ln::peer_handler::PeerConnectionLimits
+pub async fn accept_inbound_connections<CMH, RMH, L, UMH, PP>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, CMH, RMH, L, UMH, PP>>, listener: tokio::net::TcpListener) where
+		CMH: Deref + 'static + Send + Sync,
+		RMH: Deref + 'static + Send + Sync,
+		L: Deref + 'static + Send + Sync,
+		UMH: Deref + 'static + Send + Sync,
+		PP: Deref + 'static + Send + Sync,
+		CMH::Target: ChannelMessageHandler + Send + Sync,
+		RMH::Target: RoutingMessageHandler + Send + Sync,
+		L::Target: Logger + Send + Sync,
+		UMH::Target: CustomMessageHandler + Send + Sync,
+		PP::Target: PeerPolicy + Send + Sync,
+{
+	loop {
+		match listener.accept().await {
+			Ok((stream, addr)) => {
+				let peer_manager = Arc::clone(&peer_manager);
+				let remote_addr = Some(crate::net_address_from_socket_addr(addr));
+				tokio::spawn(async move {
+					if let Some(connection) = setup_inbound(peer_manager, stream, remote_addr).await {
+						connection.await;
+					}
+				});
+			},
+			Err(_) => time::sleep(Duration::from_millis(100)).await,
+		}
+	}
+}
+
+/// Completes a WebSocket handshake with the server on the provided stream, requesting the given
+/// `path` on `host`, and then processes incoming messages and feeds outgoing messages on it, as
+/// [`crate::setup_outbound`] does for TCP sockets. The server is expected to be a peer with the
+/// given public key (or to forward our connection to one).
+///
+/// The stream may be anything implementing tokio's `AsyncRead` and `AsyncWrite`, e.g. a TCP or TLS
+/// stream, or an in-memory pipe to a WebSocket implementation provided by the environment.
+/// `remote_addr` is passed to [`PeerManager::new_outbound_connection`].
+///
+/// Returns `None` if the server does not accept the upgrade to a WebSocket in time. Otherwise the
+/// returned future behaves as the one returned by [`crate::setup_outbound`].
+///
+/// [`PeerManager::new_outbound_connection`]: lightning::ln::peer_handler::PeerManager::new_outbound_connection
+pub async fn setup_outbound<CMH, RMH, L, UMH, PP, S>(peer_manager: Arc<peer_handler::PeerManager<SocketDescriptor, CMH, RMH, L, UMH, PP>>, their_node_id: PublicKey, mut stream: S, host: &str, path: &str, remote_addr: Option<NetAddress>) -> Option<impl std::future::Future<Output=()>> where
+		CMH: Deref + 'static + Send + Sync,
+		RMH: Deref + 'static + Send + Sync,
+		L: Deref + 'static + Send + Sync,
+		UMH: Deref + 'static + Send + Sync,
+		PP: Deref + 'static + Send + Sync,
+		CMH::Target: ChannelMessageHandler + Send + Sync,
+		RMH::Target: RoutingMessageHandler + Send + Sync,
+		L::Target: Logger + Send + Sync,
+		UMH::Target: CustomMessageHandler + Send + Sync,
+		PP::Target: PeerPolicy + Send + Sync,
+		S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+	if let Ok(Ok(leftover)) = time::timeout(HANDSHAKE_TIMEOUT, client_handshake(&mut stream, host, path)).await {
+		let transport = WebSocketStream::new(stream, Role::Client, leftover);
+		Some(crate::setup_outbound_transport(peer_manager, their_node_id, Box::new(transport), remote_addr, None))
+	} else { None }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use lightning::ln::peer_handler::{ErroringMessageHandler, IgnoringMessageHandler, MessageHandler, PeerManager};
+	use lightning::util::test_utils::TestLogger;
+	use bitcoin::secp256k1::{Secp256k1, SecretKey};
+
+	#[test]
+	fn test_accept_key() {
+		// The example handshake from RFC 6455 section 1.3.
+		assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+	}
+
+	#[tokio::test]
+	async fn test_frames() {
+		let (client_stream, server_stream) = io::duplex(1024);
+		let mut client = WebSocketStream::new(client_stream, Role::Client, Vec::new());
+		let mut server = WebSocketStream::new(server_stream, Role::Server, Vec::new());
+
+		// Data larger than a frame and larger than the pipe still arrives intact in both directions.
+		let data: Vec<u8> = (0..MAX_FRAME_PAYLOAD * 2 + 3).map(|i| i as u8).collect();
+		let expected = data.clone();
+		let writer = tokio::spawn(async move {
+			client.write_all(&data).await.unwrap();
+			client.flush().await.unwrap();
+			client
+		});
+		let mut received = vec![0u8; expected.len()];
+		server.read_exact(&mut received).await.unwrap();
+		assert!(received == expected);
+		let mut client = writer.await.unwrap();
+
+		// A ping is answered as soon as it's read, without waiting for us to send data, and
+		// transparently skipped on the other end. Close ends the stream.
+		client.encode_frame(OPCODE_PING, b"ping");
+		client.write_all(b"hello").await.unwrap();
+		let mut buf = [0u8; 5];
+		server.read_exact(&mut buf).await.unwrap();
+		assert_eq!(&buf, b"hello");
+		assert!(server.write_buf.is_empty());
+		server.write_all(b"world").await.unwrap();
+		client.read_exact(&mut buf).await.unwrap();
+		assert_eq!(&buf, b"world");
+
+		client.shutdown().await.unwrap();
+		assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+
+		// We don't carry text, and servers must only accept masked frames.
+		let (client_stream, server_stream) = io::duplex(1024);
+		let mut client = WebSocketStream::new(client_stream, Role::Client, Vec::new());
+		let mut server = WebSocketStream::new(server_stream, Role::Server, Vec::new());
+		client.encode_frame(OPCODE_TEXT, b"hi");
+		client.flush().await.unwrap();
+		assert_eq!(server.read(&mut buf).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+		let (client_stream, server_stream) = io::duplex(1024);
+		let mut client = WebSocketStream::new(client_stream, Role::Server, Vec::new());
+		let mut server = WebSocketStream::new(server_stream, Role::Server, Vec::new());
+		client.write_all(b"hi").await.unwrap();
+		assert_eq!(server.read(&mut buf).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+		// Fragmented messages are read as the concatenation of their frames' payloads.
+		let (mut server_stream, client_stream) = io::duplex(1024);
+		let mut client = WebSocketStream::new(client_stream, Role::Client, Vec::new());
+		server_stream.write_all(&[OPCODE_BINARY, 2, b'h', b'e', 0x80 | OPCODE_CONTINUATION, 3, b'l', b'l', b'o']).await.unwrap();
+		client.read_exact(&mut buf).await.unwrap();
+		assert_eq!(&buf, b"hello");
+
+		// A continuation frame must follow a binary frame which didn't complete its message.
+		let (client_stream, server_stream) = io::duplex(1024);
+		let mut client = WebSocketStream::new(client_stream, Role::Client, Vec::new());
+		let mut server = WebSocketStream::new(server_stream, Role::Server, Vec::new());
+		client.encode_frame(OPCODE_CONTINUATION, b"hi");
+		client.flush().await.unwrap();
+		assert_eq!(server.read(&mut buf).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+	}
+
+	#[tokio::test]
+	async fn test_handshake_rejected() {
+		let (mut client_stream, server_stream) = io::duplex(1024);
+		let key = SecretKey::from_slice(&[1; 32]).unwrap();
+		let manager = Arc::new(PeerManager::new(MessageHandler {
+			chan_handler: Arc::new(ErroringMessageHandler::new()),
+			route_handler: Arc::new(IgnoringMessageHandler{}),
+		}, key, &[1; 32], Arc::new(TestLogger::new()), Arc::new(IgnoringMessageHandler{})));
+
+		client_stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+		assert!(setup_inbound(manager, server_stream, None).await.is_none());
+		let (lines, _) = read_http_header(&mut client_stream).await.unwrap();
+		assert_eq!(lines[0], "HTTP/1.1 400 Bad Request");
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn test_websocket_connection() {
+		let secp_ctx = Secp256k1::new();
+		let make_manager = |seed: u8| {
+			let key = SecretKey::from_slice(&[seed; 32]).unwrap();
+			let manager = Arc::new(PeerManager::new(MessageHandler {
+				chan_handler: Arc::new(ErroringMessageHandler::new()),
+				route_handler: Arc::new(IgnoringMessageHandler{}),
+			}, key, &[seed; 32], Arc::new(TestLogger::new()), Arc::new(IgnoringMessageHandler{})));
+			(manager, PublicKey::from_secret_key(&secp_ctx, &key))
+		};
+		let (server_manager, server_pub) = make_manager(1);
+		let (client_manager, client_pub) = make_manager(2);
+
+		let (client_stream, server_stream) = io::duplex(4096);
+		let (server_fut, client_fut) = tokio::join!(
+			setup_inbound(Arc::clone(&server_manager), server_stream, None),
+			setup_outbound(Arc::clone(&client_manager), server_pub, client_stream, "localhost", "/", None));
+		let (server_fut, client_fut) = (server_fut.unwrap(), client_fut.unwrap());
+
+		time::timeout(Duration::from_secs(10), async {
+			while server_manager.get_peer_node_ids().is_empty() || client_manager.get_peer_node_ids().is_empty() {
+				time::sleep(Duration::from_millis(10)).await;
+			}
+		}).await.unwrap();
+		assert_eq!(server_manager.get_peer_node_ids(), vec![client_pub]);
+		assert_eq!(client_manager.get_peer_node_ids(), vec![server_pub]);
+
+		// Disconnecting sends a close frame, ending the connection on both ends.
+		client_manager.disconnect_by_node_id(server_pub, false);
+		time::timeout(Duration::from_secs(10), async { tokio::join!(server_fut, client_fut) }).await.unwrap();
+		assert!(server_manager.get_peer_node_ids().is_empty());
+		assert!(client_manager.get_peer_node_ids().is_empty());
+	}
+}
@@ -0,0 +1,367 @@
+//! Utilities that handle persisting Rust-Lightning data to a single SQLite database file.
+//!
+//! [`SqliteStore`] implements [`KVStore`] and therefore [`KVStorePersister`], [`Persister`], and
+//! [`Persist`], allowing it to be used wherever a [`FilesystemPersister`] is used today. Unlike the
+//! latter, all data lives in one file, which is well suited for mobile targets and backup tooling.
+//!
+//! [`KVStorePersister`]: lightning::util::persist::KVStorePersister
+//! [`Persister`]: lightning::util::persist::Persister
+//! [`Persist`]: lightning::chain::chainmonitor::Persist
+//! [`FilesystemPersister`]: https://docs.rs/lightning-persister/*/lightning_persister/struct.FilesystemPersister.html
+
+#![deny(broken_intra_doc_links)]
+#![deny(missing_docs)]
+
+#![cfg_attr(docsrs, feature(doc_auto_cfg))]
+
+use bitcoin::hash_types::BlockHash;
+use lightning::chain::channelmonitor::ChannelMonitor;
+use lightning::chain::keysinterface::{Sign, KeysInterface};
+use lightning::util::persist::{self, KVStore};
+use rusqlite::{Connection, OptionalExtension, params};
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// The version of the database schema written by this crate.
+///
+/// Databases created with an older schema are migrated when opened, while those created with a
+/// newer schema are rejected.
+pub const SCHEMA_VERSION: u16 = 1;
+
+/// SqliteStore persists channel data in a SQLite database, where each value is stored in a single
+/// table keyed by its namespace and key.
+///
+/// Each write is performed in its own transaction, so a [`ChannelMonitor`] or
+/// [`ChannelMonitorUpdate`] is either persisted in its entirety or not at all, even if the process
+/// crashes or the device loses power mid-write. The database is opened with
+/// `PRAGMA synchronous = FULL` so that committed transactions survive power loss.
+///
+/// As with any persister, it is up to the user to validate their entire storage stack and to keep
+/// backups of the database, especially when dealing with larger amounts of money.
+///
+/// [`ChannelMonitorUpdate`]: lightning::chain::channelmonitor::ChannelMonitorUpdate
+pub struct SqliteStore {
+	connection: Mutex<Connection>,
+}
+
+impl SqliteStore {
+	/// Opens the database at `path`, creating it if it does not exist and migrating its schema to
+	/// [`SCHEMA_VERSION`] if needed.
+	pub fn new<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+		let connection = Connection::open(path).map_err(to_io_error)?;
+		Self::with_connection(connection)
+	}
+
+	/// Opens a database which lives only in memory. Useful for testing.
+	pub fn in_memory() -> std::io::Result<Self> {
+		let connection = Connection::open_in_memory().map_err(to_io_error)?;
+		Self::with_connection(connection)
+	}
+
+	fn with_connection(mut connection: Connection) -> std::io::Result<Self> {
+		connection.pragma_update(None, "synchronous", "FULL").map_err(to_io_error)?;
+		migrate(&mut connection)?;
+		Ok(Self { connection: Mutex::new(connection) })
+	}
+
+	/// Returns the schema version of the open database.
+	pub fn schema_version(&self) -> std::io::Result<u16> {
+		let connection = self.connection.lock().unwrap();
+		read_schema_version(&connection)?
+			.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Missing schema version"))
+	}
+
+	/// Read `ChannelMonitor`s from the database.
+	pub fn read_channelmonitors<Signer: Sign, K: Deref> (
+		&self, keys_manager: K
+	) -> Result<Vec<(BlockHash, ChannelMonitor<Signer>)>, std::io::Error>
+		where K::Target: KeysInterface<Signer=Signer> + Sized,
+	{
+		persist::read_channel_monitors(self, keys_manager)
+	}
+}
+
+impl KVStore for SqliteStore {
+	fn read(&self, namespace: &str, key: &str) -> std::io::Result<Vec<u8>> {
+		let connection = self.connection.lock().unwrap();
+		connection.query_row(
+			"SELECT value FROM kv_store WHERE namespace = ?1 AND key = ?2",
+			params![namespace, key],
+			|row| row.get(0),
+		).optional().map_err(to_io_error)?
+			.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "Key not found"))
+	}
+
+	fn write(&self, namespace: &str, key: &str, buf: &[u8]) -> std::io::Result<()> {
+		let mut connection = self.connection.lock().unwrap();
+		let transaction = connection.transaction().map_err(to_io_error)?;
+		transaction.execute(
+			"INSERT OR REPLACE INTO kv_store (namespace, key, value) VALUES (?1, ?2, ?3)",
+			params![namespace, key, buf],
+		).map_err(to_io_error)?;
+		transaction.commit().map_err(to_io_error)
+	}
+
+	fn remove(&self, namespace: &str, key: &str) -> std::io::Result<()> {
+		let connection = self.connection.lock().unwrap();
+		connection.execute(
+			"DELETE FROM kv_store WHERE namespace = ?1 AND key = ?2",
+			params![namespace, key],
+		).map_err(to_io_error)?;
+		Ok(())
+	}
+
+	fn list(&self, namespace: &str) -> std::io::Result<Vec<String>> {
+		let connection = self.connection.lock().unwrap();
+		let mut statement = connection.prepare("SELECT key FROM kv_store WHERE namespace = ?1")
+			.map_err(to_io_error)?;
+		let keys = statement.query_map(params![namespace], |row| row.get(0)).map_err(to_io_error)?;
+		keys.collect::<Result<Vec<String>, _>>().map_err(to_io_error)
+	}
+}
+
+fn to_io_error(e: rusqlite::Error) -> std::io::Error {
+	std::io::Error::new(std::io::ErrorKind::Other, e)
+}
+
+fn read_schema_version(connection: &Connection) -> std::io::Result<Option<u16>> {
+	let table_exists: bool = connection.query_row(
+		"SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'schema_version')",
+		[],
+		|row| row.get(0),
+	).map_err(to_io_error)?;
+	if !table_exists {
+		return Ok(None);
+	}
+	connection.query_row("SELECT version FROM schema_version", [], |row| row.get(0))
+		.optional()
+		.map_err(to_io_error)
+}
+
+/// Brings the database schema up to [`SCHEMA_VERSION`], applying each migration in its own
+/// transaction.
+fn migrate(connection: &mut Connection) -> std::io::Result<()> {
+	let mut version = read_schema_version(connection)?.unwrap_or(0);
+	if version > SCHEMA_VERSION {
+		return Err(std::io::Error::new(
+			std::io::ErrorKind::InvalidData,
+			"Database was created by a newer version of lightning-persister-sqlite",
+		));
+	}
+
+	while version < SCHEMA_VERSION {
+		let transaction = connection.transaction().map_err(to_io_error)?;
+		match version {
+			0 => {
+				transaction.execute_batch(
+					"CREATE TABLE schema_version (version INTEGER NOT NULL);
+					INSERT INTO schema_version (version) VALUES (0);
+					CREATE TABLE kv_store (
+						namespace TEXT NOT NULL,
+						key TEXT NOT NULL,
+						value BLOB NOT NULL,
+						PRIMARY KEY (namespace, key)
+					);"
+				).map_err(to_io_error)?;
+			},
+			_ => unreachable!(),
+		}
+		version += 1;
+		transaction.execute("UPDATE schema_version SET version = ?1", params![version])
+			.map_err(to_io_error)?;
+		transaction.commit().map_err(to_io_error)?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{SCHEMA_VERSION, SqliteStore};
+	use bitcoin::blockdata::block::{Block, BlockHeader};
+	use lightning::chain::ChannelMonitorUpdateErr;
+	use lightning::chain::chainmonitor::Persist;
+	use lightning::{check_closed_broadcast, check_closed_event, check_added_monitors};
+	use lightning::ln::features::InitFeatures;
+	use lightning::ln::functional_test_utils::*;
+	use lightning::util::events::{ClosureReason, MessageSendEventsProvider};
+	use lightning::util::persist::{KVStore, MonitorUpdatingPersister};
+	use lightning::util::test_utils;
+	use std::fs;
+
+	/// Removes the database file and its journals once the test is done.
+	struct TestDatabase(&'static str);
+
+	impl Drop for TestDatabase {
+		fn drop(&mut self) {
+			for suffix in &["", "-journal", "-wal", "-shm"] {
+				let _ = fs::remove_file(format!("{}{}", self.0, suffix));
+			}
+		}
+	}
+
+	#[test]
+	fn test_key_value_store() {
+		let store = SqliteStore::in_memory().unwrap();
+		assert_eq!(store.schema_version().unwrap(), SCHEMA_VERSION);
+		assert!(store.list("").unwrap().is_empty());
+		assert_eq!(store.read("", "manager").unwrap_err().kind(), std::io::ErrorKind::NotFound);
+
+		store.write("", "manager", &[42; 3]).unwrap();
+		store.write("monitors", "foo", &[43; 3]).unwrap();
+		store.write("monitors", "bar", &[44; 3]).unwrap();
+		store.write("monitors", "bar", &[45; 3]).unwrap();
+		assert_eq!(store.read("", "manager").unwrap(), vec![42; 3]);
+		assert_eq!(store.read("monitors", "bar").unwrap(), vec![45; 3]);
+		assert_eq!(store.list("").unwrap(), vec!["manager".to_string()]);
+		let mut keys = store.list("monitors").unwrap();
+		keys.sort();
+		assert_eq!(keys, vec!["bar".to_string(), "foo".to_string()]);
+
+		store.remove("monitors", "foo").unwrap();
+		store.remove("monitors", "foo").unwrap();
+		assert_eq!(store.read("monitors", "foo").unwrap_err().kind(), std::io::ErrorKind::NotFound);
+		assert_eq!(store.list("monitors").unwrap(), vec!["bar".to_string()]);
+	}
+
+	#[test]
+	fn test_reopen_database() {
+		let database = TestDatabase("test_reopen_database.sqlite");
+		{
+			let store = SqliteStore::new(database.0).unwrap();
+			store.write("monitors", "foo", &[42; 3]).unwrap();
+		}
+		let store = SqliteStore::new(database.0).unwrap();
+		assert_eq!(store.schema_version().unwrap(), SCHEMA_VERSION);
+		assert_eq!(store.read("monitors", "foo").unwrap(), vec![42; 3]);
+	}
+
+	#[test]
+	fn test_reject_newer_schema_version() {
+		let database = TestDatabase("test_reject_newer_schema_version.sqlite");
+		{
+			let store = SqliteStore::new(database.0).unwrap();
+			store.connection.lock().unwrap()
+				.execute("UPDATE schema_version SET version = ?1", [SCHEMA_VERSION + 1])
+				.unwrap();
+		}
+		match SqliteStore::new(database.0) {
+			Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidData),
+			Ok(_) => panic!("Expected error"),
+		}
+	}
+
+	// Integration-test the SqliteStore. Test relaying a few payments and check that the persisted
+	// data is updated the appropriate number of times, including after reopening the database.
+	#[test]
+	fn test_sqlite_store_persistence() {
+		let database_0 = TestDatabase("test_sqlite_store_persistence_0.sqlite");
+		let store_0 = SqliteStore::new(database_0.0).unwrap();
+		let store_1 = SqliteStore::in_memory().unwrap();
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let mut node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let chain_mon_0 = test_utils::TestChainMonitor::new(Some(&chanmon_cfgs[0].chain_source), &chanmon_cfgs[0].tx_broadcaster, &chanmon_cfgs[0].logger, &chanmon_cfgs[0].fee_estimator, &store_0, node_cfgs[0].keys_manager);
+		let chain_mon_1 = test_utils::TestChainMonitor::new(Some(&chanmon_cfgs[1].chain_source), &chanmon_cfgs[1].tx_broadcaster, &chanmon_cfgs[1].logger, &chanmon_cfgs[1].fee_estimator, &store_1, node_cfgs[1].keys_manager);
+		node_cfgs[0].chain_monitor = chain_mon_0;
+		node_cfgs[1].chain_monitor = chain_mon_1;
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+		assert!(store_0.read_channelmonitors(nodes[0].keys_manager).unwrap().is_empty());
+		assert!(store_1.read_channelmonitors(nodes[1].keys_manager).unwrap().is_empty());
+
+		// Helper to make sure the channel is on the expected update ID.
+		macro_rules! check_persisted_data {
+			($expected_update_id: expr) => {
+				for (store, node) in [(&store_0, &nodes[0]), (&store_1, &nodes[1])].iter() {
+					let persisted_chan_data = store.read_channelmonitors(node.keys_manager).unwrap();
+					assert_eq!(persisted_chan_data.len(), 1);
+					for (_, mon) in persisted_chan_data.iter() {
+						assert_eq!(mon.get_latest_update_id(), $expected_update_id);
+					}
+				}
+			}
+		}
+
+		let _ = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+		check_persisted_data!(0);
+
+		send_payment(&nodes[0], &vec!(&nodes[1])[..], 8000000);
+		check_persisted_data!(5);
+		send_payment(&nodes[1], &vec!(&nodes[0])[..], 4000000);
+		check_persisted_data!(10);
+
+		nodes[0].node.force_close_broadcasting_latest_txn(&nodes[0].node.list_channels()[0].channel_id, &nodes[1].node.get_our_node_id()).unwrap();
+		check_closed_event!(nodes[0], 1, ClosureReason::HolderForceClosed);
+		check_closed_broadcast!(nodes[0], true);
+		check_added_monitors!(nodes[0], 1);
+
+		let node_txn = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap();
+		assert_eq!(node_txn.len(), 1);
+
+		let header = BlockHeader { version: 0x20000000, prev_blockhash: nodes[0].best_block_hash(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+		connect_block(&nodes[1], &Block { header, txdata: vec![node_txn[0].clone(), node_txn[0].clone()]});
+		check_closed_broadcast!(nodes[1], true);
+		check_closed_event!(nodes[1], 1, ClosureReason::CommitmentTxConfirmed);
+		check_added_monitors!(nodes[1], 1);
+
+		check_persisted_data!(11);
+
+		// The monitor survives reopening the database.
+		let reopened_store = SqliteStore::new(database_0.0).unwrap();
+		let persisted_chan_data = reopened_store.read_channelmonitors(nodes[0].keys_manager).unwrap();
+		assert_eq!(persisted_chan_data.len(), 1);
+		assert_eq!(persisted_chan_data[0].1.get_latest_update_id(), 11);
+	}
+
+	// Test that monitor updates persisted incrementally are replayed from the database.
+	#[test]
+	fn test_sqlite_store_with_monitor_updates() {
+		let store = SqliteStore::in_memory().unwrap();
+		let persister = MonitorUpdatingPersister::new(&store, 4);
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let mut node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let chain_mon_0 = test_utils::TestChainMonitor::new(Some(&chanmon_cfgs[0].chain_source), &chanmon_cfgs[0].tx_broadcaster, &chanmon_cfgs[0].logger, &chanmon_cfgs[0].fee_estimator, &persister, node_cfgs[0].keys_manager);
+		node_cfgs[0].chain_monitor = chain_mon_0;
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+		let _ = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+		send_payment(&nodes[0], &vec!(&nodes[1])[..], 8000000);
+
+		assert_eq!(store.read_channelmonitors(nodes[0].keys_manager).unwrap()[0].1.get_latest_update_id(), 4);
+		let monitors = persister.read_channel_monitors(nodes[0].keys_manager, nodes[0].tx_broadcaster, &chanmon_cfgs[0].fee_estimator, nodes[0].logger).unwrap();
+		assert_eq!(monitors.len(), 1);
+		assert_eq!(monitors[0].1.get_latest_update_id(), 5);
+	}
+
+	// Test that if the database cannot be written to, persisting a monitor results in a
+	// PermanentFailure.
+	#[test]
+	fn test_readonly_database_perm_failure() {
+		let database = TestDatabase("test_readonly_database_perm_failure.sqlite");
+		let store = SqliteStore::new(database.0).unwrap();
+		store.connection.lock().unwrap().pragma_update(None, "query_only", true).unwrap();
+
+		// Set up a dummy channel and force close. This will produce a monitor that we can then use
+		// to test persistence.
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+		let chan = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+		nodes[1].node.force_close_broadcasting_latest_txn(&chan.2, &nodes[0].node.get_our_node_id()).unwrap();
+		check_closed_event!(nodes[1], 1, ClosureReason::HolderForceClosed);
+		let mut added_monitors = nodes[1].chain_monitor.added_monitors.lock().unwrap();
+		let update_map = nodes[1].chain_monitor.latest_monitor_update_id.lock().unwrap();
+		let update_id = update_map.get(&added_monitors[0].0.to_channel_id()).unwrap();
+
+		match store.persist_new_channel(added_monitors[0].0, &added_monitors[0].1, update_id.2) {
+			Err(ChannelMonitorUpdateErr::PermanentFailure) => {},
+			_ => panic!("unexpected result from persisting new channel")
+		}
+
+		nodes[1].node.get_and_clear_pending_msg_events();
+		added_monitors.clear();
+	}
+}
@@ -14,14 +14,12 @@ extern crate lightning;
 extern crate bitcoin;
 extern crate libc;
 
-use bitcoin::hash_types::{BlockHash, Txid};
-use bitcoin::hashes::hex::FromHex;
+use bitcoin::hash_types::BlockHash;
 use lightning::chain::channelmonitor::ChannelMonitor;
 use lightning::chain::keysinterface::{Sign, KeysInterface};
-use lightning::util::ser::{ReadableArgs, Writeable};
-use lightning::util::persist::KVStorePersister;
+use lightning::util::ser::{Writeable, Writer};
+use lightning::util::persist::{self, KVStore};
 use std::fs;
-use std::io::Cursor;
 use std::ops::Deref;
 use std::path::{Path, PathBuf};
 
@@ -37,6 +35,11 @@ use std::path::{Path, PathBuf};
 /// Corollary: especially when dealing with larger amounts of money, it is best
 /// practice to have multiple channel data backups and not rely only on one
 /// FilesystemPersister.
+///
+/// Data is written in plaintext. Wrap the persister in an [`EncryptedKVStore`] to encrypt it at
+/// rest.
+///
+/// [`EncryptedKVStore`]: lightning::util::persist::EncryptedKVStore
 pub struct FilesystemPersister {
 	path_to_channel_data: String,
 }
@@ -61,69 +64,66 @@ impl FilesystemPersister {
 	) -> Result<Vec<(BlockHash, ChannelMonitor<Signer>)>, std::io::Error>
 		where K::Target: KeysInterface<Signer=Signer> + Sized,
 	{
+		persist::read_channel_monitors(self, keys_manager)
+	}
+
+	fn path_to(&self, namespace: &str, key: &str) -> PathBuf {
 		let mut path = PathBuf::from(&self.path_to_channel_data);
-		path.push("monitors");
+		if !namespace.is_empty() {
+			path.push(namespace);
+		}
+		path.push(key);
+		path
+	}
+}
+
+/// Writes a byte slice as-is, without any length prefix.
+struct RawBytes<'a>(&'a [u8]);
+
+impl<'a> Writeable for RawBytes<'a> {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), std::io::Error> {
+		writer.write_all(self.0)
+	}
+}
+
+impl KVStore for FilesystemPersister {
+	fn read(&self, namespace: &str, key: &str) -> std::io::Result<Vec<u8>> {
+		fs::read(self.path_to(namespace, key))
+	}
+
+	fn write(&self, namespace: &str, key: &str, buf: &[u8]) -> std::io::Result<()> {
+		util::write_to_file(self.path_to(namespace, key), &RawBytes(buf))
+	}
+
+	fn remove(&self, namespace: &str, key: &str) -> std::io::Result<()> {
+		util::remove_file(self.path_to(namespace, key))
+	}
+
+	fn list(&self, namespace: &str) -> std::io::Result<Vec<String>> {
+		let mut path = PathBuf::from(&self.path_to_channel_data);
+		path.push(namespace);
 		if !Path::new(&path).exists() {
 			return Ok(Vec::new());
 		}
-		let mut res = Vec::new();
-		for file_option in fs::read_dir(path).unwrap() {
-			let file = file_option.unwrap();
-			let owned_file_name = file.file_name();
-			let filename = owned_file_name.to_str();
-			if !filename.is_some() || !filename.unwrap().is_ascii() || filename.unwrap().len() < 65 {
-				return Err(std::io::Error::new(
-					std::io::ErrorKind::InvalidData,
-					"Invalid ChannelMonitor file name",
-				));
+		let mut keys = Vec::new();
+		for entry in fs::read_dir(path)? {
+			let entry = entry?;
+			if !entry.file_type()?.is_file() {
+				continue;
 			}
-			if filename.unwrap().ends_with(".tmp") {
+			let key = match entry.file_name().into_string() {
+				Ok(key) => key,
+				Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid file name")),
+			};
+			if key.ends_with(".tmp") {
 				// If we were in the middle of committing an new update and crashed, it should be
 				// safe to ignore the update - we should never have returned to the caller and
 				// irrevocably committed to the new state in any way.
 				continue;
 			}
-
-			let txid = Txid::from_hex(filename.unwrap().split_at(64).0);
-			if txid.is_err() {
-				return Err(std::io::Error::new(
-					std::io::ErrorKind::InvalidData,
-					"Invalid tx ID in filename",
-				));
-			}
-
-			let index = filename.unwrap().split_at(65).1.parse();
-			if index.is_err() {
-				return Err(std::io::Error::new(
-					std::io::ErrorKind::InvalidData,
-					"Invalid tx index in filename",
-				));
-			}
-
-			let contents = fs::read(&file.path())?;
-			let mut buffer = Cursor::new(&contents);
-			match <(BlockHash, ChannelMonitor<Signer>)>::read(&mut buffer, &*keys_manager) {
-				Ok((blockhash, channel_monitor)) => {
-					if channel_monitor.get_funding_txo().0.txid != txid.unwrap() || channel_monitor.get_funding_txo().0.index != index.unwrap() {
-						return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "ChannelMonitor was stored in the wrong file"));
-					}
-					res.push((blockhash, channel_monitor));
-				}
-				Err(e) => return Err(std::io::Error::new(
-					std::io::ErrorKind::InvalidData,
-					format!("Failed to deserialize ChannelMonitor: {}", e),
-				))
-			}
+			keys.push(key);
 		}
-		Ok(res)
-	}
-}
-
-impl KVStorePersister for FilesystemPersister {
-	fn persist<W: Writeable>(&self, key: &str, object: &W) -> std::io::Result<()> {
-		let mut dest_file = PathBuf::from(self.path_to_channel_data.clone());
-		dest_file.push(key);
-		util::write_to_file(dest_file, object)
+		Ok(keys)
 	}
 }
 
@@ -142,6 +142,7 @@ mod tests {
 	use lightning::ln::features::InitFeatures;
 	use lightning::ln::functional_test_utils::*;
 	use lightning::util::events::{ClosureReason, MessageSendEventsProvider};
+	use lightning::util::persist::KVStore;
 	use lightning::util::test_utils;
 	use std::fs;
 	#[cfg(target_os = "windows")]
@@ -231,6 +232,31 @@ mod tests {
 		check_persisted_data!(11);
 	}
 
+	#[test]
+	fn test_key_value_store() {
+		let persister = FilesystemPersister::new("test_key_value_store".to_string());
+		assert!(persister.list("").unwrap().is_empty());
+		assert_eq!(persister.read("", "manager").unwrap_err().kind(), std::io::ErrorKind::NotFound);
+
+		persister.write("", "manager", &[42; 3]).unwrap();
+		persister.write("monitors", "foo", &[43; 3]).unwrap();
+		persister.write("monitors", "bar", &[44; 3]).unwrap();
+		assert_eq!(persister.read("", "manager").unwrap(), vec![42; 3]);
+		assert_eq!(persister.read("monitors", "foo").unwrap(), vec![43; 3]);
+
+		// Sub-directories and temporary files left behind by an interrupted write are not listed.
+		fs::write(persister.path_to("monitors", "baz.tmp"), [45; 3]).unwrap();
+		assert_eq!(persister.list("").unwrap(), vec!["manager".to_string()]);
+		let mut keys = persister.list("monitors").unwrap();
+		keys.sort();
+		assert_eq!(keys, vec!["bar".to_string(), "foo".to_string()]);
+
+		persister.remove("monitors", "foo").unwrap();
+		persister.remove("monitors", "foo").unwrap();
+		assert_eq!(persister.read("monitors", "foo").unwrap_err().kind(), std::io::ErrorKind::NotFound);
+		assert_eq!(persister.list("monitors").unwrap(), vec!["bar".to_string()]);
+	}
+
 	// Test that if the persister's path to channel data is read-only, writing a
 	// monitor to it results in the persister returning a PermanentFailure.
 	// Windows ignores the read-only flag for folders, so this test is Unix-only.
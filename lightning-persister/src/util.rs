@@ -77,6 +77,22 @@ pub(crate) fn write_to_file<W: Writeable>(dest_file: PathBuf, data: &W) -> std::
 	Ok(())
 }
 
+pub(crate) fn remove_file(dest_file: PathBuf) -> std::io::Result<()> {
+	match fs::remove_file(&dest_file) {
+		Ok(()) => {},
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+		Err(e) => return Err(e),
+	}
+	// Fsync the parent directory on Unix so the removal is persisted.
+	#[cfg(not(target_os = "windows"))]
+	{
+		let parent_directory = dest_file.parent().unwrap();
+		let dir_file = fs::OpenOptions::new().read(true).open(parent_directory)?;
+		unsafe { libc::fsync(dir_file.as_raw_fd()); }
+	}
+	Ok(())
+}
+
 #[cfg(test)]
 mod tests {
 	use lightning::util::ser::{Writer, Writeable};
@@ -24,6 +24,7 @@
 //! servicing [`ChannelMonitor`] updates from the client.
 
 use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::transaction::Transaction;
 use bitcoin::hash_types::Txid;
 
 use chain;
@@ -37,12 +38,21 @@ use util::logger::Logger;
 use util::errors::APIError;
 use util::events;
 use util::events::EventHandler;
-use ln::channelmanager::ChannelDetails;
+use ln::channelmanager::{ChannelDetails, PersistableUpdateFuture, PersistenceNotifier};
 
 use prelude::*;
-use sync::{RwLock, RwLockReadGuard, Mutex, MutexGuard};
+use sync::{Arc, Condvar, RwLock, RwLockReadGuard, Mutex, MutexGuard};
+use alloc::sync::Weak;
+use core::cmp;
+use core::future::Future;
 use core::ops::Deref;
+use core::pin::Pin;
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+#[cfg(any(test, feature = "std"))]
+use core::time::Duration;
+#[cfg(any(test, feature = "std"))]
+use std::time::Instant;
 use bitcoin::secp256k1::PublicKey;
 
 #[derive(Clone, Copy, Hash, PartialEq, Eq)]
@@ -81,17 +91,21 @@ impl MonitorUpdateId {
 ///    implementation should return `Ok(())`, indicating normal channel operation should continue.
 ///  * If persistence happens asynchronously, implementations should first ensure the
 ///    [`ChannelMonitor`] or [`ChannelMonitorUpdate`] are written durably to disk, and then return
-///    `Err(ChannelMonitorUpdateErr::TemporaryFailure)` while the update continues in the
+///    `Err(ChannelMonitorUpdateErr::InProgress)` while the update continues in the
 ///    background. Once the update completes, [`ChainMonitor::channel_monitor_updated`] should be
 ///    called with the corresponding [`MonitorUpdateId`].
 ///
 ///    Note that unlike the direct [`chain::Watch`] interface,
 ///    [`ChainMonitor::channel_monitor_updated`] must be called once for *each* update which occurs.
+///    See [`ReplicatingPersister`] for an implementation which persists to a local store and
+///    completes updates once they have been replicated to a remote one.
 ///
 ///  * If persistence fails for some reason, implementations should return
 ///    `Err(ChannelMonitorUpdateErr::PermanentFailure)`, in which case the channel will likely be
 ///    closed without broadcasting the latest state. See
 ///    [`ChannelMonitorUpdateErr::PermanentFailure`] for more details.
+///
+/// [`ReplicatingPersister`]: crate::util::persist::ReplicatingPersister
 pub trait Persist<ChannelSigner: Sign> {
 	/// Persist a new channel's data in response to a [`chain::Watch::watch_channel`] call. This is
 	/// called by [`ChannelManager`] for new channels, or may be called directly, e.g. on startup.
@@ -101,7 +115,7 @@ pub trait Persist<ChannelSigner: Sign> {
 	/// and the stored channel data). Note that you **must** persist every new monitor to disk.
 	///
 	/// The `update_id` is used to identify this call to [`ChainMonitor::channel_monitor_updated`],
-	/// if you return [`ChannelMonitorUpdateErr::TemporaryFailure`].
+	/// if you return [`ChannelMonitorUpdateErr::InProgress`].
 	///
 	/// See [`Writeable::write`] on [`ChannelMonitor`] for writing out a `ChannelMonitor`
 	/// and [`ChannelMonitorUpdateErr`] for requirements when returning errors.
@@ -136,7 +150,7 @@ pub trait Persist<ChannelSigner: Sign> {
 	/// whereas updates are small and `O(1)`.
 	///
 	/// The `update_id` is used to identify this call to [`ChainMonitor::channel_monitor_updated`],
-	/// if you return [`ChannelMonitorUpdateErr::TemporaryFailure`].
+	/// if you return [`ChannelMonitorUpdateErr::InProgress`].
 	///
 	/// See [`Writeable::write`] on [`ChannelMonitor`] for writing out a `ChannelMonitor`,
 	/// [`Writeable::write`] on [`ChannelMonitorUpdate`] for writing out an update, and
@@ -144,6 +158,21 @@ pub trait Persist<ChannelSigner: Sign> {
 	///
 	/// [`Writeable::write`]: crate::util::ser::Writeable::write
 	fn update_persisted_channel(&self, channel_id: OutPoint, update: &Option<ChannelMonitorUpdate>, data: &ChannelMonitor<ChannelSigner>, update_id: MonitorUpdateId) -> Result<(), ChannelMonitorUpdateErr>;
+
+	/// Archive a fully resolved channel's data in response to a
+	/// [`ChainMonitor::archive_fully_resolved_channel_monitors`] call, such that it is no longer
+	/// loaded on startup. The provided [`ChannelMonitor`] is the channel's latest state.
+	///
+	/// Rather than deleting the data outright, it should be moved somewhere it can be recovered
+	/// from, hedging against a bug in determining that the channel was fully resolved.
+	///
+	/// If an error is returned, the [`ChannelMonitor`] continues to be watched and archiving it is
+	/// retried on the next call to [`ChainMonitor::archive_fully_resolved_channel_monitors`].
+	///
+	/// The default implementation returns an error, such that monitors are never archived.
+	fn archive_persisted_channel(&self, _channel_id: OutPoint, _data: &ChannelMonitor<ChannelSigner>) -> Result<(), ()> {
+		Err(())
+	}
 }
 
 struct MonitorHolder<ChannelSigner: Sign> {
@@ -151,7 +180,7 @@ struct MonitorHolder<ChannelSigner: Sign> {
 	/// The full set of pending monitor updates for this Channel.
 	///
 	/// Note that this lock must be held during updates to prevent a race where we call
-	/// update_persisted_channel, the user returns a TemporaryFailure, and then calls
+	/// update_persisted_channel, the user returns an InProgress, and then calls
 	/// channel_monitor_updated immediately, racing our insertion of the pending update into the
 	/// contained Vec.
 	///
@@ -192,6 +221,94 @@ impl<ChannelSigner: Sign> MonitorHolder<ChannelSigner> {
 	}
 }
 
+/// The state shared between a [`ChainMonitor`] and the [`MonitorUpdateCompletion`]s for a single
+/// pending update.
+struct CompletionNotifier {
+	/// Whether the update has completed, along with the wakers of any tasks awaiting it.
+	state: Mutex<(bool, Vec<Waker>)>,
+	condvar: Condvar,
+}
+
+impl CompletionNotifier {
+	fn new(completed: bool) -> Self {
+		Self { state: Mutex::new((completed, Vec::new())), condvar: Condvar::new() }
+	}
+
+	fn complete(&self) {
+		let wakers = {
+			let mut state = self.state.lock().unwrap();
+			state.0 = true;
+			core::mem::take(&mut state.1)
+		};
+		self.condvar.notify_all();
+		for waker in wakers {
+			waker.wake();
+		}
+	}
+}
+
+/// A handle which completes once a specific [`Persist`] method call which returned
+/// [`ChannelMonitorUpdateErr::InProgress`] has been marked as completed through
+/// [`ChainMonitor::channel_monitor_updated`].
+///
+/// May be polled as a [`Future`] or, with the `std` feature, blocked on via [`wait`] or
+/// [`wait_timeout`]. Note that completion of a single update does not imply the channel has been
+/// restored to normal operation, which requires all pending updates for it to complete.
+///
+/// [`wait`]: Self::wait
+/// [`wait_timeout`]: Self::wait_timeout
+pub struct MonitorUpdateCompletion {
+	notifier: Arc<CompletionNotifier>,
+}
+
+impl MonitorUpdateCompletion {
+	/// Returns whether the update has completed.
+	pub fn is_complete(&self) -> bool {
+		self.notifier.state.lock().unwrap().0
+	}
+
+	/// Blocks until the update has completed.
+	#[cfg(any(test, feature = "std"))]
+	pub fn wait(&self) {
+		let mut state = self.notifier.state.lock().unwrap();
+		while !state.0 {
+			state = self.notifier.condvar.wait(state).unwrap();
+		}
+	}
+
+	/// Blocks until the update has completed or `max_wait` has elapsed, returning whether it has
+	/// completed.
+	#[cfg(any(test, feature = "std"))]
+	pub fn wait_timeout(&self, max_wait: Duration) -> bool {
+		let start_time = Instant::now();
+		let mut state = self.notifier.state.lock().unwrap();
+		while !state.0 {
+			// Due to spurious wakeups, re-check the elapsed time rather than relying on the result of
+			// `wait_timeout`.
+			let remaining = match max_wait.checked_sub(start_time.elapsed()) {
+				Some(remaining) => remaining,
+				None => return false,
+			};
+			state = self.notifier.condvar.wait_timeout(state, remaining).unwrap().0;
+		}
+		true
+	}
+}
+
+impl Future for MonitorUpdateCompletion {
+	type Output = ();
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+		let mut state = self.notifier.state.lock().unwrap();
+		if state.0 {
+			Poll::Ready(())
+		} else {
+			state.1.push(cx.waker().clone());
+			Poll::Pending
+		}
+	}
+}
+
 /// A read-only reference to a current ChannelMonitor.
 ///
 /// Note that this holds a mutex in [`ChainMonitor`] and may block other events until it is
@@ -239,6 +356,18 @@ pub struct ChainMonitor<ChannelSigner: Sign, C: Deref, T: Deref, F: Deref, L: De
 	pending_monitor_events: Mutex<Vec<(OutPoint, Vec<MonitorEvent>, Option<PublicKey>)>>,
 	/// The best block height seen, used as a proxy for the passage of time.
 	highest_chain_height: AtomicUsize,
+	/// Notifiers for pending updates for which a [`MonitorUpdateCompletion`] was requested. Only
+	/// weak references are held so that notifiers for dropped handles are freed, with the dangling
+	/// entries pruned as new handles are requested. Always locked after the relevant
+	/// [`MonitorHolder::pending_monitor_updates`].
+	update_completions: Mutex<HashMap<(OutPoint, MonitorUpdateId), Weak<CompletionNotifier>>>,
+	/// Notified when new events become available outside of the usual chain and update
+	/// processing, see [`ChainMonitor::get_update_future`].
+	event_notifier: PersistenceNotifier,
+	/// The number of events each [`ChannelMonitor`] contributed to the last call to
+	/// [`events::AsyncEventsProvider::get_pending_events`], in order, less those since marked
+	/// handled.
+	unhandled_event_counts: Mutex<Vec<(OutPoint, usize)>>,
 }
 
 impl<ChannelSigner: Sign, C: Deref, T: Deref, F: Deref, L: Deref, P: Deref> ChainMonitor<ChannelSigner, C, T, F, L, P>
@@ -289,7 +418,7 @@ where C::Target: chain::Filter,
 						if !monitor_state.has_pending_chainsync_updates(&pending_monitor_updates) {
 							// If there are not ChainSync persists awaiting completion, go ahead and
 							// set last_chain_persist_height here - we wouldn't want the first
-							// TemporaryFailure to always immediately be considered "overly delayed".
+							// InProgress to always immediately be considered "overly delayed".
 							monitor_state.last_chain_persist_height.store(height as usize, Ordering::Release);
 						}
 					}
@@ -302,7 +431,7 @@ where C::Target: chain::Filter,
 							monitor_state.channel_perm_failed.store(true, Ordering::Release);
 							self.pending_monitor_events.lock().unwrap().push((*funding_outpoint, vec![MonitorEvent::UpdateFailed(*funding_outpoint)], monitor.get_counterparty_node_id()));
 						},
-						Err(ChannelMonitorUpdateErr::TemporaryFailure) => {
+						Err(ChannelMonitorUpdateErr::InProgress) => {
 							log_debug!(self.logger, "Channel Monitor sync for channel {} in progress, holding events until completion!", log_funding_info!(monitor));
 							pending_monitor_updates.push(update_id);
 						},
@@ -359,9 +488,26 @@ where C::Target: chain::Filter,
 			persister,
 			pending_monitor_events: Mutex::new(Vec::new()),
 			highest_chain_height: AtomicUsize::new(0),
+			update_completions: Mutex::new(HashMap::new()),
+			event_notifier: PersistenceNotifier::new(),
+			unhandled_event_counts: Mutex::new(Vec::new()),
 		}
 	}
 
+	/// Gets a [`Future`] that completes when new events may be available to be handled, e.g.
+	/// after a transaction seen in the mempool via [`chain::MempoolListener`] revealed a payment
+	/// preimage.
+	///
+	/// Events from this `ChainMonitor` are surfaced through the [`ChannelManager`]'s event
+	/// handling, so the [`ChannelManager`] should process events once this completes. Use a
+	/// [`Sleeper`] to block on both this and the [`ChannelManager`] needing persistence at once.
+	///
+	/// [`ChannelManager`]: crate::ln::channelmanager::ChannelManager
+	/// [`Sleeper`]: crate::ln::channelmanager::Sleeper
+	pub fn get_update_future(&self) -> PersistableUpdateFuture<'_> {
+		self.event_notifier.get_future()
+	}
+
 	/// Gets the balances in the contained [`ChannelMonitor`]s which are claimable on-chain or
 	/// claims which are awaiting confirmation.
 	///
@@ -409,18 +555,107 @@ where C::Target: chain::Filter,
 		self.monitors.read().unwrap().keys().map(|outpoint| *outpoint).collect()
 	}
 
+	/// Lists the [`MonitorUpdateId`]s of the updates for each [`ChannelMonitor`] for which
+	/// [`ChannelMonitorUpdateErr::InProgress`] was returned and which have not yet been marked as
+	/// completed through [`channel_monitor_updated`].
+	///
+	/// [`channel_monitor_updated`]: Self::channel_monitor_updated
+	pub fn list_pending_monitor_updates(&self) -> HashMap<OutPoint, Vec<MonitorUpdateId>> {
+		self.monitors.read().unwrap().iter().map(|(funding_txo, monitor_state)| {
+			(*funding_txo, monitor_state.pending_monitor_updates.lock().unwrap().clone())
+		}).collect()
+	}
+
+	/// Gets a [`MonitorUpdateCompletion`] which completes once the given update for the
+	/// [`ChannelMonitor`] with the given funding outpoint is marked as completed through
+	/// [`channel_monitor_updated`]. If the update is not pending, e.g. because it has already
+	/// completed, the returned handle is already complete.
+	///
+	/// Returns an [`APIError::APIMisuseError`] if `funding_txo` does not match any currently
+	/// registered [`ChannelMonitor`]s.
+	///
+	/// [`channel_monitor_updated`]: Self::channel_monitor_updated
+	pub fn get_update_completion(&self, funding_txo: OutPoint, update_id: MonitorUpdateId) -> Result<MonitorUpdateCompletion, APIError> {
+		let monitors = self.monitors.read().unwrap();
+		let monitor_data = if let Some(mon) = monitors.get(&funding_txo) { mon } else {
+			return Err(APIError::APIMisuseError { err: format!("No ChannelMonitor matching funding outpoint {:?} found", funding_txo) });
+		};
+		let pending_monitor_updates = monitor_data.pending_monitor_updates.lock().unwrap();
+		let notifier = if pending_monitor_updates.contains(&update_id) {
+			let mut update_completions = self.update_completions.lock().unwrap();
+			match update_completions.get(&(funding_txo, update_id)).and_then(|notifier| notifier.upgrade()) {
+				Some(notifier) => notifier,
+				None => {
+					update_completions.retain(|_, notifier| notifier.strong_count() > 0);
+					let notifier = Arc::new(CompletionNotifier::new(false));
+					update_completions.insert((funding_txo, update_id), Arc::downgrade(&notifier));
+					notifier
+				},
+			}
+		} else {
+			Arc::new(CompletionNotifier::new(true))
+		};
+		Ok(MonitorUpdateCompletion { notifier })
+	}
+
+	/// Archives each [`ChannelMonitor`] which has been fully resolved on-chain, as determined by
+	/// [`ChannelMonitor::check_and_update_full_resolution_status`], via
+	/// [`Persist::archive_persisted_channel`]. Archived monitors are no longer watched: they are
+	/// removed from this `ChainMonitor` and their transactions and outputs are unregistered from the
+	/// [`chain::Filter`], if any.
+	///
+	/// As the resolution status of each monitor is updated here, this should be called regularly,
+	/// e.g. once per block or once a day. Monitors with pending updates or which fail to be archived
+	/// are kept and considered again on the next call.
+	///
+	/// Returns the funding outpoints of the archived monitors.
+	pub fn archive_fully_resolved_channel_monitors(&self) -> Vec<OutPoint> {
+		// Monitors are archived and removed under the same write lock, as chain syncs would otherwise
+		// be able to persist an archived monitor again before it is removed.
+		let mut monitors = self.monitors.write().unwrap();
+		let mut archived_monitors = Vec::new();
+		for (funding_txo, monitor_state) in monitors.iter() {
+			if !monitor_state.monitor.check_and_update_full_resolution_status(&self.logger) {
+				continue;
+			}
+			if !monitor_state.pending_monitor_updates.lock().unwrap().is_empty() {
+				continue;
+			}
+			if self.persister.archive_persisted_channel(*funding_txo, &monitor_state.monitor).is_err() {
+				log_error!(self.logger, "Failed to archive fully resolved ChannelMonitor for channel {}", log_funding_info!(monitor_state.monitor));
+				continue;
+			}
+			archived_monitors.push(*funding_txo);
+		}
+		if archived_monitors.is_empty() {
+			return archived_monitors;
+		}
+
+		for funding_txo in archived_monitors.iter() {
+			let monitor_state = monitors.remove(funding_txo).unwrap();
+			if let Some(ref chain_source) = self.chain_source {
+				monitor_state.monitor.unload_outputs_to_watch(chain_source);
+			}
+			log_info!(self.logger, "Archived fully resolved ChannelMonitor for channel {}", log_funding_info!(monitor_state.monitor));
+		}
+		self.update_completions.lock().unwrap().retain(|(funding_txo, _), _| !archived_monitors.contains(funding_txo));
+		archived_monitors
+	}
+
 	#[cfg(test)]
 	pub fn remove_monitor(&self, funding_txo: &OutPoint) -> ChannelMonitor<ChannelSigner> {
-		self.monitors.write().unwrap().remove(funding_txo).unwrap().monitor
+		let monitor = self.monitors.write().unwrap().remove(funding_txo).unwrap().monitor;
+		self.update_completions.lock().unwrap().retain(|(completion_funding_txo, _), _| completion_funding_txo != funding_txo);
+		monitor
 	}
 
 	/// Indicates the persistence of a [`ChannelMonitor`] has completed after
-	/// [`ChannelMonitorUpdateErr::TemporaryFailure`] was returned from an update operation.
+	/// [`ChannelMonitorUpdateErr::InProgress`] was returned from an update operation.
 	///
 	/// Thus, the anticipated use is, at a high level:
 	///  1) This [`ChainMonitor`] calls [`Persist::update_persisted_channel`] which stores the
 	///     update to disk and begins updating any remote (e.g. watchtower/backup) copies,
-	///     returning [`ChannelMonitorUpdateErr::TemporaryFailure`],
+	///     returning [`ChannelMonitorUpdateErr::InProgress`],
 	///  2) once all remote copies are updated, you call this function with the
 	///     `completed_update_id` that completed, and once all pending updates have completed the
 	///     channel will be re-enabled.
@@ -437,6 +672,9 @@ where C::Target: chain::Filter,
 		};
 		let mut pending_monitor_updates = monitor_data.pending_monitor_updates.lock().unwrap();
 		pending_monitor_updates.retain(|update_id| *update_id != completed_update_id);
+		if let Some(notifier) = self.update_completions.lock().unwrap().remove(&(funding_txo, completed_update_id)).and_then(|notifier| notifier.upgrade()) {
+			notifier.complete();
+		}
 
 		match completed_update_id {
 			MonitorUpdateId { contents: UpdateOrigin::OffChain(_) } => {
@@ -571,6 +809,29 @@ where
 	}
 }
 
+impl<ChannelSigner: Sign, C: Deref, T: Deref, F: Deref, L: Deref, P: Deref>
+chain::MempoolListener for ChainMonitor<ChannelSigner, C, T, F, L, P>
+where
+	C::Target: chain::Filter,
+	T::Target: BroadcasterInterface,
+	F::Target: FeeEstimator,
+	L::Target: Logger,
+	P::Target: Persist<ChannelSigner>,
+{
+	fn transactions_seen_in_mempool(&self, txdata: &[Transaction]) {
+		log_trace!(self.logger, "{} transactions provided as seen in the mempool", txdata.len());
+		let monitor_states = self.monitors.read().unwrap();
+		let mut new_events = false;
+		for monitor_state in monitor_states.values() {
+			new_events |= monitor_state.monitor.transactions_seen_in_mempool(
+				txdata, &*self.broadcaster, &*self.fee_estimator, &*self.logger);
+		}
+		if new_events {
+			self.event_notifier.notify();
+		}
+	}
+}
+
 impl<ChannelSigner: Sign, C: Deref , T: Deref , F: Deref , L: Deref , P: Deref >
 chain::Watch<ChannelSigner> for ChainMonitor<ChannelSigner, C, T, F, L, P>
 where C::Target: chain::Filter,
@@ -597,15 +858,17 @@ where C::Target: chain::Filter,
 		let update_id = MonitorUpdateId::from_new_monitor(&monitor);
 		let mut pending_monitor_updates = Vec::new();
 		let persist_res = self.persister.persist_new_channel(funding_outpoint, &monitor, update_id);
-		if persist_res.is_err() {
-			log_error!(self.logger, "Failed to persist new ChannelMonitor for channel {}: {:?}", log_funding_info!(monitor), persist_res);
-		} else {
-			log_trace!(self.logger, "Finished persisting new ChannelMonitor for channel {}", log_funding_info!(monitor));
-		}
-		if persist_res == Err(ChannelMonitorUpdateErr::PermanentFailure) {
-			return persist_res;
-		} else if persist_res.is_err() {
-			pending_monitor_updates.push(update_id);
+		match persist_res {
+			Ok(()) =>
+				log_trace!(self.logger, "Finished persisting new ChannelMonitor for channel {}", log_funding_info!(monitor)),
+			Err(ChannelMonitorUpdateErr::InProgress) => {
+				log_debug!(self.logger, "Persistence of new ChannelMonitor for channel {} in progress", log_funding_info!(monitor));
+				pending_monitor_updates.push(update_id);
+			},
+			Err(ChannelMonitorUpdateErr::PermanentFailure) => {
+				log_error!(self.logger, "Failed to persist new ChannelMonitor for channel {}", log_funding_info!(monitor));
+				return persist_res;
+			},
 		}
 		if let Some(ref chain_source) = self.chain_source {
 			monitor.load_outputs_to_watch(chain_source);
@@ -648,15 +911,17 @@ where C::Target: chain::Filter,
 				let update_id = MonitorUpdateId::from_monitor_update(&update);
 				let mut pending_monitor_updates = monitor_state.pending_monitor_updates.lock().unwrap();
 				let persist_res = self.persister.update_persisted_channel(funding_txo, &Some(update), monitor, update_id);
-				if let Err(e) = persist_res {
-					if e == ChannelMonitorUpdateErr::TemporaryFailure {
+				match persist_res {
+					Ok(()) =>
+						log_trace!(self.logger, "Finished persisting ChannelMonitor update for channel {}", log_funding_info!(monitor)),
+					Err(ChannelMonitorUpdateErr::InProgress) => {
+						log_debug!(self.logger, "Persistence of ChannelMonitor update for channel {} in progress", log_funding_info!(monitor));
 						pending_monitor_updates.push(update_id);
-					} else {
+					},
+					Err(ChannelMonitorUpdateErr::PermanentFailure) => {
+						log_error!(self.logger, "Failed to persist ChannelMonitor update for channel {}", log_funding_info!(monitor));
 						monitor_state.channel_perm_failed.store(true, Ordering::Release);
-					}
-					log_error!(self.logger, "Failed to persist ChannelMonitor update for channel {}: {:?}", log_funding_info!(monitor), e);
-				} else {
-					log_trace!(self.logger, "Finished persisting ChannelMonitor update for channel {}", log_funding_info!(monitor));
+					},
 				}
 				if update_res.is_err() {
 					Err(ChannelMonitorUpdateErr::PermanentFailure)
@@ -731,6 +996,49 @@ impl<ChannelSigner: Sign, C: Deref, T: Deref, F: Deref, L: Deref, P: Deref> even
 	}
 }
 
+impl<ChannelSigner: Sign, C: Deref, T: Deref, F: Deref, L: Deref, P: Deref> events::AsyncEventsProvider for ChainMonitor<ChannelSigner, C, T, F, L, P>
+	where C::Target: chain::Filter,
+	      T::Target: BroadcasterInterface,
+	      F::Target: FeeEstimator,
+	      L::Target: Logger,
+	      P::Target: Persist<ChannelSigner>,
+{
+	/// Gets the [`SpendableOutputs`] events produced from each [`ChannelMonitor`] upon maturity,
+	/// which remain in the [`ChannelMonitor`]s until marked handled.
+	///
+	/// [`SpendableOutputs`]: events::Event::SpendableOutputs
+	fn get_pending_events(&self) -> Vec<events::Event> {
+		let mut unhandled_event_counts = self.unhandled_event_counts.lock().unwrap();
+		unhandled_event_counts.clear();
+		let mut pending_events = Vec::new();
+		for (funding_txo, monitor_state) in self.monitors.read().unwrap().iter() {
+			let mut monitor_events = monitor_state.monitor.get_pending_events();
+			if !monitor_events.is_empty() {
+				unhandled_event_counts.push((*funding_txo, monitor_events.len()));
+				pending_events.append(&mut monitor_events);
+			}
+		}
+		pending_events
+	}
+
+	fn events_handled(&self, mut handled_count: usize) {
+		let monitors = self.monitors.read().unwrap();
+		let mut unhandled_event_counts = self.unhandled_event_counts.lock().unwrap();
+		while handled_count > 0 && !unhandled_event_counts.is_empty() {
+			let (funding_txo, ref mut event_count) = unhandled_event_counts[0];
+			let monitor_handled_count = cmp::min(handled_count, *event_count);
+			if let Some(monitor_state) = monitors.get(&funding_txo) {
+				monitor_state.monitor.remove_handled_events(monitor_handled_count);
+			}
+			handled_count -= monitor_handled_count;
+			*event_count -= monitor_handled_count;
+			if *event_count == 0 {
+				unhandled_event_counts.remove(0);
+			}
+		}
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use bitcoin::BlockHeader;
@@ -745,7 +1053,13 @@ mod tests {
 	use ln::msgs::ChannelMessageHandler;
 	use util::errors::APIError;
 	use util::events::{ClosureReason, MessageSendEvent, MessageSendEventsProvider};
-	use util::test_utils::{OnRegisterOutput, TxOutReference};
+	use util::test_utils::{OnRegisterOutput, TxOutReference, counting_waker};
+	use prelude::*;
+	use sync::Arc;
+	use core::future::Future;
+	use core::sync::atomic::{AtomicUsize, Ordering};
+	use core::task::{Context, Poll};
+	use core::time::Duration;
 
 	/// Tests that in-block dependent transactions are processed by `block_connected` when not
 	/// included in `txdata` but returned by [`chain::Filter::register_output`]. For instance,
@@ -806,7 +1120,7 @@ mod tests {
 		let (payment_preimage_2, payment_hash_2, _) = route_payment(&nodes[0], &[&nodes[1]], 1_000_000);
 
 		chanmon_cfgs[1].persister.offchain_monitor_updates.lock().unwrap().clear();
-		chanmon_cfgs[1].persister.set_update_ret(Err(ChannelMonitorUpdateErr::TemporaryFailure));
+		chanmon_cfgs[1].persister.set_update_ret(Err(ChannelMonitorUpdateErr::InProgress));
 
 		nodes[1].node.claim_funds(payment_preimage_1);
 		check_added_monitors!(nodes[1], 1);
@@ -822,13 +1136,36 @@ mod tests {
 		let (funding_txo, updates) = persistences.iter().next().unwrap();
 		assert_eq!(updates.len(), 2);
 
+		// Both updates are pending, and each may be awaited individually.
+		let pending_updates = nodes[1].chain_monitor.chain_monitor.list_pending_monitor_updates();
+		assert_eq!(pending_updates.get(funding_txo).unwrap().len(), 2);
+		let wake_count = Arc::new(AtomicUsize::new(0));
+		let waker = counting_waker(&wake_count);
+		// Handles which are dropped don't keep their notifier alive.
+		drop(nodes[1].chain_monitor.chain_monitor.get_update_completion(*funding_txo, *updates.iter().next().unwrap()).unwrap());
+		assert!(nodes[1].chain_monitor.chain_monitor.update_completions.lock().unwrap().values().all(|notifier| notifier.strong_count() == 0));
+		let mut completions: Vec<_> = updates.iter().map(|update_id| {
+			let mut completion = Box::pin(nodes[1].chain_monitor.chain_monitor.get_update_completion(*funding_txo, *update_id).unwrap());
+			assert_eq!(completion.as_mut().poll(&mut Context::from_waker(&waker)), Poll::Pending);
+			completion
+		}).collect();
+		assert_eq!(nodes[1].chain_monitor.chain_monitor.update_completions.lock().unwrap().len(), 2);
+
 		// Note that updates is a HashMap so the ordering here is actually random. This shouldn't
 		// fail either way but if it fails intermittently it's depending on the ordering of updates.
 		let mut update_iter = updates.iter();
 		nodes[1].chain_monitor.chain_monitor.channel_monitor_updated(*funding_txo, update_iter.next().unwrap().clone()).unwrap();
 		assert!(nodes[1].chain_monitor.release_pending_monitor_events().is_empty());
 		assert!(nodes[1].node.get_and_clear_pending_msg_events().is_empty());
+		assert_eq!(wake_count.load(Ordering::Acquire), 1);
+		assert_eq!(completions[0].as_mut().poll(&mut Context::from_waker(&waker)), Poll::Ready(()));
+		assert!(!completions[1].is_complete());
 		nodes[1].chain_monitor.chain_monitor.channel_monitor_updated(*funding_txo, update_iter.next().unwrap().clone()).unwrap();
+		assert_eq!(wake_count.load(Ordering::Acquire), 2);
+		assert!(completions[1].wait_timeout(Duration::from_secs(0)));
+		assert!(nodes[1].chain_monitor.chain_monitor.list_pending_monitor_updates().get(funding_txo).unwrap().is_empty());
+		assert!(nodes[1].chain_monitor.chain_monitor.get_update_completion(*funding_txo, *updates.iter().next().unwrap()).unwrap().is_complete());
+		assert!(nodes[1].chain_monitor.chain_monitor.update_completions.lock().unwrap().is_empty());
 
 		// Now manually walk the commitment signed dance - because we claimed two payments
 		// back-to-back it doesn't fit into the neat walk commitment_signed_dance does.
@@ -893,7 +1230,7 @@ mod tests {
 
 		// Temp-fail the block connection which will hold the channel-closed event
 		chanmon_cfgs[0].persister.chain_sync_monitor_persistences.lock().unwrap().clear();
-		chanmon_cfgs[0].persister.set_update_ret(Err(ChannelMonitorUpdateErr::TemporaryFailure));
+		chanmon_cfgs[0].persister.set_update_ret(Err(ChannelMonitorUpdateErr::InProgress));
 
 		// Connect B's commitment transaction, but only to the ChainMonitor/ChannelMonitor. The
 		// channel is now closed, but the ChannelManager doesn't know that yet.
@@ -69,7 +69,7 @@ pub struct ChannelMonitorUpdate {
 	/// increasing and increase by one for each new update, with one exception specified below.
 	///
 	/// This sequence number is also used to track up to which points updates which returned
-	/// ChannelMonitorUpdateErr::TemporaryFailure have been applied to all copies of a given
+	/// ChannelMonitorUpdateErr::InProgress have been applied to all copies of a given
 	/// ChannelMonitor when ChannelManager::channel_monitor_updated is called.
 	///
 	/// The only instance where update_id values are not strictly increasing is the case where we
@@ -125,9 +125,9 @@ pub enum MonitorEvent {
 	CommitmentTxConfirmed(OutPoint),
 
 	/// Indicates a [`ChannelMonitor`] update has completed. See
-	/// [`ChannelMonitorUpdateErr::TemporaryFailure`] for more information on how this is used.
+	/// [`ChannelMonitorUpdateErr::InProgress`] for more information on how this is used.
 	///
-	/// [`ChannelMonitorUpdateErr::TemporaryFailure`]: super::ChannelMonitorUpdateErr::TemporaryFailure
+	/// [`ChannelMonitorUpdateErr::InProgress`]: super::ChannelMonitorUpdateErr::InProgress
 	UpdateCompleted {
 		/// The funding outpoint of the [`ChannelMonitor`] that was updated
 		funding_txo: OutPoint,
@@ -208,6 +208,17 @@ pub(crate) const LATENCY_GRACE_PERIOD_BLOCKS: u32 = 3;
 // solved by a previous claim tx. What we want to avoid is reorg evicting our claim tx and us not
 // keep bumping another claim tx to solve the outpoint.
 pub const ANTI_REORG_DELAY: u32 = 6;
+/// Number of blocks a [`ChannelMonitor`] must have had no claimable balances for before it is
+/// considered fully resolved and may be archived, see
+/// [`ChannelMonitor::check_and_update_full_resolution_status`].
+///
+/// This is roughly four weeks worth of blocks, well beyond any reorganization we expect to handle,
+/// giving us ample time to notice a counterparty broadcasting a stale state.
+pub const ARCHIVAL_DELAY_BLOCKS: u32 = 4032;
+/// Number of blocks after which we forget about an unconfirmed transaction we acted upon in
+/// [`ChannelMonitor::transactions_seen_in_mempool`], matching the default two week mempool expiry
+/// of Bitcoin Core. Should the transaction still be around, acting upon it again is harmless.
+const MEMPOOL_TXID_EXPIRY_BLOCKS: u32 = 2016;
 /// Number of blocks before confirmation at which we fail back an un-relayed HTLC or at which we
 /// refuse to accept a new HTLC.
 ///
@@ -626,8 +637,10 @@ pub(crate) struct ChannelMonitorImpl<Signer: Sign> {
 
 	commitment_secrets: CounterpartyCommitmentSecrets,
 	/// The set of outpoints in each counterparty commitment transaction. We always need at least
-	/// the payment hash from `HTLCOutputInCommitment` to claim even a revoked commitment
-	/// transaction broadcast as we need to be able to construct the witness script in all cases.
+	/// the payment hash from `HTLCOutputInCommitment` to claim the HTLC outputs of even a revoked
+	/// commitment transaction broadcast as we need to be able to construct the witness script in
+	/// all cases. Pruned once a funding spend has irrevocably confirmed, see
+	/// `prune_counterparty_commitment_data`.
 	counterparty_claimable_outpoints: HashMap<Txid, Vec<(HTLCOutputInCommitment, Option<Box<HTLCSource>>)>>,
 	/// We cannot identify HTLC-Success or HTLC-Timeout transactions by themselves on the chain.
 	/// Nor can we figure out their commitment numbers without the commitment transaction they are
@@ -637,7 +650,7 @@ pub(crate) struct ChannelMonitorImpl<Signer: Sign> {
 	counterparty_commitment_txn_on_chain: HashMap<Txid, u64>,
 	/// Cache used to make pruning of payment_preimages faster.
 	/// Maps payment_hash values to commitment numbers for counterparty transactions for non-revoked
-	/// counterparty transactions (ie should remain pretty small).
+	/// counterparty transactions (ie should remain pretty small, see `provide_secret`).
 	/// Serialized to disk but should generally not be sent to Watchtowers.
 	counterparty_hash_commitment_number: HashMap<PaymentHash, u64>,
 
@@ -715,6 +728,25 @@ pub(crate) struct ChannelMonitorImpl<Signer: Sign> {
 	/// spending CSV for revocable outputs).
 	htlcs_resolved_on_chain: Vec<IrrevocablyResolvedHTLC>,
 
+	/// The height at which we first noticed [`ChannelMonitor::get_claimable_balances`] returning
+	/// no balances after the funding output was spent, reset if balances re-appear (e.g. on
+	/// reorg).
+	balances_empty_height: Option<u32>,
+
+	/// The txids of unconfirmed transactions we've already acted upon in
+	/// `transactions_seen_in_mempool`, along with the best block height at the time, so that
+	/// seeing them again is a no-op. Only transactions relevant to this channel are tracked, and
+	/// entries are dropped once the transaction confirms or after `MEMPOOL_TXID_EXPIRY_BLOCKS`.
+	/// This is not persisted as acting on a transaction again after a restart is harmless.
+	#[cfg(test)]
+	pub mempool_txids_handled: HashMap<Txid, u32>,
+	#[cfg(not(test))]
+	mempool_txids_handled: HashMap<Txid, u32>,
+	/// The outbound HTLCs we failed back in `transactions_seen_in_mempool`, so that we don't fail
+	/// them back again once the commitment transaction confirms. Not persisted as the
+	/// `ChannelManager` ignores failures for HTLCs it no longer knows about.
+	htlcs_failed_back_from_mempool: HashSet<HTLCSource>,
+
 	// We simply modify best_block in Channel's block_connected so that serialization is
 	// consistent but hopefully the users' copy handles block_connected in a consistent way.
 	// (we do *not*, however, update them in update_monitor to ensure any local user copies keep
@@ -779,7 +811,8 @@ impl<Signer: Sign> PartialEq for ChannelMonitorImpl<Signer> {
 			self.holder_tx_signed != other.holder_tx_signed ||
 			self.funding_spend_seen != other.funding_spend_seen ||
 			self.funding_spend_confirmed != other.funding_spend_confirmed ||
-			self.htlcs_resolved_on_chain != other.htlcs_resolved_on_chain
+			self.htlcs_resolved_on_chain != other.htlcs_resolved_on_chain ||
+			self.balances_empty_height != other.balances_empty_height
 		{
 			false
 		} else {
@@ -958,6 +991,7 @@ impl<Signer: Sign> Writeable for ChannelMonitorImpl<Signer> {
 			(5, self.pending_monitor_events, vec_type),
 			(7, self.funding_spend_seen, required),
 			(9, self.counterparty_node_id, option),
+			(11, self.balances_empty_height, option),
 		});
 
 		Ok(())
@@ -1065,6 +1099,9 @@ impl<Signer: Sign> ChannelMonitor<Signer> {
 			funding_spend_seen: false,
 			funding_spend_confirmed: None,
 			htlcs_resolved_on_chain: Vec::new(),
+			balances_empty_height: None,
+			mempool_txids_handled: HashMap::new(),
+			htlcs_failed_back_from_mempool: HashSet::new(),
 
 			best_block,
 			counterparty_node_id: Some(counterparty_node_id),
@@ -1186,6 +1223,26 @@ impl<Signer: Sign> ChannelMonitor<Signer> {
 		}
 	}
 
+	/// Unregisters the transactions and outputs previously registered by [`load_outputs_to_watch`]
+	/// with the given [`chain::Filter`], used once this monitor has been fully resolved and no
+	/// longer needs to be watched on-chain.
+	///
+	/// [`load_outputs_to_watch`]: Self::load_outputs_to_watch
+	pub fn unload_outputs_to_watch<F: Deref>(&self, filter: &F) where F::Target: chain::Filter {
+		let lock = self.inner.lock().unwrap();
+		filter.unregister_tx(&lock.get_funding_txo().0.txid, &lock.get_funding_txo().1);
+		for (txid, outputs) in lock.get_outputs_to_watch().iter() {
+			for (index, script_pubkey) in outputs.iter() {
+				assert!(*index <= u16::max_value() as u32);
+				filter.unregister_output(&WatchedOutput {
+					block_hash: None,
+					outpoint: OutPoint { txid: *txid, index: *index as u16 },
+					script_pubkey: script_pubkey.clone(),
+				});
+			}
+		}
+	}
+
 	/// Get the list of HTLCs who's status has been updated on chain. This should be called by
 	/// ChannelManager via [`chain::Watch::release_pending_monitor_events`].
 	pub fn get_and_clear_pending_monitor_events(&self) -> Vec<MonitorEvent> {
@@ -1202,6 +1259,26 @@ impl<Signer: Sign> ChannelMonitor<Signer> {
 		self.inner.lock().unwrap().get_and_clear_pending_events()
 	}
 
+	/// Gets the pending events without clearing them, see [`Self::remove_handled_events`].
+	pub(crate) fn get_pending_events(&self) -> Vec<Event> {
+		self.inner.lock().unwrap().pending_events.clone()
+	}
+
+	/// Removes the first `handled_count` pending events, which were returned by
+	/// [`Self::get_pending_events`] and have since been handled.
+	pub(crate) fn remove_handled_events(&self, handled_count: usize) {
+		let mut inner = self.inner.lock().unwrap();
+		let handled_count = cmp::min(handled_count, inner.pending_events.len());
+		inner.pending_events.drain(..handled_count);
+	}
+
+	/// Returns whether there are any events pending to be returned via
+	/// [`Self::get_and_clear_pending_monitor_events`] or [`Self::get_and_clear_pending_events`].
+	pub(crate) fn has_pending_events(&self) -> bool {
+		let inner = self.inner.lock().unwrap();
+		!inner.pending_monitor_events.is_empty() || !inner.pending_events.is_empty()
+	}
+
 	pub(crate) fn get_min_seen_secret(&self) -> u64 {
 		self.inner.lock().unwrap().get_min_seen_secret()
 	}
@@ -1336,6 +1413,44 @@ impl<Signer: Sign> ChannelMonitor<Signer> {
 			txid, broadcaster, &bounded_fee_estimator, logger);
 	}
 
+	/// Processes transactions which have been broadcast but not yet confirmed, e.g., as seen in the
+	/// mempool. See [`chain::MempoolListener`] for calling expectations.
+	///
+	/// Only actions which remain safe if the transaction never confirms are taken:
+	///  * A payment preimage revealed by a transaction spending an HTLC output we forwarded or sent
+	///    is returned via [`get_and_clear_pending_monitor_events`], allowing the HTLC to be claimed
+	///    upstream without waiting for a confirmation.
+	///  * For a revoked counterparty commitment transaction, justice transactions claiming its
+	///    outputs are broadcast immediately, as they are simply invalid if it never confirms.
+	///  * For any commitment transaction, once the channel has been closed on our side, outbound
+	///    HTLCs which have no output in any of our or our counterparty's valid commitment
+	///    transactions are failed back via [`get_and_clear_pending_monitor_events`], as they can no
+	///    longer be claimed either on-chain or off-chain.
+	///
+	/// Everything else, including tracking and fee-bumping claims, still requires the transaction
+	/// to be given via [`chain::Listen`] or [`chain::Confirm`] once confirmed. Transactions which
+	/// were acted upon are ignored if seen again.
+	///
+	/// Returns whether new events are available via [`get_and_clear_pending_monitor_events`].
+	///
+	/// [`get_and_clear_pending_monitor_events`]: Self::get_and_clear_pending_monitor_events
+	pub fn transactions_seen_in_mempool<B: Deref, F: Deref, L: Deref>(
+		&self,
+		txdata: &[Transaction],
+		broadcaster: B,
+		fee_estimator: F,
+		logger: L,
+	) -> bool
+	where
+		B::Target: BroadcasterInterface,
+		F::Target: FeeEstimator,
+		L::Target: Logger,
+	{
+		let bounded_fee_estimator = LowerBoundedFeeEstimator::new(fee_estimator);
+		self.inner.lock().unwrap().transactions_seen_in_mempool(
+			txdata, &broadcaster, &bounded_fee_estimator, &logger)
+	}
+
 	/// Updates the monitor with the current best chain tip, returning new outputs to watch. See
 	/// [`block_connected`] for details.
 	///
@@ -1380,6 +1495,38 @@ impl<Signer: Sign> ChannelMonitor<Signer> {
 		self.inner.lock().unwrap().best_block.clone()
 	}
 
+	/// Checks whether this monitor is fully resolved, i.e. its funding output has been spent and
+	/// [`get_claimable_balances`] has returned no balances for at least [`ARCHIVAL_DELAY_BLOCKS`].
+	///
+	/// The height at which balances were first found empty is tracked (and persisted) by the
+	/// monitor itself, so this should be called periodically, e.g. once per block. Once it returns
+	/// true the monitor no longer needs to be watched on-chain and may be archived, see
+	/// [`ChainMonitor::archive_fully_resolved_channel_monitors`].
+	///
+	/// [`get_claimable_balances`]: Self::get_claimable_balances
+	/// [`ChainMonitor::archive_fully_resolved_channel_monitors`]: crate::chain::chainmonitor::ChainMonitor::archive_fully_resolved_channel_monitors
+	pub fn check_and_update_full_resolution_status<L: Deref>(&self, logger: &L) -> bool where L::Target: Logger {
+		let balances_empty = self.get_claimable_balances().is_empty();
+		let mut inner = self.inner.lock().unwrap();
+		let current_height = inner.best_block.height();
+		match (inner.balances_empty_height, balances_empty && inner.funding_spend_seen) {
+			(Some(balances_empty_height), true) => {
+				current_height >= balances_empty_height.saturating_add(ARCHIVAL_DELAY_BLOCKS)
+			},
+			(Some(_), false) => {
+				log_info!(logger, "Claimable balances re-appeared for channel {}, no longer considering it for archival", log_funding_info!(inner));
+				inner.balances_empty_height = None;
+				false
+			},
+			(None, true) => {
+				log_info!(logger, "Channel {} has no more claimable balances, it will be archivable in {} blocks", log_funding_info!(inner), ARCHIVAL_DELAY_BLOCKS);
+				inner.balances_empty_height = Some(current_height);
+				false
+			},
+			(None, false) => false,
+		}
+	}
+
 	/// Gets the balances in this channel which are either claimable by us if we were to
 	/// force-close the channel now or which are claimable on-chain (possibly awaiting
 	/// confirmation).
@@ -1783,11 +1930,15 @@ impl<Signer: Sign> ChannelMonitorImpl<Signer> {
 			}
 		}
 
+		// Payment hashes only included in revoked counterparty commitment transactions are no longer
+		// needed, see `counterparty_hash_commitment_number`.
+		let min_idx = self.get_min_seen_secret();
+		self.counterparty_hash_commitment_number.retain(|_, commitment_number| *commitment_number < min_idx);
+
 		if !self.payment_preimages.is_empty() {
 			let cur_holder_signed_commitment_tx = &self.current_holder_commitment_tx;
 			let prev_holder_signed_commitment_tx = self.prev_holder_signed_commitment_tx.as_ref();
-			let min_idx = self.get_min_seen_secret();
-			let counterparty_hash_commitment_number = &mut self.counterparty_hash_commitment_number;
+			let counterparty_hash_commitment_number = &self.counterparty_hash_commitment_number;
 
 			self.payment_preimages.retain(|&k, _| {
 				for &(ref htlc, _, _) in cur_holder_signed_commitment_tx.htlc_outputs.iter() {
@@ -1802,22 +1953,31 @@ impl<Signer: Sign> ChannelMonitorImpl<Signer> {
 						}
 					}
 				}
-				let contains = if let Some(cn) = counterparty_hash_commitment_number.get(&k) {
-					if *cn < min_idx {
-						return true
-					}
-					true
-				} else { false };
-				if contains {
-					counterparty_hash_commitment_number.remove(&k);
-				}
-				false
+				counterparty_hash_commitment_number.contains_key(&k)
 			});
 		}
 
 		Ok(())
 	}
 
+	/// Drops the per-HTLC data of all counterparty commitment transactions other than the current,
+	/// previous and confirmed ones once a funding spend has irrevocably confirmed (ie reached
+	/// `ANTI_REORG_DELAY` confirmations), as no other commitment transaction can confirm anymore.
+	///
+	/// Until then the data of every revoked commitment transaction is kept, as we need it to claim
+	/// its HTLC outputs should it be broadcast.
+	fn prune_counterparty_commitment_data(&mut self) {
+		let funding_spend_confirmed = match self.funding_spend_confirmed {
+			Some(txid) => txid,
+			None => return,
+		};
+		let current_txid = self.current_counterparty_commitment_txid;
+		let prev_txid = self.prev_counterparty_commitment_txid;
+		self.counterparty_claimable_outpoints.retain(|txid, _| {
+			*txid == funding_spend_confirmed || Some(*txid) == current_txid || Some(*txid) == prev_txid
+		});
+	}
+
 	pub(crate) fn provide_latest_counterparty_commitment_tx<L: Deref>(&mut self, txid: Txid, htlc_outputs: Vec<(HTLCOutputInCommitment, Option<Box<HTLCSource>>)>, commitment_number: u64, their_per_commitment_point: PublicKey, logger: &L) where L::Target: Logger {
 		// TODO: Encrypt the htlc_outputs data with the single-hash of the commitment transaction
 		// so that a remote monitor doesn't learn anything unless there is a malicious close.
@@ -2089,56 +2249,19 @@ impl<Signer: Sign> ChannelMonitorImpl<Signer> {
 	/// Return updates for HTLC pending in the channel and failed automatically by the broadcast of
 	/// revoked counterparty commitment tx
 	fn check_spend_counterparty_transaction<L: Deref>(&mut self, tx: &Transaction, height: u32, logger: &L) -> (Vec<PackageTemplate>, TransactionOutputs) where L::Target: Logger {
-		// Most secp and related errors trying to create keys means we have no hope of constructing
-		// a spend transaction...so we return no transactions to broadcast
 		let mut claimable_outpoints = Vec::new();
 		let mut watch_outputs = Vec::new();
 
 		let commitment_txid = tx.txid(); //TODO: This is gonna be a performance bottleneck for watchtowers!
 		let per_commitment_option = self.counterparty_claimable_outpoints.get(&commitment_txid);
 
-		macro_rules! ignore_error {
-			( $thing : expr ) => {
-				match $thing {
-					Ok(a) => a,
-					Err(_) => return (claimable_outpoints, (commitment_txid, watch_outputs))
-				}
-			};
-		}
-
-		let commitment_number = 0xffffffffffff - ((((tx.input[0].sequence as u64 & 0xffffff) << 3*8) | (tx.lock_time as u64 & 0xffffff)) ^ self.commitment_transaction_number_obscure_factor);
+		let commitment_number = self.get_commitment_number(tx);
 		if commitment_number >= self.get_min_seen_secret() {
-			let secret = self.get_secret(commitment_number).unwrap();
-			let per_commitment_key = ignore_error!(SecretKey::from_slice(&secret));
-			let per_commitment_point = PublicKey::from_secret_key(&self.secp_ctx, &per_commitment_key);
-			let revocation_pubkey = ignore_error!(chan_utils::derive_public_revocation_key(&self.secp_ctx, &per_commitment_point, &self.holder_revocation_basepoint));
-			let delayed_key = ignore_error!(chan_utils::derive_public_key(&self.secp_ctx, &PublicKey::from_secret_key(&self.secp_ctx, &per_commitment_key), &self.counterparty_commitment_params.counterparty_delayed_payment_base_key));
-
-			let revokeable_redeemscript = chan_utils::get_revokeable_redeemscript(&revocation_pubkey, self.counterparty_commitment_params.on_counterparty_tx_csv, &delayed_key);
-			let revokeable_p2wsh = revokeable_redeemscript.to_v0_p2wsh();
-
-			// First, process non-htlc outputs (to_holder & to_counterparty)
-			for (idx, outp) in tx.output.iter().enumerate() {
-				if outp.script_pubkey == revokeable_p2wsh {
-					let revk_outp = RevokedOutput::build(per_commitment_point, self.counterparty_commitment_params.counterparty_delayed_payment_base_key, self.counterparty_commitment_params.counterparty_htlc_base_key, per_commitment_key, outp.value, self.counterparty_commitment_params.on_counterparty_tx_csv);
-					let justice_package = PackageTemplate::build_package(commitment_txid, idx as u32, PackageSolvingData::RevokedOutput(revk_outp), height + self.counterparty_commitment_params.on_counterparty_tx_csv as u32, true, height);
-					claimable_outpoints.push(justice_package);
-				}
-			}
-
-			// Then, try to find revoked htlc outputs
-			if let Some(ref per_commitment_data) = per_commitment_option {
-				for (_, &(ref htlc, _)) in per_commitment_data.iter().enumerate() {
-					if let Some(transaction_output_index) = htlc.transaction_output_index {
-						if transaction_output_index as usize >= tx.output.len() ||
-								tx.output[transaction_output_index as usize].value != htlc.amount_msat / 1000 {
-							return (claimable_outpoints, (commitment_txid, watch_outputs)); // Corrupted per_commitment_data, fuck this user
-						}
-						let revk_htlc_outp = RevokedHTLCOutput::build(per_commitment_point, self.counterparty_commitment_params.counterparty_delayed_payment_base_key, self.counterparty_commitment_params.counterparty_htlc_base_key, per_commitment_key, htlc.amount_msat / 1000, htlc.clone(), self.onchain_tx_handler.channel_transaction_parameters.opt_anchors.is_some());
-						let justice_package = PackageTemplate::build_package(commitment_txid, transaction_output_index, PackageSolvingData::RevokedHTLCOutput(revk_htlc_outp), htlc.cltv_expiry, true, height);
-						claimable_outpoints.push(justice_package);
-					}
-				}
+			let (revoked_claims, consistent) =
+				self.get_revoked_counterparty_claim_reqs(tx, commitment_txid, commitment_number, height);
+			claimable_outpoints = revoked_claims;
+			if !consistent {
+				return (claimable_outpoints, (commitment_txid, watch_outputs));
 			}
 
 			// Last, track onchain revoked commitment transaction and fail backward outgoing HTLCs as payment path is broken
@@ -2189,6 +2312,65 @@ impl<Signer: Sign> ChannelMonitorImpl<Signer> {
 		(claimable_outpoints, (commitment_txid, watch_outputs))
 	}
 
+	/// Gets the commitment number encoded in the obscured locktime and sequence of a commitment
+	/// transaction.
+	fn get_commitment_number(&self, tx: &Transaction) -> u64 {
+		0xffffffffffff - ((((tx.input[0].sequence as u64 & 0xffffff) << 3*8) | (tx.lock_time as u64 & 0xffffff)) ^ self.commitment_transaction_number_obscure_factor)
+	}
+
+	/// Builds the requests claiming the outputs of a revoked counterparty commitment transaction
+	/// with the given commitment number, for which we must have the revocation secret. The returned
+	/// bool is false if keys could not be derived or our per-commitment data doesn't match the
+	/// transaction, in which case the requests may be incomplete.
+	fn get_revoked_counterparty_claim_reqs(&self, tx: &Transaction, commitment_txid: Txid, commitment_number: u64, height: u32) -> (Vec<PackageTemplate>, bool) {
+		// Most secp and related errors trying to create keys means we have no hope of constructing
+		// a spend transaction...so we return no transactions to broadcast
+		let mut claimable_outpoints = Vec::new();
+
+		macro_rules! ignore_error {
+			( $thing : expr ) => {
+				match $thing {
+					Ok(a) => a,
+					Err(_) => return (claimable_outpoints, false)
+				}
+			};
+		}
+
+		let secret = self.get_secret(commitment_number).unwrap();
+		let per_commitment_key = ignore_error!(SecretKey::from_slice(&secret));
+		let per_commitment_point = PublicKey::from_secret_key(&self.secp_ctx, &per_commitment_key);
+		let revocation_pubkey = ignore_error!(chan_utils::derive_public_revocation_key(&self.secp_ctx, &per_commitment_point, &self.holder_revocation_basepoint));
+		let delayed_key = ignore_error!(chan_utils::derive_public_key(&self.secp_ctx, &PublicKey::from_secret_key(&self.secp_ctx, &per_commitment_key), &self.counterparty_commitment_params.counterparty_delayed_payment_base_key));
+
+		let revokeable_redeemscript = chan_utils::get_revokeable_redeemscript(&revocation_pubkey, self.counterparty_commitment_params.on_counterparty_tx_csv, &delayed_key);
+		let revokeable_p2wsh = revokeable_redeemscript.to_v0_p2wsh();
+
+		// First, process non-htlc outputs (to_holder & to_counterparty)
+		for (idx, outp) in tx.output.iter().enumerate() {
+			if outp.script_pubkey == revokeable_p2wsh {
+				let revk_outp = RevokedOutput::build(per_commitment_point, self.counterparty_commitment_params.counterparty_delayed_payment_base_key, self.counterparty_commitment_params.counterparty_htlc_base_key, per_commitment_key, outp.value, self.counterparty_commitment_params.on_counterparty_tx_csv);
+				let justice_package = PackageTemplate::build_package(commitment_txid, idx as u32, PackageSolvingData::RevokedOutput(revk_outp), height + self.counterparty_commitment_params.on_counterparty_tx_csv as u32, true, height);
+				claimable_outpoints.push(justice_package);
+			}
+		}
+
+		// Then, try to find revoked htlc outputs
+		if let Some(ref per_commitment_data) = self.counterparty_claimable_outpoints.get(&commitment_txid) {
+			for (_, &(ref htlc, _)) in per_commitment_data.iter().enumerate() {
+				if let Some(transaction_output_index) = htlc.transaction_output_index {
+					if transaction_output_index as usize >= tx.output.len() ||
+							tx.output[transaction_output_index as usize].value != htlc.amount_msat / 1000 {
+						return (claimable_outpoints, false); // Corrupted per_commitment_data, fuck this user
+					}
+					let revk_htlc_outp = RevokedHTLCOutput::build(per_commitment_point, self.counterparty_commitment_params.counterparty_delayed_payment_base_key, self.counterparty_commitment_params.counterparty_htlc_base_key, per_commitment_key, htlc.amount_msat / 1000, htlc.clone(), self.onchain_tx_handler.channel_transaction_parameters.opt_anchors.is_some());
+					let justice_package = PackageTemplate::build_package(commitment_txid, transaction_output_index, PackageSolvingData::RevokedHTLCOutput(revk_htlc_outp), htlc.cltv_expiry, true, height);
+					claimable_outpoints.push(justice_package);
+				}
+			}
+		}
+		(claimable_outpoints, true)
+	}
+
 	fn get_counterparty_htlc_output_claim_reqs(&self, commitment_number: u64, commitment_txid: Txid, tx: Option<&Transaction>) -> Vec<PackageTemplate> {
 		let mut claimable_outpoints = Vec::new();
 		if let Some(htlc_outputs) = self.counterparty_claimable_outpoints.get(&commitment_txid) {
@@ -2565,6 +2747,14 @@ impl<Signer: Sign> ChannelMonitorImpl<Signer> {
 		log_trace!(logger, "Processing {} matched transactions for block at height {}.", txn_matched.len(), conf_height);
 		debug_assert!(self.best_block.height() >= conf_height);
 
+		// Forget about unconfirmed transactions we acted upon once they confirm or have likely
+		// dropped out of the mempool.
+		for tx in txn_matched.iter() {
+			self.mempool_txids_handled.remove(&tx.txid());
+		}
+		let best_height = self.best_block.height();
+		self.mempool_txids_handled.retain(|_, seen_height| *seen_height + MEMPOOL_TXID_EXPIRY_BLOCKS > best_height);
+
 		let should_broadcast = self.should_broadcast_holder_commitment_txn(logger);
 		if should_broadcast {
 			let funding_outp = HolderFundingOutput::build(self.funding_redeemscript.clone());
@@ -2584,6 +2774,8 @@ impl<Signer: Sign> ChannelMonitorImpl<Signer> {
 			claimable_outpoints.append(&mut new_outpoints);
 		}
 
+		let funding_spend_was_confirmed = self.funding_spend_confirmed.is_some();
+
 		// Find which on-chain events have reached their confirmation threshold.
 		let onchain_events_awaiting_threshold_conf =
 			self.onchain_events_awaiting_threshold_conf.drain(..).collect::<Vec<_>>();
@@ -2628,14 +2820,19 @@ impl<Signer: Sign> ChannelMonitorImpl<Signer> {
 						matured_htlcs.push(source.clone());
 					}
 
-					log_debug!(logger, "HTLC {} failure update in {} has got enough confirmations to be passed upstream",
-						log_bytes!(payment_hash.0), entry.txid);
-					self.pending_monitor_events.push(MonitorEvent::HTLCEvent(HTLCUpdate {
-						payment_hash,
-						payment_preimage: None,
-						source: source.clone(),
-						htlc_value_satoshis,
-					}));
+					if self.htlcs_failed_back_from_mempool.contains(source) {
+						log_debug!(logger, "HTLC {} failure update in {} has got enough confirmations, but was already passed upstream",
+							log_bytes!(payment_hash.0), entry.txid);
+					} else {
+						log_debug!(logger, "HTLC {} failure update in {} has got enough confirmations to be passed upstream",
+							log_bytes!(payment_hash.0), entry.txid);
+						self.pending_monitor_events.push(MonitorEvent::HTLCEvent(HTLCUpdate {
+							payment_hash,
+							payment_preimage: None,
+							source: source.clone(),
+							htlc_value_satoshis,
+						}));
+					}
 					if let Some(idx) = commitment_tx_output_idx {
 						self.htlcs_resolved_on_chain.push(IrrevocablyResolvedHTLC { commitment_tx_output_idx: idx, payment_preimage: None });
 					}
@@ -2654,6 +2851,9 @@ impl<Signer: Sign> ChannelMonitorImpl<Signer> {
 				},
 			}
 		}
+		if self.funding_spend_confirmed.is_some() && !funding_spend_was_confirmed {
+			self.prune_counterparty_commitment_data();
+		}
 
 		self.onchain_tx_handler.update_claims_view(&txn_matched, claimable_outpoints, conf_height, self.best_block.height(), broadcaster, fee_estimator, logger);
 
@@ -2832,6 +3032,170 @@ impl<Signer: Sign> ChannelMonitorImpl<Signer> {
 		false
 	}
 
+	fn transactions_seen_in_mempool<B: Deref, F: Deref, L: Deref>(
+		&mut self, txdata: &[Transaction], broadcaster: &B, fee_estimator: &LowerBoundedFeeEstimator<F>, logger: &L
+	) -> bool where B::Target: BroadcasterInterface, F::Target: FeeEstimator, L::Target: Logger {
+		let pending_events_count = self.pending_monitor_events.len();
+		for tx in txdata {
+			let txid = tx.txid();
+			if self.mempool_txids_handled.contains_key(&txid) {
+				continue;
+			}
+			let mut handled = false;
+			if tx.input.len() == 1 && tx.input[0].previous_output == self.funding_info.0.into_bitcoin_outpoint() {
+				handled = self.commitment_tx_seen_in_mempool(tx, txid, broadcaster, fee_estimator, logger);
+			}
+			for input in &tx.input {
+				// Both the accepted and offered HTLC preimage claim paths place the preimage just
+				// before the witness script. Rather than classifying the witness exactly as we do for
+				// confirmed transactions, we check the candidate preimage against the payment hash of
+				// the spent HTLC, which leaves revocation and timeout claims unmatched.
+				if input.witness.last().map(|w| w.len()).and_then(HTLCType::scriptlen_to_htlctype).is_none() {
+					continue;
+				}
+				let mut payment_preimage = PaymentPreimage([0; 32]);
+				match input.witness.second_to_last() {
+					Some(preimage) if preimage.len() == 32 => payment_preimage.0.copy_from_slice(preimage),
+					_ => continue,
+				}
+				let payment_hash = PaymentHash(Sha256::hash(&payment_preimage.0).into_inner());
+				if let Some((source, amount_msat)) = self.get_source_for_htlc_output(&input.previous_output, &payment_hash) {
+					handled = true;
+					if self.pending_monitor_events.iter().any(
+						|update| if let &MonitorEvent::HTLCEvent(ref upd) = update { upd.source == source } else { false }) {
+						continue;
+					}
+					log_info!(logger, "Input spending {}:{} in unconfirmed transaction {} reveals preimage for outbound HTLC with payment hash {}",
+						input.previous_output.txid, input.previous_output.vout, txid, log_bytes!(payment_hash.0));
+					self.pending_monitor_events.push(MonitorEvent::HTLCEvent(HTLCUpdate {
+						source,
+						payment_preimage: Some(payment_preimage),
+						payment_hash,
+						htlc_value_satoshis: Some(amount_msat / 1000),
+					}));
+				}
+			}
+			if handled {
+				self.mempool_txids_handled.insert(txid, self.best_block.height());
+			}
+		}
+		self.pending_monitor_events.len() > pending_events_count
+	}
+
+	/// Handles an unconfirmed transaction spending our funding output, returning whether it was
+	/// acted upon.
+	fn commitment_tx_seen_in_mempool<B: Deref, F: Deref, L: Deref>(
+		&mut self, tx: &Transaction, commitment_txid: Txid, broadcaster: &B,
+		fee_estimator: &LowerBoundedFeeEstimator<F>, logger: &L
+	) -> bool where B::Target: BroadcasterInterface, F::Target: FeeEstimator, L::Target: Logger {
+		let mut handled = false;
+		let commitment_number = self.get_commitment_number(tx);
+		if commitment_number >= self.get_min_seen_secret() {
+			// The claims are only broadcast, not tracked, as we'll build them again and track them
+			// as usual once the commitment transaction confirms.
+			let height = self.best_block.height();
+			let (revoked_claims, _) =
+				self.get_revoked_counterparty_claim_reqs(tx, commitment_txid, commitment_number, height);
+			if !revoked_claims.is_empty() {
+				log_error!(logger, "Saw revoked counterparty commitment transaction {} in the mempool, broadcasting justice transaction(s) for {} outputs",
+					commitment_txid, revoked_claims.len());
+				let justice_txn = self.onchain_tx_handler.generate_untracked_claim_txn(
+					revoked_claims, height, fee_estimator, logger);
+				for justice_tx in justice_txn {
+					broadcaster.broadcast_transaction(&justice_tx);
+				}
+				handled = true;
+			}
+		}
+
+		// Once the channel is closed on our side, HTLCs can no longer be claimed off-chain, so any
+		// outbound HTLC without an output in every valid commitment transaction, i.e. one below
+		// the dust limit of each side, will never be claimed and can be failed back as soon as a
+		// commitment transaction is broadcast. Note that while the channel is still open we have
+		// to wait for the commitment transaction to confirm, as the counterparty may yet claim it
+		// off-chain.
+		if self.lockdown_from_offchain {
+			let mut htlcs_with_outputs = Vec::new();
+			let mut dust_htlcs: Vec<(HTLCSource, PaymentHash, u64)> = Vec::new();
+			let holder_htlcs = core::iter::once(&self.current_holder_commitment_tx)
+				.chain(self.prev_holder_signed_commitment_tx.iter())
+				.flat_map(|holder_tx| holder_tx.htlc_outputs.iter()
+					.filter_map(|&(ref htlc, _, ref source)| source.as_ref().map(|source| (htlc, source))));
+			let counterparty_htlcs = self.current_counterparty_commitment_txid.iter()
+				.chain(self.prev_counterparty_commitment_txid.iter())
+				.filter_map(|txid| self.counterparty_claimable_outpoints.get(txid))
+				.flat_map(|htlc_outputs| htlc_outputs.iter()
+					.filter_map(|&(ref htlc, ref source)| source.as_ref().map(|source| (htlc, &**source))));
+			for (htlc, source) in holder_htlcs.chain(counterparty_htlcs) {
+				if htlc.transaction_output_index.is_some() {
+					htlcs_with_outputs.push(source);
+				} else if !dust_htlcs.iter().any(|&(ref dust_source, _, _)| dust_source == source) {
+					dust_htlcs.push((source.clone(), htlc.payment_hash, htlc.amount_msat));
+				}
+			}
+			dust_htlcs.retain(|&(ref source, ref payment_hash, _)| {
+				!htlcs_with_outputs.contains(&source) && !self.payment_preimages.contains_key(payment_hash) &&
+					!self.htlcs_failed_back_from_mempool.contains(source)
+			});
+			for (source, payment_hash, amount_msat) in dust_htlcs {
+				log_info!(logger, "Failing dust HTLC with payment hash {} back as commitment transaction {} was broadcast after the channel was closed",
+					log_bytes!(payment_hash.0), commitment_txid);
+				self.htlcs_failed_back_from_mempool.insert(source.clone());
+				self.pending_monitor_events.push(MonitorEvent::HTLCEvent(HTLCUpdate {
+					source,
+					payment_preimage: None,
+					payment_hash,
+					htlc_value_satoshis: Some(amount_msat / 1000),
+				}));
+				handled = true;
+			}
+		}
+		handled
+	}
+
+	/// Looks up the source and amount of an outbound HTLC with the given payment hash paid to
+	/// `outpoint` in one of our holder commitment transactions or a counterparty commitment
+	/// transaction.
+	fn get_source_for_htlc_output(&self, outpoint: &bitcoin::OutPoint, payment_hash: &PaymentHash) -> Option<(HTLCSource, u64)> {
+		let matches_outpoint = |htlc: &HTLCOutputInCommitment| {
+			htlc.transaction_output_index == Some(outpoint.vout) && htlc.payment_hash == *payment_hash
+		};
+		let holder_commitment_txn = core::iter::once(&self.current_holder_commitment_tx)
+			.chain(self.prev_holder_signed_commitment_tx.iter());
+		for holder_commitment_tx in holder_commitment_txn.filter(|tx| tx.txid == outpoint.txid) {
+			for &(ref htlc, _, ref source) in holder_commitment_tx.htlc_outputs.iter() {
+				if let Some(source) = source {
+					if matches_outpoint(htlc) { return Some((source.clone(), htlc.amount_msat)); }
+				}
+			}
+		}
+		if let Some(htlc_outputs) = self.counterparty_claimable_outpoints.get(&outpoint.txid) {
+			for &(ref htlc, ref source) in htlc_outputs.iter().filter(|&&(ref htlc, _)| matches_outpoint(htlc)) {
+				if let Some(source) = source {
+					return Some(((**source).clone(), htlc.amount_msat));
+				}
+				// HTLCs in revoked counterparty commitment transactions no longer track their source,
+				// so look for the same HTLC in the unrevoked ones.
+				let unrevoked_txids = self.current_counterparty_commitment_txid.iter()
+					.chain(self.prev_counterparty_commitment_txid.iter());
+				for txid in unrevoked_txids {
+					let pending_htlcs = match self.counterparty_claimable_outpoints.get(txid) {
+						Some(pending_htlcs) => pending_htlcs,
+						None => continue,
+					};
+					for &(ref pending_htlc, ref pending_source) in pending_htlcs {
+						if pending_htlc.payment_hash == htlc.payment_hash && pending_htlc.amount_msat == htlc.amount_msat {
+							if let Some(source) = pending_source {
+								return Some(((**source).clone(), htlc.amount_msat));
+							}
+						}
+					}
+				}
+			}
+		}
+		None
+	}
+
 	/// Check if any transaction broadcasted is resolving HTLC output by a success or timeout on a holder
 	/// or counterparty commitment tx, if so send back the source, preimage if found and payment_hash of resolved HTLC
 	fn is_resolving_htlc_output<L: Deref>(&mut self, tx: &Transaction, height: u32, logger: &L) where L::Target: Logger {
@@ -3359,12 +3723,14 @@ impl<'a, Signer: Sign, K: KeysInterface<Signer = Signer>> ReadableArgs<&'a K>
 		let mut htlcs_resolved_on_chain = Some(Vec::new());
 		let mut funding_spend_seen = Some(false);
 		let mut counterparty_node_id = None;
+		let mut balances_empty_height = None;
 		read_tlv_fields!(reader, {
 			(1, funding_spend_confirmed, option),
 			(3, htlcs_resolved_on_chain, vec_type),
 			(5, pending_monitor_events, vec_type),
 			(7, funding_spend_seen, option),
 			(9, counterparty_node_id, option),
+			(11, balances_empty_height, option),
 		});
 
 		let mut secp_ctx = Secp256k1::new();
@@ -3416,6 +3782,9 @@ impl<'a, Signer: Sign, K: KeysInterface<Signer = Signer>> ReadableArgs<&'a K>
 			funding_spend_seen: funding_spend_seen.unwrap(),
 			funding_spend_confirmed,
 			htlcs_resolved_on_chain: htlcs_resolved_on_chain.unwrap(),
+			balances_empty_height,
+			mempool_txids_handled: HashMap::new(),
+			htlcs_failed_back_from_mempool: HashSet::new(),
 
 			best_block,
 			counterparty_node_id,
@@ -3455,13 +3824,14 @@ mod tests {
 	use ln::{PaymentPreimage, PaymentHash};
 	use ln::chan_utils;
 	use ln::chan_utils::{HTLCOutputInCommitment, ChannelPublicKeys, ChannelTransactionParameters, HolderCommitmentTransaction, CounterpartyChannelTransactionParameters};
-	use ln::channelmanager::PaymentSendFailure;
+	use ln::channelmanager::{BREAKDOWN_TIMEOUT, PaymentSendFailure};
 	use ln::features::InitFeatures;
 	use ln::functional_test_utils::*;
 	use ln::script::ShutdownScript;
 	use util::errors::APIError;
 	use util::events::{ClosureReason, MessageSendEventsProvider};
 	use util::test_utils::{TestLogger, TestBroadcaster, TestFeeEstimator};
+	use util::enforcing_trait_impls::EnforcingSigner;
 	use util::ser::{ReadableArgs, Writeable};
 	use sync::{Arc, Mutex};
 	use io;
@@ -3701,6 +4071,88 @@ mod tests {
 		test_preimages_exist!(&preimages[0..5], monitor);
 	}
 
+	#[test]
+	fn test_prune_counterparty_commitment_data() {
+		// Tests that the HTLC data of every revoked counterparty commitment transaction is kept (and
+		// round-trips through serialization) until a funding spend has irrevocably confirmed, while
+		// payment hashes only included in revoked commitment transactions are pruned as states are
+		// revoked.
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+		let channel = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+
+		let failed_payment_hash = route_payment(&nodes[0], &[&nodes[1]], 10_000).1;
+		fail_payment(&nodes[0], &[&nodes[1]], failed_payment_hash);
+
+		let mut counterparty_txids = Vec::new();
+		for _ in 0..10 {
+			let payment_preimage = route_payment(&nodes[0], &[&nodes[1]], 1_000_000).0;
+			claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+			let monitor = get_monitor!(nodes[0], channel.2);
+			let inner = monitor.inner.lock().unwrap();
+			counterparty_txids.extend(inner.counterparty_claimable_outpoints.keys().cloned());
+			assert!(counterparty_txids.iter().all(|txid| inner.counterparty_claimable_outpoints.contains_key(txid)));
+			let min_idx = inner.get_min_seen_secret();
+			assert!(inner.counterparty_hash_commitment_number.values().all(|commitment_number| *commitment_number < min_idx));
+			assert!(!inner.counterparty_hash_commitment_number.contains_key(&failed_payment_hash));
+		}
+
+		{
+			let monitor = get_monitor!(nodes[0], channel.2);
+			let (_, read_monitor) = <(BlockHash, ChannelMonitor<EnforcingSigner>)>::read(
+				&mut io::Cursor::new(&monitor.encode()), nodes[0].keys_manager).unwrap();
+			assert!(read_monitor == *monitor);
+		}
+
+		// Once a funding spend irrevocably confirms, no other commitment transaction can confirm.
+		nodes[0].node.force_close_broadcasting_latest_txn(&channel.2, &nodes[1].node.get_our_node_id()).unwrap();
+		check_closed_broadcast!(nodes[0], true);
+		check_added_monitors!(nodes[0], 1);
+		check_closed_event!(nodes[0], 1, ClosureReason::HolderForceClosed);
+		let commitment_tx = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().split_off(0);
+		mine_transaction(&nodes[0], &commitment_tx[0]);
+		connect_blocks(&nodes[0], BREAKDOWN_TIMEOUT as u32 - 1);
+		let monitor = get_monitor!(nodes[0], channel.2);
+		let inner = monitor.inner.lock().unwrap();
+		assert_eq!(inner.funding_spend_confirmed, Some(commitment_tx[0].txid()));
+		assert_eq!(inner.counterparty_claimable_outpoints.len(), 1);
+		assert!(inner.counterparty_claimable_outpoints.contains_key(&inner.current_counterparty_commitment_txid.unwrap()));
+	}
+
+	#[test]
+	fn test_claim_htlc_outputs_of_old_revoked_commitment() {
+		// Tests that we can still claim the HTLC outputs of a revoked counterparty commitment
+		// transaction broadcast long after it was revoked, as we keep its HTLC data.
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+		let channel = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+
+		let payment_preimage = route_payment(&nodes[0], &[&nodes[1]], 3_000_000).0;
+		let revoked_local_txn = get_local_commitment_txn!(nodes[0], channel.2);
+		assert_eq!(revoked_local_txn[0].output.len(), 2);
+		claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+		for _ in 0..10 {
+			let payment_preimage = route_payment(&nodes[0], &[&nodes[1]], 1_000_000).0;
+			claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+		}
+
+		mine_transaction(&nodes[1], &revoked_local_txn[0]);
+		check_closed_broadcast!(nodes[1], true);
+		check_added_monitors!(nodes[1], 1);
+		check_closed_event!(nodes[1], 1, ClosureReason::CommitmentTxConfirmed);
+
+		// Both the to_local and the HTLC output are swept by the justice transaction.
+		let node_txn = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap().split_off(0);
+		assert_eq!(node_txn[0].input.len(), 2);
+		check_spends!(node_txn[0], revoked_local_txn[0]);
+		let htlc_output_idx = revoked_local_txn[0].output.iter().position(|output| output.value == 3_000).unwrap();
+		assert!(node_txn[0].input.iter().any(|input| input.previous_output.vout == htlc_output_idx as u32));
+	}
+
 	#[test]
 	fn test_claim_txn_weight_computation() {
 		// We test Claim txn weight, knowing that we want expected weigth and
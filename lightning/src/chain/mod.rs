@@ -107,6 +107,25 @@ pub trait Listen {
 	fn block_disconnected(&self, header: &BlockHeader, height: u32);
 }
 
+/// The `MempoolListener` trait is used to notify of transactions which have been broadcast but not
+/// yet confirmed, e.g., as seen in a node's mempool.
+///
+/// Useful for learning about the resolution of HTLCs sooner than waiting for a confirmation. For
+/// instance, a counterparty claiming an HTLC we forwarded reveals the payment preimage, allowing us
+/// to claim the corresponding HTLC upstream right away. Similarly, a revoked commitment transaction
+/// can be punished before it confirms.
+///
+/// Transactions given via this interface may never confirm, may be replaced by conflicting ones, or
+/// may be given more than once. Implementations must only act on them in ways that remain safe in
+/// all such cases. Confirmations must still be given via [`Listen`] or [`Confirm`].
+pub trait MempoolListener {
+	/// Notifies the listener of transactions seen unconfirmed in the mempool.
+	///
+	/// Should be called for any transactions spending an output registered by
+	/// [`Filter::register_output`], though may be called with unrelated transactions as well.
+	fn transactions_seen_in_mempool(&self, txdata: &[Transaction]);
+}
+
 /// The `Confirm` trait is used to notify when transactions have been confirmed on chain or
 /// unconfirmed during a chain reorganization.
 ///
@@ -187,21 +206,23 @@ pub trait Confirm {
 	fn get_relevant_txids(&self) -> Vec<Txid>;
 }
 
-/// An error enum representing a failure to persist a channel monitor update.
-#[derive(Clone, Copy, Debug, PartialEq)]
+/// An enum representing a channel monitor update which either is still being persisted or failed
+/// to persist.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ChannelMonitorUpdateErr {
-	/// Used to indicate a temporary failure (eg connection to a watchtower or remote backup of
-	/// our state failed, but is expected to succeed at some point in the future).
+	/// Used to indicate that persistence of the update is still in progress, e.g. because it is
+	/// being replicated to a watchtower or remote backup, and is expected to complete at some point
+	/// in the future.
 	///
-	/// Such a failure will "freeze" a channel, preventing us from revoking old states or
-	/// submitting new commitment transactions to the counterparty. Once the update(s) that failed
-	/// have been successfully applied, a [`MonitorEvent::UpdateCompleted`] event should be returned
-	/// via [`Watch::release_pending_monitor_events`] which will then restore the channel to an
+	/// Such an update will "freeze" a channel, preventing us from revoking old states or
+	/// submitting new commitment transactions to the counterparty. Once the update(s) in progress
+	/// have completed, a [`MonitorEvent::UpdateCompleted`] event should be returned via
+	/// [`Watch::release_pending_monitor_events`] which will then restore the channel to an
 	/// operational state.
 	///
 	/// Note that a given ChannelManager will *never* re-generate a given ChannelMonitorUpdate. If
-	/// you return a TemporaryFailure you must ensure that it is written to disk safely before
-	/// writing out the latest ChannelManager state.
+	/// you return InProgress you must ensure that it is written to disk safely before writing out
+	/// the latest ChannelManager state.
 	///
 	/// Even when a channel has been "frozen" updates to the ChannelMonitor can continue to occur
 	/// (eg if an inbound HTLC which we forwarded was claimed upstream resulting in us attempting
@@ -211,24 +232,25 @@ pub enum ChannelMonitorUpdateErr {
 	/// the channel which would invalidate previous ChannelMonitors are not made when a channel has
 	/// been "frozen".
 	///
-	/// Note that even if updates made after TemporaryFailure succeed you must still provide a
-	/// [`MonitorEvent::UpdateCompleted`] to ensure you have the latest monitor and re-enable
-	/// normal channel operation. Note that this is normally generated through a call to
+	/// Note that even if updates made after an InProgress update complete immediately you must
+	/// still provide a [`MonitorEvent::UpdateCompleted`] to ensure you have the latest monitor and
+	/// re-enable normal channel operation. Note that this is normally generated through a call to
 	/// [`ChainMonitor::channel_monitor_updated`].
 	///
 	/// Note that the update being processed here will not be replayed for you when you return a
 	/// [`MonitorEvent::UpdateCompleted`] event via [`Watch::release_pending_monitor_events`], so
-	/// you must store the update itself on your own local disk prior to returning a
-	/// TemporaryFailure. You may, of course, employ a journaling approach, storing only the
-	/// ChannelMonitorUpdate on disk without updating the monitor itself, replaying the journal at
-	/// reload-time.
+	/// you must store the update itself on your own local disk prior to returning InProgress. You
+	/// may, of course, employ a journaling approach, storing only the ChannelMonitorUpdate on disk
+	/// without updating the monitor itself, replaying the journal at reload-time.
 	///
 	/// For deployments where a copy of ChannelMonitors and other local state are backed up in a
 	/// remote location (with local copies persisted immediately), it is anticipated that all
-	/// updates will return TemporaryFailure until the remote copies could be updated.
+	/// updates will return InProgress until the remote copies could be updated. See
+	/// [`ReplicatingPersister`] for such an implementation.
 	///
 	/// [`ChainMonitor::channel_monitor_updated`]: chainmonitor::ChainMonitor::channel_monitor_updated
-	TemporaryFailure,
+	/// [`ReplicatingPersister`]: crate::util::persist::ReplicatingPersister
+	InProgress,
 	/// Used to indicate no further channel monitor updates will be allowed (eg we've moved on to a
 	/// different watchtower and cannot update with all watchtowers that were previously informed
 	/// of this channel).
@@ -252,6 +274,13 @@ pub enum ChannelMonitorUpdateErr {
 	PermanentFailure,
 }
 
+impl ChannelMonitorUpdateErr {
+	/// The former name of [`ChannelMonitorUpdateErr::InProgress`].
+	#[deprecated(note = "Renamed to ChannelMonitorUpdateErr::InProgress")]
+	#[allow(non_upper_case_globals)]
+	pub const TemporaryFailure: ChannelMonitorUpdateErr = ChannelMonitorUpdateErr::InProgress;
+}
+
 /// The `Watch` trait defines behavior for watching on-chain activity pertaining to channels as
 /// blocks are connected and disconnected.
 ///
@@ -268,9 +297,11 @@ pub enum ChannelMonitorUpdateErr {
 /// backup copies), then it must ensure that updates are applied across all instances. Otherwise, it
 /// could result in a revoked transaction being broadcast, allowing the counterparty to claim all
 /// funds in the channel. See [`ChannelMonitorUpdateErr`] for more details about how to handle
-/// multiple instances.
+/// multiple instances, and [`FencedReplicaPersister`] for replicating monitors such that only a
+/// single instance may update them.
 ///
 /// [`PermanentFailure`]: ChannelMonitorUpdateErr::PermanentFailure
+/// [`FencedReplicaPersister`]: crate::util::persist::FencedReplicaPersister
 pub trait Watch<ChannelSigner: Sign> {
 	/// Watches a channel identified by `funding_txo` using `monitor`.
 	///
@@ -302,7 +333,7 @@ pub trait Watch<ChannelSigner: Sign> {
 	/// to disk.
 	///
 	/// For details on asynchronous [`ChannelMonitor`] updating and returning
-	/// [`MonitorEvent::UpdateCompleted`] here, see [`ChannelMonitorUpdateErr::TemporaryFailure`].
+	/// [`MonitorEvent::UpdateCompleted`] here, see [`ChannelMonitorUpdateErr::InProgress`].
 	fn release_pending_monitor_events(&self) -> Vec<(OutPoint, Vec<MonitorEvent>, Option<PublicKey>)>;
 }
 
@@ -321,9 +352,9 @@ pub trait Watch<ChannelSigner: Sign> {
 /// Note that use as part of a [`Watch`] implementation involves reentrancy. Therefore, the `Filter`
 /// should not block on I/O. Implementations should instead queue the newly monitored data to be
 /// processed later. Then, in order to block until the data has been processed, any [`Watch`]
-/// invocation that has called the `Filter` must return [`TemporaryFailure`].
+/// invocation that has called the `Filter` must return [`InProgress`].
 ///
-/// [`TemporaryFailure`]: ChannelMonitorUpdateErr::TemporaryFailure
+/// [`InProgress`]: ChannelMonitorUpdateErr::InProgress
 /// [BIP 157]: https://github.com/bitcoin/bips/blob/master/bip-0157.mediawiki
 /// [BIP 158]: https://github.com/bitcoin/bips/blob/master/bip-0158.mediawiki
 pub trait Filter {
@@ -341,6 +372,25 @@ pub trait Filter {
 	/// such descendant transactions were already included (e.g., when a BIP 157 client provides the
 	/// full block).
 	fn register_output(&self, output: WatchedOutput) -> Option<(usize, Transaction)>;
+
+	/// Unregisters interest in a transaction previously registered via [`Filter::register_tx`],
+	/// called once the [`ChannelMonitor`] which registered it has been fully resolved and archived.
+	///
+	/// The default implementation does nothing, which is always safe as it only results in more
+	/// transactions than necessary being given to [`ChainMonitor`].
+	///
+	/// [`ChannelMonitor`]: channelmonitor::ChannelMonitor
+	/// [`ChainMonitor`]: chainmonitor::ChainMonitor
+	fn unregister_tx(&self, _txid: &Txid, _script_pubkey: &Script) {}
+
+	/// Unregisters interest in spends of a transaction output previously registered via
+	/// [`Filter::register_output`], called once the [`ChannelMonitor`] which registered it has been
+	/// fully resolved and archived.
+	///
+	/// Note that `output.block_hash` is always `None` here. The default implementation does nothing.
+	///
+	/// [`ChannelMonitor`]: channelmonitor::ChannelMonitor
+	fn unregister_output(&self, _output: &WatchedOutput) {}
 }
 
 /// A transaction output watched by a [`ChannelMonitor`] for spends on-chain.
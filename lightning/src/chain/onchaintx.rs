@@ -408,6 +408,39 @@ impl<ChannelSigner: Sign> OnchainTxHandler<ChannelSigner> {
 		None
 	}
 
+	/// Builds and signs claim transactions for the given requests without tracking them, e.g. to
+	/// claim the outputs of a transaction which has been broadcast but not yet confirmed. The
+	/// requests must still be provided via [`Self::update_claims_view`] once the transaction
+	/// confirms to have them tracked and fee-bumped as usual.
+	///
+	/// Requests are aggregated into as few transactions as possible, as done for tracked claims,
+	/// and any which are still timelocked are skipped.
+	pub(crate) fn generate_untracked_claim_txn<F: Deref, L: Deref>(&mut self, requests: Vec<PackageTemplate>, cur_height: u32, fee_estimator: &LowerBoundedFeeEstimator<F>, logger: &L) -> Vec<Transaction>
+		where F::Target: FeeEstimator,
+					L::Target: Logger,
+	{
+		let mut preprocessed_requests = Vec::with_capacity(requests.len());
+		let mut aggregated_request: Option<PackageTemplate> = None;
+		for req in requests {
+			if req.package_timelock() > cur_height + 1 {
+				continue;
+			}
+			if req.timelock() <= cur_height + CLTV_SHARED_CLAIM_BUFFER || !req.aggregable() {
+				preprocessed_requests.push(req);
+			} else if let Some(aggregated_request) = aggregated_request.as_mut() {
+				aggregated_request.merge_package(req);
+			} else {
+				aggregated_request = Some(req);
+			}
+		}
+		preprocessed_requests.extend(aggregated_request);
+
+		preprocessed_requests.iter()
+			.filter_map(|request| self.generate_claim_tx(cur_height, request, fee_estimator, logger))
+			.map(|(_, _, tx)| tx)
+			.collect()
+	}
+
 	/// Upon channelmonitor.block_connected(..) or upon provision of a preimage on the forward link
 	/// for this channel, provide new relevant on-chain transactions and/or new claim requests.
 	/// Formerly this was named `block_connected`, but it is now also used for claiming an HTLC output
@@ -68,6 +68,9 @@ use sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard};
 use core::sync::atomic::{AtomicUsize, Ordering};
 use core::time::Duration;
 use core::ops::Deref;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
 
 #[cfg(any(test, feature = "std"))]
 use std::time::Instant;
@@ -1354,8 +1357,8 @@ macro_rules! handle_monitor_err {
 						$chan.force_shutdown(true), $self.get_channel_update_for_broadcast(&$chan).ok() ));
 				(res, true)
 			},
-			ChannelMonitorUpdateErr::TemporaryFailure => {
-				log_info!($self.logger, "Disabling channel {} due to monitor update TemporaryFailure. On restore will send {} and process {} forwards, {} fails, and {} fulfill finalizations",
+			ChannelMonitorUpdateErr::InProgress => {
+				log_info!($self.logger, "Disabling channel {} due to monitor update in progress. On restore will send {} and process {} forwards, {} fails, and {} fulfill finalizations",
 						log_bytes!($chan_id[..]),
 						if $resend_commitment && $resend_raa {
 								match $action_type {
@@ -1413,14 +1416,14 @@ macro_rules! return_monitor_err {
 	}
 }
 
-// Does not break in case of TemporaryFailure!
+// Does not break in case of InProgress!
 macro_rules! maybe_break_monitor_err {
 	($self: ident, $err: expr, $channel_state: expr, $entry: expr, $action_type: path, $resend_raa: expr, $resend_commitment: expr) => {
 		match (handle_monitor_err!($self, $err, $channel_state, $entry, $action_type, $resend_raa, $resend_commitment), $err) {
 			(e, ChannelMonitorUpdateErr::PermanentFailure) => {
 				break e;
 			},
-			(_, ChannelMonitorUpdateErr::TemporaryFailure) => { },
+			(_, ChannelMonitorUpdateErr::InProgress) => { },
 		}
 	}
 }
@@ -2965,7 +2968,18 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 	/// Panics if `addresses` is absurdly large (more than 100).
 	///
 	/// [`get_and_clear_pending_msg_events`]: MessageSendEventsProvider::get_and_clear_pending_msg_events
-	pub fn broadcast_node_announcement(&self, rgb: [u8; 3], alias: [u8; 32], mut addresses: Vec<NetAddress>) {
+	pub fn broadcast_node_announcement(&self, rgb: [u8; 3], alias: [u8; 32], addresses: Vec<NetAddress>) {
+		self.broadcast_node_announcement_with_features(rgb, alias, addresses, NodeFeatures::empty())
+	}
+
+	/// Generates a node_announcement as with [`Self::broadcast_node_announcement`], additionally
+	/// setting the given (e.g. custom) feature bits, such as those provided by
+	/// [`CustomMessageHandler::provided_node_features`].
+	///
+	/// Panics if `addresses` is absurdly large (more than 100).
+	///
+	/// [`CustomMessageHandler::provided_node_features`]: crate::ln::peer_handler::CustomMessageHandler::provided_node_features
+	pub fn broadcast_node_announcement_with_features(&self, rgb: [u8; 3], alias: [u8; 32], mut addresses: Vec<NetAddress>, features: NodeFeatures) {
 		let _persistence_guard = PersistenceNotifierGuard::notify_on_drop(&self.total_consistency_lock, &self.persistence_notifier);
 
 		if addresses.len() > 100 {
@@ -2977,7 +2991,7 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 		addresses.sort_by_key(|addr| addr.get_id());
 
 		let announcement = msgs::UnsignedNodeAnnouncement {
-			features: NodeFeatures::known(),
+			features: NodeFeatures::known().or(features),
 			timestamp: self.last_node_announcement_serial.fetch_add(1, Ordering::AcqRel) as u32,
 			node_id: self.get_our_node_id(),
 			rgb, alias, addresses,
@@ -4626,7 +4640,7 @@ impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> ChannelMana
 					assert!(failed_htlcs.is_empty());
 					return Err(MsgHandleErrInternal::send_err_msg_no_close("ChannelMonitor storage failure".to_owned(), funding_msg.channel_id));
 				},
-				ChannelMonitorUpdateErr::TemporaryFailure => {
+				ChannelMonitorUpdateErr::InProgress => {
 					// There's no problem signing a counterparty's funding transaction if our monitor
 					// hasn't persisted to disk yet - we can't lose money on a transaction that we haven't
 					// accepted payment from yet. We do, however, need to wait to send our channel_ready
@@ -5704,6 +5718,41 @@ where
 	}
 }
 
+impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> events::AsyncEventsProvider for ChannelManager<Signer, M, T, K, F, L>
+where
+	M::Target: chain::Watch<Signer>,
+	T::Target: BroadcasterInterface,
+	K::Target: KeysInterface<Signer = Signer>,
+	F::Target: FeeEstimator,
+	L::Target: Logger,
+{
+	/// Gets the pending events, without marking them handled.
+	///
+	/// As with [`EventsProvider::process_pending_events`], pending [`MonitorEvent`]s are processed
+	/// first, possibly generating new events.
+	fn get_pending_events(&self) -> Vec<events::Event> {
+		let _read_guard = self.total_consistency_lock.read().unwrap();
+		if self.process_pending_monitor_events() {
+			self.persistence_notifier.notify();
+		}
+		self.pending_events.lock().unwrap().clone()
+	}
+
+	/// Marks the given number of pending events handled, after which the [`ChannelManager`] needs
+	/// to be persisted.
+	fn events_handled(&self, handled_count: usize) {
+		PersistenceNotifierGuard::optionally_notify(&self.total_consistency_lock, &self.persistence_notifier, || {
+			if handled_count == 0 {
+				return NotifyOption::SkipPersist;
+			}
+			let mut pending_events = self.pending_events.lock().unwrap();
+			let handled_count = cmp::min(handled_count, pending_events.len());
+			pending_events.drain(..handled_count);
+			NotifyOption::DoPersist
+		});
+	}
+}
+
 impl<Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref> chain::Listen for ChannelManager<Signer, M, T, K, F, L>
 where
 	M::Target: chain::Watch<Signer>,
@@ -5994,6 +6043,16 @@ where
 		self.persistence_notifier.wait()
 	}
 
+	/// Gets a [`Future`] that completes when the ChannelManager needs to be persisted.
+	///
+	/// This is the non-blocking equivalent of [`Self::await_persistable_update`], for use in async
+	/// contexts where blocking the current thread is not an option (e.g. single-threaded runtimes).
+	/// As with the blocking variants, only one listener across all waiting futures and threads is
+	/// guaranteed to be woken up for each persistable update.
+	pub fn get_persistable_update_future(&self) -> PersistableUpdateFuture<'_> {
+		self.persistence_notifier.get_future()
+	}
+
 	#[cfg(any(test, feature = "_test_utils"))]
 	pub fn get_persistence_condvar_value(&self) -> bool {
 		let mutcond = &self.persistence_notifier.persistence_lock;
@@ -6165,6 +6224,10 @@ impl<Signer: Sign, M: Deref , T: Deref , K: Deref , F: Deref , L: Deref >
 		}
 	}
 
+	fn has_channels_with(&self, counterparty_node_id: &PublicKey) -> bool {
+		self.channel_state.lock().unwrap().by_id.values().any(|chan| chan.get_counterparty_node_id() == *counterparty_node_id)
+	}
+
 	fn peer_connected(&self, counterparty_node_id: &PublicKey, init_msg: &msgs::Init) {
 		log_debug!(self.logger, "Generating channel_reestablish events for {}", log_pubkey!(counterparty_node_id));
 
@@ -6243,19 +6306,35 @@ impl<Signer: Sign, M: Deref , T: Deref , K: Deref , F: Deref , L: Deref >
 
 /// Used to signal to the ChannelManager persister that the manager needs to be re-persisted to
 /// disk/backups, through `await_persistable_update_timeout` and `await_persistable_update`.
-struct PersistenceNotifier {
+///
+/// Also used by [`ChainMonitor`] to signal that new events are available.
+///
+/// [`ChainMonitor`]: crate::chain::chainmonitor::ChainMonitor
+pub(crate) struct PersistenceNotifier {
 	/// Users won't access the persistence_lock directly, but rather wait on its bool using
 	/// `wait_timeout` and `wait`.
 	persistence_lock: (Mutex<bool>, Condvar),
+	/// The wakers of any [`PersistableUpdateFuture`]s which were polled before an update was
+	/// available. Only ever locked while holding the `persistence_lock` mutex.
+	wakers: Mutex<Vec<Waker>>,
+	/// The wake-up flags of any [`Sleeper`]s currently blocked waiting on this notifier, among
+	/// others. Only ever locked while holding the `persistence_lock` mutex.
+	sleepers: Mutex<Vec<Arc<(Mutex<bool>, Condvar)>>>,
 }
 
 impl PersistenceNotifier {
-	fn new() -> Self {
+	pub(crate) fn new() -> Self {
 		Self {
 			persistence_lock: (Mutex::new(false), Condvar::new()),
+			wakers: Mutex::new(Vec::new()),
+			sleepers: Mutex::new(Vec::new()),
 		}
 	}
 
+	pub(crate) fn get_future(&self) -> PersistableUpdateFuture<'_> {
+		PersistableUpdateFuture { notifier: self }
+	}
+
 	fn wait(&self) {
 		loop {
 			let &(ref mtx, ref cvar) = &self.persistence_lock;
@@ -6303,12 +6382,118 @@ impl PersistenceNotifier {
 	}
 
 	// Signal to the ChannelManager persister that there are updates necessitating persisting to disk.
-	fn notify(&self) {
+	pub(crate) fn notify(&self) {
 		let &(ref persist_mtx, ref cnd) = &self.persistence_lock;
 		let mut persistence_lock = persist_mtx.lock().unwrap();
 		*persistence_lock = true;
+		let wakers = mem::replace(&mut *self.wakers.lock().unwrap(), Vec::new());
+		let sleepers = mem::replace(&mut *self.sleepers.lock().unwrap(), Vec::new());
 		mem::drop(persistence_lock);
 		cnd.notify_all();
+		for waker in wakers {
+			waker.wake();
+		}
+		for sleeper in sleepers {
+			*sleeper.0.lock().unwrap() = true;
+			sleeper.1.notify_all();
+		}
+	}
+
+	fn poll_update(&self, cx: &mut Context<'_>) -> Poll<()> {
+		let mut persistence_lock = self.persistence_lock.0.lock().unwrap();
+		if *persistence_lock {
+			*persistence_lock = false;
+			return Poll::Ready(());
+		}
+		// Register the waker while still holding the persistence lock so that a concurrent `notify`
+		// cannot slip in between the check above and the registration. We avoid storing duplicate
+		// wakers so that a task which repeatedly polls fresh futures doesn't grow the list.
+		let mut wakers = self.wakers.lock().unwrap();
+		if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+			wakers.push(cx.waker().clone());
+		}
+		Poll::Pending
+	}
+}
+
+/// A [`Future`] which completes once the [`ChannelManager`] needs to be persisted, or once a
+/// [`ChainMonitor`] has new events available.
+///
+/// See [`ChannelManager::get_persistable_update_future`] and [`ChainMonitor::get_update_future`]
+/// for more info.
+///
+/// [`ChainMonitor`]: crate::chain::chainmonitor::ChainMonitor
+/// [`ChainMonitor::get_update_future`]: crate::chain::chainmonitor::ChainMonitor::get_update_future
+pub struct PersistableUpdateFuture<'a> {
+	notifier: &'a PersistenceNotifier,
+}
+
+impl<'a> Future for PersistableUpdateFuture<'a> {
+	type Output = ();
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+		self.notifier.poll_update(cx)
+	}
+}
+
+/// Blocks the current thread until any one of several [`PersistableUpdateFuture`]s completes, e.g.
+/// to wait on both a [`ChannelManager`] needing persistence and a [`ChainMonitor`] having new
+/// events at once.
+///
+/// [`ChainMonitor`]: crate::chain::chainmonitor::ChainMonitor
+pub struct Sleeper<'a> {
+	notifiers: Vec<&'a PersistenceNotifier>,
+}
+
+impl<'a> Sleeper<'a> {
+	/// Constructs a new sleeper which wakes once either of the given futures completes.
+	pub fn from_two_futures(fut_a: PersistableUpdateFuture<'a>, fut_b: PersistableUpdateFuture<'a>) -> Self {
+		Self { notifiers: vec![fut_a.notifier, fut_b.notifier] }
+	}
+
+	/// Blocks until any of the futures completes or `max_wait` has elapsed, returning whether any
+	/// completed. The updates of all completed futures are consumed.
+	#[cfg(any(test, feature = "std"))]
+	pub fn wait_timeout(&self, max_wait: Duration) -> bool {
+		self.wait_timeout_completed(max_wait).iter().any(|completed| *completed)
+	}
+
+	/// Blocks until any of the futures completes or `max_wait` has elapsed, returning whether each
+	/// future completed, in the order they were given. The updates of all completed futures are
+	/// consumed.
+	#[cfg(any(test, feature = "std"))]
+	pub fn wait_timeout_completed(&self, max_wait: Duration) -> Vec<bool> {
+		let wake_flag = Arc::new((Mutex::new(false), Condvar::new()));
+		let mut completed = vec![false; self.notifiers.len()];
+		for (notifier, completed) in self.notifiers.iter().zip(completed.iter_mut()) {
+			// Register while holding the persistence lock so that a concurrent `notify` cannot slip
+			// in between checking for a pending update and the registration.
+			let mut persistence_lock = notifier.persistence_lock.0.lock().unwrap();
+			if mem::replace(&mut *persistence_lock, false) {
+				*completed = true;
+			} else {
+				notifier.sleepers.lock().unwrap().push(Arc::clone(&wake_flag));
+			}
+		}
+		if !completed.iter().any(|completed| *completed) {
+			let start_time = Instant::now();
+			let mut woken = wake_flag.0.lock().unwrap();
+			while !*woken {
+				// Due to spurious wakeups, re-check the elapsed time rather than relying on the
+				// result of `wait_timeout`.
+				let remaining = match max_wait.checked_sub(start_time.elapsed()) {
+					Some(remaining) => remaining,
+					None => break,
+				};
+				woken = wake_flag.1.wait_timeout(woken, remaining).unwrap().0;
+			}
+		}
+		for (notifier, completed) in self.notifiers.iter().zip(completed.iter_mut()) {
+			let mut persistence_lock = notifier.persistence_lock.0.lock().unwrap();
+			if mem::replace(&mut *persistence_lock, false) { *completed = true; }
+			notifier.sleepers.lock().unwrap().retain(|sleeper| !Arc::ptr_eq(sleeper, &wake_flag));
+		}
+		completed
 	}
 }
 
@@ -7407,6 +7592,116 @@ mod tests {
 		}
 	}
 
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_sleeper_wakes_on_either_future() {
+		use ln::channelmanager::{PersistenceNotifier, Sleeper};
+		use sync::Arc;
+		use std::thread;
+
+		let notifier_a = PersistenceNotifier::new();
+		let notifier_b = Arc::new(PersistenceNotifier::new());
+
+		// Without any updates we time out, and don't leave our registration behind.
+		assert!(!Sleeper::from_two_futures(notifier_a.get_future(), notifier_b.get_future())
+			.wait_timeout(Duration::from_millis(10)));
+		assert!(notifier_a.sleepers.lock().unwrap().is_empty());
+		assert!(notifier_b.sleepers.lock().unwrap().is_empty());
+
+		// An update which arrived before waiting completes the wait immediately and is consumed,
+		// and we learn which future completed.
+		notifier_b.notify();
+		assert_eq!(Sleeper::from_two_futures(notifier_a.get_future(), notifier_b.get_future())
+			.wait_timeout_completed(Duration::from_secs(10)), vec![false, true]);
+		assert!(!*notifier_b.persistence_lock.0.lock().unwrap());
+
+		// An update arriving while waiting wakes us up.
+		let thread_notifier = Arc::clone(&notifier_b);
+		let notifying_thread = thread::spawn(move || {
+			thread::sleep(Duration::from_millis(10));
+			thread_notifier.notify();
+		});
+		assert!(Sleeper::from_two_futures(notifier_a.get_future(), notifier_b.get_future())
+			.wait_timeout(Duration::from_secs(10)));
+		notifying_thread.join().unwrap();
+		assert!(notifier_a.sleepers.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn test_persistable_update_future() {
+		use ln::channelmanager::{PersistenceNotifier, PersistableUpdateFuture};
+		use core::future::Future;
+		use core::pin::Pin;
+		use core::sync::atomic::AtomicUsize;
+		use core::task::{Context, Poll};
+		use sync::Arc;
+
+		let wake_count = Arc::new(AtomicUsize::new(0));
+		let waker = test_utils::counting_waker(&wake_count);
+		let mut cx = Context::from_waker(&waker);
+
+		let notifier = PersistenceNotifier::new();
+		let mut future = PersistableUpdateFuture { notifier: &notifier };
+
+		// Nothing to persist yet, so the future is pending and our waker is registered (once).
+		assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Pending);
+		assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Pending);
+		assert_eq!(notifier.wakers.lock().unwrap().len(), 1);
+		assert_eq!(wake_count.load(Ordering::SeqCst), 0);
+
+		// Notifying wakes the task, after which the update is consumed by the next poll.
+		notifier.notify();
+		assert_eq!(wake_count.load(Ordering::SeqCst), 1);
+		assert!(notifier.wakers.lock().unwrap().is_empty());
+		assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Ready(()));
+		assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Pending);
+
+		// An update which arrived before polling completes the future immediately.
+		notifier.notify();
+		let mut fresh_future = PersistableUpdateFuture { notifier: &notifier };
+		assert_eq!(Pin::new(&mut fresh_future).poll(&mut cx), Poll::Ready(()));
+	}
+
+	#[test]
+	fn test_async_events_only_removed_once_handled() {
+		// Tests that events gotten via `AsyncEventsProvider` remain pending, and are thus persisted
+		// with the ChannelManager, until marked handled.
+		use util::events::AsyncEventsProvider;
+
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+		create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+		let (payment_preimage_a, _, _) = route_payment(&nodes[0], &[&nodes[1]], 100_000);
+		let (payment_preimage_b, _, _) = route_payment(&nodes[0], &[&nodes[1]], 100_000);
+
+		nodes[1].node.claim_funds(payment_preimage_a);
+		check_added_monitors!(nodes[1], 1);
+		nodes[1].node.claim_funds(payment_preimage_b);
+		check_added_monitors!(nodes[1], 1);
+		assert!(nodes[1].node.await_persistable_update_timeout(Duration::from_millis(1)));
+
+		let events = nodes[1].node.get_pending_events();
+		assert_eq!(events.len(), 2);
+		assert_eq!(nodes[1].node.get_pending_events().len(), 2);
+		assert!(!nodes[1].node.await_persistable_update_timeout(Duration::from_millis(1)));
+
+		nodes[1].node.events_handled(1);
+		assert!(nodes[1].node.await_persistable_update_timeout(Duration::from_millis(1)));
+		let remaining_events = nodes[1].node.get_pending_events();
+		assert_eq!(remaining_events.len(), 1);
+		match (&events[1], &remaining_events[0]) {
+			(Event::PaymentClaimed { payment_hash: a, .. }, Event::PaymentClaimed { payment_hash: b, .. }) => assert_eq!(a, b),
+			_ => panic!("Unexpected events"),
+		}
+
+		nodes[1].node.events_handled(1);
+		assert!(nodes[1].node.get_pending_events().is_empty());
+		assert!(nodes[1].node.get_and_clear_pending_events().is_empty());
+		nodes[1].node.get_and_clear_pending_msg_events();
+	}
+
 	#[test]
 	fn test_notify_limits() {
 		// Check that a few cases which don't require the persistence of a new ChannelManager,
@@ -0,0 +1,419 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Utilities for running several protocols built on custom messages side by side.
+//!
+//! A [`PeerManager`] accepts a single [`CustomMessageHandler`]. The
+//! [`composite_custom_message_handler`] macro defines a handler which multiplexes several
+//! handlers, each responsible for a range of custom message types, into one.
+//!
+//! [`PeerManager`]: crate::ln::peer_handler::PeerManager
+//! [`CustomMessageHandler`]: crate::ln::peer_handler::CustomMessageHandler
+//! [`composite_custom_message_handler`]: crate::composite_custom_message_handler
+
+// Re-exported for use in `composite_custom_message_handler`, regardless of the caller's imports.
+#[doc(hidden)]
+pub use bitcoin::secp256k1::PublicKey;
+#[doc(hidden)]
+pub use core::ops::Deref;
+
+/// Defines a [`CustomMessageHandler`] which dispatches messages to several other handlers by
+/// message type, along with the message type it reads.
+///
+/// The macro takes a struct, with one field per handler, and an enum, with one variant per
+/// handler (in the same order), giving the message type ids the handler is responsible for as
+/// one or more `|`-separated patterns. Each handler field must be of a type which [`Deref`]s to a
+/// [`CustomMessageHandler`], e.g. an `Arc` or a reference, allowing the handler to also be used
+/// directly. Message types matching no variant are left unhandled. If the patterns of several
+/// variants overlap, the first matching variant is used.
+///
+/// The generated handler sets the union of the feature bits provided by each handler and
+/// notifies each handler of peers connecting and disconnecting.
+///
+/// Attributes (including doc comments) may be given for both the struct and the enum. When
+/// building `lightning` tests, the enum must derive `PartialEq`.
+///
+/// ```
+/// # extern crate bitcoin;
+/// # #[macro_use] extern crate lightning;
+/// # use bitcoin::secp256k1::PublicKey;
+/// # use lightning::io;
+/// # use lightning::ln::features::InitFeatures;
+/// # use lightning::ln::msgs::{DecodeError, LightningError};
+/// # use lightning::ln::peer_handler::CustomMessageHandler;
+/// # use lightning::ln::wire::{CustomMessageReader, Type};
+/// # use lightning::util::ser::{Writeable, Writer};
+/// # use std::sync::Arc;
+/// # #[derive(Debug)]
+/// # pub struct LspMessage;
+/// # impl Type for LspMessage { fn type_id(&self) -> u16 { 37913 } }
+/// # impl Writeable for LspMessage { fn write<W: Writer>(&self, _: &mut W) -> Result<(), io::Error> { Ok(()) } }
+/// # pub struct LspHandler;
+/// # impl CustomMessageReader for LspHandler {
+/// # 	type CustomMessage = LspMessage;
+/// # 	fn read<R: io::Read>(&self, _: u16, _: &mut R) -> Result<Option<LspMessage>, DecodeError> { Ok(Some(LspMessage)) }
+/// # }
+/// # impl CustomMessageHandler for LspHandler {
+/// # 	fn handle_custom_message(&self, _: LspMessage, _: &PublicKey) -> Result<(), LightningError> { Ok(()) }
+/// # 	fn get_and_clear_pending_msg(&self) -> Vec<(PublicKey, LspMessage)> { Vec::new() }
+/// # 	fn provided_init_features(&self, _: &PublicKey) -> InitFeatures {
+/// # 		let mut features = InitFeatures::empty();
+/// # 		features.set_optional_custom_bit(729).unwrap();
+/// # 		features
+/// # 	}
+/// # }
+/// # use LspHandler as SwapHandler;
+/// composite_custom_message_handler!(
+/// 	/// Handles both our LSP and swap protocols.
+/// 	pub struct CompositeHandler {
+/// 		lsp: Arc<LspHandler>,
+/// 		swap: Arc<SwapHandler>,
+/// 	}
+///
+/// 	/// A message of either our LSP or swap protocol.
+/// 	pub enum CompositeMessage {
+/// 		Lsp(37913),
+/// 		Swap(40000..=40099 | 40200),
+/// 	}
+/// );
+///
+/// # fn main() {
+/// let handler = CompositeHandler { lsp: Arc::new(LspHandler), swap: Arc::new(SwapHandler) };
+/// match handler.read(40200, &mut io::Cursor::new(Vec::new())).unwrap() {
+/// 	Some(CompositeMessage::Swap(_)) => {},
+/// 	_ => panic!(),
+/// }
+/// assert!(handler.read(37915, &mut io::Cursor::new(Vec::new())).unwrap().is_none());
+/// # }
+/// ```
+///
+/// [`CustomMessageHandler`]: crate::ln::peer_handler::CustomMessageHandler
+/// [`Deref`]: core::ops::Deref
+#[macro_export]
+macro_rules! composite_custom_message_handler {
+	(
+		$(#[$handler_attrs: meta])*
+		$handler_visibility: vis struct $handler: ident {
+			$($field_visibility: vis $field: ident: $type: ty),* $(,)*
+		}
+
+		$(#[$message_attrs: meta])*
+		$message_visibility: vis enum $message: ident {
+			$($variant: ident($($pattern: pat)|+)),* $(,)*
+		}
+	) => {
+		$(#[$handler_attrs])*
+		$handler_visibility struct $handler {
+			$($field_visibility $field: $type,)*
+		}
+
+		$(#[$message_attrs])*
+		#[derive(Debug)]
+		$message_visibility enum $message {
+			$($variant(<<$type as $crate::ln::custom_message::Deref>::Target as $crate::ln::wire::CustomMessageReader>::CustomMessage),)*
+		}
+
+		impl $crate::ln::wire::Type for $message {
+			fn type_id(&self) -> u16 {
+				match self {
+					$($message::$variant(message) => $crate::ln::wire::Type::type_id(message),)*
+				}
+			}
+		}
+
+		impl $crate::util::ser::Writeable for $message {
+			fn write<W: $crate::util::ser::Writer>(&self, writer: &mut W) -> Result<(), $crate::io::Error> {
+				match self {
+					$($message::$variant(message) => $crate::util::ser::Writeable::write(message, writer),)*
+				}
+			}
+		}
+
+		impl $crate::ln::wire::CustomMessageReader for $handler {
+			type CustomMessage = $message;
+			fn read<R: $crate::io::Read>(&self, message_type: u16, buffer: &mut R) -> Result<Option<$message>, $crate::ln::msgs::DecodeError> {
+				match message_type {
+					$($($pattern)|+ => {
+						let message = $crate::ln::wire::CustomMessageReader::read(&*self.$field, message_type, buffer)?;
+						Ok(message.map($message::$variant))
+					},)*
+					_ => Ok(None),
+				}
+			}
+		}
+
+		impl $crate::ln::peer_handler::CustomMessageHandler for $handler {
+			fn handle_custom_message(&self, msg: $message, sender_node_id: &$crate::ln::custom_message::PublicKey) -> Result<(), $crate::ln::msgs::LightningError> {
+				match msg {
+					$($message::$variant(message) => $crate::ln::peer_handler::CustomMessageHandler::handle_custom_message(&*self.$field, message, sender_node_id),)*
+				}
+			}
+
+			fn get_and_clear_pending_msg(&self) -> Vec<($crate::ln::custom_message::PublicKey, $message)> {
+				let mut msgs = Vec::new();
+				$(
+					msgs.extend($crate::ln::peer_handler::CustomMessageHandler::get_and_clear_pending_msg(&*self.$field)
+						.into_iter().map(|(node_id, message)| (node_id, $message::$variant(message))));
+				)*
+				msgs
+			}
+
+			fn peer_connected(&self, their_node_id: &$crate::ln::custom_message::PublicKey, msg: &$crate::ln::msgs::Init) {
+				$($crate::ln::peer_handler::CustomMessageHandler::peer_connected(&*self.$field, their_node_id, msg);)*
+			}
+
+			fn peer_disconnected(&self, their_node_id: &$crate::ln::custom_message::PublicKey) {
+				$($crate::ln::peer_handler::CustomMessageHandler::peer_disconnected(&*self.$field, their_node_id);)*
+			}
+
+			fn provided_init_features(&self, their_node_id: &$crate::ln::custom_message::PublicKey) -> $crate::ln::features::InitFeatures {
+				let features = $crate::ln::features::InitFeatures::empty();
+				$(let features = features.or($crate::ln::peer_handler::CustomMessageHandler::provided_init_features(&*self.$field, their_node_id));)*
+				features
+			}
+
+			fn provided_node_features(&self) -> $crate::ln::features::NodeFeatures {
+				let features = $crate::ln::features::NodeFeatures::empty();
+				$(let features = features.or($crate::ln::peer_handler::CustomMessageHandler::provided_node_features(&*self.$field));)*
+				features
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+	use io;
+	use ln::features::{InitFeatures, NodeFeatures};
+	use ln::msgs::{self, DecodeError, LightningError};
+	use ln::peer_handler::{CustomMessageHandler, IgnoringMessageHandler, MessageHandler, PeerManager, SocketDescriptor};
+	use ln::wire::{CustomMessageReader, Type};
+	use util::ser::{Readable, Writeable, Writer};
+	use util::test_utils;
+
+	use prelude::*;
+	use sync::{Arc, Mutex};
+
+	#[derive(Debug, PartialEq)]
+	struct TestMessage {
+		type_id: u16,
+		payload: u8,
+	}
+
+	impl Type for TestMessage {
+		fn type_id(&self) -> u16 { self.type_id }
+	}
+
+	impl Writeable for TestMessage {
+		fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+			self.payload.write(writer)
+		}
+	}
+
+	/// Handles the message types in `type_ids`, advertising support for `feature_bit`.
+	struct TestHandler {
+		type_ids: (u16, u16),
+		feature_bit: usize,
+		received: Mutex<Vec<(PublicKey, TestMessage)>>,
+		pending: Mutex<Vec<(PublicKey, TestMessage)>>,
+		connected_peers: Mutex<Vec<PublicKey>>,
+	}
+
+	impl TestHandler {
+		fn new(type_ids: (u16, u16), feature_bit: usize) -> Self {
+			TestHandler {
+				type_ids, feature_bit,
+				received: Mutex::new(Vec::new()),
+				pending: Mutex::new(Vec::new()),
+				connected_peers: Mutex::new(Vec::new()),
+			}
+		}
+	}
+
+	impl CustomMessageReader for TestHandler {
+		type CustomMessage = TestMessage;
+		fn read<R: io::Read>(&self, message_type: u16, buffer: &mut R) -> Result<Option<TestMessage>, DecodeError> {
+			if message_type < self.type_ids.0 || message_type > self.type_ids.1 {
+				return Ok(None);
+			}
+			Ok(Some(TestMessage { type_id: message_type, payload: Readable::read(buffer)? }))
+		}
+	}
+
+	impl CustomMessageHandler for TestHandler {
+		fn handle_custom_message(&self, msg: TestMessage, sender_node_id: &PublicKey) -> Result<(), LightningError> {
+			self.received.lock().unwrap().push((*sender_node_id, msg));
+			Ok(())
+		}
+
+		fn get_and_clear_pending_msg(&self) -> Vec<(PublicKey, TestMessage)> {
+			self.pending.lock().unwrap().split_off(0)
+		}
+
+		fn peer_connected(&self, their_node_id: &PublicKey, _msg: &msgs::Init) {
+			self.connected_peers.lock().unwrap().push(*their_node_id);
+		}
+
+		fn peer_disconnected(&self, their_node_id: &PublicKey) {
+			self.connected_peers.lock().unwrap().retain(|node_id| node_id != their_node_id);
+		}
+
+		fn provided_init_features(&self, _their_node_id: &PublicKey) -> InitFeatures {
+			let mut features = InitFeatures::empty();
+			features.set_required_custom_bit(self.feature_bit).unwrap();
+			features
+		}
+
+		fn provided_node_features(&self) -> NodeFeatures {
+			let mut features = NodeFeatures::empty();
+			features.set_optional_custom_bit(self.feature_bit + 1).unwrap();
+			features
+		}
+	}
+
+	composite_custom_message_handler!(
+		struct CompositeHandler {
+			lsp: Arc<TestHandler>,
+			swap: Arc<TestHandler>,
+		}
+
+		#[derive(PartialEq)]
+		enum CompositeMessage {
+			Lsp(37913 | 37915),
+			Swap(40000..=40099),
+		}
+	);
+
+	fn composite_handler() -> CompositeHandler {
+		CompositeHandler {
+			lsp: Arc::new(TestHandler::new((37913, 37915), 256)),
+			swap: Arc::new(TestHandler::new((40000, 40199), 258)),
+		}
+	}
+
+	#[test]
+	fn dispatches_by_message_type() {
+		let handler = composite_handler();
+		let node_id = PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[42; 32]).unwrap());
+		let read = |type_id: u16| handler.read(type_id, &mut io::Cursor::new(vec![7])).unwrap();
+
+		assert_eq!(read(37913), Some(CompositeMessage::Lsp(TestMessage { type_id: 37913, payload: 7 })));
+		assert_eq!(read(40042), Some(CompositeMessage::Swap(TestMessage { type_id: 40042, payload: 7 })));
+		// 37914 isn't routed to the LSP handler, and the swap handler isn't given 40100 even though
+		// it would read it.
+		assert_eq!(read(37914), None);
+		assert_eq!(read(40100), None);
+		assert!(handler.read(40000, &mut io::Cursor::new(Vec::new())).is_err());
+
+		let message = read(40001).unwrap();
+		assert_eq!(message.type_id(), 40001);
+		assert_eq!(message.encode(), vec![7]);
+		handler.handle_custom_message(message, &node_id).unwrap();
+		assert!(handler.lsp.received.lock().unwrap().is_empty());
+		assert_eq!(handler.swap.received.lock().unwrap().len(), 1);
+
+		handler.lsp.pending.lock().unwrap().push((node_id, TestMessage { type_id: 37915, payload: 1 }));
+		handler.swap.pending.lock().unwrap().push((node_id, TestMessage { type_id: 40002, payload: 2 }));
+		assert_eq!(handler.get_and_clear_pending_msg(), vec![
+			(node_id, CompositeMessage::Lsp(TestMessage { type_id: 37915, payload: 1 })),
+			(node_id, CompositeMessage::Swap(TestMessage { type_id: 40002, payload: 2 })),
+		]);
+		assert!(handler.get_and_clear_pending_msg().is_empty());
+
+		let mut expected_features = NodeFeatures::empty();
+		expected_features.set_optional_custom_bit(257).unwrap();
+		expected_features.set_optional_custom_bit(259).unwrap();
+		assert_eq!(handler.provided_node_features(), expected_features);
+	}
+
+	#[derive(Clone)]
+	struct TestDescriptor {
+		fd: u16,
+		outbound_data: Arc<Mutex<Vec<u8>>>,
+	}
+	impl PartialEq for TestDescriptor {
+		fn eq(&self, other: &Self) -> bool { self.fd == other.fd }
+	}
+	impl Eq for TestDescriptor {}
+	impl core::hash::Hash for TestDescriptor {
+		fn hash<H: core::hash::Hasher>(&self, hasher: &mut H) { self.fd.hash(hasher) }
+	}
+	impl SocketDescriptor for TestDescriptor {
+		fn send_data(&mut self, data: &[u8], _resume_read: bool) -> usize {
+			self.outbound_data.lock().unwrap().extend_from_slice(data);
+			data.len()
+		}
+		fn disconnect_socket(&mut self) {}
+	}
+
+	#[test]
+	fn advertises_features_and_tracks_peers() {
+		// Our peer requires the custom feature bits of both our handlers, which we thus accept,
+		// while a peer with a plain handler disconnects us as we require them too.
+		let chan_handler = test_utils::TestChannelMessageHandler::new();
+		let logger = test_utils::TestLogger::new();
+		let msg_handler = || MessageHandler { chan_handler: &chan_handler, route_handler: IgnoringMessageHandler {} };
+		let (handler_a, handler_b) = (composite_handler(), composite_handler());
+		let peer_a = PeerManager::new(msg_handler(), SecretKey::from_slice(&[42; 32]).unwrap(), &[0; 32], &logger, &handler_a);
+		let peer_b = PeerManager::new(msg_handler(), SecretKey::from_slice(&[43; 32]).unwrap(), &[1; 32], &logger, &handler_b);
+		let plain_peer = PeerManager::new(msg_handler(), SecretKey::from_slice(&[44; 32]).unwrap(), &[2; 32], &logger, IgnoringMessageHandler {});
+		let secp_ctx = Secp256k1::new();
+		let a_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[42; 32]).unwrap());
+		let b_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[43; 32]).unwrap());
+
+		let mut fd_a = TestDescriptor { fd: 1, outbound_data: Arc::new(Mutex::new(Vec::new())) };
+		let mut fd_b = TestDescriptor { fd: 1, outbound_data: Arc::new(Mutex::new(Vec::new())) };
+		let initial_data = peer_b.new_outbound_connection(a_id, fd_b.clone(), None).unwrap();
+		peer_a.new_inbound_connection(fd_a.clone(), None).unwrap();
+		peer_a.read_event(&mut fd_a, &initial_data).unwrap();
+		for _ in 0..3 {
+			peer_a.process_events();
+			let a_data = fd_a.outbound_data.lock().unwrap().split_off(0);
+			peer_b.read_event(&mut fd_b, &a_data).unwrap();
+			peer_b.process_events();
+			let b_data = fd_b.outbound_data.lock().unwrap().split_off(0);
+			peer_a.read_event(&mut fd_a, &b_data).unwrap();
+		}
+		assert_eq!(*handler_a.lsp.connected_peers.lock().unwrap(), vec![b_id]);
+		assert_eq!(*handler_a.swap.connected_peers.lock().unwrap(), vec![b_id]);
+		assert_eq!(*handler_b.swap.connected_peers.lock().unwrap(), vec![a_id]);
+
+		// Custom messages queued by either handler reach the right handler on the other side.
+		handler_b.swap.pending.lock().unwrap().push((a_id, TestMessage { type_id: 40005, payload: 5 }));
+		peer_b.process_events();
+		let b_data = fd_b.outbound_data.lock().unwrap().split_off(0);
+		peer_a.read_event(&mut fd_a, &b_data).unwrap();
+		assert_eq!(*handler_a.swap.received.lock().unwrap(), vec![(b_id, TestMessage { type_id: 40005, payload: 5 })]);
+		assert!(handler_a.lsp.received.lock().unwrap().is_empty());
+
+		peer_a.socket_disconnected(&fd_a);
+		assert!(handler_a.lsp.connected_peers.lock().unwrap().is_empty());
+		assert!(handler_a.swap.connected_peers.lock().unwrap().is_empty());
+
+		let mut fd_plain = TestDescriptor { fd: 2, outbound_data: Arc::new(Mutex::new(Vec::new())) };
+		let mut fd_a = TestDescriptor { fd: 2, outbound_data: Arc::new(Mutex::new(Vec::new())) };
+		let initial_data = plain_peer.new_outbound_connection(a_id, fd_plain.clone(), None).unwrap();
+		peer_a.new_inbound_connection(fd_a.clone(), None).unwrap();
+		peer_a.read_event(&mut fd_a, &initial_data).unwrap();
+		peer_a.process_events();
+		let a_data = fd_a.outbound_data.lock().unwrap().split_off(0);
+		plain_peer.read_event(&mut fd_plain, &a_data).unwrap();
+		plain_peer.process_events();
+		let plain_data = fd_plain.outbound_data.lock().unwrap().split_off(0);
+		peer_a.read_event(&mut fd_a, &plain_data).unwrap();
+		peer_a.process_events();
+		let plain_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[44; 32]).unwrap());
+		assert_eq!(*handler_a.lsp.connected_peers.lock().unwrap(), vec![plain_id]);
+		let a_data = fd_a.outbound_data.lock().unwrap().split_off(0);
+		assert!(plain_peer.read_event(&mut fd_plain, &a_data).is_err());
+		peer_a.socket_disconnected(&fd_a);
+		assert!(handler_a.lsp.connected_peers.lock().unwrap().is_empty());
+	}
+}
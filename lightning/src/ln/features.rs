@@ -525,16 +525,6 @@ impl InitFeatures {
 		Ok(())
 	}
 
-	/// or's another InitFeatures into this one.
-	pub(crate) fn or(mut self, o: InitFeatures) -> InitFeatures {
-		let total_feature_len = cmp::max(self.flags.len(), o.flags.len());
-		self.flags.resize(total_feature_len, 0u8);
-		for (byte, o_byte) in self.flags.iter_mut().zip(o.flags.iter()) {
-			*byte |= *o_byte;
-		}
-		self
-	}
-
 	/// Converts `InitFeatures` to `Features<C>`. Only known `InitFeatures` relevant to context `C`
 	/// are included in the result.
 	pub(crate) fn to_context<C: sealed::Context>(&self) -> Features<C> {
@@ -648,6 +638,10 @@ impl FromBase32 for InvoiceFeatures {
 	}
 }
 
+/// The lowest feature bit which may be set via [`Features::set_required_custom_bit`] or
+/// [`Features::set_optional_custom_bit`], leaving lower bits to be assigned by the BOLTs.
+const MIN_CUSTOM_FEATURE_BIT: usize = 256;
+
 impl<T: sealed::Context> Features<T> {
 	/// Create a blank Features with no features set
 	pub fn empty() -> Self {
@@ -665,6 +659,53 @@ impl<T: sealed::Context> Features<T> {
 		}
 	}
 
+	/// Returns the union of the features set in this `Features` and in `o`.
+	pub fn or(mut self, o: Self) -> Self {
+		let total_feature_len = cmp::max(self.flags.len(), o.flags.len());
+		self.flags.resize(total_feature_len, 0u8);
+		for (byte, o_byte) in self.flags.iter_mut().zip(o.flags.iter()) {
+			*byte |= *o_byte;
+		}
+		self
+	}
+
+	/// Sets a required custom feature bit. Errors if `bit` is outside the custom range, i.e., is
+	/// below 256, or is not even.
+	///
+	/// Custom feature bits are not known to the implementation, but may be advertised by a
+	/// [`CustomMessageHandler`] to signal support for the protocol it implements.
+	///
+	/// [`CustomMessageHandler`]: crate::ln::peer_handler::CustomMessageHandler
+	pub fn set_required_custom_bit(&mut self, bit: usize) -> Result<(), ()> {
+		if bit % 2 != 0 {
+			return Err(());
+		}
+		self.set_custom_bit(bit)
+	}
+
+	/// Sets an optional custom feature bit. Errors if `bit` is outside the custom range, i.e., is
+	/// below 256, or is not odd.
+	///
+	/// See [`Self::set_required_custom_bit`] for more details.
+	pub fn set_optional_custom_bit(&mut self, bit: usize) -> Result<(), ()> {
+		if bit % 2 != 1 {
+			return Err(());
+		}
+		self.set_custom_bit(bit)
+	}
+
+	fn set_custom_bit(&mut self, bit: usize) -> Result<(), ()> {
+		if bit < MIN_CUSTOM_FEATURE_BIT {
+			return Err(());
+		}
+		let byte_offset = bit / 8;
+		if self.flags.len() <= byte_offset {
+			self.flags.resize(byte_offset + 1, 0u8);
+		}
+		self.flags[byte_offset] |= 1 << (bit % 8);
+		Ok(())
+	}
+
 	/// Converts `Features<T>` to `Features<C>`. Only known `T` features relevant to context `C` are
 	/// included in the result.
 	fn to_context_internal<C: sealed::Context>(&self) -> Features<C> {
@@ -733,6 +774,24 @@ impl<T: sealed::Context> Features<T> {
 		})
 	}
 
+	/// Returns true if this `Features` object contains feature flags which are set as "required"
+	/// and are neither known to the implementation nor supported (as either required or optional)
+	/// in `supported`.
+	pub(crate) fn requires_unknown_bits_not_in(&self, supported: &Self) -> bool {
+		let byte_count = T::KNOWN_FEATURE_MASK.len();
+		self.flags.iter().enumerate().any(|(i, &byte)| {
+			let required_features = 0b01_01_01_01;
+			let supported_byte = supported.flags.get(i).cloned().unwrap_or(0);
+			let supported_features = supported_byte | ((supported_byte & 0b10_10_10_10) >> 1);
+			let unknown_features = if i < byte_count {
+				!T::KNOWN_FEATURE_MASK[i]
+			} else {
+				0b11_11_11_11
+			};
+			(byte & (required_features & unknown_features & !supported_features)) != 0
+		})
+	}
+
 	pub(crate) fn supports_unknown_bits(&self) -> bool {
 		// Bitwise AND-ing with all even and odd bits set except for known features will select
 		// both required and optional unknown features.
@@ -990,6 +1049,34 @@ mod tests {
 		assert!(features.supports_payment_secret());
 	}
 
+	#[test]
+	fn set_custom_feature_bits() {
+		let mut features = InitFeatures::empty();
+		assert!(features.set_required_custom_bit(255).is_err());
+		assert!(features.set_optional_custom_bit(254).is_err());
+		assert!(features.set_required_custom_bit(257).is_err());
+		assert!(features.set_optional_custom_bit(256).is_err());
+		features.set_optional_custom_bit(257).unwrap();
+		assert!(!features.requires_unknown_bits());
+		assert!(features.supports_unknown_bits());
+
+		let mut required = InitFeatures::empty();
+		required.set_required_custom_bit(258).unwrap();
+		assert!(required.requires_unknown_bits());
+		assert_eq!(required.le_flags().len(), 33);
+
+		// Required custom bits are only a problem if we don't support them ourselves.
+		assert!(required.requires_unknown_bits_not_in(&features));
+		let mut supported = InitFeatures::empty();
+		supported.set_optional_custom_bit(259).unwrap();
+		assert!(!required.requires_unknown_bits_not_in(&supported));
+		assert!(!required.requires_unknown_bits_not_in(&required.clone()));
+
+		let combined = features.or(required);
+		assert!(combined.requires_unknown_bits());
+		assert!(combined.supports_unknown_bits());
+	}
+
 	#[test]
 	fn invoice_features_encoding() {
 		let features_as_u5s = vec![
@@ -12,7 +12,7 @@
 //! claim outputs on-chain.
 
 use chain;
-use chain::{Confirm, Listen, Watch};
+use chain::{Confirm, Listen, MempoolListener, Watch};
 use chain::chaininterface::LowerBoundedFeeEstimator;
 use chain::channelmonitor;
 use chain::channelmonitor::{ChannelMonitor, CLTV_CLAIM_BUFFER, LATENCY_GRACE_PERIOD_BLOCKS, ANTI_REORG_DELAY};
@@ -2874,6 +2874,162 @@ fn test_htlc_on_chain_success() {
 	check_tx_local_broadcast!(nodes[0], true, node_a_commitment_tx[0], chan_1.3);
 }
 
+#[test]
+fn test_htlc_preimage_learned_from_mempool() {
+	// Test that a preimage revealed by an unconfirmed HTLC-Success transaction is passed backwards
+	// as soon as the transaction is seen in the mempool, without closing the downstream channel.
+	// A --------------------> B ----------------------> C (preimage)
+	use core::future::Future;
+	use core::pin::Pin;
+	use core::sync::atomic::{AtomicUsize, Ordering};
+	use core::task::{Context, Poll};
+
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, None, None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let chan_2 = create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+
+	let (payment_preimage, payment_hash, _payment_secret) = route_payment(&nodes[0], &[&nodes[1], &nodes[2]], 3_000_000);
+
+	// C claims the HTLC but B never receives the update_fulfill_htlc. Instead, C goes on-chain.
+	let commitment_tx = get_local_commitment_txn!(nodes[2], chan_2.2);
+	assert_eq!(commitment_tx.len(), 1);
+	nodes[2].node.claim_funds(payment_preimage);
+	expect_payment_claimed!(nodes[2], payment_hash, 3_000_000);
+	check_added_monitors!(nodes[2], 1);
+	get_htlc_update_msgs!(nodes[2], nodes[1].node.get_our_node_id());
+
+	mine_transaction(&nodes[2], &commitment_tx[0]);
+	check_closed_broadcast!(nodes[2], true);
+	check_added_monitors!(nodes[2], 1);
+	check_closed_event!(nodes[2], 1, ClosureReason::CommitmentTxConfirmed);
+	let htlc_success_tx = nodes[2].tx_broadcaster.txn_broadcasted.lock().unwrap()[0].clone();
+	check_spends!(htlc_success_tx, commitment_tx[0]);
+	assert_eq!(htlc_success_tx.input[0].witness.last().unwrap().len(), ACCEPTED_HTLC_SCRIPT_WEIGHT);
+
+	let wake_count = Arc::new(AtomicUsize::new(0));
+	let waker = test_utils::counting_waker(&wake_count);
+	let mut update_future = nodes[1].chain_monitor.chain_monitor.get_update_future();
+	assert_eq!(Pin::new(&mut update_future).poll(&mut Context::from_waker(&waker)), Poll::Pending);
+
+	// Transactions not revealing a preimage, such as the commitment transaction itself, are ignored.
+	nodes[1].chain_monitor.chain_monitor.transactions_seen_in_mempool(&[commitment_tx[0].clone()]);
+	assert!(nodes[1].node.get_and_clear_pending_msg_events().is_empty());
+	assert_eq!(wake_count.load(Ordering::Acquire), 0);
+
+	// Once B sees the HTLC-Success transaction unconfirmed, it claims the HTLC from A right away,
+	// and anyone waiting on new events from the ChainMonitor is woken.
+	nodes[1].chain_monitor.chain_monitor.transactions_seen_in_mempool(&[commitment_tx[0].clone(), htlc_success_tx.clone()]);
+	assert_eq!(wake_count.load(Ordering::Acquire), 1);
+	assert_eq!(Pin::new(&mut update_future).poll(&mut Context::from_waker(&waker)), Poll::Ready(()));
+	let updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	check_added_monitors!(nodes[1], 1);
+	assert_eq!(updates.update_fulfill_htlcs.len(), 1);
+	expect_payment_forwarded!(nodes[1], nodes[0], nodes[2], Some(1000), true, false);
+
+	nodes[0].node.handle_update_fulfill_htlc(&nodes[1].node.get_our_node_id(), &updates.update_fulfill_htlcs[0]);
+	commitment_signed_dance!(nodes[0], nodes[1], updates.commitment_signed, false);
+	expect_payment_sent!(nodes[0], payment_preimage);
+
+	// Nothing was done on-chain as the transactions may never confirm, so the channel with C is
+	// still open from B's point of view, and seeing the same transaction again is a no-op.
+	assert!(nodes[1].node.list_channels().iter().any(|chan| chan.channel_id == chan_2.2));
+	assert!(nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+	nodes[1].chain_monitor.chain_monitor.transactions_seen_in_mempool(&[htlc_success_tx]);
+	assert!(nodes[1].node.get_and_clear_pending_msg_events().is_empty());
+	assert!(nodes[1].node.get_and_clear_pending_events().is_empty());
+}
+
+#[test]
+fn test_justice_tx_broadcast_from_mempool() {
+	// Test that a revoked counterparty commitment transaction is punished as soon as it is seen in
+	// the mempool, without closing the channel as the transaction may never confirm.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	let chan_1 = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+
+	let payment_preimage = route_payment(&nodes[0], &[&nodes[1]], 3_000_000).0;
+	let revoked_local_txn = get_local_commitment_txn!(nodes[0], chan_1.2);
+	assert_eq!(revoked_local_txn[0].input[0].previous_output.txid, chan_1.3.txid());
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage);
+
+	// Both the to_local output and the HTLC output are claimed right away in a single transaction.
+	nodes[1].chain_monitor.chain_monitor.transactions_seen_in_mempool(&[revoked_local_txn[0].clone()]);
+	{
+		let mut node_txn = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap();
+		assert_eq!(node_txn.len(), 1);
+		assert_eq!(node_txn[0].input.len(), 2);
+		check_spends!(node_txn[0], revoked_local_txn[0]);
+		node_txn.clear();
+	}
+	assert!(nodes[1].node.get_and_clear_pending_msg_events().is_empty());
+	assert!(nodes[1].node.list_channels().iter().any(|chan| chan.channel_id == chan_1.2));
+
+	// Seeing the transaction again is a no-op.
+	nodes[1].chain_monitor.chain_monitor.transactions_seen_in_mempool(&[revoked_local_txn[0].clone()]);
+	assert!(nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+	assert!(get_monitor!(nodes[1], chan_1.2).inner.lock().unwrap().mempool_txids_handled.contains_key(&revoked_local_txn[0].txid()));
+
+	// Once it confirms, the claims are tracked as usual and the transaction is forgotten about.
+	mine_transaction(&nodes[1], &revoked_local_txn[0]);
+	check_closed_broadcast!(nodes[1], true);
+	check_added_monitors!(nodes[1], 1);
+	check_closed_event!(nodes[1], 1, ClosureReason::CommitmentTxConfirmed);
+	assert!(get_monitor!(nodes[1], chan_1.2).inner.lock().unwrap().mempool_txids_handled.is_empty());
+	let node_txn = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap().clone();
+	assert_eq!(node_txn[0].input.len(), 2);
+	check_spends!(node_txn[0], revoked_local_txn[0]);
+}
+
+#[test]
+fn test_dust_htlc_failed_back_from_mempool() {
+	// Test that once a channel has been closed on our side, an outbound HTLC which is dust in every
+	// commitment transaction is failed back as soon as a commitment transaction is seen in the
+	// mempool, and isn't failed back again once the commitment transaction confirms.
+	// A --------------------> B ----------------------> C
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, None, None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let chan_2 = create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+
+	let bs_dust_limit = nodes[1].node.channel_state.lock().unwrap().by_id.get(&chan_2.2).unwrap().holder_dust_limit_satoshis;
+	route_payment(&nodes[0], &[&nodes[1], &nodes[2]], bs_dust_limit * 1000);
+
+	nodes[1].node.force_close_broadcasting_latest_txn(&chan_2.2, &nodes[2].node.get_our_node_id()).unwrap();
+	check_closed_broadcast!(nodes[1], true);
+	check_added_monitors!(nodes[1], 1);
+	check_closed_event!(nodes[1], 1, ClosureReason::HolderForceClosed);
+	let commitment_tx = {
+		let mut node_txn = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap();
+		assert_eq!(node_txn.len(), 1);
+		check_spends!(node_txn[0], chan_2.3);
+		node_txn.remove(0)
+	};
+
+	nodes[1].chain_monitor.chain_monitor.transactions_seen_in_mempool(&[commitment_tx.clone()]);
+	expect_pending_htlcs_forwardable_and_htlc_handling_failed!(nodes[1], vec![HTLCDestination::NextHopChannel { node_id: Some(nodes[2].node.get_our_node_id()), channel_id: chan_2.2 }]);
+	check_added_monitors!(nodes[1], 1);
+	let updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	assert_eq!(updates.update_fail_htlcs.len(), 1);
+
+	// Seeing the transaction again, or it reaching ANTI_REORG_DELAY confirmations, doesn't fail the
+	// HTLC back again.
+	nodes[1].chain_monitor.chain_monitor.transactions_seen_in_mempool(&[commitment_tx.clone()]);
+	mine_transaction(&nodes[1], &commitment_tx);
+	connect_blocks(&nodes[1], ANTI_REORG_DELAY - 1);
+	assert!(nodes[1].node.get_and_clear_pending_events().is_empty());
+	assert!(nodes[1].node.get_and_clear_pending_msg_events().is_empty());
+}
+
 fn do_test_htlc_on_chain_timeout(connect_style: ConnectStyle) {
 	// Test that in case of a unilateral close onchain, we detect the state of output and
 	// timeout the HTLC backward accordingly. So here we test that ChannelManager is
@@ -29,6 +29,8 @@ pub mod channelmanager;
 pub mod inbound_payment;
 pub mod msgs;
 pub mod peer_handler;
+pub mod peer_policy;
+pub mod custom_message;
 pub mod chan_utils;
 pub mod features;
 pub mod script;
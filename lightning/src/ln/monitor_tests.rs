@@ -9,7 +9,7 @@
 
 //! Further functional tests which test blockchain reorganizations.
 
-use chain::channelmonitor::{ANTI_REORG_DELAY, Balance};
+use chain::channelmonitor::{ANTI_REORG_DELAY, ARCHIVAL_DELAY_BLOCKS, Balance};
 use chain::transaction::OutPoint;
 use ln::channel;
 use ln::channelmanager::BREAKDOWN_TIMEOUT;
@@ -223,6 +223,152 @@ fn chanmon_claim_value_coop_close() {
 	check_closed_event!(nodes[1], 1, ClosureReason::CooperativeClosure);
 }
 
+#[test]
+fn archive_fully_resolved_monitors() {
+	// Tests that a `ChannelMonitor` is only archived once it has had no claimable balances for
+	// `ARCHIVAL_DELAY_BLOCKS`, after which it is no longer watched on-chain.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	let (_, _, chan_id, funding_tx) = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let funding_outpoint = OutPoint { txid: funding_tx.txid(), index: 0 };
+	assert!(nodes[0].chain_monitor.chain_monitor.archive_fully_resolved_channel_monitors().is_empty());
+
+	let (_, _, closing_tx) = close_channel(&nodes[0], &nodes[1], &chan_id, funding_tx, true);
+	mine_transaction(&nodes[0], &closing_tx);
+	mine_transaction(&nodes[1], &closing_tx);
+	check_closed_event!(nodes[0], 1, ClosureReason::CooperativeClosure);
+	check_closed_event!(nodes[1], 1, ClosureReason::CooperativeClosure);
+
+	// Balances remain until the closing transaction has reached ANTI_REORG_DELAY confirmations.
+	assert!(nodes[0].chain_monitor.chain_monitor.archive_fully_resolved_channel_monitors().is_empty());
+	connect_blocks(&nodes[0], ANTI_REORG_DELAY - 1);
+	test_spendable_output(&nodes[0], &closing_tx);
+	assert_eq!(Vec::<Balance>::new(),
+		nodes[0].chain_monitor.chain_monitor.get_monitor(funding_outpoint).unwrap().get_claimable_balances());
+
+	assert!(nodes[0].chain_monitor.chain_monitor.archive_fully_resolved_channel_monitors().is_empty());
+	connect_blocks(&nodes[0], ARCHIVAL_DELAY_BLOCKS - 1);
+	assert!(nodes[0].chain_monitor.chain_monitor.archive_fully_resolved_channel_monitors().is_empty());
+	connect_blocks(&nodes[0], 1);
+	assert_eq!(nodes[0].chain_monitor.chain_monitor.archive_fully_resolved_channel_monitors(), vec![funding_outpoint]);
+
+	assert!(nodes[0].chain_monitor.chain_monitor.list_monitors().is_empty());
+	assert!(chanmon_cfgs[0].persister.archived_channels.lock().unwrap().contains(&funding_outpoint));
+	assert!(!chanmon_cfgs[0].chain_source.watched_txn.lock().unwrap().iter().any(|(txid, _)| *txid == funding_outpoint.txid));
+	assert!(nodes[0].chain_monitor.chain_monitor.archive_fully_resolved_channel_monitors().is_empty());
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn archive_monitor_racing_block_connection() {
+	// Tests that a block connected while a `ChannelMonitor` is being archived doesn't persist it
+	// again, which would leave a stale copy of the archived monitor to be loaded on startup.
+	use chain;
+	use chain::ChannelMonitorUpdateErr;
+	use chain::chainmonitor::{ChainMonitor, MonitorUpdateId, Persist};
+	use chain::channelmonitor::{ChannelMonitor, ChannelMonitorUpdate};
+	use util::enforcing_trait_impls::EnforcingSigner;
+	use util::ser::{ReadableArgs, Writeable};
+	use util::test_utils;
+
+	use bitcoin::blockdata::block::{Block, BlockHeader};
+	use bitcoin::hash_types::BlockHash;
+
+	use io;
+	use core::time::Duration;
+	use sync::{Arc, Condvar, Mutex};
+
+	/// A [`Persist`] implementation recording the order of writes, which notifies a waiting
+	/// thread and stalls once archiving starts.
+	struct StallingArchivePersister {
+		writes: Mutex<Vec<(&'static str, OutPoint)>>,
+		archiving: Mutex<bool>,
+		archiving_condvar: Condvar,
+	}
+
+	impl Persist<EnforcingSigner> for StallingArchivePersister {
+		fn persist_new_channel(&self, funding_txo: OutPoint, _data: &ChannelMonitor<EnforcingSigner>, _update_id: MonitorUpdateId) -> Result<(), ChannelMonitorUpdateErr> {
+			self.writes.lock().unwrap().push(("persist", funding_txo));
+			Ok(())
+		}
+
+		fn update_persisted_channel(&self, funding_txo: OutPoint, _update: &Option<ChannelMonitorUpdate>, _data: &ChannelMonitor<EnforcingSigner>, _update_id: MonitorUpdateId) -> Result<(), ChannelMonitorUpdateErr> {
+			self.writes.lock().unwrap().push(("update", funding_txo));
+			Ok(())
+		}
+
+		fn archive_persisted_channel(&self, funding_txo: OutPoint, _data: &ChannelMonitor<EnforcingSigner>) -> Result<(), ()> {
+			self.writes.lock().unwrap().push(("archive", funding_txo));
+			*self.archiving.lock().unwrap() = true;
+			self.archiving_condvar.notify_all();
+			// Give the block connection a chance to run before the monitor is removed.
+			std::thread::sleep(Duration::from_millis(100));
+			Ok(())
+		}
+	}
+
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	let (_, _, chan_id, funding_tx) = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let funding_outpoint = OutPoint { txid: funding_tx.txid(), index: 0 };
+	let (_, _, closing_tx) = close_channel(&nodes[0], &nodes[1], &chan_id, funding_tx, true);
+	mine_transaction(&nodes[0], &closing_tx);
+	mine_transaction(&nodes[1], &closing_tx);
+	check_closed_event!(nodes[0], 1, ClosureReason::CooperativeClosure);
+	check_closed_event!(nodes[1], 1, ClosureReason::CooperativeClosure);
+	connect_blocks(&nodes[0], ANTI_REORG_DELAY - 1);
+	test_spendable_output(&nodes[0], &closing_tx);
+	assert!(nodes[0].chain_monitor.chain_monitor.archive_fully_resolved_channel_monitors().is_empty());
+	connect_blocks(&nodes[0], ARCHIVAL_DELAY_BLOCKS);
+
+	// Load the fully resolved monitor into a `ChainMonitor` which may be shared across threads.
+	let encoded_monitor = nodes[0].chain_monitor.chain_monitor.get_monitor(funding_outpoint).unwrap().encode();
+	let (_, monitor) = <(BlockHash, ChannelMonitor<EnforcingSigner>)>::read(
+		&mut io::Cursor::new(&encoded_monitor), nodes[0].keys_manager).unwrap();
+	let best_block = monitor.current_best_block();
+	let persister = Arc::new(StallingArchivePersister {
+		writes: Mutex::new(Vec::new()), archiving: Mutex::new(false), archiving_condvar: Condvar::new(),
+	});
+	let chain_monitor = Arc::new(ChainMonitor::new(
+		None::<Arc<test_utils::TestChainSource>>,
+		Arc::new(test_utils::TestBroadcaster::new(Arc::new(Mutex::new(Vec::new())))),
+		Arc::new(test_utils::TestLogger::new()),
+		Arc::new(test_utils::TestFeeEstimator { sat_per_kw: Mutex::new(253) }),
+		Arc::clone(&persister)));
+	chain::Watch::watch_channel(&*chain_monitor, funding_outpoint, monitor).unwrap();
+
+	// Connect a block as soon as archiving starts, which must wait for the monitor to be removed.
+	let block_connection = {
+		let chain_monitor = Arc::clone(&chain_monitor);
+		let persister = Arc::clone(&persister);
+		std::thread::spawn(move || {
+			let mut archiving = persister.archiving.lock().unwrap();
+			while !*archiving {
+				archiving = persister.archiving_condvar.wait(archiving).unwrap();
+			}
+			core::mem::drop(archiving);
+			let monitors = chain_monitor.list_monitors();
+			let header = BlockHeader {
+				version: 0x20000000, prev_blockhash: best_block.block_hash(), merkle_root: Default::default(),
+				time: 42, bits: 42, nonce: 42,
+			};
+			chain::Listen::block_connected(&*chain_monitor, &Block { header, txdata: Vec::new() }, best_block.height() + 1);
+			monitors
+		})
+	};
+	assert_eq!(chain_monitor.archive_fully_resolved_channel_monitors(), vec![funding_outpoint]);
+	assert!(block_connection.join().unwrap().is_empty());
+
+	assert_eq!(*persister.writes.lock().unwrap(), vec![("persist", funding_outpoint), ("archive", funding_outpoint)]);
+	assert!(chain_monitor.list_monitors().is_empty());
+}
+
 fn sorted_vec<T: Ord>(mut v: Vec<T>) -> Vec<T> {
 	v.sort_unstable();
 	v
@@ -877,6 +877,15 @@ pub trait ChannelMessageHandler : MessageSendEventsProvider {
 
 	/// Handle a peer reconnecting, possibly generating channel_reestablish message(s).
 	fn peer_connected(&self, their_node_id: &PublicKey, msg: &Init);
+	/// Returns true if we have any channel with the given peer, including ones still being opened.
+	///
+	/// Used by the [`PeerManager`] to decide which peers count against
+	/// [`PeerConnectionLimits::max_peers_without_channels`] and which may be disconnected to make
+	/// room for new inbound connections.
+	///
+	/// [`PeerManager`]: crate::ln::peer_handler::PeerManager
+	/// [`PeerConnectionLimits::max_peers_without_channels`]: crate::ln::peer_handler::PeerConnectionLimits::max_peers_without_channels
+	fn has_channels_with(&self, their_node_id: &PublicKey) -> bool;
 	/// Handle an incoming channel_reestablish message from the given peer.
 	fn handle_channel_reestablish(&self, their_node_id: &PublicKey, msg: &ChannelReestablish);
 
@@ -633,10 +633,10 @@ fn do_test_dup_htlc_onchain_fails_on_reload(persist_manager_post_event: bool, co
 	}
 
 	// Now connect the HTLC claim transaction with the ChainMonitor-generated ChannelMonitor update
-	// returning TemporaryFailure. This should cause the claim event to never make its way to the
+	// returning InProgress. This should cause the claim event to never make its way to the
 	// ChannelManager.
 	chanmon_cfgs[0].persister.chain_sync_monitor_persistences.lock().unwrap().clear();
-	chanmon_cfgs[0].persister.set_update_ret(Err(ChannelMonitorUpdateErr::TemporaryFailure));
+	chanmon_cfgs[0].persister.set_update_ret(Err(ChannelMonitorUpdateErr::InProgress));
 
 	if payment_timeout {
 		connect_blocks(&nodes[0], 1);
@@ -17,12 +17,13 @@
 
 use bitcoin::secp256k1::{self, Secp256k1, SecretKey, PublicKey};
 
-use ln::features::InitFeatures;
+use ln::features::{InitFeatures, NodeFeatures};
 use ln::msgs;
 use ln::msgs::{ChannelMessageHandler, LightningError, NetAddress, RoutingMessageHandler};
 use ln::channelmanager::{SimpleArcChannelManager, SimpleRefChannelManager};
 use util::ser::{VecWriter, Writeable, Writer};
 use ln::peer_channel_encryptor::{PeerChannelEncryptor,NextNoiseStep};
+use ln::peer_policy::{AllowAllPeerPolicy, PeerMisbehavior, PeerPolicy};
 use ln::wire;
 use ln::wire::Encode;
 use routing::gossip::{NetworkGraph, P2PGossipSync};
@@ -38,6 +39,7 @@ use core::sync::atomic::{AtomicBool, Ordering};
 use core::{cmp, hash, fmt, mem};
 use core::ops::Deref;
 use core::convert::Infallible;
+use core::time::Duration;
 #[cfg(feature = "std")] use std::error;
 
 use bitcoin::hashes::sha256::Hash as Sha256;
@@ -55,6 +57,29 @@ pub trait CustomMessageHandler: wire::CustomMessageReader {
 	/// correspond to the intended recipients node ids. If no connection to one of the
 	/// specified node does not exist, the message is simply not sent to it.
 	fn get_and_clear_pending_msg(&self) -> Vec<(PublicKey, Self::CustomMessage)>;
+
+	/// Called when a connection is established with a peer, once we've received its `init`
+	/// message.
+	fn peer_connected(&self, _their_node_id: &PublicKey, _msg: &msgs::Init) {}
+
+	/// Called when a connection to a peer which we previously told the handler about via
+	/// [`Self::peer_connected`] is closed.
+	fn peer_disconnected(&self, _their_node_id: &PublicKey) {}
+
+	/// Gets the feature bits this handler wishes to set in the `init` message we send to the peer
+	/// with the given node id, in addition to those set by LDK.
+	///
+	/// As peers which require unknown feature bits are disconnected, any (custom) feature bits the
+	/// peer requires which are supported (as either required or optional) here are considered
+	/// known.
+	fn provided_init_features(&self, _their_node_id: &PublicKey) -> InitFeatures { InitFeatures::empty() }
+
+	/// Gets the feature bits this handler wishes to set in our `node_announcement`, in addition to
+	/// those set by LDK. Pass them to [`ChannelManager::broadcast_node_announcement_with_features`]
+	/// to have them announced.
+	///
+	/// [`ChannelManager::broadcast_node_announcement_with_features`]: crate::ln::channelmanager::ChannelManager::broadcast_node_announcement_with_features
+	fn provided_node_features(&self) -> NodeFeatures { NodeFeatures::empty() }
 }
 
 /// A dummy struct which implements `RoutingMessageHandler` without storing any routing information
@@ -191,6 +216,7 @@ impl ChannelMessageHandler for ErroringMessageHandler {
 	fn handle_channel_update(&self, _their_node_id: &PublicKey, _msg: &msgs::ChannelUpdate) {}
 	fn peer_disconnected(&self, _their_node_id: &PublicKey, _no_connection_possible: bool) {}
 	fn peer_connected(&self, _their_node_id: &PublicKey, _msg: &msgs::Init) {}
+	fn has_channels_with(&self, _their_node_id: &PublicKey) -> bool { false }
 	fn handle_error(&self, _their_node_id: &PublicKey, _msg: &msgs::ErrorMessage) {}
 }
 impl Deref for ErroringMessageHandler {
@@ -296,10 +322,10 @@ const FORWARD_INIT_SYNC_BUFFER_LIMIT_RATIO: usize = 2;
 
 /// When the outbound buffer has this many messages, we'll stop reading bytes from the peer until
 /// we have fewer than this many messages in the outbound buffer again.
-/// We also use this as the target number of outbound gossip messages to keep in the write buffer,
-/// refilled as we send bytes.
+/// We also use this as the target number of initial sync gossip messages to keep in the gossip
+/// buffer, refilled as we send bytes.
 const OUTBOUND_BUFFER_LIMIT_READ_PAUSE: usize = 10;
-/// When the outbound buffer has this many messages, we'll simply skip relaying gossip messages to
+/// When the gossip buffer has this many messages, we'll simply skip relaying gossip messages to
 /// the peer.
 const OUTBOUND_BUFFER_LIMIT_DROP_GOSSIP: usize = OUTBOUND_BUFFER_LIMIT_READ_PAUSE * FORWARD_INIT_SYNC_BUFFER_LIMIT_RATIO;
 
@@ -325,14 +351,269 @@ const MAX_BUFFER_DRAIN_TICK_INTERVALS_PER_PEER: i8 = 4;
 /// process before the next ping.
 const BUFFER_DRAIN_MSGS_PER_TICK: usize = 32;
 
+/// Limits on the peers a [`PeerManager`] will be connected to, protecting against being flooded
+/// with inbound connections. Outbound connections are never refused, but do count against the
+/// limits on inbound ones.
+///
+/// Passed to [`PeerManager::new_with_limits`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerConnectionLimits {
+	/// The maximum number of peers we'll be connected to before refusing new inbound connections.
+	///
+	/// When this limit is reached, an idle inbound peer with which we have no channels is
+	/// disconnected to make room for a new inbound connection, if there is one.
+	///
+	/// Default value: 500
+	pub max_total_peers: usize,
+	/// The maximum number of inbound peers with which we have no channels we'll be connected to
+	/// before refusing new such peers. This is checked once the peer has identified itself by
+	/// completing the handshake. Outbound peers don't count towards this limit.
+	///
+	/// Default value: 250
+	pub max_peers_without_channels: usize,
+	/// The maximum number of inbound connections we accept from a single source subnet, as
+	/// determined by [`Self::ipv4_subnet_prefix_len`] and [`Self::ipv6_subnet_prefix_len`].
+	///
+	/// Only applies to connections for which a remote network address was provided to
+	/// [`PeerManager::new_inbound_connection`]. Note that connections accepted via a local Tor
+	/// daemon all appear to come from the same address, so you may wish to raise this limit if
+	/// you accept such connections.
+	///
+	/// Default value: 8
+	pub max_inbound_connections_per_subnet: usize,
+	/// The length of the prefix which makes up the subnet of an IPv4 source address. Set this to
+	/// 32 to limit connections per source address rather than per subnet.
+	///
+	/// Default value: 24
+	pub ipv4_subnet_prefix_len: u8,
+	/// The length of the prefix which makes up the subnet of an IPv6 source address. Set this to
+	/// 128 to limit connections per source address rather than per subnet.
+	///
+	/// Default value: 64
+	pub ipv6_subnet_prefix_len: u8,
+	/// The number of calls to [`PeerManager::timer_tick_occurred`] we allow a peer to complete
+	/// its handshake in before we disconnect it. Because a timer tick may occur just after the
+	/// connection is made, this should be at least 1.
+	///
+	/// Default value: 1
+	pub handshake_timeout_ticks: u8,
+}
+
+impl Default for PeerConnectionLimits {
+	fn default() -> Self {
+		PeerConnectionLimits {
+			max_total_peers: 500,
+			max_peers_without_channels: 250,
+			max_inbound_connections_per_subnet: 8,
+			ipv4_subnet_prefix_len: 24,
+			ipv6_subnet_prefix_len: 64,
+			handshake_timeout_ticks: 1,
+		}
+	}
+}
+
+/// Configures the bandwidth a [`PeerManager`] spends sending gossip to each of its peers.
+///
+/// Passed to [`PeerManager::with_gossip_config`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GossipBandwidthConfig {
+	/// The number of bytes of gossip we'll send each peer per call to
+	/// [`PeerManager::timer_tick_occurred`], covering both our initial routing table sync and
+	/// relayed gossip broadcasts.
+	///
+	/// Gossip is queued separately from channel messages, which are never subject to this budget
+	/// and are always sent ahead of any queued gossip. Once a peer's budget is used up, we pause
+	/// its initial sync and queue (or, if too much is queued, drop) gossip broadcasts until the
+	/// next timer tick. A gossip message is sent as long as any budget remains, so the budget may
+	/// be overrun by up to one message per tick.
+	///
+	/// Default value: 4 MiB
+	pub bytes_per_timer_tick: usize,
+}
+
+impl Default for GossipBandwidthConfig {
+	fn default() -> Self {
+		GossipBandwidthConfig {
+			bytes_per_timer_tick: 4 * 1024 * 1024,
+		}
+	}
+}
+
+impl PeerConnectionLimits {
+	/// Gets the subnet the given address lies in, if it is an IP address.
+	fn subnet(&self, address: &NetAddress) -> Option<(u8, [u8; 16])> {
+		let (version, addr, prefix_len) = match address {
+			NetAddress::IPv4 { addr, .. } => {
+				let mut padded = [0; 16];
+				padded[..4].copy_from_slice(addr);
+				(4, padded, cmp::min(self.ipv4_subnet_prefix_len, 32))
+			},
+			NetAddress::IPv6 { addr, .. } => (6, *addr, cmp::min(self.ipv6_subnet_prefix_len, 128)),
+			_ => return None,
+		};
+		let mut subnet = [0; 16];
+		for (i, byte) in subnet.iter_mut().enumerate() {
+			let bits = cmp::min(prefix_len.saturating_sub(i as u8 * 8), 8);
+			if bits > 0 {
+				*byte = addr[i] & (0xff << (8 - bits));
+			}
+		}
+		Some((version, subnet))
+	}
+}
+
+#[cfg(not(feature = "no-std"))]
+type ConfiguredTime = std::time::Instant;
+#[cfg(feature = "no-std")]
+use util::time::{Eternity, Time};
+#[cfg(feature = "no-std")]
+type ConfiguredTime = Eternity;
+
+/// The number of messages and bytes of some kind exchanged with a peer, see [`TrafficStats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MessageStats {
+	/// The number of messages.
+	pub messages: u64,
+	/// The total length of the messages, counting their plaintext including the type but not the
+	/// encryption overhead.
+	pub bytes: u64,
+}
+
+impl MessageStats {
+	fn add(&mut self, other: &MessageStats) {
+		self.messages += other.messages;
+		self.bytes += other.bytes;
+	}
+}
+
+/// The traffic in one direction of a connection with a peer, see [`PeerStats`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TrafficStats {
+	/// The number of bytes passed to or from the socket, including the handshake and the
+	/// encryption overhead.
+	pub wire_bytes: u64,
+	/// The messages exchanged, by message type. Messages which we failed to decode are not
+	/// counted.
+	pub by_message_type: HashMap<u16, MessageStats>,
+}
+
+impl TrafficStats {
+	/// Returns the messages of all types.
+	pub fn total(&self) -> MessageStats {
+		self.sum_matching(|_| true)
+	}
+
+	/// Returns the gossip messages, i.e., announcements, updates and gossip queries.
+	pub fn gossip(&self) -> MessageStats {
+		self.sum_matching(is_gossip_msg)
+	}
+
+	/// Returns the messages relating to our channels with the peer, from `open_channel` through
+	/// HTLC updates to `closing_signed`, as well as `announcement_signatures`.
+	pub fn channel(&self) -> MessageStats {
+		self.sum_matching(is_channel_msg)
+	}
+
+	fn sum_matching<F: Fn(u16) -> bool>(&self, f: F) -> MessageStats {
+		let mut sum = MessageStats::default();
+		for (type_id, stats) in self.by_message_type.iter() {
+			if f(*type_id) { sum.add(stats); }
+		}
+		sum
+	}
+
+	fn record_message(&mut self, type_id: u16, len: usize) {
+		let stats = self.by_message_type.entry(type_id).or_default();
+		stats.messages += 1;
+		stats.bytes += len as u64;
+	}
+}
+
+/// Statistics about the connection with a peer, as returned by [`PeerManager::peer_stats`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerStats {
+	/// The peer's node id.
+	pub node_id: PublicKey,
+	/// The remote network address passed when the connection was set up, if any.
+	pub remote_network_address: Option<NetAddress>,
+	/// Whether the peer connected to us, rather than us to it.
+	pub inbound_connection: bool,
+	/// How long ago the connection was set up.
+	///
+	/// Always zero when built with `no-std`.
+	pub connected_for: Duration,
+	/// What we've sent the peer.
+	pub sent: TrafficStats,
+	/// What we've received from the peer.
+	pub received: TrafficStats,
+	/// The time between queueing our last answered ping and receiving the peer's pong, if the
+	/// peer has answered one yet. This includes the time the ping spent behind other messages in
+	/// our outbound buffer.
+	///
+	/// Always zero when built with `no-std`.
+	pub ping_latency: Option<Duration>,
+	/// The number of times we asked for reads from the peer to be paused as our outbound buffer
+	/// for it was full, i.e., [`PeerManager::read_event`] returned true.
+	pub read_pauses: u64,
+	/// The number of non-gossip messages currently queued to be sent to the peer.
+	pub pending_outbound_messages: usize,
+	/// The number of gossip messages currently queued to be sent to the peer once all other
+	/// messages have been sent.
+	pub pending_gossip_messages: usize,
+}
+
+/// The statistics we track about a connection, see [`PeerStats`].
+struct ConnectionStats {
+	connected_at: ConfiguredTime,
+	sent: TrafficStats,
+	received: TrafficStats,
+	/// When we queued the oldest ping which the peer has yet to answer.
+	ping_queued_at: Option<ConfiguredTime>,
+	ping_latency: Option<Duration>,
+	read_pauses: u64,
+}
+
+impl ConnectionStats {
+	fn new() -> Self {
+		Self {
+			connected_at: ConfiguredTime::now(),
+			sent: TrafficStats::default(),
+			received: TrafficStats::default(),
+			ping_queued_at: None,
+			ping_latency: None,
+			read_pauses: 0,
+		}
+	}
+
+	fn record_sent_message(&mut self, encoded_message: &[u8]) {
+		let type_id = ((encoded_message[0] as u16) << 8) | encoded_message[1] as u16;
+		self.sent.record_message(type_id, encoded_message.len());
+		if type_id == msgs::Ping::TYPE && self.ping_queued_at.is_none() {
+			self.ping_queued_at = Some(ConfiguredTime::now());
+		}
+	}
+
+	fn record_pong(&mut self) {
+		if let Some(ping_queued_at) = self.ping_queued_at.take() {
+			self.ping_latency = Some(ping_queued_at.elapsed());
+		}
+	}
+}
+
 struct Peer {
 	channel_encryptor: PeerChannelEncryptor,
 	their_node_id: Option<PublicKey>,
 	their_features: Option<InitFeatures>,
 	their_net_address: Option<NetAddress>,
+	inbound_connection: bool,
 
 	pending_outbound_buffer: LinkedList<Vec<u8>>,
 	pending_outbound_buffer_first_msg_offset: usize,
+	/// Encoded gossip messages waiting to be sent, which are only encrypted and moved to the
+	/// `pending_outbound_buffer` once it is empty, so that they never delay channel messages.
+	gossip_broadcast_buffer: VecDeque<Vec<u8>>,
+	/// The number of gossip bytes we may still send before the next timer tick.
+	gossip_bandwidth_remaining: usize,
 	awaiting_write_event: bool,
 
 	pending_read_buffer: Vec<u8>,
@@ -344,7 +625,14 @@ struct Peer {
 	msgs_sent_since_pong: usize,
 	awaiting_pong_timer_tick_intervals: i8,
 	received_message_since_timer_tick: bool,
+	/// The number of timer ticks since the peer last sent us a message other than a ping or
+	/// pong, used to pick the most idle peer when we need to evict one.
+	timer_ticks_without_messages: u32,
+	/// The number of timer ticks which have passed while the peer was completing its handshake.
+	handshake_timer_ticks: u8,
 	sent_gossip_timestamp_filter: bool,
+
+	stats: ConnectionStats,
 }
 
 impl Peer {
@@ -378,6 +666,28 @@ impl Peer {
 			InitSyncTracker::NodesSyncing(pk) => pk < node_id,
 		}
 	}
+
+	/// Returns true if we should fetch more of our routing table to send the peer as a part of
+	/// its initial sync.
+	fn should_buffer_gossip_backfill(&self) -> bool {
+		self.gossip_broadcast_buffer.len() < OUTBOUND_BUFFER_LIMIT_READ_PAUSE
+			&& self.msgs_sent_since_pong < BUFFER_DRAIN_MSGS_PER_TICK
+			&& self.gossip_bandwidth_remaining > 0
+	}
+
+	/// Returns true if we should move a message from the gossip buffer to the outbound buffer,
+	/// which we only do once all channel messages have been sent.
+	fn should_send_gossip(&self) -> bool {
+		self.pending_outbound_buffer.is_empty()
+			&& self.msgs_sent_since_pong < BUFFER_DRAIN_MSGS_PER_TICK
+			&& self.gossip_bandwidth_remaining > 0
+	}
+
+	/// Returns true if the gossip buffer is too full to relay further gossip broadcasts.
+	fn should_drop_gossip_broadcast(&self) -> bool {
+		self.gossip_broadcast_buffer.len() > OUTBOUND_BUFFER_LIMIT_DROP_GOSSIP
+			|| self.msgs_sent_since_pong > BUFFER_DRAIN_MSGS_PER_TICK * FORWARD_INIT_SYNC_BUFFER_LIMIT_RATIO
+	}
 }
 
 /// SimpleArcPeerManager is useful when you need a PeerManager with a static lifetime, e.g.
@@ -418,11 +728,12 @@ pub type SimpleRefPeerManager<'a, 'b, 'c, 'd, 'e, 'f, 'g, 'h, SD, M, T, F, C, L>
 /// you're using lightning-net-tokio.
 ///
 /// [`read_event`]: PeerManager::read_event
-pub struct PeerManager<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> where
+pub struct PeerManager<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref, PP: Deref = AllowAllPeerPolicy> where
 		CM::Target: ChannelMessageHandler,
 		RM::Target: RoutingMessageHandler,
 		L::Target: Logger,
-		CMH::Target: CustomMessageHandler {
+		CMH::Target: CustomMessageHandler,
+		PP::Target: PeerPolicy {
 	message_handler: MessageHandler<CM, RM>,
 	/// Connection state for each connected peer - we have an outer read-write lock which is taken
 	/// as read while we're doing processing for a peer and taken write when a peer is being added
@@ -438,6 +749,15 @@ pub struct PeerManager<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: De
 	/// lock held. Entries may be added with only the `peers` read lock held (though the
 	/// `Descriptor` value must already exist in `peers`).
 	node_id_to_descriptor: Mutex<HashMap<PublicKey, Descriptor>>,
+	/// The inbound peers with which we had no channels once they completed the handshake, which
+	/// count towards [`PeerConnectionLimits::max_peers_without_channels`]. Peers with which we
+	/// have since opened channels are removed on each timer tick. Entries are removed alongside
+	/// those in `node_id_to_descriptor`.
+	inbound_peers_without_channels: Mutex<HashSet<PublicKey>>,
+	/// The number of inbound connections in `peers` from each source subnet, which count towards
+	/// [`PeerConnectionLimits::max_inbound_connections_per_subnet`]. Only modified with the
+	/// `peers` write lock held, alongside `peers` itself.
+	inbound_connections_per_subnet: Mutex<HashMap<(u8, [u8; 16]), usize>>,
 	/// We can only have one thread processing events at once, but we don't usually need the full
 	/// `peers` write lock to do so, so instead we block on this empty mutex when entering
 	/// `process_events`.
@@ -452,6 +772,9 @@ pub struct PeerManager<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: De
 	custom_message_handler: CMH,
 
 	peer_counter: AtomicCounter,
+	limits: PeerConnectionLimits,
+	gossip_config: GossipBandwidthConfig,
+	peer_policy: PP,
 
 	logger: L,
 	secp_ctx: Secp256k1<secp256k1::SignOnly>
@@ -534,7 +857,7 @@ impl core::fmt::Display for OptionalFromDebugger<'_> {
 /// A function used to filter out local or private addresses
 /// https://www.iana.org./assignments/ipv4-address-space/ipv4-address-space.xhtml
 /// https://www.iana.org/assignments/ipv6-address-space/ipv6-address-space.xhtml
-fn filter_addresses(ip_address: Option<NetAddress>) -> Option<NetAddress> {
+pub(crate) fn filter_addresses(ip_address: Option<NetAddress>) -> Option<NetAddress> {
 	match ip_address{
 		// For IPv4 range 10.0.0.0 - 10.255.255.255 (10/8)
 		Some(NetAddress::IPv4{addr: [10, _, _, _], port: _}) => None,
@@ -569,7 +892,35 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 	/// Constructs a new PeerManager with the given message handlers and node_id secret key
 	/// ephemeral_random_data is used to derive per-connection ephemeral keys and must be
 	/// cryptographically secure random bytes.
+	///
+	/// Uses the default [`PeerConnectionLimits`], see [`Self::new_with_limits`] to change them.
 	pub fn new(message_handler: MessageHandler<CM, RM>, our_node_secret: SecretKey, ephemeral_random_data: &[u8; 32], logger: L, custom_message_handler: CMH) -> Self {
+		Self::new_with_limits(message_handler, our_node_secret, ephemeral_random_data, logger, custom_message_handler, PeerConnectionLimits::default())
+	}
+
+	/// Constructs a new PeerManager as with [`Self::new`], enforcing the given limits on the
+	/// peers we accept inbound connections from.
+	///
+	/// All peers are allowed regardless of their misbehavior, as with an [`AllowAllPeerPolicy`],
+	/// see [`PeerManager::new_with_policy`] to ban misbehaving peers.
+	pub fn new_with_limits(message_handler: MessageHandler<CM, RM>, our_node_secret: SecretKey, ephemeral_random_data: &[u8; 32], logger: L, custom_message_handler: CMH, limits: PeerConnectionLimits) -> Self {
+		Self::new_with_policy(message_handler, our_node_secret, ephemeral_random_data, logger, custom_message_handler, limits, AllowAllPeerPolicy {})
+	}
+}
+
+impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref, PP: Deref> PeerManager<Descriptor, CM, RM, L, CMH, PP> where
+		CM::Target: ChannelMessageHandler,
+		RM::Target: RoutingMessageHandler,
+		L::Target: Logger,
+		CMH::Target: CustomMessageHandler,
+		PP::Target: PeerPolicy {
+	/// Constructs a new PeerManager as with [`PeerManager::new_with_limits`], reporting the
+	/// protocol violations of our peers to the given [`PeerPolicy`] and refusing connections it
+	/// doesn't allow.
+	///
+	/// Peers with which we have channels are exempt from the policy, as refusing them could
+	/// prevent us from resolving our channels with them.
+	pub fn new_with_policy(message_handler: MessageHandler<CM, RM>, our_node_secret: SecretKey, ephemeral_random_data: &[u8; 32], logger: L, custom_message_handler: CMH, limits: PeerConnectionLimits, peer_policy: PP) -> Self {
 		let mut ephemeral_key_midstate = Sha256::engine();
 		ephemeral_key_midstate.input(ephemeral_random_data);
 
@@ -581,17 +932,34 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 			message_handler,
 			peers: FairRwLock::new(HashMap::new()),
 			node_id_to_descriptor: Mutex::new(HashMap::new()),
+			inbound_peers_without_channels: Mutex::new(HashSet::new()),
+			inbound_connections_per_subnet: Mutex::new(HashMap::new()),
 			event_processing_lock: Mutex::new(()),
 			blocked_event_processors: AtomicBool::new(false),
 			our_node_secret,
 			ephemeral_key_midstate,
 			peer_counter: AtomicCounter::new(),
+			limits,
+			gossip_config: GossipBandwidthConfig::default(),
+			peer_policy,
 			logger,
 			custom_message_handler,
 			secp_ctx,
 		}
 	}
 
+	/// Sets the bandwidth we spend sending gossip to each peer, replacing the default
+	/// [`GossipBandwidthConfig`].
+	pub fn with_gossip_config(mut self, gossip_config: GossipBandwidthConfig) -> Self {
+		self.gossip_config = gossip_config;
+		self
+	}
+
+	/// Gets the [`PeerPolicy`] this PeerManager consults, e.g. to persist it.
+	pub fn peer_policy(&self) -> &PP::Target {
+		&*self.peer_policy
+	}
+
 	/// Get the list of node ids for peers which have completed the initial handshake.
 	///
 	/// For outbound connections, this will be the same as the their_node_id parameter passed in to
@@ -608,6 +976,32 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 		}).collect()
 	}
 
+	/// Gets statistics about the connection with each peer which has completed the initial
+	/// handshake, such as the traffic we exchanged by message type and the peer's ping latency.
+	///
+	/// This can be used to find slow or spammy peers.
+	pub fn peer_stats(&self) -> Vec<PeerStats> {
+		let peers = self.peers.read().unwrap();
+		peers.values().filter_map(|peer_mutex| {
+			let p = peer_mutex.lock().unwrap();
+			if !p.channel_encryptor.is_ready_for_encryption() || p.their_features.is_none() {
+				return None;
+			}
+			Some(PeerStats {
+				node_id: p.their_node_id?,
+				remote_network_address: p.their_net_address.clone(),
+				inbound_connection: p.inbound_connection,
+				connected_for: p.stats.connected_at.elapsed(),
+				sent: p.stats.sent.clone(),
+				received: p.stats.received.clone(),
+				ping_latency: p.stats.ping_latency,
+				read_pauses: p.stats.read_pauses,
+				pending_outbound_messages: p.pending_outbound_buffer.len(),
+				pending_gossip_messages: p.gossip_broadcast_buffer.len(),
+			})
+		}).collect()
+	}
+
 	fn get_ephemeral_key(&self) -> SecretKey {
 		let mut ephemeral_hash = self.ephemeral_key_midstate.clone();
 		let counter = self.peer_counter.get_increment();
@@ -615,6 +1009,27 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 		SecretKey::from_slice(&Sha256::from_engine(ephemeral_hash).into_inner()).expect("You broke SHA-256!")
 	}
 
+	/// Returns whether our [`PeerPolicy`] allows a connection with the given peer, which is always
+	/// the case for peers with which we have channels.
+	fn peer_allowed(&self, node_id: Option<&PublicKey>, remote_network_address: Option<&NetAddress>) -> bool {
+		match node_id {
+			Some(node_id) => self.message_handler.chan_handler.has_channels_with(node_id) ||
+				self.peer_policy.allow_peer(node_id, remote_network_address),
+			None => self.peer_policy.allow_connection(remote_network_address),
+		}
+	}
+
+	/// Reports a protocol violation to our [`PeerPolicy`], returning whether it still allows the
+	/// peer afterwards.
+	fn peer_misbehaved(&self, node_id: Option<&PublicKey>, remote_network_address: Option<&NetAddress>, misbehavior: PeerMisbehavior) -> bool {
+		if let Some(node_id) = node_id {
+			if self.message_handler.chan_handler.has_channels_with(node_id) { return true; }
+		}
+		log_trace!(self.logger, "Reporting {:?}{} to our peer policy", misbehavior, OptionalFromDebugger(&node_id.cloned()));
+		self.peer_policy.peer_misbehaved(node_id, remote_network_address, misbehavior);
+		self.peer_allowed(node_id, remote_network_address)
+	}
+
 	/// Indicates a new outbound connection has been established to a node with the given node_id
 	/// and an optional remote network address.
 	///
@@ -622,7 +1037,8 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 	/// peer using the init message.
 	/// The user should pass the remote network address of the host they are connected to.
 	///
-	/// If an `Err` is returned here you must disconnect the connection immediately.
+	/// If an `Err` is returned here you must disconnect the connection immediately. This is the
+	/// case if our [`PeerPolicy`] doesn't allow the peer.
 	///
 	/// Returns a small number of bytes to send to the remote node (currently always 50).
 	///
@@ -631,9 +1047,15 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 	///
 	/// [`socket_disconnected()`]: PeerManager::socket_disconnected
 	pub fn new_outbound_connection(&self, their_node_id: PublicKey, descriptor: Descriptor, remote_network_address: Option<NetAddress>) -> Result<Vec<u8>, PeerHandleError> {
+		if !self.peer_allowed(Some(&their_node_id), remote_network_address.as_ref()) {
+			log_debug!(self.logger, "Refusing outbound connection to {} as our peer policy doesn't allow it", log_pubkey!(their_node_id));
+			return Err(PeerHandleError { no_connection_possible: false });
+		}
 		let mut peer_encryptor = PeerChannelEncryptor::new_outbound(their_node_id.clone(), self.get_ephemeral_key());
 		let res = peer_encryptor.get_act_one(&self.secp_ctx).to_vec();
 		let pending_read_buffer = [0; 50].to_vec(); // Noise act two is 50 bytes
+		let mut stats = ConnectionStats::new();
+		stats.sent.wire_bytes = res.len() as u64;
 
 		let mut peers = self.peers.write().unwrap();
 		if peers.insert(descriptor, Mutex::new(Peer {
@@ -641,9 +1063,12 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 			their_node_id: None,
 			their_features: None,
 			their_net_address: remote_network_address,
+			inbound_connection: false,
 
 			pending_outbound_buffer: LinkedList::new(),
 			pending_outbound_buffer_first_msg_offset: 0,
+			gossip_broadcast_buffer: VecDeque::new(),
+			gossip_bandwidth_remaining: self.gossip_config.bytes_per_timer_tick,
 			awaiting_write_event: false,
 
 			pending_read_buffer,
@@ -655,7 +1080,11 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 			msgs_sent_since_pong: 0,
 			awaiting_pong_timer_tick_intervals: 0,
 			received_message_since_timer_tick: false,
+			timer_ticks_without_messages: 0,
+			handshake_timer_ticks: 0,
 			sent_gossip_timestamp_filter: false,
+
+			stats,
 		})).is_some() {
 			panic!("PeerManager driver duplicated descriptors!");
 		};
@@ -673,23 +1102,56 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 	/// (outbound connector always speaks first). If an `Err` is returned here you must disconnect
 	/// the connection immediately.
 	///
+	/// The connection is refused if our [`PeerPolicy`] doesn't allow its address, and if the peer's
+	/// node id turns out not to be allowed the connection is closed once the handshake completes.
+	///
+	/// The connection is also refused if it would exceed our [`PeerConnectionLimits`], unless we can
+	/// make room for it by disconnecting an idle peer with which we have no channels. Thus, this
+	/// may call [`disconnect_socket`] on the descriptor of another peer, so be careful about
+	/// reentrancy issues.
+	///
 	/// Panics if descriptor is duplicative with some other descriptor which has not yet been
 	/// [`socket_disconnected()`].
 	///
 	/// [`socket_disconnected()`]: PeerManager::socket_disconnected
+	/// [`disconnect_socket`]: SocketDescriptor::disconnect_socket
 	pub fn new_inbound_connection(&self, descriptor: Descriptor, remote_network_address: Option<NetAddress>) -> Result<(), PeerHandleError> {
 		let peer_encryptor = PeerChannelEncryptor::new_inbound(&self.our_node_secret, &self.secp_ctx);
 		let pending_read_buffer = [0; 50].to_vec(); // Noise act one is 50 bytes
 
+		if !self.peer_allowed(None, remote_network_address.as_ref()) {
+			log_debug!(self.logger, "Refusing inbound connection as our peer policy doesn't allow its address");
+			return Err(PeerHandleError { no_connection_possible: false });
+		}
+
 		let mut peers = self.peers.write().unwrap();
+		let subnet = remote_network_address.as_ref().and_then(|addr| self.limits.subnet(addr));
+		if let Some(subnet) = subnet {
+			let subnet_connections = self.inbound_connections_per_subnet.lock().unwrap().get(&subnet).cloned().unwrap_or(0);
+			if subnet_connections >= self.limits.max_inbound_connections_per_subnet {
+				log_debug!(self.logger, "Refusing inbound connection as we already have {} connections from its subnet", subnet_connections);
+				return Err(PeerHandleError { no_connection_possible: false });
+			}
+		}
+		let mut evicted_peer = None;
+		if peers.len() >= self.limits.max_total_peers {
+			evicted_peer = self.remove_idle_peer_without_channels(&mut *peers);
+			if evicted_peer.is_none() {
+				log_debug!(self.logger, "Refusing inbound connection as we already have {} peers", peers.len());
+				return Err(PeerHandleError { no_connection_possible: false });
+			}
+		}
 		if peers.insert(descriptor, Mutex::new(Peer {
 			channel_encryptor: peer_encryptor,
 			their_node_id: None,
 			their_features: None,
 			their_net_address: remote_network_address,
+			inbound_connection: true,
 
 			pending_outbound_buffer: LinkedList::new(),
 			pending_outbound_buffer_first_msg_offset: 0,
+			gossip_broadcast_buffer: VecDeque::new(),
+			gossip_bandwidth_remaining: self.gossip_config.bytes_per_timer_tick,
 			awaiting_write_event: false,
 
 			pending_read_buffer,
@@ -701,28 +1163,90 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 			msgs_sent_since_pong: 0,
 			awaiting_pong_timer_tick_intervals: 0,
 			received_message_since_timer_tick: false,
+			timer_ticks_without_messages: 0,
+			handshake_timer_ticks: 0,
 			sent_gossip_timestamp_filter: false,
+
+			stats: ConnectionStats::new(),
 		})).is_some() {
 			panic!("PeerManager driver duplicated descriptors!");
 		};
+		if let Some(subnet) = subnet {
+			*self.inbound_connections_per_subnet.lock().unwrap().entry(subnet).or_insert(0) += 1;
+		}
+		mem::drop(peers);
+
+		if let Some((mut descriptor, node_id)) = evicted_peer {
+			self.message_handler.chan_handler.peer_disconnected(&node_id, false);
+			self.custom_message_handler.peer_disconnected(&node_id);
+			descriptor.disconnect_socket();
+		}
 		Ok(())
 	}
 
+	/// Removes the most idle inbound peer with which we have no channels and which hasn't sent us
+	/// any message other than pings and pongs since at least the last timer tick, returning its
+	/// descriptor and node id if we found one.
+	///
+	/// The caller must inform the message handlers of the disconnection and disconnect the socket
+	/// once it has released the `peers` write lock.
+	fn remove_idle_peer_without_channels(&self, peers: &mut HashMap<Descriptor, Mutex<Peer>>) -> Option<(Descriptor, PublicKey)> {
+		let mut candidates: Vec<(u32, Descriptor, PublicKey)> = peers.iter().filter_map(|(descriptor, peer_mutex)| {
+			let peer = peer_mutex.lock().unwrap();
+			let node_id = peer.their_node_id?;
+			if !peer.inbound_connection || peer.their_features.is_none() || peer.timer_ticks_without_messages == 0 {
+				return None;
+			}
+			Some((peer.timer_ticks_without_messages, descriptor.clone(), node_id))
+		}).collect();
+		candidates.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+		let (_, descriptor, node_id) = {
+			let inbound_peers_without_channels = self.inbound_peers_without_channels.lock().unwrap();
+			// Channels may have been opened since `inbound_peers_without_channels` was last refreshed.
+			candidates.into_iter().find(|(_, _, node_id)| inbound_peers_without_channels.contains(node_id)
+				&& !self.message_handler.chan_handler.has_channels_with(node_id))?
+		};
+		log_debug!(self.logger, "Disconnecting idle peer {} to make room for a new inbound connection", log_pubkey!(node_id));
+		if let Some(peer_mutex) = peers.remove(&descriptor) {
+			self.inbound_connection_removed(&*peer_mutex.lock().unwrap());
+		}
+		self.node_id_to_descriptor.lock().unwrap().remove(&node_id);
+		self.inbound_peers_without_channels.lock().unwrap().remove(&node_id);
+		Some((descriptor, node_id))
+	}
+
+	/// Updates `inbound_connections_per_subnet` for a peer which was just removed from `peers`.
+	/// Must be called with the `peers` write lock held.
+	fn inbound_connection_removed(&self, peer: &Peer) {
+		if !peer.inbound_connection {
+			return;
+		}
+		if let Some(subnet) = peer.their_net_address.as_ref().and_then(|addr| self.limits.subnet(addr)) {
+			let mut inbound_connections_per_subnet = self.inbound_connections_per_subnet.lock().unwrap();
+			if let hash_map::Entry::Occupied(mut entry) = inbound_connections_per_subnet.entry(subnet) {
+				*entry.get_mut() -= 1;
+				if *entry.get() == 0 {
+					entry.remove();
+				}
+			}
+		}
+	}
+
 	fn do_attempt_write_data(&self, descriptor: &mut Descriptor, peer: &mut Peer) {
 		while !peer.awaiting_write_event {
-			if peer.pending_outbound_buffer.len() < OUTBOUND_BUFFER_LIMIT_READ_PAUSE && peer.msgs_sent_since_pong < BUFFER_DRAIN_MSGS_PER_TICK {
+			if peer.should_buffer_gossip_backfill() {
 				match peer.sync_status {
 					InitSyncTracker::NoSyncRequested => {},
 					InitSyncTracker::ChannelsSyncing(c) if c < 0xffff_ffff_ffff_ffff => {
-						let steps = ((OUTBOUND_BUFFER_LIMIT_READ_PAUSE - peer.pending_outbound_buffer.len() + 2) / 3) as u8;
+						let steps = ((OUTBOUND_BUFFER_LIMIT_READ_PAUSE - peer.gossip_broadcast_buffer.len() + 2) / 3) as u8;
 						let all_messages = self.message_handler.route_handler.get_next_channel_announcements(c, steps);
 						for &(ref announce, ref update_a_option, ref update_b_option) in all_messages.iter() {
-							self.enqueue_message(peer, announce);
+							self.enqueue_gossip_message(peer, announce);
 							if let &Some(ref update_a) = update_a_option {
-								self.enqueue_message(peer, update_a);
+								self.enqueue_gossip_message(peer, update_a);
 							}
 							if let &Some(ref update_b) = update_b_option {
-								self.enqueue_message(peer, update_b);
+								self.enqueue_gossip_message(peer, update_b);
 							}
 							peer.sync_status = InitSyncTracker::ChannelsSyncing(announce.contents.short_channel_id + 1);
 						}
@@ -731,10 +1255,10 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 						}
 					},
 					InitSyncTracker::ChannelsSyncing(c) if c == 0xffff_ffff_ffff_ffff => {
-						let steps = (OUTBOUND_BUFFER_LIMIT_READ_PAUSE - peer.pending_outbound_buffer.len()) as u8;
+						let steps = (OUTBOUND_BUFFER_LIMIT_READ_PAUSE - peer.gossip_broadcast_buffer.len()) as u8;
 						let all_messages = self.message_handler.route_handler.get_next_node_announcements(None, steps);
 						for msg in all_messages.iter() {
-							self.enqueue_message(peer, msg);
+							self.enqueue_gossip_message(peer, msg);
 							peer.sync_status = InitSyncTracker::NodesSyncing(msg.contents.node_id);
 						}
 						if all_messages.is_empty() || all_messages.len() != steps as usize {
@@ -743,10 +1267,10 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 					},
 					InitSyncTracker::ChannelsSyncing(_) => unreachable!(),
 					InitSyncTracker::NodesSyncing(key) => {
-						let steps = (OUTBOUND_BUFFER_LIMIT_READ_PAUSE - peer.pending_outbound_buffer.len()) as u8;
+						let steps = (OUTBOUND_BUFFER_LIMIT_READ_PAUSE - peer.gossip_broadcast_buffer.len()) as u8;
 						let all_messages = self.message_handler.route_handler.get_next_node_announcements(Some(&key), steps);
 						for msg in all_messages.iter() {
-							self.enqueue_message(peer, msg);
+							self.enqueue_gossip_message(peer, msg);
 							peer.sync_status = InitSyncTracker::NodesSyncing(msg.contents.node_id);
 						}
 						if all_messages.is_empty() || all_messages.len() != steps as usize {
@@ -755,6 +1279,12 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 					},
 				}
 			}
+			if peer.should_send_gossip() {
+				if let Some(encoded_message) = peer.gossip_broadcast_buffer.pop_front() {
+					peer.gossip_bandwidth_remaining = peer.gossip_bandwidth_remaining.saturating_sub(encoded_message.len());
+					self.enqueue_encoded_message(peer, &encoded_message);
+				}
+			}
 			if peer.msgs_sent_since_pong >= BUFFER_DRAIN_MSGS_PER_TICK {
 				self.maybe_send_extra_ping(peer);
 			}
@@ -769,6 +1299,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 				let pending = &next_buff[peer.pending_outbound_buffer_first_msg_offset..];
 				let data_sent = descriptor.send_data(pending, should_be_reading);
 				peer.pending_outbound_buffer_first_msg_offset += data_sent;
+				peer.stats.sent.wire_bytes += data_sent as u64;
 				if peer.pending_outbound_buffer_first_msg_offset == next_buff.len() { true } else { false }
 			} {
 				peer.pending_outbound_buffer_first_msg_offset = 0;
@@ -837,6 +1368,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 	/// Append a message to a peer's pending outbound/write buffer
 	fn enqueue_encoded_message(&self, peer: &mut Peer, encoded_message: &Vec<u8>) {
 		peer.msgs_sent_since_pong += 1;
+		peer.stats.record_sent_message(&encoded_message[..]);
 		peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encoded_message[..]));
 	}
 
@@ -853,6 +1385,21 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 		self.enqueue_encoded_message(peer, &buffer.0);
 	}
 
+	/// Append a gossip message to a peer's gossip buffer, to be sent once its outbound/write
+	/// buffer has been drained.
+	fn enqueue_encoded_gossip_message(&self, peer: &mut Peer, encoded_message: &[u8]) {
+		peer.gossip_broadcast_buffer.push_back(encoded_message.to_vec());
+	}
+
+	/// Append a gossip message to a peer's gossip buffer
+	fn enqueue_gossip_message<M: wire::Type>(&self, peer: &mut Peer, message: &M) {
+		let mut buffer = VecWriter(Vec::with_capacity(2048));
+		wire::write(message, &mut buffer).unwrap(); // crash if the write failed
+
+		log_gossip!(self.logger, "Enqueueing gossip message {:?} to {}", message, log_pubkey!(peer.their_node_id.unwrap()));
+		self.enqueue_encoded_gossip_message(peer, &buffer.0);
+	}
+
 	fn do_read_event(&self, peer_descriptor: &mut Descriptor, data: &[u8]) -> Result<bool, PeerHandleError> {
 		let mut pause_read = false;
 		let peers = self.peers.read().unwrap();
@@ -868,8 +1415,20 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 			Some(peer_mutex) => {
 				let mut read_pos = 0;
 				while read_pos < data.len() {
+					// Reports a protocol violation by the peer, evaluating to whether our policy
+					// still allows it. Note that `$peer` may lock the peer, so we only use it once.
+					macro_rules! report_misbehavior {
+						($peer: expr, $misbehavior: expr) => { {
+							let (node_id, net_address) = {
+								let peer: &Peer = &*$peer;
+								(peer.their_node_id, peer.their_net_address.clone())
+							};
+							self.peer_misbehaved(node_id.as_ref(), net_address.as_ref(), $misbehavior)
+						} }
+					}
+
 					macro_rules! try_potential_handleerror {
-						($peer: expr, $thing: expr) => {
+						($peer: expr, $thing: expr, $misbehavior: expr) => {
 							match $thing {
 								Ok(x) => x,
 								Err(e) => {
@@ -877,6 +1436,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 										msgs::ErrorAction::DisconnectPeer { msg: _ } => {
 											//TODO: Try to push msg
 											log_debug!(self.logger, "Error handling message{}; disconnecting peer with: {}", OptionalFromDebugger(&peer_node_id), e.err);
+											report_misbehavior!($peer, $misbehavior);
 											return Err(PeerHandleError{ no_connection_possible: false });
 										},
 										msgs::ErrorAction::IgnoreAndLog(level) => {
@@ -891,11 +1451,19 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 										msgs::ErrorAction::SendErrorMessage { msg } => {
 											log_debug!(self.logger, "Error handling message{}; sending error message with: {}", OptionalFromDebugger(&peer_node_id), e.err);
 											self.enqueue_message($peer, &msg);
+											if !report_misbehavior!($peer, $misbehavior) {
+												log_debug!(self.logger, "Disconnecting peer{} as our peer policy no longer allows it", OptionalFromDebugger(&peer_node_id));
+												return Err(PeerHandleError{ no_connection_possible: false });
+											}
 											continue;
 										},
 										msgs::ErrorAction::SendWarningMessage { msg, log_level } => {
 											log_given_level!(self.logger, log_level, "Error handling message{}; sending warning message with: {}", OptionalFromDebugger(&peer_node_id), e.err);
 											self.enqueue_message($peer, &msg);
+											if !report_misbehavior!($peer, $misbehavior) {
+												log_debug!(self.logger, "Disconnecting peer{} as our peer policy no longer allows it", OptionalFromDebugger(&peer_node_id));
+												return Err(PeerHandleError{ no_connection_possible: false });
+											}
 											continue;
 										},
 									}
@@ -919,6 +1487,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 						peer.pending_read_buffer[peer.pending_read_buffer_pos..peer.pending_read_buffer_pos + data_to_copy].copy_from_slice(&data[read_pos..read_pos + data_to_copy]);
 						read_pos += data_to_copy;
 						peer.pending_read_buffer_pos += data_to_copy;
+						peer.stats.received.wire_bytes += data_to_copy as u64;
 					}
 
 					if peer.pending_read_buffer_pos == peer.pending_read_buffer.len() {
@@ -926,6 +1495,11 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 
 						macro_rules! insert_node_id {
 							() => {
+								if !self.peer_allowed(peer.their_node_id.as_ref(), peer.their_net_address.as_ref()) {
+									log_debug!(self.logger, "Closing connection with {} as our peer policy doesn't allow it", log_pubkey!(peer.their_node_id.unwrap()));
+									peer.their_node_id = None; // Unset so that we don't generate a peer_disconnected event
+									return Err(PeerHandleError{ no_connection_possible: false })
+								}
 								match self.node_id_to_descriptor.lock().unwrap().entry(peer.their_node_id.unwrap()) {
 									hash_map::Entry::Occupied(_) => {
 										log_trace!(self.logger, "Got second connection with {}, closing", log_pubkey!(peer.their_node_id.unwrap()));
@@ -945,33 +1519,33 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 							NextNoiseStep::ActOne => {
 								let act_two = try_potential_handleerror!(peer, peer.channel_encryptor
 									.process_act_one_with_keys(&peer.pending_read_buffer[..],
-										&self.our_node_secret, self.get_ephemeral_key(), &self.secp_ctx)).to_vec();
+										&self.our_node_secret, self.get_ephemeral_key(), &self.secp_ctx), PeerMisbehavior::InvalidEncryption).to_vec();
 								peer.pending_outbound_buffer.push_back(act_two);
 								peer.pending_read_buffer = [0; 66].to_vec(); // act three is 66 bytes long
 							},
 							NextNoiseStep::ActTwo => {
 								let (act_three, their_node_id) = try_potential_handleerror!(peer,
 									peer.channel_encryptor.process_act_two(&peer.pending_read_buffer[..],
-										&self.our_node_secret, &self.secp_ctx));
+										&self.our_node_secret, &self.secp_ctx), PeerMisbehavior::InvalidEncryption);
 								peer.pending_outbound_buffer.push_back(act_three.to_vec());
 								peer.pending_read_buffer = [0; 18].to_vec(); // Message length header is 18 bytes
 								peer.pending_read_is_header = true;
 
 								peer.their_node_id = Some(their_node_id);
 								insert_node_id!();
-								let features = InitFeatures::known();
+								let features = InitFeatures::known().or(self.custom_message_handler.provided_init_features(&their_node_id));
 								let resp = msgs::Init { features, remote_network_address: filter_addresses(peer.their_net_address.clone()) };
 								self.enqueue_message(peer, &resp);
 								peer.awaiting_pong_timer_tick_intervals = 0;
 							},
 							NextNoiseStep::ActThree => {
 								let their_node_id = try_potential_handleerror!(peer,
-									peer.channel_encryptor.process_act_three(&peer.pending_read_buffer[..]));
+									peer.channel_encryptor.process_act_three(&peer.pending_read_buffer[..]), PeerMisbehavior::InvalidEncryption);
 								peer.pending_read_buffer = [0; 18].to_vec(); // Message length header is 18 bytes
 								peer.pending_read_is_header = true;
 								peer.their_node_id = Some(their_node_id);
 								insert_node_id!();
-								let features = InitFeatures::known();
+								let features = InitFeatures::known().or(self.custom_message_handler.provided_init_features(&their_node_id));
 								let resp = msgs::Init { features, remote_network_address: filter_addresses(peer.their_net_address.clone()) };
 								self.enqueue_message(peer, &resp);
 								peer.awaiting_pong_timer_tick_intervals = 0;
@@ -979,16 +1553,17 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 							NextNoiseStep::NoiseComplete => {
 								if peer.pending_read_is_header {
 									let msg_len = try_potential_handleerror!(peer,
-										peer.channel_encryptor.decrypt_length_header(&peer.pending_read_buffer[..]));
+										peer.channel_encryptor.decrypt_length_header(&peer.pending_read_buffer[..]), PeerMisbehavior::InvalidEncryption);
 									if peer.pending_read_buffer.capacity() > 8192 { peer.pending_read_buffer = Vec::new(); }
 									peer.pending_read_buffer.resize(msg_len as usize + 16, 0);
 									if msg_len < 2 { // Need at least the message type tag
+										report_misbehavior!(peer, PeerMisbehavior::InvalidMessage);
 										return Err(PeerHandleError{ no_connection_possible: false });
 									}
 									peer.pending_read_is_header = false;
 								} else {
 									let msg_data = try_potential_handleerror!(peer,
-										peer.channel_encryptor.decrypt_message(&peer.pending_read_buffer[..]));
+										peer.channel_encryptor.decrypt_message(&peer.pending_read_buffer[..]), PeerMisbehavior::InvalidEncryption);
 									assert!(msg_data.len() >= 2);
 
 									// Reset read buffer
@@ -1018,6 +1593,9 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 												(_, Some(ty)) if is_gossip_msg(ty) => {
 													log_gossip!(self.logger, "Got an invalid value while deserializing a gossip message");
 													self.enqueue_message(peer, &msgs::WarningMessage { channel_id: [0; 32], data: "Unreadable/bogus gossip message".to_owned() });
+													if !report_misbehavior!(peer, PeerMisbehavior::InvalidGossip) {
+														return Err(PeerHandleError { no_connection_possible: false });
+													}
 													continue;
 												}
 												(msgs::DecodeError::UnknownRequiredFeature, ty) => {
@@ -1028,31 +1606,45 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 												(msgs::DecodeError::UnknownVersion, _) => return Err(PeerHandleError { no_connection_possible: false }),
 												(msgs::DecodeError::InvalidValue, _) => {
 													log_debug!(self.logger, "Got an invalid value while deserializing message");
+													report_misbehavior!(peer, PeerMisbehavior::InvalidMessage);
 													return Err(PeerHandleError { no_connection_possible: false });
 												}
 												(msgs::DecodeError::ShortRead, _) => {
 													log_debug!(self.logger, "Deserialization failed due to shortness of message");
+													report_misbehavior!(peer, PeerMisbehavior::InvalidMessage);
+													return Err(PeerHandleError { no_connection_possible: false });
+												}
+												(msgs::DecodeError::BadLengthDescriptor, _) => {
+													report_misbehavior!(peer, PeerMisbehavior::InvalidMessage);
 													return Err(PeerHandleError { no_connection_possible: false });
 												}
-												(msgs::DecodeError::BadLengthDescriptor, _) => return Err(PeerHandleError { no_connection_possible: false }),
 												(msgs::DecodeError::Io(_), _) => return Err(PeerHandleError { no_connection_possible: false }),
 											}
 										}
 									};
 
+									peer.stats.received.record_message(message.type_id(), msg_data.len());
 									msg_to_handle = Some(message);
 								}
 							}
 						}
 					}
 					pause_read = peer.pending_outbound_buffer.len() > OUTBOUND_BUFFER_LIMIT_READ_PAUSE;
+					if pause_read && read_pos == data.len() {
+						peer.stats.read_pauses += 1;
+					}
 
 					if let Some(message) = msg_to_handle {
+						let handling_misbehavior = if is_gossip_msg(message.type_id()) {
+							PeerMisbehavior::InvalidGossip
+						} else {
+							PeerMisbehavior::HandlingError
+						};
 						match self.handle_message(&peer_mutex, peer_lock, message) {
 							Err(handling_error) => match handling_error {
 								MessageHandlingError::PeerHandleError(e) => { return Err(e) },
 								MessageHandlingError::LightningError(e) => {
-									try_potential_handleerror!(&mut peer_mutex.lock().unwrap(), Err(e));
+									try_potential_handleerror!(&mut peer_mutex.lock().unwrap(), Err(e), handling_misbehavior);
 								},
 							},
 							Ok(Some(msg)) => {
@@ -1082,14 +1674,19 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 	) -> Result<Option<wire::Message<<<CMH as core::ops::Deref>::Target as wire::CustomMessageReader>::CustomMessage>>, MessageHandlingError> {
 		let their_node_id = peer_lock.their_node_id.clone().expect("We know the peer's public key by the time we receive messages");
 		peer_lock.received_message_since_timer_tick = true;
+		match message {
+			wire::Message::Ping(_) | wire::Message::Pong(_) => {},
+			_ => peer_lock.timer_ticks_without_messages = 0,
+		}
 
 		// Need an Init as first message
 		if let wire::Message::Init(msg) = message {
-			if msg.features.requires_unknown_bits() {
+			if msg.features.requires_unknown_bits_not_in(&self.custom_message_handler.provided_init_features(&their_node_id)) {
 				log_debug!(self.logger, "Peer features required unknown version bits");
 				return Err(PeerHandleError{ no_connection_possible: true }.into());
 			}
 			if peer_lock.their_features.is_some() {
+				self.peer_misbehaved(Some(&their_node_id), peer_lock.their_net_address.as_ref(), PeerMisbehavior::InvalidMessage);
 				return Err(PeerHandleError{ no_connection_possible: false }.into());
 			}
 
@@ -1105,13 +1702,24 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 				return Err(PeerHandleError{ no_connection_possible: true }.into());
 			}
 
+			if peer_lock.inbound_connection && !self.message_handler.chan_handler.has_channels_with(&their_node_id) {
+				let mut inbound_peers_without_channels = self.inbound_peers_without_channels.lock().unwrap();
+				if inbound_peers_without_channels.len() >= self.limits.max_peers_without_channels {
+					log_debug!(self.logger, "Disconnecting inbound peer {} as we already have {} inbound peers without channels", log_pubkey!(their_node_id), inbound_peers_without_channels.len());
+					return Err(PeerHandleError{ no_connection_possible: false }.into());
+				}
+				inbound_peers_without_channels.insert(their_node_id);
+			}
+
 			self.message_handler.route_handler.peer_connected(&their_node_id, &msg);
 
 			self.message_handler.chan_handler.peer_connected(&their_node_id, &msg);
+			self.custom_message_handler.peer_connected(&their_node_id, &msg);
 			peer_lock.their_features = Some(msg.features);
 			return Ok(None);
 		} else if peer_lock.their_features.is_none() {
 			log_debug!(self.logger, "Peer {} sent non-Init first message", log_pubkey!(their_node_id));
+			self.peer_misbehaved(Some(&their_node_id), peer_lock.their_net_address.as_ref(), PeerMisbehavior::InvalidMessage);
 			return Err(PeerHandleError{ no_connection_possible: false }.into());
 		}
 
@@ -1127,6 +1735,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 		}
 
 		let their_features = peer_lock.their_features.clone();
+		let their_net_address = peer_lock.their_net_address.clone();
 		mem::drop(peer_lock);
 
 		if is_gossip_msg(message.type_id()) {
@@ -1159,8 +1768,14 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 				} else {
 					log_debug!(self.logger, "Got Err message from {} with non-ASCII error message", log_pubkey!(their_node_id));
 				}
+				// Report the error before handling it, as the channels it closes would otherwise no
+				// longer exempt the peer from our policy.
+				let closes_all_channels = msg.channel_id == [0; 32];
+				if closes_all_channels {
+					self.peer_misbehaved(Some(&their_node_id), their_net_address.as_ref(), PeerMisbehavior::ErrorMessage);
+				}
 				self.message_handler.chan_handler.handle_error(&their_node_id, &msg);
-				if msg.channel_id == [0; 32] {
+				if closes_all_channels {
 					return Err(PeerHandleError{ no_connection_possible: true }.into());
 				}
 			},
@@ -1190,6 +1805,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 				let mut peer_lock = peer_mutex.lock().unwrap();
 				peer_lock.awaiting_pong_timer_tick_intervals = 0;
 				peer_lock.msgs_sent_since_pong = 0;
+				peer_lock.stats.record_pong();
 			},
 
 			// Channel messages:
@@ -1308,10 +1924,8 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 							!peer.should_forward_channel_announcement(msg.contents.short_channel_id) {
 						continue
 					}
-					if peer.pending_outbound_buffer.len() > OUTBOUND_BUFFER_LIMIT_DROP_GOSSIP
-						|| peer.msgs_sent_since_pong > BUFFER_DRAIN_MSGS_PER_TICK * FORWARD_INIT_SYNC_BUFFER_LIMIT_RATIO
-					{
-						log_gossip!(self.logger, "Skipping broadcast message to {:?} as its outbound buffer is full", peer.their_node_id);
+					if peer.should_drop_gossip_broadcast() {
+						log_gossip!(self.logger, "Skipping broadcast message to {:?} as its gossip buffer is full", peer.their_node_id);
 						continue;
 					}
 					if peer.their_node_id.as_ref() == Some(&msg.contents.node_id_1) ||
@@ -1321,7 +1935,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 					if except_node.is_some() && peer.their_node_id.as_ref() == except_node {
 						continue;
 					}
-					self.enqueue_encoded_message(&mut *peer, &encoded_msg);
+					self.enqueue_encoded_gossip_message(&mut *peer, &encoded_msg);
 				}
 			},
 			wire::Message::NodeAnnouncement(ref msg) => {
@@ -1334,10 +1948,8 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 							!peer.should_forward_node_announcement(msg.contents.node_id) {
 						continue
 					}
-					if peer.pending_outbound_buffer.len() > OUTBOUND_BUFFER_LIMIT_DROP_GOSSIP
-						|| peer.msgs_sent_since_pong > BUFFER_DRAIN_MSGS_PER_TICK * FORWARD_INIT_SYNC_BUFFER_LIMIT_RATIO
-					{
-						log_gossip!(self.logger, "Skipping broadcast message to {:?} as its outbound buffer is full", peer.their_node_id);
+					if peer.should_drop_gossip_broadcast() {
+						log_gossip!(self.logger, "Skipping broadcast message to {:?} as its gossip buffer is full", peer.their_node_id);
 						continue;
 					}
 					if peer.their_node_id.as_ref() == Some(&msg.contents.node_id) {
@@ -1346,7 +1958,7 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 					if except_node.is_some() && peer.their_node_id.as_ref() == except_node {
 						continue;
 					}
-					self.enqueue_encoded_message(&mut *peer, &encoded_msg);
+					self.enqueue_encoded_gossip_message(&mut *peer, &encoded_msg);
 				}
 			},
 			wire::Message::ChannelUpdate(ref msg) => {
@@ -1359,16 +1971,14 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 							!peer.should_forward_channel_announcement(msg.contents.short_channel_id)  {
 						continue
 					}
-					if peer.pending_outbound_buffer.len() > OUTBOUND_BUFFER_LIMIT_DROP_GOSSIP
-						|| peer.msgs_sent_since_pong > BUFFER_DRAIN_MSGS_PER_TICK * FORWARD_INIT_SYNC_BUFFER_LIMIT_RATIO
-					{
-						log_gossip!(self.logger, "Skipping broadcast message to {:?} as its outbound buffer is full", peer.their_node_id);
+					if peer.should_drop_gossip_broadcast() {
+						log_gossip!(self.logger, "Skipping broadcast message to {:?} as its gossip buffer is full", peer.their_node_id);
 						continue;
 					}
 					if except_node.is_some() && peer.their_node_id.as_ref() == except_node {
 						continue;
 					}
-					self.enqueue_encoded_message(&mut *peer, &encoded_msg);
+					self.enqueue_encoded_gossip_message(&mut *peer, &encoded_msg);
 				}
 			},
 			_ => debug_assert!(false, "We shouldn't attempt to forward anything but gossip messages"),
@@ -1646,8 +2256,13 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 				// thread can be holding the peer lock if we have the global write
 				// lock).
 
-				if let Some(mut descriptor) = self.node_id_to_descriptor.lock().unwrap().remove(&node_id) {
+				let descriptor_opt = self.node_id_to_descriptor.lock().unwrap().remove(&node_id);
+				self.inbound_peers_without_channels.lock().unwrap().remove(&node_id);
+				if let Some(mut descriptor) = descriptor_opt {
+					let mut peer_connected = false;
 					if let Some(peer_mutex) = peers.remove(&descriptor) {
+						self.inbound_connection_removed(&*peer_mutex.lock().unwrap());
+						peer_connected = peer_mutex.lock().unwrap().their_features.is_some();
 						if let Some(msg) = msg {
 							log_trace!(self.logger, "Handling DisconnectPeer HandleError event in peer_handler for node {} with message {}",
 									log_pubkey!(node_id),
@@ -1663,6 +2278,9 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 					}
 					descriptor.disconnect_socket();
 					self.message_handler.chan_handler.peer_disconnected(&node_id, false);
+					if peer_connected {
+						self.custom_message_handler.peer_disconnected(&node_id);
+					}
 				}
 			}
 		}
@@ -1684,12 +2302,17 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 			},
 			Some(peer_lock) => {
 				let peer = peer_lock.lock().unwrap();
+				self.inbound_connection_removed(&*peer);
 				if let Some(node_id) = peer.their_node_id {
 					log_trace!(self.logger,
 						"Handling disconnection of peer {}, with {}future connection to the peer possible.",
 						log_pubkey!(node_id), if no_connection_possible { "no " } else { "" });
 					self.node_id_to_descriptor.lock().unwrap().remove(&node_id);
+					self.inbound_peers_without_channels.lock().unwrap().remove(&node_id);
 					self.message_handler.chan_handler.peer_disconnected(&node_id, no_connection_possible);
+					if peer.their_features.is_some() {
+						self.custom_message_handler.peer_disconnected(&node_id);
+					}
 				}
 			}
 		};
@@ -1707,9 +2330,17 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 	pub fn disconnect_by_node_id(&self, node_id: PublicKey, no_connection_possible: bool) {
 		let mut peers_lock = self.peers.write().unwrap();
 		if let Some(mut descriptor) = self.node_id_to_descriptor.lock().unwrap().remove(&node_id) {
+			self.inbound_peers_without_channels.lock().unwrap().remove(&node_id);
 			log_trace!(self.logger, "Disconnecting peer with id {} due to client request", node_id);
-			peers_lock.remove(&descriptor);
+			let peer_connected = peers_lock.remove(&descriptor).map_or(false, |peer_mutex| {
+				let peer = peer_mutex.lock().unwrap();
+				self.inbound_connection_removed(&*peer);
+				peer.their_features.is_some()
+			});
 			self.message_handler.chan_handler.peer_disconnected(&node_id, no_connection_possible);
+			if peer_connected {
+				self.custom_message_handler.peer_disconnected(&node_id);
+			}
 			descriptor.disconnect_socket();
 		}
 	}
@@ -1720,11 +2351,17 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 	pub fn disconnect_all_peers(&self) {
 		let mut peers_lock = self.peers.write().unwrap();
 		self.node_id_to_descriptor.lock().unwrap().clear();
+		self.inbound_peers_without_channels.lock().unwrap().clear();
+		self.inbound_connections_per_subnet.lock().unwrap().clear();
 		let peers = &mut *peers_lock;
-		for (mut descriptor, peer) in peers.drain() {
-			if let Some(node_id) = peer.lock().unwrap().their_node_id {
+		for (mut descriptor, peer_mutex) in peers.drain() {
+			let peer = peer_mutex.lock().unwrap();
+			if let Some(node_id) = peer.their_node_id {
 				log_trace!(self.logger, "Disconnecting peer with id {} due to client request to disconnect all peers", node_id);
 				self.message_handler.chan_handler.peer_disconnected(&node_id, false);
+				if peer.their_features.is_some() {
+					self.custom_message_handler.peer_disconnected(&node_id);
+				}
 			}
 			descriptor.disconnect_socket();
 		}
@@ -1760,21 +2397,27 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 		{
 			let peers_lock = self.peers.read().unwrap();
 
+			self.inbound_peers_without_channels.lock().unwrap()
+				.retain(|node_id| !self.message_handler.chan_handler.has_channels_with(node_id));
+			self.peer_policy.timer_tick_occurred();
+
 			for (descriptor, peer_mutex) in peers_lock.iter() {
 				let mut peer = peer_mutex.lock().unwrap();
 				if !peer.channel_encryptor.is_ready_for_encryption() || peer.their_node_id.is_none() {
 					// The peer needs to complete its handshake before we can exchange messages. We
-					// give peers one timer tick to complete handshake, reusing
-					// `awaiting_pong_timer_tick_intervals` to track number of timer ticks taken
-					// for handshake completion.
-					if peer.awaiting_pong_timer_tick_intervals != 0 {
+					// give peers `handshake_timeout_ticks` timer ticks to complete handshake.
+					if peer.handshake_timer_ticks >= self.limits.handshake_timeout_ticks {
 						descriptors_needing_disconnect.push(descriptor.clone());
 					} else {
-						peer.awaiting_pong_timer_tick_intervals = 1;
+						peer.handshake_timer_ticks += 1;
 					}
 					continue;
 				}
 
+				peer.timer_ticks_without_messages = peer.timer_ticks_without_messages.saturating_add(1);
+
+				peer.gossip_bandwidth_remaining = self.gossip_config.bytes_per_timer_tick;
+
 				if peer.awaiting_pong_timer_tick_intervals == -1 {
 					// Magic value set in `maybe_send_extra_ping`.
 					peer.awaiting_pong_timer_tick_intervals = 1;
@@ -1810,11 +2453,17 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 			{
 				let mut peers_lock = self.peers.write().unwrap();
 				for descriptor in descriptors_needing_disconnect.iter() {
-					if let Some(peer) = peers_lock.remove(descriptor) {
-						if let Some(node_id) = peer.lock().unwrap().their_node_id {
+					if let Some(peer_mutex) = peers_lock.remove(descriptor) {
+						let peer = peer_mutex.lock().unwrap();
+						self.inbound_connection_removed(&*peer);
+						if let Some(node_id) = peer.their_node_id {
 							log_trace!(self.logger, "Disconnecting peer with id {} due to ping timeout", node_id);
 							self.node_id_to_descriptor.lock().unwrap().remove(&node_id);
+							self.inbound_peers_without_channels.lock().unwrap().remove(&node_id);
 							self.message_handler.chan_handler.peer_disconnected(&node_id, false);
+							if peer.their_features.is_some() {
+								self.custom_message_handler.peer_disconnected(&node_id);
+							}
 						}
 					}
 				}
@@ -1827,6 +2476,28 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, CMH: Deref> P
 	}
 }
 
+fn is_channel_msg(type_id: u16) -> bool {
+	match type_id {
+		msgs::OpenChannel::TYPE |
+		msgs::AcceptChannel::TYPE |
+		msgs::FundingCreated::TYPE |
+		msgs::FundingSigned::TYPE |
+		msgs::ChannelReady::TYPE |
+		msgs::Shutdown::TYPE |
+		msgs::ClosingSigned::TYPE |
+		msgs::UpdateAddHTLC::TYPE |
+		msgs::UpdateFulfillHTLC::TYPE |
+		msgs::UpdateFailHTLC::TYPE |
+		msgs::UpdateFailMalformedHTLC::TYPE |
+		msgs::CommitmentSigned::TYPE |
+		msgs::RevokeAndACK::TYPE |
+		msgs::UpdateFee::TYPE |
+		msgs::ChannelReestablish::TYPE |
+		msgs::AnnouncementSignatures::TYPE => true,
+		_ => false
+	}
+}
+
 fn is_gossip_msg(type_id: u16) -> bool {
 	match type_id {
 		msgs::ChannelAnnouncement::TYPE |
@@ -1842,11 +2513,14 @@ fn is_gossip_msg(type_id: u16) -> bool {
 
 #[cfg(test)]
 mod tests {
-	use ln::peer_handler::{PeerManager, MessageHandler, SocketDescriptor, IgnoringMessageHandler, filter_addresses};
+	use ln::peer_handler::{PeerManager, MessageHandler, SocketDescriptor, IgnoringMessageHandler, GossipBandwidthConfig, PeerConnectionLimits, PeerHandleError, filter_addresses};
+	use ln::peer_policy::{AllowAllPeerPolicy, DefaultPeerPolicyUsingTime, PeerPolicy, PeerPolicyParameters};
 	use ln::{msgs, wire};
 	use ln::msgs::NetAddress;
+	use ln::wire::Encode;
 	use util::events;
 	use util::test_utils;
+	use util::time::tests::SinceEpoch;
 
 	use bitcoin::secp256k1::Secp256k1;
 	use bitcoin::secp256k1::{SecretKey, PublicKey};
@@ -1854,6 +2528,8 @@ mod tests {
 	use prelude::*;
 	use sync::{Arc, Mutex};
 	use core::sync::atomic::Ordering;
+	use core::ops::Deref;
+	use core::time::Duration;
 
 	#[derive(Clone)]
 	struct FileDescriptor {
@@ -1902,7 +2578,9 @@ mod tests {
 		cfgs
 	}
 
-	fn create_network<'a>(peer_count: usize, cfgs: &'a Vec<PeerManagerCfg>) -> Vec<PeerManager<FileDescriptor, &'a test_utils::TestChannelMessageHandler, &'a test_utils::TestRoutingMessageHandler, &'a test_utils::TestLogger, IgnoringMessageHandler>> {
+	type TestPeerManager<'a, PP> = PeerManager<FileDescriptor, &'a test_utils::TestChannelMessageHandler, &'a test_utils::TestRoutingMessageHandler, &'a test_utils::TestLogger, IgnoringMessageHandler, PP>;
+
+	fn create_network<'a>(peer_count: usize, cfgs: &'a Vec<PeerManagerCfg>) -> Vec<TestPeerManager<'a, AllowAllPeerPolicy>> {
 		let mut peers = Vec::new();
 		for i in 0..peer_count {
 			let node_secret = SecretKey::from_slice(&[42 + i as u8; 32]).unwrap();
@@ -1915,13 +2593,21 @@ mod tests {
 		peers
 	}
 
-	fn establish_connection<'a>(peer_a: &PeerManager<FileDescriptor, &'a test_utils::TestChannelMessageHandler, &'a test_utils::TestRoutingMessageHandler, &'a test_utils::TestLogger, IgnoringMessageHandler>, peer_b: &PeerManager<FileDescriptor, &'a test_utils::TestChannelMessageHandler, &'a test_utils::TestRoutingMessageHandler, &'a test_utils::TestLogger, IgnoringMessageHandler>) -> (FileDescriptor, FileDescriptor) {
+	fn establish_connection<'a, PA: Deref, PB: Deref>(peer_a: &TestPeerManager<'a, PA>, peer_b: &TestPeerManager<'a, PB>) -> (FileDescriptor, FileDescriptor)
+	where PA::Target: PeerPolicy, PB::Target: PeerPolicy {
+		try_establish_connection(peer_a, peer_b, 1, None).unwrap()
+	}
+
+	/// Connects `peer_b` to `peer_a` over descriptors with the given `fd`, with `peer_a` seeing the
+	/// connection as coming from `remote_network_address`.
+	fn try_establish_connection<'a, PA: Deref, PB: Deref>(peer_a: &TestPeerManager<'a, PA>, peer_b: &TestPeerManager<'a, PB>, fd: u16, remote_network_address: Option<NetAddress>) -> Result<(FileDescriptor, FileDescriptor), PeerHandleError>
+	where PA::Target: PeerPolicy, PB::Target: PeerPolicy {
 		let secp_ctx = Secp256k1::new();
 		let a_id = PublicKey::from_secret_key(&secp_ctx, &peer_a.our_node_secret);
-		let mut fd_a = FileDescriptor { fd: 1, outbound_data: Arc::new(Mutex::new(Vec::new())) };
-		let mut fd_b = FileDescriptor { fd: 1, outbound_data: Arc::new(Mutex::new(Vec::new())) };
+		let mut fd_a = FileDescriptor { fd, outbound_data: Arc::new(Mutex::new(Vec::new())) };
+		let mut fd_b = FileDescriptor { fd, outbound_data: Arc::new(Mutex::new(Vec::new())) };
 		let initial_data = peer_b.new_outbound_connection(a_id, fd_b.clone(), None).unwrap();
-		peer_a.new_inbound_connection(fd_a.clone(), None).unwrap();
+		peer_a.new_inbound_connection(fd_a.clone(), remote_network_address)?;
 		assert_eq!(peer_a.read_event(&mut fd_a, &initial_data).unwrap(), false);
 		peer_a.process_events();
 
@@ -1930,13 +2616,13 @@ mod tests {
 
 		peer_b.process_events();
 		let b_data = fd_b.outbound_data.lock().unwrap().split_off(0);
-		assert_eq!(peer_a.read_event(&mut fd_a, &b_data).unwrap(), false);
+		assert_eq!(peer_a.read_event(&mut fd_a, &b_data)?, false);
 
 		peer_a.process_events();
 		let a_data = fd_a.outbound_data.lock().unwrap().split_off(0);
 		assert_eq!(peer_b.read_event(&mut fd_b, &a_data).unwrap(), false);
 
-		(fd_a.clone(), fd_b.clone())
+		Ok((fd_a.clone(), fd_b.clone()))
 	}
 
 	#[test]
@@ -2196,4 +2882,247 @@ mod tests {
 		// For (None)
 		assert_eq!(filter_addresses(None), None);
 	}
+
+	#[test]
+	fn test_inbound_connection_limits() {
+		// Inbound connections are refused once we have `max_total_peers` peers unless we can evict
+		// an idle peer with no channels, and are limited per source subnet.
+		let cfgs = create_peermgr_cfgs(4);
+		let mut peers = create_network(4, &cfgs);
+		peers[0].limits = PeerConnectionLimits {
+			max_total_peers: 2, max_inbound_connections_per_subnet: 1, ..Default::default()
+		};
+		let secp_ctx = Secp256k1::new();
+		let ids: Vec<PublicKey> = peers.iter().map(|peer| PublicKey::from_secret_key(&secp_ctx, &peer.our_node_secret)).collect();
+		let addr = |addr: [u8; 4]| Some(NetAddress::IPv4 { addr, port: 9735 });
+
+		try_establish_connection(&peers[0], &peers[1], 1, addr([1, 2, 3, 4])).unwrap();
+		assert!(try_establish_connection(&peers[0], &peers[2], 2, addr([1, 2, 3, 5])).is_err());
+		// Our failed attempts remain open on the connecting side, so we retry with new descriptors.
+		try_establish_connection(&peers[0], &peers[2], 4, addr([5, 6, 7, 8])).unwrap();
+		assert_eq!(peers[0].get_peer_node_ids().len(), 2);
+
+		// Both peers have sent us their Init since the last timer tick, so neither is idle.
+		assert!(try_establish_connection(&peers[0], &peers[3], 3, addr([9, 9, 9, 9])).is_err());
+		assert_eq!(peers[0].get_peer_node_ids().len(), 2);
+
+		// Once they're idle, we evict the peer we have no channels with to make room.
+		peers[0].timer_tick_occurred();
+		cfgs[0].chan_handler.peers_with_channels.lock().unwrap().insert(ids[2]);
+		try_establish_connection(&peers[0], &peers[3], 5, addr([9, 9, 9, 9])).unwrap();
+		let mut connected = peers[0].get_peer_node_ids();
+		connected.sort();
+		let mut expected = vec![ids[2], ids[3]];
+		expected.sort();
+		assert_eq!(connected, expected);
+	}
+
+	#[test]
+	fn test_evicts_most_idle_peer() {
+		// When we need to make room for a new inbound connection, we evict the peer without
+		// channels which has been idle for the most timer ticks.
+		let cfgs = create_peermgr_cfgs(4);
+		let mut peers = create_network(4, &cfgs);
+		let secp_ctx = Secp256k1::new();
+		let ids: Vec<PublicKey> = peers.iter().map(|peer| PublicKey::from_secret_key(&secp_ctx, &peer.our_node_secret)).collect();
+
+		// Answering our pings doesn't keep a peer from being considered idle.
+		let exchange_messages = |peer: &TestPeerManager<AllowAllPeerPolicy>, fd_a: &mut FileDescriptor, fd_b: &mut FileDescriptor| {
+			loop {
+				let a_data = fd_a.outbound_data.lock().unwrap().split_off(0);
+				assert_eq!(peer.read_event(fd_b, &a_data).unwrap(), false);
+				peer.process_events();
+				let b_data = fd_b.outbound_data.lock().unwrap().split_off(0);
+				assert_eq!(peers[0].read_event(fd_a, &b_data).unwrap(), false);
+				peers[0].process_events();
+				if a_data.is_empty() && b_data.is_empty() && fd_a.outbound_data.lock().unwrap().is_empty() { break; }
+			}
+		};
+		let (mut fd_2a, mut fd_2b) = try_establish_connection(&peers[0], &peers[2], 2, None).unwrap();
+		peers[0].timer_tick_occurred();
+		exchange_messages(&peers[2], &mut fd_2a, &mut fd_2b);
+		let (mut fd_1a, mut fd_1b) = try_establish_connection(&peers[0], &peers[1], 1, None).unwrap();
+		peers[0].timer_tick_occurred();
+		exchange_messages(&peers[2], &mut fd_2a, &mut fd_2b);
+		exchange_messages(&peers[1], &mut fd_1a, &mut fd_1b);
+
+		peers[0].limits.max_total_peers = 2;
+		try_establish_connection(&peers[0], &peers[3], 3, None).unwrap();
+		let mut connected = peers[0].get_peer_node_ids();
+		connected.sort();
+		let mut expected = vec![ids[1], ids[3]];
+		expected.sort();
+		assert_eq!(connected, expected);
+		assert!(!peers[0].inbound_peers_without_channels.lock().unwrap().contains(&ids[2]));
+	}
+
+	#[test]
+	fn test_peers_without_channels_limit_and_handshake_timeout() {
+		let cfgs = create_peermgr_cfgs(4);
+		let mut peers = create_network(4, &cfgs);
+		peers[0].limits = PeerConnectionLimits {
+			max_peers_without_channels: 1, handshake_timeout_ticks: 2, ..Default::default()
+		};
+		let secp_ctx = Secp256k1::new();
+		let ids: Vec<PublicKey> = peers.iter().map(|peer| PublicKey::from_secret_key(&secp_ctx, &peer.our_node_secret)).collect();
+
+		// Once the peer identifies itself, we refuse it if we have too many peers without channels,
+		// unless we have a channel with it.
+		try_establish_connection(&peers[0], &peers[1], 1, None).unwrap();
+		assert!(try_establish_connection(&peers[0], &peers[2], 2, None).is_err());
+		assert_eq!(peers[0].get_peer_node_ids(), vec![ids[1]]);
+		peers[2].disconnect_all_peers();
+		cfgs[0].chan_handler.peers_with_channels.lock().unwrap().insert(ids[2]);
+		try_establish_connection(&peers[0], &peers[2], 2, None).unwrap();
+		assert_eq!(peers[0].get_peer_node_ids().len(), 2);
+
+		// Outbound peers don't count towards the limit.
+		try_establish_connection(&peers[3], &peers[0], 4, None).unwrap();
+		assert_eq!(peers[0].get_peer_node_ids().len(), 3);
+
+		// A peer which never completes the handshake is disconnected after
+		// `handshake_timeout_ticks` full timer ticks.
+		let fd = FileDescriptor { fd: 3, outbound_data: Arc::new(Mutex::new(Vec::new())) };
+		peers[0].new_inbound_connection(fd.clone(), None).unwrap();
+		for _ in 0..2 {
+			peers[0].timer_tick_occurred();
+			assert!(peers[0].peers.read().unwrap().contains_key(&fd));
+		}
+		peers[0].timer_tick_occurred();
+		assert!(!peers[0].peers.read().unwrap().contains_key(&fd));
+	}
+
+	#[test]
+	fn test_peer_policy_bans_misbehaving_peers() {
+		// Protocol violations are reported to the peer policy, and once it bans a peer we refuse
+		// its connections, by address before the handshake and by node id after it.
+		let cfgs = create_peermgr_cfgs(2);
+		let peers = create_network(2, &cfgs);
+		let policy = DefaultPeerPolicyUsingTime::<SinceEpoch>::new(PeerPolicyParameters { ban_by_address: true, ..Default::default() });
+		let msg_handler = MessageHandler { chan_handler: &cfgs[0].chan_handler, route_handler: &cfgs[0].routing_handler };
+		let peer_a = PeerManager::new_with_policy(msg_handler, peers[0].our_node_secret, &[0; 32], &cfgs[0].logger, IgnoringMessageHandler {}, PeerConnectionLimits::default(), &policy);
+		let secp_ctx = Secp256k1::new();
+		let b_id = PublicKey::from_secret_key(&secp_ctx, &peers[1].our_node_secret);
+		let addr = |addr: [u8; 4]| Some(NetAddress::IPv4 { addr, port: 9735 });
+
+		// A message too short to hold a type is an invalid message, twice of which gets a peer
+		// banned with the default parameters.
+		for fd in 1..3 {
+			let (mut fd_a, fd_b) = try_establish_connection(&peer_a, &peers[1], fd, addr([1, 2, 3, 4])).unwrap();
+			let invalid_message = peers[1].peers.read().unwrap().get(&fd_b).unwrap().lock().unwrap()
+				.channel_encryptor.encrypt_message(&[]);
+			assert!(peer_a.read_event(&mut fd_a, &invalid_message).is_err());
+			peers[1].disconnect_all_peers();
+		}
+		assert!(policy.is_peer_banned(&b_id));
+		assert!(try_establish_connection(&peer_a, &peers[1], 3, addr([1, 2, 3, 4])).is_err());
+		peers[1].disconnect_all_peers();
+		assert!(try_establish_connection(&peer_a, &peers[1], 4, addr([5, 6, 7, 8])).is_err());
+		assert!(peer_a.get_peer_node_ids().is_empty());
+		peers[1].disconnect_all_peers();
+
+		// Peers with which we have channels are exempt from the policy.
+		cfgs[0].chan_handler.peers_with_channels.lock().unwrap().insert(b_id);
+		try_establish_connection(&peer_a, &peers[1], 5, addr([5, 6, 7, 8])).unwrap();
+		assert_eq!(peer_a.get_peer_node_ids(), vec![b_id]);
+		peers[1].disconnect_all_peers();
+		peer_a.disconnect_all_peers();
+		cfgs[0].chan_handler.peers_with_channels.lock().unwrap().clear();
+
+		// Once the ban expires, the peer may connect again.
+		SinceEpoch::advance(Duration::from_secs(60 * 60 * 24));
+		try_establish_connection(&peer_a, &peers[1], 6, addr([1, 2, 3, 4])).unwrap();
+		assert_eq!(peer_a.get_peer_node_ids(), vec![b_id]);
+	}
+
+	#[test]
+	fn test_peer_stats() {
+		// Traffic is counted by message type in both directions, and a ping answered by the peer
+		// gives us its latency.
+		let cfgs = create_peermgr_cfgs(2);
+		let peers = create_network(2, &cfgs);
+		let (mut fd_a, mut fd_b) = establish_connection(&peers[0], &peers[1]);
+		let secp_ctx = Secp256k1::new();
+		let b_id = PublicKey::from_secret_key(&secp_ctx, &peers[1].our_node_secret);
+
+		let stats = peers[0].peer_stats();
+		assert_eq!(stats.len(), 1);
+		assert_eq!(stats[0].node_id, b_id);
+		assert!(stats[0].inbound_connection);
+		assert_eq!(stats[0].sent.by_message_type.get(&msgs::Init::TYPE).unwrap().messages, 1);
+		assert_eq!(stats[0].received.by_message_type.get(&msgs::Init::TYPE).unwrap().messages, 1);
+		assert_eq!(stats[0].received.channel(), Default::default());
+		assert!(stats[0].ping_latency.is_none());
+
+		// Deliver the ping and whatever the peers send in response until they're both idle.
+		peers[0].timer_tick_occurred();
+		loop {
+			let a_data = fd_a.outbound_data.lock().unwrap().split_off(0);
+			assert_eq!(peers[1].read_event(&mut fd_b, &a_data).unwrap(), false);
+			peers[1].process_events();
+			let b_data = fd_b.outbound_data.lock().unwrap().split_off(0);
+			assert_eq!(peers[0].read_event(&mut fd_a, &b_data).unwrap(), false);
+			peers[0].process_events();
+			if a_data.is_empty() && b_data.is_empty() && fd_a.outbound_data.lock().unwrap().is_empty() { break; }
+		}
+
+		let a_stats = peers[0].peer_stats().pop().unwrap();
+		let pings = a_stats.sent.by_message_type.get(&msgs::Ping::TYPE).unwrap().messages;
+		assert!(pings > 0);
+		assert_eq!(a_stats.received.by_message_type.get(&msgs::Pong::TYPE).unwrap().messages, pings);
+		assert!(a_stats.ping_latency.is_some());
+		assert_eq!(a_stats.read_pauses, 0);
+		assert_eq!(a_stats.pending_outbound_messages, 0);
+		assert_eq!(a_stats.pending_gossip_messages, 0);
+
+		// Everything either peer sent has been delivered to the other.
+		let b_stats = peers[1].peer_stats().pop().unwrap();
+		assert!(!b_stats.inbound_connection);
+		assert_eq!(a_stats.sent.wire_bytes, b_stats.received.wire_bytes);
+		assert_eq!(a_stats.received.wire_bytes, b_stats.sent.wire_bytes);
+		assert_eq!(a_stats.sent.total(), b_stats.received.total());
+		assert_eq!(a_stats.received.total(), b_stats.sent.total());
+	}
+
+	#[test]
+	fn test_gossip_bandwidth_budget() {
+		// Channel messages are sent ahead of queued gossip and regardless of the gossip budget,
+		// which limits how much of our initial sync goes out each timer tick.
+		let cfgs = create_peermgr_cfgs(2);
+		cfgs[0].routing_handler.request_full_sync.store(true, Ordering::Release);
+		cfgs[1].routing_handler.request_full_sync.store(true, Ordering::Release);
+		let mut peers = create_network(2, &cfgs);
+		peers[0].gossip_config = GossipBandwidthConfig { bytes_per_timer_tick: 1 };
+		let (mut fd_a, mut fd_b) = establish_connection(&peers[0], &peers[1]);
+		let secp_ctx = Secp256k1::new();
+		let b_id = PublicKey::from_secret_key(&secp_ctx, &peers[1].our_node_secret);
+
+		// Once B's gossip_timestamp_filter reaches A, A starts its initial sync.
+		peers[1].process_events();
+		let b_data = fd_b.outbound_data.lock().unwrap().split_off(0);
+		peers[0].read_event(&mut fd_a, &b_data).unwrap();
+		peers[0].process_events();
+		let a_data = fd_a.outbound_data.lock().unwrap().split_off(0);
+		peers[1].read_event(&mut fd_b, &a_data).unwrap();
+
+		let gossip_received = || cfgs[1].routing_handler.chan_anns_recvd.load(Ordering::Acquire)
+			+ cfgs[1].routing_handler.chan_upds_recvd.load(Ordering::Acquire);
+		assert_eq!(gossip_received(), 1);
+		assert!(peers[0].peer_stats().pop().unwrap().pending_gossip_messages > 0);
+
+		cfgs[0].chan_handler.pending_events.lock().unwrap().push(events::MessageSendEvent::SendShutdown {
+			node_id: b_id, msg: msgs::Shutdown { channel_id: [0; 32], scriptpubkey: bitcoin::Script::new() },
+		});
+		peers[0].process_events();
+		let a_data = fd_a.outbound_data.lock().unwrap().split_off(0);
+		peers[1].read_event(&mut fd_b, &a_data).unwrap();
+		assert_eq!(peers[1].peer_stats().pop().unwrap().received.channel().messages, 1);
+		assert_eq!(gossip_received(), 1);
+
+		// Each timer tick refills the budget, letting one more message through.
+		peers[0].timer_tick_occurred();
+		let a_data = fd_a.outbound_data.lock().unwrap().split_off(0);
+		peers[1].read_event(&mut fd_b, &a_data).unwrap();
+		assert_eq!(gossip_received(), 2);
+	}
 }
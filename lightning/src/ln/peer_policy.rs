@@ -0,0 +1,710 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Policies deciding which peers the [`PeerManager`] accepts connections from.
+//!
+//! The [`PeerManager`] reports protocol violations committed by its peers to a [`PeerPolicy`]
+//! and consults it before accepting a connection or completing a handshake. The
+//! [`DefaultPeerPolicy`] scores violations, bans peers which misbehave repeatedly and can
+//! optionally restrict connections to an allowlist of node ids, while the [`AllowAllPeerPolicy`]
+//! allows every peer.
+//!
+//! [`PeerManager`]: crate::ln::peer_handler::PeerManager
+
+use bitcoin::secp256k1::PublicKey;
+
+use ln::msgs::{DecodeError, NetAddress};
+use ln::peer_handler::filter_addresses;
+use routing::scoring::ConfiguredTime;
+use util::ser::{Readable, ReadableArgs, Writeable, Writer};
+use util::time::Time;
+
+use prelude::*;
+use io::{self, Read};
+use sync::Mutex;
+use core::ops::Deref;
+use core::time::Duration;
+
+/// A protocol violation committed by a peer, as reported to a [`PeerPolicy`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerMisbehavior {
+	/// The peer failed the noise handshake or sent data which failed to decrypt.
+	InvalidEncryption,
+	/// The peer sent a message which failed to decode or which violated the connection setup
+	/// rules, e.g., by not starting with an `init` message.
+	InvalidMessage,
+	/// The peer sent a gossip message which failed to decode or was rejected by the routing
+	/// message handler.
+	InvalidGossip,
+	/// The peer sent an `error` message which applies to all channels.
+	ErrorMessage,
+	/// The peer sent a message which the channel or custom message handler rejected, causing us
+	/// to send an `error` or `warning` message or to disconnect.
+	HandlingError,
+}
+
+/// A hook deciding which peers the [`PeerManager`] connects to, informed of the protocol
+/// violations they commit.
+///
+/// Note that peers with which we have channels are exempt from any policy, as refusing them
+/// could prevent us from resolving our channels with them.
+///
+/// [`PeerManager`]: crate::ln::peer_handler::PeerManager
+pub trait PeerPolicy {
+	/// Returns whether we should accept a new inbound connection from the given address, before
+	/// the peer's node id is known.
+	fn allow_connection(&self, remote_network_address: Option<&NetAddress>) -> bool;
+
+	/// Returns whether we should continue a connection with the given peer. Called once the
+	/// handshake with an inbound peer completes and before we initiate an outbound connection.
+	fn allow_peer(&self, node_id: &PublicKey, remote_network_address: Option<&NetAddress>) -> bool;
+
+	/// Informs the policy that a peer committed the given protocol violation. The node id is
+	/// `None` if the violation happened before the handshake completed.
+	///
+	/// If, afterwards, [`Self::allow_peer`] (or [`Self::allow_connection`] during the handshake)
+	/// no longer allows the peer, it is disconnected.
+	fn peer_misbehaved(&self, node_id: Option<&PublicKey>, remote_network_address: Option<&NetAddress>, misbehavior: PeerMisbehavior);
+
+	/// Called on every [`PeerManager::timer_tick_occurred`], e.g. to drop state which is no longer
+	/// relevant.
+	///
+	/// [`PeerManager::timer_tick_occurred`]: crate::ln::peer_handler::PeerManager::timer_tick_occurred
+	fn timer_tick_occurred(&self) {}
+}
+
+/// Refers to a [`PeerPolicy`] which can also be written to disk.
+///
+/// We need this trait to be able to pass in a peer policy to `lightning-background-processor`
+/// that will enable us to use the [`Persister`] to persist it.
+///
+/// [`Persister`]: crate::util::persist::Persister
+pub trait WriteablePeerPolicy: PeerPolicy + Writeable {}
+
+impl<T> WriteablePeerPolicy for T where T: PeerPolicy + Writeable {}
+
+/// A [`PeerPolicy`] which allows all peers regardless of their misbehavior.
+///
+/// It persists as a [`DefaultPeerPolicy`] without any bans, so a node may switch to the latter
+/// later on.
+pub struct AllowAllPeerPolicy {}
+
+impl PeerPolicy for AllowAllPeerPolicy {
+	fn allow_connection(&self, _remote_network_address: Option<&NetAddress>) -> bool { true }
+	fn allow_peer(&self, _node_id: &PublicKey, _remote_network_address: Option<&NetAddress>) -> bool { true }
+	fn peer_misbehaved(&self, _node_id: Option<&PublicKey>, _remote_network_address: Option<&NetAddress>, _misbehavior: PeerMisbehavior) {}
+}
+
+impl Deref for AllowAllPeerPolicy {
+	type Target = AllowAllPeerPolicy;
+	fn deref(&self) -> &Self { self }
+}
+
+impl Writeable for AllowAllPeerPolicy {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+		write_tlv_fields!(w, {});
+		Ok(())
+	}
+}
+
+impl Readable for AllowAllPeerPolicy {
+	fn read<R: Read>(r: &mut R) -> Result<Self, DecodeError> {
+		read_tlv_fields!(r, {});
+		Ok(Self {})
+	}
+}
+
+/// Parameters for configuring [`DefaultPeerPolicy`].
+#[derive(Clone, Debug)]
+pub struct PeerPolicyParameters {
+	/// The score added for [`PeerMisbehavior::InvalidEncryption`].
+	///
+	/// Default value: 25
+	pub invalid_encryption_penalty: u64,
+
+	/// The score added for [`PeerMisbehavior::InvalidMessage`].
+	///
+	/// Default value: 50
+	pub invalid_message_penalty: u64,
+
+	/// The score added for [`PeerMisbehavior::InvalidGossip`].
+	///
+	/// Default value: 10
+	pub invalid_gossip_penalty: u64,
+
+	/// The score added for [`PeerMisbehavior::ErrorMessage`].
+	///
+	/// Default value: 20
+	pub error_message_penalty: u64,
+
+	/// The score added for [`PeerMisbehavior::HandlingError`].
+	///
+	/// Default value: 20
+	pub handling_error_penalty: u64,
+
+	/// The score at which a peer is banned.
+	///
+	/// Default value: 100
+	pub ban_threshold: u64,
+
+	/// How long a peer is banned for once its score reaches [`Self::ban_threshold`].
+	///
+	/// Default value: 24 hours
+	pub ban_duration: Duration,
+
+	/// The time it takes for a peer's score to decay by half.
+	///
+	/// Default value: 1 hour
+	pub score_half_life: Duration,
+
+	/// Whether to also score and ban the IP address a peer connected from, rather than only its
+	/// node id, which costs nothing to replace.
+	///
+	/// Only public IP addresses are scored, as loopback and private addresses are commonly shared
+	/// by many peers, e.g. all those connecting through a local Tor daemon or a reverse proxy.
+	/// Note that many unrelated users may still share a public address behind a NAT. Onion and
+	/// hostname addresses are never scored. Addresses may always be banned manually.
+	///
+	/// Default value: false
+	pub ban_by_address: bool,
+}
+
+impl Default for PeerPolicyParameters {
+	fn default() -> Self {
+		Self {
+			invalid_encryption_penalty: 25,
+			invalid_message_penalty: 50,
+			invalid_gossip_penalty: 10,
+			error_message_penalty: 20,
+			handling_error_penalty: 20,
+			ban_threshold: 100,
+			ban_duration: Duration::from_secs(60 * 60 * 24),
+			score_half_life: Duration::from_secs(60 * 60),
+			ban_by_address: false,
+		}
+	}
+}
+
+impl PeerPolicyParameters {
+	fn penalty(&self, misbehavior: PeerMisbehavior) -> u64 {
+		match misbehavior {
+			PeerMisbehavior::InvalidEncryption => self.invalid_encryption_penalty,
+			PeerMisbehavior::InvalidMessage => self.invalid_message_penalty,
+			PeerMisbehavior::InvalidGossip => self.invalid_gossip_penalty,
+			PeerMisbehavior::ErrorMessage => self.error_message_penalty,
+			PeerMisbehavior::HandlingError => self.handling_error_penalty,
+		}
+	}
+}
+
+/// [`PeerPolicy`] implementation banning peers whose protocol violations add up.
+///
+/// Each [`PeerMisbehavior`] adds a penalty from the [`PeerPolicyParameters`] to the score of the
+/// peer's node id and, if enabled, of its public IP address. Scores decay by half every
+/// [`PeerPolicyParameters::score_half_life`], and once one reaches the ban threshold the node id
+/// (or address) is banned for [`PeerPolicyParameters::ban_duration`]. Peers may also be banned
+/// and unbanned manually.
+///
+/// For private nodes, an allowlist may be set with [`Self::set_allowlist`], in which case only
+/// the listed node ids are accepted.
+///
+/// Bans are persisted when the policy is written, e.g., under
+/// [`PEER_POLICY_PERSISTENCE_KEY`], and can be read back with [`read_peer_policy`]. Scores and
+/// the allowlist are not persisted. At most 1000 node ids and 1000 addresses are scored at once,
+/// with the lowest score being forgotten to make room for a new one.
+///
+/// # Note
+///
+/// Mixing the `no-std` feature between serialization and deserialization results in undefined
+/// behavior. With `no-std`, bans never expire.
+///
+/// [`PEER_POLICY_PERSISTENCE_KEY`]: crate::util::persist::PEER_POLICY_PERSISTENCE_KEY
+/// [`read_peer_policy`]: crate::util::persist::read_peer_policy
+pub type DefaultPeerPolicy = DefaultPeerPolicyUsingTime<ConfiguredTime>;
+
+/// [`PeerPolicy`] implementation generic over the time source, see [`DefaultPeerPolicy`].
+///
+/// (C-not exported) generally all users should use the [`DefaultPeerPolicy`] type alias.
+pub struct DefaultPeerPolicyUsingTime<T: Time> {
+	params: PeerPolicyParameters,
+	state: Mutex<PolicyState<T>>,
+}
+
+struct PolicyState<T: Time> {
+	node_scores: HashMap<PublicKey, MisbehaviorScore<T>>,
+	address_scores: HashMap<BanAddress, MisbehaviorScore<T>>,
+	node_bans: HashMap<PublicKey, Ban<T>>,
+	address_bans: HashMap<BanAddress, Ban<T>>,
+	allowlist: Option<HashSet<PublicKey>>,
+}
+
+/// The part of a [`NetAddress`] we ban by, ignoring the port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum BanAddress {
+	IPv4([u8; 4]),
+	IPv6([u8; 16]),
+}
+
+impl BanAddress {
+	fn from_net_address(address: &NetAddress) -> Option<Self> {
+		match address {
+			NetAddress::IPv4 { addr, .. } => Some(BanAddress::IPv4(*addr)),
+			NetAddress::IPv6 { addr, .. } => Some(BanAddress::IPv6(*addr)),
+			_ => None,
+		}
+	}
+
+	fn to_net_address(self) -> NetAddress {
+		match self {
+			BanAddress::IPv4(addr) => NetAddress::IPv4 { addr, port: 0 },
+			BanAddress::IPv6(addr) => NetAddress::IPv6 { addr, port: 0 },
+		}
+	}
+}
+
+struct MisbehaviorScore<T: Time> {
+	score: u64,
+	last_updated: T,
+}
+
+impl<T: Time> MisbehaviorScore<T> {
+	fn decayed_score(&self, half_life: Duration) -> u64 {
+		let half_lives = self.last_updated.elapsed().as_secs() / core::cmp::max(half_life.as_secs(), 1);
+		if half_lives >= 64 { 0 } else { self.score >> half_lives }
+	}
+}
+
+struct Ban<T: Time> {
+	banned_at: T,
+	duration: Duration,
+}
+
+impl<T: Time> Ban<T> {
+	fn remaining(&self) -> Option<Duration> {
+		self.duration.checked_sub(self.banned_at.elapsed()).filter(|remaining| *remaining > Duration::from_secs(0))
+	}
+}
+
+/// The maximum number of node ids, and separately of addresses, we track a score for. Node ids
+/// cost nothing to generate, so without a limit a peer could grow our state without bound.
+const MAX_TRACKED_SCORES: usize = 1000;
+
+/// Adds `penalty` to the score in `scores` for `key`, returning whether the score reached the
+/// ban threshold, in which case the score is reset.
+///
+/// If we already track [`MAX_TRACKED_SCORES`] scores, the lowest one is evicted to make room.
+fn add_penalty<K: Copy + Eq + core::hash::Hash, T: Time>(scores: &mut HashMap<K, MisbehaviorScore<T>>, key: K, penalty: u64, params: &PeerPolicyParameters) -> bool {
+	if scores.len() >= MAX_TRACKED_SCORES && !scores.contains_key(&key) {
+		let lowest = scores.iter()
+			.min_by_key(|(_, score)| score.decayed_score(params.score_half_life))
+			.map(|(key, _)| *key);
+		if let Some(lowest) = lowest {
+			scores.remove(&lowest);
+		}
+	}
+	let score = scores.entry(key).or_insert(MisbehaviorScore { score: 0, last_updated: T::now() });
+	score.score = score.decayed_score(params.score_half_life).saturating_add(penalty);
+	score.last_updated = T::now();
+	score.score >= params.ban_threshold
+}
+
+/// Returns whether `key` is banned in `bans`, removing the ban if it expired.
+fn check_ban<K: Eq + core::hash::Hash, T: Time>(bans: &mut HashMap<K, Ban<T>>, key: &K) -> bool {
+	match bans.get(key).map(|ban| ban.remaining().is_some()) {
+		Some(true) => true,
+		Some(false) => { bans.remove(key); false },
+		None => false,
+	}
+}
+
+impl<T: Time> DefaultPeerPolicyUsingTime<T> {
+	/// Creates a new policy with the given parameters and no bans or allowlist.
+	pub fn new(params: PeerPolicyParameters) -> Self {
+		Self {
+			params,
+			state: Mutex::new(PolicyState {
+				node_scores: HashMap::new(),
+				address_scores: HashMap::new(),
+				node_bans: HashMap::new(),
+				address_bans: HashMap::new(),
+				allowlist: None,
+			}),
+		}
+	}
+
+	/// Bans the given node id for `duration`, replacing any existing ban.
+	pub fn ban_peer(&self, node_id: &PublicKey, duration: Duration) {
+		let mut state = self.state.lock().unwrap();
+		state.node_scores.remove(node_id);
+		state.node_bans.insert(*node_id, Ban { banned_at: T::now(), duration });
+	}
+
+	/// Bans the IP address of the given [`NetAddress`] for `duration`, replacing any existing ban.
+	/// The port is ignored. Returns false if the address is not an IP address and thus can't be
+	/// banned.
+	pub fn ban_address(&self, address: &NetAddress, duration: Duration) -> bool {
+		match BanAddress::from_net_address(address) {
+			Some(address) => {
+				let mut state = self.state.lock().unwrap();
+				state.address_scores.remove(&address);
+				state.address_bans.insert(address, Ban { banned_at: T::now(), duration });
+				true
+			},
+			None => false,
+		}
+	}
+
+	/// Lifts any ban on the given node id and resets its score.
+	pub fn unban_peer(&self, node_id: &PublicKey) {
+		let mut state = self.state.lock().unwrap();
+		state.node_scores.remove(node_id);
+		state.node_bans.remove(node_id);
+	}
+
+	/// Lifts any ban on the IP address of the given [`NetAddress`] and resets its score.
+	pub fn unban_address(&self, address: &NetAddress) {
+		if let Some(address) = BanAddress::from_net_address(address) {
+			let mut state = self.state.lock().unwrap();
+			state.address_scores.remove(&address);
+			state.address_bans.remove(&address);
+		}
+	}
+
+	/// Returns whether the given node id is currently banned.
+	pub fn is_peer_banned(&self, node_id: &PublicKey) -> bool {
+		check_ban(&mut self.state.lock().unwrap().node_bans, node_id)
+	}
+
+	/// Returns whether the IP address of the given [`NetAddress`] is currently banned.
+	pub fn is_address_banned(&self, address: &NetAddress) -> bool {
+		match BanAddress::from_net_address(address) {
+			Some(address) => check_ban(&mut self.state.lock().unwrap().address_bans, &address),
+			None => false,
+		}
+	}
+
+	/// Restricts connections to the given node ids, or lifts the restriction if `None`.
+	///
+	/// Bans still apply to allowlisted peers. Note that inbound connections are only checked
+	/// against the allowlist once the handshake completes, as the peer's node id isn't known
+	/// before.
+	pub fn set_allowlist(&self, allowlist: Option<Vec<PublicKey>>) {
+		self.state.lock().unwrap().allowlist = allowlist.map(|node_ids| node_ids.into_iter().collect());
+	}
+}
+
+impl<T: Time> PeerPolicy for DefaultPeerPolicyUsingTime<T> {
+	fn allow_connection(&self, remote_network_address: Option<&NetAddress>) -> bool {
+		match remote_network_address.and_then(BanAddress::from_net_address) {
+			Some(address) => !check_ban(&mut self.state.lock().unwrap().address_bans, &address),
+			None => true,
+		}
+	}
+
+	fn allow_peer(&self, node_id: &PublicKey, remote_network_address: Option<&NetAddress>) -> bool {
+		let mut state = self.state.lock().unwrap();
+		if let Some(allowlist) = &state.allowlist {
+			if !allowlist.contains(node_id) {
+				return false;
+			}
+		}
+		if check_ban(&mut state.node_bans, node_id) {
+			return false;
+		}
+		match remote_network_address.and_then(BanAddress::from_net_address) {
+			Some(address) => !check_ban(&mut state.address_bans, &address),
+			None => true,
+		}
+	}
+
+	fn peer_misbehaved(&self, node_id: Option<&PublicKey>, remote_network_address: Option<&NetAddress>, misbehavior: PeerMisbehavior) {
+		let penalty = self.params.penalty(misbehavior);
+		let mut state = self.state.lock().unwrap();
+		if let Some(node_id) = node_id {
+			if add_penalty(&mut state.node_scores, *node_id, penalty, &self.params) {
+				state.node_scores.remove(node_id);
+				state.node_bans.insert(*node_id, Ban { banned_at: T::now(), duration: self.params.ban_duration });
+			}
+		}
+		if !self.params.ban_by_address { return; }
+		let public_address = remote_network_address.and_then(|address| filter_addresses(Some(address.clone())));
+		if let Some(address) = public_address.as_ref().and_then(BanAddress::from_net_address) {
+			if add_penalty(&mut state.address_scores, address, penalty, &self.params) {
+				state.address_scores.remove(&address);
+				state.address_bans.insert(address, Ban { banned_at: T::now(), duration: self.params.ban_duration });
+			}
+		}
+	}
+
+	fn timer_tick_occurred(&self) {
+		// Scores which decayed entirely carry no information, so drop them along with expired
+		// bans to keep our state bounded by the peers which misbehaved recently.
+		let half_life = self.params.score_half_life;
+		let mut state = self.state.lock().unwrap();
+		state.node_scores.retain(|_, score| score.decayed_score(half_life) > 0);
+		state.address_scores.retain(|_, score| score.decayed_score(half_life) > 0);
+		state.node_bans.retain(|_, ban| ban.remaining().is_some());
+		state.address_bans.retain(|_, ban| ban.remaining().is_some());
+	}
+}
+
+/// A ban as persisted, with its expiry given as the duration since the UNIX epoch.
+struct PersistedNodeBan {
+	node_id: PublicKey,
+	expiry: Duration,
+}
+
+impl_writeable_tlv_based!(PersistedNodeBan, {
+	(0, node_id, required),
+	(2, expiry, required),
+});
+
+struct PersistedAddressBan {
+	address: NetAddress,
+	expiry: Duration,
+}
+
+impl_writeable_tlv_based!(PersistedAddressBan, {
+	(0, address, required),
+	(2, expiry, required),
+});
+
+impl<T: Time> Writeable for DefaultPeerPolicyUsingTime<T> {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+		// Bans are written as wall-clock expiry times, as `T` may be a monotonic clock which is
+		// meaningless across restarts.
+		let wall_clock_now = T::duration_since_epoch();
+		let expiry = |remaining: Duration| wall_clock_now.checked_add(remaining).unwrap_or(wall_clock_now);
+		let state = self.state.lock().unwrap();
+		let node_bans: Vec<PersistedNodeBan> = state.node_bans.iter()
+			.filter_map(|(node_id, ban)| ban.remaining().map(|remaining| PersistedNodeBan { node_id: *node_id, expiry: expiry(remaining) }))
+			.collect();
+		let address_bans: Vec<PersistedAddressBan> = state.address_bans.iter()
+			.filter_map(|(address, ban)| ban.remaining().map(|remaining| PersistedAddressBan { address: address.to_net_address(), expiry: expiry(remaining) }))
+			.collect();
+		write_tlv_fields!(w, {
+			(0, node_bans, vec_type),
+			(2, address_bans, vec_type),
+		});
+		Ok(())
+	}
+}
+
+impl<T: Time> ReadableArgs<PeerPolicyParameters> for DefaultPeerPolicyUsingTime<T> {
+	fn read<R: Read>(r: &mut R, params: PeerPolicyParameters) -> Result<Self, DecodeError> {
+		let mut node_bans: Option<Vec<PersistedNodeBan>> = Some(Vec::new());
+		let mut address_bans: Option<Vec<PersistedAddressBan>> = Some(Vec::new());
+		read_tlv_fields!(r, {
+			(0, node_bans, vec_type),
+			(2, address_bans, vec_type),
+		});
+		let policy = Self::new(params);
+		{
+			// Bans which expired while we were offline are dropped, the rest are restarted now
+			// with their remaining duration.
+			let wall_clock_now = T::duration_since_epoch();
+			let mut state = policy.state.lock().unwrap();
+			for ban in node_bans.unwrap() {
+				if let Some(duration) = ban.expiry.checked_sub(wall_clock_now) {
+					state.node_bans.insert(ban.node_id, Ban { banned_at: T::now(), duration });
+				}
+			}
+			for ban in address_bans.unwrap() {
+				let address = BanAddress::from_net_address(&ban.address).ok_or(DecodeError::InvalidValue)?;
+				if let Some(duration) = ban.expiry.checked_sub(wall_clock_now) {
+					state.address_bans.insert(address, Ban { banned_at: T::now(), duration });
+				}
+			}
+		}
+		Ok(policy)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{AllowAllPeerPolicy, DefaultPeerPolicyUsingTime, MAX_TRACKED_SCORES, PeerMisbehavior, PeerPolicy, PeerPolicyParameters};
+	use ln::msgs::NetAddress;
+	use util::ser::{ReadableArgs, Writeable};
+	use util::time::tests::SinceEpoch;
+
+	use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+	use io;
+	use core::time::Duration;
+
+	type Policy = DefaultPeerPolicyUsingTime<SinceEpoch>;
+
+	fn node_id(byte: u8) -> PublicKey {
+		PublicKey::from_secret_key(&Secp256k1::signing_only(), &SecretKey::from_slice(&[byte; 32]).unwrap())
+	}
+
+	fn address(byte: u8) -> NetAddress {
+		NetAddress::IPv4 { addr: [1, 2, 3, byte], port: 9735 }
+	}
+
+	#[test]
+	fn bans_peers_reaching_the_threshold() {
+		let policy = Policy::new(PeerPolicyParameters { ban_by_address: true, ..Default::default() });
+		let (peer, addr) = (node_id(42), address(1));
+		assert!(policy.allow_connection(Some(&addr)));
+		assert!(policy.allow_peer(&peer, Some(&addr)));
+
+		policy.peer_misbehaved(Some(&peer), Some(&addr), PeerMisbehavior::InvalidMessage);
+		assert!(policy.allow_peer(&peer, Some(&addr)));
+		policy.peer_misbehaved(Some(&peer), Some(&addr), PeerMisbehavior::InvalidMessage);
+		assert!(!policy.allow_peer(&peer, Some(&addr)));
+		assert!(!policy.allow_peer(&peer, None));
+		assert!(!policy.allow_connection(Some(&addr)));
+		// Only the IP address is banned, other connections from the same host on another port
+		// are refused too but other hosts are unaffected.
+		assert!(!policy.allow_connection(Some(&NetAddress::IPv4 { addr: [1, 2, 3, 1], port: 1 })));
+		assert!(policy.allow_connection(Some(&address(2))));
+		assert!(policy.allow_peer(&node_id(43), Some(&address(2))));
+
+		SinceEpoch::advance(Duration::from_secs(60 * 60 * 24));
+		assert!(policy.allow_peer(&peer, Some(&addr)));
+		assert!(!policy.is_peer_banned(&peer));
+		assert!(!policy.is_address_banned(&addr));
+	}
+
+	#[test]
+	fn only_scores_public_addresses() {
+		// By default, and for local or private addresses, only the node id is scored.
+		for (params, addr) in [
+			(PeerPolicyParameters::default(), address(1)),
+			(PeerPolicyParameters { ban_by_address: true, ..Default::default() }, NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 }),
+			(PeerPolicyParameters { ban_by_address: true, ..Default::default() }, NetAddress::IPv4 { addr: [192, 168, 0, 1], port: 9735 }),
+		].iter() {
+			let policy = Policy::new(params.clone());
+			for _ in 0..2 {
+				policy.peer_misbehaved(None, Some(addr), PeerMisbehavior::InvalidMessage);
+				policy.peer_misbehaved(Some(&node_id(42)), Some(addr), PeerMisbehavior::InvalidMessage);
+			}
+			assert!(policy.allow_connection(Some(addr)));
+			assert!(policy.allow_peer(&node_id(43), Some(addr)));
+			assert!(!policy.allow_peer(&node_id(42), Some(addr)));
+		}
+	}
+
+	#[test]
+	fn prunes_decayed_state_on_timer_tick() {
+		let policy = Policy::new(PeerPolicyParameters { ban_by_address: true, ..Default::default() });
+		policy.peer_misbehaved(Some(&node_id(42)), Some(&address(1)), PeerMisbehavior::InvalidGossip);
+		policy.ban_peer(&node_id(43), Duration::from_secs(10));
+		policy.timer_tick_occurred();
+		{
+			let state = policy.state.lock().unwrap();
+			assert_eq!(state.node_scores.len(), 1);
+			assert_eq!(state.address_scores.len(), 1);
+			assert_eq!(state.node_bans.len(), 1);
+		}
+
+		// A score of 10 decays to nothing after four half-lives.
+		SinceEpoch::advance(Duration::from_secs(60 * 60 * 4));
+		policy.timer_tick_occurred();
+		let state = policy.state.lock().unwrap();
+		assert!(state.node_scores.is_empty());
+		assert!(state.address_scores.is_empty());
+		assert!(state.node_bans.is_empty());
+	}
+
+	#[test]
+	fn caps_tracked_scores() {
+		let policy = Policy::new(PeerPolicyParameters::default());
+		let secp_ctx = Secp256k1::signing_only();
+		let repeat_offender = node_id(42);
+		policy.peer_misbehaved(Some(&repeat_offender), None, PeerMisbehavior::InvalidMessage);
+		for i in 0..MAX_TRACKED_SCORES as u32 {
+			let mut key = [1; 32];
+			key[..4].copy_from_slice(&i.to_be_bytes());
+			let peer = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&key).unwrap());
+			policy.peer_misbehaved(Some(&peer), None, PeerMisbehavior::InvalidGossip);
+		}
+		assert_eq!(policy.state.lock().unwrap().node_scores.len(), MAX_TRACKED_SCORES);
+
+		// Lower scores were evicted to make room, but we still remember the worst offender.
+		policy.peer_misbehaved(Some(&repeat_offender), None, PeerMisbehavior::InvalidMessage);
+		assert!(!policy.allow_peer(&repeat_offender, None));
+	}
+
+	#[test]
+	fn scores_decay() {
+		let policy = Policy::new(PeerPolicyParameters::default());
+		let peer = node_id(42);
+		policy.peer_misbehaved(Some(&peer), Some(&address(1)), PeerMisbehavior::InvalidMessage);
+		SinceEpoch::advance(Duration::from_secs(60 * 60));
+		// The score decayed to 25, so another 50 doesn't reach the threshold.
+		policy.peer_misbehaved(Some(&peer), Some(&address(1)), PeerMisbehavior::InvalidMessage);
+		assert!(policy.allow_peer(&peer, None));
+		policy.peer_misbehaved(Some(&peer), Some(&address(1)), PeerMisbehavior::InvalidEncryption);
+		assert!(!policy.allow_peer(&peer, None));
+		// We don't ban by address, so the address remains allowed.
+		assert!(policy.allow_connection(Some(&address(1))));
+	}
+
+	#[test]
+	fn manual_bans() {
+		let policy = Policy::new(PeerPolicyParameters::default());
+		let peer = node_id(42);
+		policy.ban_peer(&peer, Duration::from_secs(10));
+		assert!(!policy.allow_peer(&peer, None));
+		policy.unban_peer(&peer);
+		assert!(policy.allow_peer(&peer, None));
+
+		assert!(policy.ban_address(&address(1), Duration::from_secs(10)));
+		assert!(!policy.allow_connection(Some(&address(1))));
+		assert!(!policy.allow_peer(&peer, Some(&address(1))));
+		SinceEpoch::advance(Duration::from_secs(10));
+		assert!(policy.allow_connection(Some(&address(1))));
+
+		let onion = NetAddress::OnionV3 { ed25519_pubkey: [42; 32], checksum: 0, version: 3, port: 9735 };
+		assert!(!policy.ban_address(&onion, Duration::from_secs(10)));
+		assert!(policy.allow_connection(Some(&onion)));
+	}
+
+	#[test]
+	fn allowlist() {
+		let policy = Policy::new(PeerPolicyParameters::default());
+		policy.set_allowlist(Some(vec![node_id(42)]));
+		assert!(policy.allow_peer(&node_id(42), None));
+		assert!(!policy.allow_peer(&node_id(43), None));
+		// Allowlisted peers are still subject to bans.
+		policy.ban_peer(&node_id(42), Duration::from_secs(10));
+		assert!(!policy.allow_peer(&node_id(42), None));
+		policy.set_allowlist(None);
+		assert!(policy.allow_peer(&node_id(43), None));
+	}
+
+	#[test]
+	fn persists_bans() {
+		let policy = Policy::new(PeerPolicyParameters::default());
+		policy.ban_peer(&node_id(42), Duration::from_secs(100));
+		policy.ban_peer(&node_id(43), Duration::from_secs(10));
+		policy.ban_address(&address(1), Duration::from_secs(100));
+		policy.peer_misbehaved(Some(&node_id(44)), None, PeerMisbehavior::InvalidMessage);
+		let serialized = policy.encode();
+
+		SinceEpoch::advance(Duration::from_secs(50));
+		let read_policy = Policy::read(&mut io::Cursor::new(&serialized), PeerPolicyParameters::default()).unwrap();
+		assert!(read_policy.is_peer_banned(&node_id(42)));
+		assert!(!read_policy.is_peer_banned(&node_id(43)));
+		assert!(read_policy.is_address_banned(&address(1)));
+		// Scores are not persisted.
+		read_policy.peer_misbehaved(Some(&node_id(44)), None, PeerMisbehavior::InvalidMessage);
+		assert!(read_policy.allow_peer(&node_id(44), None));
+
+		SinceEpoch::advance(Duration::from_secs(50));
+		assert!(!read_policy.is_peer_banned(&node_id(42)));
+		assert!(!read_policy.is_address_banned(&address(1)));
+
+		// An `AllowAllPeerPolicy` reads back as a policy without bans.
+		let read_policy = Policy::read(&mut io::Cursor::new(&Writeable::encode(&AllowAllPeerPolicy {})), PeerPolicyParameters::default()).unwrap();
+		assert!(read_policy.state.lock().unwrap().node_bans.is_empty());
+	}
+}
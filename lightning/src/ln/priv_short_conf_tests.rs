@@ -653,7 +653,7 @@ fn test_simple_0conf_channel() {
 #[test]
 fn test_0conf_channel_with_async_monitor() {
 	// Test that we properly send out channel_ready in (both inbound- and outbound-) zero-conf
-	// channels if ChannelMonitor updates return a `TemporaryFailure` during the initial channel
+	// channels if ChannelMonitor updates return an `InProgress` during the initial channel
 	// negotiation.
 
 	let chanmon_cfgs = create_chanmon_cfgs(3);
@@ -687,7 +687,7 @@ fn test_0conf_channel_with_async_monitor() {
 	nodes[0].node.funding_transaction_generated(&temporary_channel_id, &nodes[1].node.get_our_node_id(), tx.clone()).unwrap();
 	let funding_created = get_event_msg!(nodes[0], MessageSendEvent::SendFundingCreated, nodes[1].node.get_our_node_id());
 
-	chanmon_cfgs[1].persister.set_update_ret(Err(ChannelMonitorUpdateErr::TemporaryFailure));
+	chanmon_cfgs[1].persister.set_update_ret(Err(ChannelMonitorUpdateErr::InProgress));
 	nodes[1].node.handle_funding_created(&nodes[0].node.get_our_node_id(), &funding_created);
 	check_added_monitors!(nodes[1], 1);
 	assert!(nodes[1].node.get_and_clear_pending_events().is_empty());
@@ -697,7 +697,7 @@ fn test_0conf_channel_with_async_monitor() {
 
 	let bs_signed_locked = nodes[1].node.get_and_clear_pending_msg_events();
 	assert_eq!(bs_signed_locked.len(), 2);
-	chanmon_cfgs[0].persister.set_update_ret(Err(ChannelMonitorUpdateErr::TemporaryFailure));
+	chanmon_cfgs[0].persister.set_update_ret(Err(ChannelMonitorUpdateErr::InProgress));
 
 	match &bs_signed_locked[0] {
 		MessageSendEvent::SendFundingSigned { node_id, msg } => {
@@ -773,7 +773,7 @@ fn test_0conf_channel_with_async_monitor() {
 	nodes[0].node.handle_commitment_signed(&nodes[1].node.get_our_node_id(), &bs_commitment_signed);
 	check_added_monitors!(nodes[0], 1);
 
-	chanmon_cfgs[1].persister.set_update_ret(Err(ChannelMonitorUpdateErr::TemporaryFailure));
+	chanmon_cfgs[1].persister.set_update_ret(Err(ChannelMonitorUpdateErr::InProgress));
 	nodes[1].node.handle_revoke_and_ack(&nodes[0].node.get_our_node_id(), &get_event_msg!(nodes[0], MessageSendEvent::SendRevokeAndACK, nodes[1].node.get_our_node_id()));
 	check_added_monitors!(nodes[1], 1);
 	assert!(nodes[1].node.get_and_clear_pending_msg_events().is_empty());
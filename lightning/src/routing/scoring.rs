@@ -278,11 +278,11 @@ impl ReadableArgs<u64> for FixedPenaltyScorer {
 }
 
 #[cfg(not(feature = "no-std"))]
-type ConfiguredTime = std::time::Instant;
+pub(crate) type ConfiguredTime = std::time::Instant;
 #[cfg(feature = "no-std")]
 use util::time::Eternity;
 #[cfg(feature = "no-std")]
-type ConfiguredTime = Eternity;
+pub(crate) type ConfiguredTime = Eternity;
 
 /// [`Score`] implementation using channel success probability distributions.
 ///
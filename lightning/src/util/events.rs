@@ -1228,6 +1228,35 @@ pub trait EventsProvider {
 	fn process_pending_events<H: Deref>(&self, handler: H) where H::Target: EventHandler;
 }
 
+/// A trait indicating an object may generate events which are only marked handled once the caller
+/// reports having handled them, allowing events to be handled asynchronously.
+///
+/// With [`EventsProvider::process_pending_events`], events are marked handled before being handed
+/// to the handler, so an event whose (asynchronous) handling is still in progress may be lost if
+/// the provider is persisted in the meantime and we then crash. Instead, events returned by
+/// [`get_pending_events`] remain pending, and are thus persisted with the provider, until
+/// [`events_handled`] is called.
+///
+/// Events should only be processed via one of [`EventsProvider`] or this trait, and from a single
+/// task at a time.
+///
+/// (C-not implementable) As there is likely no reason for a user to implement this trait on their
+/// own type(s).
+///
+/// [`get_pending_events`]: Self::get_pending_events
+/// [`events_handled`]: Self::events_handled
+pub trait AsyncEventsProvider {
+	/// Gets all pending events, in order, without marking any of them handled.
+	///
+	/// Events not yet marked handled via [`Self::events_handled`] are returned again by subsequent
+	/// calls.
+	fn get_pending_events(&self) -> Vec<Event>;
+
+	/// Marks the first `handled_count` events returned by the last call to
+	/// [`Self::get_pending_events`] which have not been marked handled yet as handled.
+	fn events_handled(&self, handled_count: usize);
+}
+
 /// A trait implemented for objects handling events from [`EventsProvider`].
 pub trait EventHandler {
 	/// Handles the given [`Event`].
@@ -4,25 +4,306 @@
 // You may not use this file except in accordance with one or both of these
 // licenses.
 
-//! This module contains a simple key-value store trait KVStorePersister that
-//! allows one to implement the persistence for [`ChannelManager`], [`NetworkGraph`],
-//! and [`ChannelMonitor`] all in one place.
+//! This module contains a simple key-value store trait [`KVStore`] that allows one to implement
+//! the persistence for [`ChannelManager`], [`NetworkGraph`], and [`ChannelMonitor`] all in one
+//! place, as well as helpers to read each of them back from any such store. [`EncryptedKVStore`]
+//! wraps any such store to encrypt the persisted data at rest.
 
 use core::ops::Deref;
-use bitcoin::hashes::hex::ToHex;
+use bitcoin::hash_types::{BlockHash, Txid};
+use bitcoin::hashes::hex::{FromHex, ToHex};
 use io::{self};
 use routing::scoring::WriteableScore;
+use ln::peer_policy::{DefaultPeerPolicy, PeerPolicyParameters, WriteablePeerPolicy};
 
-use crate::{chain::{keysinterface::{Sign, KeysInterface}, self, transaction::{OutPoint}, chaininterface::{BroadcasterInterface, FeeEstimator}, chainmonitor::{Persist, MonitorUpdateId}, channelmonitor::{ChannelMonitor, ChannelMonitorUpdate}}, ln::channelmanager::ChannelManager, routing::gossip::NetworkGraph};
-use super::{logger::Logger, ser::Writeable};
+use crate::{chain::{keysinterface::{Sign, KeysInterface}, self, transaction::{OutPoint}, chaininterface::{BroadcasterInterface, FeeEstimator}, chainmonitor::{ChainMonitor, Persist, MonitorUpdateId}, channelmonitor::{ChannelMonitor, ChannelMonitorUpdate, CLOSED_CHANNEL_UPDATE_ID}}, ln::channelmanager::{ChannelManager, ChannelManagerReadArgs}, routing::gossip::NetworkGraph};
+use super::{chacha20poly1305rfc::ChaCha20Poly1305RFC, crypto::hkdf_extract_expand_twice, logger::Logger, ser::{Readable, ReadableArgs, Writeable}};
+
+use prelude::*;
+use sync::Mutex;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// The namespace under which [`ChannelMonitor`]s are persisted, each keyed by its funding outpoint
+/// as `{funding_txo_id}_{funding_txo_index}`.
+pub const CHANNEL_MONITOR_PERSISTENCE_NAMESPACE: &str = "monitors";
+
+/// The namespace under which a [`MonitorUpdatingPersister`] persists [`ChannelMonitorUpdate`]s,
+/// each nested in a namespace named after the key of its [`ChannelMonitor`] and keyed by its
+/// update id.
+pub const CHANNEL_MONITOR_UPDATE_PERSISTENCE_NAMESPACE: &str = "monitor_updates";
+
+/// The namespace under which fully resolved [`ChannelMonitor`]s are archived, keyed like in
+/// [`CHANNEL_MONITOR_PERSISTENCE_NAMESPACE`], see [`Persist::archive_persisted_channel`].
+pub const ARCHIVED_CHANNEL_MONITOR_PERSISTENCE_NAMESPACE: &str = "archived_monitors";
+
+/// The key under which the [`ChannelManager`] is persisted, in the empty namespace.
+pub const CHANNEL_MANAGER_PERSISTENCE_KEY: &str = "manager";
+
+/// The key under which the [`NetworkGraph`] is persisted, in the empty namespace.
+pub const NETWORK_GRAPH_PERSISTENCE_KEY: &str = "network_graph";
+
+/// The key under which the [`WriteableScore`] is persisted, in the empty namespace.
+pub const SCORER_PERSISTENCE_KEY: &str = "scorer";
+
+/// The key under which the bans of a [`DefaultPeerPolicy`] are persisted, in the empty namespace.
+///
+/// [`DefaultPeerPolicy`]: crate::ln::peer_policy::DefaultPeerPolicy
+pub const PEER_POLICY_PERSISTENCE_KEY: &str = "peer_policy";
+
+/// Trait for a key-value store in which values are grouped into namespaces.
+///
+/// Namespaces and keys should consist only of ASCII alphanumeric characters, `-`, and `_`, so that
+/// backends may map them directly onto file names, table names, or similar. Namespaces may also be
+/// nested by separating their components with `/`. The empty namespace is valid, but keys must not
+/// be empty.
+///
+/// Implementing `KVStore` provides an auto-implementation for [`KVStorePersister`], and therefore
+/// for the [`Persister`] and [`Persist`] traits. Use [`read_channel_manager`],
+/// [`read_network_graph`], [`read_scorer`], and [`read_channel_monitors`] to read back the objects
+/// persisted through them.
+pub trait KVStore {
+	/// Returns the data stored for `key` in the given `namespace`.
+	///
+	/// Returns an error of kind [`io::ErrorKind::NotFound`] if no data is stored for `key`.
+	fn read(&self, namespace: &str, key: &str) -> io::Result<Vec<u8>>;
+
+	/// Stores `buf` for `key` in the given `namespace`, replacing any previously stored data.
+	///
+	/// Must only return once the data has been durably persisted, such that a subsequent
+	/// [`read`] returns either the old or the new data in its entirety even if interrupted.
+	///
+	/// [`read`]: Self::read
+	fn write(&self, namespace: &str, key: &str, buf: &[u8]) -> io::Result<()>;
+
+	/// Removes any data stored for `key` in the given `namespace`.
+	///
+	/// Removing a key for which no data is stored is not an error.
+	fn remove(&self, namespace: &str, key: &str) -> io::Result<()>;
+
+	/// Returns the keys for which data is stored in the given `namespace`, in no particular order.
+	fn list(&self, namespace: &str) -> io::Result<Vec<String>>;
+}
 
 /// Trait for a key-value store for persisting some writeable object at some key
 /// Implementing `KVStorePersister` provides auto-implementations for [`Persister`]
 /// and [`Persist`] traits.  It uses "manager", "network_graph",
 /// and "monitors/{funding_txo_id}_{funding_txo_index}" for keys.
+///
+/// Any [`KVStore`] implements this trait, treating everything up to the last `/` in a key as the
+/// namespace.
 pub trait KVStorePersister {
 	/// Persist the given writeable using the provided key
 	fn persist<W: Writeable>(&self, key: &str, object: &W) -> io::Result<()>;
+
+	/// Removes whatever was persisted using the provided key, used when archiving a
+	/// [`ChannelMonitor`] via [`Persist::archive_persisted_channel`].
+	///
+	/// The default implementation returns an error as removal is unsupported, in which case
+	/// archiving fails and fully resolved monitors continue to be watched and persisted.
+	fn remove_persisted(&self, _key: &str) -> io::Result<()> {
+		Err(io::Error::new(io::ErrorKind::Other, "Removing persisted data is unsupported"))
+	}
+}
+
+/// Splits a [`KVStorePersister`] key into a [`KVStore`] namespace and key.
+fn split_persister_key(key: &str) -> (&str, &str) {
+	match key.rfind('/') {
+		Some(index) => (&key[..index], &key[index + 1..]),
+		None => ("", key),
+	}
+}
+
+impl<K: KVStore> KVStorePersister for K {
+	fn persist<W: Writeable>(&self, key: &str, object: &W) -> io::Result<()> {
+		let (namespace, key) = split_persister_key(key);
+		self.write(namespace, key, &object.encode())
+	}
+
+	fn remove_persisted(&self, key: &str) -> io::Result<()> {
+		let (namespace, key) = split_persister_key(key);
+		self.remove(namespace, key)
+	}
+}
+
+/// Reads the data stored for `key` in the given `namespace`, if any.
+fn read_if_present<K: Deref>(kv_store: &K, namespace: &str, key: &str) -> io::Result<Option<Vec<u8>>>
+where K::Target: KVStore {
+	match kv_store.read(namespace, key) {
+		Ok(buf) => Ok(Some(buf)),
+		Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+		Err(e) => Err(e),
+	}
+}
+
+/// Reads the [`ChannelManager`] persisted under [`CHANNEL_MANAGER_PERSISTENCE_KEY`], returning
+/// `None` if none has been persisted yet.
+///
+/// See [`ChannelManagerReadArgs`] for the requirements on `read_args`. In particular, all
+/// [`ChannelMonitor`]s, e.g., as returned by [`read_channel_monitors`], must be provided.
+pub fn read_channel_manager<'a, KV: Deref, Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>(
+	kv_store: KV, read_args: ChannelManagerReadArgs<'a, Signer, M, T, K, F, L>
+) -> io::Result<Option<(BlockHash, ChannelManager<Signer, M, T, K, F, L>)>>
+where
+	KV::Target: KVStore,
+	M::Target: chain::Watch<Signer>,
+	T::Target: BroadcasterInterface,
+	K::Target: KeysInterface<Signer = Signer>,
+	F::Target: FeeEstimator,
+	L::Target: Logger,
+{
+	match read_if_present(&kv_store, "", CHANNEL_MANAGER_PERSISTENCE_KEY)? {
+		None => Ok(None),
+		Some(buf) => <(BlockHash, ChannelManager<Signer, M, T, K, F, L>)>::read(&mut io::Cursor::new(buf), read_args)
+			.map(Some)
+			.map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Failed to deserialize ChannelManager")),
+	}
+}
+
+/// Reads the [`NetworkGraph`] persisted under [`NETWORK_GRAPH_PERSISTENCE_KEY`], returning `None`
+/// if none has been persisted yet.
+pub fn read_network_graph<KV: Deref, L: Deref>(kv_store: KV, logger: L) -> io::Result<Option<NetworkGraph<L>>>
+where
+	KV::Target: KVStore,
+	L::Target: Logger,
+{
+	match read_if_present(&kv_store, "", NETWORK_GRAPH_PERSISTENCE_KEY)? {
+		None => Ok(None),
+		Some(buf) => NetworkGraph::read(&mut io::Cursor::new(buf), logger)
+			.map(Some)
+			.map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Failed to deserialize NetworkGraph")),
+	}
+}
+
+/// Reads the scorer persisted under [`SCORER_PERSISTENCE_KEY`], returning `None` if none has been
+/// persisted yet.
+///
+/// The scorer type is given by `S`, e.g., [`ProbabilisticScorer`] with `args` being its parameters,
+/// the [`NetworkGraph`], and a logger.
+///
+/// [`ProbabilisticScorer`]: crate::routing::scoring::ProbabilisticScorer
+pub fn read_scorer<KV: Deref, S: ReadableArgs<A>, A>(kv_store: KV, args: A) -> io::Result<Option<S>>
+where KV::Target: KVStore {
+	match read_if_present(&kv_store, "", SCORER_PERSISTENCE_KEY)? {
+		None => Ok(None),
+		Some(buf) => S::read(&mut io::Cursor::new(buf), args)
+			.map(Some)
+			.map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Failed to deserialize scorer")),
+	}
+}
+
+/// Reads the [`DefaultPeerPolicy`] persisted under [`PEER_POLICY_PERSISTENCE_KEY`], returning
+/// `None` if none has been persisted yet.
+///
+/// Only bans are persisted, the policy is otherwise configured by `params`.
+pub fn read_peer_policy<KV: Deref>(kv_store: KV, params: PeerPolicyParameters) -> io::Result<Option<DefaultPeerPolicy>>
+where KV::Target: KVStore {
+	match read_if_present(&kv_store, "", PEER_POLICY_PERSISTENCE_KEY)? {
+		None => Ok(None),
+		Some(buf) => DefaultPeerPolicy::read(&mut io::Cursor::new(buf), params)
+			.map(Some)
+			.map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Failed to deserialize peer policy")),
+	}
+}
+
+/// Returns the key under which the [`ChannelMonitor`] for the given funding outpoint is persisted.
+fn channel_monitor_key(funding_txo: &OutPoint) -> String {
+	format!("{}_{}", funding_txo.txid.to_hex(), funding_txo.index)
+}
+
+/// Writes `monitor` under [`ARCHIVED_CHANNEL_MONITOR_PERSISTENCE_NAMESPACE`] and then removes it,
+/// along with any [`ChannelMonitorUpdate`]s persisted by a [`MonitorUpdatingPersister`], from the
+/// namespaces read on startup.
+fn archive_channel_monitor<K: Deref, ChannelSigner: Sign>(kv_store: &K, funding_txo: &OutPoint, monitor: &ChannelMonitor<ChannelSigner>) -> io::Result<()>
+where K::Target: KVStore {
+	let monitor_key = channel_monitor_key(funding_txo);
+	kv_store.write(ARCHIVED_CHANNEL_MONITOR_PERSISTENCE_NAMESPACE, &monitor_key, &monitor.encode())?;
+	kv_store.remove(CHANNEL_MONITOR_PERSISTENCE_NAMESPACE, &monitor_key)?;
+
+	let namespace = format!("{}/{}", CHANNEL_MONITOR_UPDATE_PERSISTENCE_NAMESPACE, monitor_key);
+	for key in kv_store.list(&namespace)? {
+		kv_store.remove(&namespace, &key)?;
+	}
+	Ok(())
+}
+
+/// Reads the [`ChannelMonitor`] persisted under `key`, checking that `key` is its funding outpoint.
+fn read_channel_monitor<KV: Deref, Signer: Sign, K: Deref>(
+	kv_store: &KV, key: &str, keys_manager: &K
+) -> io::Result<(BlockHash, ChannelMonitor<Signer>)>
+where
+	KV::Target: KVStore,
+	K::Target: KeysInterface<Signer = Signer> + Sized,
+{
+	if !key.is_ascii() || key.len() < 66 || key.as_bytes()[64] != b'_' {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid ChannelMonitor key"));
+	}
+	let txid = Txid::from_hex(&key[..64])
+		.map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid tx ID in key"))?;
+	let index: u16 = key[65..].parse()
+		.map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid tx index in key"))?;
+
+	let buf = kv_store.read(CHANNEL_MONITOR_PERSISTENCE_NAMESPACE, key)?;
+	match <(BlockHash, ChannelMonitor<Signer>)>::read(&mut io::Cursor::new(buf), &**keys_manager) {
+		Ok((blockhash, channel_monitor)) => {
+			if channel_monitor.get_funding_txo().0 != (OutPoint { txid, index }) {
+				return Err(io::Error::new(io::ErrorKind::InvalidData, "ChannelMonitor was stored under the wrong key"));
+			}
+			Ok((blockhash, channel_monitor))
+		},
+		Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "Failed to deserialize ChannelMonitor")),
+	}
+}
+
+/// Reads the updates persisted by a [`MonitorUpdatingPersister`] for the monitor under
+/// `monitor_key` which are newer than `latest_update_id`, in order.
+fn read_pending_updates<K: Deref>(kv_store: &K, monitor_key: &str, latest_update_id: u64) -> io::Result<Vec<ChannelMonitorUpdate>>
+where K::Target: KVStore {
+	let namespace = format!("{}/{}", CHANNEL_MONITOR_UPDATE_PERSISTENCE_NAMESPACE, monitor_key);
+	let mut updates = Vec::new();
+	for key in kv_store.list(&namespace)? {
+		let update_id: u64 = key.parse()
+			.map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid ChannelMonitorUpdate key"))?;
+		if update_id <= latest_update_id {
+			// Already included in the full monitor, but not yet removed.
+			continue;
+		}
+		let buf = kv_store.read(&namespace, &key)?;
+		let update = ChannelMonitorUpdate::read(&mut io::Cursor::new(buf))
+			.map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Failed to deserialize ChannelMonitorUpdate"))?;
+		if update.update_id != update_id {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, "ChannelMonitorUpdate was stored under the wrong key"));
+		}
+		updates.push(update);
+	}
+	updates.sort_unstable_by_key(|update| update.update_id);
+	Ok(updates)
+}
+
+/// Reads all [`ChannelMonitor`]s persisted under [`CHANNEL_MONITOR_PERSISTENCE_NAMESPACE`].
+///
+/// Fails if any key is not a funding outpoint or does not match the funding outpoint of the
+/// monitor stored under it.
+///
+/// Monitors persisted via a [`MonitorUpdatingPersister`] must instead be read using
+/// [`MonitorUpdatingPersister::read_channel_monitors`], which also applies any pending updates.
+/// As a monitor returned without its pending updates would be stale, this fails if any are found.
+pub fn read_channel_monitors<KV: Deref, Signer: Sign, K: Deref>(
+	kv_store: KV, keys_manager: K
+) -> io::Result<Vec<(BlockHash, ChannelMonitor<Signer>)>>
+where
+	KV::Target: KVStore,
+	K::Target: KeysInterface<Signer = Signer> + Sized,
+{
+	let mut res = Vec::new();
+	for key in kv_store.list(CHANNEL_MONITOR_PERSISTENCE_NAMESPACE)? {
+		let (blockhash, channel_monitor) = read_channel_monitor(&kv_store, &key, &keys_manager)?;
+		if !read_pending_updates(&kv_store, &key, channel_monitor.get_latest_update_id())?.is_empty() {
+			return Err(io::Error::new(io::ErrorKind::InvalidData,
+				"ChannelMonitor has pending updates, read it via MonitorUpdatingPersister::read_channel_monitors"));
+		}
+		res.push((blockhash, channel_monitor));
+	}
+	Ok(res)
 }
 
 /// Trait that handles persisting a [`ChannelManager`], [`NetworkGraph`], and [`WriteableScore`] to disk.
@@ -42,6 +323,11 @@ pub trait Persister<'a, Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L:
 
 	/// Persist the given [`WriteableScore`] to disk, returning an error if persistence failed.
 	fn persist_scorer(&self, scorer: &S) -> Result<(), io::Error>;
+
+	/// Persist the given [`WriteablePeerPolicy`] to disk, returning an error if persistence failed.
+	///
+	/// Does nothing by default, in which case any bans are lost on restart.
+	fn persist_peer_policy<P: WriteablePeerPolicy>(&self, _peer_policy: &P) -> Result<(), io::Error> { Ok(()) }
 }
 
 impl<'a, A: KVStorePersister, Signer: Sign, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref, S> Persister<'a, Signer, M, T, K, F, L, S> for A
@@ -54,17 +340,22 @@ impl<'a, A: KVStorePersister, Signer: Sign, M: Deref, T: Deref, K: Deref, F: Der
 {
 	/// Persist the given ['ChannelManager'] to disk with the name "manager", returning an error if persistence failed.
 	fn persist_manager(&self, channel_manager: &ChannelManager<Signer, M, T, K, F, L>) -> Result<(), io::Error> {
-		self.persist("manager", channel_manager)
+		self.persist(CHANNEL_MANAGER_PERSISTENCE_KEY, channel_manager)
 	}
 
 	/// Persist the given [`NetworkGraph`] to disk with the name "network_graph", returning an error if persistence failed.
 	fn persist_graph(&self, network_graph: &NetworkGraph<L>) -> Result<(), io::Error> {
-		self.persist("network_graph", network_graph)
+		self.persist(NETWORK_GRAPH_PERSISTENCE_KEY, network_graph)
 	}
 
 	/// Persist the given [`WriteableScore`] to disk with name "scorer", returning an error if persistence failed.
 	fn persist_scorer(&self, scorer: &S) -> Result<(), io::Error> {
-		self.persist("scorer", &scorer)
+		self.persist(SCORER_PERSISTENCE_KEY, &scorer)
+	}
+
+	/// Persist the given [`WriteablePeerPolicy`] to disk with name "peer_policy", returning an error if persistence failed.
+	fn persist_peer_policy<P: WriteablePeerPolicy>(&self, peer_policy: &P) -> Result<(), io::Error> {
+		self.persist(PEER_POLICY_PERSISTENCE_KEY, peer_policy)
 	}
 }
 
@@ -75,14 +366,1146 @@ impl<ChannelSigner: Sign, K: KVStorePersister> Persist<ChannelSigner> for K {
 	// even broadcasting!
 
 	fn persist_new_channel(&self, funding_txo: OutPoint, monitor: &ChannelMonitor<ChannelSigner>, _update_id: MonitorUpdateId) -> Result<(), chain::ChannelMonitorUpdateErr> {
-		let key = format!("monitors/{}_{}", funding_txo.txid.to_hex(), funding_txo.index);
+		let key = format!("{}/{}", CHANNEL_MONITOR_PERSISTENCE_NAMESPACE, channel_monitor_key(&funding_txo));
 		self.persist(&key, monitor)
 			.map_err(|_| chain::ChannelMonitorUpdateErr::PermanentFailure)
 	}
 
 	fn update_persisted_channel(&self, funding_txo: OutPoint, _update: &Option<ChannelMonitorUpdate>, monitor: &ChannelMonitor<ChannelSigner>, _update_id: MonitorUpdateId) -> Result<(), chain::ChannelMonitorUpdateErr> {
-		let key = format!("monitors/{}_{}", funding_txo.txid.to_hex(), funding_txo.index);
+		let key = format!("{}/{}", CHANNEL_MONITOR_PERSISTENCE_NAMESPACE, channel_monitor_key(&funding_txo));
 		self.persist(&key, monitor)
 			.map_err(|_| chain::ChannelMonitorUpdateErr::PermanentFailure)
 	}
+
+	fn archive_persisted_channel(&self, funding_txo: OutPoint, monitor: &ChannelMonitor<ChannelSigner>) -> Result<(), ()> {
+		let monitor_key = channel_monitor_key(&funding_txo);
+		self.persist(&format!("{}/{}", ARCHIVED_CHANNEL_MONITOR_PERSISTENCE_NAMESPACE, monitor_key), monitor)
+			.and_then(|_| self.remove_persisted(&format!("{}/{}", CHANNEL_MONITOR_PERSISTENCE_NAMESPACE, monitor_key)))
+			.map_err(|_| ())
+	}
+}
+
+/// A [`Persist`] implementation which persists [`ChannelMonitorUpdate`]s individually rather than
+/// rewriting the entire [`ChannelMonitor`] on every update.
+///
+/// Each update is written under [`CHANNEL_MONITOR_UPDATE_PERSISTENCE_NAMESPACE`] until
+/// `maximum_pending_updates` updates have accumulated, at which point the full monitor is written
+/// under [`CHANNEL_MONITOR_PERSISTENCE_NAMESPACE`] and the updates it includes are removed.
+///
+/// Changes due to chain data are not captured by any update, so the full monitor is written when
+/// chain data generated new events, which must not be lost, and otherwise only once every
+/// [`CHAIN_SYNC_FULL_MONITOR_WRITE_INTERVAL`] blocks. Monitors read on startup may thus be up to
+/// that many blocks behind, which is harmless as they are re-synced with the chain from their best
+/// block anyway.
+///
+/// Use [`read_channel_monitors`] on startup to read each monitor and replay its pending updates.
+///
+/// [`read_channel_monitors`]: Self::read_channel_monitors
+pub struct MonitorUpdatingPersister<K: Deref> where K::Target: KVStore {
+	kv_store: K,
+	maximum_pending_updates: u64,
+	/// The best block height of each monitor as of its last full write.
+	full_write_heights: Mutex<HashMap<OutPoint, u32>>,
+}
+
+/// The number of blocks after which a [`MonitorUpdatingPersister`] writes a full
+/// [`ChannelMonitor`] when syncing it with the chain, absent any new events.
+pub const CHAIN_SYNC_FULL_MONITOR_WRITE_INTERVAL: u32 = 50;
+
+impl<K: Deref> MonitorUpdatingPersister<K> where K::Target: KVStore {
+	/// Creates a persister writing to `kv_store`, which consolidates updates into the full monitor
+	/// every `maximum_pending_updates` updates. A value of zero or one writes the full monitor on
+	/// every update.
+	pub fn new(kv_store: K, maximum_pending_updates: u64) -> Self {
+		Self { kv_store, maximum_pending_updates, full_write_heights: Mutex::new(HashMap::new()) }
+	}
+
+	/// Reads all [`ChannelMonitor`]s, applying any updates persisted since each was last written in
+	/// full.
+	///
+	/// The monitors should be given to [`chain::Watch::watch_channel`] as usual, which consolidates
+	/// any replayed updates into the full monitor.
+	///
+	/// Fails if any monitor or update cannot be read, or if the persisted updates for a monitor are
+	/// not contiguous.
+	pub fn read_channel_monitors<Signer: Sign, KI: Deref, B: Deref, F: Deref, L: Deref>(
+		&self, keys_manager: KI, broadcaster: B, fee_estimator: F, logger: L
+	) -> io::Result<Vec<(BlockHash, ChannelMonitor<Signer>)>>
+	where
+		KI::Target: KeysInterface<Signer = Signer> + Sized,
+		B::Target: BroadcasterInterface,
+		F::Target: FeeEstimator,
+		L::Target: Logger,
+	{
+		let mut res = Vec::new();
+		for key in self.kv_store.list(CHANNEL_MONITOR_PERSISTENCE_NAMESPACE)? {
+			let (blockhash, channel_monitor) = read_channel_monitor(&self.kv_store, &key, &keys_manager)?;
+			for update in read_pending_updates(&self.kv_store, &key, channel_monitor.get_latest_update_id())? {
+				if update.update_id != channel_monitor.get_latest_update_id() + 1 {
+					return Err(io::Error::new(io::ErrorKind::InvalidData, "Missing ChannelMonitorUpdate"));
+				}
+				channel_monitor.update_monitor(&update, &broadcaster, &*fee_estimator, &logger)
+					.map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Failed to apply ChannelMonitorUpdate"))?;
+			}
+			res.push((blockhash, channel_monitor));
+		}
+		Ok(res)
+	}
+
+	/// Returns whether a chain sync of `monitor` should write it in full.
+	fn should_persist_chain_sync<ChannelSigner: Sign>(&self, funding_txo: &OutPoint, monitor: &ChannelMonitor<ChannelSigner>) -> bool {
+		if monitor.has_pending_events() {
+			return true;
+		}
+		let height = monitor.current_best_block().height();
+		match self.full_write_heights.lock().unwrap().get(funding_txo) {
+			// Also write on reorgs below the last written height, for simplicity.
+			Some(&written_height) =>
+				height < written_height || height >= written_height + CHAIN_SYNC_FULL_MONITOR_WRITE_INTERVAL,
+			None => true,
+		}
+	}
+
+	/// Writes the full monitor and removes any updates it includes.
+	fn persist_full_monitor<ChannelSigner: Sign>(&self, funding_txo: &OutPoint, monitor: &ChannelMonitor<ChannelSigner>) -> io::Result<()> {
+		let monitor_key = channel_monitor_key(funding_txo);
+		self.kv_store.write(CHANNEL_MONITOR_PERSISTENCE_NAMESPACE, &monitor_key, &monitor.encode())?;
+		self.full_write_heights.lock().unwrap().insert(*funding_txo, monitor.current_best_block().height());
+
+		// Updates are only ever read back if newer than the full monitor, so failing to remove any
+		// here is harmless. They will be removed once the monitor is next written in full.
+		let namespace = format!("{}/{}", CHANNEL_MONITOR_UPDATE_PERSISTENCE_NAMESPACE, monitor_key);
+		let latest_update_id = monitor.get_latest_update_id();
+		if let Ok(keys) = self.kv_store.list(&namespace) {
+			for key in keys {
+				match key.parse::<u64>() {
+					Ok(update_id) if update_id <= latest_update_id => {
+						let _ = self.kv_store.remove(&namespace, &key);
+					},
+					_ => {},
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+impl<ChannelSigner: Sign, K: Deref> Persist<ChannelSigner> for MonitorUpdatingPersister<K> where K::Target: KVStore {
+	fn persist_new_channel(&self, funding_txo: OutPoint, monitor: &ChannelMonitor<ChannelSigner>, _update_id: MonitorUpdateId) -> Result<(), chain::ChannelMonitorUpdateErr> {
+		self.persist_full_monitor(&funding_txo, monitor)
+			.map_err(|_| chain::ChannelMonitorUpdateErr::PermanentFailure)
+	}
+
+	fn update_persisted_channel(&self, funding_txo: OutPoint, update: &Option<ChannelMonitorUpdate>, monitor: &ChannelMonitor<ChannelSigner>, _update_id: MonitorUpdateId) -> Result<(), chain::ChannelMonitorUpdateErr> {
+		let result = match update {
+			// Updates after the channel has closed all share the same update id, so they cannot be
+			// stored individually.
+			Some(update) if update.update_id != CLOSED_CHANNEL_UPDATE_ID && update.update_id % self.maximum_pending_updates.max(1) != 0 => {
+				let namespace = format!("{}/{}", CHANNEL_MONITOR_UPDATE_PERSISTENCE_NAMESPACE, channel_monitor_key(&funding_txo));
+				self.kv_store.write(&namespace, &update.update_id.to_string(), &update.encode())
+			},
+			None if !self.should_persist_chain_sync(&funding_txo, monitor) => Ok(()),
+			_ => self.persist_full_monitor(&funding_txo, monitor),
+		};
+		result.map_err(|_| chain::ChannelMonitorUpdateErr::PermanentFailure)
+	}
+
+	fn archive_persisted_channel(&self, funding_txo: OutPoint, monitor: &ChannelMonitor<ChannelSigner>) -> Result<(), ()> {
+		archive_channel_monitor(&self.kv_store, &funding_txo, monitor).map_err(|_| ())?;
+		self.full_write_heights.lock().unwrap().remove(&funding_txo);
+		Ok(())
+	}
+}
+
+/// A channel's latest [`ChannelMonitor`] awaiting replication by a [`ReplicatingPersister`].
+struct PendingReplication {
+	funding_txo: OutPoint,
+	/// The encoded monitor, or `None` if too many channels were queued to hold it in memory, in
+	/// which case it is read back from the local store when replicated.
+	monitor: Option<Vec<u8>>,
+	/// The updates which complete once `monitor` has been replicated.
+	update_ids: Vec<MonitorUpdateId>,
+}
+
+/// A [`Persist`] implementation which writes each [`ChannelMonitor`] to a local [`KVStore`] before
+/// returning and replicates it to a remote [`KVStore`] in the background.
+///
+/// Each update is written in full to `local_store` under [`CHANNEL_MONITOR_PERSISTENCE_NAMESPACE`]
+/// and queued for replication, returning [`ChannelMonitorUpdateErr::InProgress`]. The channel is
+/// thus frozen, i.e., no HTLCs are released, until the monitor has been replicated. Any transport
+/// to the remote store, e.g., an HTTP client, may be used by implementing [`KVStore`] for it.
+///
+/// Replication happens in [`replicate_pending`], which should be called regularly, e.g., from a
+/// background thread. Each channel has at most one entry in the queue, holding its latest monitor.
+/// At most `max_queued_channels` monitors are held in memory at once. Beyond that, channels are
+/// still queued, but their monitor is read back from `local_store` when replicated, so a backlog
+/// of replications only ever keeps channels frozen rather than failing them.
+///
+/// On startup, monitors should be read from `local_store` via [`read_channel_monitors`].
+///
+/// [`replicate_pending`]: Self::replicate_pending
+/// [`ChannelMonitorUpdateErr::InProgress`]: chain::ChannelMonitorUpdateErr::InProgress
+pub struct ReplicatingPersister<L: Deref, R: Deref> where L::Target: KVStore, R::Target: KVStore {
+	local_store: L,
+	remote_store: R,
+	max_queued_channels: usize,
+	max_attempts: usize,
+	queue: Mutex<VecDeque<PendingReplication>>,
+	/// Held while replicating so that the same monitor isn't written concurrently.
+	replication_lock: Mutex<()>,
+}
+
+impl<L: Deref, R: Deref> ReplicatingPersister<L, R> where L::Target: KVStore, R::Target: KVStore {
+	/// Creates a persister writing to `local_store` and replicating to `remote_store`, holding at
+	/// most `max_queued_channels` queued monitors in memory and attempting each write to
+	/// `remote_store` up to `max_attempts` times per call to [`replicate_pending`].
+	///
+	/// [`replicate_pending`]: Self::replicate_pending
+	pub fn new(local_store: L, remote_store: R, max_queued_channels: usize, max_attempts: usize) -> Self {
+		Self {
+			local_store, remote_store, max_queued_channels, max_attempts,
+			queue: Mutex::new(VecDeque::new()),
+			replication_lock: Mutex::new(()),
+		}
+	}
+
+	/// Returns the number of channels whose latest [`ChannelMonitor`] is awaiting replication.
+	pub fn pending_replication_count(&self) -> usize {
+		self.queue.lock().unwrap().len()
+	}
+
+	/// Replicates each queued [`ChannelMonitor`] to the remote store, notifying `chain_monitor` of
+	/// the completed updates via [`ChainMonitor::channel_monitor_updated`].
+	///
+	/// Monitors which still fail to be written after the configured number of attempts remain
+	/// queued until the next call. Returns the number of channels still awaiting replication.
+	pub fn replicate_pending<ChannelSigner: Sign, C: Deref, T: Deref, F: Deref, Lg: Deref, P: Deref>(
+		&self, chain_monitor: &ChainMonitor<ChannelSigner, C, T, F, Lg, P>
+	) -> usize
+	where
+		C::Target: chain::Filter,
+		T::Target: BroadcasterInterface,
+		F::Target: FeeEstimator,
+		Lg::Target: Logger,
+		P::Target: Persist<ChannelSigner>,
+	{
+		let _replication_lock = self.replication_lock.lock().unwrap();
+		let queued_count = self.queue.lock().unwrap().len();
+		for _ in 0..queued_count {
+			let mut pending = match self.queue.lock().unwrap().pop_front() {
+				Some(pending) => pending,
+				None => break,
+			};
+
+			let monitor_key = channel_monitor_key(&pending.funding_txo);
+			let encoded_monitor = match pending.monitor {
+				Some(ref encoded_monitor) => Ok(encoded_monitor.clone()),
+				None => self.local_store.read(CHANNEL_MONITOR_PERSISTENCE_NAMESPACE, &monitor_key),
+			};
+			let replicated = encoded_monitor.map(|encoded_monitor| (0..self.max_attempts.max(1)).any(|_| {
+				self.remote_store.write(CHANNEL_MONITOR_PERSISTENCE_NAMESPACE, &monitor_key, &encoded_monitor).is_ok()
+			})).unwrap_or(false);
+			if replicated {
+				for update_id in pending.update_ids {
+					// The only possible error is for a monitor which is no longer registered, which
+					// can be ignored.
+					let _ = chain_monitor.channel_monitor_updated(pending.funding_txo, update_id);
+				}
+				continue;
+			}
+
+			// Requeue the monitor for the next call, unless it was superseded in the meantime, in
+			// which case its updates complete once the newer monitor is replicated.
+			let mut queue = self.queue.lock().unwrap();
+			match queue.iter_mut().find(|queued| queued.funding_txo == pending.funding_txo) {
+				Some(queued) => {
+					pending.update_ids.append(&mut queued.update_ids);
+					queued.update_ids = pending.update_ids;
+				},
+				None => queue.push_back(pending),
+			}
+		}
+		self.queue.lock().unwrap().len()
+	}
+
+	/// Writes the monitor to the local store and queues it for replication.
+	fn persist_monitor<ChannelSigner: Sign>(&self, funding_txo: OutPoint, monitor: &ChannelMonitor<ChannelSigner>, update_id: MonitorUpdateId) -> Result<(), chain::ChannelMonitorUpdateErr> {
+		let encoded_monitor = monitor.encode();
+		let mut queue = self.queue.lock().unwrap();
+		self.local_store.write(CHANNEL_MONITOR_PERSISTENCE_NAMESPACE, &channel_monitor_key(&funding_txo), &encoded_monitor)
+			.map_err(|_| chain::ChannelMonitorUpdateErr::PermanentFailure)?;
+
+		match queue.iter().position(|queued| queued.funding_txo == funding_txo) {
+			Some(index) => {
+				let queued = &mut queue[index];
+				if queued.monitor.is_some() {
+					queued.monitor = Some(encoded_monitor);
+				}
+				queued.update_ids.push(update_id);
+			},
+			None => {
+				let monitor = if queue.len() < self.max_queued_channels { Some(encoded_monitor) } else { None };
+				queue.push_back(PendingReplication { funding_txo, monitor, update_ids: vec![update_id] });
+			},
+		}
+		Err(chain::ChannelMonitorUpdateErr::InProgress)
+	}
+}
+
+impl<ChannelSigner: Sign, L: Deref, R: Deref> Persist<ChannelSigner> for ReplicatingPersister<L, R> where L::Target: KVStore, R::Target: KVStore {
+	fn persist_new_channel(&self, funding_txo: OutPoint, monitor: &ChannelMonitor<ChannelSigner>, update_id: MonitorUpdateId) -> Result<(), chain::ChannelMonitorUpdateErr> {
+		self.persist_monitor(funding_txo, monitor, update_id)
+	}
+
+	fn update_persisted_channel(&self, funding_txo: OutPoint, _update: &Option<ChannelMonitorUpdate>, monitor: &ChannelMonitor<ChannelSigner>, update_id: MonitorUpdateId) -> Result<(), chain::ChannelMonitorUpdateErr> {
+		self.persist_monitor(funding_txo, monitor, update_id)
+	}
+
+	fn archive_persisted_channel(&self, funding_txo: OutPoint, monitor: &ChannelMonitor<ChannelSigner>) -> Result<(), ()> {
+		// Archive the remote copy first so that a failure leaves the local copy, from which the
+		// monitor is read on startup, in place.
+		let _replication_lock = self.replication_lock.lock().unwrap();
+		archive_channel_monitor(&self.remote_store, &funding_txo, monitor)
+			.and_then(|_| archive_channel_monitor(&self.local_store, &funding_txo, monitor))
+			.map_err(|_| ())?;
+		self.queue.lock().unwrap().retain(|queued| queued.funding_txo != funding_txo);
+		Ok(())
+	}
+}
+
+/// The namespace under which a [`FencedReplicaPersister`] persists the latest
+/// [`ChannelMonitorUpdate::update_id`] written for each [`ChannelMonitor`], keyed like the monitor
+/// itself.
+pub const CHANNEL_MONITOR_FENCE_PERSISTENCE_NAMESPACE: &str = "monitor_fences";
+
+/// A hook through which a [`FencedReplicaPersister`] determines whether this instance is the only
+/// one allowed to write, e.g. by holding a lease in a coordination service.
+pub trait LeaderLease {
+	/// Returns whether this instance currently holds the lease.
+	///
+	/// Must only return `true` if no other instance can acquire the lease before a write started
+	/// now has completed, e.g. by renewing a time-bounded lease well before it expires.
+	fn holds_lease(&self) -> bool;
+}
+
+/// A [`Persist`] and [`Persister`] implementation which replicates [`ChannelMonitorUpdate`]s to
+/// several [`KVStore`]s while fencing out any other instance writing to them.
+///
+/// Each replica is written to as by a [`MonitorUpdatingPersister`], with the latest update id
+/// persisted for each channel under [`CHANNEL_MONITOR_FENCE_PERSISTENCE_NAMESPACE`]. A write
+/// succeeds once at least `min_replicas` replicas have been written to, and otherwise results in
+/// [`ChannelMonitorUpdateErr::PermanentFailure`].
+///
+/// This instance is fenced, refusing all further writes, once any of the following occurs:
+///  * The [`LeaderLease`] is not held.
+///  * An update's id does not directly follow the previous update's id for its channel.
+///  * A replica holds a newer update for a channel than this instance has written, e.g. because a
+///    stale [`ChannelMonitor`] was loaded on startup or another instance wrote to it.
+///
+/// A replica which missed some updates for a channel, e.g. because it was unavailable, is written
+/// the full [`ChannelMonitor`] rather than the next update, bringing it back up to date.
+///
+/// When fenced, monitor writes return [`ChannelMonitorUpdateErr::InProgress`], which never
+/// completes, freezing the affected channels rather than force-closing them as a
+/// [`ChannelMonitorUpdateErr::PermanentFailure`] would. Writes of the [`ChannelManager`] and other
+/// objects fail. Once [`is_fenced`] returns `true`, the process should be shut down.
+///
+/// Note that a frozen [`ChannelMonitor`] still broadcasts transactions in response to new blocks,
+/// e.g. its possibly-stale commitment transaction once an HTLC times out, which may conflict with
+/// the transactions broadcast by the instance which fenced this one out. To avoid this, the
+/// [`BroadcasterInterface`] given to the [`ChainMonitor`] and [`ChannelManager`] should be wrapped
+/// in a [`FencedBroadcaster`], which drops all broadcasts once fenced.
+///
+/// [`is_fenced`]: Self::is_fenced
+/// [`ChannelMonitorUpdateErr::InProgress`]: chain::ChannelMonitorUpdateErr::InProgress
+/// [`ChannelMonitorUpdateErr::PermanentFailure`]: chain::ChannelMonitorUpdateErr::PermanentFailure
+pub struct FencedReplicaPersister<K: Deref, LL: Deref> where K::Target: KVStore, LL::Target: LeaderLease {
+	/// A persister for each replica, tracking when it last wrote each monitor in full.
+	replicas: Vec<MonitorUpdatingPersister<K>>,
+	min_replicas: usize,
+	lease: LL,
+	latest_update_ids: Mutex<HashMap<OutPoint, u64>>,
+	fenced: AtomicBool,
+}
+
+impl<K: Deref, LL: Deref> FencedReplicaPersister<K, LL> where K::Target: KVStore, LL::Target: LeaderLease {
+	/// Creates a persister writing to each of `replicas`, requiring at least `min_replicas` of them
+	/// to succeed, while `lease` is held.
+	///
+	/// See [`MonitorUpdatingPersister::new`] for the meaning of `maximum_pending_updates`.
+	pub fn new(replicas: Vec<K>, min_replicas: usize, maximum_pending_updates: u64, lease: LL) -> Self {
+		let replicas = replicas.into_iter()
+			.map(|replica| MonitorUpdatingPersister::new(replica, maximum_pending_updates))
+			.collect();
+		Self {
+			replicas, min_replicas, lease,
+			latest_update_ids: Mutex::new(HashMap::new()),
+			fenced: AtomicBool::new(false),
+		}
+	}
+
+	/// Returns whether this instance has been fenced and refuses all further writes.
+	pub fn is_fenced(&self) -> bool {
+		self.fenced.load(Ordering::Acquire)
+	}
+
+	/// Returns whether this instance may write, fencing it if the [`LeaderLease`] is not held.
+	fn may_write(&self) -> bool {
+		if self.is_fenced() || !self.lease.holds_lease() {
+			self.fence();
+			return false;
+		}
+		true
+	}
+
+	/// Reads all [`ChannelMonitor`]s, taking the most recent copy of each across replicas.
+	///
+	/// Fails if fewer than `min_replicas` replicas could be read.
+	pub fn read_channel_monitors<Signer: Sign, KI: Deref, B: Deref, F: Deref, L: Deref>(
+		&self, keys_manager: KI, broadcaster: B, fee_estimator: F, logger: L
+	) -> io::Result<Vec<(BlockHash, ChannelMonitor<Signer>)>>
+	where
+		KI::Target: KeysInterface<Signer = Signer> + Sized,
+		B::Target: BroadcasterInterface,
+		F::Target: FeeEstimator,
+		L::Target: Logger,
+	{
+		let mut monitors: HashMap<OutPoint, (BlockHash, ChannelMonitor<Signer>)> = HashMap::new();
+		let mut read_replicas = 0;
+		let mut last_err = None;
+		for persister in self.replicas.iter() {
+			match persister.read_channel_monitors(&*keys_manager, &*broadcaster, &*fee_estimator, &*logger) {
+				Ok(replica_monitors) => {
+					read_replicas += 1;
+					for (blockhash, monitor) in replica_monitors {
+						let funding_txo = monitor.get_funding_txo().0;
+						let is_newer = monitors.get(&funding_txo)
+							.map_or(true, |(_, known)| monitor.get_latest_update_id() > known.get_latest_update_id());
+						if is_newer {
+							monitors.insert(funding_txo, (blockhash, monitor));
+						}
+					}
+				},
+				Err(e) => last_err = Some(e),
+			}
+		}
+		if read_replicas < self.min_replicas {
+			return Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::Other, "Not enough replicas")));
+		}
+		Ok(monitors.into_iter().map(|(_, monitor)| monitor).collect())
+	}
+
+	/// Fences this instance, returning the error which monitor writes return once fenced.
+	fn fence(&self) -> chain::ChannelMonitorUpdateErr {
+		self.fenced.store(true, Ordering::Release);
+		chain::ChannelMonitorUpdateErr::InProgress
+	}
+
+	/// Checks that this instance may write and that no replica holds a newer update for the
+	/// channel than `latest_update_id`, returning the replicas which may be written to along with
+	/// whether each is up to date, i.e. holds all updates up to `latest_update_id`.
+	fn writable_replicas(&self, funding_txo: &OutPoint, latest_update_id: u64) -> Result<Vec<(&MonitorUpdatingPersister<K>, bool)>, chain::ChannelMonitorUpdateErr> {
+		if !self.may_write() {
+			return Err(chain::ChannelMonitorUpdateErr::InProgress);
+		}
+		let monitor_key = channel_monitor_key(funding_txo);
+		let mut writable_replicas = Vec::with_capacity(self.replicas.len());
+		for replica in self.replicas.iter() {
+			match read_if_present(&replica.kv_store, CHANNEL_MONITOR_FENCE_PERSISTENCE_NAMESPACE, &monitor_key) {
+				Ok(None) => writable_replicas.push((replica, false)),
+				Ok(Some(buf)) => match u64::read(&mut io::Cursor::new(buf)) {
+					Ok(fence) if fence > latest_update_id => return Err(self.fence()),
+					Ok(fence) => writable_replicas.push((replica, fence == latest_update_id)),
+					Err(_) => {},
+				},
+				Err(_) => {},
+			}
+		}
+		Ok(writable_replicas)
+	}
+
+	/// Writes to each replica using `write`, which is told whether the replica is up to date,
+	/// followed by the channel's new fence, succeeding if at least `min_replicas` replicas were
+	/// written to.
+	fn write_replicas<W>(&self, funding_txo: OutPoint, replicas: Vec<(&MonitorUpdatingPersister<K>, bool)>, update_id: u64, write: W) -> Result<(), chain::ChannelMonitorUpdateErr>
+	where W: Fn(&MonitorUpdatingPersister<K>, bool) -> Result<(), chain::ChannelMonitorUpdateErr> {
+		let monitor_key = channel_monitor_key(&funding_txo);
+		let written_replicas = replicas.into_iter().filter(|(replica, up_to_date)| {
+			write(replica, *up_to_date).is_ok() &&
+				replica.kv_store.write(CHANNEL_MONITOR_FENCE_PERSISTENCE_NAMESPACE, &monitor_key, &update_id.encode()).is_ok()
+		}).count();
+		if written_replicas < self.min_replicas {
+			return Err(chain::ChannelMonitorUpdateErr::PermanentFailure);
+		}
+		self.latest_update_ids.lock().unwrap().insert(funding_txo, update_id);
+		Ok(())
+	}
+
+	/// Writes `buf` under `key` in the empty namespace of each replica, succeeding if at least
+	/// `min_replicas` replicas were written to.
+	fn persist_to_replicas(&self, key: &str, buf: &[u8]) -> io::Result<()> {
+		if !self.may_write() {
+			return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Persister is fenced"));
+		}
+		let written_replicas = self.replicas.iter().filter(|replica| replica.kv_store.write("", key, buf).is_ok()).count();
+		if written_replicas < self.min_replicas {
+			return Err(io::Error::new(io::ErrorKind::Other, "Failed to write to enough replicas"));
+		}
+		Ok(())
+	}
+}
+
+impl<ChannelSigner: Sign, K: Deref, LL: Deref> Persist<ChannelSigner> for FencedReplicaPersister<K, LL> where K::Target: KVStore, LL::Target: LeaderLease {
+	fn persist_new_channel(&self, funding_txo: OutPoint, monitor: &ChannelMonitor<ChannelSigner>, update_id: MonitorUpdateId) -> Result<(), chain::ChannelMonitorUpdateErr> {
+		let latest_update_id = monitor.get_latest_update_id();
+		let replicas = self.writable_replicas(&funding_txo, latest_update_id)?;
+		self.write_replicas(funding_txo, replicas, latest_update_id, |persister, _| persister.persist_new_channel(funding_txo, monitor, update_id))
+	}
+
+	fn update_persisted_channel(&self, funding_txo: OutPoint, update: &Option<ChannelMonitorUpdate>, monitor: &ChannelMonitor<ChannelSigner>, update_id: MonitorUpdateId) -> Result<(), chain::ChannelMonitorUpdateErr> {
+		let previous_update_id = match self.latest_update_ids.lock().unwrap().get(&funding_txo) {
+			Some(update_id) => *update_id,
+			None => return Err(self.fence()),
+		};
+		let latest_update_id = match update {
+			Some(update) if update.update_id == CLOSED_CHANNEL_UPDATE_ID => CLOSED_CHANNEL_UPDATE_ID,
+			Some(update) if previous_update_id != CLOSED_CHANNEL_UPDATE_ID && update.update_id == previous_update_id + 1 => update.update_id,
+			Some(_) => return Err(self.fence()),
+			None => previous_update_id,
+		};
+		let replicas = self.writable_replicas(&funding_txo, previous_update_id)?;
+		self.write_replicas(funding_txo, replicas, latest_update_id, |persister, up_to_date| {
+			if up_to_date {
+				persister.update_persisted_channel(funding_txo, update, monitor, update_id)
+			} else {
+				// The update would be applied on top of missing ones, so write the full monitor.
+				persister.persist_new_channel(funding_txo, monitor, update_id)
+			}
+		})
+	}
+
+	fn archive_persisted_channel(&self, funding_txo: OutPoint, monitor: &ChannelMonitor<ChannelSigner>) -> Result<(), ()> {
+		// The fence is left in place so that a stale instance can never write the monitor again.
+		let replicas = self.writable_replicas(&funding_txo, monitor.get_latest_update_id()).map_err(|_| ())?;
+		let archived_replicas = replicas.into_iter()
+			.filter(|(replica, _)| replica.archive_persisted_channel(funding_txo, monitor).is_ok())
+			.count();
+		if archived_replicas < self.min_replicas {
+			return Err(());
+		}
+		self.latest_update_ids.lock().unwrap().remove(&funding_txo);
+		Ok(())
+	}
+}
+
+impl<'a, K: Deref, LL: Deref, Signer: Sign, M: Deref, T: Deref, KI: Deref, F: Deref, L: Deref, S> Persister<'a, Signer, M, T, KI, F, L, S> for FencedReplicaPersister<K, LL>
+	where K::Target: KVStore,
+		LL::Target: LeaderLease,
+		M::Target: 'static + chain::Watch<Signer>,
+		T::Target: 'static + BroadcasterInterface,
+		KI::Target: 'static + KeysInterface<Signer = Signer>,
+		F::Target: 'static + FeeEstimator,
+		L::Target: 'static + Logger,
+		S: WriteableScore<'a>,
+{
+	/// Persist the given [`ChannelManager`] to each replica, failing if this instance is fenced.
+	fn persist_manager(&self, channel_manager: &ChannelManager<Signer, M, T, KI, F, L>) -> Result<(), io::Error> {
+		self.persist_to_replicas(CHANNEL_MANAGER_PERSISTENCE_KEY, &channel_manager.encode())
+	}
+
+	/// Persist the given [`NetworkGraph`] to each replica, failing if this instance is fenced.
+	fn persist_graph(&self, network_graph: &NetworkGraph<L>) -> Result<(), io::Error> {
+		self.persist_to_replicas(NETWORK_GRAPH_PERSISTENCE_KEY, &network_graph.encode())
+	}
+
+	/// Persist the given [`WriteableScore`] to each replica, failing if this instance is fenced.
+	fn persist_scorer(&self, scorer: &S) -> Result<(), io::Error> {
+		self.persist_to_replicas(SCORER_PERSISTENCE_KEY, &scorer.encode())
+	}
+
+	/// Persist the given [`WriteablePeerPolicy`] to each replica, failing if this instance is fenced.
+	fn persist_peer_policy<P: WriteablePeerPolicy>(&self, peer_policy: &P) -> Result<(), io::Error> {
+		self.persist_to_replicas(PEER_POLICY_PERSISTENCE_KEY, &peer_policy.encode())
+	}
+}
+
+/// A [`BroadcasterInterface`] which drops all broadcasts once the given [`FencedReplicaPersister`]
+/// has been fenced, so that an instance which was fenced out never broadcasts possibly-stale state.
+pub struct FencedBroadcaster<B: Deref, P: Deref> where B::Target: BroadcasterInterface {
+	broadcaster: B,
+	persister: P,
+}
+
+impl<B: Deref, P: Deref> FencedBroadcaster<B, P> where B::Target: BroadcasterInterface {
+	/// Creates a broadcaster which passes transactions on to `broadcaster` unless `persister` has
+	/// been fenced.
+	pub fn new(broadcaster: B, persister: P) -> Self {
+		Self { broadcaster, persister }
+	}
+}
+
+impl<B: Deref, P: Deref, K: Deref, LL: Deref> BroadcasterInterface for FencedBroadcaster<B, P>
+	where B::Target: BroadcasterInterface,
+		P: Deref<Target = FencedReplicaPersister<K, LL>>,
+		K::Target: KVStore,
+		LL::Target: LeaderLease,
+{
+	fn broadcast_transaction(&self, tx: &bitcoin::Transaction) {
+		if self.persister.may_write() {
+			self.broadcaster.broadcast_transaction(tx);
+		}
+	}
+}
+
+/// The version of the header [`EncryptedKVStore`] prepends to the data it writes.
+const ENCRYPTED_DATA_VERSION: u8 = 1;
+/// The length of the random nonce in the header [`EncryptedKVStore`] prepends to the data it writes.
+const ENCRYPTED_DATA_NONCE_LEN: usize = 32;
+/// The length of the header [`EncryptedKVStore`] prepends to the data it writes.
+const ENCRYPTED_DATA_HEADER_LEN: usize = 1 + ENCRYPTED_DATA_NONCE_LEN;
+/// The length of the Poly1305 tag [`EncryptedKVStore`] appends to the data it writes.
+const ENCRYPTED_DATA_TAG_LEN: usize = 16;
+
+/// A [`KVStore`] which encrypts all data before writing it to an underlying [`KVStore`], such as a
+/// `FilesystemPersister`, and authenticates it when reading it back.
+///
+/// Data is encrypted with ChaCha20-Poly1305 under a key derived from
+/// [`KeysInterface::get_inbound_payment_key_material`], so the same [`KeysInterface`] (i.e., the
+/// same seed) must be used to read data back. Each value is prefixed by a header containing a
+/// version byte and a random 32-byte nonce, from which a fresh key is derived for every write. The
+/// header, namespace, and key are all authenticated, so data which has been modified, truncated, or
+/// moved to a different key fails to read with an [`io::ErrorKind::InvalidData`] error rather than
+/// being deserialized.
+///
+/// Only values are encrypted. Namespaces and keys, e.g., the funding outpoints of channels, are
+/// passed through to the underlying store as-is.
+///
+/// As it implements [`KVStore`], this may be used anywhere a [`KVStorePersister`], [`Persister`],
+/// or [`Persist`] is expected, as well as with [`MonitorUpdatingPersister`] and the
+/// `read_*` helpers in this module.
+pub struct EncryptedKVStore<K: Deref, KI: Deref> where K::Target: KVStore, KI::Target: KeysInterface {
+	kv_store: K,
+	keys_manager: KI,
+	encryption_key: [u8; 32],
+}
+
+impl<K: Deref, KI: Deref> EncryptedKVStore<K, KI> where K::Target: KVStore, KI::Target: KeysInterface {
+	/// Creates a store encrypting data written to `kv_store` under a key derived from
+	/// `keys_manager`, which is also used as the source of randomness for nonces.
+	pub fn new(kv_store: K, keys_manager: KI) -> Self {
+		let key_material = keys_manager.get_inbound_payment_key_material();
+		let (encryption_key, _) = hkdf_extract_expand_twice(b"LDK persistence encryption key", &key_material.0);
+		Self { kv_store, keys_manager, encryption_key }
+	}
+
+	/// Returns the key used to encrypt a single value, derived from the nonce in its header.
+	fn data_key(&self, nonce: &[u8]) -> [u8; 32] {
+		let (data_key, _) = hkdf_extract_expand_twice(&self.encryption_key, nonce);
+		data_key
+	}
+
+	/// Returns the data authenticated along with a value: its header, namespace, and key.
+	fn associated_data(header: &[u8], namespace: &str, key: &str) -> Vec<u8> {
+		let mut aad = Vec::with_capacity(header.len() + namespace.len() + 1 + key.len());
+		aad.extend_from_slice(header);
+		aad.extend_from_slice(namespace.as_bytes());
+		aad.push(0);
+		aad.extend_from_slice(key.as_bytes());
+		aad
+	}
+}
+
+impl<K: Deref, KI: Deref> KVStore for EncryptedKVStore<K, KI> where K::Target: KVStore, KI::Target: KeysInterface {
+	fn read(&self, namespace: &str, key: &str) -> io::Result<Vec<u8>> {
+		let buf = self.kv_store.read(namespace, key)?;
+		if buf.len() < ENCRYPTED_DATA_HEADER_LEN + ENCRYPTED_DATA_TAG_LEN {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, "Encrypted data is truncated"));
+		}
+		if buf[0] != ENCRYPTED_DATA_VERSION {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, "Encrypted data has an unknown version"));
+		}
+
+		let (header, rest) = buf.split_at(ENCRYPTED_DATA_HEADER_LEN);
+		let (ciphertext, tag) = rest.split_at(rest.len() - ENCRYPTED_DATA_TAG_LEN);
+		let aad = Self::associated_data(header, namespace, key);
+		let mut chacha = ChaCha20Poly1305RFC::new(&self.data_key(&header[1..]), &[0; 12], &aad);
+		let mut plaintext = vec![0; ciphertext.len()];
+		if !chacha.decrypt(ciphertext, &mut plaintext, tag) {
+			return Err(io::Error::new(io::ErrorKind::InvalidData,
+				"Failed to authenticate encrypted data, it was either modified or encrypted under a different key"));
+		}
+		Ok(plaintext)
+	}
+
+	fn write(&self, namespace: &str, key: &str, buf: &[u8]) -> io::Result<()> {
+		let mut encrypted = vec![0; ENCRYPTED_DATA_HEADER_LEN + buf.len() + ENCRYPTED_DATA_TAG_LEN];
+		encrypted[0] = ENCRYPTED_DATA_VERSION;
+		encrypted[1..ENCRYPTED_DATA_HEADER_LEN].copy_from_slice(&self.keys_manager.get_secure_random_bytes());
+
+		let (header, rest) = encrypted.split_at_mut(ENCRYPTED_DATA_HEADER_LEN);
+		let (ciphertext, tag) = rest.split_at_mut(buf.len());
+		let aad = Self::associated_data(header, namespace, key);
+		let mut chacha = ChaCha20Poly1305RFC::new(&self.data_key(&header[1..]), &[0; 12], &aad);
+		chacha.encrypt(buf, ciphertext, tag);
+		self.kv_store.write(namespace, key, &encrypted)
+	}
+
+	fn remove(&self, namespace: &str, key: &str) -> io::Result<()> {
+		self.kv_store.remove(namespace, key)
+	}
+
+	fn list(&self, namespace: &str) -> io::Result<Vec<String>> {
+		self.kv_store.list(namespace)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::blockdata::block::BlockHeader;
+	use bitcoin::Transaction;
+	use chain::{ChannelMonitorUpdateErr, Confirm, Watch};
+	use chain::channelmonitor::MonitorEvent;
+	use ln::features::InitFeatures;
+	use ln::functional_test_utils::*;
+	use routing::scoring::FixedPenaltyScorer;
+	use util::enforcing_trait_impls::EnforcingSigner;
+	use util::test_utils;
+	use sync::Arc;
+
+	#[test]
+	fn persist_and_read_channel_monitors() {
+		let store = test_utils::TestStore::new();
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let mut node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let chain_mon_0 = test_utils::TestChainMonitor::new(Some(&chanmon_cfgs[0].chain_source), &chanmon_cfgs[0].tx_broadcaster, &chanmon_cfgs[0].logger, &chanmon_cfgs[0].fee_estimator, &store, node_cfgs[0].keys_manager);
+		node_cfgs[0].chain_monitor = chain_mon_0;
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+		assert!(read_channel_monitors(&store, nodes[0].keys_manager).unwrap().is_empty());
+
+		let chan = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+		send_payment(&nodes[0], &vec!(&nodes[1])[..], 8000000);
+		let monitors = read_channel_monitors(&store, nodes[0].keys_manager).unwrap();
+		assert_eq!(monitors.len(), 1);
+		assert_eq!(monitors[0].1.get_funding_txo().0.to_channel_id(), chan.2);
+		assert_eq!(monitors[0].1.get_latest_update_id(), 5);
+
+		let keys = store.list(CHANNEL_MONITOR_PERSISTENCE_NAMESPACE).unwrap();
+		assert_eq!(keys.len(), 1);
+		let funding_txo = monitors[0].1.get_funding_txo().0;
+		assert_eq!(keys[0], format!("{}_{}", funding_txo.txid.to_hex(), funding_txo.index));
+
+		// Failing to write a monitor update results in a permanent failure.
+		store.fail_writes.store(true, core::sync::atomic::Ordering::Release);
+		let update_id = nodes[0].chain_monitor.latest_monitor_update_id.lock().unwrap().get(&chan.2).unwrap().2;
+		match store.update_persisted_channel(funding_txo, &None, &monitors[0].1, update_id) {
+			Err(ChannelMonitorUpdateErr::PermanentFailure) => {},
+			_ => panic!("Unexpected result from persisting channel update"),
+		}
+		store.fail_writes.store(false, core::sync::atomic::Ordering::Release);
+
+		// Monitors stored under a key other than their funding outpoint are rejected.
+		let buf = store.read(CHANNEL_MONITOR_PERSISTENCE_NAMESPACE, &keys[0]).unwrap();
+		let wrong_key = format!("{}_{}", funding_txo.txid.to_hex(), funding_txo.index + 1);
+		store.write(CHANNEL_MONITOR_PERSISTENCE_NAMESPACE, &wrong_key, &buf).unwrap();
+		assert!(read_channel_monitors(&store, nodes[0].keys_manager).is_err());
+
+		// Removed monitors are no longer read.
+		store.remove(CHANNEL_MONITOR_PERSISTENCE_NAMESPACE, &wrong_key).unwrap();
+		store.remove(CHANNEL_MONITOR_PERSISTENCE_NAMESPACE, &keys[0]).unwrap();
+		store.remove(CHANNEL_MONITOR_PERSISTENCE_NAMESPACE, &keys[0]).unwrap();
+		assert!(read_channel_monitors(&store, nodes[0].keys_manager).unwrap().is_empty());
+
+		// Archived monitors are moved to the archive namespace.
+		store.write(CHANNEL_MONITOR_PERSISTENCE_NAMESPACE, &keys[0], &buf).unwrap();
+		store.archive_persisted_channel(funding_txo, &monitors[0].1).unwrap();
+		assert!(read_channel_monitors(&store, nodes[0].keys_manager).unwrap().is_empty());
+		assert_eq!(store.list(ARCHIVED_CHANNEL_MONITOR_PERSISTENCE_NAMESPACE).unwrap(), keys);
+	}
+
+	#[test]
+	fn persist_channel_monitor_updates_incrementally() {
+		let store = test_utils::TestStore::new();
+		let persister = MonitorUpdatingPersister::new(&store, 3);
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let mut node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let chain_mon_0 = test_utils::TestChainMonitor::new(Some(&chanmon_cfgs[0].chain_source), &chanmon_cfgs[0].tx_broadcaster, &chanmon_cfgs[0].logger, &chanmon_cfgs[0].fee_estimator, &persister, node_cfgs[0].keys_manager);
+		node_cfgs[0].chain_monitor = chain_mon_0;
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+		let chan = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+		let funding_txo = nodes[0].chain_monitor.chain_monitor.list_monitors()[0];
+		let update_namespace = format!("{}/{}", CHANNEL_MONITOR_UPDATE_PERSISTENCE_NAMESPACE, channel_monitor_key(&funding_txo));
+		send_payment(&nodes[0], &vec!(&nodes[1])[..], 8000000);
+
+		// Updates 1 through 5 were persisted, with the full monitor written at update 3 and update 3
+		// and prior removed. Only updates 4 and 5 remain.
+		let mut update_keys = store.list(&update_namespace).unwrap();
+		update_keys.sort();
+		assert_eq!(update_keys, vec!["4".to_string(), "5".to_string()]);
+
+		// Reading the full monitors alone would return a stale monitor, so is refused.
+		match read_channel_monitors(&store, nodes[0].keys_manager) {
+			Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+			Ok(_) => panic!("Expected error"),
+		}
+
+		let read_monitors = || persister.read_channel_monitors(
+			nodes[0].keys_manager, nodes[0].tx_broadcaster, &chanmon_cfgs[0].fee_estimator, nodes[0].logger);
+		{
+			let monitors = read_monitors().unwrap();
+			assert_eq!(monitors.len(), 1);
+			assert_eq!(monitors[0].1.get_latest_update_id(), 5);
+			assert!(monitors[0].1 == *nodes[0].chain_monitor.chain_monitor.get_monitor(funding_txo).unwrap());
+		}
+
+		// Stale updates which were not removed are skipped, while missing updates are detected.
+		let update_4 = store.read(&update_namespace, "4").unwrap();
+		store.write(&update_namespace, "2", &update_4).unwrap();
+		match read_monitors() {
+			Ok(monitors) => assert_eq!(monitors[0].1.get_latest_update_id(), 5),
+			Err(e) => panic!("Unexpected error: {:?}", e),
+		}
+		store.remove(&update_namespace, "4").unwrap();
+		match read_monitors() {
+			Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+			Ok(_) => panic!("Expected error"),
+		}
+		store.write(&update_namespace, "4", &update_4).unwrap();
+
+		// New blocks without new events only result in the full monitor being written, and all
+		// updates removed, periodically.
+		connect_blocks(&nodes[0], 1);
+		assert!(!store.list(&update_namespace).unwrap().is_empty());
+		connect_blocks(&nodes[0], CHAIN_SYNC_FULL_MONITOR_WRITE_INTERVAL - 1);
+		assert!(store.list(&update_namespace).unwrap().is_empty());
+		assert_eq!(read_channel_monitors(&store, nodes[0].keys_manager).unwrap()[0].1.get_latest_update_id(), 5);
+
+		// Failing to write an update results in a permanent failure.
+		store.fail_writes.store(true, core::sync::atomic::Ordering::Release);
+		let update_id = nodes[0].chain_monitor.latest_monitor_update_id.lock().unwrap().get(&chan.2).unwrap().2;
+		let update = ChannelMonitorUpdate::read(&mut io::Cursor::new(&update_4)).unwrap();
+		match persister.update_persisted_channel(funding_txo, &Some(update), &*nodes[0].chain_monitor.chain_monitor.get_monitor(funding_txo).unwrap(), update_id) {
+			Err(ChannelMonitorUpdateErr::PermanentFailure) => {},
+			_ => panic!("Unexpected result from persisting channel update"),
+		}
+		store.fail_writes.store(false, core::sync::atomic::Ordering::Release);
+
+		// Archiving moves the latest monitor out of the namespaces read on startup, removing any
+		// pending updates.
+		store.write(&update_namespace, "6", &update_4).unwrap();
+		persister.archive_persisted_channel(funding_txo, &*nodes[0].chain_monitor.chain_monitor.get_monitor(funding_txo).unwrap()).unwrap();
+		assert!(read_monitors().unwrap().is_empty());
+		assert!(store.list(&update_namespace).unwrap().is_empty());
+		assert_eq!(store.list(ARCHIVED_CHANNEL_MONITOR_PERSISTENCE_NAMESPACE).unwrap(), vec![channel_monitor_key(&funding_txo)]);
+	}
+
+	#[test]
+	fn read_channel_monitors_with_invalid_key() {
+		let store = test_utils::TestStore::new();
+		let keys_manager = test_utils::TestKeysInterface::new(&[0; 32], bitcoin::network::constants::Network::Testnet);
+		store.write(CHANNEL_MONITOR_PERSISTENCE_NAMESPACE, "foo", &[]).unwrap();
+		match read_channel_monitors(&store, &keys_manager) {
+			Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+			Ok(_) => panic!("Expected error"),
+		}
+	}
+
+	#[test]
+	fn persist_and_read_network_graph_and_scorer() {
+		let store = test_utils::TestStore::new();
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+		assert!(read_network_graph(&store, nodes[0].logger).unwrap().is_none());
+		assert!(read_scorer::<_, FixedPenaltyScorer, _>(&store, 1000).unwrap().is_none());
+
+		create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+		store.persist(NETWORK_GRAPH_PERSISTENCE_KEY, nodes[0].network_graph).unwrap();
+		let network_graph = read_network_graph(&store, nodes[0].logger).unwrap().unwrap();
+		assert!(network_graph == *nodes[0].network_graph);
+		assert_eq!(network_graph.read_only().channels().len(), 1);
+
+		store.persist(SCORER_PERSISTENCE_KEY, &FixedPenaltyScorer::with_penalty(1000)).unwrap();
+		assert!(read_scorer::<_, FixedPenaltyScorer, _>(&store, 1000).unwrap().is_some());
+
+		// Objects which fail to deserialize are reported as invalid data.
+		store.write("", NETWORK_GRAPH_PERSISTENCE_KEY, &[42]).unwrap();
+		match read_network_graph(&store, nodes[0].logger) {
+			Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+			Ok(_) => panic!("Expected error"),
+		}
+	}
+
+	#[test]
+	fn persist_with_namespaced_key() {
+		let store = test_utils::TestStore::new();
+		store.persist("manager", &42u8).unwrap();
+		store.persist("foo/bar/baz", &43u8).unwrap();
+		assert_eq!(store.read("", "manager").unwrap(), vec![42]);
+		assert_eq!(store.read("foo/bar", "baz").unwrap(), vec![43]);
+		assert_eq!(store.list("foo/bar").unwrap(), vec!["baz".to_string()]);
+		assert_eq!(store.read("foo", "bar").unwrap_err().kind(), io::ErrorKind::NotFound);
+	}
+
+	#[test]
+	fn encrypted_store_authenticates_data() {
+		let store = test_utils::TestStore::new();
+		let keys_manager = test_utils::TestKeysInterface::new(&[0; 32], bitcoin::network::constants::Network::Testnet);
+		let encrypted_store = EncryptedKVStore::new(&store, &keys_manager);
+		encrypted_store.write("monitors", "foo", &[42; 3]).unwrap();
+		encrypted_store.write("monitors", "bar", &[]).unwrap();
+		assert_eq!(encrypted_store.read("monitors", "foo").unwrap(), vec![42; 3]);
+		assert_eq!(encrypted_store.read("monitors", "bar").unwrap(), Vec::<u8>::new());
+		assert_eq!(encrypted_store.read("monitors", "baz").unwrap_err().kind(), io::ErrorKind::NotFound);
+		let mut keys = encrypted_store.list("monitors").unwrap();
+		keys.sort();
+		assert_eq!(keys, vec!["bar".to_string(), "foo".to_string()]);
+
+		// The underlying store only sees the header, ciphertext, and tag, and a fresh nonce is used
+		// for every write.
+		let buf = store.read("monitors", "foo").unwrap();
+		assert_eq!(buf.len(), ENCRYPTED_DATA_HEADER_LEN + 3 + ENCRYPTED_DATA_TAG_LEN);
+		assert_eq!(buf[0], ENCRYPTED_DATA_VERSION);
+		assert_ne!(&buf[ENCRYPTED_DATA_HEADER_LEN..ENCRYPTED_DATA_HEADER_LEN + 3], &[42; 3]);
+		encrypted_store.write("monitors", "foo", &[42; 3]).unwrap();
+		assert_ne!(store.read("monitors", "foo").unwrap(), buf);
+
+		let expect_invalid_data = |namespace, key| match encrypted_store.read(namespace, key) {
+			Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+			Ok(_) => panic!("Expected error"),
+		};
+
+		// Modifying any byte of the header, ciphertext, or tag is detected.
+		for i in 0..buf.len() {
+			let mut tampered = buf.clone();
+			tampered[i] ^= 1;
+			store.write("monitors", "foo", &tampered).unwrap();
+			expect_invalid_data("monitors", "foo");
+		}
+
+		// So is truncated data, and data moved to a different namespace or key.
+		store.write("monitors", "foo", &buf[..ENCRYPTED_DATA_HEADER_LEN + ENCRYPTED_DATA_TAG_LEN - 1]).unwrap();
+		expect_invalid_data("monitors", "foo");
+		store.write("monitors", "baz", &buf).unwrap();
+		expect_invalid_data("monitors", "baz");
+		store.write("", "foo", &buf).unwrap();
+		expect_invalid_data("", "foo");
+
+		// Data encrypted under a different key cannot be read.
+		store.write("monitors", "foo", &buf).unwrap();
+		let other_keys_manager = test_utils::TestKeysInterface::new(&[1; 32], bitcoin::network::constants::Network::Testnet);
+		match EncryptedKVStore::new(&store, &other_keys_manager).read("monitors", "foo") {
+			Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+			Ok(_) => panic!("Expected error"),
+		}
+		assert_eq!(encrypted_store.read("monitors", "foo").unwrap(), vec![42; 3]);
+
+		encrypted_store.remove("monitors", "foo").unwrap();
+		assert_eq!(store.read("monitors", "foo").unwrap_err().kind(), io::ErrorKind::NotFound);
+	}
+
+	#[test]
+	fn persist_and_read_encrypted_channel_monitors() {
+		let store = test_utils::TestStore::new();
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let mut node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let encrypted_store = EncryptedKVStore::new(&store, node_cfgs[0].keys_manager);
+		let chain_mon_0 = test_utils::TestChainMonitor::new(Some(&chanmon_cfgs[0].chain_source), &chanmon_cfgs[0].tx_broadcaster, &chanmon_cfgs[0].logger, &chanmon_cfgs[0].fee_estimator, &encrypted_store, node_cfgs[0].keys_manager);
+		node_cfgs[0].chain_monitor = chain_mon_0;
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+		create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+		send_payment(&nodes[0], &vec!(&nodes[1])[..], 8000000);
+		let monitors = read_channel_monitors(&encrypted_store, nodes[0].keys_manager).unwrap();
+		assert_eq!(monitors.len(), 1);
+		assert_eq!(monitors[0].1.get_latest_update_id(), 5);
+
+		// The monitor cannot be read without decrypting it.
+		match read_channel_monitors(&store, nodes[0].keys_manager) {
+			Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+			Ok(_) => panic!("Expected error"),
+		}
+	}
+
+	#[test]
+	fn replicate_channel_monitors_to_remote_store() {
+		let store = test_utils::TestStore::new();
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let mut node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let chain_mon_0 = test_utils::TestChainMonitor::new(Some(&chanmon_cfgs[0].chain_source), &chanmon_cfgs[0].tx_broadcaster, &chanmon_cfgs[0].logger, &chanmon_cfgs[0].fee_estimator, &store, node_cfgs[0].keys_manager);
+		node_cfgs[0].chain_monitor = chain_mon_0;
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+		create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+		create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+		let mut monitors = read_channel_monitors(&store, nodes[0].keys_manager).unwrap();
+		assert_eq!(monitors.len(), 2);
+		let (_, monitor_a) = monitors.remove(0);
+		let (_, monitor_b) = monitors.remove(0);
+		let funding_txo = monitor_a.get_funding_txo().0;
+		let monitor_key = channel_monitor_key(&funding_txo);
+
+		// Load the monitors into a ChainMonitor replicating to a remote store which holds only a
+		// single queued monitor in memory.
+		let local_store = test_utils::TestStore::new();
+		let remote_store = test_utils::TestStore::new();
+		let persister = ReplicatingPersister::new(&local_store, &remote_store, 1, 3);
+		let chain_monitor = ChainMonitor::new(None::<&test_utils::TestChainSource>, &chanmon_cfgs[0].tx_broadcaster, &chanmon_cfgs[0].logger, &chanmon_cfgs[0].fee_estimator, &persister);
+		assert_eq!(chain_monitor.watch_channel(funding_txo, monitor_a), Err(ChannelMonitorUpdateErr::InProgress));
+		let funding_txo_b = monitor_b.get_funding_txo().0;
+		assert_eq!(chain_monitor.watch_channel(funding_txo_b, monitor_b), Err(ChannelMonitorUpdateErr::InProgress));
+		assert_eq!(persister.pending_replication_count(), 2);
+
+		let update_ids = chain_monitor.list_pending_monitor_updates().remove(&funding_txo).unwrap();
+		assert_eq!(update_ids.len(), 1);
+		let completion = chain_monitor.get_update_completion(funding_txo, update_ids[0]).unwrap();
+		assert!(!completion.is_complete());
+		assert!(local_store.read(CHANNEL_MONITOR_PERSISTENCE_NAMESPACE, &monitor_key).is_ok());
+		assert_eq!(remote_store.read(CHANNEL_MONITOR_PERSISTENCE_NAMESPACE, &monitor_key).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+		// A new block results in the latest monitor superseding the queued one.
+		let (prev_blockhash, height) = nodes[0].best_block_info();
+		let header = BlockHeader { version: 0x20000000, prev_blockhash, merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+		chain_monitor.best_block_updated(&header, height + 1);
+		assert_eq!(persister.pending_replication_count(), 2);
+		assert_eq!(chain_monitor.list_pending_monitor_updates().get(&funding_txo).unwrap().len(), 2);
+
+		// Failed writes remain queued until the next call.
+		remote_store.fail_writes.store(true, core::sync::atomic::Ordering::Release);
+		assert_eq!(persister.replicate_pending(&chain_monitor), 2);
+		assert!(!completion.is_complete());
+		assert!(chain_monitor.release_pending_monitor_events().is_empty());
+		remote_store.fail_writes.store(false, core::sync::atomic::Ordering::Release);
+		assert_eq!(persister.replicate_pending(&chain_monitor), 0);
+		assert!(completion.wait_timeout(core::time::Duration::from_secs(0)));
+		assert!(chain_monitor.list_pending_monitor_updates().get(&funding_txo).unwrap().is_empty());
+		assert_eq!(remote_store.read(CHANNEL_MONITOR_PERSISTENCE_NAMESPACE, &monitor_key).unwrap(),
+			local_store.read(CHANNEL_MONITOR_PERSISTENCE_NAMESPACE, &monitor_key).unwrap());
+		// The monitor which didn't fit in memory was read back from the local store.
+		let monitor_key_b = channel_monitor_key(&funding_txo_b);
+		assert_eq!(remote_store.read(CHANNEL_MONITOR_PERSISTENCE_NAMESPACE, &monitor_key_b).unwrap(),
+			local_store.read(CHANNEL_MONITOR_PERSISTENCE_NAMESPACE, &monitor_key_b).unwrap());
+
+		// Once replicated, the channels may be restored to normal operation.
+		let monitor_events = chain_monitor.release_pending_monitor_events();
+		assert_eq!(monitor_events.len(), 2);
+		let mut completed_funding_txos: Vec<_> = monitor_events.iter().map(|(_, events, _)| {
+			if let MonitorEvent::UpdateCompleted { funding_txo: completed_funding_txo, .. } = events[0] {
+				completed_funding_txo
+			} else { panic!("Unexpected monitor event"); }
+		}).collect();
+		completed_funding_txos.sort();
+		let mut expected_funding_txos = vec![funding_txo, funding_txo_b];
+		expected_funding_txos.sort();
+		assert_eq!(completed_funding_txos, expected_funding_txos);
+	}
+
+	struct TestLease(AtomicBool);
+
+	impl LeaderLease for TestLease {
+		fn holds_lease(&self) -> bool { self.0.load(Ordering::Acquire) }
+	}
+
+	#[test]
+	fn fence_replicated_channel_monitors() {
+		let replicas = [test_utils::TestStore::new(), test_utils::TestStore::new()];
+		let lease = TestLease(AtomicBool::new(true));
+		let persister = FencedReplicaPersister::new(vec![&replicas[0], &replicas[1]], 1, 100, &lease);
+		let chanmon_cfgs = create_chanmon_cfgs(2);
+		let mut node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+		let chain_mon_0 = test_utils::TestChainMonitor::new(Some(&chanmon_cfgs[0].chain_source), &chanmon_cfgs[0].tx_broadcaster, &chanmon_cfgs[0].logger, &chanmon_cfgs[0].fee_estimator, &persister, node_cfgs[0].keys_manager);
+		node_cfgs[0].chain_monitor = chain_mon_0;
+		let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+		let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+		let read_monitors = |persister: &FencedReplicaPersister<&test_utils::TestStore, &TestLease>| persister.read_channel_monitors(
+			nodes[0].keys_manager, nodes[0].tx_broadcaster, &chanmon_cfgs[0].fee_estimator, nodes[0].logger).unwrap();
+		let read_fence = |replica: &test_utils::TestStore, funding_txo| u64::read(&mut io::Cursor::new(
+			replica.read(CHANNEL_MONITOR_FENCE_PERSISTENCE_NAMESPACE, &channel_monitor_key(funding_txo)).unwrap())).unwrap();
+
+		let chan = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+		let (_, stale_monitor) = read_monitors(&persister).pop().unwrap();
+		let funding_txo = stale_monitor.get_funding_txo().0;
+		send_payment(&nodes[0], &vec!(&nodes[1])[..], 8000000);
+		assert_eq!(read_fence(&replicas[0], &funding_txo), 5);
+		assert_eq!(read_fence(&replicas[1], &funding_txo), 5);
+
+		// A replica failing to be written is tolerated while at least one replica is written, and
+		// the most recent copy of each monitor is read.
+		replicas[1].fail_writes.store(true, Ordering::Release);
+		send_payment(&nodes[0], &vec!(&nodes[1])[..], 8000000);
+		replicas[1].fail_writes.store(false, Ordering::Release);
+		assert_eq!(read_fence(&replicas[0], &funding_txo), 10);
+		assert_eq!(read_fence(&replicas[1], &funding_txo), 5);
+		let (_, monitor) = read_monitors(&persister).pop().unwrap();
+		assert_eq!(monitor.get_latest_update_id(), 10);
+		assert!(!persister.is_fenced());
+
+		// The lagging replica is written the full monitor rather than updates it cannot apply.
+		send_payment(&nodes[0], &vec!(&nodes[1])[..], 8000000);
+		assert_eq!(read_fence(&replicas[0], &funding_txo), 15);
+		assert_eq!(read_fence(&replicas[1], &funding_txo), 15);
+		let (_, lagging_monitor) = MonitorUpdatingPersister::new(&replicas[1], 100).read_channel_monitors::<EnforcingSigner, _, _, _, _>(
+			nodes[0].keys_manager, nodes[0].tx_broadcaster, &chanmon_cfgs[0].fee_estimator, nodes[0].logger).unwrap().pop().unwrap();
+		assert_eq!(lagging_monitor.get_latest_update_id(), 15);
+		let (_, monitor) = read_monitors(&persister).pop().unwrap();
+
+		// New blocks only write each replica's full monitor periodically.
+		let written_heights = || replicas.iter().map(|replica| {
+			let (_, monitor) = read_channel_monitor::<_, EnforcingSigner, _>(&replica, &channel_monitor_key(&funding_txo), &nodes[0].keys_manager).unwrap();
+			monitor.current_best_block().height()
+		}).collect::<Vec<_>>();
+		let initial_heights = written_heights();
+		connect_blocks(&nodes[0], 10);
+		assert_eq!(written_heights(), initial_heights);
+		connect_blocks(&nodes[0], CHAIN_SYNC_FULL_MONITOR_WRITE_INTERVAL);
+		assert!(written_heights().iter().zip(initial_heights.iter()).all(|(height, initial_height)| height > initial_height));
+
+		// Another instance which loads a stale monitor is fenced out without writing anything.
+		let stale_persister = FencedReplicaPersister::new(vec![&replicas[0], &replicas[1]], 1, 3, &lease);
+		let update_id = MonitorUpdateId::from_new_monitor(&stale_monitor);
+		assert_eq!(stale_persister.persist_new_channel(funding_txo, &stale_monitor, update_id), Err(ChannelMonitorUpdateErr::InProgress));
+		assert!(stale_persister.is_fenced());
+		assert_eq!(read_fence(&replicas[0], &funding_txo), 15);
+
+		// Another instance which skips an update is fenced out.
+		let other_persister = FencedReplicaPersister::new(vec![&replicas[0], &replicas[1]], 2, 3, &lease);
+		let update_id = MonitorUpdateId::from_new_monitor(&monitor);
+		assert_eq!(other_persister.persist_new_channel(funding_txo, &monitor, update_id), Ok(()));
+		let mut update = nodes[0].chain_monitor.monitor_updates.lock().unwrap().get(&chan.2).unwrap()[0].clone();
+		update.update_id = 12;
+		let update_id = MonitorUpdateId::from_monitor_update(&update);
+		assert_eq!(other_persister.update_persisted_channel(funding_txo, &Some(update), &monitor, update_id), Err(ChannelMonitorUpdateErr::InProgress));
+		assert!(other_persister.is_fenced());
+		assert!(other_persister.persist_to_replicas(CHANNEL_MANAGER_PERSISTENCE_KEY, &[42]).is_err());
+
+		// Writes fail if not enough replicas can be written to.
+		let update_id = MonitorUpdateId::from_new_monitor(&monitor);
+		let strict_persister = FencedReplicaPersister::new(vec![&replicas[0], &replicas[1]], 2, 3, &lease);
+		replicas[1].fail_writes.store(true, Ordering::Release);
+		assert_eq!(strict_persister.persist_new_channel(funding_txo, &monitor, update_id), Err(ChannelMonitorUpdateErr::PermanentFailure));
+		assert!(strict_persister.persist_to_replicas(CHANNEL_MANAGER_PERSISTENCE_KEY, &[42]).is_err());
+		replicas[1].fail_writes.store(false, Ordering::Release);
+		assert!(!strict_persister.is_fenced());
+
+		// Losing the lease fences this instance, freezing its channels and dropping broadcasts.
+		let tx_broadcaster = test_utils::TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()), blocks: Arc::new(Mutex::new(Vec::new())) };
+		let fenced_broadcaster = FencedBroadcaster::new(&tx_broadcaster, &persister);
+		let tx = Transaction { version: 2, lock_time: 0, input: Vec::new(), output: Vec::new() };
+		fenced_broadcaster.broadcast_transaction(&tx);
+		assert_eq!(tx_broadcaster.txn_broadcasted.lock().unwrap().split_off(0), vec![tx.clone()]);
+		assert!(persister.persist_to_replicas(CHANNEL_MANAGER_PERSISTENCE_KEY, &[42]).is_ok());
+		lease.0.store(false, Ordering::Release);
+		connect_blocks(&nodes[0], 1);
+		assert!(persister.is_fenced());
+		assert!(!nodes[0].chain_monitor.chain_monitor.list_pending_monitor_updates().get(&funding_txo).unwrap().is_empty());
+		lease.0.store(true, Ordering::Release);
+		assert!(persister.persist_to_replicas(CHANNEL_MANAGER_PERSISTENCE_KEY, &[42]).is_err());
+		fenced_broadcaster.broadcast_transaction(&tx);
+		assert!(tx_broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+	}
 }
@@ -23,6 +23,7 @@ use ln::script::ShutdownScript;
 use routing::scoring::FixedPenaltyScorer;
 use util::enforcing_trait_impls::{EnforcingSigner, EnforcementState};
 use util::events;
+use util::persist;
 use util::logger::{Logger, Level, Record};
 use util::ser::{Readable, ReadableArgs, Writer, Writeable};
 
@@ -44,6 +45,7 @@ use prelude::*;
 use core::time::Duration;
 use sync::{Mutex, Arc};
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{RawWaker, RawWakerVTable, Waker};
 use core::{cmp, mem};
 use bitcoin::bech32::u5;
 use chain::keysinterface::{InMemorySigner, Recipient, KeyMaterial};
@@ -184,6 +186,8 @@ pub struct TestPersister {
 	/// When we get an update_persisted_channel call *with* a ChannelMonitorUpdate, we insert the
 	/// MonitorUpdateId here.
 	pub offchain_monitor_updates: Mutex<HashMap<OutPoint, HashSet<MonitorUpdateId>>>,
+	/// When we get an archive_persisted_channel call, we insert the channel's funding outpoint here.
+	pub archived_channels: Mutex<HashSet<OutPoint>>,
 }
 impl TestPersister {
 	pub fn new() -> Self {
@@ -192,6 +196,7 @@ impl TestPersister {
 			next_update_ret: Mutex::new(None),
 			chain_sync_monitor_persistences: Mutex::new(HashMap::new()),
 			offchain_monitor_updates: Mutex::new(HashMap::new()),
+			archived_channels: Mutex::new(HashSet::new()),
 		}
 	}
 
@@ -224,6 +229,54 @@ impl<Signer: keysinterface::Sign> chainmonitor::Persist<Signer> for TestPersiste
 		}
 		ret
 	}
+
+	fn archive_persisted_channel(&self, funding_txo: OutPoint, _data: &channelmonitor::ChannelMonitor<Signer>) -> Result<(), ()> {
+		self.archived_channels.lock().unwrap().insert(funding_txo);
+		Ok(())
+	}
+}
+
+/// An in-memory [`KVStore`] which counts successful writes and may be made to fail them.
+///
+/// [`KVStore`]: crate::util::persist::KVStore
+pub struct TestStore {
+	pub entries: Mutex<HashMap<(String, String), Vec<u8>>>,
+	pub writes: AtomicUsize,
+	pub fail_writes: AtomicBool,
+}
+impl TestStore {
+	pub fn new() -> Self {
+		Self { entries: Mutex::new(HashMap::new()), writes: AtomicUsize::new(0), fail_writes: AtomicBool::new(false) }
+	}
+}
+impl persist::KVStore for TestStore {
+	fn read(&self, namespace: &str, key: &str) -> io::Result<Vec<u8>> {
+		match self.entries.lock().unwrap().get(&(namespace.to_string(), key.to_string())) {
+			Some(buf) => Ok(buf.clone()),
+			None => Err(io::Error::new(io::ErrorKind::NotFound, "key not found")),
+		}
+	}
+
+	fn write(&self, namespace: &str, key: &str, buf: &[u8]) -> io::Result<()> {
+		if self.fail_writes.load(Ordering::Acquire) {
+			return Err(io::Error::new(io::ErrorKind::Other, "write failed"));
+		}
+		self.writes.fetch_add(1, Ordering::AcqRel);
+		self.entries.lock().unwrap().insert((namespace.to_string(), key.to_string()), buf.to_vec());
+		Ok(())
+	}
+
+	fn remove(&self, namespace: &str, key: &str) -> io::Result<()> {
+		self.entries.lock().unwrap().remove(&(namespace.to_string(), key.to_string()));
+		Ok(())
+	}
+
+	fn list(&self, namespace: &str) -> io::Result<Vec<String>> {
+		Ok(self.entries.lock().unwrap().keys()
+			.filter(|(entry_namespace, _)| entry_namespace == namespace)
+			.map(|(_, key)| key.clone())
+			.collect())
+	}
 }
 
 pub struct TestBroadcaster {
@@ -253,6 +306,7 @@ impl chaininterface::BroadcasterInterface for TestBroadcaster {
 
 pub struct TestChannelMessageHandler {
 	pub pending_events: Mutex<Vec<events::MessageSendEvent>>,
+	pub peers_with_channels: Mutex<HashSet<PublicKey>>,
 	expected_recv_msgs: Mutex<Option<Vec<wire::Message<()>>>>,
 }
 
@@ -260,6 +314,7 @@ impl TestChannelMessageHandler {
 	pub fn new() -> Self {
 		TestChannelMessageHandler {
 			pending_events: Mutex::new(Vec::new()),
+			peers_with_channels: Mutex::new(HashSet::new()),
 			expected_recv_msgs: Mutex::new(None),
 		}
 	}
@@ -346,6 +401,9 @@ impl msgs::ChannelMessageHandler for TestChannelMessageHandler {
 		self.received_msg(wire::Message::ChannelReestablish(msg.clone()));
 	}
 	fn peer_disconnected(&self, _their_node_id: &PublicKey, _no_connection_possible: bool) {}
+	fn has_channels_with(&self, their_node_id: &PublicKey) -> bool {
+		self.peers_with_channels.lock().unwrap().contains(their_node_id)
+	}
 	fn peer_connected(&self, _their_node_id: &PublicKey, _msg: &msgs::Init) {
 		// Don't bother with `received_msg` for Init as its auto-generated and we don't want to
 		// bother re-generating the expected Init message in all tests.
@@ -518,6 +576,84 @@ impl events::MessageSendEventsProvider for TestRoutingMessageHandler {
 	}
 }
 
+/// A [`msgs::ChannelMessageHandler`] and [`msgs::RoutingMessageHandler`] which ignores all
+/// messages, calling the given callbacks when a specific peer connects or disconnects. Used to
+/// test the network stack implementations in `lightning-net-*`.
+pub struct TestPeerConnectionHandler {
+	expected_pubkey: PublicKey,
+	on_connected: Box<dyn Fn() + Send + Sync>,
+	on_disconnected: Box<dyn Fn() + Send + Sync>,
+	pub disconnected_flag: AtomicBool,
+	pub pending_events: Mutex<Vec<events::MessageSendEvent>>,
+}
+
+impl TestPeerConnectionHandler {
+	pub fn new<C, D>(expected_pubkey: PublicKey, on_connected: C, on_disconnected: D) -> Self
+	where C: Fn() + Send + Sync + 'static, D: Fn() + Send + Sync + 'static {
+		TestPeerConnectionHandler {
+			expected_pubkey,
+			on_connected: Box::new(on_connected),
+			on_disconnected: Box::new(on_disconnected),
+			disconnected_flag: AtomicBool::new(false),
+			pending_events: Mutex::new(Vec::new()),
+		}
+	}
+}
+
+impl msgs::RoutingMessageHandler for TestPeerConnectionHandler {
+	fn handle_node_announcement(&self, _msg: &msgs::NodeAnnouncement) -> Result<bool, msgs::LightningError> { Ok(false) }
+	fn handle_channel_announcement(&self, _msg: &msgs::ChannelAnnouncement) -> Result<bool, msgs::LightningError> { Ok(false) }
+	fn handle_channel_update(&self, _msg: &msgs::ChannelUpdate) -> Result<bool, msgs::LightningError> { Ok(false) }
+	fn get_next_channel_announcements(&self, _starting_point: u64, _batch_amount: u8) -> Vec<(msgs::ChannelAnnouncement, Option<msgs::ChannelUpdate>, Option<msgs::ChannelUpdate>)> { Vec::new() }
+	fn get_next_node_announcements(&self, _starting_point: Option<&PublicKey>, _batch_amount: u8) -> Vec<msgs::NodeAnnouncement> { Vec::new() }
+	fn peer_connected(&self, _their_node_id: &PublicKey, _init_msg: &msgs::Init) {}
+	fn handle_reply_channel_range(&self, _their_node_id: &PublicKey, _msg: msgs::ReplyChannelRange) -> Result<(), msgs::LightningError> { Ok(()) }
+	fn handle_reply_short_channel_ids_end(&self, _their_node_id: &PublicKey, _msg: msgs::ReplyShortChannelIdsEnd) -> Result<(), msgs::LightningError> { Ok(()) }
+	fn handle_query_channel_range(&self, _their_node_id: &PublicKey, _msg: msgs::QueryChannelRange) -> Result<(), msgs::LightningError> { Ok(()) }
+	fn handle_query_short_channel_ids(&self, _their_node_id: &PublicKey, _msg: msgs::QueryShortChannelIds) -> Result<(), msgs::LightningError> { Ok(()) }
+}
+
+impl msgs::ChannelMessageHandler for TestPeerConnectionHandler {
+	fn handle_open_channel(&self, _their_node_id: &PublicKey, _their_features: InitFeatures, _msg: &msgs::OpenChannel) {}
+	fn handle_accept_channel(&self, _their_node_id: &PublicKey, _their_features: InitFeatures, _msg: &msgs::AcceptChannel) {}
+	fn handle_funding_created(&self, _their_node_id: &PublicKey, _msg: &msgs::FundingCreated) {}
+	fn handle_funding_signed(&self, _their_node_id: &PublicKey, _msg: &msgs::FundingSigned) {}
+	fn handle_channel_ready(&self, _their_node_id: &PublicKey, _msg: &msgs::ChannelReady) {}
+	fn handle_shutdown(&self, _their_node_id: &PublicKey, _their_features: &InitFeatures, _msg: &msgs::Shutdown) {}
+	fn handle_closing_signed(&self, _their_node_id: &PublicKey, _msg: &msgs::ClosingSigned) {}
+	fn handle_update_add_htlc(&self, _their_node_id: &PublicKey, _msg: &msgs::UpdateAddHTLC) {}
+	fn handle_update_fulfill_htlc(&self, _their_node_id: &PublicKey, _msg: &msgs::UpdateFulfillHTLC) {}
+	fn handle_update_fail_htlc(&self, _their_node_id: &PublicKey, _msg: &msgs::UpdateFailHTLC) {}
+	fn handle_update_fail_malformed_htlc(&self, _their_node_id: &PublicKey, _msg: &msgs::UpdateFailMalformedHTLC) {}
+	fn handle_commitment_signed(&self, _their_node_id: &PublicKey, _msg: &msgs::CommitmentSigned) {}
+	fn handle_revoke_and_ack(&self, _their_node_id: &PublicKey, _msg: &msgs::RevokeAndACK) {}
+	fn handle_update_fee(&self, _their_node_id: &PublicKey, _msg: &msgs::UpdateFee) {}
+	fn handle_announcement_signatures(&self, _their_node_id: &PublicKey, _msg: &msgs::AnnouncementSignatures) {}
+	fn handle_channel_update(&self, _their_node_id: &PublicKey, _msg: &msgs::ChannelUpdate) {}
+	fn peer_disconnected(&self, their_node_id: &PublicKey, _no_connection_possible: bool) {
+		if *their_node_id == self.expected_pubkey {
+			self.disconnected_flag.store(true, Ordering::SeqCst);
+			(self.on_disconnected)();
+		}
+	}
+	fn peer_connected(&self, their_node_id: &PublicKey, _msg: &msgs::Init) {
+		if *their_node_id == self.expected_pubkey {
+			(self.on_connected)();
+		}
+	}
+	fn has_channels_with(&self, _their_node_id: &PublicKey) -> bool { false }
+	fn handle_channel_reestablish(&self, _their_node_id: &PublicKey, _msg: &msgs::ChannelReestablish) {}
+	fn handle_error(&self, _their_node_id: &PublicKey, _msg: &msgs::ErrorMessage) {}
+}
+
+impl events::MessageSendEventsProvider for TestPeerConnectionHandler {
+	fn get_and_clear_pending_msg_events(&self) -> Vec<events::MessageSendEvent> {
+		let mut ret = Vec::new();
+		mem::swap(&mut ret, &mut *self.pending_events.lock().unwrap());
+		ret
+	}
+}
+
 pub struct TestLogger {
 	level: Level,
 	#[cfg(feature = "std")]
@@ -781,6 +917,14 @@ impl chain::Filter for TestChainSource {
 		self.watched_outputs.lock().unwrap().insert((output.outpoint, output.script_pubkey));
 		dependent_tx
 	}
+
+	fn unregister_tx(&self, txid: &Txid, script_pubkey: &Script) {
+		self.watched_txn.lock().unwrap().remove(&(*txid, script_pubkey.clone()));
+	}
+
+	fn unregister_output(&self, output: &WatchedOutput) {
+		self.watched_outputs.lock().unwrap().remove(&(output.outpoint, output.script_pubkey.clone()));
+	}
 }
 
 impl Drop for TestChainSource {
@@ -834,3 +978,27 @@ impl core::fmt::Debug for OnRegisterOutput {
 
 /// A scorer useful in testing, when the passage of time isn't a concern.
 pub type TestScorer = FixedPenaltyScorer;
+
+/// Returns a [`Waker`] which counts the number of times it has been woken.
+pub fn counting_waker(wake_count: &Arc<AtomicUsize>) -> Waker {
+	fn clone(data: *const ()) -> RawWaker {
+		let wake_count = unsafe { Arc::from_raw(data as *const AtomicUsize) };
+		let cloned = Arc::clone(&wake_count);
+		core::mem::forget(wake_count);
+		RawWaker::new(Arc::into_raw(cloned) as *const (), &VTABLE)
+	}
+	fn wake(data: *const ()) {
+		wake_by_ref(data);
+		drop(data);
+	}
+	fn wake_by_ref(data: *const ()) {
+		let wake_count = unsafe { &*(data as *const AtomicUsize) };
+		wake_count.fetch_add(1, Ordering::AcqRel);
+	}
+	fn drop(data: *const ()) {
+		unsafe { Arc::from_raw(data as *const AtomicUsize) };
+	}
+	static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop);
+	let data = Arc::into_raw(Arc::clone(wake_count)) as *const ();
+	unsafe { Waker::from_raw(RawWaker::new(data, &VTABLE)) }
+}